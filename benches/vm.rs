@@ -0,0 +1,115 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+// Compares the bytecode VM with the C backend on the programs in
+// `benches/programs`. For each it times how long it takes to build and run
+// the program from source, which is what the VM is for, and how long the
+// built program then takes to run on its own. Run with `cargo bench`.
+
+extern crate tempfile;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+// Each timing is the fastest of this many runs
+const RUNS: usize = 3;
+
+// Runs a command, failing if it does
+fn time(command: &mut Command) -> Duration {
+    let start = Instant::now();
+    let output = command.output().expect("Failed to run the benchmark");
+    let elapsed = start.elapsed();
+    assert!(
+        output.status.success(),
+        "{command:?} failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    elapsed
+}
+
+fn fastest(mut run: impl FnMut() -> Duration) -> Duration {
+    (0..RUNS).map(|_| run()).min().expect("No runs were timed")
+}
+
+fn nimra() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_nimra"))
+}
+
+// How long a program takes to build and run, then to run once built, with
+// a backend
+fn measure(source: &Path, backend: &str, built: &Path) -> (Duration, Duration) {
+    let backend = format!("--backend={backend}");
+    let from_source = fastest(|| {
+        time(
+            nimra()
+                .arg("run")
+                .arg(source)
+                .arg(&backend)
+                .arg("--release"),
+        )
+    });
+    time(
+        nimra()
+            .arg(source)
+            .arg(&backend)
+            .arg("--release")
+            .arg("-o")
+            .arg(built),
+    );
+    let run = if built
+        .extension()
+        .is_some_and(|extension| extension == "nbc")
+    {
+        fastest(|| time(nimra().arg("run").arg(built)))
+    } else {
+        fastest(|| time(&mut Command::new(built)))
+    };
+    (from_source, run)
+}
+
+fn main() {
+    let programs = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/programs");
+    let out_dir = tempfile::tempdir().expect("Failed to create temp directory");
+    let mut sources: Vec<PathBuf> = fs::read_dir(&programs)
+        .expect("Failed to read the benchmark programs")
+        .map(|entry| entry.expect("Failed to read the benchmark programs").path())
+        .filter(|path| path.extension().is_some_and(|found| found == "nimra"))
+        .collect();
+    sources.sort();
+    println!(
+        "{:<12} {:>14} {:>14} {:>14} {:>14}",
+        "program", "c from source", "vm from source", "c run", "vm run"
+    );
+    for source in &sources {
+        let name = source
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .expect("Benchmark program with no name");
+        let (c_source, c_run) = measure(source, "c", &out_dir.path().join(name));
+        let (vm_source, vm_run) = measure(
+            source,
+            "bytecode",
+            &out_dir.path().join(name).with_extension("nbc"),
+        );
+        println!(
+            "{name:<12} {:>14.2?} {:>14.2?} {:>14.2?} {:>14.2?}",
+            c_source, vm_source, c_run, vm_run
+        );
+    }
+}
@@ -0,0 +1,76 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+extern crate tempfile;
+use self::tempfile::Builder;
+
+use crate::diagnostic::{codes, Diagnostic, Stage};
+use crate::options::Options;
+use crate::runtime;
+
+pub fn assemble(assembly: &str, options: &Options) -> Result<PathBuf, Diagnostic> {
+    // 1. Write the generated code and the runtime into a temp directory
+    let dir = Builder::new()
+        .prefix("nimra")
+        .tempdir()
+        .map_err(|e| error(format!("Failed to create temp directory: {e}")))?;
+    let main = dir.path().join("main.s");
+    fs::write(&main, assembly)
+        .map_err(|e| error(format!("Failed to write to temp source file: {e}")))?;
+    let runtime = dir.path().join("runtime.s");
+    fs::write(&runtime, runtime::ASSEMBLY)
+        .map_err(|e| error(format!("Failed to write the runtime: {e}")))?;
+
+    // 2. Assemble each file, then link them with nothing else
+    let mut objects = Vec::new();
+    for source in [&main, &runtime] {
+        let object = source.with_extension("o");
+        run(Command::new("as").arg(source).arg("-o").arg(&object), "as")?;
+        objects.push(object);
+    }
+    run(
+        Command::new("ld")
+            .arg("-static")
+            .args(&objects)
+            .arg("-o")
+            .arg(&options.output),
+        "ld",
+    )?;
+
+    Ok(Path::new(&options.output).to_path_buf())
+}
+
+fn error(message: String) -> Diagnostic {
+    Diagnostic::error(Stage::Compilation, message)
+}
+
+// Runs a tool, keeping what it reports for the diagnostic if it fails
+fn run(command: &mut Command, name: &str) -> Result<(), Diagnostic> {
+    let output = command
+        .output()
+        .map_err(|e| error(format!("Failed to run {name}: {e}")))?;
+    if !output.status.success() {
+        let mut report = String::from_utf8_lossy(&output.stdout).into_owned();
+        report.push_str(&String::from_utf8_lossy(&output.stderr));
+        return Err(error(format!("{name} failed"))
+            .with_code(codes::ASSEMBLER_FAILED)
+            .with_note(format!("{name} reported:\n{}", report.trim_end())));
+    }
+    Ok(())
+}
@@ -0,0 +1,1226 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::collections::HashMap;
+
+use crate::generator::{self, ICInstruction};
+use crate::ir::{Function, Instruction, Operand, Reg, Terminator};
+use crate::lexer::{Length, Literal, Span, Type};
+use crate::options::Options;
+use crate::parser::{BinaryOp, UnaryOp};
+use crate::sema::{FormatPiece, Intrinsic, IoFunction, TraitMethod};
+
+/// A program compiled for the VM. Everything it names, such as functions,
+/// text and constants, is an index into one of its tables, so it is cheap
+/// to load and needs no compiler to run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    /// Debug builds panic on integer overflow and print backtraces
+    pub debug: bool,
+    /// Source file named in panic locations
+    pub file: String,
+    /// The text of string constants, printed text and function names
+    pub strings: Vec<String>,
+    pub constants: Vec<Constant>,
+    pub shapes: Vec<Shape>,
+    pub functions: Vec<Code>,
+    /// The functions of each vtable, in the order of its trait's methods
+    pub vtables: Vec<Vec<u32>>,
+    pub main: u32,
+    /// How the error of a `main` that returns a `Result` is printed
+    pub main_error: Option<Kind>,
+}
+
+/// A compiled function. Its registers are those of the intermediate code,
+/// followed by any the compiler adds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Code {
+    pub name: u32,
+    /// What backtraces call the function
+    pub source_name: u32,
+    pub params: Vec<u32>,
+    pub env: Option<u32>,
+    /// The shape of each register, which starts out as its zero value
+    pub regs: Vec<u32>,
+    pub ops: Vec<Op>,
+}
+
+/// What an instruction reads: a register of its function, or a constant of
+/// the program
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arg {
+    Reg(u32),
+    Const(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    /// An integer, held in 64 bits the way the VM holds one of its type
+    Int(i64),
+    Bool(bool),
+    Char(char),
+    /// A `str`, as an index into the program's strings
+    Str(u32),
+}
+
+/// The layout of a type, as far as the VM needs it to make the zero value
+/// registers and moved-out places are set to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    Void,
+    Int,
+    Bool,
+    Char,
+    Str,
+    String,
+    /// A reference, box, buffer, function or `dyn Trait`, which is empty
+    Null,
+    /// The shapes of the fields of a struct, `Option` or `Result`
+    Struct(Vec<u32>),
+    Array(u32, u64),
+}
+
+/// The type of the values an operation works on, where it matters to how
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    Bool,
+    Char,
+    Str,
+    String,
+    Other,
+}
+
+/// A piece of the text a print instruction writes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Piece {
+    Text(u32),
+    Placeholder,
+}
+
+/// An instruction of the register machine the VM runs. Most mirror an
+/// instruction of the intermediate code; jumps name the offset of the
+/// instruction they go to in their function.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Copy {
+        dest: u32,
+        value: Arg,
+    },
+    Zero {
+        dest: u32,
+    },
+    Binary {
+        dest: u32,
+        op: BinaryOp,
+        kind: Kind,
+        lhs: Arg,
+        rhs: Arg,
+        at: Span,
+    },
+    Neg {
+        dest: u32,
+        kind: Kind,
+        value: Arg,
+        at: Span,
+    },
+    Not {
+        dest: u32,
+        value: Arg,
+    },
+    /// Converts a value to `kind`
+    Cast {
+        dest: u32,
+        kind: Kind,
+        value: Arg,
+    },
+    Call {
+        dest: Option<u32>,
+        function: u32,
+        args: Vec<Arg>,
+    },
+    CallValue {
+        dest: Option<u32>,
+        callee: Arg,
+        args: Vec<Arg>,
+    },
+    /// Calls the function at `method` in the vtable of the first argument,
+    /// passing the value it holds instead
+    CallDyn {
+        dest: Option<u32>,
+        method: u32,
+        args: Vec<Arg>,
+    },
+    /// `kind` is that of the first argument
+    Intrinsic {
+        dest: Option<u32>,
+        intrinsic: Intrinsic,
+        kind: Kind,
+        args: Vec<Arg>,
+        at: Span,
+    },
+    /// The value of a `Some` or `Ok`, panicking on a `None` or an `Err`
+    Unwrap {
+        dest: Option<u32>,
+        value: Arg,
+        result: bool,
+        at: Span,
+    },
+    UnwrapOr {
+        dest: u32,
+        value: Arg,
+        default: Arg,
+    },
+    Print {
+        function: IoFunction,
+        pieces: Vec<Piece>,
+        args: Vec<(Arg, Kind)>,
+    },
+    ToDyn {
+        dest: u32,
+        vtable: u32,
+        value: Arg,
+    },
+    MakeFn {
+        dest: u32,
+        function: u32,
+        env: Option<Arg>,
+    },
+    /// The zero value of the register's struct with the given fields set
+    Struct {
+        dest: u32,
+        fields: Vec<(u32, Arg)>,
+    },
+    Array {
+        dest: u32,
+        elements: Vec<Arg>,
+    },
+    ArrayRepeat {
+        dest: u32,
+        len: u64,
+        value: Arg,
+    },
+    Field {
+        dest: u32,
+        value: Arg,
+        field: u32,
+    },
+    Index {
+        dest: u32,
+        array: Arg,
+        index: Arg,
+        at: Span,
+    },
+    /// Moves the value of a register whose address is taken to the heap,
+    /// leaving the register pointing to it
+    Local {
+        reg: u32,
+    },
+    FieldAddress {
+        dest: u32,
+        base: Arg,
+        field: u32,
+    },
+    IndexAddress {
+        dest: u32,
+        base: Arg,
+        index: Arg,
+        at: Span,
+    },
+    Load {
+        dest: u32,
+        address: Arg,
+    },
+    Store {
+        address: Arg,
+        value: Arg,
+    },
+    /// Points `dest` to a new value of the given shape
+    Alloc {
+        dest: u32,
+        shape: u32,
+    },
+    Move {
+        dest: u32,
+        address: Arg,
+    },
+    NextChar {
+        dest: u32,
+        text: Arg,
+        pos: Arg,
+    },
+    Jump {
+        target: u32,
+    },
+    Branch {
+        condition: Arg,
+        then_target: u32,
+        else_target: u32,
+    },
+    /// Jumps to the target of the first case whose key is the value as an
+    /// integer, or to `default`
+    Switch {
+        value: Arg,
+        cases: Vec<(i64, u32)>,
+        default: u32,
+    },
+    Return {
+        value: Option<Arg>,
+    },
+    Unreachable,
+}
+
+impl Kind {
+    pub fn of(ty: &Type) -> Kind {
+        match ty {
+            Type::I8 => Kind::I8,
+            Type::I16 => Kind::I16,
+            Type::I32 => Kind::I32,
+            Type::I64 => Kind::I64,
+            Type::U8 => Kind::U8,
+            Type::U16 => Kind::U16,
+            Type::U32 => Kind::U32,
+            Type::U64 => Kind::U64,
+            Type::Bool => Kind::Bool,
+            Type::Char => Kind::Char,
+            Type::Str => Kind::Str,
+            Type::String => Kind::String,
+            _ => Kind::Other,
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(self, Kind::I8 | Kind::I16 | Kind::I32 | Kind::I64)
+    }
+
+    /// The smallest and largest values of an integer kind
+    pub fn range(self) -> Option<(i128, i128)> {
+        let bits = match self {
+            Kind::I8 | Kind::U8 => 8,
+            Kind::I16 | Kind::U16 => 16,
+            Kind::I32 | Kind::U32 => 32,
+            Kind::I64 | Kind::U64 => 64,
+            _ => return None,
+        };
+        Some(if self.is_signed() {
+            (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+        } else {
+            (0, (1 << bits) - 1)
+        })
+    }
+
+    /// Cuts an integer down to the bits of this kind, sign-extending signed
+    /// kinds and zero-extending unsigned ones, which is how the VM holds them
+    pub fn truncate(self, n: i64) -> i64 {
+        match self {
+            Kind::I8 => i64::from(n as i8),
+            Kind::I16 => i64::from(n as i16),
+            Kind::I32 => i64::from(n as i32),
+            Kind::U8 => i64::from(n as u8),
+            Kind::U16 => i64::from(n as u16),
+            Kind::U32 => i64::from(n as u32),
+            _ => n,
+        }
+    }
+
+    /// The number an integer of this kind held in 64 bits stands for
+    pub fn widen(self, n: i64) -> i128 {
+        if self == Kind::U64 {
+            i128::from(n as u64)
+        } else {
+            i128::from(n)
+        }
+    }
+}
+
+/// Functions and the types their instructions refer to by index
+struct Compiler<'a> {
+    ic: &'a [ICInstruction],
+    program: Program,
+    function_ids: HashMap<&'a str, u32>,
+    traits: HashMap<&'a str, &'a [TraitMethod]>,
+    /// The trait and type of each vtable
+    vtable_types: Vec<(&'a str, &'a Type)>,
+    /// The type of each shape
+    shape_types: Vec<Type>,
+    string_ids: HashMap<String, u32>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(ic: &'a [ICInstruction], options: &Options) -> Compiler<'a> {
+        let mut function_ids = HashMap::new();
+        let mut traits = HashMap::new();
+        let mut vtable_types = Vec::new();
+        for instruction in ic {
+            match instruction {
+                ICInstruction::Function(function) => {
+                    let id = function_ids.len() as u32;
+                    function_ids.insert(function.name.as_str(), id);
+                }
+                ICInstruction::TraitDecl { name, methods } => {
+                    traits.insert(name.as_str(), methods.as_slice());
+                }
+                ICInstruction::Vtable { trait_name, ty, .. } => {
+                    vtable_types.push((trait_name.as_str(), ty));
+                }
+                _ => {}
+            }
+        }
+        Compiler {
+            ic,
+            program: Program {
+                debug: !options.release,
+                file: options.input.clone(),
+                strings: Vec::new(),
+                constants: Vec::new(),
+                shapes: Vec::new(),
+                functions: Vec::new(),
+                vtables: Vec::new(),
+                main: 0,
+                main_error: None,
+            },
+            function_ids,
+            traits,
+            vtable_types,
+            shape_types: Vec::new(),
+            string_ids: HashMap::new(),
+        }
+    }
+
+    fn compile(mut self) -> Result<Program, String> {
+        self.program.main = self.function_id("main")?;
+        for &(trait_name, ty) in &self.vtable_types {
+            let methods = self.trait_methods(trait_name)?;
+            let functions = methods
+                .iter()
+                .map(|method| {
+                    self.function_id(&generator::mangle_method(trait_name, ty, &method.name))
+                })
+                .collect::<Result<_, _>>()?;
+            self.program.vtables.push(functions);
+        }
+        for instruction in self.ic {
+            if let ICInstruction::Function(function) = instruction {
+                if let (Type::Result(_, error), "main") =
+                    (&function.return_type, function.name.as_str())
+                {
+                    self.program.main_error = Some(Kind::of(error));
+                }
+                let code = self.function(function)?;
+                self.program.functions.push(code);
+            }
+        }
+        Ok(self.program)
+    }
+
+    fn function_id(&self, name: &str) -> Result<u32, String> {
+        self.function_ids
+            .get(name)
+            .copied()
+            .ok_or_else(|| format!("Unknown function {name}"))
+    }
+
+    fn trait_methods(&self, name: &str) -> Result<&'a [TraitMethod], String> {
+        self.traits
+            .get(name)
+            .copied()
+            .ok_or_else(|| format!("Unknown trait {name}"))
+    }
+
+    fn string(&mut self, text: &str) -> u32 {
+        if let Some(&id) = self.string_ids.get(text) {
+            return id;
+        }
+        let id = self.program.strings.len() as u32;
+        self.program.strings.push(text.to_string());
+        self.string_ids.insert(text.to_string(), id);
+        id
+    }
+
+    fn constant(&mut self, literal: &Literal, ty: &Type) -> u32 {
+        let constant = match literal {
+            Literal::Number(n) => Constant::Int(Kind::of(ty).truncate(*n)),
+            Literal::String(s) => Constant::Str(self.string(s)),
+            Literal::Bool(b) => Constant::Bool(*b),
+            Literal::Char(c) => Constant::Char(*c),
+        };
+        match self.program.constants.iter().position(|c| *c == constant) {
+            Some(id) => id as u32,
+            None => {
+                self.program.constants.push(constant);
+                self.program.constants.len() as u32 - 1
+            }
+        }
+    }
+
+    fn shape(&mut self, ty: &Type) -> Result<u32, String> {
+        if let Some(id) = self.shape_types.iter().position(|known| known == ty) {
+            return Ok(id as u32);
+        }
+        let shape = match ty {
+            Type::Void => Shape::Void,
+            Type::Bool => Shape::Bool,
+            Type::Char => Shape::Char,
+            Type::Str => Shape::Str,
+            Type::String => Shape::String,
+            ty if ty.is_integer() => Shape::Int,
+            Type::Struct(..) | Type::Option(_) | Type::Result(..) => {
+                let fields = self.struct_fields(ty)?;
+                Shape::Struct(
+                    fields
+                        .iter()
+                        .map(|(_, field)| self.shape(field))
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            Type::Array(element, Length::Known(len)) => Shape::Array(self.shape(element)?, *len),
+            Type::Ref(..) | Type::Box(_) | Type::Buffer(_) | Type::Fn(..) | Type::Dyn(_) => {
+                Shape::Null
+            }
+            ty => return Err(format!("Cannot compile a value of type {ty} to bytecode")),
+        };
+        self.shape_types.push(ty.clone());
+        self.program.shapes.push(shape);
+        Ok(self.program.shapes.len() as u32 - 1)
+    }
+
+    fn struct_fields(&self, ty: &Type) -> Result<&'a [(String, Type)], String> {
+        let name = generator::mangle(ty);
+        self.ic
+            .iter()
+            .find_map(|ic| match ic {
+                ICInstruction::StructDecl { name: decl, fields } if *decl == name => {
+                    Some(fields.as_slice())
+                }
+                _ => None,
+            })
+            .ok_or_else(|| format!("Unknown struct {ty}"))
+    }
+
+    fn field_index(&self, ty: &Type, field: &str) -> Result<u32, String> {
+        self.struct_fields(ty)?
+            .iter()
+            .position(|(name, _)| name == field)
+            .map(|index| index as u32)
+            .ok_or_else(|| format!("{ty} has no field {field}"))
+    }
+
+    // Compiles a function. A register whose address is taken lives on the
+    // heap, where its register points, so every instruction that reads or
+    // writes it goes through a spare register loaded from or stored to there.
+    fn function(&mut self, function: &Function) -> Result<Code, String> {
+        let mut function = function.clone();
+        let mut spares: Vec<Option<u32>> = vec![None; function.regs.len()];
+        let blocks = function.blocks.iter();
+        for instruction in blocks.flat_map(|block| &block.instructions) {
+            if let Instruction::AddressOf { reg, .. } = instruction {
+                if spares[*reg].is_none() {
+                    spares[*reg] = Some(function.regs.len() as u32);
+                    function.regs.push(function.regs[*reg].clone());
+                }
+            }
+        }
+        let function = &function;
+        let regs = function
+            .regs
+            .iter()
+            .map(|reg| self.shape(&reg.ty))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut ops: Vec<Op> = (0..spares.len())
+            .filter(|reg| spares[*reg].is_some())
+            .map(|reg| Op::Local { reg: reg as u32 })
+            .collect();
+        let mut starts = Vec::new();
+        for block in &function.blocks {
+            starts.push(ops.len() as u32);
+            for instruction in &block.instructions {
+                let mut instruction = instruction.clone();
+                let mut loaded = Vec::new();
+                for operand in instruction.operands_mut() {
+                    spill(operand, &spares, &mut loaded);
+                }
+                let stored = instruction.dest_mut().and_then(|dest| {
+                    let spare = spares[*dest]?;
+                    Some((std::mem::replace(dest, spare as Reg), spare))
+                });
+                ops.extend(loaded.into_iter().map(|(reg, spare)| Op::Load {
+                    dest: spare,
+                    address: Arg::Reg(reg as u32),
+                }));
+                if let Some(op) = self.instruction(function, &instruction)? {
+                    ops.push(op);
+                }
+                if let Some((reg, spare)) = stored {
+                    ops.push(Op::Store {
+                        address: Arg::Reg(reg as u32),
+                        value: Arg::Reg(spare),
+                    });
+                }
+            }
+            let mut terminator = block.terminator.clone();
+            let mut loaded = Vec::new();
+            for operand in terminator.operands_mut() {
+                spill(operand, &spares, &mut loaded);
+            }
+            ops.extend(loaded.into_iter().map(|(reg, spare)| Op::Load {
+                dest: spare,
+                address: Arg::Reg(reg as u32),
+            }));
+            let op = self.terminator(function, &terminator)?;
+            ops.push(op);
+        }
+        // Jumps were compiled to the blocks they go to
+        for op in &mut ops {
+            match op {
+                Op::Jump { target } => *target = starts[*target as usize],
+                Op::Branch {
+                    then_target,
+                    else_target,
+                    ..
+                } => {
+                    *then_target = starts[*then_target as usize];
+                    *else_target = starts[*else_target as usize];
+                }
+                Op::Switch { cases, default, .. } => {
+                    for (_, target) in cases {
+                        *target = starts[*target as usize];
+                    }
+                    *default = starts[*default as usize];
+                }
+                _ => {}
+            }
+        }
+        Ok(Code {
+            name: self.string(&function.name),
+            source_name: self.string(&function.source_name),
+            params: function.params.iter().map(|reg| *reg as u32).collect(),
+            env: function.env.map(|reg| reg as u32),
+            regs,
+            ops,
+        })
+    }
+
+    fn arg(&mut self, operand: &Operand) -> Arg {
+        match operand {
+            Operand::Reg(reg) => Arg::Reg(*reg as u32),
+            Operand::Const(literal, ty) => Arg::Const(self.constant(literal, ty)),
+        }
+    }
+
+    fn args(&mut self, operands: &[Operand]) -> Vec<Arg> {
+        operands.iter().map(|operand| self.arg(operand)).collect()
+    }
+
+    // The op an instruction compiles to; drops compile to nothing, since the
+    // VM frees values once nothing refers to them
+    fn instruction(
+        &mut self,
+        function: &Function,
+        instruction: &Instruction,
+    ) -> Result<Option<Op>, String> {
+        let reg = |reg: &Reg| *reg as u32;
+        let ty = |reg: &Reg| &function.regs[*reg].ty;
+        Ok(Some(match instruction {
+            Instruction::Copy { dest, value } => Op::Copy {
+                dest: reg(dest),
+                value: self.arg(value),
+            },
+            Instruction::Zero { dest } => Op::Zero { dest: reg(dest) },
+            Instruction::Binary {
+                dest,
+                op,
+                lhs,
+                rhs,
+                span,
+            } => Op::Binary {
+                dest: reg(dest),
+                op: *op,
+                kind: Kind::of(&function.operand_type(lhs)),
+                lhs: self.arg(lhs),
+                rhs: self.arg(rhs),
+                at: *span,
+            },
+            Instruction::Unary {
+                dest,
+                op: UnaryOp::Neg,
+                operand,
+                span,
+            } => Op::Neg {
+                dest: reg(dest),
+                kind: Kind::of(ty(dest)),
+                value: self.arg(operand),
+                at: *span,
+            },
+            Instruction::Unary {
+                dest,
+                op: UnaryOp::Not,
+                operand,
+                ..
+            } => Op::Not {
+                dest: reg(dest),
+                value: self.arg(operand),
+            },
+            Instruction::Cast { dest, value } => Op::Cast {
+                dest: reg(dest),
+                kind: Kind::of(ty(dest)),
+                value: self.arg(value),
+            },
+            Instruction::Call {
+                dest,
+                function: callee,
+                args,
+            } => Op::Call {
+                dest: dest.as_ref().map(reg),
+                function: self.function_id(callee)?,
+                args: self.args(args),
+            },
+            Instruction::CallValue { dest, callee, args } => Op::CallValue {
+                dest: dest.as_ref().map(reg),
+                callee: self.arg(callee),
+                args: self.args(args),
+            },
+            Instruction::CallDyn {
+                dest,
+                trait_name,
+                method,
+                args,
+            } => Op::CallDyn {
+                dest: dest.as_ref().map(reg),
+                method: self
+                    .trait_methods(trait_name)?
+                    .iter()
+                    .position(|found| found.name == *method)
+                    .ok_or_else(|| format!("Trait {trait_name} has no method {method}"))?
+                    as u32,
+                args: self.args(args),
+            },
+            Instruction::Intrinsic {
+                dest,
+                intrinsic: Intrinsic::Unwrap,
+                args,
+                span,
+            } => Op::Unwrap {
+                dest: dest.as_ref().map(reg),
+                value: self.arg(&args[0]),
+                result: matches!(function.operand_type(&args[0]), Type::Result(..)),
+                at: *span,
+            },
+            Instruction::Intrinsic {
+                dest: Some(dest),
+                intrinsic: Intrinsic::UnwrapOr,
+                args,
+                ..
+            } => Op::UnwrapOr {
+                dest: reg(dest),
+                value: self.arg(&args[0]),
+                default: self.arg(&args[1]),
+            },
+            Instruction::Intrinsic {
+                intrinsic: Intrinsic::ArrayLen,
+                ..
+            } => return Err("The length of an array is known before code generation".to_string()),
+            Instruction::Intrinsic {
+                dest,
+                intrinsic,
+                args,
+                span,
+            } => Op::Intrinsic {
+                dest: dest.as_ref().map(reg),
+                intrinsic: *intrinsic,
+                kind: args
+                    .first()
+                    .map_or(Kind::Other, |arg| Kind::of(&function.operand_type(arg))),
+                args: self.args(args),
+                at: *span,
+            },
+            Instruction::Print {
+                function: io_function,
+                pieces,
+                args,
+            } => Op::Print {
+                function: *io_function,
+                pieces: pieces
+                    .iter()
+                    .map(|piece| match piece {
+                        FormatPiece::Text(text) => Piece::Text(self.string(text)),
+                        FormatPiece::Placeholder => Piece::Placeholder,
+                    })
+                    .collect(),
+                args: args
+                    .iter()
+                    .map(|arg| (self.arg(arg), Kind::of(&function.operand_type(arg))))
+                    .collect(),
+            },
+            Instruction::ToDyn { dest, value } => {
+                let Type::Dyn(trait_name) = ty(dest) else {
+                    return Err(format!("Cannot convert a value to {}", ty(dest)));
+                };
+                let value_type = function.operand_type(value);
+                let vtable = self
+                    .vtable_types
+                    .iter()
+                    .position(|(name, ty)| name == trait_name && **ty == value_type)
+                    .ok_or_else(|| format!("{value_type} does not implement {trait_name}"))?;
+                Op::ToDyn {
+                    dest: reg(dest),
+                    vtable: vtable as u32,
+                    value: self.arg(value),
+                }
+            }
+            Instruction::MakeFn {
+                dest,
+                function: callee,
+                env,
+            } => Op::MakeFn {
+                dest: reg(dest),
+                function: self.function_id(callee)?,
+                env: env.as_ref().map(|env| self.arg(env)),
+            },
+            Instruction::Struct { dest, fields } => Op::Struct {
+                dest: reg(dest),
+                fields: fields
+                    .iter()
+                    .map(|(field, value)| Ok((self.field_index(ty(dest), field)?, self.arg(value))))
+                    .collect::<Result<_, String>>()?,
+            },
+            Instruction::Array { dest, elements } => Op::Array {
+                dest: reg(dest),
+                elements: self.args(elements),
+            },
+            Instruction::ArrayRepeat { dest, value } => {
+                let Type::Array(_, Length::Known(len)) = ty(dest) else {
+                    return Err(format!("Cannot repeat a value into {}", ty(dest)));
+                };
+                Op::ArrayRepeat {
+                    dest: reg(dest),
+                    len: *len,
+                    value: self.arg(value),
+                }
+            }
+            Instruction::Field { dest, value, field } => Op::Field {
+                dest: reg(dest),
+                field: self.field_index(&function.operand_type(value), field)?,
+                value: self.arg(value),
+            },
+            Instruction::Index {
+                dest,
+                array,
+                index,
+                span,
+            } => Op::Index {
+                dest: reg(dest),
+                array: self.arg(array),
+                index: self.arg(index),
+                at: *span,
+            },
+            // The register was moved to the heap, and now holds its address
+            Instruction::AddressOf { dest, reg: local } => Op::Copy {
+                dest: reg(dest),
+                value: Arg::Reg(reg(local)),
+            },
+            Instruction::FieldAddress { dest, base, field } => Op::FieldAddress {
+                dest: reg(dest),
+                field: self.field_index(&pointee(&function.operand_type(base)), field)?,
+                base: self.arg(base),
+            },
+            Instruction::IndexAddress {
+                dest,
+                base,
+                index,
+                span,
+            } => Op::IndexAddress {
+                dest: reg(dest),
+                base: self.arg(base),
+                index: self.arg(index),
+                at: *span,
+            },
+            Instruction::Load { dest, address } => Op::Load {
+                dest: reg(dest),
+                address: self.arg(address),
+            },
+            Instruction::Store { address, value } => Op::Store {
+                address: self.arg(address),
+                value: self.arg(value),
+            },
+            Instruction::Alloc { dest } => Op::Alloc {
+                dest: reg(dest),
+                shape: self.shape(&pointee(ty(dest)))?,
+            },
+            Instruction::Move { dest, address } => Op::Move {
+                dest: reg(dest),
+                address: self.arg(address),
+            },
+            Instruction::Drop { .. } => return Ok(None),
+            Instruction::NextChar { dest, text, pos } => Op::NextChar {
+                dest: reg(dest),
+                text: self.arg(text),
+                pos: self.arg(pos),
+            },
+            Instruction::Phi { .. } => {
+                return Err("Phis must be removed before code generation".to_string())
+            }
+        }))
+    }
+
+    // A terminator, jumping to block numbers until they are replaced by offsets
+    fn terminator(&mut self, function: &Function, terminator: &Terminator) -> Result<Op, String> {
+        Ok(match terminator {
+            Terminator::Jump(target) => Op::Jump {
+                target: *target as u32,
+            },
+            Terminator::Branch {
+                condition,
+                then_block,
+                else_block,
+            } => Op::Branch {
+                condition: self.arg(condition),
+                then_target: *then_block as u32,
+                else_target: *else_block as u32,
+            },
+            Terminator::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                let kind = Kind::of(&function.operand_type(value));
+                let cases = cases
+                    .iter()
+                    .map(|(literal, target)| {
+                        let key = match literal {
+                            Literal::Number(n) => kind.truncate(*n),
+                            Literal::Bool(b) => i64::from(*b),
+                            Literal::Char(c) => i64::from(u32::from(*c)),
+                            Literal::String(_) => {
+                                return Err("Cannot switch on a string".to_string())
+                            }
+                        };
+                        Ok((key, *target as u32))
+                    })
+                    .collect::<Result<_, String>>()?;
+                Op::Switch {
+                    value: self.arg(value),
+                    cases,
+                    default: *default as u32,
+                }
+            }
+            Terminator::Return(value) => Op::Return {
+                value: value.as_ref().map(|value| self.arg(value)),
+            },
+            Terminator::Unreachable => Op::Unreachable,
+        })
+    }
+}
+
+// Replaces a register that lives on the heap with its spare, noting that the
+// spare has to be loaded first
+fn spill(operand: &mut Operand, spares: &[Option<u32>], loaded: &mut Vec<(Reg, u32)>) {
+    if let Operand::Reg(reg) = operand {
+        if let Some(spare) = spares[*reg] {
+            if !loaded.contains(&(*reg, spare)) {
+                loaded.push((*reg, spare));
+            }
+            *operand = Operand::Reg(spare as Reg);
+        }
+    }
+}
+
+/// The type a reference or box points to
+fn pointee(ty: &Type) -> Type {
+    match ty {
+        Type::Ref(inner, _) | Type::Box(inner) => (**inner).clone(),
+        ty => ty.clone(),
+    }
+}
+
+/// Compiles optimized intermediate code, which has no phis left, to bytecode
+pub fn compile(ic: &[ICInstruction], options: &Options) -> Result<Program, String> {
+    Compiler::new(ic, options).compile()
+}
+
+/// Prints a program as text: its shapes and vtables, then each function
+/// with its instructions at their offsets, which jumps go to
+pub fn disassemble(program: &Program) -> String {
+    let build = if program.debug { "debug" } else { "release" };
+    let mut text = format!("; {build} build of {}\n", program.file);
+    for (id, shape) in program.shapes.iter().enumerate() {
+        let shape = match shape {
+            Shape::Struct(fields) => format!(
+                "{{{}}}",
+                fields
+                    .iter()
+                    .map(|field| format!("s{field}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Shape::Array(element, len) => format!("[s{element}; {len}]"),
+            shape => format!("{shape:?}").to_lowercase(),
+        };
+        text.push_str(&format!("shape s{id} = {shape}\n"));
+    }
+    for (id, functions) in program.vtables.iter().enumerate() {
+        let functions = functions
+            .iter()
+            .map(|function| function_name(program, *function))
+            .collect::<Vec<_>>();
+        text.push_str(&format!("vtable {id} = [{}]\n", functions.join(", ")));
+    }
+    for (id, code) in program.functions.iter().enumerate() {
+        let mut params = code
+            .params
+            .iter()
+            .map(|reg| format!("r{reg}"))
+            .collect::<Vec<_>>();
+        if let Some(env) = code.env {
+            params.insert(0, format!("env r{env}"));
+        }
+        let main = if id as u32 == program.main {
+            " main"
+        } else {
+            ""
+        };
+        text.push_str(&format!(
+            "fn {} {:?}({}){main} {{\n",
+            function_name(program, id as u32),
+            string(program, code.source_name),
+            params.join(", ")
+        ));
+        for (reg, shape) in code.regs.iter().enumerate() {
+            text.push_str(&format!("    reg r{reg}: s{shape}\n"));
+        }
+        for (offset, op) in code.ops.iter().enumerate() {
+            text.push_str(&format!("{offset:4}  {}\n", op_text(program, op)));
+        }
+        text.push_str("}\n");
+    }
+    text
+}
+
+fn string(program: &Program, id: u32) -> &str {
+    program.strings.get(id as usize).map_or("?", String::as_str)
+}
+
+fn function_name(program: &Program, id: u32) -> &str {
+    program
+        .functions
+        .get(id as usize)
+        .map_or("?", |code| string(program, code.name))
+}
+
+fn arg_text(program: &Program, arg: Arg) -> String {
+    match arg {
+        Arg::Reg(reg) => format!("r{reg}"),
+        Arg::Const(id) => match program.constants.get(id as usize) {
+            Some(Constant::Int(n)) => n.to_string(),
+            Some(Constant::Bool(b)) => b.to_string(),
+            Some(Constant::Char(c)) => format!("{c:?}"),
+            Some(Constant::Str(s)) => format!("{:?}", string(program, *s)),
+            None => format!("c{id}?"),
+        },
+    }
+}
+
+fn op_text(program: &Program, op: &Op) -> String {
+    let arg = |a: &Arg| arg_text(program, *a);
+    let args = |args: &[Arg]| args.iter().map(arg).collect::<Vec<_>>().join(", ");
+    let dest = |dest: &Option<u32>| dest.map_or(String::new(), |dest| format!("r{dest} = "));
+    let at = |span: &Span| format!(" @{}:{}", span.line, span.column);
+    let kind = |kind: &Kind| format!("{kind:?}").to_lowercase();
+    match op {
+        Op::Copy { dest, value } => format!("r{dest} = {}", arg(value)),
+        Op::Zero { dest } => format!("r{dest} = zero"),
+        Op::Binary {
+            dest,
+            op,
+            kind: k,
+            lhs,
+            rhs,
+            at: span,
+        } => format!(
+            "r{dest} = {} {} {}, {}{}",
+            format!("{op:?}").to_lowercase(),
+            kind(k),
+            arg(lhs),
+            arg(rhs),
+            at(span)
+        ),
+        Op::Neg {
+            dest,
+            kind: k,
+            value,
+            at: span,
+        } => format!("r{dest} = neg {} {}{}", kind(k), arg(value), at(span)),
+        Op::Not { dest, value } => format!("r{dest} = not {}", arg(value)),
+        Op::Cast {
+            dest,
+            kind: k,
+            value,
+        } => format!("r{dest} = cast {} {}", kind(k), arg(value)),
+        Op::Call {
+            dest: d,
+            function,
+            args: a,
+        } => format!(
+            "{}call {}({})",
+            dest(d),
+            function_name(program, *function),
+            args(a)
+        ),
+        Op::CallValue {
+            dest: d,
+            callee,
+            args: a,
+        } => format!("{}call_value {}({})", dest(d), arg(callee), args(a)),
+        Op::CallDyn {
+            dest: d,
+            method,
+            args: a,
+        } => format!("{}call_dyn {method}({})", dest(d), args(a)),
+        Op::Intrinsic {
+            dest: d,
+            intrinsic,
+            kind: k,
+            args: a,
+            at: span,
+        } => format!(
+            "{}{} {}({}){}",
+            dest(d),
+            format!("{intrinsic:?}").to_lowercase(),
+            kind(k),
+            args(a),
+            at(span)
+        ),
+        Op::Unwrap {
+            dest: d,
+            value,
+            result,
+            at: span,
+        } => {
+            let from = if *result { "result" } else { "option" };
+            format!("{}unwrap {from} {}{}", dest(d), arg(value), at(span))
+        }
+        Op::UnwrapOr {
+            dest,
+            value,
+            default,
+        } => format!("r{dest} = unwrap_or {}, {}", arg(value), arg(default)),
+        Op::Print {
+            function,
+            pieces,
+            args: a,
+        } => {
+            let pieces = pieces
+                .iter()
+                .map(|piece| match piece {
+                    Piece::Text(text) => format!("{:?}", string(program, *text)),
+                    Piece::Placeholder => "{}".to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            let a = a
+                .iter()
+                .map(|(value, k)| format!("{} {}", kind(k), arg(value)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("{} [{pieces}]({a})", format!("{function:?}").to_lowercase())
+        }
+        Op::ToDyn {
+            dest,
+            vtable,
+            value,
+        } => format!("r{dest} = to_dyn {vtable} {}", arg(value)),
+        Op::MakeFn {
+            dest,
+            function,
+            env,
+        } => {
+            let env = env.map_or(String::new(), |env| format!(", {}", arg(&env)));
+            format!(
+                "r{dest} = make_fn {}{env}",
+                function_name(program, *function)
+            )
+        }
+        Op::Struct { dest, fields } => {
+            let fields = fields
+                .iter()
+                .map(|(field, value)| format!("{field}: {}", arg(value)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("r{dest} = struct {{{fields}}}")
+        }
+        Op::Array { dest, elements } => format!("r{dest} = array [{}]", args(elements)),
+        Op::ArrayRepeat { dest, len, value } => {
+            format!("r{dest} = array [{}; {len}]", arg(value))
+        }
+        Op::Field { dest, value, field } => format!("r{dest} = field {}, {field}", arg(value)),
+        Op::Index {
+            dest,
+            array,
+            index,
+            at: span,
+        } => format!("r{dest} = index {}, {}{}", arg(array), arg(index), at(span)),
+        Op::Local { reg } => format!("local r{reg}"),
+        Op::FieldAddress { dest, base, field } => {
+            format!("r{dest} = field_address {}, {field}", arg(base))
+        }
+        Op::IndexAddress {
+            dest,
+            base,
+            index,
+            at: span,
+        } => format!(
+            "r{dest} = index_address {}, {}{}",
+            arg(base),
+            arg(index),
+            at(span)
+        ),
+        Op::Load { dest, address } => format!("r{dest} = load {}", arg(address)),
+        Op::Store { address, value } => format!("store {}, {}", arg(address), arg(value)),
+        Op::Alloc { dest, shape } => format!("r{dest} = alloc s{shape}"),
+        Op::Move { dest, address } => format!("r{dest} = move {}", arg(address)),
+        Op::NextChar { dest, text, pos } => {
+            format!("r{dest} = next_char {}, {}", arg(text), arg(pos))
+        }
+        Op::Jump { target } => format!("jump {target}"),
+        Op::Branch {
+            condition,
+            then_target,
+            else_target,
+        } => format!("branch {}, {then_target}, {else_target}", arg(condition)),
+        Op::Switch {
+            value,
+            cases,
+            default,
+        } => {
+            let cases = cases
+                .iter()
+                .map(|(key, target)| format!("{key} => {target}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("switch {} [{cases}] else {default}", arg(value))
+        }
+        Op::Return { value: None } => "return".to_string(),
+        Op::Return { value: Some(value) } => format!("return {}", arg(value)),
+        Op::Unreachable => "unreachable".to_string(),
+    }
+}
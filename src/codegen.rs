@@ -16,221 +16,1342 @@
  */
 
 use crate::{
-    generator::ICInstruction,
-    lexer::{Literal, Type},
-    parser::ASTNode,
+    diagnostic::{Diagnostic, Stage},
+    generator::{self, ICInstruction},
+    ir::{Function, Instruction, Operand, Terminator},
+    lexer::{Length, Literal, Span, Type},
+    options::Options,
+    parser::{BinaryOp, UnaryOp},
+    runtime::{self, Part},
+    sema::{self, FormatPiece, Intrinsic, IoFunction, TraitMethod},
 };
 
+/// Generated C source and the runtime parts it has to be linked with
+pub struct CProgram {
+    pub code: String,
+    pub runtime_parts: Vec<Part>,
+}
+
 pub struct CodeGen {
     ic: Vec<ICInstruction>,
     code: String,
     pos: usize,
     needs_stdlib: bool,
+    needs_stdio: bool,
+    runtime_parts: Vec<Part>,
+    in_main: bool,
+    /// The types of the registers of the function being generated
+    regs: Vec<Type>,
+    /// Source file named in panic locations
+    file: String,
+    /// Debug builds keep a stack of Nimra frames for panic backtraces and
+    /// panic on integer overflow, where release builds wrap around
+    debug: bool,
+    /// The `Option` and `Result` types whose values are unwrapped
+    unwrapped: Vec<Type>,
 }
 
 impl CodeGen {
     // Creates a new code generator using a vector of ICInstructions
-    pub fn new(ic: Vec<ICInstruction>) -> Self {
-        let needs_stdlib = ic.iter().any(contains_exit_call_ic);
+    pub fn new(ic: Vec<ICInstruction>, options: &Options) -> Self {
+        let (mut needs_stdlib, mut needs_stdio) = (false, false);
+        let debug = !options.release;
+        let mut runtime_parts = Vec::new();
+        if debug {
+            runtime_parts.push(Part::Core);
+        }
+        let mut unwrapped = Vec::new();
+        for instruction in &ic {
+            let function = match instruction {
+                ICInstruction::StructDecl { fields, .. } => {
+                    if fields.iter().any(|(_, ty)| uses_runtime_type(ty)) {
+                        runtime_parts.push(Part::Core);
+                    }
+                    continue;
+                }
+                ICInstruction::ArrayType { element, .. } => {
+                    if uses_runtime_type(element) {
+                        runtime_parts.push(Part::Core);
+                    }
+                    continue;
+                }
+                ICInstruction::TraitDecl { methods, .. } => {
+                    let mut types = methods
+                        .iter()
+                        .flat_map(|method| method.params.iter().chain([&method.return_type]));
+                    if types.any(uses_runtime_type) {
+                        runtime_parts.push(Part::Core);
+                    }
+                    continue;
+                }
+                ICInstruction::FnType {
+                    params,
+                    return_type,
+                } => {
+                    if params.iter().chain([return_type]).any(uses_runtime_type) {
+                        runtime_parts.push(Part::Core);
+                    }
+                    continue;
+                }
+                // Values are copied to the heap when they become a `dyn Trait`,
+                // and boxes and strings are allocated and freed by the runtime
+                ICInstruction::OwnedType(Type::String) => {
+                    runtime_parts.push(Part::String);
+                    continue;
+                }
+                ICInstruction::Vtable { .. } | ICInstruction::OwnedType(_) => {
+                    runtime_parts.push(Part::Alloc);
+                    continue;
+                }
+                ICInstruction::Function(function) => function,
+            };
+            // A main that returns an error prints it
+            if let (Type::Result(_, error), "main") =
+                (&function.return_type, function.name.as_str())
+            {
+                needs_stdio = true;
+                if **error == Type::Char {
+                    runtime_parts.push(Part::Utf8);
+                }
+            }
+            // Constants are typed too, and a string one is a runtime `nrt_str`
+            let constants = function.blocks.iter().flat_map(|block| {
+                block
+                    .instructions
+                    .iter()
+                    .flat_map(Instruction::operands)
+                    .chain(block.terminator.operands())
+                    .filter_map(|operand| match operand {
+                        Operand::Const(_, ty) => Some(ty),
+                        Operand::Reg(_) => None,
+                    })
+            });
+            let mut types = function.regs.iter().map(|reg| &reg.ty).chain(constants);
+            if types.any(uses_runtime_type) || uses_runtime_type(&function.return_type) {
+                runtime_parts.push(Part::Core);
+            }
+            let instructions = function.blocks.iter().flat_map(|block| &block.instructions);
+            for instruction in instructions {
+                match instruction {
+                    Instruction::Intrinsic {
+                        intrinsic: Intrinsic::Exit,
+                        ..
+                    } => needs_stdlib = true,
+                    Instruction::Intrinsic {
+                        intrinsic:
+                            Intrinsic::BoxNew
+                            | Intrinsic::BufferNew
+                            | Intrinsic::BufferLen
+                            | Intrinsic::BufferPush
+                            | Intrinsic::BufferPop,
+                        ..
+                    } => runtime_parts.push(Part::Alloc),
+                    // A string is hashed as the text it holds
+                    Instruction::Intrinsic {
+                        intrinsic: Intrinsic::Hash,
+                        args,
+                        ..
+                    } => runtime_parts.push(if function.operand_type(&args[0]) == Type::String {
+                        Part::String
+                    } else {
+                        Part::Core
+                    }),
+                    Instruction::Intrinsic {
+                        intrinsic: Intrinsic::Unwrap | Intrinsic::UnwrapOr,
+                        args,
+                        ..
+                    } => {
+                        let ty = function.operand_type(&args[0]);
+                        if !unwrapped.contains(&ty) {
+                            unwrapped.push(ty);
+                        }
+                        runtime_parts.push(Part::Core);
+                    }
+                    Instruction::Intrinsic {
+                        intrinsic:
+                            Intrinsic::Panic
+                            | Intrinsic::Wrapping(_)
+                            | Intrinsic::Overflows(_)
+                            | Intrinsic::Saturating(_),
+                        ..
+                    } => runtime_parts.push(Part::Core),
+                    Instruction::Intrinsic { .. } => runtime_parts.push(Part::String),
+                    Instruction::Binary { dest, op, .. }
+                        if is_checked(*op, &function.regs[*dest].ty) =>
+                    {
+                        runtime_parts.push(Part::Core);
+                    }
+                    Instruction::Unary {
+                        op: UnaryOp::Neg, ..
+                    }
+                    | Instruction::Index { .. }
+                    | Instruction::IndexAddress { .. } => runtime_parts.push(Part::Core),
+                    Instruction::Print { args, .. } => {
+                        needs_stdio = true;
+                        if args
+                            .iter()
+                            .any(|arg| function.operand_type(arg) == Type::Char)
+                        {
+                            runtime_parts.push(Part::Utf8);
+                        }
+                    }
+                    // Captured variables are kept in an environment on the heap
+                    Instruction::Alloc { .. } => runtime_parts.push(Part::Alloc),
+                    Instruction::NextChar { .. } => runtime_parts.push(Part::Utf8),
+                    _ => {}
+                }
+            }
+        }
         CodeGen {
             ic,
             code: String::new(),
             pos: 0,
             needs_stdlib,
+            needs_stdio,
+            runtime_parts: runtime::resolve(&runtime_parts),
+            in_main: false,
+            regs: Vec::new(),
+            file: options.input.clone(),
+            debug,
+            unwrapped,
         }
     }
 
-    pub fn generate(&mut self) -> Result<String, String> {
+    pub fn generate(&mut self) -> Result<CProgram, String> {
+        self.code
+            .push_str("#include <stdbool.h>\n#include <stdint.h>\n");
         if self.needs_stdlib {
             self.code.push_str("#include <stdlib.h>\n");
         }
-        while self.pos < self.ic.len() {
+        if self.needs_stdio {
             self.code
-                .push_str(&self.generate_instruction(&self.ic[self.pos])?);
-            self.pos += 1;
+                .push_str("#include <inttypes.h>\n#include <stdio.h>\n");
         }
-        Ok(self.code.clone())
-    }
-
-    fn generate_instruction(&self, ic: &ICInstruction) -> Result<String, String> {
-        match ic {
-            ICInstruction::Literal(lit) => Ok(format!("{};", self.literal_to_c(lit))),
-            ICInstruction::Import { .. } => Ok(String::new()),
-            ICInstruction::FnCall { function, args } => {
-                if function == "return" {
-                    let arg_str = if let Some(arg) = args.first() {
-                        self.ast_node_expr(arg)
-                    } else {
-                        String::new()
-                    };
-                    Ok(format!("return {arg_str};"))
-                } else if function == "exit" {
-                    let arg_list = args
-                        .iter()
-                        .map(|arg| self.ast_node_expr(arg))
-                        .collect::<Vec<_>>()
-                        .join(", ");
-                    Ok(format!("exit({arg_list});"))
-                } else {
-                    let arg_list = args
+        if !self.runtime_parts.is_empty() {
+            self.code.push_str("#include \"nimra.h\"\n");
+        }
+        // Structs are declared up front so vtables can mention them before
+        // they are defined
+        for ic in &self.ic {
+            match ic {
+                ICInstruction::StructDecl { name, .. } => self
+                    .code
+                    .push_str(&format!("typedef struct s_{name} s_{name};\n")),
+                ICInstruction::ArrayType { element, len } => {
+                    let id = array_id(element, *len);
+                    self.code
+                        .push_str(&format!("typedef struct a_{id} a_{id};\n"));
+                }
+                ICInstruction::FnType {
+                    params,
+                    return_type,
+                } => {
+                    let id = fn_type_id(params, return_type);
+                    self.code
+                        .push_str(&format!("typedef struct fp_{id} fp_{id};\n"));
+                }
+                _ => {}
+            }
+        }
+        for ic in &self.ic {
+            if let ICInstruction::TraitDecl { name, methods } = ic {
+                let id = trait_id(name);
+                let mut entries = String::new();
+                for method in methods {
+                    let params = method
+                        .params
                         .iter()
-                        .map(|arg| self.ast_node_expr(arg))
-                        .collect::<Vec<_>>()
-                        .join(", ");
-                    Ok(format!("{function}({arg_list});"))
+                        .map(|ty| format!(", {}", self.type_to_c(ty)))
+                        .collect::<String>();
+                    entries.push_str(&format!(
+                        "{} (*{})(void *{params});",
+                        self.type_to_c(&method.return_type),
+                        method.name
+                    ));
                 }
+                if entries.is_empty() {
+                    entries.push_str("char unused;");
+                }
+                self.code.push_str(&format!(
+                    "typedef struct {{{entries}}} vt_{id};\ntypedef struct {{void *data; const vt_{id} *vtable;}} d_{id};\n"
+                ));
             }
-            ICInstruction::FnDecl {
-                name,
-                args,
-                body,
+        }
+        // A function value is a plain function pointer, or a closure that
+        // takes its environment before its arguments; only one of them is set
+        for ic in &self.ic {
+            if let ICInstruction::FnType {
+                params,
                 return_type,
-            } => {
-                let is_main = name == "main";
-                let ret_type = if is_main {
-                    "int"
-                } else {
-                    self.type_to_c(return_type)
-                };
-                let arg_list = if args.is_empty() {
+            } = ic
+            {
+                let id = fn_type_id(params, return_type);
+                let params = params
+                    .iter()
+                    .map(|ty| self.type_to_c(ty))
+                    .collect::<Vec<_>>();
+                let return_type = self.type_to_c(return_type);
+                let plain = if params.is_empty() {
                     "void".to_string()
                 } else {
-                    args.iter()
-                        .map(|_| "void".to_string())
-                        .collect::<Vec<_>>()
-                        .join(", ")
+                    params.join(", ")
                 };
-                let mut body_code = String::new();
-                for stmt in body {
-                    body_code.push_str(&self.ast_node_to_c(stmt)?);
+                let with_env = std::iter::once("void *".to_string())
+                    .chain(params)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                self.code.push_str(&format!(
+                    "struct fp_{id} {{{return_type} (*plain)({plain}); {return_type} (*with_env)({with_env}); void *env;}};\n"
+                ));
+            }
+        }
+        // Arrays are wrapped in structs so they can be passed and returned by value
+        for ic in &self.ic {
+            match ic {
+                ICInstruction::StructDecl { name, fields } => {
+                    let mut members = fields
+                        .iter()
+                        .map(|(field, ty)| format!("{} f_{field};", self.type_to_c(ty)))
+                        .collect::<String>();
+                    if members.is_empty() {
+                        // C structs cannot be empty
+                        members.push_str("char unused;");
+                    }
+                    self.code
+                        .push_str(&format!("struct s_{name} {{{members}}};\n"));
                 }
-                if is_main && !has_explicit_return_or_exit(body) {
-                    body_code.push_str("return 0;");
+                ICInstruction::ArrayType { element, len } => {
+                    let id = array_id(element, *len);
+                    let element = self.type_to_c(element);
+                    self.code.push_str(&format!(
+                        "struct a_{id} {{{element} items[{len}];}};\n\
+                         static inline a_{id} ar_{id}({element} value) {{a_{id} array; for (uint64_t i = 0; i < {len}; i++) {{array.items[i] = value;}} return array;}}\n"
+                    ));
                 }
-                Ok(format!("{ret_type} {name}({arg_list}) {{{body_code}}}"))
+                _ => {}
             }
         }
+        // Dropping a value drops what it owns first, which may be of a type
+        // that contains it, so every drop function is declared up front
+        for ic in &self.ic {
+            if let ICInstruction::OwnedType(ty) = ic {
+                self.code.push_str(&format!(
+                    "[[maybe_unused]] static void dr_{}({} *place);\n",
+                    generator::mangle(ty),
+                    self.type_to_c(ty)
+                ));
+            }
+        }
+        for ic in &self.ic {
+            if let ICInstruction::OwnedType(ty) = ic {
+                let code = self.owned_type_to_c(ty);
+                self.code.push_str(&code);
+            }
+        }
+        for ty in &self.unwrapped {
+            let code = self.unwrap_to_c(ty);
+            self.code.push_str(&code);
+        }
+        for ic in &self.ic {
+            if let ICInstruction::Function(function) = ic {
+                if function.name != "main" || function.return_type != Type::Void {
+                    self.regs = function.regs.iter().map(|reg| reg.ty.clone()).collect();
+                    let signature = self.signature_to_c(function);
+                    self.code
+                        .push_str(&format!("[[maybe_unused]] static {signature};\n"));
+                }
+            }
+        }
+        for ic in &self.ic {
+            match ic {
+                ICInstruction::TraitDecl { name, methods } => {
+                    let code = self.dispatch_to_c(name, methods);
+                    self.code.push_str(&code);
+                }
+                ICInstruction::FnType {
+                    params,
+                    return_type,
+                } => {
+                    let code = self.fn_call_to_c(params, return_type);
+                    self.code.push_str(&code);
+                }
+                ICInstruction::Vtable {
+                    trait_name,
+                    ty,
+                    methods,
+                } => {
+                    let code = self.vtable_to_c(trait_name, ty, methods);
+                    self.code.push_str(&code);
+                }
+                _ => {}
+            }
+        }
+        while self.pos < self.ic.len() {
+            let ic = self.ic[self.pos].clone();
+            let code = self.generate_instruction(&ic)?;
+            self.code.push_str(&code);
+            self.pos += 1;
+        }
+        Ok(CProgram {
+            code: self.code.clone(),
+            runtime_parts: self.runtime_parts.clone(),
+        })
+    }
+
+    /// Emits a function per trait method that calls through a `dyn Trait`'s vtable
+    fn dispatch_to_c(&self, name: &str, methods: &[TraitMethod]) -> String {
+        let id = trait_id(name);
+        let mut code = String::new();
+        for method in methods {
+            let (params, args) = self.forwarded_params(&method.params);
+            let call = format!("self.vtable->{}(self.data{args})", method.name);
+            code.push_str(&format!(
+                "static inline {} dy_{id}{}{}(d_{id} self{params}) {{{}}}\n",
+                self.type_to_c(&method.return_type),
+                method.name.len(),
+                method.name,
+                return_to_c(&method.return_type, &call)
+            ));
+        }
+        code
+    }
+
+    /// Emits the function that calls a value of a function type
+    fn fn_call_to_c(&self, params: &[Type], return_type: &Type) -> String {
+        let id = fn_type_id(params, return_type);
+        let (declared, passed) = self.forwarded_params(params);
+        let with_env = return_to_c(return_type, &format!("f.with_env(f.env{passed})"));
+        let plain = return_to_c(
+            return_type,
+            &format!("f.plain({})", passed.trim_start_matches(", ")),
+        );
+        format!(
+            "static inline {} fc_{id}(fp_{id} f{declared}) {{if (f.with_env) {{{with_env}}} else {{{plain}}}}}\n",
+            self.type_to_c(return_type)
+        )
+    }
+
+    /// Emits the vtable for `ty` as a `dyn Trait`, the functions in it, and
+    /// the function that moves a value to the heap to make a `dyn Trait` of it
+    fn vtable_to_c(&self, trait_name: &str, ty: &Type, methods: &[TraitMethod]) -> String {
+        let trait_id = trait_id(trait_name);
+        let id = format!("{trait_id}{}", generator::mangle(ty));
+        let c_type = self.type_to_c(ty);
+        let mut code = String::new();
+        let mut entries = Vec::new();
+        for method in methods {
+            let (params, args) = self.forwarded_params(&method.params);
+            let function = generator::mangle_method(trait_name, ty, &method.name);
+            let call = format!("fn_{function}(*({c_type} *)self{args})");
+            code.push_str(&format!(
+                "static {} th_{function}(void *self{params}) {{{}}}\n",
+                self.type_to_c(&method.return_type),
+                return_to_c(&method.return_type, &call)
+            ));
+            entries.push(format!(".{} = th_{function}", method.name));
+        }
+        if entries.is_empty() {
+            entries.push("0".to_string());
+        }
+        code.push_str(&format!(
+            "static const vt_{trait_id} vi_{id} = {{{}}};\n",
+            entries.join(", ")
+        ));
+        code.push_str(&format!(
+            "[[maybe_unused]] static d_{trait_id} to_{id}({c_type} value) {{{c_type} *data = nrt_alloc(sizeof value); *data = value; return (d_{trait_id}){{data, &vi_{id}}};}}\n"
+        ));
+        code
+    }
+
+    /// Emits the function that drops a value of an owned type, freeing what it
+    /// owns, and the one that moves a value out of a place, leaving it empty.
+    /// Dropping an empty value does nothing. A box type also gets a function
+    /// that moves a value to the heap, and a buffer type functions that push,
+    /// pop and find an element.
+    fn owned_type_to_c(&self, ty: &Type) -> String {
+        let id = generator::mangle(ty);
+        let c_type = self.type_to_c(ty);
+        let drop = match ty {
+            Type::Box(inner) => {
+                let inner_drop = if self.is_owned(inner) {
+                    format!("if (*place) {{dr_{}(*place);}} ", generator::mangle(inner))
+                } else {
+                    String::new()
+                };
+                format!("{inner_drop}nrt_box_free(*place);")
+            }
+            Type::String => "nrt_string_free(place);".to_string(),
+            // Buffers only hold values that own nothing
+            Type::Buffer(_) => "nrt_buffer_free(*place);".to_string(),
+            Type::Array(element, _) => format!(
+                "for (uint64_t i = 0; i < sizeof place->items / sizeof *place->items; i++) {{dr_{}(&place->items[i]);}}",
+                generator::mangle(element)
+            ),
+            _ => self
+                .ic
+                .iter()
+                .find_map(|ic| match ic {
+                    ICInstruction::StructDecl { name, fields } if *name == id => Some(fields),
+                    _ => None,
+                })
+                .into_iter()
+                .flatten()
+                .filter(|(_, field_type)| self.is_owned(field_type))
+                .map(|(field, field_type)| {
+                    format!("dr_{}(&place->f_{field});", generator::mangle(field_type))
+                })
+                .collect(),
+        };
+        let mut code = format!(
+            "static void dr_{id}({c_type} *place) {{{drop}}}\n\
+             [[maybe_unused]] static inline {c_type} mv_{id}({c_type} *place) {{{c_type} value = *place; *place = ({c_type}){{0}}; return value;}}\n"
+        );
+        if let Type::Box(inner) = ty {
+            let inner = self.type_to_c(inner);
+            code.push_str(&format!(
+                "[[maybe_unused]] static inline {c_type} bx_{id}({inner} value) {{{c_type} box = nrt_box_new(sizeof *box); *box = value; return box;}}\n"
+            ));
+        }
+        if let Type::Buffer(element) = ty {
+            let element = self.type_to_c(element);
+            code.push_str(&format!(
+                "[[maybe_unused]] static void pu_{id}({c_type} *place, {element} value) {{*place = nrt_buffer_push(*place, sizeof value); (*place)[nrt_buffer_len(*place) - 1] = value;}}\n\
+                 [[maybe_unused]] static {element} po_{id}({c_type} *place, nrt_location at) {{return (*place)[nrt_buffer_pop(*place, at)];}}\n\
+                 [[maybe_unused]] static inline {c_type} at_{id}({c_type} items, uint64_t index, nrt_location at) {{return &items[nrt_check_index(index, nrt_buffer_len(items), at)];}}\n"
+            ));
+        }
+        code
+    }
+
+    /// Emits the functions that take the value out of an `Option` or `Result`:
+    /// `uw_` panics if there is none, and `uo_` returns a default instead,
+    /// dropping whichever of the value, the error and the default it does not return
+    fn unwrap_to_c(&self, ty: &Type) -> String {
+        let (value, error, message) = match ty {
+            Type::Option(value) => (value, None, "called unwrap on None"),
+            Type::Result(value, error) => (value, Some(error), "called unwrap on an Err"),
+            _ => return String::new(),
+        };
+        let id = generator::mangle(ty);
+        let c_type = self.type_to_c(ty);
+        let value_type = self.type_to_c(value);
+        let tag = format!("value.f_{}", sema::tag_field(ty));
+        let message = self.literal_to_c(&Literal::String(message.to_string()), &Type::Str);
+        let taken = if **value == Type::Void {
+            String::new()
+        } else {
+            "return value.f_value;".to_string()
+        };
+        let mut code = format!(
+            "[[maybe_unused]] static {value_type} uw_{id}({c_type} value, nrt_location at) {{if (!{tag}) {{nrt_panic(at, {message});}} {taken}}}
+"
+        );
+        if **value != Type::Void {
+            let drop = |place: &str, ty: &Type| {
+                if self.is_owned(ty) {
+                    format!("dr_{}(&{place}); ", generator::mangle(ty))
+                } else {
+                    String::new()
+                }
+            };
+            let drop_default = drop("fallback", value);
+            let drop_error = error.map_or(String::new(), |error| drop("value.f_error", error));
+            code.push_str(&format!(
+                "[[maybe_unused]] static {value_type} uo_{id}({c_type} value, {value_type} fallback) {{if ({tag}) {{{drop_default}return value.f_value;}} {drop_error}return fallback;}}
+"
+            ));
+        }
+        code
+    }
+
+    fn is_owned(&self, ty: &Type) -> bool {
+        self.ic
+            .iter()
+            .any(|ic| matches!(ic, ICInstruction::OwnedType(owned) if owned == ty))
+    }
+
+    // Declares `a_N` parameters of the given types, and the argument list passing them on
+    fn forwarded_params(&self, params: &[Type]) -> (String, String) {
+        let declared = params
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| format!(", {} a_{i}", self.type_to_c(ty)))
+            .collect();
+        let passed = (0..params.len()).map(|i| format!(", a_{i}")).collect();
+        (declared, passed)
     }
 
-    fn ast_node_to_c(&self, node: &ASTNode) -> Result<String, String> {
-        match node {
-            ASTNode::Literal(lit) => Ok(format!("{};", self.literal_to_c(lit))),
-            ASTNode::FnCall { function, args } => {
-                if function == "return" {
-                    let arg_str = if let Some(arg) = args.first() {
-                        self.ast_node_expr(arg)
+    fn generate_instruction(&mut self, ic: &ICInstruction) -> Result<String, String> {
+        match ic {
+            ICInstruction::StructDecl { .. }
+            | ICInstruction::ArrayType { .. }
+            | ICInstruction::TraitDecl { .. }
+            | ICInstruction::FnType { .. }
+            | ICInstruction::Vtable { .. }
+            | ICInstruction::OwnedType(_) => Ok(String::new()),
+            ICInstruction::Function(function) => {
+                self.in_main = function.name == "main" && function.return_type == Type::Void;
+                self.regs = function.regs.iter().map(|reg| reg.ty.clone()).collect();
+                let body_code = self.body_to_c(function)?;
+                if self.in_main {
+                    return Ok(format!("int main(void) {{{body_code}}}\n"));
+                }
+                let signature = self.signature_to_c(function);
+                let mut code = format!("static {signature} {{{body_code}}}\n");
+                // A main that returns a Result is called by the C main, which
+                // prints the error and fails if there is one
+                if let (Type::Result(_, error), "main") =
+                    (&function.return_type, function.name.as_str())
+                {
+                    let report = self.print_to_c(
+                        IoFunction::Eprintln,
+                        &[
+                            FormatPiece::Text("Error: ".to_string()),
+                            FormatPiece::Placeholder,
+                        ],
+                        &[("v_result.f_error".to_string(), (**error).clone())],
+                    )?;
+                    // The error is dropped after it is printed
+                    let drop = if self.is_owned(&function.return_type) {
+                        format!(
+                            "dr_{}(&v_result);",
+                            generator::mangle(&function.return_type)
+                        )
                     } else {
                         String::new()
                     };
-                    Ok(format!("return {arg_str};"))
-                } else if function == "exit" {
-                    if let Some(ASTNode::Literal(Literal::Number(n))) = args.first() {
-                        if !(*n >= 0 && *n <= 255) {
-                            return Err("Exit should be between 0 and 255".to_string());
-                        }
-                    }
-                    let arg_list = args
-                        .iter()
-                        .map(|arg| self.ast_node_expr(arg))
-                        .collect::<Vec<_>>()
-                        .join(", ");
-                    Ok(format!("exit({arg_list});"))
-                } else {
-                    let arg_list = args
-                        .iter()
-                        .map(|arg| self.ast_node_expr(arg))
-                        .collect::<Vec<_>>()
-                        .join(", ");
-                    Ok(format!("{function}({arg_list});"))
+                    code.push_str(&format!(
+                        "int main(void) {{{} v_result = fn_main(); if (v_result.f_ok) {{return 0;}} {report} {drop} return 1;}}\n",
+                        self.type_to_c(&function.return_type)
+                    ));
                 }
+                Ok(code)
             }
-            ASTNode::Import { .. } => Ok(String::new()),
-            ASTNode::FnDecl {
-                name,
-                args,
-                body,
-                return_type,
+        }
+    }
+
+    // The body of a function: pushing its frame, declaring its registers, and
+    // its blocks, each labelled but the first, which is where it starts
+    fn body_to_c(&mut self, function: &Function) -> Result<String, String> {
+        let mut code = String::new();
+        if self.debug {
+            // The cleanup attribute pops the frame however the function is left
+            code.push_str(&format!(
+                "[[gnu::cleanup(nrt_frame_leave)]] nrt_frame nrt_frame_local = {{{}, nrt_frame_top}}; nrt_frame_top = &nrt_frame_local;",
+                escape_c_string(&function.source_name)
+            ));
+        }
+        for (reg, ty) in self.regs.iter().enumerate() {
+            if function.params.contains(&reg) {
+                continue;
+            }
+            let value = if function.env == Some(reg) {
+                "env"
+            } else {
+                "{0}"
+            };
+            code.push_str(&format!(
+                "[[maybe_unused]] {} r_{reg} = {value};",
+                self.type_to_c(ty)
+            ));
+        }
+        for (id, block) in function.blocks.iter().enumerate() {
+            if id > 0 {
+                code.push_str(&format!("b_{id}: "));
+            }
+            for instruction in &block.instructions {
+                code.push_str(&self.instruction_to_c(instruction)?);
+            }
+            code.push_str(&self.terminator_to_c(&block.terminator));
+        }
+        // Every block ends in a jump, a return or a call that never returns,
+        // which gcc cannot always see for a function that loops forever
+        if function.return_type != Type::Void {
+            code.push_str("__builtin_unreachable();");
+        }
+        Ok(code)
+    }
+
+    /// A closure that captures variables takes its environment as a `void *`,
+    /// since every closure of a function type is called the same way
+    fn signature_to_c(&self, function: &Function) -> String {
+        let mut params: Vec<String> = function
+            .params
+            .iter()
+            .map(|reg| {
+                format!(
+                    "[[maybe_unused]] {} r_{reg}",
+                    self.type_to_c(&self.regs[*reg])
+                )
+            })
+            .collect();
+        if function.env.is_some() {
+            params.insert(0, "void *env".to_string());
+        }
+        if params.is_empty() {
+            params.push("void".to_string());
+        }
+        format!(
+            "{} fn_{}({})",
+            self.type_to_c(&function.return_type),
+            function.name,
+            params.join(", ")
+        )
+    }
+
+    fn terminator_to_c(&self, terminator: &Terminator) -> String {
+        match terminator {
+            Terminator::Jump(target) => format!("goto b_{target};"),
+            Terminator::Branch {
+                condition,
+                then_block,
+                else_block,
+            } => format!(
+                "if ({}) {{goto b_{then_block};}} goto b_{else_block};",
+                self.operand_to_c(condition)
+            ),
+            Terminator::Switch {
+                value,
+                cases,
+                default,
             } => {
-                let is_main = name == "main";
-                let ret_type = if is_main {
-                    "int"
-                } else {
-                    self.type_to_c(return_type)
+                let mut value_code = self.operand_to_c(value);
+                if self.operand_type(value) == Type::Bool {
+                    // GCC warns about switching on a bool
+                    value_code = format!("(int){value_code}");
+                }
+                let cases = cases
+                    .iter()
+                    .map(|(literal, target)| {
+                        format!(
+                            "case {}: goto b_{target}; ",
+                            self.literal_to_c(literal, &self.operand_type(value))
+                        )
+                    })
+                    .collect::<String>();
+                format!("switch ({value_code}) {{{cases}default: goto b_{default};}}")
+            }
+            Terminator::Return(None) if self.in_main => "return 0;".to_string(),
+            Terminator::Return(None) => "return;".to_string(),
+            Terminator::Return(Some(value)) => format!("return {};", self.operand_to_c(value)),
+            Terminator::Unreachable => "__builtin_unreachable();".to_string(),
+        }
+    }
+
+    fn instruction_to_c(&self, instruction: &Instruction) -> Result<String, String> {
+        let value = match instruction {
+            Instruction::Copy { value, .. } => self.operand_to_c(value),
+            Instruction::Zero { dest } => format!("({}){{0}}", self.type_to_c(&self.regs[*dest])),
+            Instruction::Binary {
+                dest,
+                op,
+                lhs,
+                rhs,
+                span,
+            } => self.binary_to_c(
+                *op,
+                &self.regs[*dest],
+                &self.operand_to_c(lhs),
+                &self.operand_to_c(rhs),
+                *span,
+            ),
+            Instruction::Unary {
+                dest,
+                op,
+                operand,
+                span,
+            } => {
+                let operand = self.operand_to_c(operand);
+                match op {
+                    UnaryOp::Neg if self.debug => format!(
+                        "nrt_neg_{}({operand}, {})",
+                        self.regs[*dest],
+                        self.location_to_c(*span)
+                    ),
+                    UnaryOp::Neg => format!("nrt_wrapping_neg_{}({operand})", self.regs[*dest]),
+                    UnaryOp::Not => format!("(!{operand})"),
+                }
+            }
+            Instruction::Cast { dest, value } => format!(
+                "(({})({}))",
+                self.type_to_c(&self.regs[*dest]),
+                self.operand_to_c(value)
+            ),
+            Instruction::Call { function, args, .. } => {
+                format!("fn_{function}({})", self.args_to_c(args))
+            }
+            Instruction::CallValue { callee, args, .. } => {
+                let Type::Fn(params, return_type) = self.operand_type(callee) else {
+                    return Err(format!(
+                        "Cannot call a value of type {}",
+                        self.operand_type(callee)
+                    ));
                 };
-                let arg_list = if args.is_empty() {
-                    "void".to_string()
-                } else {
-                    args.iter()
-                        .map(|_| "void".to_string())
-                        .collect::<Vec<_>>()
-                        .join(", ")
+                let mut arg_list = self.args_to_c(args);
+                if !arg_list.is_empty() {
+                    arg_list.insert_str(0, ", ");
+                }
+                format!(
+                    "fc_{}({}{arg_list})",
+                    fn_type_id(&params, &return_type),
+                    self.operand_to_c(callee)
+                )
+            }
+            Instruction::CallDyn {
+                trait_name,
+                method,
+                args,
+                ..
+            } => format!(
+                "dy_{}{}{method}({})",
+                trait_id(trait_name),
+                method.len(),
+                self.args_to_c(args)
+            ),
+            Instruction::Intrinsic {
+                intrinsic,
+                args,
+                span,
+                ..
+            } => self.intrinsic_to_c(*intrinsic, args, *span)?,
+            Instruction::Print {
+                function,
+                pieces,
+                args,
+            } => {
+                let args: Vec<(String, Type)> = args
+                    .iter()
+                    .map(|arg| (self.operand_to_c(arg), self.operand_type(arg)))
+                    .collect();
+                return self.print_to_c(*function, pieces, &args);
+            }
+            Instruction::ToDyn { dest, value } => {
+                let Type::Dyn(trait_name) = &self.regs[*dest] else {
+                    return Err(format!("Cannot convert a value to {}", self.regs[*dest]));
                 };
-                let mut body_code = String::new();
-                for stmt in body {
-                    body_code.push_str(&self.ast_node_to_c(stmt)?);
+                format!(
+                    "to_{}{}({})",
+                    trait_id(trait_name),
+                    generator::mangle(&self.operand_type(value)),
+                    self.operand_to_c(value)
+                )
+            }
+            Instruction::MakeFn {
+                dest,
+                function,
+                env: None,
+            } => format!(
+                "(({}){{.plain = fn_{function}}})",
+                self.type_to_c(&self.regs[*dest])
+            ),
+            Instruction::MakeFn {
+                dest,
+                function,
+                env: Some(env),
+            } => format!(
+                "(({}){{.with_env = fn_{function}, .env = {}}})",
+                self.type_to_c(&self.regs[*dest]),
+                self.operand_to_c(env)
+            ),
+            Instruction::Struct { dest, fields } => {
+                let mut initializers = fields
+                    .iter()
+                    .map(|(field, value)| format!(".f_{field} = {}", self.operand_to_c(value)))
+                    .collect::<Vec<_>>();
+                if initializers.is_empty() {
+                    initializers.push("0".to_string());
                 }
-                if is_main && !has_explicit_return_or_exit(body) {
-                    body_code.push_str("return 0;");
+                format!(
+                    "(({}){{{}}})",
+                    self.type_to_c(&self.regs[*dest]),
+                    initializers.join(", ")
+                )
+            }
+            Instruction::Array { dest, elements } => format!(
+                "(({}){{{{{}}}}})",
+                self.type_to_c(&self.regs[*dest]),
+                self.args_to_c(elements)
+            ),
+            Instruction::ArrayRepeat { dest, value } => format!(
+                "ar_{}({})",
+                generator::mangle(&self.regs[*dest]),
+                self.operand_to_c(value)
+            ),
+            Instruction::Field { value, field, .. } => {
+                format!("{}.f_{field}", self.operand_to_c(value))
+            }
+            Instruction::Index {
+                array, index, span, ..
+            } => match self.operand_type(array) {
+                ty @ Type::Buffer(_) => format!(
+                    "(*at_{}({}, {}, {}))",
+                    generator::mangle(&ty),
+                    self.operand_to_c(array),
+                    self.operand_to_c(index),
+                    self.location_to_c(*span)
+                ),
+                Type::Array(_, Length::Known(len)) => format!(
+                    "{}.items[nrt_check_index({}, {len}, {})]",
+                    self.operand_to_c(array),
+                    self.operand_to_c(index),
+                    self.location_to_c(*span)
+                ),
+                ty => return Err(format!("Cannot index into a value of type {ty}")),
+            },
+            Instruction::AddressOf { reg, .. } => format!("(&r_{reg})"),
+            Instruction::FieldAddress { base, field, .. } => {
+                format!("(&{}->f_{field})", self.operand_to_c(base))
+            }
+            Instruction::IndexAddress {
+                base, index, span, ..
+            } => match self.operand_type(base) {
+                ty @ Type::Buffer(_) => format!(
+                    "at_{}({}, {}, {})",
+                    generator::mangle(&ty),
+                    self.operand_to_c(base),
+                    self.operand_to_c(index),
+                    self.location_to_c(*span)
+                ),
+                Type::Ref(array, _) => {
+                    let Type::Array(_, Length::Known(len)) = *array else {
+                        return Err(format!("Cannot index into a value of type {array}"));
+                    };
+                    format!(
+                        "(&{}->items[nrt_check_index({}, {len}, {})])",
+                        self.operand_to_c(base),
+                        self.operand_to_c(index),
+                        self.location_to_c(*span)
+                    )
                 }
-                Ok(format!("{ret_type} {name}({arg_list}) {{{body_code}}}"))
+                ty => return Err(format!("Cannot index into a value of type {ty}")),
+            },
+            Instruction::Load { address, .. } => format!("(*{})", self.operand_to_c(address)),
+            Instruction::Store { address, value } => {
+                return Ok(format!(
+                    "*{} = {};",
+                    self.operand_to_c(address),
+                    self.operand_to_c(value)
+                ));
             }
-        }
+            Instruction::Alloc { dest } => format!("nrt_alloc(sizeof *r_{dest})"),
+            Instruction::Move { address, .. } => format!(
+                "mv_{}({})",
+                generator::mangle(&pointee(&self.operand_type(address))),
+                self.operand_to_c(address)
+            ),
+            Instruction::Drop { address } => {
+                return Ok(format!(
+                    "dr_{}({});",
+                    generator::mangle(&pointee(&self.operand_type(address))),
+                    self.operand_to_c(address)
+                ));
+            }
+            Instruction::NextChar { text, pos, .. } => format!(
+                "nrt_str_next_char({}, {})",
+                self.operand_to_c(text),
+                self.operand_to_c(pos)
+            ),
+            Instruction::Phi { .. } => {
+                return Err("Phis must be removed before code generation".to_string())
+            }
+        };
+        Ok(match instruction.dest() {
+            Some(dest) => format!("r_{dest} = {value};"),
+            None => format!("{value};"),
+        })
     }
 
-    /// Generates expressions only (no trailing semicolon)
-    fn ast_node_expr(&self, node: &ASTNode) -> String {
-        match node {
-            ASTNode::Literal(lit) => self.literal_to_c(lit),
-            _ => String::new(),
+    fn operand_to_c(&self, operand: &Operand) -> String {
+        match operand {
+            Operand::Reg(reg) => format!("r_{reg}"),
+            Operand::Const(literal, ty) => self.literal_to_c(literal, ty),
         }
     }
 
-    fn literal_to_c(&self, lit: &Literal) -> String {
-        match lit {
-            Literal::Number(n) => n.to_string(),
-            Literal::String(s) => format!("\"{s}\""),
+    fn operand_type(&self, operand: &Operand) -> Type {
+        match operand {
+            Operand::Reg(reg) => self.regs[*reg].clone(),
+            Operand::Const(_, ty) => ty.clone(),
         }
     }
 
-    fn type_to_c(&self, ty: &Type) -> &'static str {
-        match ty {
-            Type::Void => "void",
+    fn binary_to_c(&self, op: BinaryOp, ty: &Type, lhs: &str, rhs: &str, span: Span) -> String {
+        if is_checked(op, ty) {
+            // Division by zero panics even when overflow wraps around
+            if self.debug || matches!(op, BinaryOp::Div | BinaryOp::Rem) {
+                return format!(
+                    "nrt_{}_{ty}({lhs}, {rhs}, {})",
+                    op_name(op),
+                    self.location_to_c(span)
+                );
+            }
+            return format!("nrt_wrapping_{}_{ty}({lhs}, {rhs})", op_name(op));
+        }
+        let c_op = match op {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+        };
+        if ty.is_integer() {
+            // Arithmetic happens after integer promotion, so narrow the result back
+            format!("(({})({lhs} {c_op} {rhs}))", self.type_to_c(ty))
+        } else {
+            format!("({lhs} {c_op} {rhs})")
         }
     }
-}
 
-fn has_explicit_return_or_exit(stmts: &[ASTNode]) -> bool {
-    for stmt in stmts {
-        match stmt {
-            ASTNode::FnCall { function, .. } if function == "return" || function == "exit" => {
-                return true
+    fn args_to_c(&self, args: &[Operand]) -> String {
+        args.iter()
+            .map(|arg| self.operand_to_c(arg))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn intrinsic_to_c(
+        &self,
+        intrinsic: Intrinsic,
+        args: &[Operand],
+        span: Span,
+    ) -> Result<String, String> {
+        let arg_list = self.args_to_c(args);
+        let function = match intrinsic {
+            Intrinsic::Exit => "exit",
+            Intrinsic::Unwrap => {
+                return Ok(format!(
+                    "uw_{}({arg_list}, {})",
+                    generator::mangle(&self.operand_type(&args[0])),
+                    self.location_to_c(span)
+                ));
+            }
+            Intrinsic::UnwrapOr => {
+                return Ok(format!(
+                    "uo_{}({arg_list})",
+                    generator::mangle(&self.operand_type(&args[0]))
+                ));
+            }
+            Intrinsic::Panic => {
+                return Ok(format!(
+                    "nrt_panic({}, {arg_list})",
+                    self.location_to_c(span)
+                ));
+            }
+            Intrinsic::StringNew => "nrt_string_new",
+            Intrinsic::StringFrom => "nrt_string_from",
+            Intrinsic::StringAsStr => "nrt_string_as_str",
+            Intrinsic::StringPushStr => "nrt_string_push_str",
+            Intrinsic::StringPushChar => "nrt_string_push_char",
+            Intrinsic::StrLen => return Ok(format!("({arg_list}).len")),
+            Intrinsic::BoxNew => {
+                let id = generator::mangle(&Type::Box(Box::new(self.operand_type(&args[0]))));
+                return Ok(format!("bx_{id}({arg_list})"));
+            }
+            Intrinsic::BufferNew => return Ok("NULL".to_string()),
+            Intrinsic::BufferLen => "nrt_buffer_len",
+            Intrinsic::BufferPush => {
+                return Ok(format!(
+                    "pu_{}({arg_list})",
+                    generator::mangle(&pointee(&self.operand_type(&args[0])))
+                ));
+            }
+            Intrinsic::BufferPop => {
+                return Ok(format!(
+                    "po_{}({arg_list}, {})",
+                    generator::mangle(&pointee(&self.operand_type(&args[0]))),
+                    self.location_to_c(span)
+                ));
             }
-            ASTNode::FnDecl { body, .. } => {
-                if has_explicit_return_or_exit(body) {
-                    return true;
+            Intrinsic::Hash => {
+                return Ok(match self.operand_type(&args[0]) {
+                    Type::Str => format!("nrt_hash_str({arg_list})"),
+                    Type::String => format!("nrt_hash_str(nrt_string_as_str({arg_list}))"),
+                    _ => format!("nrt_hash_u64((uint64_t)({arg_list}))"),
+                });
+            }
+            Intrinsic::ArrayLen => {
+                return Err("The length of an array is known before code generation".to_string())
+            }
+            Intrinsic::Checked(_) => {
+                return Err("Checked arithmetic is lowered before code generation".to_string())
+            }
+            Intrinsic::StrSlice | Intrinsic::StrParseI64 => {
+                let function = if intrinsic == Intrinsic::StrSlice {
+                    "nrt_str_slice"
+                } else {
+                    "nrt_str_parse_i64"
+                };
+                return Ok(format!(
+                    "{function}({arg_list}, {})",
+                    self.location_to_c(span)
+                ));
+            }
+            Intrinsic::StrConcat => "nrt_str_concat",
+            Intrinsic::StrEqual => "nrt_str_equal",
+            Intrinsic::StrCompare => "nrt_str_compare",
+            Intrinsic::IntToString
+                if args
+                    .first()
+                    .is_some_and(|a| self.operand_type(a).is_signed()) =>
+            {
+                return Ok(format!("nrt_i64_to_string((int64_t)({arg_list}))"));
+            }
+            Intrinsic::IntToString => {
+                return Ok(format!("nrt_u64_to_string((uint64_t)({arg_list}))"));
+            }
+            Intrinsic::CharToString => "nrt_char_to_string",
+            Intrinsic::Overflows(op) => {
+                return Ok(format!(
+                    "nrt_overflows_{}_{}({arg_list})",
+                    op_name(op),
+                    self.operand_type(&args[0])
+                ));
+            }
+            Intrinsic::Wrapping(op) => {
+                return Ok(format!(
+                    "nrt_wrapping_{}_{}({arg_list})",
+                    op_name(op),
+                    self.operand_type(&args[0])
+                ));
+            }
+            Intrinsic::Saturating(op) => {
+                return Ok(format!(
+                    "nrt_saturating_{}_{}({arg_list})",
+                    op_name(op),
+                    self.operand_type(&args[0])
+                ));
+            }
+        };
+        Ok(format!("{function}({arg_list})"))
+    }
+
+    /// Lowers `print`, `println` and `eprintln` of the given C expressions to
+    /// one write per piece. Text and strings are written with `fwrite`,
+    /// which unlike a `%s` conversion does not stop at a NUL byte in them.
+    fn print_to_c(
+        &self,
+        function: IoFunction,
+        pieces: &[FormatPiece],
+        args: &[(String, Type)],
+    ) -> Result<String, String> {
+        let stream = if function == IoFunction::Eprintln {
+            "stderr"
+        } else {
+            "stdout"
+        };
+        let newline = if function == IoFunction::Print {
+            ""
+        } else {
+            "\n"
+        };
+        let mut writes = Vec::new();
+        // Text is written once it is known where it ends
+        let mut text = String::new();
+        let mut args = args.iter();
+        for piece in pieces {
+            let (arg, ty) = match piece {
+                FormatPiece::Text(piece) => {
+                    text.push_str(piece);
+                    continue;
                 }
+                FormatPiece::Placeholder => args
+                    .next()
+                    .ok_or(format!("{function:?} has too few arguments"))?,
+            };
+            if !text.is_empty() {
+                writes.push(write_text_to_c(&std::mem::take(&mut text), stream));
             }
-            _ => {}
+            writes.push(match ty {
+                Type::Bool => format!("fputs({arg} ? \"true\" : \"false\", {stream});"),
+                Type::Char => format!(
+                    "{{nrt_utf8 encoded = nrt_char_encode({arg}); fwrite(encoded.bytes, 1, encoded.len, {stream});}}"
+                ),
+                Type::Str | Type::String => {
+                    format!("fwrite({arg}.ptr, 1, (size_t){arg}.len, {stream});")
+                }
+                ty => {
+                    let conversion = match ty {
+                        Type::I8 => "PRId8",
+                        Type::I16 => "PRId16",
+                        Type::I32 => "PRId32",
+                        Type::I64 => "PRId64",
+                        Type::U8 => "PRIu8",
+                        Type::U16 => "PRIu16",
+                        Type::U32 => "PRIu32",
+                        Type::U64 => "PRIu64",
+                        ty => return Err(format!("Cannot print a value of type {ty}")),
+                    };
+                    format!("fprintf({stream}, \"%\" {conversion}, {arg});")
+                }
+            });
+        }
+        text.push_str(newline);
+        if !text.is_empty() {
+            writes.push(write_text_to_c(&text, stream));
+        }
+        Ok(writes.join(" "))
+    }
+
+    fn location_to_c(&self, span: Span) -> String {
+        // Code from a standard module written in Nimra has no place in the source
+        if span.line == 0 {
+            return "((nrt_location){NULL, 0, 0})".to_string();
         }
+        format!(
+            "((nrt_location){{{}, {}, {}}})",
+            escape_c_string(&self.file),
+            span.line,
+            span.column
+        )
+    }
+
+    fn literal_to_c(&self, lit: &Literal, ty: &Type) -> String {
+        match lit {
+            Literal::Number(n) if *n == i64::MIN => format!("(({})INT64_MIN)", self.type_to_c(ty)),
+            Literal::Number(n) => format!("(({}){n})", self.type_to_c(ty)),
+            Literal::String(s) => format!("((nrt_str){{{}, {}}})", escape_c_string(s), s.len()),
+            Literal::Bool(b) => b.to_string(),
+            Literal::Char(c) => format!("UINT32_C({})", u32::from(*c)),
+        }
+    }
+
+    fn type_to_c(&self, ty: &Type) -> String {
+        let name = match ty {
+            Type::Void => "void",
+            Type::Bool => "bool",
+            Type::Char => "uint32_t",
+            Type::I8 => "int8_t",
+            Type::I16 => "int16_t",
+            Type::I32 => "int32_t",
+            Type::I64 => "int64_t",
+            Type::U8 => "uint8_t",
+            Type::U16 => "uint16_t",
+            Type::U32 => "uint32_t",
+            Type::U64 => "uint64_t",
+            Type::Str => "nrt_str",
+            Type::String => "nrt_string",
+            Type::Struct(..) | Type::Option(_) | Type::Result(..) => {
+                return format!("s_{}", generator::mangle(ty))
+            }
+            Type::Dyn(name) => return format!("d_{}", trait_id(name)),
+            Type::Fn(params, return_type) => {
+                return format!("fp_{}", fn_type_id(params, return_type))
+            }
+            Type::Array(..) => return format!("a_{}", generator::mangle(ty)),
+            Type::Ref(inner, _) | Type::Box(inner) | Type::Buffer(inner) => {
+                return format!("{} *", self.type_to_c(inner))
+            }
+            Type::Param(name) => unreachable!("type parameter {} survived monomorphization", name),
+        };
+        name.to_string()
+    }
+}
+
+/// Names a function type in C identifiers
+fn fn_type_id(params: &[Type], return_type: &Type) -> String {
+    generator::mangle(&Type::Fn(params.to_vec(), Box::new(return_type.clone())))
+}
+
+/// Names an array type in C identifiers
+fn array_id(element: &Type, len: u64) -> String {
+    generator::mangle(&Type::Array(Box::new(element.clone()), Length::Known(len)))
+}
+
+/// Names a trait in C identifiers the way `generator::mangle` does
+fn trait_id(name: &str) -> String {
+    format!("{}{name}", name.len())
+}
+
+// A C `return` of `value`, which cannot be written as an expression in a void function
+fn return_to_c(ty: &Type, value: &str) -> String {
+    if *ty == Type::Void {
+        format!("{value};")
+    } else {
+        format!("return {value};")
     }
-    false
 }
 
-fn contains_exit_call_ic(ic: &ICInstruction) -> bool {
-    match ic {
-        ICInstruction::FnCall { function, .. } if function == "exit" => true,
-        ICInstruction::FnDecl { body, .. } => body.iter().any(contains_exit_call_ast),
-        _ => false,
+/// Whether an operation on integers can overflow or divide by zero, and so
+/// goes through one of the runtime's panicking helpers
+fn is_checked(op: BinaryOp, ty: &Type) -> bool {
+    ty.is_integer()
+        && matches!(
+            op,
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem
+        )
+}
+
+/// The name the runtime's arithmetic helpers use for `op`
+fn op_name(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "add",
+        BinaryOp::Sub => "sub",
+        BinaryOp::Mul => "mul",
+        BinaryOp::Div => "div",
+        _ => "rem",
     }
 }
-fn contains_exit_call_ast(ast: &ASTNode) -> bool {
-    match ast {
-        ASTNode::FnCall { function, .. } if function == "exit" => true,
-        ASTNode::FnDecl { body, .. } => body.iter().any(contains_exit_call_ast),
-        _ => false,
+
+/// The type a reference points to
+fn pointee(ty: &Type) -> Type {
+    match ty {
+        Type::Ref(inner, _) => (**inner).clone(),
+        ty => ty.clone(),
+    }
+}
+
+fn uses_runtime_type(ty: &Type) -> bool {
+    match ty {
+        Type::Array(element, _)
+        | Type::Ref(element, _)
+        | Type::Box(element)
+        | Type::Buffer(element)
+        | Type::Option(element) => uses_runtime_type(element),
+        Type::Result(value, error) => uses_runtime_type(value) || uses_runtime_type(error),
+        Type::Fn(params, return_type) => {
+            params.iter().chain([&**return_type]).any(uses_runtime_type)
+        }
+        ty => matches!(ty, Type::Str | Type::String),
+    }
+}
+
+/// Quotes a string as a C string literal, escaping anything that is not printable ASCII
+// Writes literal text, which may hold NUL bytes, to a stream
+fn write_text_to_c(text: &str, stream: &str) -> String {
+    format!(
+        "fwrite({}, 1, {}, {stream});",
+        escape_c_string(text),
+        text.len()
+    )
+}
+
+fn escape_c_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for byte in s.bytes() {
+        match byte {
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            b'?' => escaped.push_str("\\?"),
+            b'\n' => escaped.push_str("\\n"),
+            b'\t' => escaped.push_str("\\t"),
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\{byte:03o}")),
+        }
     }
+    escaped.push('"');
+    escaped
 }
 
-pub fn codegen(ic: Vec<ICInstruction>) -> Result<String, String> {
-    CodeGen::new(ic).generate()
+pub fn codegen(ic: Vec<ICInstruction>, options: &Options) -> Result<CProgram, Diagnostic> {
+    CodeGen::new(ic, options)
+        .generate()
+        .map_err(|e| Diagnostic::error(Stage::CodeGeneration, e))
 }
@@ -15,53 +15,85 @@
  * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
  */
 
-use std::io::Write;
-use std::process::{Command, Stdio};
+use std::fs;
+use std::process::Command;
 extern crate tempfile;
 use self::tempfile::Builder;
 use std::path::PathBuf;
 
-pub fn compile(c: &str) -> Result<PathBuf, String> {
-    // 1. Create a temp source file with a .c extension
-    let mut src = Builder::new()
-        .suffix(".c")
-        .tempfile()
-        .map_err(|e| format!("Failed to create temp source file: {e}"))?;
-    src.write_all(c.as_bytes())
-        .map_err(|e| format!("Failed to write to temp source file: {e}"))?;
-    let src_path = src.path().to_path_buf();
+use crate::codegen::CProgram;
+use crate::diagnostic::{codes, Diagnostic, Stage};
+use crate::options::Options;
+use crate::runtime;
 
-    // 2. Output file path
-    let out_path = "./a.out";
+pub fn compile(program: &CProgram, options: &Options) -> Result<PathBuf, Diagnostic> {
+    let error = |message: String| Diagnostic::error(Stage::Compilation, message);
+    // 1. Write the generated code, the runtime header and the runtime parts it uses
+    //    into a temp directory
+    let dir = Builder::new()
+        .prefix("nimra")
+        .tempdir()
+        .map_err(|e| error(format!("Failed to create temp directory: {e}")))?;
+    let src_path = dir.path().join("main.c");
+    fs::write(&src_path, &program.code)
+        .map_err(|e| error(format!("Failed to write to temp source file: {e}")))?;
+    fs::write(dir.path().join("nimra.h"), runtime::header())
+        .map_err(|e| error(format!("Failed to write runtime header: {e}")))?;
+    let mut sources = vec![src_path];
+    for part in &program.runtime_parts {
+        let path = dir.path().join(part.file_name());
+        fs::write(&path, part.source()).map_err(|e| {
+            error(format!(
+                "Failed to write runtime source {}: {e}",
+                part.file_name()
+            ))
+        })?;
+        sources.push(path);
+    }
 
-    // 3. GCC flags
+    // 2. GCC flags. gcc takes a function whose only way out is a panic for
+    // endless recursion, so that warning stays off.
     let flags = [
 		"-std=c2x", "-pedantic-errors", "-Wall", "-Wextra", "-Wconversion", "-Wshadow",
 		"-Wstrict-aliasing=3", "-Wcast-align", "-Wcast-qual", "-Wwrite-strings",
 		"-Wformat=2", "-Wswitch-enum", "-Wswitch-default", "-Wfloat-equal", "-Wundef",
 		"-Wredundant-decls", "-Wpointer-arith", "-Winit-self", "-Wmissing-declarations",
 		"-Wmissing-prototypes", "-Wstrict-prototypes", "-Wold-style-definition", "-Werror",
-		"-fno-common", "-O3", "-flto", "-march=native", "-funroll-loops",
-		"-fstack-protector-strong", "-fstack-clash-protection", "-D_FORTIFY_SOURCE=2",
+		"-Wno-infinite-recursion",
+		"-fno-common", "-flto", "-march=native", "-funroll-loops",
+		"-fstack-protector-strong", "-fstack-clash-protection",
 		"-fPIC",
 		"-fsanitize=undefined,address,leak,signed-integer-overflow,shift,alignment,bounds,object-size,float-divide-by-zero,float-cast-overflow",
 		"-fno-omit-frame-pointer", "-fvisibility=hidden",
 	];
 
-    // 4. Run gcc
-    let status = Command::new("gcc")
+    // gcc optimizes as much as the compiler did, so the output of each level
+    // shows what its passes are worth. Fortified library calls need gcc to
+    // optimize, and it warns without that.
+    let mut optimization = vec![format!("-O{}", options.opt_level)];
+    if options.opt_level > 0 {
+        optimization.push("-D_FORTIFY_SOURCE=2".to_string());
+    }
+
+    // 3. Run gcc, keeping what it reports for the diagnostic if it fails
+    let output = Command::new("gcc")
         .args(flags)
-        .arg(&src_path)
+        .args(&optimization)
+        .arg("-I")
+        .arg(dir.path())
+        .args(&sources)
         .arg("-o")
-        .arg(out_path)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .status()
-        .map_err(|e| format!("Failed to run gcc: {e}"))?;
+        .arg(&options.output)
+        .output()
+        .map_err(|e| error(format!("Failed to run gcc: {e}")))?;
 
-    if !status.success() {
-        return Err("gcc compilation failed".to_string());
+    if !output.status.success() {
+        let mut report = String::from_utf8_lossy(&output.stdout).into_owned();
+        report.push_str(&String::from_utf8_lossy(&output.stderr));
+        return Err(error("gcc compilation failed".to_string())
+            .with_code(codes::C_COMPILER_FAILED)
+            .with_note(format!("gcc reported:\n{}", report.trim_end())));
     }
 
-    Ok(PathBuf::from(out_path))
+    Ok(PathBuf::from(&options.output))
 }
@@ -0,0 +1,157 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+extern crate tempfile;
+use self::tempfile::Builder;
+
+use crate::diagnostic::{codes, Diagnostic, Stage};
+use crate::options::Options;
+use crate::runtime::{self, Part};
+
+pub fn compile(ll: &str, options: &Options) -> Result<PathBuf, Diagnostic> {
+    // 1. Write the generated module and the whole runtime into a temp directory
+    let dir = Builder::new()
+        .prefix("nimra")
+        .tempdir()
+        .map_err(|e| error(format!("Failed to create temp directory: {e}")))?;
+    let main = dir.path().join("main.ll");
+    fs::write(&main, ll).map_err(|e| error(format!("Failed to write to temp source file: {e}")))?;
+    fs::write(dir.path().join("nimra.h"), runtime::header())
+        .map_err(|e| error(format!("Failed to write runtime header: {e}")))?;
+    let mut sources = Vec::new();
+    for part in runtime::resolve(&[Part::String]) {
+        let path = dir.path().join(part.file_name());
+        fs::write(&path, part.source()).map_err(|e| {
+            error(format!(
+                "Failed to write runtime source {}: {e}",
+                part.file_name()
+            ))
+        })?;
+        sources.push(path);
+    }
+
+    // 2. Compile the module to an object, with clang if there is one and
+    //    with opt and llc otherwise
+    let level = format!("-O{}", options.opt_level);
+    let object = dir.path().join("main.o");
+    let linker = if let Some(llvm) = version("clang") {
+        let mut clang = Command::new("clang");
+        clang.args(opaque_pointers(llvm, &["-Xclang"]));
+        run(
+            clang
+                .arg(&level)
+                .arg("-c")
+                .arg(&main)
+                .arg("-o")
+                .arg(&object),
+            "clang",
+        )?;
+        "clang"
+    } else if let Some(llvm) = version("llc") {
+        let mut input = main;
+        if options.opt_level > 0 && version("opt").is_some() {
+            let optimized = dir.path().join("main.opt.ll");
+            run(
+                Command::new("opt")
+                    .args(opaque_pointers(llvm, &[]))
+                    .arg(&level)
+                    .arg("-S")
+                    .arg(&input)
+                    .arg("-o")
+                    .arg(&optimized),
+                "opt",
+            )?;
+            input = optimized;
+        }
+        // gcc links position-independent executables by default
+        run(
+            Command::new("llc")
+                .args(opaque_pointers(llvm, &[]))
+                .arg(&level)
+                .args(["-relocation-model=pic", "-filetype=obj"])
+                .arg(&input)
+                .arg("-o")
+                .arg(&object),
+            "llc",
+        )?;
+        "gcc"
+    } else {
+        return Err(error(
+            "The LLVM backend needs clang or llc to be installed".to_string(),
+        ));
+    };
+
+    // 3. Link the object with the runtime
+    run(
+        Command::new(linker)
+            .args(["-std=c2x", &level, "-I"])
+            .arg(dir.path())
+            .arg(&object)
+            .args(&sources)
+            .arg("-o")
+            .arg(&options.output),
+        linker,
+    )?;
+
+    Ok(Path::new(&options.output).to_path_buf())
+}
+
+// The major LLVM version of a tool, if it is installed
+fn version(tool: &str) -> Option<u32> {
+    let output = Command::new(tool).arg("--version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8_lossy(&output.stdout);
+    let (_, rest) = text.split_once("version ")?;
+    rest.split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()
+}
+
+// Generated code only uses opaque pointers, which LLVM 14 reads behind a flag
+// and later versions by default
+fn opaque_pointers(version: u32, prefix: &[&'static str]) -> Vec<&'static str> {
+    if version >= 15 {
+        return Vec::new();
+    }
+    let mut flags = prefix.to_vec();
+    flags.push("-opaque-pointers");
+    flags
+}
+
+fn error(message: String) -> Diagnostic {
+    Diagnostic::error(Stage::Compilation, message)
+}
+
+// Runs a tool, keeping what it reports for the diagnostic if it fails
+fn run(command: &mut Command, name: &str) -> Result<(), Diagnostic> {
+    let output = command
+        .output()
+        .map_err(|e| error(format!("Failed to run {name}: {e}")))?;
+    if !output.status.success() {
+        let mut report = String::from_utf8_lossy(&output.stdout).into_owned();
+        report.push_str(&String::from_utf8_lossy(&output.stderr));
+        return Err(error(format!("{name} failed"))
+            .with_code(codes::LLVM_FAILED)
+            .with_note(format!("{name} reported:\n{}", report.trim_end())));
+    }
+    Ok(())
+}
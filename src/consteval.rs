@@ -0,0 +1,507 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::diagnostic::{codes, Diagnostic, Stage};
+use crate::generator::ICInstruction;
+use crate::interp::{self, Overflow};
+use crate::ir::{Function, Instruction, Operand, Reg, Register};
+use crate::lexer::{Length, Literal, Span, Type};
+use crate::options::Options;
+use crate::parser::{BinaryOp, UnaryOp};
+use crate::sema::{Intrinsic, TypedExpr, TypedExprKind};
+
+/// The value of a constant expression. Integers of every type fit in an `i128`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i128),
+    Bool(bool),
+    Char(char),
+    Str(String),
+}
+
+impl Value {
+    fn from_literal(literal: &Literal, ty: &Type) -> Value {
+        match literal {
+            // `u64` values above `i64::MAX` are stored wrapped around
+            Literal::Number(n) if *n < 0 && !ty.is_signed() => Value::Int(i128::from(*n as u64)),
+            Literal::Number(n) => Value::Int(i128::from(*n)),
+            Literal::Bool(b) => Value::Bool(*b),
+            Literal::Char(c) => Value::Char(*c),
+            Literal::String(s) => Value::Str(s.clone()),
+        }
+    }
+
+    /// The literal that stands for this value in a program
+    pub fn to_literal(&self) -> Literal {
+        match self {
+            Value::Int(n) => Literal::Number(*n as i64),
+            Value::Bool(b) => Literal::Bool(*b),
+            Value::Char(c) => Literal::Char(*c),
+            Value::Str(s) => Literal::String(s.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{n}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Char(c) => write!(f, "{c:?}"),
+            Value::Str(s) => write!(f, "{s:?}"),
+        }
+    }
+}
+
+/// Evaluates an expression at compile time. Fails if the expression is not
+/// constant, or if evaluating it overflows or divides by zero.
+pub fn eval(expr: &TypedExpr) -> Result<Value, String> {
+    match &expr.kind {
+        TypedExprKind::Literal(literal) => Ok(Value::from_literal(literal, &expr.ty)),
+        TypedExprKind::Unary { op, operand, .. } => match (op, eval(operand)?) {
+            (UnaryOp::Neg, Value::Int(n)) => fit(-n, &expr.ty)
+                .ok_or_else(|| format!("Attempt to negate {n} with overflow in {}", expr.ty)),
+            (UnaryOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+            (op, value) => Err(format!("Cannot apply {op:?} to {value}")),
+        },
+        TypedExprKind::Binary { op, lhs, rhs, .. } => {
+            let lhs = eval(lhs)?;
+            // `&&` and `||` only evaluate their right side when they need it
+            match (op, &lhs) {
+                (BinaryOp::And, Value::Bool(false)) => return Ok(Value::Bool(false)),
+                (BinaryOp::Or, Value::Bool(true)) => return Ok(Value::Bool(true)),
+                _ => {}
+            }
+            binary(*op, lhs, eval(rhs)?, &expr.ty)
+        }
+        TypedExprKind::Cast(value) => cast(eval(value)?, &expr.ty),
+        TypedExprKind::Intrinsic {
+            intrinsic: intrinsic @ (Intrinsic::Wrapping(op) | Intrinsic::Saturating(op)),
+            args,
+            ..
+        } => {
+            let (Value::Int(a), Value::Int(b)) = (eval(&args[0])?, eval(&args[1])?) else {
+                return Err(format!("{intrinsic:?} takes integers"));
+            };
+            let exact = arithmetic(*op, a, b)?;
+            let (min, max) = range(&expr.ty)?;
+            let result = match intrinsic {
+                Intrinsic::Wrapping(_) => exact.map(|n| wrap(n, min, max)),
+                _ => exact.map(|n| n.clamp(min, max)),
+            };
+            result
+                .map(Value::Int)
+                .ok_or_else(|| overflow(*op, a, b, &expr.ty))
+        }
+        TypedExprKind::Intrinsic {
+            intrinsic: Intrinsic::Overflows(op),
+            args,
+            ..
+        } => {
+            let (Value::Int(a), Value::Int(b)) = (eval(&args[0])?, eval(&args[1])?) else {
+                return Err("Overflows takes integers".to_string());
+            };
+            // Division by zero counts as overflow
+            let fits = arithmetic_in(*op, a, b, &args[0].ty).is_ok_and(|n| n.is_some());
+            Ok(Value::Bool(!fits))
+        }
+        TypedExprKind::Variable(name) => Err(format!(
+            "{} is a variable, not a constant",
+            source_name(name)
+        )),
+        TypedExprKind::Call { function, .. } => {
+            Err(format!("Cannot call {function} in a constant expression"))
+        }
+        _ => Err("Expression is not constant".to_string()),
+    }
+}
+
+fn binary(op: BinaryOp, lhs: Value, rhs: Value, ty: &Type) -> Result<Value, String> {
+    let ordering = match (&lhs, &rhs) {
+        (Value::Int(a), Value::Int(b)) => a.cmp(b),
+        (Value::Char(a), Value::Char(b)) => a.cmp(b),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        _ => return Err(format!("Cannot evaluate {lhs} {} {rhs}", symbol(op))),
+    };
+    let result = match op {
+        BinaryOp::Equal => ordering.is_eq(),
+        BinaryOp::NotEqual => ordering.is_ne(),
+        BinaryOp::Less => ordering.is_lt(),
+        BinaryOp::LessEqual => ordering.is_le(),
+        BinaryOp::Greater => ordering.is_gt(),
+        BinaryOp::GreaterEqual => ordering.is_ge(),
+        BinaryOp::And | BinaryOp::Or => rhs == Value::Bool(true),
+        op => {
+            let (Value::Int(a), Value::Int(b)) = (lhs, rhs) else {
+                return Err(format!("Operator {} takes integers", symbol(op)));
+            };
+            return arithmetic_in(op, a, b, ty)?.ok_or_else(|| overflow(op, a, b, ty));
+        }
+    };
+    Ok(Value::Bool(result))
+}
+
+// The exact result of integer arithmetic, or None if it does not even fit in an `i128`
+fn arithmetic(op: BinaryOp, a: i128, b: i128) -> Result<Option<i128>, String> {
+    match op {
+        BinaryOp::Add => Ok(a.checked_add(b)),
+        BinaryOp::Sub => Ok(a.checked_sub(b)),
+        BinaryOp::Mul => Ok(a.checked_mul(b)),
+        BinaryOp::Div if b == 0 => Err(format!("Attempt to divide {a} by zero")),
+        BinaryOp::Rem if b == 0 => Err(format!(
+            "Attempt to calculate the remainder of {a} with a divisor of zero"
+        )),
+        BinaryOp::Div => Ok(Some(a / b)),
+        BinaryOp::Rem => Ok(Some(a % b)),
+        op => Err(format!("Operator {} takes booleans", symbol(op))),
+    }
+}
+
+// The result of integer arithmetic in `ty`, or None if it overflows. The
+// remainder overflows wherever the quotient does, as `MIN % -1` does.
+fn arithmetic_in(op: BinaryOp, a: i128, b: i128, ty: &Type) -> Result<Option<Value>, String> {
+    if op == BinaryOp::Rem
+        && arithmetic(BinaryOp::Div, a, b)?
+            .and_then(|n| fit(n, ty))
+            .is_none()
+    {
+        return Ok(None);
+    }
+    Ok(arithmetic(op, a, b)?.and_then(|n| fit(n, ty)))
+}
+
+/// Converts a value the way `as` does, wrapping integers into the target type
+fn cast(value: Value, target: &Type) -> Result<Value, String> {
+    let n = match value {
+        Value::Int(n) if *target == Type::Char => {
+            return Ok(Value::Char(char::from(n as u8)));
+        }
+        Value::Int(n) => n,
+        Value::Bool(b) => i128::from(b),
+        Value::Char(c) if target.is_integer() => i128::from(u32::from(c)),
+        value => return Ok(value),
+    };
+    let (min, max) = range(target)?;
+    Ok(Value::Int(wrap(n, min, max)))
+}
+
+fn fit(n: i128, ty: &Type) -> Option<Value> {
+    let (min, max) = ty.integer_range()?;
+    (min..=max).contains(&n).then_some(Value::Int(n))
+}
+
+fn range(ty: &Type) -> Result<(i128, i128), String> {
+    ty.integer_range()
+        .ok_or_else(|| format!("{ty} is not an integer type"))
+}
+
+fn wrap(n: i128, min: i128, max: i128) -> i128 {
+    (n - min).rem_euclid(max - min + 1) + min
+}
+
+fn overflow(op: BinaryOp, a: i128, b: i128, ty: &Type) -> String {
+    format!(
+        "Attempt to compute {a} {} {b}, which overflows {ty}",
+        symbol(op)
+    )
+}
+
+fn symbol(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Rem => "%",
+        BinaryOp::Equal => "==",
+        BinaryOp::NotEqual => "!=",
+        BinaryOp::Less => "<",
+        BinaryOp::LessEqual => "<=",
+        BinaryOp::Greater => ">",
+        BinaryOp::GreaterEqual => ">=",
+        BinaryOp::And => "&&",
+        BinaryOp::Or => "||",
+    }
+}
+
+// Undoes the renaming of shadowing bindings, which are called `<n>_<name>`
+fn source_name(unique: &str) -> &str {
+    match unique.split_once('_') {
+        Some((count, name)) if count.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => unique,
+    }
+}
+
+/// Replaces instructions working out constants in every function with their
+/// values, and uses of the temporaries holding them with the values too.
+/// Overflow, division by zero and indexing out of bounds in them become
+/// compile errors, as does an exit code that is out of range, except that
+/// release builds wrap around on overflow as they do at run time. Variables
+/// are left alone, so what they hold is only checked at run time.
+pub fn fold(ic: Vec<ICInstruction>, options: &Options) -> Result<Vec<ICInstruction>, Diagnostic> {
+    ic.into_iter()
+        .map(|instruction| match instruction {
+            ICInstruction::Function(mut function) => {
+                fold_function(&mut function, options.release).map_err(|mut diagnostic| {
+                    diagnostic.message = format!(
+                        "In function {}: {}",
+                        function.source_name, diagnostic.message
+                    );
+                    diagnostic
+                })?;
+                Ok(ICInstruction::Function(function))
+            }
+            instruction => Ok(instruction),
+        })
+        .collect()
+}
+
+fn fold_function(function: &mut Function, release: bool) -> Result<(), Diagnostic> {
+    // A temporary is written once, before it is read, unless its address
+    // is taken, when it may also be written through that
+    let mut writes = vec![0; function.regs.len()];
+    for block in &function.blocks {
+        for instruction in &block.instructions {
+            if let Some(dest) = instruction.dest() {
+                writes[dest] += 1;
+            }
+            if let Instruction::AddressOf { reg, .. } = instruction {
+                writes[*reg] += 1;
+            }
+        }
+    }
+    let mut constants: HashMap<Reg, Operand> = HashMap::new();
+    for block in &mut function.blocks {
+        for instruction in &mut block.instructions {
+            for operand in instruction.operands_mut() {
+                if let Some(value) = operand_constant(operand, &constants) {
+                    *operand = value;
+                }
+            }
+            check(instruction, &function.regs)?;
+            let Some(dest) = instruction.dest() else {
+                continue;
+            };
+            let ty = function.regs[dest].ty.clone();
+            let folded = match fold_instruction(instruction, &ty) {
+                Err(e) if release => wrapped(instruction, &ty).map(Some).ok_or(e),
+                folded => folded,
+            };
+            let Some(value) = folded.map_err(|e| {
+                error(
+                    codes::ARITHMETIC_ERROR,
+                    e,
+                    instruction.span(),
+                    "always panics",
+                )
+            })?
+            else {
+                continue;
+            };
+            let value = Operand::Const(value.to_literal(), ty);
+            *instruction = Instruction::Copy {
+                dest,
+                value: value.clone(),
+            };
+            if writes[dest] == 1 && function.regs[dest].name.is_none() {
+                constants.insert(dest, value);
+            }
+        }
+        for operand in block.terminator.operands_mut() {
+            if let Some(value) = operand_constant(operand, &constants) {
+                *operand = value;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn operand_constant(operand: &Operand, constants: &HashMap<Reg, Operand>) -> Option<Operand> {
+    match operand {
+        Operand::Reg(reg) => constants.get(reg).cloned(),
+        Operand::Const(..) => None,
+    }
+}
+
+/// The value of an instruction whose operands are all constants, if it is an
+/// operation on them that can be worked out at compile time
+pub fn fold_instruction(instruction: &Instruction, ty: &Type) -> Result<Option<Value>, String> {
+    let expr = |kind| TypedExpr {
+        kind,
+        ty: ty.clone(),
+    };
+    let expr = match instruction {
+        Instruction::Copy {
+            value: Operand::Const(literal, _),
+            ..
+        } => return Ok(Some(Value::from_literal(literal, ty))),
+        Instruction::Binary {
+            op, lhs, rhs, span, ..
+        } => {
+            let (Some(lhs), Some(rhs)) = (literal(lhs), literal(rhs)) else {
+                return Ok(None);
+            };
+            expr(TypedExprKind::Binary {
+                op: *op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+                span: *span,
+            })
+        }
+        Instruction::Unary {
+            op, operand, span, ..
+        } => {
+            let Some(operand) = literal(operand) else {
+                return Ok(None);
+            };
+            expr(TypedExprKind::Unary {
+                op: *op,
+                operand: Box::new(operand),
+                span: *span,
+            })
+        }
+        Instruction::Cast { value, .. } => {
+            let Some(value) = literal(value) else {
+                return Ok(None);
+            };
+            expr(TypedExprKind::Cast(Box::new(value)))
+        }
+        Instruction::Intrinsic {
+            intrinsic:
+                intrinsic
+                @ (Intrinsic::Wrapping(_) | Intrinsic::Overflows(_) | Intrinsic::Saturating(_)),
+            args,
+            span,
+            ..
+        } => {
+            let Some(args) = args.iter().map(literal).collect::<Option<Vec<_>>>() else {
+                return Ok(None);
+            };
+            expr(TypedExprKind::Intrinsic {
+                intrinsic: *intrinsic,
+                args,
+                span: *span,
+            })
+        }
+        _ => return Ok(None),
+    };
+    eval(&expr).map(Some)
+}
+
+// The value of arithmetic on constants that overflows, wrapped around the
+// way a release build does it. Division by zero still has none.
+fn wrapped(instruction: &Instruction, ty: &Type) -> Option<Value> {
+    let range = ty.integer_range()?;
+    let int = |operand: &Operand| match operand {
+        Operand::Const(literal, ty) => match Value::from_literal(literal, ty) {
+            Value::Int(n) => Some(n),
+            _ => None,
+        },
+        Operand::Reg(_) => None,
+    };
+    let n = match instruction {
+        Instruction::Binary {
+            op: op @ (BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul),
+            lhs,
+            rhs,
+            ..
+        } => interp::arithmetic(*op, int(lhs)?, int(rhs)?, range, Overflow::Plain, true),
+        Instruction::Unary {
+            op: UnaryOp::Neg,
+            operand,
+            ..
+        } => interp::negate(int(operand)?, range, true),
+        _ => return None,
+    };
+    n.ok().map(Value::Int)
+}
+
+fn literal(operand: &Operand) -> Option<TypedExpr> {
+    match operand {
+        Operand::Const(literal, ty) => Some(TypedExpr {
+            kind: TypedExprKind::Literal(literal.clone()),
+            ty: ty.clone(),
+        }),
+        Operand::Reg(_) => None,
+    }
+}
+
+// Reports what an instruction is sure to fail with, given its constant operands
+fn check(instruction: &Instruction, regs: &[Register]) -> Result<(), Diagnostic> {
+    match instruction {
+        Instruction::Intrinsic {
+            intrinsic: Intrinsic::Exit,
+            args,
+            span,
+            ..
+        } => {
+            if let Some(Operand::Const(Literal::Number(n), _)) = args.first() {
+                if !(0..=255).contains(n) {
+                    return Err(error(
+                        codes::EXIT_CODE_OUT_OF_RANGE,
+                        format!("Exit code {n} is not between 0 and 255"),
+                        *span,
+                        "not an exit code",
+                    ));
+                }
+            }
+        }
+        Instruction::Index {
+            array: Operand::Reg(array),
+            index: Operand::Const(Literal::Number(i), _),
+            span,
+            ..
+        }
+        | Instruction::IndexAddress {
+            base: Operand::Reg(array),
+            index: Operand::Const(Literal::Number(i), _),
+            span,
+            ..
+        } => {
+            let array_type = match &regs[*array].ty {
+                Type::Ref(inner, _) => inner,
+                ty => ty,
+            };
+            if let Type::Array(_, Length::Known(len)) = array_type {
+                // Indices are `u64`, so ones above `i64::MAX` are stored wrapped around
+                if *i < 0 || *i as u64 >= *len {
+                    return Err(error(
+                        codes::INDEX_OUT_OF_BOUNDS,
+                        format!(
+                            "Index out of bounds: the length is {len} but the index is {}",
+                            *i as u64
+                        ),
+                        *span,
+                        "always panics",
+                    ));
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// An error in code that is sure to fail, pointing at that code
+fn error(code: &'static str, message: String, span: Span, label: &str) -> Diagnostic {
+    Diagnostic::error(Stage::ConstantEvaluation, message)
+        .with_code(code)
+        .with_primary(span, label)
+}
@@ -0,0 +1,452 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::fmt;
+use std::iter;
+
+use crate::lexer::Span;
+
+/// How serious a diagnostic is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Stops the program from being compiled
+    Error,
+    /// Points out something that is likely a mistake, which is compiled anyway
+    Warning,
+}
+
+/// The stage of the compiler a diagnostic comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Lex,
+    Parse,
+    Semantic,
+    IcParse,
+    IcGeneration,
+    ConstantEvaluation,
+    Optimization,
+    CodeGeneration,
+    Compilation,
+}
+
+impl Stage {
+    // How errors from the stage are introduced
+    fn label(self) -> &'static str {
+        match self {
+            Stage::Lex => "Lex error",
+            Stage::Parse => "Parse error",
+            Stage::Semantic => "Semantic error",
+            Stage::IcParse => "IC parse error",
+            Stage::IcGeneration => "IC generation error",
+            Stage::ConstantEvaluation => "Constant evaluation error",
+            Stage::Optimization => "Optimization error",
+            Stage::CodeGeneration => "Code generation error",
+            Stage::Compilation => "Compilation error",
+        }
+    }
+
+    // The name JSON gives the stage
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Lex => "lex",
+            Stage::Parse => "parse",
+            Stage::Semantic => "semantic",
+            Stage::IcParse => "ic-parse",
+            Stage::IcGeneration => "ic-generation",
+            Stage::ConstantEvaluation => "constant-evaluation",
+            Stage::Optimization => "optimization",
+            Stage::CodeGeneration => "code-generation",
+            Stage::Compilation => "compilation",
+        }
+    }
+
+    /// The code of an error from the stage that has no code of its own
+    pub fn code(self) -> &'static str {
+        match self {
+            Stage::Lex => "E0000",
+            Stage::Parse => "E0100",
+            Stage::Semantic => "E0200",
+            Stage::IcGeneration => "E0300",
+            Stage::ConstantEvaluation => "E0400",
+            Stage::Optimization => "E0500",
+            Stage::CodeGeneration => "E0600",
+            Stage::Compilation => "E0700",
+            Stage::IcParse => "E0800",
+        }
+    }
+}
+
+/// The codes of the diagnostics that say more than their stage's code. Each
+/// stage's errors are numbered from its own code, and warnings from W0001.
+pub mod codes {
+    /// A character that starts no token
+    pub const UNKNOWN_CHARACTER: &str = "E0001";
+    /// A string literal with no closing quote
+    pub const UNTERMINATED_STRING: &str = "E0002";
+    /// A character literal that is not one character between single quotes
+    pub const BAD_CHARACTER_LITERAL: &str = "E0003";
+    /// An integer literal too large for any integer type
+    pub const INTEGER_TOO_LARGE: &str = "E0004";
+    /// The input ends in the middle of an item
+    pub const UNEXPECTED_END: &str = "E0101";
+    /// A token other than the one the grammar needs
+    pub const UNEXPECTED_TOKEN: &str = "E0102";
+    /// A name that no variable in scope has
+    pub const UNKNOWN_VARIABLE: &str = "E0201";
+    /// A program with no `main` function
+    pub const NO_MAIN: &str = "E0202";
+    /// A name declared twice where only one declaration can have it
+    pub const DUPLICATE_DEFINITION: &str = "E0203";
+    /// A type, function, field, method or module that does not exist
+    pub const UNKNOWN_ITEM: &str = "E0204";
+    /// A struct literal or impl that leaves out a field or method
+    pub const MISSING_ITEM: &str = "E0205";
+    /// A value of one type where another is needed
+    pub const MISMATCHED_TYPES: &str = "E0206";
+    /// A type that cannot be used where it is written
+    pub const INVALID_TYPE: &str = "E0207";
+    /// A type that does not implement a trait it needs to
+    pub const UNSATISFIED_BOUND: &str = "E0208";
+    /// A call with too few or too many arguments
+    pub const WRONG_ARGUMENT_COUNT: &str = "E0209";
+    /// A value used after it was moved
+    pub const USE_AFTER_MOVE: &str = "E0210";
+    /// A borrow, move or assignment that conflicts with a live borrow
+    pub const BORROW_CONFLICT: &str = "E0211";
+    /// A change to something that cannot be changed
+    pub const ASSIGN_TO_IMMUTABLE: &str = "E0212";
+    /// A variable used before it is given a value
+    pub const UNINITIALIZED_VARIABLE: &str = "E0213";
+    /// A type that has to be written because it cannot be inferred
+    pub const TYPE_NEEDED: &str = "E0214";
+    /// A reference that outlives what it refers to
+    pub const DANGLING_REFERENCE: &str = "E0215";
+    /// A function that can finish without returning a value
+    pub const MISSING_RETURN: &str = "E0216";
+    /// A match that leaves some values unmatched
+    pub const NON_EXHAUSTIVE_MATCH: &str = "E0217";
+    /// A match arm that no value reaches
+    pub const UNREACHABLE_PATTERN: &str = "E0218";
+    /// A temporary used where it has to be stored in a variable first
+    pub const TEMPORARY_NEEDS_VARIABLE: &str = "E0219";
+    /// An operator, call or statement its operands do not support
+    pub const INVALID_OPERATION: &str = "E0220";
+    /// A variable declared `mut` that is never changed
+    pub const UNNEEDED_MUT: &str = "E0221";
+    /// A format string with a placeholder that cannot be filled
+    pub const BAD_FORMAT_STRING: &str = "E0222";
+    /// A literal outside the range of its type
+    pub const LITERAL_OUT_OF_RANGE: &str = "E0223";
+    /// A value that has to be known at compile time but is not
+    pub const NOT_CONSTANT: &str = "E0224";
+    /// A `?` where it cannot be used
+    pub const INVALID_TRY: &str = "E0225";
+    /// A move out of a place that cannot be left without a value
+    pub const CANNOT_MOVE: &str = "E0226";
+    /// A struct that holds itself, so it would have no end
+    pub const RECURSIVE_TYPE: &str = "E0301";
+    /// A generic function that instantiates itself with ever deeper types
+    pub const TYPE_TOO_DEEP: &str = "E0302";
+    /// An exit code that is a constant outside 0 to 255
+    pub const EXIT_CODE_OUT_OF_RANGE: &str = "E0401";
+    /// An index that is a constant outside the array it indexes
+    pub const INDEX_OUT_OF_BOUNDS: &str = "E0402";
+    /// Arithmetic on constants that overflows or divides by zero
+    pub const ARITHMETIC_ERROR: &str = "E0403";
+    /// The C compiler rejected the C the compiler generated
+    pub const C_COMPILER_FAILED: &str = "E0701";
+    /// The assembler or linker rejected the assembly the compiler generated
+    pub const ASSEMBLER_FAILED: &str = "E0702";
+    /// An LLVM tool or the linker rejected the module the compiler generated
+    pub const LLVM_FAILED: &str = "E0703";
+    /// Code after a statement that never finishes
+    pub const UNREACHABLE_CODE: &str = "W0001";
+}
+
+/// A part of the source a diagnostic points at, with what it has to say about
+/// it. The primary label points at where the problem is, and the others at
+/// code that explains it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    pub primary: bool,
+}
+
+/// An edit that would fix what a diagnostic reports: `replacement` goes in
+/// at `span`, in place of the `len` characters there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fix {
+    pub message: String,
+    pub span: Span,
+    pub len: usize,
+    pub replacement: String,
+}
+
+/// An error or warning about the program being compiled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub stage: Stage,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub fixes: Vec<Fix>,
+}
+
+impl Diagnostic {
+    pub fn error(stage: Stage, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code: stage.code(),
+            stage,
+            message: message.into(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+            fixes: Vec::new(),
+        }
+    }
+
+    pub fn warning(stage: Stage, code: &'static str, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            code,
+            ..Diagnostic::error(stage, message)
+        }
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Diagnostic {
+        self.code = code;
+        self
+    }
+
+    /// Points at where the problem is. Code from a standard module written
+    /// in Nimra has no location, so it is not pointed at.
+    pub fn with_primary(mut self, span: Span, message: impl Into<String>) -> Diagnostic {
+        if span.line > 0 {
+            self.labels.retain(|label| !label.primary);
+            self.labels.push(Label {
+                span,
+                message: message.into(),
+                primary: true,
+            });
+        }
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Span, message: impl Into<String>) -> Diagnostic {
+        if span.line > 0 {
+            self.labels.push(Label {
+                span,
+                message: message.into(),
+                primary: false,
+            });
+        }
+        self
+    }
+
+    /// The label that points at where the problem is, if the diagnostic has one
+    pub fn primary(&self) -> Option<&Label> {
+        self.labels.iter().find(|label| label.primary)
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_fix(mut self, fix: Fix) -> Diagnostic {
+        if fix.span.line > 0 {
+            self.fixes.push(fix);
+        }
+        self
+    }
+
+    /// Renders the diagnostic for people, quoting the lines of `source` it
+    /// points at:
+    ///
+    /// ```text
+    /// Parse error[E0102]: Expected semicolon after let, found CloseBrace
+    ///  --> main.nimra:3:1
+    ///   |
+    /// 3 | }
+    ///   | ^ expected semicolon
+    ///   |
+    ///   = help: insert a semicolon: `;`
+    /// ```
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let mut text = format!("{self}\n");
+        let mut labels: Vec<&Label> = self.labels.iter().collect();
+        labels.sort_by_key(|label| (label.span.line, label.span.column));
+        let lines: Vec<&str> = source.lines().collect();
+        let width = labels
+            .iter()
+            .map(|label| label.span.line.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let margin = " ".repeat(width);
+        if let Some(label) = self.primary().or(labels.first().copied()) {
+            text.push_str(&format!(
+                "{margin}--> {file}:{}:{}\n",
+                label.span.line, label.span.column
+            ));
+        }
+        if !labels.is_empty() {
+            text.push_str(&format!("{margin} |\n"));
+        }
+        let mut quoted = 0;
+        for label in &labels {
+            let line = lines.get(label.span.line - 1).copied().unwrap_or("");
+            if label.span.line != quoted {
+                if quoted > 0 && label.span.line > quoted + 1 {
+                    text.push_str(&format!("{margin}...\n"));
+                }
+                text.push_str(&format!("{:>width$} | {line}\n", label.span.line));
+                quoted = label.span.line;
+            }
+            // Tabs before the marker are kept, so it lines up under the
+            // code however wide they are shown
+            let indent: String = line
+                .chars()
+                .map(|c| if c == '\t' { c } else { ' ' })
+                .chain(iter::repeat(' '))
+                .take(label.span.column.saturating_sub(1))
+                .collect();
+            let marker = if label.primary { "^" } else { "-" };
+            let underline = marker.repeat(token_len(line, label.span.column));
+            let message = if label.message.is_empty() {
+                String::new()
+            } else {
+                format!(" {}", label.message)
+            };
+            text.push_str(&format!("{margin} | {indent}{underline}{message}\n"));
+        }
+        if !labels.is_empty() && (!self.notes.is_empty() || !self.fixes.is_empty()) {
+            text.push_str(&format!("{margin} |\n"));
+        }
+        for note in &self.notes {
+            text.push_str(&format!("{margin} = note: {note}\n"));
+        }
+        for fix in &self.fixes {
+            // A fix that only removes code has nothing to show
+            if fix.replacement.is_empty() {
+                text.push_str(&format!("{margin} = help: {}\n", fix.message));
+            } else {
+                // A fix that adds a line is shown without its line break
+                text.push_str(&format!(
+                    "{margin} = help: {}: `{}`\n",
+                    fix.message,
+                    fix.replacement.trim_end()
+                ));
+            }
+        }
+        text
+    }
+
+    /// Renders the diagnostic as one line of JSON, for tools to read
+    pub fn to_json(&self, file: &str) -> String {
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|label| {
+                format!(
+                    "{{\"line\":{},\"column\":{},\"message\":{},\"primary\":{}}}",
+                    label.span.line,
+                    label.span.column,
+                    json_string(&label.message),
+                    label.primary
+                )
+            })
+            .collect();
+        let notes: Vec<String> = self.notes.iter().map(|note| json_string(note)).collect();
+        let fixes: Vec<String> = self
+            .fixes
+            .iter()
+            .map(|fix| {
+                format!(
+                    "{{\"message\":{},\"line\":{},\"column\":{},\"length\":{},\"replacement\":{}}}",
+                    json_string(&fix.message),
+                    fix.span.line,
+                    fix.span.column,
+                    fix.len,
+                    json_string(&fix.replacement)
+                )
+            })
+            .collect();
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        format!(
+            "{{\"severity\":\"{severity}\",\"code\":\"{}\",\"stage\":\"{}\",\"message\":{},\"file\":{},\"labels\":[{}],\"notes\":[{}],\"fixes\":[{}]}}",
+            self.code,
+            self.stage.name(),
+            json_string(&self.message),
+            json_string(file),
+            labels.join(","),
+            notes.join(","),
+            fixes.join(",")
+        )
+    }
+}
+
+/// Writes the first line of a diagnostic, such as
+/// `Semantic error[E0201]: Unknown variable x`
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Error => self.stage.label(),
+            Severity::Warning => "Warning",
+        };
+        write!(f, "{label}[{}]: {}", self.code, self.message)
+    }
+}
+
+// How many characters to underline for the token at a column of a line: a
+// whole word or string literal, or one character
+fn token_len(line: &str, column: usize) -> usize {
+    let mut chars = line.chars().skip(column.saturating_sub(1)).peekable();
+    match chars.peek() {
+        Some(c) if c.is_alphanumeric() || *c == '_' => chars
+            .take_while(|c| c.is_alphanumeric() || *c == '_')
+            .count(),
+        Some('"') => {
+            let len = chars.skip(1).take_while(|c| *c != '"').count();
+            (len + 2).min(line.chars().count() + 1 - column)
+        }
+        _ => 1,
+    }
+}
+
+// Quotes text as a JSON string
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -15,85 +15,2094 @@
  * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
  */
 
-use crate::lexer::{Literal, Type};
-use crate::parser::ASTNode;
+use std::collections::{HashMap, HashSet};
+
+use crate::diagnostic::{codes, Diagnostic, Stage};
+use crate::ir::{Block, BlockId, Function, Instruction, Operand, Reg, Register, Terminator};
+use crate::lexer::{Length, Literal, Span, Type};
+use crate::parser::{BinaryOp, UnaryOp};
+use crate::sema::{
+    self, Capture, Intrinsic, TraitMethod, TypedArm, TypedExpr, TypedExprKind, TypedFunction,
+    TypedProgram, TypedStmt, TypedStruct, TypedTrait,
+};
+
+/// How deeply type arguments may nest before instantiation is assumed to recurse forever
+const MAX_TYPE_DEPTH: usize = 32;
 
 #[derive(Clone, Debug)]
 pub enum ICInstruction {
-    Literal(Literal),
-    FnDecl {
+    /// A trait used as `dyn Trait`, which needs a vtable layout
+    TraitDecl {
         name: String,
-        args: Vec<ASTNode>,
-        body: Vec<ASTNode>,
-        return_type: Type,
-    },
-    FnCall {
-        function: String,
-        args: Vec<ASTNode>,
+        methods: Vec<TraitMethod>,
     },
-    Import {
-        module: String,
+    /// A struct with all of its type arguments filled in, named by `mangle`
+    StructDecl {
         name: String,
+        fields: Vec<(String, Type)>,
     },
+    /// An array type, declared after its element type like a struct
+    ArrayType { element: Type, len: u64 },
+    /// A function or closure lowered to basic blocks
+    Function(Function),
+    /// A function type, which is a function pointer and the environment of
+    /// the closure it may point to
+    FnType {
+        params: Vec<Type>,
+        return_type: Type,
+    },
+    /// The vtable through which `ty` is used as a `dyn Trait`
+    Vtable {
+        trait_name: String,
+        ty: Type,
+        methods: Vec<TraitMethod>,
+    },
+    /// A type whose values own heap memory, which needs functions to move
+    /// and drop them
+    OwnedType(Type),
 }
 
 pub struct Generator {
     ic: Vec<ICInstruction>,
-    ast: Vec<ASTNode>,
+    functions: Vec<TypedFunction>,
+    structs: HashMap<String, TypedStruct>,
+    traits: HashMap<String, TypedTrait>,
+    /// Traits used as `dyn Trait`, and the types converted to each of them
+    dyn_traits: Vec<String>,
+    vtables: Vec<(String, Type)>,
+    /// Instances of generic functions that are used but not generated yet
+    pending: Vec<(String, Vec<Type>)>,
+    instances: HashSet<String>,
+    /// Every struct and array type the generated functions use
+    struct_types: Vec<Type>,
+    /// Every function type, each after the function types it mentions
+    fn_types: Vec<Type>,
+    /// Every type that owns heap memory, each after the owned types it contains
+    owned_types: Vec<Type>,
+    /// How many temporaries have been introduced to drop values
+    temps: usize,
+    /// The checks of the `?`s in the statement being lowered, which go before it
+    hoisted: Vec<TypedStmt>,
+    /// How many closures have been lifted out into functions of their own
+    closures: usize,
+    /// Variables of the current function that closures capture by reference.
+    /// They live on the heap, so the closures can outlive the function.
+    shared: HashSet<String>,
+    builder: Builder,
     pos: usize,
 }
 
+// The function whose blocks are being built
+#[derive(Default)]
+struct Builder {
+    source_name: String,
+    regs: Vec<Register>,
+    blocks: Vec<Block>,
+    /// The block instructions are added to, which has no terminator yet
+    current: BlockId,
+    variables: HashMap<String, Binding>,
+    /// What the closure being built captures, kept in the struct `env` points to
+    captures: Vec<Capture>,
+    env: Option<Reg>,
+}
+
+// Where the value of a variable is kept
+#[derive(Clone, Copy)]
+enum Binding {
+    Reg(Reg),
+    /// A shared variable is on the heap, where the register points
+    Shared(Reg),
+}
+
+impl Builder {
+    fn reg(&mut self, ty: Type) -> Reg {
+        self.regs.push(Register { ty, name: None });
+        self.regs.len() - 1
+    }
+
+    // A new register for the result of a call, unless it returns nothing
+    fn result(&mut self, ty: &Type) -> Option<Reg> {
+        (*ty != Type::Void).then(|| self.reg(ty.clone()))
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.blocks[self.current].instructions.push(instruction);
+    }
+
+    // Adds an instruction that writes to a new register of type `ty`
+    fn define(&mut self, ty: Type, instruction: impl FnOnce(Reg) -> Instruction) -> Operand {
+        let dest = self.reg(ty);
+        self.emit(instruction(dest));
+        Operand::Reg(dest)
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push(Block {
+            instructions: Vec::new(),
+            terminator: Terminator::Unreachable,
+        });
+        self.blocks.len() - 1
+    }
+
+    // Ends the current block. What follows goes in a new block, which is
+    // unreachable unless something jumps to it.
+    fn terminate(&mut self, terminator: Terminator) {
+        self.blocks[self.current].terminator = terminator;
+        self.current = self.new_block();
+    }
+}
+
 impl Generator {
-    pub fn new(ast: Vec<ASTNode>) -> Generator {
+    pub fn new(program: TypedProgram) -> Generator {
         let ic = Vec::new();
         let pos = 0;
-        Generator { ic, ast, pos }
+        // Impls are never generic, so their methods are generated like plain functions
+        let mut functions = program.functions;
+        for typed_impl in program.impls {
+            for method in typed_impl.methods {
+                functions.push(TypedFunction {
+                    name: mangle_method(&typed_impl.trait_name, &typed_impl.ty, &method.name),
+                    ..method
+                });
+            }
+        }
+        Generator {
+            ic,
+            functions,
+            structs: program
+                .structs
+                .into_iter()
+                .map(|definition| (definition.name.clone(), definition))
+                .collect(),
+            traits: program
+                .traits
+                .into_iter()
+                .map(|definition| (definition.name.clone(), definition))
+                .collect(),
+            dyn_traits: Vec::new(),
+            vtables: Vec::new(),
+            pending: Vec::new(),
+            instances: HashSet::new(),
+            struct_types: Vec::new(),
+            fn_types: Vec::new(),
+            owned_types: Vec::new(),
+            temps: 0,
+            hoisted: Vec::new(),
+            closures: 0,
+            shared: HashSet::new(),
+            builder: Builder::default(),
+            pos,
+        }
     }
 
-    pub fn generate(&mut self) -> Result<Vec<ICInstruction>, String> {
+    pub fn generate(&mut self) -> Result<Vec<ICInstruction>, Diagnostic> {
         while self.generate_one_ic()? {}
-        Ok(self.ic.clone())
+        // Types go first, each struct or array after the types it contains
+        let mut types: Vec<ICInstruction> = self
+            .dyn_traits
+            .iter()
+            .map(|name| ICInstruction::TraitDecl {
+                name: name.clone(),
+                methods: self.dispatchable_methods(name),
+            })
+            .collect();
+        for ty in &self.fn_types {
+            if let Type::Fn(params, return_type) = ty {
+                types.push(ICInstruction::FnType {
+                    params: params.clone(),
+                    return_type: (**return_type).clone(),
+                });
+            }
+        }
+        let mut declared = HashSet::new();
+        for ty in self.struct_types.clone() {
+            self.declare_type(&ty, &mut Vec::new(), &mut declared, &mut types)?;
+        }
+        types.extend(
+            self.owned_types
+                .iter()
+                .cloned()
+                .map(ICInstruction::OwnedType),
+        );
+        types.append(&mut self.ic);
+        for (trait_name, ty) in &self.vtables {
+            types.push(ICInstruction::Vtable {
+                trait_name: trait_name.clone(),
+                ty: ty.clone(),
+                methods: self.dispatchable_methods(trait_name),
+            });
+        }
+        Ok(types)
     }
 
-    fn generate_one_ic(&mut self) -> Result<bool, String> {
-        if self.pos == self.ast.len() {
+    // Generates the next non-generic function, then the instances of generic
+    // functions they use, until there are none left
+    fn generate_one_ic(&mut self) -> Result<bool, Diagnostic> {
+        while self
+            .functions
+            .get(self.pos)
+            .is_some_and(|function| !function.type_params.is_empty())
+        {
+            self.pos += 1;
+        }
+        let (function, bindings, name) = if let Some(function) = self.functions.get(self.pos) {
+            self.pos += 1;
+            (function.clone(), HashMap::new(), function.name.clone())
+        } else if let Some((name, type_args)) = self.pending.pop() {
+            let function = self
+                .functions
+                .iter()
+                .find(|function| function.name == name)
+                .ok_or_else(|| error(format!("Unknown generic function {name}")))?
+                .clone();
+            let bindings = function
+                .type_params
+                .iter()
+                .cloned()
+                .zip(type_args.iter().cloned())
+                .collect();
+            (function, bindings, mangle_function(&name, &type_args))
+        } else {
             return Ok(false);
+        };
+        let args: Vec<(String, Type)> = function
+            .params
+            .iter()
+            .map(|(arg, ty)| (arg.clone(), self.lower_type(ty, &bindings)))
+            .collect();
+        let return_type = self.lower_type(&function.return_type, &bindings);
+        let body = self.lower_stmts(&function.body, &bindings)?;
+        let body = self.insert_drops(&args, body);
+        let mut shared = HashSet::new();
+        sema::visit_exprs(&body, &mut |expr| {
+            if let TypedExprKind::Closure { captures, .. } = &expr.kind {
+                for capture in captures.iter().filter(|capture| capture.by_ref) {
+                    shared.insert(capture.name.clone());
+                }
+            }
+        });
+        self.shared = shared;
+        let function =
+            self.build_function(name.clone(), name, &args, &return_type, &body, Vec::new())?;
+        self.ic.push(ICInstruction::Function(function));
+        Ok(true)
+    }
+
+    fn declare_type(
+        &self,
+        ty: &Type,
+        containing: &mut Vec<Type>,
+        declared: &mut HashSet<String>,
+        structs: &mut Vec<ICInstruction>,
+    ) -> Result<(), Diagnostic> {
+        if !matches!(
+            ty,
+            Type::Struct(..) | Type::Array(..) | Type::Option(_) | Type::Result(..)
+        ) {
+            return Ok(());
         }
-        let ast_node = self
-            .ast
-            .get(self.pos)
-            .ok_or_else(|| format!("AST index {} out of bounds", self.pos))?;
-        self.pos += 1;
-        match ast_node {
-            ASTNode::Literal(lit) => self.ic.push(ICInstruction::Literal(lit.clone())),
-            ASTNode::FnCall { function, args } => {
-                self.ic.push(ICInstruction::FnCall {
-                    function: function.clone(),
-                    args: args.clone(),
+        let mangled = mangle(ty);
+        if declared.contains(&mangled) {
+            return Ok(());
+        }
+        if containing.contains(ty) {
+            return Err(error(format!("Struct {ty} contains itself"))
+                .with_code(codes::RECURSIVE_TYPE)
+                .with_note("a struct can hold one of its own type in a Box"));
+        }
+        let (name, args) = match ty {
+            Type::Struct(name, args) => (name, args),
+            Type::Array(element, len) => {
+                let Length::Known(len) = len else {
+                    return Err(error(format!("The length of {ty} is not known")));
+                };
+                containing.push(ty.clone());
+                self.declare_type(element, containing, declared, structs)?;
+                containing.pop();
+                declared.insert(mangled);
+                structs.push(ICInstruction::ArrayType {
+                    element: (**element).clone(),
+                    len: *len,
                 });
+                return Ok(());
+            }
+            Type::Option(_) | Type::Result(..) => {
+                let fields = sema::variant_fields(ty);
+                containing.push(ty.clone());
+                for (_, field_type) in &fields {
+                    self.declare_type(field_type, containing, declared, structs)?;
+                }
+                containing.pop();
+                declared.insert(mangled.clone());
+                structs.push(ICInstruction::StructDecl {
+                    name: mangled,
+                    fields,
+                });
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        let definition = &self.structs[name];
+        let bindings = definition
+            .type_params
+            .iter()
+            .cloned()
+            .zip(args.iter().cloned())
+            .collect();
+        let fields: Vec<(String, Type)> = definition
+            .fields
+            .iter()
+            .map(|(field, field_type)| (field.clone(), sema::substitute(field_type, &bindings)))
+            .collect();
+        containing.push(ty.clone());
+        for (_, field_type) in &fields {
+            self.declare_type(field_type, containing, declared, structs)?;
+        }
+        containing.pop();
+        declared.insert(mangled.clone());
+        structs.push(ICInstruction::StructDecl {
+            name: mangled,
+            fields,
+        });
+        Ok(())
+    }
+
+    // Substitutes type arguments into `ty` and remembers the structs it uses
+    fn lower_type(&mut self, ty: &Type, bindings: &HashMap<String, Type>) -> Type {
+        let ty = sema::substitute(ty, bindings);
+        self.use_type(&ty);
+        ty
+    }
+
+    fn dispatchable_methods(&self, trait_name: &str) -> Vec<TraitMethod> {
+        self.traits[trait_name]
+            .methods
+            .iter()
+            .filter(|method| method.is_dispatchable())
+            .cloned()
+            .collect()
+    }
+
+    fn use_type(&mut self, ty: &Type) {
+        match ty {
+            Type::Struct(name, args) if !self.struct_types.contains(ty) => {
+                self.struct_types.push(ty.clone());
+                for arg in args {
+                    self.use_type(arg);
+                }
+                // Fields may be of types nothing else mentions
+                if depth(ty) <= MAX_TYPE_DEPTH {
+                    let definition = &self.structs[name];
+                    let bindings = definition
+                        .type_params
+                        .iter()
+                        .cloned()
+                        .zip(args.iter().cloned())
+                        .collect();
+                    let fields: Vec<Type> = definition
+                        .fields
+                        .iter()
+                        .map(|(_, field_type)| sema::substitute(field_type, &bindings))
+                        .collect();
+                    for field_type in &fields {
+                        self.use_type(field_type);
+                    }
+                }
+            }
+            Type::Array(element, _) if !self.struct_types.contains(ty) => {
+                self.struct_types.push(ty.clone());
+                self.use_type(element);
+            }
+            Type::Option(_) | Type::Result(..) if !self.struct_types.contains(ty) => {
+                self.struct_types.push(ty.clone());
+                for (_, field_type) in sema::variant_fields(ty) {
+                    self.use_type(&field_type);
+                }
             }
-            ASTNode::FnDecl {
+            Type::Ref(inner, _) | Type::Box(inner) | Type::Buffer(inner) => self.use_type(inner),
+            Type::Fn(params, return_type) if !self.fn_types.contains(ty) => {
+                for param in params {
+                    self.use_type(param);
+                }
+                self.use_type(return_type);
+                self.fn_types.push(ty.clone());
+            }
+            Type::Dyn(name) if !self.dyn_traits.contains(name) => {
+                self.dyn_traits.push(name.clone());
+                // The vtable mentions every type the trait's methods do
+                for method in self.dispatchable_methods(name) {
+                    for ty in method.params.iter().chain([&method.return_type]) {
+                        self.use_type(ty);
+                    }
+                }
+            }
+            _ => {}
+        }
+        if !self.owned_types.contains(ty) && sema::needs_drop(ty, &self.structs) {
+            self.owned_types.push(ty.clone());
+        }
+    }
+
+    // Drops what a function's parameters and variables own when they go out of
+    // scope or the function returns. Variables that closures capture live on
+    // in the closures, so they are never dropped.
+    fn insert_drops(&mut self, params: &[(String, Type)], body: Vec<TypedStmt>) -> Vec<TypedStmt> {
+        let mut captured = HashSet::new();
+        sema::visit_exprs(&body, &mut |expr| {
+            if let TypedExprKind::Closure { captures, .. } = &expr.kind {
+                captured.extend(captures.iter().map(|capture| capture.name.clone()));
+            }
+        });
+        let owned = params
+            .iter()
+            .filter(|(name, ty)| !captured.contains(name) && sema::needs_drop(ty, &self.structs))
+            .cloned()
+            .collect();
+        self.drop_scope(body, owned, &mut Vec::new(), &captured)
+    }
+
+    // Inserts drops into a block, whose own variables start with `owned`, and
+    // drops those at its end. `scopes` holds the owned variables of the
+    // blocks around it, innermost last.
+    fn drop_scope(
+        &mut self,
+        stmts: Vec<TypedStmt>,
+        owned: Vec<(String, Type)>,
+        scopes: &mut Vec<Vec<(String, Type)>>,
+        captured: &HashSet<String>,
+    ) -> Vec<TypedStmt> {
+        scopes.push(owned);
+        let mut block = Vec::new();
+        for stmt in stmts {
+            self.drop_stmt(stmt, scopes, captured, &mut block);
+        }
+        let owned = scopes.pop().unwrap_or_default();
+        if !sema::diverges(&block) {
+            block.extend(
+                owned
+                    .into_iter()
+                    .rev()
+                    .map(|(name, ty)| TypedStmt::Drop(variable(name, ty))),
+            );
+        }
+        block
+    }
+
+    fn drop_stmt(
+        &mut self,
+        stmt: TypedStmt,
+        scopes: &mut Vec<Vec<(String, Type)>>,
+        captured: &HashSet<String>,
+        block: &mut Vec<TypedStmt>,
+    ) {
+        match stmt {
+            TypedStmt::Let {
                 name,
-                args,
+                var_type,
+                value,
+            } => {
+                if !captured.contains(&name) && sema::needs_drop(&var_type, &self.structs) {
+                    if let Some(scope) = scopes.last_mut() {
+                        scope.push((name.clone(), var_type.clone()));
+                    }
+                }
+                block.push(TypedStmt::Let {
+                    name,
+                    var_type,
+                    value,
+                });
+            }
+            // The new value is worked out before the old one is dropped, and
+            // the place is only worked out once
+            TypedStmt::Assign { target, value } if sema::needs_drop(&target.ty, &self.structs) => {
+                let value = self.temp(value, block);
+                let target = match target.kind {
+                    TypedExprKind::Variable(_) => target,
+                    _ => {
+                        let ty = target.ty.clone();
+                        let place = self.temp(
+                            TypedExpr {
+                                ty: Type::Ref(Box::new(ty.clone()), true),
+                                kind: TypedExprKind::Ref(Box::new(target)),
+                            },
+                            block,
+                        );
+                        TypedExpr {
+                            kind: TypedExprKind::Deref(Box::new(place)),
+                            ty,
+                        }
+                    }
+                };
+                block.push(TypedStmt::Drop(target.clone()));
+                block.push(TypedStmt::Assign { target, value });
+            }
+            TypedStmt::Expr(value) if sema::needs_drop(&value.ty, &self.structs) => {
+                let value = self.temp(value, block);
+                block.push(TypedStmt::Drop(value));
+            }
+            TypedStmt::Return(value) => {
+                let live: Vec<(String, Type)> = scopes.iter().flatten().cloned().collect();
+                // The returned value is worked out before anything it may use is dropped
+                let value = match value {
+                    Some(value) if !live.is_empty() => Some(self.temp(value, block)),
+                    value => value,
+                };
+                block.extend(
+                    live.into_iter()
+                        .rev()
+                        .map(|(name, ty)| TypedStmt::Drop(variable(name, ty))),
+                );
+                block.push(TypedStmt::Return(value));
+            }
+            TypedStmt::If {
+                condition,
+                then_body,
+                else_body,
+            } => block.push(TypedStmt::If {
+                condition,
+                then_body: self.drop_scope(then_body, Vec::new(), scopes, captured),
+                else_body: self.drop_scope(else_body, Vec::new(), scopes, captured),
+            }),
+            TypedStmt::While { condition, body } => block.push(TypedStmt::While {
+                condition,
+                body: self.drop_scope(body, Vec::new(), scopes, captured),
+            }),
+            // Each iteration owns the element it is given
+            TypedStmt::For {
+                var,
+                iterable,
                 body,
-                return_type,
-            } => self.ic.push(ICInstruction::FnDecl {
+            } => {
+                // A buffer that is not in a place belongs to the loop, and is
+                // dropped with the block's variables
+                let iterable = match &iterable.ty {
+                    Type::Buffer(_) if !sema::is_place(&iterable) => {
+                        self.owned_temp(iterable, scopes, block)
+                    }
+                    _ => iterable,
+                };
+                let owned = match &iterable.ty {
+                    Type::Array(element, _)
+                        if !captured.contains(&var) && sema::needs_drop(element, &self.structs) =>
+                    {
+                        vec![(var.clone(), (**element).clone())]
+                    }
+                    _ => Vec::new(),
+                };
+                block.push(TypedStmt::For {
+                    var,
+                    iterable,
+                    body: self.drop_scope(body, owned, scopes, captured),
+                });
+            }
+            // An enum is matched on its flag. An arm that binds the value
+            // inside moves it out of the enum, which is kept in a temporary
+            // and dropped with the block's variables if it owns what is left.
+            TypedStmt::Match { scrutinee, arms }
+                if matches!(scrutinee.ty, Type::Option(_) | Type::Result(..)) =>
+            {
+                let ty = scrutinee.ty.clone();
+                let value = if sema::is_place(&scrutinee)
+                    || !sema::needs_drop(&scrutinee.ty, &self.structs)
+                {
+                    self.temp(scrutinee, block)
+                } else {
+                    self.owned_temp(scrutinee, scopes, block)
+                };
+                let fields = sema::variant_fields(&ty);
+                let arms = arms
+                    .into_iter()
+                    .map(|arm| {
+                        let mut body = Vec::new();
+                        if let Some(name) = arm.binding.clone() {
+                            let payload = match arm.patterns[0].kind {
+                                TypedExprKind::Literal(Literal::Bool(true)) => "value",
+                                _ => "error",
+                            };
+                            if let Some((_, payload_type)) =
+                                fields.iter().find(|(field, _)| field == payload)
+                            {
+                                body.push(TypedStmt::Let {
+                                    name,
+                                    var_type: payload_type.clone(),
+                                    value: Some(self.take(field(
+                                        value.clone(),
+                                        payload,
+                                        payload_type.clone(),
+                                    ))),
+                                });
+                            }
+                        }
+                        body.extend(arm.body);
+                        TypedArm {
+                            binding: None,
+                            body: self.drop_scope(body, Vec::new(), scopes, captured),
+                            ..arm
+                        }
+                    })
+                    .collect();
+                block.push(TypedStmt::Match {
+                    scrutinee: field(value, sema::tag_field(&ty), Type::Bool),
+                    arms,
+                });
+            }
+            TypedStmt::Match { scrutinee, arms } => block.push(TypedStmt::Match {
+                scrutinee,
+                arms: arms
+                    .into_iter()
+                    .map(|arm| TypedArm {
+                        body: self.drop_scope(arm.body, Vec::new(), scopes, captured),
+                        ..arm
+                    })
+                    .collect(),
+            }),
+            stmt => block.push(stmt),
+        }
+    }
+
+    // Stores a value in a new variable, so it can be used after the statements
+    // that follow
+    fn temp(&mut self, value: TypedExpr, block: &mut Vec<TypedStmt>) -> TypedExpr {
+        self.temps += 1;
+        // Names from the source never end in `_`
+        let name = format!("{}_", self.temps);
+        let ty = value.ty.clone();
+        block.push(TypedStmt::Let {
+            name: name.clone(),
+            var_type: ty.clone(),
+            value: Some(value),
+        });
+        variable(name, ty)
+    }
+
+    // Stores a value that owns heap memory in a new variable, which is
+    // dropped with the variables of the innermost block
+    fn owned_temp(
+        &mut self,
+        value: TypedExpr,
+        scopes: &mut [Vec<(String, Type)>],
+        block: &mut Vec<TypedStmt>,
+    ) -> TypedExpr {
+        let temp = self.temp(value, block);
+        if let (TypedExprKind::Variable(name), Some(scope)) = (&temp.kind, scopes.last_mut()) {
+            scope.push((name.clone(), temp.ty.clone()));
+        }
+        temp
+    }
+
+    // Reads a place, moving out of it if its value owns heap memory
+    fn take(&self, place: TypedExpr) -> TypedExpr {
+        if !sema::needs_drop(&place.ty, &self.structs) {
+            return place;
+        }
+        TypedExpr {
+            ty: place.ty.clone(),
+            kind: TypedExprKind::Move(Box::new(place)),
+        }
+    }
+
+    // Lowers each statement after the checks of the `?`s in it
+    fn lower_stmts(
+        &mut self,
+        stmts: &[TypedStmt],
+        bindings: &HashMap<String, Type>,
+    ) -> Result<Vec<TypedStmt>, Diagnostic> {
+        let mut lowered = Vec::new();
+        for stmt in stmts {
+            let outer = std::mem::take(&mut self.hoisted);
+            let stmt = self.lower_stmt(stmt, bindings);
+            lowered.append(&mut std::mem::replace(&mut self.hoisted, outer));
+            lowered.push(stmt?);
+        }
+        Ok(lowered)
+    }
+
+    // Lowers `value?` to a temporary holding the enum, and the check that
+    // returns its `None` or `Err` from the function
+    fn lower_try(
+        &mut self,
+        value: &TypedExpr,
+        returns: &Type,
+        bindings: &HashMap<String, Type>,
+    ) -> Result<(TypedExpr, TypedStmt), Diagnostic> {
+        let value = self.lower_expr(value, bindings)?;
+        let returns = self.lower_type(returns, bindings);
+        let mut hoisted = std::mem::take(&mut self.hoisted);
+        let value = self.temp(value, &mut hoisted);
+        self.hoisted = hoisted;
+        let tag = field(value.clone(), sema::tag_field(&value.ty), Type::Bool);
+        let mut failure = vec![(
+            sema::tag_field(&returns).to_string(),
+            TypedExpr {
+                kind: TypedExprKind::Literal(Literal::Bool(false)),
+                ty: Type::Bool,
+            },
+        )];
+        if let Type::Result(_, error) = &value.ty {
+            let error = self.take(field(value.clone(), "error", (**error).clone()));
+            failure.push(("error".to_string(), error));
+        }
+        let check = TypedStmt::If {
+            condition: TypedExpr {
+                kind: TypedExprKind::Unary {
+                    op: UnaryOp::Not,
+                    operand: Box::new(tag),
+                    span: Span::default(),
+                },
+                ty: Type::Bool,
+            },
+            then_body: vec![TypedStmt::Return(Some(TypedExpr {
+                kind: TypedExprKind::StructLiteral(failure),
+                ty: returns,
+            }))],
+            else_body: Vec::new(),
+        };
+        Ok((value, check))
+    }
+
+    fn lower_stmt(
+        &mut self,
+        stmt: &TypedStmt,
+        bindings: &HashMap<String, Type>,
+    ) -> Result<TypedStmt, Diagnostic> {
+        Ok(match stmt {
+            TypedStmt::Let {
+                name,
+                var_type,
+                value,
+            } => TypedStmt::Let {
                 name: name.clone(),
-                args: args.clone(),
-                body: body.clone(),
-                return_type: return_type.clone(),
+                var_type: self.lower_type(var_type, bindings),
+                value: match value {
+                    Some(value) => Some(self.lower_expr(value, bindings)?),
+                    None => None,
+                },
+            },
+            TypedStmt::Assign { target, value } => TypedStmt::Assign {
+                target: self.lower_expr(target, bindings)?,
+                value: self.lower_expr(value, bindings)?,
+            },
+            TypedStmt::CompoundAssign {
+                op,
+                target,
+                value,
+                span,
+            } => TypedStmt::CompoundAssign {
+                op: *op,
+                target: self.lower_expr(target, bindings)?,
+                value: self.lower_expr(value, bindings)?,
+                span: *span,
+            },
+            // A `?` on a `Result<void, E>` leaves nothing but its check
+            TypedStmt::Expr(TypedExpr {
+                kind: TypedExprKind::Try { value, returns },
+                ty: Type::Void,
+            }) => self.lower_try(value, returns, bindings)?.1,
+            TypedStmt::Expr(expr) => TypedStmt::Expr(self.lower_expr(expr, bindings)?),
+            TypedStmt::Return(value) => TypedStmt::Return(match value {
+                Some(value) => Some(self.lower_expr(value, bindings)?),
+                None => None,
+            }),
+            TypedStmt::If {
+                condition,
+                then_body,
+                else_body,
+            } => TypedStmt::If {
+                condition: self.lower_expr(condition, bindings)?,
+                then_body: self.lower_stmts(then_body, bindings)?,
+                else_body: self.lower_stmts(else_body, bindings)?,
+            },
+            TypedStmt::While { condition, body } => TypedStmt::While {
+                condition: self.lower_expr(condition, bindings)?,
+                body: self.lower_stmts(body, bindings)?,
+            },
+            TypedStmt::For {
+                var,
+                iterable,
+                body,
+            } => TypedStmt::For {
+                var: var.clone(),
+                iterable: self.lower_expr(iterable, bindings)?,
+                body: self.lower_stmts(body, bindings)?,
+            },
+            TypedStmt::Drop(place) => TypedStmt::Drop(self.lower_expr(place, bindings)?),
+            TypedStmt::Match { scrutinee, arms } => TypedStmt::Match {
+                scrutinee: self.lower_expr(scrutinee, bindings)?,
+                arms: arms
+                    .iter()
+                    .map(|arm| {
+                        Ok(TypedArm {
+                            body: self.lower_stmts(&arm.body, bindings)?,
+                            ..arm.clone()
+                        })
+                    })
+                    .collect::<Result<_, Diagnostic>>()?,
+            },
+        })
+    }
+
+    // Fills in type arguments, names calls to generic functions after the
+    // instance they need, and lowers operators whose meaning depends on the
+    // type they were instantiated with
+    fn lower_expr(
+        &mut self,
+        expr: &TypedExpr,
+        bindings: &HashMap<String, Type>,
+    ) -> Result<TypedExpr, Diagnostic> {
+        let ty = self.lower_type(&expr.ty, bindings);
+        let kind = match &expr.kind {
+            TypedExprKind::Literal(_) | TypedExprKind::Variable(_) | TypedExprKind::Function(_) => {
+                expr.kind.clone()
+            }
+            TypedExprKind::Binary { op, lhs, rhs, span } => {
+                let lhs = self.lower_expr(lhs, bindings)?;
+                let rhs = self.lower_expr(rhs, bindings)?;
+                if lhs.ty.is_string() {
+                    return sema::string_binary(*op, lhs, rhs, *span);
+                }
+                TypedExprKind::Binary {
+                    op: *op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                    span: *span,
+                }
+            }
+            TypedExprKind::Unary { op, operand, span } => TypedExprKind::Unary {
+                op: *op,
+                operand: Box::new(self.lower_expr(operand, bindings)?),
+                span: *span,
+            },
+            TypedExprKind::Cast(value) => {
+                TypedExprKind::Cast(Box::new(self.lower_expr(value, bindings)?))
+            }
+            TypedExprKind::Call {
+                function,
+                type_args,
+                args,
+                span,
+            } => {
+                let args = self.lower_exprs(args, bindings)?;
+                if type_args.is_empty() {
+                    TypedExprKind::Call {
+                        function: function.clone(),
+                        type_args: Vec::new(),
+                        args,
+                        span: *span,
+                    }
+                } else {
+                    let type_args: Vec<Type> = type_args
+                        .iter()
+                        .map(|arg| self.lower_type(arg, bindings))
+                        .collect();
+                    if type_args.iter().any(|arg| depth(arg) > MAX_TYPE_DEPTH) {
+                        return Err(error(format!(
+                            "Instantiating {function} nests type arguments too deeply"
+                        ))
+                        .with_code(codes::TYPE_TOO_DEEP)
+                        .with_primary(*span, "instantiated here"));
+                    }
+                    let instance = mangle_function(function, &type_args);
+                    if self.instances.insert(instance.clone()) {
+                        self.pending.push((function.clone(), type_args));
+                    }
+                    TypedExprKind::Call {
+                        function: instance,
+                        type_args: Vec::new(),
+                        args,
+                        span: *span,
+                    }
+                }
+            }
+            TypedExprKind::Intrinsic {
+                intrinsic,
+                args,
+                span,
+            } => {
+                let mut args = self.lower_exprs(args, bindings)?;
+                // A `str` looks into a `String` that something has to drop,
+                // so a temporary one is kept in a variable ahead of the
+                // statement, which is dropped with the block's variables
+                if *intrinsic == Intrinsic::StringAsStr && !sema::is_place(&args[0]) {
+                    let mut hoisted = std::mem::take(&mut self.hoisted);
+                    args[0] = self.temp(args[0].clone(), &mut hoisted);
+                    self.hoisted = hoisted;
+                }
+                TypedExprKind::Intrinsic {
+                    intrinsic: *intrinsic,
+                    args,
+                    span: *span,
+                }
+            }
+            TypedExprKind::Io {
+                function,
+                pieces,
+                args,
+            } => TypedExprKind::Io {
+                function: *function,
+                pieces: pieces.clone(),
+                args: self.lower_exprs(args, bindings)?,
+            },
+            TypedExprKind::TraitCall {
+                trait_name,
+                method,
+                args,
+            } => {
+                let args = self.lower_exprs(args, bindings)?;
+                // Only `dyn Trait` receivers are dispatched at run time
+                match &args[0].ty {
+                    Type::Dyn(_) => TypedExprKind::TraitCall {
+                        trait_name: trait_name.clone(),
+                        method: method.clone(),
+                        args,
+                    },
+                    receiver => TypedExprKind::Call {
+                        function: mangle_method(trait_name, receiver, method),
+                        type_args: Vec::new(),
+                        args,
+                        span: Span::default(),
+                    },
+                }
+            }
+            TypedExprKind::ToDyn(value) => {
+                let value = self.lower_expr(value, bindings)?;
+                if let Type::Dyn(trait_name) = &ty {
+                    let vtable = (trait_name.clone(), value.ty.clone());
+                    if !self.vtables.contains(&vtable) {
+                        self.vtables.push(vtable);
+                    }
+                }
+                TypedExprKind::ToDyn(Box::new(value))
+            }
+            TypedExprKind::StructLiteral(fields) => TypedExprKind::StructLiteral(
+                fields
+                    .iter()
+                    .map(|(field, value)| Ok((field.clone(), self.lower_expr(value, bindings)?)))
+                    .collect::<Result<_, Diagnostic>>()?,
+            ),
+            TypedExprKind::Field { receiver, field } => TypedExprKind::Field {
+                receiver: Box::new(self.lower_expr(receiver, bindings)?),
+                field: field.clone(),
+            },
+            TypedExprKind::Closure {
+                params,
+                captures,
+                body,
+            } => {
+                let params: Vec<(String, Type)> = params
+                    .iter()
+                    .map(|(param, ty)| (param.clone(), self.lower_type(ty, bindings)))
+                    .collect();
+                let body = self.lower_stmts(body, bindings)?;
+                TypedExprKind::Closure {
+                    body: self.insert_drops(&params, body),
+                    params,
+                    captures: captures
+                        .iter()
+                        .map(|capture| Capture {
+                            ty: self.lower_type(&capture.ty, bindings),
+                            ..capture.clone()
+                        })
+                        .collect(),
+                }
+            }
+            TypedExprKind::CallValue { callee, args } => TypedExprKind::CallValue {
+                callee: Box::new(self.lower_expr(callee, bindings)?),
+                args: self.lower_exprs(args, bindings)?,
+            },
+            TypedExprKind::ArrayLiteral(elements) => {
+                TypedExprKind::ArrayLiteral(self.lower_exprs(elements, bindings)?)
+            }
+            TypedExprKind::ArrayRepeat(value) => {
+                TypedExprKind::ArrayRepeat(Box::new(self.lower_expr(value, bindings)?))
+            }
+            TypedExprKind::Index { array, index, span } => TypedExprKind::Index {
+                array: Box::new(self.lower_expr(array, bindings)?),
+                index: Box::new(self.lower_expr(index, bindings)?),
+                span: *span,
+            },
+            TypedExprKind::Ref(place) => {
+                TypedExprKind::Ref(Box::new(self.lower_expr(place, bindings)?))
+            }
+            TypedExprKind::Deref(reference) => {
+                TypedExprKind::Deref(Box::new(self.lower_expr(reference, bindings)?))
+            }
+            TypedExprKind::Move(place) => {
+                TypedExprKind::Move(Box::new(self.lower_expr(place, bindings)?))
+            }
+            TypedExprKind::Try { value, returns } => {
+                let (value, check) = self.lower_try(value, returns, bindings)?;
+                self.hoisted.push(check);
+                return Ok(self.take(field(value, "value", ty)));
+            }
+        };
+        Ok(TypedExpr { kind, ty })
+    }
+
+    fn lower_exprs(
+        &mut self,
+        exprs: &[TypedExpr],
+        bindings: &HashMap<String, Type>,
+    ) -> Result<Vec<TypedExpr>, Diagnostic> {
+        exprs
+            .iter()
+            .map(|expr| self.lower_expr(expr, bindings))
+            .collect()
+    }
+
+    // Lowers a function, or the body of a closure, to blocks of instructions
+    fn build_function(
+        &mut self,
+        name: String,
+        source_name: String,
+        params: &[(String, Type)],
+        return_type: &Type,
+        body: &[TypedStmt],
+        captures: Vec<Capture>,
+    ) -> Result<Function, Diagnostic> {
+        let mut builder = Builder {
+            source_name: source_name.clone(),
+            ..Builder::default()
+        };
+        builder.new_block();
+        if !captures.is_empty() {
+            let env_type = Type::Struct(name.clone(), Vec::new());
+            builder.env = Some(builder.reg(Type::Ref(Box::new(env_type), true)));
+        }
+        builder.captures = captures;
+        let outer = std::mem::replace(&mut self.builder, builder);
+        let mut param_regs = Vec::new();
+        for (param, ty) in params {
+            let reg = self.variable_reg(param, ty);
+            param_regs.push(reg);
+            // Shared parameters are moved to the heap
+            if self.shared.contains(param) {
+                let pointer = self.builder.reg(Type::Ref(Box::new(ty.clone()), true));
+                self.builder.emit(Instruction::Alloc { dest: pointer });
+                self.builder.emit(Instruction::Store {
+                    address: Operand::Reg(pointer),
+                    value: Operand::Reg(reg),
+                });
+                self.builder
+                    .variables
+                    .insert(param.clone(), Binding::Shared(pointer));
+            } else {
+                self.builder
+                    .variables
+                    .insert(param.clone(), Binding::Reg(reg));
+            }
+        }
+        let built = self.build_stmts(body);
+        // Sema makes sure a function that returns a value never reaches its end
+        let end = if *return_type == Type::Void {
+            Terminator::Return(None)
+        } else {
+            Terminator::Unreachable
+        };
+        self.builder.terminate(end);
+        let builder = std::mem::replace(&mut self.builder, outer);
+        built?;
+        let mut function = Function {
+            name,
+            source_name,
+            params: param_regs,
+            env: builder.env,
+            return_type: return_type.clone(),
+            regs: builder.regs,
+            blocks: builder.blocks,
+        };
+        function.remove_unreachable_blocks();
+        Ok(function)
+    }
+
+    fn variable_reg(&mut self, name: &str, ty: &Type) -> Reg {
+        self.builder.regs.push(Register {
+            ty: ty.clone(),
+            name: Some(name.to_string()),
+        });
+        self.builder.regs.len() - 1
+    }
+
+    // Declares a variable, on the heap if a closure captures it by reference.
+    // One without a value is zero, so assigning it drops nothing.
+    fn bind(&mut self, name: &str, ty: &Type, value: Option<Operand>) {
+        let value = value.unwrap_or_else(|| {
+            self.builder
+                .define(ty.clone(), |dest| Instruction::Zero { dest })
+        });
+        if self.shared.contains(name) {
+            let pointer = self.builder.reg(Type::Ref(Box::new(ty.clone()), true));
+            self.builder.emit(Instruction::Alloc { dest: pointer });
+            self.builder.emit(Instruction::Store {
+                address: Operand::Reg(pointer),
+                value,
+            });
+            self.builder
+                .variables
+                .insert(name.to_string(), Binding::Shared(pointer));
+        } else {
+            let reg = self.variable_reg(name, ty);
+            self.builder.emit(Instruction::Copy { dest: reg, value });
+            self.builder
+                .variables
+                .insert(name.to_string(), Binding::Reg(reg));
+        }
+    }
+
+    fn build_stmts(&mut self, stmts: &[TypedStmt]) -> Result<(), Diagnostic> {
+        for stmt in stmts {
+            self.build_stmt(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn build_stmt(&mut self, stmt: &TypedStmt) -> Result<(), Diagnostic> {
+        match stmt {
+            TypedStmt::Let {
+                name,
+                var_type,
+                value,
+            } => {
+                let value = match value {
+                    Some(value) => Some(self.build_value(value)?),
+                    None => None,
+                };
+                self.bind(name, var_type, value);
+            }
+            TypedStmt::Assign { target, value } => {
+                let value = self.build_value(value)?;
+                match self.register_of(target) {
+                    Some(dest) => self.builder.emit(Instruction::Copy { dest, value }),
+                    None => {
+                        let address = self.build_address(target)?;
+                        self.builder.emit(Instruction::Store { address, value });
+                    }
+                }
+            }
+            // The target is worked out once, through its address unless it is
+            // a variable in a register
+            TypedStmt::CompoundAssign {
+                op,
+                target,
+                value,
+                span,
+            } => match self.register_of(target) {
+                Some(dest) => {
+                    let value = self.build_value(value)?;
+                    self.builder.emit(Instruction::Binary {
+                        dest,
+                        op: *op,
+                        lhs: Operand::Reg(dest),
+                        rhs: value,
+                        span: *span,
+                    });
+                }
+                None => {
+                    let address = self.build_address(target)?;
+                    let old = self
+                        .builder
+                        .define(target.ty.clone(), |dest| Instruction::Load {
+                            dest,
+                            address: address.clone(),
+                        });
+                    let value = self.build_value(value)?;
+                    let new = self
+                        .builder
+                        .define(target.ty.clone(), |dest| Instruction::Binary {
+                            dest,
+                            op: *op,
+                            lhs: old,
+                            rhs: value,
+                            span: *span,
+                        });
+                    self.builder.emit(Instruction::Store {
+                        address,
+                        value: new,
+                    });
+                }
+            },
+            TypedStmt::Expr(expr) => {
+                self.build_expr(expr)?;
+            }
+            TypedStmt::Return(value) => {
+                let value = match value {
+                    Some(value) => Some(self.build_value(value)?),
+                    None => None,
+                };
+                self.builder.terminate(Terminator::Return(value));
+            }
+            TypedStmt::If {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                let condition = self.build_value(condition)?;
+                let then_block = self.builder.new_block();
+                let else_block = self.builder.new_block();
+                let join = if else_body.is_empty() {
+                    else_block
+                } else {
+                    self.builder.new_block()
+                };
+                self.builder.terminate(Terminator::Branch {
+                    condition,
+                    then_block,
+                    else_block,
+                });
+                self.builder.current = then_block;
+                self.build_stmts(then_body)?;
+                self.builder.terminate(Terminator::Jump(join));
+                if !else_body.is_empty() {
+                    self.builder.current = else_block;
+                    self.build_stmts(else_body)?;
+                    self.builder.terminate(Terminator::Jump(join));
+                }
+                self.builder.current = join;
+            }
+            TypedStmt::While { condition, body } => {
+                let header = self.builder.new_block();
+                self.builder.terminate(Terminator::Jump(header));
+                self.builder.current = header;
+                let condition = self.build_value(condition)?;
+                let body_block = self.builder.new_block();
+                let exit = self.builder.new_block();
+                // Only a return or exit leaves `while true`
+                let terminator = match condition {
+                    Operand::Const(Literal::Bool(true), _) => Terminator::Jump(body_block),
+                    condition => Terminator::Branch {
+                        condition,
+                        then_block: body_block,
+                        else_block: exit,
+                    },
+                };
+                self.builder.terminate(terminator);
+                self.builder.current = body_block;
+                self.build_stmts(body)?;
+                self.builder.terminate(Terminator::Jump(header));
+                self.builder.current = exit;
+            }
+            TypedStmt::For {
+                var,
+                iterable,
+                body,
+            } => self.build_for(var, iterable, body)?,
+            TypedStmt::Match { scrutinee, arms } => {
+                let value = self.build_value(scrutinee)?;
+                let join = self.builder.new_block();
+                let mut cases: Vec<(Literal, BlockId)> = Vec::new();
+                let mut default = join;
+                let mut bodies = Vec::new();
+                for arm in arms {
+                    let block = self.builder.new_block();
+                    for pattern in &arm.patterns {
+                        let TypedExprKind::Literal(literal) = &pattern.kind else {
+                            return Err(error("Match patterns must be literals"));
+                        };
+                        // Only the first arm with a pattern is ever run
+                        if !cases.iter().any(|(case, _)| case == literal) {
+                            cases.push((literal.clone(), block));
+                        }
+                    }
+                    if arm.catch_all {
+                        default = block;
+                    }
+                    bodies.push((block, &arm.body));
+                }
+                self.builder.terminate(Terminator::Switch {
+                    value,
+                    cases,
+                    default,
+                });
+                for (block, body) in bodies {
+                    self.builder.current = block;
+                    self.build_stmts(body)?;
+                    self.builder.terminate(Terminator::Jump(join));
+                }
+                self.builder.current = join;
+            }
+            TypedStmt::Drop(place) => {
+                let address = self.build_address(place)?;
+                self.builder.emit(Instruction::Drop { address });
+            }
+        }
+        Ok(())
+    }
+
+    // Lowers a loop over the elements of a copy of an array, the elements of
+    // a buffer, or the chars of a `str`, counting through them with `pos`
+    fn build_for(
+        &mut self,
+        var: &str,
+        iterable: &TypedExpr,
+        body: &[TypedStmt],
+    ) -> Result<(), Diagnostic> {
+        let mut items = self.build_value(iterable)?;
+        if let (Type::Array(..), Operand::Reg(_)) = (&iterable.ty, &items) {
+            let value = items;
+            items = self
+                .builder
+                .define(iterable.ty.clone(), |dest| Instruction::Copy {
+                    dest,
+                    value,
+                });
+        }
+        let pos = self.builder.reg(Type::U64);
+        self.builder.emit(Instruction::Copy {
+            dest: pos,
+            value: Operand::Const(Literal::Number(0), Type::U64),
+        });
+        let header = self.builder.new_block();
+        self.builder.terminate(Terminator::Jump(header));
+        self.builder.current = header;
+        let (len, element) = match &iterable.ty {
+            Type::Array(element, Length::Known(len)) => (
+                Operand::Const(Literal::Number(*len as i64), Type::U64),
+                (**element).clone(),
+            ),
+            Type::Buffer(element) => {
+                let len = self.intrinsic(Intrinsic::BufferLen, vec![items.clone()], Type::U64);
+                (len, (**element).clone())
+            }
+            Type::Str => {
+                let len = self.intrinsic(Intrinsic::StrLen, vec![items.clone()], Type::U64);
+                (len, Type::Char)
+            }
+            ty => return Err(error(format!("Cannot iterate over {ty}"))),
+        };
+        let more = self.builder.define(Type::Bool, |dest| Instruction::Binary {
+            dest,
+            op: BinaryOp::Less,
+            lhs: Operand::Reg(pos),
+            rhs: len,
+            span: Span::default(),
+        });
+        let body_block = self.builder.new_block();
+        let exit = self.builder.new_block();
+        self.builder.terminate(Terminator::Branch {
+            condition: more,
+            then_block: body_block,
+            else_block: exit,
+        });
+        self.builder.current = body_block;
+        let next = if iterable.ty == Type::Str {
+            let pos = self
+                .builder
+                .define(Type::Ref(Box::new(Type::U64), true), |dest| {
+                    Instruction::AddressOf { dest, reg: pos }
+                });
+            self.builder
+                .define(Type::Char, |dest| Instruction::NextChar {
+                    dest,
+                    text: items,
+                    pos,
+                })
+        } else {
+            let next = self
+                .builder
+                .define(element.clone(), |dest| Instruction::Index {
+                    dest,
+                    array: items,
+                    index: Operand::Reg(pos),
+                    span: Span::default(),
+                });
+            self.builder.emit(Instruction::Intrinsic {
+                dest: Some(pos),
+                intrinsic: Intrinsic::Wrapping(BinaryOp::Add),
+                args: vec![
+                    Operand::Reg(pos),
+                    Operand::Const(Literal::Number(1), Type::U64),
+                ],
+                span: Span::default(),
+            });
+            next
+        };
+        self.bind(var, &element, Some(next));
+        self.build_stmts(body)?;
+        self.builder.terminate(Terminator::Jump(header));
+        self.builder.current = exit;
+        Ok(())
+    }
+
+    fn intrinsic(&mut self, intrinsic: Intrinsic, args: Vec<Operand>, ty: Type) -> Operand {
+        self.builder.define(ty, |dest| Instruction::Intrinsic {
+            dest: Some(dest),
+            intrinsic,
+            args,
+            span: Span::default(),
+        })
+    }
+
+    fn build_value(&mut self, expr: &TypedExpr) -> Result<Operand, Diagnostic> {
+        self.build_expr(expr)?
+            .ok_or_else(|| error(format!("A value of type {} cannot be used", expr.ty)))
+    }
+
+    fn build_values(&mut self, exprs: &[TypedExpr]) -> Result<Vec<Operand>, Diagnostic> {
+        exprs.iter().map(|expr| self.build_value(expr)).collect()
+    }
+
+    // Lowers an expression to the instructions working it out, and the
+    // operand holding its value, if it has one
+    fn build_expr(&mut self, expr: &TypedExpr) -> Result<Option<Operand>, Diagnostic> {
+        let ty = expr.ty.clone();
+        let value = match &expr.kind {
+            TypedExprKind::Literal(literal) => Operand::Const(literal.clone(), ty),
+            TypedExprKind::Variable(name) => match self.builder.variables.get(name) {
+                Some(Binding::Reg(reg)) => Operand::Reg(*reg),
+                _ => {
+                    let address = self.variable_address(name, &ty)?;
+                    self.builder
+                        .define(ty, |dest| Instruction::Load { dest, address })
+                }
+            },
+            // The right side is only worked out if the left does not decide the result
+            TypedExprKind::Binary {
+                op: op @ (BinaryOp::And | BinaryOp::Or),
+                lhs,
+                rhs,
+                ..
+            } => {
+                let result = self.builder.reg(Type::Bool);
+                let lhs = self.build_value(lhs)?;
+                self.builder.emit(Instruction::Copy {
+                    dest: result,
+                    value: lhs.clone(),
+                });
+                let rhs_block = self.builder.new_block();
+                let join = self.builder.new_block();
+                let (then_block, else_block) = if *op == BinaryOp::And {
+                    (rhs_block, join)
+                } else {
+                    (join, rhs_block)
+                };
+                self.builder.terminate(Terminator::Branch {
+                    condition: lhs,
+                    then_block,
+                    else_block,
+                });
+                self.builder.current = rhs_block;
+                let rhs = self.build_value(rhs)?;
+                self.builder.emit(Instruction::Copy {
+                    dest: result,
+                    value: rhs,
+                });
+                self.builder.terminate(Terminator::Jump(join));
+                self.builder.current = join;
+                Operand::Reg(result)
+            }
+            TypedExprKind::Binary { op, lhs, rhs, span } => {
+                let lhs = self.build_value(lhs)?;
+                let rhs = self.build_value(rhs)?;
+                self.builder.define(ty, |dest| Instruction::Binary {
+                    dest,
+                    op: *op,
+                    lhs,
+                    rhs,
+                    span: *span,
+                })
+            }
+            TypedExprKind::Unary { op, operand, span } => {
+                let operand = self.build_value(operand)?;
+                self.builder.define(ty, |dest| Instruction::Unary {
+                    dest,
+                    op: *op,
+                    operand,
+                    span: *span,
+                })
+            }
+            TypedExprKind::Cast(value) => {
+                let value = self.build_value(value)?;
+                self.builder
+                    .define(ty, |dest| Instruction::Cast { dest, value })
+            }
+            TypedExprKind::Call { function, args, .. } => {
+                let args = self.build_values(args)?;
+                let dest = self.builder.result(&ty);
+                self.builder.emit(Instruction::Call {
+                    dest,
+                    function: function.clone(),
+                    args,
+                });
+                return Ok(dest.map(Operand::Reg));
+            }
+            TypedExprKind::Intrinsic {
+                intrinsic,
+                args,
+                span,
+            } => return self.build_intrinsic(*intrinsic, args, &ty, *span),
+            TypedExprKind::Io {
+                function,
+                pieces,
+                args,
+            } => {
+                let args = self.build_values(args)?;
+                self.builder.emit(Instruction::Print {
+                    function: *function,
+                    pieces: pieces.clone(),
+                    args,
+                });
+                return Ok(None);
+            }
+            TypedExprKind::TraitCall {
+                trait_name,
+                method,
+                args,
+            } => {
+                let args = self.build_values(args)?;
+                let dest = self.builder.result(&ty);
+                self.builder.emit(Instruction::CallDyn {
+                    dest,
+                    trait_name: trait_name.clone(),
+                    method: method.clone(),
+                    args,
+                });
+                return Ok(dest.map(Operand::Reg));
+            }
+            TypedExprKind::ToDyn(value) => {
+                let value = self.build_value(value)?;
+                self.builder
+                    .define(ty, |dest| Instruction::ToDyn { dest, value })
+            }
+            TypedExprKind::StructLiteral(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|(field, value)| Ok((field.clone(), self.build_value(value)?)))
+                    .collect::<Result<_, Diagnostic>>()?;
+                self.builder
+                    .define(ty, |dest| Instruction::Struct { dest, fields })
+            }
+            TypedExprKind::Field { receiver, field } => {
+                if self.through_memory(receiver) {
+                    let address = self.build_address(expr)?;
+                    self.builder
+                        .define(ty, |dest| Instruction::Load { dest, address })
+                } else {
+                    let value = self.build_value(receiver)?;
+                    self.builder.define(ty, |dest| Instruction::Field {
+                        dest,
+                        value,
+                        field: field.clone(),
+                    })
+                }
+            }
+            TypedExprKind::Function(name) => self.builder.define(ty, |dest| Instruction::MakeFn {
+                dest,
+                function: name.clone(),
+                env: None,
             }),
-            ASTNode::Import { module, name } => {
-                self.ic.push(ICInstruction::Import {
-                    module: module.clone(),
+            TypedExprKind::Closure {
+                params,
+                captures,
+                body,
+            } => self.build_closure(params, captures, body, &ty)?,
+            TypedExprKind::CallValue { callee, args } => {
+                let Type::Fn(_, return_type) = &callee.ty else {
+                    return Err(error(format!("Cannot call a value of type {}", callee.ty)));
+                };
+                let callee = self.build_value(callee)?;
+                let args = self.build_values(args)?;
+                let dest = self.builder.result(return_type);
+                self.builder
+                    .emit(Instruction::CallValue { dest, callee, args });
+                return Ok(dest.map(Operand::Reg));
+            }
+            TypedExprKind::ArrayLiteral(elements) => {
+                let elements = self.build_values(elements)?;
+                self.builder
+                    .define(ty, |dest| Instruction::Array { dest, elements })
+            }
+            TypedExprKind::ArrayRepeat(value) => {
+                let value = self.build_value(value)?;
+                self.builder
+                    .define(ty, |dest| Instruction::ArrayRepeat { dest, value })
+            }
+            TypedExprKind::Index { array, index, span } => {
+                if !matches!(array.ty, Type::Buffer(_)) && self.through_memory(array) {
+                    let address = self.build_address(expr)?;
+                    self.builder
+                        .define(ty, |dest| Instruction::Load { dest, address })
+                } else {
+                    let array = self.build_value(array)?;
+                    let index = self.build_value(index)?;
+                    self.builder.define(ty, |dest| Instruction::Index {
+                        dest,
+                        array,
+                        index,
+                        span: *span,
+                    })
+                }
+            }
+            TypedExprKind::Ref(place) => self.build_address(place)?,
+            TypedExprKind::Deref(reference) => {
+                let address = self.build_value(reference)?;
+                self.builder
+                    .define(ty, |dest| Instruction::Load { dest, address })
+            }
+            TypedExprKind::Move(place) => {
+                let address = self.build_address(place)?;
+                self.builder
+                    .define(ty, |dest| Instruction::Move { dest, address })
+            }
+            TypedExprKind::Try { .. } => {
+                return Err(error("? must be lowered before code generation"))
+            }
+        };
+        Ok(Some(value))
+    }
+
+    fn build_intrinsic(
+        &mut self,
+        intrinsic: Intrinsic,
+        args: &[TypedExpr],
+        ty: &Type,
+        span: Span,
+    ) -> Result<Option<Operand>, Diagnostic> {
+        let mut operands = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            let changes_receiver = matches!(
+                intrinsic,
+                Intrinsic::StringPushStr
+                    | Intrinsic::StringPushChar
+                    | Intrinsic::BufferPush
+                    | Intrinsic::BufferPop
+            );
+            operands.push(if i == 0 && changes_receiver {
+                self.build_address(arg)?
+            } else {
+                self.build_value(arg)?
+            });
+        }
+        if let Intrinsic::Checked(op) = intrinsic {
+            return self.build_checked(op, operands, ty, span).map(Some);
+        }
+        // The array is still worked out for its side effects
+        if intrinsic == Intrinsic::ArrayLen {
+            let Type::Array(_, Length::Known(len)) = &args[0].ty else {
+                return Err(error(format!("{} has no length", args[0].ty)));
+            };
+            return Ok(Some(Operand::Const(
+                Literal::Number(*len as i64),
+                Type::U64,
+            )));
+        }
+        // A constant exit code is checked at compile time
+        if intrinsic == Intrinsic::Exit && matches!(operands[0], Operand::Reg(_)) {
+            self.build_exit_check(&operands[0], span);
+        }
+        let dest = self.builder.result(ty);
+        self.builder.emit(Instruction::Intrinsic {
+            dest,
+            intrinsic,
+            args: operands,
+            span,
+        });
+        if matches!(intrinsic, Intrinsic::Exit | Intrinsic::Panic) {
+            self.builder.terminate(Terminator::Unreachable);
+        }
+        Ok(dest.map(Operand::Reg))
+    }
+
+    // Panics at `span` unless an exit code is between 0 and 255, which is all
+    // a process can exit with
+    fn build_exit_check(&mut self, code: &Operand, span: Span) {
+        let panic_block = self.builder.new_block();
+        let ok_block = self.builder.new_block();
+        let below = self.compare(BinaryOp::Less, code, 0, span);
+        let upper_block = self.builder.new_block();
+        self.builder.terminate(Terminator::Branch {
+            condition: below,
+            then_block: panic_block,
+            else_block: upper_block,
+        });
+        self.builder.current = upper_block;
+        let above = self.compare(BinaryOp::Greater, code, 255, span);
+        self.builder.terminate(Terminator::Branch {
+            condition: above,
+            then_block: panic_block,
+            else_block: ok_block,
+        });
+        self.builder.current = panic_block;
+        let message = "exit code is not between 0 and 255".to_string();
+        self.builder.emit(Instruction::Intrinsic {
+            dest: None,
+            intrinsic: Intrinsic::Panic,
+            args: vec![Operand::Const(Literal::String(message), Type::Str)],
+            span,
+        });
+        self.builder.terminate(Terminator::Unreachable);
+        self.builder.current = ok_block;
+    }
+
+    // Compares an `i32` with a constant
+    fn compare(&mut self, op: BinaryOp, value: &Operand, bound: i64, span: Span) -> Operand {
+        let lhs = value.clone();
+        let rhs = Operand::Const(Literal::Number(bound), Type::I32);
+        self.builder.define(Type::Bool, |dest| Instruction::Binary {
+            dest,
+            op,
+            lhs,
+            rhs,
+            span,
+        })
+    }
+
+    // Lowers `checked_*`, which gives `None` if the operation would overflow
+    // or divide by zero, and only works it out if it would not
+    fn build_checked(
+        &mut self,
+        op: BinaryOp,
+        operands: Vec<Operand>,
+        ty: &Type,
+        span: Span,
+    ) -> Result<Operand, Diagnostic> {
+        let Type::Option(value_type) = ty else {
+            return Err(error(format!("Checked arithmetic cannot give a {ty}")));
+        };
+        let result = self.builder.reg(ty.clone());
+        let overflows = self.intrinsic(Intrinsic::Overflows(op), operands.clone(), Type::Bool);
+        let none_block = self.builder.new_block();
+        let some_block = self.builder.new_block();
+        let join = self.builder.new_block();
+        self.builder.terminate(Terminator::Branch {
+            condition: overflows,
+            then_block: none_block,
+            else_block: some_block,
+        });
+        let tag = |set| {
+            (
+                sema::tag_field(ty).to_string(),
+                Operand::Const(Literal::Bool(set), Type::Bool),
+            )
+        };
+        self.builder.current = none_block;
+        let none = self.builder.define(ty.clone(), |dest| Instruction::Struct {
+            dest,
+            fields: vec![tag(false)],
+        });
+        self.builder.emit(Instruction::Copy {
+            dest: result,
+            value: none,
+        });
+        self.builder.terminate(Terminator::Jump(join));
+        self.builder.current = some_block;
+        let (lhs, rhs) = (operands[0].clone(), operands[1].clone());
+        let value = self
+            .builder
+            .define((**value_type).clone(), |dest| Instruction::Binary {
+                dest,
+                op,
+                lhs,
+                rhs,
+                span,
+            });
+        let some = self.builder.define(ty.clone(), |dest| Instruction::Struct {
+            dest,
+            fields: vec![tag(true), ("value".to_string(), value)],
+        });
+        self.builder.emit(Instruction::Copy {
+            dest: result,
+            value: some,
+        });
+        self.builder.terminate(Terminator::Jump(join));
+        self.builder.current = join;
+        Ok(Operand::Reg(result))
+    }
+
+    // Lifts a closure out into a function of its own, named with a number.
+    // One that captures variables takes a struct of them on the heap, named
+    // the same, which holds the address of each variable captured by reference.
+    fn build_closure(
+        &mut self,
+        params: &[(String, Type)],
+        captures: &[Capture],
+        body: &[TypedStmt],
+        ty: &Type,
+    ) -> Result<Operand, Diagnostic> {
+        let Type::Fn(_, return_type) = ty else {
+            return Err(error(format!("A closure cannot have type {ty}")));
+        };
+        self.closures += 1;
+        let name = self.closures.to_string();
+        let fields: Vec<(String, Type)> = captures
+            .iter()
+            .map(|capture| {
+                let ty = if capture.by_ref {
+                    Type::Ref(Box::new(capture.ty.clone()), true)
+                } else {
+                    capture.ty.clone()
+                };
+                (capture.name.clone(), ty)
+            })
+            .collect();
+        let env_type = Type::Struct(name.clone(), Vec::new());
+        if !captures.is_empty() {
+            self.structs.insert(
+                name.clone(),
+                TypedStruct {
                     name: name.clone(),
+                    type_params: Vec::new(),
+                    fields: fields.clone(),
+                },
+            );
+            self.use_type(&env_type);
+        }
+        let source_name = format!("{}::{{closure}}", self.builder.source_name);
+        let function = self.build_function(
+            name.clone(),
+            source_name,
+            params,
+            return_type,
+            body,
+            captures.to_vec(),
+        )?;
+        self.ic.push(ICInstruction::Function(function));
+        let env = if captures.is_empty() {
+            None
+        } else {
+            let env = self
+                .builder
+                .define(Type::Ref(Box::new(env_type), true), |dest| {
+                    Instruction::Alloc { dest }
                 });
+            for (capture, (field, field_type)) in captures.iter().zip(fields) {
+                let variable = variable(capture.name.clone(), capture.ty.clone());
+                let value = if capture.by_ref {
+                    self.build_address(&variable)?
+                } else {
+                    self.build_value(&variable)?
+                };
+                let base = env.clone();
+                let address = self
+                    .builder
+                    .define(Type::Ref(Box::new(field_type), true), |dest| {
+                        Instruction::FieldAddress { dest, base, field }
+                    });
+                self.builder.emit(Instruction::Store { address, value });
             }
+            Some(env)
+        };
+        Ok(self.builder.define(ty.clone(), |dest| Instruction::MakeFn {
+            dest,
+            function: name,
+            env,
+        }))
+    }
+
+    // The register a variable is kept in, if the place is one
+    fn register_of(&self, place: &TypedExpr) -> Option<Reg> {
+        let TypedExprKind::Variable(name) = &place.kind else {
+            return None;
+        };
+        match self.builder.variables.get(name) {
+            Some(Binding::Reg(reg)) => Some(*reg),
+            _ => None,
+        }
+    }
+
+    // Whether a place is in memory rather than in a register: behind a
+    // reference, in a shared variable or in a closure's captures
+    fn through_memory(&self, place: &TypedExpr) -> bool {
+        match &place.kind {
+            TypedExprKind::Variable(_) => self.register_of(place).is_none(),
+            TypedExprKind::Field { receiver, .. } => self.through_memory(receiver),
+            TypedExprKind::Index { array, .. } => {
+                !matches!(array.ty, Type::Buffer(_)) && self.through_memory(array)
+            }
+            TypedExprKind::Deref(_) => true,
+            _ => false,
         }
-        Ok(true)
     }
+
+    fn variable_address(&mut self, name: &str, ty: &Type) -> Result<Operand, Diagnostic> {
+        let pointer_type = Type::Ref(Box::new(ty.clone()), true);
+        match self.builder.variables.get(name).copied() {
+            Some(Binding::Reg(reg)) => Ok(self
+                .builder
+                .define(pointer_type, |dest| Instruction::AddressOf { dest, reg })),
+            Some(Binding::Shared(pointer)) => Ok(Operand::Reg(pointer)),
+            None => {
+                let (Some(capture), Some(env)) = (
+                    self.builder
+                        .captures
+                        .iter()
+                        .find(|capture| capture.name == name)
+                        .cloned(),
+                    self.builder.env,
+                ) else {
+                    return Err(error(format!("Unknown variable {name}")));
+                };
+                let field_type = if capture.by_ref {
+                    pointer_type.clone()
+                } else {
+                    ty.clone()
+                };
+                let field = self
+                    .builder
+                    .define(Type::Ref(Box::new(field_type), true), |dest| {
+                        Instruction::FieldAddress {
+                            dest,
+                            base: Operand::Reg(env),
+                            field: name.to_string(),
+                        }
+                    });
+                if !capture.by_ref {
+                    return Ok(field);
+                }
+                Ok(self.builder.define(pointer_type, |dest| Instruction::Load {
+                    dest,
+                    address: field,
+                }))
+            }
+        }
+    }
+
+    // Lowers a place to its address. Anything else is stored in a new
+    // register first.
+    fn build_address(&mut self, place: &TypedExpr) -> Result<Operand, Diagnostic> {
+        let pointer_type = Type::Ref(Box::new(place.ty.clone()), true);
+        match &place.kind {
+            TypedExprKind::Variable(name) => self.variable_address(name, &place.ty),
+            TypedExprKind::Field { receiver, field } => {
+                let base = self.build_address(receiver)?;
+                Ok(self
+                    .builder
+                    .define(pointer_type, |dest| Instruction::FieldAddress {
+                        dest,
+                        base,
+                        field: field.clone(),
+                    }))
+            }
+            TypedExprKind::Index { array, index, span } => {
+                // A buffer is the address of its elements
+                let base = if matches!(array.ty, Type::Buffer(_)) {
+                    self.build_value(array)?
+                } else {
+                    self.build_address(array)?
+                };
+                let index = self.build_value(index)?;
+                Ok(self
+                    .builder
+                    .define(pointer_type, |dest| Instruction::IndexAddress {
+                        dest,
+                        base,
+                        index,
+                        span: *span,
+                    }))
+            }
+            TypedExprKind::Deref(reference) => self.build_value(reference),
+            _ => {
+                let reg = match self.build_value(place)? {
+                    Operand::Reg(reg) => reg,
+                    value => {
+                        let reg = self.builder.reg(place.ty.clone());
+                        self.builder.emit(Instruction::Copy { dest: reg, value });
+                        reg
+                    }
+                };
+                Ok(self
+                    .builder
+                    .define(pointer_type, |dest| Instruction::AddressOf { dest, reg }))
+            }
+        }
+    }
+}
+
+/// Spells a type as part of a C identifier. Struct names are prefixed with
+/// their length and type arguments are wrapped in `I`...`E`; no built-in
+/// type name starts with a digit, so distinct types never mangle the same.
+/// Function types are `F`, their parameters, `E` and their return type, and
+/// array types are `A`, their length, `_` and their element type. References
+/// are `R`, or `M` if mutable, and the type they point to; boxes are `B` and
+/// the type they hold, and buffers `U` and their element type. Options are
+/// `O` and the type they hold, and results `Q`, their value and error types.
+pub fn mangle(ty: &Type) -> String {
+    match ty {
+        Type::Option(value) => format!("O{}", mangle(value)),
+        Type::Result(value, error) => format!("Q{}{}", mangle(value), mangle(error)),
+        Type::Box(inner) => format!("B{}", mangle(inner)),
+        Type::Buffer(element) => format!("U{}", mangle(element)),
+        Type::Ref(inner, false) => format!("R{}", mangle(inner)),
+        Type::Ref(inner, true) => format!("M{}", mangle(inner)),
+        Type::Array(element, len) => format!("A{len}_{}", mangle(element)),
+        Type::Struct(name, args) => mangle_function(name, args),
+        Type::Dyn(name) => format!("D{}{name}", name.len()),
+        Type::Fn(params, return_type) => format!(
+            "F{}E{}",
+            params.iter().map(mangle).collect::<String>(),
+            mangle(return_type)
+        ),
+        ty => ty.to_string(),
+    }
+}
+
+/// Names the method of an impl. Unlike an instance of a generic function,
+/// the trait name is never followed by `I`.
+pub fn mangle_method(trait_name: &str, ty: &Type, method: &str) -> String {
+    format!(
+        "{}{trait_name}{}{}{method}",
+        trait_name.len(),
+        mangle(ty),
+        method.len()
+    )
+}
+
+/// Names an instance of a generic function, or a struct, the same way as `mangle`.
+/// Identifiers cannot start with a digit, so this never clashes with a plain function.
+pub fn mangle_function(name: &str, type_args: &[Type]) -> String {
+    let mut mangled = format!("{}{name}", name.len());
+    if !type_args.is_empty() {
+        mangled.push('I');
+        for arg in type_args {
+            mangled.push_str(&mangle(arg));
+        }
+        mangled.push('E');
+    }
+    mangled
+}
+
+fn depth(ty: &Type) -> usize {
+    match ty {
+        Type::Struct(_, args) => 1 + args.iter().map(depth).max().unwrap_or(0),
+        Type::Array(element, _)
+        | Type::Ref(element, _)
+        | Type::Box(element)
+        | Type::Buffer(element)
+        | Type::Option(element) => 1 + depth(element),
+        Type::Result(value, error) => 1 + depth(value).max(depth(error)),
+        Type::Fn(params, return_type) => {
+            1 + params
+                .iter()
+                .chain([&**return_type])
+                .map(depth)
+                .max()
+                .unwrap_or(0)
+        }
+        _ => 0,
+    }
+}
+
+fn variable(name: String, ty: Type) -> TypedExpr {
+    TypedExpr {
+        kind: TypedExprKind::Variable(name),
+        ty,
+    }
+}
+
+fn field(receiver: TypedExpr, name: &str, ty: Type) -> TypedExpr {
+    TypedExpr {
+        kind: TypedExprKind::Field {
+            receiver: Box::new(receiver),
+            field: name.to_string(),
+        },
+        ty,
+    }
+}
+
+pub fn generate(program: TypedProgram) -> Result<Vec<ICInstruction>, Diagnostic> {
+    Generator::new(program).generate()
 }
 
-pub fn generate(ast: Vec<ASTNode>) -> Result<Vec<ICInstruction>, String> {
-    Generator::new(ast).generate()
+// An error in lowering a program. Most are in programs sema should have
+// rejected, so only the ones a program can run into have a code of their own.
+fn error(message: impl Into<String>) -> Diagnostic {
+    Diagnostic::error(Stage::IcGeneration, message)
 }
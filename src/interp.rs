@@ -0,0 +1,1304 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::io::{self, BufWriter, Stdout, Write};
+use std::ptr;
+use std::rc::Rc;
+use std::thread;
+
+use crate::generator::{mangle_function, mangle_method};
+use crate::lexer::{Length, Literal, Span, Type};
+use crate::options::Options;
+use crate::parser::{BinaryOp, UnaryOp};
+use crate::sema::{
+    self, FormatPiece, Intrinsic, IoFunction, TypedArm, TypedExpr, TypedExprKind, TypedFunction,
+    TypedProgram, TypedStmt,
+};
+
+/// The stack the interpreter runs on. Each call in the program recurses
+/// through several of the interpreter's own functions, so it needs far more
+/// than a compiled program would.
+const STACK_SIZE: usize = 1 << 30;
+
+/// How much of the stack may be used before a call stops the program, as a
+/// compiled program is stopped when it runs out of stack. The rest is left
+/// for the interpreter to get from one call to the next, however deeply the
+/// code between them nests.
+const MAX_STACK: usize = STACK_SIZE - (1 << 26);
+
+/// A variable, or anything else a reference can point to
+type Slot<'a> = Rc<RefCell<Value<'a>>>;
+
+type Bindings = Rc<HashMap<String, Type>>;
+
+/// A value while the program runs. Integers of every type are held as
+/// `i128` and kept within their type's range by the operations on them.
+#[derive(Clone)]
+enum Value<'a> {
+    Void,
+    Int(i128),
+    Bool(bool),
+    Char(char),
+    /// The text between two byte offsets of a buffer
+    Str(Rc<RefCell<String>>, usize, usize),
+    /// The first bytes of a buffer, which is only ever appended to. Copies
+    /// share the buffer until one of them grows.
+    String(Rc<RefCell<String>>, usize),
+    Array(Vec<Value<'a>>),
+    /// A struct, or the struct an `Option` or `Result` is laid out as
+    Struct(Vec<(&'a str, Value<'a>)>),
+    Ref(Place<'a>),
+    Box(Slot<'a>),
+    Buffer(Rc<RefCell<Vec<Value<'a>>>>),
+    Fn(Callable<'a>),
+    /// A `dyn Trait`, with the type of the value it holds
+    Dyn(Rc<(Type, Value<'a>)>),
+}
+
+#[derive(Clone)]
+enum Callable<'a> {
+    Function(&'a str),
+    Closure(Rc<Closure<'a>>),
+}
+
+struct Closure<'a> {
+    /// What backtraces call it, after the function it was made in
+    name: String,
+    params: &'a [(String, Type)],
+    body: &'a [TypedStmt],
+    /// The slot of each variable captured. One captured by reference shares
+    /// the variable's; one captured by value has its own, kept between calls.
+    captures: Vec<(&'a str, Slot<'a>)>,
+    bindings: Bindings,
+}
+
+/// Where a place is: a slot or an element of a buffer, then the fields and
+/// array elements inside it
+#[derive(Clone)]
+struct Place<'a> {
+    root: Root<'a>,
+    path: Vec<Step<'a>>,
+}
+
+#[derive(Clone)]
+enum Root<'a> {
+    Slot(Slot<'a>),
+    Element(Rc<RefCell<Vec<Value<'a>>>>, usize),
+}
+
+#[derive(Clone)]
+enum Step<'a> {
+    Field(&'a str),
+    Index(usize),
+}
+
+impl<'a> Place<'a> {
+    fn slot(slot: Slot<'a>) -> Place<'a> {
+        Place {
+            root: Root::Slot(slot),
+            path: Vec::new(),
+        }
+    }
+}
+
+/// Why the statements being run stopped early
+enum Unwind<'a> {
+    /// A `return`, or a `?` returning the `None` or `Err` it found
+    Return(Value<'a>),
+    /// The program exits with this code, from `exit` or a panic
+    Exit(i32),
+    /// Something semantic analysis should have ruled out
+    Error(String),
+}
+
+type Outcome<'a, T> = Result<T, Unwind<'a>>;
+
+/// How integer arithmetic handles overflow
+#[derive(Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// Panics in debug builds and wraps around in release builds
+    Plain,
+    Checked,
+    Wrapping,
+    Saturating,
+}
+
+/// The variables of a function or closure being run, by unique name
+struct Frame<'a> {
+    vars: HashMap<&'a str, Slot<'a>>,
+    /// The type arguments of the instance of a generic function being run
+    bindings: Bindings,
+}
+
+impl<'a> Frame<'a> {
+    fn slot(&self, name: &str) -> Outcome<'a, Slot<'a>> {
+        self.vars
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Unwind::Error(format!("Unknown variable {name}")))
+    }
+
+    // A type with the type arguments filled in
+    fn ty(&self, ty: &Type) -> Type {
+        if self.bindings.is_empty() {
+            ty.clone()
+        } else {
+            sema::substitute(ty, &self.bindings)
+        }
+    }
+}
+
+struct Interpreter<'a> {
+    /// Every function, with impl methods under their mangled names
+    functions: HashMap<String, &'a TypedFunction>,
+    options: &'a Options,
+    stdout: BufWriter<Stdout>,
+    /// The names of the functions being run, innermost last, as compiled
+    /// programs name them in backtraces
+    calls: Vec<String>,
+    /// Where the interpreter's stack starts, to tell how much of it is used
+    stack_base: usize,
+}
+
+impl<'a> Interpreter<'a> {
+    fn new(program: &'a TypedProgram, options: &'a Options) -> Interpreter<'a> {
+        let mut functions: HashMap<String, &'a TypedFunction> = program
+            .functions
+            .iter()
+            .map(|function| (function.name.clone(), function))
+            .collect();
+        for typed_impl in &program.impls {
+            for method in &typed_impl.methods {
+                functions.insert(
+                    mangle_method(&typed_impl.trait_name, &typed_impl.ty, &method.name),
+                    method,
+                );
+            }
+        }
+        Interpreter {
+            functions,
+            options,
+            stdout: BufWriter::new(io::stdout()),
+            calls: Vec::new(),
+            stack_base: stack_address(),
+        }
+    }
+
+    // Runs `main`, returning the code the program exits with
+    fn run(mut self) -> Result<i32, String> {
+        let main = self.function("main").map_err(unwind_error)?;
+        let code = match self.call(main, "main".to_string(), Rc::default(), Vec::new()) {
+            Ok(result) => self.finish(main, &result),
+            Err(unwind) => Err(unwind),
+        };
+        let code = match code {
+            Ok(code) | Err(Unwind::Exit(code)) => code,
+            Err(unwind) => return Err(unwind_error(unwind)),
+        };
+        self.flush().map_err(unwind_error)?;
+        Ok(code)
+    }
+
+    // A `main` that returns a Result prints the error and fails if there is one
+    fn finish(&mut self, main: &TypedFunction, result: &Value<'a>) -> Outcome<'a, i32> {
+        if !matches!(main.return_type, Type::Result(..)) || is_set(result)? {
+            return Ok(0);
+        }
+        let error = field(result, "error")?.clone();
+        self.print(
+            IoFunction::Eprintln,
+            &[
+                FormatPiece::Text("Error: ".to_string()),
+                FormatPiece::Placeholder,
+            ],
+            &[error],
+        )?;
+        Ok(1)
+    }
+
+    fn function(&self, name: &str) -> Outcome<'a, &'a TypedFunction> {
+        self.functions
+            .get(name)
+            .copied()
+            .ok_or_else(|| Unwind::Error(format!("Unknown function {name}")))
+    }
+
+    fn call(
+        &mut self,
+        function: &'a TypedFunction,
+        name: String,
+        bindings: Bindings,
+        args: Vec<Value<'a>>,
+    ) -> Outcome<'a, Value<'a>> {
+        let vars = function
+            .params
+            .iter()
+            .map(|(param, _)| param.as_str())
+            .zip(args.into_iter().map(slot))
+            .collect();
+        self.enter(name, Frame { vars, bindings }, &function.body)
+    }
+
+    fn call_value(&mut self, callee: Value<'a>, args: Vec<Value<'a>>) -> Outcome<'a, Value<'a>> {
+        match callee {
+            Value::Fn(Callable::Function(name)) => {
+                let function = self.function(name)?;
+                self.call(function, name.to_string(), Rc::default(), args)
+            }
+            Value::Fn(Callable::Closure(closure)) => {
+                let mut vars: HashMap<&'a str, Slot<'a>> =
+                    closure.captures.iter().cloned().collect();
+                vars.extend(
+                    closure
+                        .params
+                        .iter()
+                        .map(|(param, _)| param.as_str())
+                        .zip(args.into_iter().map(slot)),
+                );
+                let frame = Frame {
+                    vars,
+                    bindings: closure.bindings.clone(),
+                };
+                self.enter(closure.name.clone(), frame, closure.body)
+            }
+            _ => Err(Unwind::Error(
+                "Cannot call a value that is not a function".to_string(),
+            )),
+        }
+    }
+
+    // Runs the body of a function, returning what it returns
+    fn enter(
+        &mut self,
+        name: String,
+        mut frame: Frame<'a>,
+        body: &'a [TypedStmt],
+    ) -> Outcome<'a, Value<'a>> {
+        if self.stack_base.abs_diff(stack_address()) > MAX_STACK {
+            return Err(Unwind::Error(
+                "The program overflowed its stack".to_string(),
+            ));
+        }
+        self.calls.push(name);
+        let result = self.exec_block(body, &mut frame);
+        self.calls.pop();
+        match result {
+            Ok(()) => Ok(Value::Void),
+            Err(Unwind::Return(value)) => Ok(value),
+            Err(unwind) => Err(unwind),
+        }
+    }
+
+    fn exec_block(&mut self, stmts: &'a [TypedStmt], frame: &mut Frame<'a>) -> Outcome<'a, ()> {
+        for stmt in stmts {
+            self.exec(stmt, frame)?;
+        }
+        Ok(())
+    }
+
+    fn exec(&mut self, stmt: &'a TypedStmt, frame: &mut Frame<'a>) -> Outcome<'a, ()> {
+        match stmt {
+            // Every `let` makes a new slot, so closures made in a loop each
+            // capture their own variable
+            TypedStmt::Let { name, value, .. } => {
+                let value = match value {
+                    Some(value) => self.value(value, frame)?,
+                    None => Value::Void,
+                };
+                frame.vars.insert(name, slot(value));
+            }
+            TypedStmt::Assign { target, value } => {
+                let value = self.value(value, frame)?;
+                let place = self.place(target, frame)?;
+                access(&place, |slot| *slot = value)?;
+            }
+            TypedStmt::CompoundAssign {
+                op,
+                target,
+                value,
+                span,
+            } => {
+                let place = self.place(target, frame)?;
+                let old = int(&access(&place, |slot| slot.clone())?)?;
+                let value = int(&self.value(value, frame)?)?;
+                let ty = frame.ty(&target.ty);
+                let new = self.arithmetic(*op, old, value, &ty, *span, Overflow::Plain)?;
+                access(&place, |slot| *slot = new)?;
+            }
+            TypedStmt::Expr(expr) => {
+                self.value(expr, frame)?;
+            }
+            TypedStmt::Return(value) => {
+                let value = match value {
+                    Some(value) => self.value(value, frame)?,
+                    None => Value::Void,
+                };
+                return Err(Unwind::Return(value));
+            }
+            TypedStmt::If {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                if self.condition(condition, frame)? {
+                    self.exec_block(then_body, frame)?;
+                } else {
+                    self.exec_block(else_body, frame)?;
+                }
+            }
+            TypedStmt::While { condition, body } => {
+                while self.condition(condition, frame)? {
+                    self.exec_block(body, frame)?;
+                }
+            }
+            TypedStmt::For {
+                var,
+                iterable,
+                body,
+            } => self.exec_for(var, iterable, body, frame)?,
+            TypedStmt::Match { scrutinee, arms } => self.exec_match(scrutinee, arms, frame)?,
+            // Memory is Rust's to free here
+            TypedStmt::Drop(_) => {}
+        }
+        Ok(())
+    }
+
+    // Runs a loop over a copy of an array, the elements of a buffer, which
+    // may grow while it runs, or the chars of a `str`
+    fn exec_for(
+        &mut self,
+        var: &'a str,
+        iterable: &'a TypedExpr,
+        body: &'a [TypedStmt],
+        frame: &mut Frame<'a>,
+    ) -> Outcome<'a, ()> {
+        match self.value(iterable, frame)? {
+            Value::Array(items) => {
+                for item in items {
+                    frame.vars.insert(var, slot(item));
+                    self.exec_block(body, frame)?;
+                }
+            }
+            Value::Buffer(items) => {
+                let mut pos = 0;
+                loop {
+                    let item = items.borrow().get(pos).cloned();
+                    let Some(item) = item else {
+                        break;
+                    };
+                    pos += 1;
+                    frame.vars.insert(var, slot(item));
+                    self.exec_block(body, frame)?;
+                }
+            }
+            Value::Str(buffer, start, end) => {
+                let mut pos = start;
+                while pos < end {
+                    let next = buffer.borrow()[pos..end].chars().next();
+                    let Some(c) = next else {
+                        break;
+                    };
+                    pos += c.len_utf8();
+                    frame.vars.insert(var, slot(Value::Char(c)));
+                    self.exec_block(body, frame)?;
+                }
+            }
+            _ => {
+                return Err(Unwind::Error(format!(
+                    "Cannot iterate over {}",
+                    iterable.ty
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    // Runs the first arm with a pattern equal to the scrutinee, or else the
+    // one with `_`. An `Option` or `Result` is matched on its flag, and an arm
+    // that binds the value inside gets a variable holding it.
+    fn exec_match(
+        &mut self,
+        scrutinee: &'a TypedExpr,
+        arms: &'a [TypedArm],
+        frame: &mut Frame<'a>,
+    ) -> Outcome<'a, ()> {
+        let value = self.value(scrutinee, frame)?;
+        let ty = frame.ty(&scrutinee.ty);
+        let is_enum = matches!(ty, Type::Option(_) | Type::Result(..));
+        let tag = if is_enum {
+            Value::Bool(is_set(&value)?)
+        } else {
+            value.clone()
+        };
+        let mut chosen = None;
+        'arms: for arm in arms {
+            for pattern in &arm.patterns {
+                if compare(&self.value(pattern, frame)?, &tag)? == Ordering::Equal {
+                    chosen = Some(arm);
+                    break 'arms;
+                }
+            }
+        }
+        let Some(arm) = chosen.or_else(|| arms.iter().rev().find(|arm| arm.catch_all)) else {
+            return Ok(());
+        };
+        if let (true, Some(name)) = (is_enum, &arm.binding) {
+            let payload = match arm.patterns.first().map(|pattern| &pattern.kind) {
+                Some(TypedExprKind::Literal(Literal::Bool(true))) => "value",
+                _ => "error",
+            };
+            if let Value::Struct(fields) = &value {
+                if let Some((_, inside)) = fields.iter().find(|(field, _)| *field == payload) {
+                    frame.vars.insert(name, slot(inside.clone()));
+                }
+            }
+        }
+        self.exec_block(&arm.body, frame)
+    }
+
+    fn condition(&mut self, expr: &'a TypedExpr, frame: &mut Frame<'a>) -> Outcome<'a, bool> {
+        match self.value(expr, frame)? {
+            Value::Bool(b) => Ok(b),
+            _ => Err(Unwind::Error("A condition is not a bool".to_string())),
+        }
+    }
+
+    fn values(
+        &mut self,
+        exprs: &'a [TypedExpr],
+        frame: &mut Frame<'a>,
+    ) -> Outcome<'a, Vec<Value<'a>>> {
+        exprs.iter().map(|expr| self.value(expr, frame)).collect()
+    }
+
+    fn value(&mut self, expr: &'a TypedExpr, frame: &mut Frame<'a>) -> Outcome<'a, Value<'a>> {
+        Ok(match &expr.kind {
+            TypedExprKind::Literal(literal) => literal_value(literal, &frame.ty(&expr.ty)),
+            // Only the value read is copied, not what it is part of
+            TypedExprKind::Variable(_)
+            | TypedExprKind::Field { .. }
+            | TypedExprKind::Index { .. }
+            | TypedExprKind::Deref(_) => {
+                let place = self.place(expr, frame)?;
+                access(&place, |value| value.clone())?
+            }
+            TypedExprKind::Move(place) => self.value(place, frame)?,
+            TypedExprKind::Binary {
+                op: op @ (BinaryOp::And | BinaryOp::Or),
+                lhs,
+                rhs,
+                ..
+            } => {
+                let lhs = self.condition(lhs, frame)?;
+                // The right side is only worked out if the left does not decide the result
+                if lhs == (*op == BinaryOp::Or) {
+                    Value::Bool(lhs)
+                } else {
+                    Value::Bool(self.condition(rhs, frame)?)
+                }
+            }
+            TypedExprKind::Binary { op, lhs, rhs, span } => {
+                let ty = frame.ty(&lhs.ty);
+                let lhs = self.value(lhs, frame)?;
+                let rhs = self.value(rhs, frame)?;
+                self.binary(*op, &lhs, &rhs, &ty, *span)?
+            }
+            TypedExprKind::Unary { op, operand, span } => match (op, self.value(operand, frame)?) {
+                (UnaryOp::Not, Value::Bool(b)) => Value::Bool(!b),
+                (UnaryOp::Neg, Value::Int(n)) => {
+                    let ty = frame.ty(&expr.ty);
+                    self.negate(n, &ty, *span)?
+                }
+                _ => {
+                    return Err(Unwind::Error(format!(
+                        "Operator {op:?} is not defined for {}",
+                        operand.ty
+                    )))
+                }
+            },
+            TypedExprKind::Cast(value) => cast(self.value(value, frame)?, &frame.ty(&expr.ty)),
+            TypedExprKind::Call {
+                function,
+                type_args,
+                args,
+                ..
+            } => {
+                let args = self.values(args, frame)?;
+                let callee = self.function(function)?;
+                let type_args: Vec<Type> = type_args.iter().map(|ty| frame.ty(ty)).collect();
+                let name = if type_args.is_empty() {
+                    function.clone()
+                } else {
+                    mangle_function(function, &type_args)
+                };
+                let bindings = callee.type_params.iter().cloned().zip(type_args).collect();
+                self.call(callee, name, Rc::new(bindings), args)?
+            }
+            TypedExprKind::Intrinsic {
+                intrinsic,
+                args,
+                span,
+            } => self.intrinsic(*intrinsic, args, &frame.ty(&expr.ty), *span, frame)?,
+            TypedExprKind::Io {
+                function,
+                pieces,
+                args,
+            } => {
+                let args = self.values(args, frame)?;
+                self.print(*function, pieces, &args)?;
+                Value::Void
+            }
+            // A `dyn Trait` receiver calls the method of the type it holds
+            TypedExprKind::TraitCall {
+                trait_name,
+                method,
+                args,
+            } => {
+                let mut ty = args
+                    .first()
+                    .map(|receiver| frame.ty(&receiver.ty))
+                    .unwrap_or(Type::Void);
+                let mut args = self.values(args, frame)?;
+                if let Some(Value::Dyn(inside)) = args.first().cloned() {
+                    ty = inside.0.clone();
+                    args[0] = inside.1.clone();
+                }
+                let name = mangle_method(trait_name, &ty, method);
+                let function = self.function(&name)?;
+                self.call(function, name, Rc::default(), args)?
+            }
+            TypedExprKind::ToDyn(value) => {
+                let ty = frame.ty(&value.ty);
+                Value::Dyn(Rc::new((ty, self.value(value, frame)?)))
+            }
+            TypedExprKind::StructLiteral(fields) => Value::Struct(
+                fields
+                    .iter()
+                    .map(|(name, value)| Ok((name.as_str(), self.value(value, frame)?)))
+                    .collect::<Outcome<_>>()?,
+            ),
+            TypedExprKind::Function(name) => Value::Fn(Callable::Function(name)),
+            TypedExprKind::Closure {
+                params,
+                captures,
+                body,
+            } => {
+                let captures = captures
+                    .iter()
+                    .map(|capture| {
+                        let variable = frame.slot(&capture.name)?;
+                        let captured = if capture.by_ref {
+                            variable
+                        } else {
+                            let value = variable.borrow().clone();
+                            slot(value)
+                        };
+                        Ok((capture.name.as_str(), captured))
+                    })
+                    .collect::<Outcome<_>>()?;
+                let parent = self.calls.last().map(String::as_str).unwrap_or("main");
+                Value::Fn(Callable::Closure(Rc::new(Closure {
+                    name: format!("{parent}::{{closure}}"),
+                    params,
+                    body,
+                    captures,
+                    bindings: frame.bindings.clone(),
+                })))
+            }
+            TypedExprKind::CallValue { callee, args } => {
+                let callee = self.value(callee, frame)?;
+                let args = self.values(args, frame)?;
+                self.call_value(callee, args)?
+            }
+            TypedExprKind::ArrayLiteral(elements) => Value::Array(self.values(elements, frame)?),
+            TypedExprKind::ArrayRepeat(value) => {
+                let Type::Array(_, Length::Known(len)) = &expr.ty else {
+                    return Err(Unwind::Error(format!(
+                        "{} is not an array of known length",
+                        expr.ty
+                    )));
+                };
+                let value = self.value(value, frame)?;
+                Value::Array(vec![value; *len as usize])
+            }
+            TypedExprKind::Ref(place) => Value::Ref(self.place(place, frame)?),
+            TypedExprKind::Try { value, returns } => {
+                let ty = frame.ty(&value.ty);
+                let value = self.value(value, frame)?;
+                if is_set(&value)? {
+                    return Ok(field(&value, "value").cloned().unwrap_or(Value::Void));
+                }
+                let returns = frame.ty(returns);
+                let mut failure = vec![(sema::tag_field(&returns), Value::Bool(false))];
+                if let Type::Result(..) = ty {
+                    failure.push(("error", field(&value, "error")?.clone()));
+                }
+                return Err(Unwind::Return(Value::Struct(failure)));
+            }
+        })
+    }
+
+    // The place an expression names. One that names no place, such as a
+    // call, is worked out into a slot of its own.
+    fn place(&mut self, expr: &'a TypedExpr, frame: &mut Frame<'a>) -> Outcome<'a, Place<'a>> {
+        match &expr.kind {
+            TypedExprKind::Variable(name) => Ok(Place::slot(frame.slot(name)?)),
+            TypedExprKind::Field { receiver, field } => {
+                let mut place = self.place(receiver, frame)?;
+                place.path.push(Step::Field(field));
+                Ok(place)
+            }
+            TypedExprKind::Index { array, index, span } if matches!(array.ty, Type::Buffer(_)) => {
+                let Value::Buffer(items) = self.value(array, frame)? else {
+                    return Err(Unwind::Error(
+                        "Indexing a buffer that is not one".to_string(),
+                    ));
+                };
+                let index = int(&self.value(index, frame)?)?;
+                let len = items.borrow().len();
+                let index = self.check_index(index, len, *span)?;
+                Ok(Place {
+                    root: Root::Element(items, index),
+                    path: Vec::new(),
+                })
+            }
+            TypedExprKind::Index { array, index, span } => {
+                let mut place = self.place(array, frame)?;
+                let index = int(&self.value(index, frame)?)?;
+                let len = access(&place, |value| match value {
+                    Value::Array(items) => Some(items.len()),
+                    _ => None,
+                })?
+                .ok_or_else(|| Unwind::Error("Indexing an array that is not one".to_string()))?;
+                let index = self.check_index(index, len, *span)?;
+                place.path.push(Step::Index(index));
+                Ok(place)
+            }
+            TypedExprKind::Deref(reference) => match self.value(reference, frame)? {
+                Value::Ref(place) => Ok(place),
+                Value::Box(inside) => Ok(Place::slot(inside)),
+                _ => Err(Unwind::Error(format!(
+                    "Cannot dereference {}",
+                    reference.ty
+                ))),
+            },
+            _ => Ok(Place::slot(slot(self.value(expr, frame)?))),
+        }
+    }
+
+    fn check_index(&mut self, index: i128, len: usize, span: Span) -> Outcome<'a, usize> {
+        match usize::try_from(index) {
+            Ok(index) if index < len => Ok(index),
+            _ => Err(self.panic(
+                span,
+                &format!("index out of bounds: the len is {len} but the index is {index}"),
+            )),
+        }
+    }
+
+    fn intrinsic(
+        &mut self,
+        intrinsic: Intrinsic,
+        args: &'a [TypedExpr],
+        ty: &Type,
+        span: Span,
+        frame: &mut Frame<'a>,
+    ) -> Outcome<'a, Value<'a>> {
+        // These change their receiver, so it is a place
+        if matches!(
+            intrinsic,
+            Intrinsic::StringPushStr
+                | Intrinsic::StringPushChar
+                | Intrinsic::BufferPush
+                | Intrinsic::BufferPop
+        ) {
+            let Some((receiver, args)) = args.split_first() else {
+                return Err(Unwind::Error(format!("{intrinsic:?} needs a receiver")));
+            };
+            let place = self.place(receiver, frame)?;
+            let args = self.values(args, frame)?;
+            return self.change(intrinsic, &place, &args, span);
+        }
+        let receiver_type = args.first().map(|arg| frame.ty(&arg.ty));
+        let args = self.values(args, frame)?;
+        Ok(match (intrinsic, args.as_slice()) {
+            (Intrinsic::Exit, [code]) => {
+                let code = int(code)?;
+                if !(0..=255).contains(&code) {
+                    return Err(self.panic(span, "exit code is not between 0 and 255"));
+                }
+                self.flush()?;
+                return Err(Unwind::Exit(code as i32));
+            }
+            (Intrinsic::Panic, [message]) => return Err(self.panic(span, &text(message)?)),
+            (Intrinsic::StringNew, []) => string(String::new()),
+            (Intrinsic::StringFrom, [s]) => string(text(s)?),
+            (Intrinsic::StringAsStr, [Value::String(buffer, len)]) => {
+                Value::Str(buffer.clone(), 0, *len)
+            }
+            (Intrinsic::StrLen, [s]) => Value::Int(text(s)?.len() as i128),
+            (Intrinsic::StrSlice, [Value::Str(buffer, start, end), from, to]) => {
+                let (from, to) = (int(from)?, int(to)?);
+                let len = (end - start) as i128;
+                if from > to || to > len {
+                    return Err(self.panic(span, "string slice out of bounds"));
+                }
+                let (from, to) = (start + from as usize, start + to as usize);
+                let on_boundary = {
+                    let buffer = buffer.borrow();
+                    let view = &buffer[..*end];
+                    view.is_char_boundary(from) && view.is_char_boundary(to)
+                };
+                if !on_boundary {
+                    return Err(self.panic(span, "string slice is not on a char boundary"));
+                }
+                Value::Str(buffer.clone(), from, to)
+            }
+            (Intrinsic::StrConcat, [a, b]) => string(text(a)? + &text(b)?),
+            (Intrinsic::StrEqual, [a, b]) => Value::Bool(text(a)? == text(b)?),
+            (Intrinsic::StrCompare, [a, b]) => Value::Int(text(a)?.cmp(&text(b)?) as i128),
+            (Intrinsic::StrParseI64, [s]) => match parse_i64(&text(s)?) {
+                Ok(n) => Value::Int(i128::from(n)),
+                Err(message) => return Err(self.panic(span, message)),
+            },
+            (Intrinsic::IntToString, [n]) => string(int(n)?.to_string()),
+            (Intrinsic::CharToString, [Value::Char(c)]) => string(c.to_string()),
+            (Intrinsic::BoxNew, [value]) => Value::Box(slot(value.clone())),
+            (Intrinsic::BufferNew, []) => Value::Buffer(Rc::default()),
+            (Intrinsic::BufferLen, [Value::Buffer(items)]) => {
+                Value::Int(items.borrow().len() as i128)
+            }
+            (Intrinsic::Hash, [value]) => Value::Int(i128::from(hash(value)?)),
+            (Intrinsic::ArrayLen, [Value::Array(items)]) => Value::Int(items.len() as i128),
+            // Overflow and division by zero both give `None`
+            (Intrinsic::Checked(op), [a, b]) => {
+                let range = receiver_type
+                    .as_ref()
+                    .and_then(Type::integer_range)
+                    .ok_or_else(|| Unwind::Error(format!("{intrinsic:?} takes integers")))?;
+                match arithmetic(op, int(a)?, int(b)?, range, Overflow::Checked, false) {
+                    Ok(n) => Value::Struct(vec![
+                        (sema::tag_field(ty), Value::Bool(true)),
+                        ("value", Value::Int(n)),
+                    ]),
+                    Err(_) => Value::Struct(vec![(sema::tag_field(ty), Value::Bool(false))]),
+                }
+            }
+            (Intrinsic::Wrapping(op), [a, b]) => {
+                self.arithmetic(op, int(a)?, int(b)?, ty, span, Overflow::Wrapping)?
+            }
+            (Intrinsic::Saturating(op), [a, b]) => {
+                self.arithmetic(op, int(a)?, int(b)?, ty, span, Overflow::Saturating)?
+            }
+            (Intrinsic::Unwrap, [value]) => {
+                if !is_set(value)? {
+                    let message = match receiver_type {
+                        Some(Type::Result(..)) => "called unwrap on an Err",
+                        _ => "called unwrap on None",
+                    };
+                    return Err(self.panic(span, message));
+                }
+                field(value, "value").cloned().unwrap_or(Value::Void)
+            }
+            (Intrinsic::UnwrapOr, [value, default]) => {
+                if is_set(value)? {
+                    field(value, "value")?.clone()
+                } else {
+                    default.clone()
+                }
+            }
+            _ => {
+                return Err(Unwind::Error(format!(
+                    "{intrinsic:?} cannot take these arguments"
+                )))
+            }
+        })
+    }
+
+    // Runs an intrinsic that changes the place it is given
+    fn change(
+        &mut self,
+        intrinsic: Intrinsic,
+        place: &Place<'a>,
+        args: &[Value<'a>],
+        span: Span,
+    ) -> Outcome<'a, Value<'a>> {
+        match (intrinsic, args) {
+            (Intrinsic::StringPushStr, [s]) => push_str(place, &text(s)?)?,
+            (Intrinsic::StringPushChar, [Value::Char(c)]) => push_str(place, &c.to_string())?,
+            (Intrinsic::BufferPush, [value]) => buffer(place)?.borrow_mut().push(value.clone()),
+            (Intrinsic::BufferPop, []) => {
+                let popped = buffer(place)?.borrow_mut().pop();
+                return match popped {
+                    Some(value) => Ok(value),
+                    None => Err(self.panic(span, "pop from an empty buffer")),
+                };
+            }
+            _ => {
+                return Err(Unwind::Error(format!(
+                    "{intrinsic:?} cannot take these arguments"
+                )))
+            }
+        }
+        Ok(Value::Void)
+    }
+
+    fn binary(
+        &mut self,
+        op: BinaryOp,
+        lhs: &Value<'a>,
+        rhs: &Value<'a>,
+        ty: &Type,
+        span: Span,
+    ) -> Outcome<'a, Value<'a>> {
+        let ordering = match op {
+            BinaryOp::Add if ty.is_string() => return Ok(string(text(lhs)? + &text(rhs)?)),
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => {
+                return self.arithmetic(op, int(lhs)?, int(rhs)?, ty, span, Overflow::Plain);
+            }
+            _ => compare(lhs, rhs)?,
+        };
+        Ok(Value::Bool(match op {
+            BinaryOp::Equal => ordering == Ordering::Equal,
+            BinaryOp::NotEqual => ordering != Ordering::Equal,
+            BinaryOp::Less => ordering == Ordering::Less,
+            BinaryOp::LessEqual => ordering != Ordering::Greater,
+            BinaryOp::Greater => ordering == Ordering::Greater,
+            BinaryOp::GreaterEqual => ordering != Ordering::Less,
+            _ => {
+                return Err(Unwind::Error(format!(
+                    "Operator {op:?} is not defined here"
+                )))
+            }
+        }))
+    }
+
+    // Integer arithmetic in `ty`, panicking with the messages the runtime uses
+    fn arithmetic(
+        &mut self,
+        op: BinaryOp,
+        a: i128,
+        b: i128,
+        ty: &Type,
+        span: Span,
+        overflow: Overflow,
+    ) -> Outcome<'a, Value<'a>> {
+        let range = ty
+            .integer_range()
+            .ok_or_else(|| Unwind::Error(format!("{ty} is not an integer type")))?;
+        if !matches!(
+            op,
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem
+        ) {
+            return Err(Unwind::Error(format!("{op:?} is not arithmetic")));
+        }
+        match arithmetic(op, a, b, range, overflow, self.options.release) {
+            Ok(n) => Ok(Value::Int(n)),
+            Err(message) => Err(self.panic(span, message)),
+        }
+    }
+
+    fn negate(&mut self, n: i128, ty: &Type, span: Span) -> Outcome<'a, Value<'a>> {
+        let range = ty
+            .integer_range()
+            .ok_or_else(|| Unwind::Error(format!("{ty} is not an integer type")))?;
+        match negate(n, range, self.options.release) {
+            Ok(n) => Ok(Value::Int(n)),
+            Err(message) => Err(self.panic(span, message)),
+        }
+    }
+
+    fn print(
+        &mut self,
+        function: IoFunction,
+        pieces: &[FormatPiece],
+        args: &[Value<'a>],
+    ) -> Outcome<'a, ()> {
+        let mut line = String::new();
+        let mut args = args.iter();
+        for piece in pieces {
+            match piece {
+                FormatPiece::Text(text) => line.push_str(text),
+                FormatPiece::Placeholder => {
+                    let arg = args.next().ok_or_else(|| {
+                        Unwind::Error(format!("{function:?} has too few arguments"))
+                    })?;
+                    line.push_str(&display(arg)?);
+                }
+            }
+        }
+        if function != IoFunction::Print {
+            line.push('\n');
+        }
+        if function == IoFunction::Eprintln {
+            self.flush()?;
+            io::stderr().write_all(line.as_bytes())
+        } else {
+            self.stdout.write_all(line.as_bytes())
+        }
+        .map_err(|e| Unwind::Error(format!("Failed to write output: {e}")))
+    }
+
+    fn flush(&mut self) -> Outcome<'a, ()> {
+        self.stdout
+            .flush()
+            .map_err(|e| Unwind::Error(format!("Failed to write output: {e}")))
+    }
+
+    // Reports a panic the way compiled programs do, with a backtrace in
+    // debug builds if `NIMRA_BACKTRACE` asks for one
+    fn panic(&mut self, span: Span, message: &str) -> Unwind<'a> {
+        if let Err(unwind) = self.flush() {
+            return unwind;
+        }
+        let mut report = if span.line == 0 {
+            format!("panicked: {message}\n")
+        } else {
+            format!(
+                "panicked at {}:{}:{}: {message}\n",
+                self.options.input, span.line, span.column
+            )
+        };
+        if !self.options.release {
+            match env::var_os("NIMRA_BACKTRACE") {
+                Some(setting) if setting != "0" => {
+                    report.push_str("stack backtrace:\n");
+                    for (depth, name) in self.calls.iter().rev().enumerate() {
+                        report.push_str(&format!("{depth:4}: {name}\n"));
+                    }
+                }
+                _ => report.push_str("note: run with `NIMRA_BACKTRACE=1` to display a backtrace\n"),
+            }
+        }
+        match io::stderr().write_all(report.as_bytes()) {
+            Ok(()) => Unwind::Exit(101),
+            Err(e) => Unwind::Error(format!("Failed to write output: {e}")),
+        }
+    }
+}
+
+fn slot(value: Value) -> Slot {
+    Rc::new(RefCell::new(value))
+}
+
+fn string<'a>(text: String) -> Value<'a> {
+    let len = text.len();
+    Value::String(Rc::new(RefCell::new(text)), len)
+}
+
+fn unwind_error(unwind: Unwind) -> String {
+    match unwind {
+        Unwind::Error(e) => e,
+        Unwind::Return(_) => "A return escaped its function".to_string(),
+        Unwind::Exit(code) => format!("Exited with {code} while reporting an error"),
+    }
+}
+
+// Runs `f` on the value at a place
+fn access<'a, R>(place: &Place<'a>, f: impl FnOnce(&mut Value<'a>) -> R) -> Outcome<'a, R> {
+    match &place.root {
+        Root::Slot(slot) => Ok(f(walk(&mut slot.borrow_mut(), &place.path)?)),
+        Root::Element(items, index) => {
+            let mut items = items.borrow_mut();
+            let element = items
+                .get_mut(*index)
+                .ok_or_else(|| Unwind::Error("A reference outlived its element".to_string()))?;
+            Ok(f(walk(element, &place.path)?))
+        }
+    }
+}
+
+// Follows a place's path from the value it starts at
+fn walk<'v, 'a>(mut value: &'v mut Value<'a>, path: &[Step<'a>]) -> Outcome<'a, &'v mut Value<'a>> {
+    for step in path {
+        value = match (value, step) {
+            (Value::Struct(fields), Step::Field(name)) => fields
+                .iter_mut()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value),
+            (Value::Array(items), Step::Index(index)) => items.get_mut(*index),
+            _ => None,
+        }
+        .ok_or_else(|| Unwind::Error("A place is not in the value it names".to_string()))?;
+    }
+    Ok(value)
+}
+
+fn buffer<'a>(place: &Place<'a>) -> Outcome<'a, Rc<RefCell<Vec<Value<'a>>>>> {
+    match access(place, |value| value.clone())? {
+        Value::Buffer(items) => Ok(items),
+        _ => Err(Unwind::Error(
+            "Pushing to a buffer that is not one".to_string(),
+        )),
+    }
+}
+
+// Appends to the string at a place, first giving it a buffer of its own if
+// a copy of it has grown the one they shared
+fn push_str<'a>(place: &Place<'a>, text: &str) -> Outcome<'a, ()> {
+    let pushed = access(place, |value| {
+        let Value::String(buffer, len) = value else {
+            return false;
+        };
+        if buffer.borrow().len() != *len {
+            let own = buffer.borrow()[..*len].to_string();
+            *buffer = Rc::new(RefCell::new(own));
+        }
+        buffer.borrow_mut().push_str(text);
+        *len += text.len();
+        true
+    })?;
+    if pushed {
+        Ok(())
+    } else {
+        Err(Unwind::Error(
+            "Pushing to a string that is not one".to_string(),
+        ))
+    }
+}
+
+fn field<'v, 'a>(value: &'v Value<'a>, name: &str) -> Outcome<'a, &'v Value<'a>> {
+    if let Value::Struct(fields) = value {
+        if let Some((_, value)) = fields.iter().find(|(field, _)| *field == name) {
+            return Ok(value);
+        }
+    }
+    Err(Unwind::Error(format!("No field {name}")))
+}
+
+// Whether an `Option` or `Result` holds `Some` or `Ok`; its flag comes first
+fn is_set<'a>(value: &Value<'a>) -> Outcome<'a, bool> {
+    match value {
+        Value::Struct(fields) => match fields.first() {
+            Some((_, Value::Bool(set))) => Ok(*set),
+            _ => Err(Unwind::Error("An enum has no flag".to_string())),
+        },
+        _ => Err(Unwind::Error("Expected an Option or Result".to_string())),
+    }
+}
+
+fn int<'a>(value: &Value<'a>) -> Outcome<'a, i128> {
+    match value {
+        Value::Int(n) => Ok(*n),
+        _ => Err(Unwind::Error("Expected an integer".to_string())),
+    }
+}
+
+fn text<'a>(value: &Value<'a>) -> Outcome<'a, String> {
+    match value {
+        Value::Str(buffer, start, end) => Ok(buffer.borrow()[*start..*end].to_string()),
+        Value::String(buffer, len) => Ok(buffer.borrow()[..*len].to_string()),
+        _ => Err(Unwind::Error("Expected a string".to_string())),
+    }
+}
+
+fn display<'a>(value: &Value<'a>) -> Outcome<'a, String> {
+    match value {
+        Value::Int(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        Value::Char(c) => Ok(c.to_string()),
+        value => text(value),
+    }
+}
+
+fn compare<'a>(a: &Value<'a>, b: &Value<'a>) -> Outcome<'a, Ordering> {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Ok(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Ok(a.cmp(b)),
+        (Value::Char(a), Value::Char(b)) => Ok(a.cmp(b)),
+        (a, b) => Ok(text(a)?.cmp(&text(b)?)),
+    }
+}
+
+fn literal_value<'a>(literal: &Literal, ty: &Type) -> Value<'a> {
+    match literal {
+        // A negative literal of an unsigned type stands for its bits
+        Literal::Number(n) if *n < 0 && ty.is_integer() && !ty.is_signed() => {
+            Value::Int(i128::from(*n as u64))
+        }
+        Literal::Number(n) => Value::Int(i128::from(*n)),
+        Literal::String(s) => Value::Str(Rc::new(RefCell::new(s.clone())), 0, s.len()),
+        Literal::Bool(b) => Value::Bool(*b),
+        Literal::Char(c) => Value::Char(*c),
+    }
+}
+
+fn cast<'a>(value: Value<'a>, target: &Type) -> Value<'a> {
+    let n = match value {
+        Value::Int(n) if *target == Type::Char => return Value::Char(char::from(n as u8)),
+        Value::Int(n) => n,
+        Value::Bool(b) => i128::from(b),
+        Value::Char(c) if target.is_integer() => i128::from(u32::from(c)),
+        value => return value,
+    };
+    match target.integer_range() {
+        Some((min, max)) => Value::Int(wrap(n, min, max)),
+        None => Value::Int(n),
+    }
+}
+
+/// Integer arithmetic on values in the range `min..=max`, or the message
+/// of the panic it causes. The bytecode VM shares it, so that both panic
+/// just like compiled programs.
+pub fn arithmetic(
+    op: BinaryOp,
+    a: i128,
+    b: i128,
+    (min, max): (i128, i128),
+    overflow: Overflow,
+    release: bool,
+) -> Result<i128, &'static str> {
+    let (exact, wrapped, message) = match op {
+        BinaryOp::Add => (
+            a.checked_add(b),
+            a.wrapping_add(b),
+            "attempt to add with overflow",
+        ),
+        BinaryOp::Sub => (
+            a.checked_sub(b),
+            a.wrapping_sub(b),
+            "attempt to subtract with overflow",
+        ),
+        BinaryOp::Mul => (
+            a.checked_mul(b),
+            a.wrapping_mul(b),
+            "attempt to multiply with overflow",
+        ),
+        // Division by zero panics even when overflow wraps around
+        BinaryOp::Div | BinaryOp::Rem => {
+            let (by_zero, overflowed) = if op == BinaryOp::Div {
+                (
+                    "attempt to divide by zero",
+                    "attempt to divide with overflow",
+                )
+            } else {
+                (
+                    "attempt to calculate the remainder with a divisor of zero",
+                    "attempt to calculate the remainder with overflow",
+                )
+            };
+            if b == 0 {
+                return Err(by_zero);
+            }
+            if a == min && b == -1 {
+                return Err(overflowed);
+            }
+            return Ok(if op == BinaryOp::Div { a / b } else { a % b });
+        }
+        _ => return Err("attempt to do arithmetic with a comparison"),
+    };
+    if let Some(n) = exact.filter(|n| (min..=max).contains(n)) {
+        return Ok(n);
+    }
+    match overflow {
+        // Overflow only goes past max when adding a positive number, and so on
+        Overflow::Saturating => {
+            let past_max = match op {
+                BinaryOp::Add => b > 0,
+                BinaryOp::Sub => b <= 0,
+                _ => (a > 0) == (b > 0),
+            };
+            Ok(if past_max { max } else { min })
+        }
+        Overflow::Wrapping => Ok(wrap(wrapped, min, max)),
+        Overflow::Plain if release => Ok(wrap(wrapped, min, max)),
+        Overflow::Plain | Overflow::Checked => Err(message),
+    }
+}
+
+/// Negates a value in the range `min..=max`, which only wraps around in
+/// release builds
+pub fn negate(n: i128, (min, max): (i128, i128), release: bool) -> Result<i128, &'static str> {
+    if (min..=max).contains(&-n) || release {
+        Ok(wrap(-n, min, max))
+    } else {
+        Err("attempt to negate with overflow")
+    }
+}
+
+/// Brings `n` into the range `min..=max`, which spans a power of two, as if
+/// it had been computed in that many bits
+pub fn wrap(n: i128, min: i128, max: i128) -> i128 {
+    n.wrapping_sub(min).rem_euclid(max - min + 1) + min
+}
+
+fn hash<'a>(value: &Value<'a>) -> Outcome<'a, u64> {
+    Ok(match value {
+        Value::Int(n) => hash_u64(*n as u64),
+        Value::Bool(b) => hash_u64(u64::from(*b)),
+        Value::Char(c) => hash_u64(u64::from(u32::from(*c))),
+        value => text(value)?.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        }),
+    })
+}
+
+/// The finalizer of SplitMix64, which the runtime hashes integers with
+pub fn hash_u64(value: u64) -> u64 {
+    let value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
+/// Parses an integer with an optional sign, failing the way the runtime does
+pub fn parse_i64(text: &str) -> Result<i64, &'static str> {
+    let (negative, digits) = match text.as_bytes() {
+        [b'-', digits @ ..] => (true, digits),
+        [b'+', digits @ ..] => (false, digits),
+        digits => (false, digits),
+    };
+    if digits.is_empty() {
+        return Err("cannot parse an empty string as an integer");
+    }
+    let mut magnitude: i128 = 0;
+    for digit in digits {
+        if !digit.is_ascii_digit() {
+            return Err("invalid digit in integer");
+        }
+        magnitude = magnitude * 10 + i128::from(digit - b'0');
+        if magnitude > 1 << 63 {
+            return Err("integer does not fit in i64");
+        }
+    }
+    let n = if negative { -magnitude } else { magnitude };
+    i64::try_from(n).map_err(|_| "integer does not fit in i64")
+}
+
+// An address on the stack, just below the frame of the function calling this
+fn stack_address() -> usize {
+    let marker = 0u8;
+    ptr::addr_of!(marker) as usize
+}
+
+/// Runs a checked program directly, without compiling it, and returns the
+/// code it exits with. It prints, panics and exits just like the program
+/// would once compiled.
+pub fn run(program: &TypedProgram, options: &Options) -> Result<i32, String> {
+    thread::scope(|scope| {
+        thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(scope, || Interpreter::new(program, options).run())
+            .map_err(|e| format!("Failed to start the interpreter: {e}"))?
+            .join()
+            .map_err(|_| "The interpreter crashed".to_string())?
+    })
+}
@@ -0,0 +1,619 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use crate::lexer::{Literal, Span, Type};
+use crate::parser::{BinaryOp, UnaryOp};
+use crate::sema::{self, FormatPiece, Intrinsic, IoFunction, TraitMethod};
+
+/// A virtual register, the index of its `Register` in its function
+pub type Reg = usize;
+
+/// A basic block, its index in its function
+pub type BlockId = usize;
+
+#[derive(Debug, Clone)]
+pub struct Register {
+    pub ty: Type,
+    /// The variable the register holds, or None for a temporary
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Reg(Reg),
+    Const(Literal, Type),
+}
+
+/// An instruction, which writes its result to `dest` if it has one. Addresses
+/// are registers of reference types.
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    Copy {
+        dest: Reg,
+        value: Operand,
+    },
+    /// Sets a register to the zero value of its type, which owns nothing
+    Zero {
+        dest: Reg,
+    },
+    /// Never `&&` or `||`, which are lowered to branches
+    Binary {
+        dest: Reg,
+        op: BinaryOp,
+        lhs: Operand,
+        rhs: Operand,
+        span: Span,
+    },
+    Unary {
+        dest: Reg,
+        op: UnaryOp,
+        operand: Operand,
+        span: Span,
+    },
+    Cast {
+        dest: Reg,
+        value: Operand,
+    },
+    Call {
+        dest: Option<Reg>,
+        function: String,
+        args: Vec<Operand>,
+    },
+    /// A call through a value of a function type
+    CallValue {
+        dest: Option<Reg>,
+        callee: Operand,
+        args: Vec<Operand>,
+    },
+    /// A call through the vtable of a `dyn Trait`; `args` starts with the receiver
+    CallDyn {
+        dest: Option<Reg>,
+        trait_name: String,
+        method: String,
+        args: Vec<Operand>,
+    },
+    /// The first argument of `StringPushStr`, `StringPushChar`, `BufferPush`
+    /// and `BufferPop` is the address of the value they change
+    Intrinsic {
+        dest: Option<Reg>,
+        intrinsic: Intrinsic,
+        args: Vec<Operand>,
+        span: Span,
+    },
+    Print {
+        function: IoFunction,
+        pieces: Vec<FormatPiece>,
+        args: Vec<Operand>,
+    },
+    /// Turns a value into a `dyn Trait` of the type of `dest`
+    ToDyn {
+        dest: Reg,
+        value: Operand,
+    },
+    /// A value of a function type calling `function`, which is a closure
+    /// taking `env` before its arguments if there is one
+    MakeFn {
+        dest: Reg,
+        function: String,
+        env: Option<Operand>,
+    },
+    /// A struct, `Option` or `Result` made of the values of its fields
+    Struct {
+        dest: Reg,
+        fields: Vec<(String, Operand)>,
+    },
+    Array {
+        dest: Reg,
+        elements: Vec<Operand>,
+    },
+    /// An array with every element set to `value`
+    ArrayRepeat {
+        dest: Reg,
+        value: Operand,
+    },
+    Field {
+        dest: Reg,
+        value: Operand,
+        field: String,
+    },
+    /// An element of an array or buffer, which panics at `span` if `index`
+    /// is out of bounds
+    Index {
+        dest: Reg,
+        array: Operand,
+        index: Operand,
+        span: Span,
+    },
+    AddressOf {
+        dest: Reg,
+        reg: Reg,
+    },
+    FieldAddress {
+        dest: Reg,
+        base: Operand,
+        field: String,
+    },
+    /// The address of an element of the array `base` points to, or of the
+    /// buffer `base`; panics at `span` if `index` is out of bounds
+    IndexAddress {
+        dest: Reg,
+        base: Operand,
+        index: Operand,
+        span: Span,
+    },
+    Load {
+        dest: Reg,
+        address: Operand,
+    },
+    Store {
+        address: Operand,
+        value: Operand,
+    },
+    /// Allocates room on the heap for a value of the type `dest` points to
+    Alloc {
+        dest: Reg,
+    },
+    /// Reads a value that owns heap memory, leaving its place empty
+    Move {
+        dest: Reg,
+        address: Operand,
+    },
+    /// Frees the heap memory the value at `address` owns
+    Drop {
+        address: Operand,
+    },
+    /// Decodes the char of `text` at the offset `pos` points to, and moves
+    /// the offset past it
+    NextChar {
+        dest: Reg,
+        text: Operand,
+        pos: Operand,
+    },
+    /// The value from whichever predecessor the block was entered from. Phis
+    /// only exist in SSA form, at the start of a block.
+    Phi {
+        dest: Reg,
+        incoming: Vec<(BlockId, Operand)>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch {
+        condition: Operand,
+        then_block: BlockId,
+        else_block: BlockId,
+    },
+    /// Jumps to the block of the first case equal to `value`, or to `default`
+    Switch {
+        value: Operand,
+        cases: Vec<(Literal, BlockId)>,
+        default: BlockId,
+    },
+    Return(Option<Operand>),
+    /// Ends a block that is never left, such as one that exits or panics
+    Unreachable,
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub instructions: Vec<Instruction>,
+    pub terminator: Terminator,
+}
+
+/// A function, or a closure lifted out of one, in the intermediate code the
+/// generator lowers to: three-address instructions on typed virtual
+/// registers, in basic blocks that each end in an explicit terminator. The
+/// body starts at the first block.
+#[derive(Debug, Clone)]
+pub struct Function {
+    /// Closures are named with a number, which no other function is
+    pub name: String,
+    /// What backtraces call the function; a closure is called after the
+    /// function it is in
+    pub source_name: String,
+    pub params: Vec<Reg>,
+    /// A closure that captures variables takes a pointer to a struct of them
+    /// before its parameters
+    pub env: Option<Reg>,
+    pub return_type: Type,
+    pub regs: Vec<Register>,
+    pub blocks: Vec<Block>,
+}
+
+impl Instruction {
+    pub fn dest(&self) -> Option<Reg> {
+        match self {
+            Instruction::Copy { dest, .. }
+            | Instruction::Zero { dest }
+            | Instruction::Binary { dest, .. }
+            | Instruction::Unary { dest, .. }
+            | Instruction::Cast { dest, .. }
+            | Instruction::ToDyn { dest, .. }
+            | Instruction::MakeFn { dest, .. }
+            | Instruction::Struct { dest, .. }
+            | Instruction::Array { dest, .. }
+            | Instruction::ArrayRepeat { dest, .. }
+            | Instruction::Field { dest, .. }
+            | Instruction::Index { dest, .. }
+            | Instruction::AddressOf { dest, .. }
+            | Instruction::FieldAddress { dest, .. }
+            | Instruction::IndexAddress { dest, .. }
+            | Instruction::Load { dest, .. }
+            | Instruction::Alloc { dest }
+            | Instruction::Move { dest, .. }
+            | Instruction::NextChar { dest, .. }
+            | Instruction::Phi { dest, .. } => Some(*dest),
+            Instruction::Call { dest, .. }
+            | Instruction::CallValue { dest, .. }
+            | Instruction::CallDyn { dest, .. }
+            | Instruction::Intrinsic { dest, .. } => *dest,
+            Instruction::Print { .. } | Instruction::Store { .. } | Instruction::Drop { .. } => {
+                None
+            }
+        }
+    }
+
+    /// Where the instruction panics from, or no place for one that cannot
+    pub fn span(&self) -> Span {
+        match self {
+            Instruction::Binary { span, .. }
+            | Instruction::Unary { span, .. }
+            | Instruction::Intrinsic { span, .. }
+            | Instruction::Index { span, .. }
+            | Instruction::IndexAddress { span, .. } => *span,
+            _ => Span::default(),
+        }
+    }
+
+    pub fn dest_mut(&mut self) -> Option<&mut Reg> {
+        match self {
+            Instruction::Copy { dest, .. }
+            | Instruction::Zero { dest }
+            | Instruction::Binary { dest, .. }
+            | Instruction::Unary { dest, .. }
+            | Instruction::Cast { dest, .. }
+            | Instruction::ToDyn { dest, .. }
+            | Instruction::MakeFn { dest, .. }
+            | Instruction::Struct { dest, .. }
+            | Instruction::Array { dest, .. }
+            | Instruction::ArrayRepeat { dest, .. }
+            | Instruction::Field { dest, .. }
+            | Instruction::Index { dest, .. }
+            | Instruction::AddressOf { dest, .. }
+            | Instruction::FieldAddress { dest, .. }
+            | Instruction::IndexAddress { dest, .. }
+            | Instruction::Load { dest, .. }
+            | Instruction::Alloc { dest }
+            | Instruction::Move { dest, .. }
+            | Instruction::NextChar { dest, .. }
+            | Instruction::Phi { dest, .. } => Some(dest),
+            Instruction::Call { dest, .. }
+            | Instruction::CallValue { dest, .. }
+            | Instruction::CallDyn { dest, .. }
+            | Instruction::Intrinsic { dest, .. } => dest.as_mut(),
+            Instruction::Print { .. } | Instruction::Store { .. } | Instruction::Drop { .. } => {
+                None
+            }
+        }
+    }
+
+    /// The operands the instruction reads. The register of an `AddressOf`
+    /// is not one, since its value is not read.
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Instruction::Zero { .. }
+            | Instruction::AddressOf { .. }
+            | Instruction::Alloc { .. } => Vec::new(),
+            Instruction::Copy { value, .. }
+            | Instruction::Unary { operand: value, .. }
+            | Instruction::Cast { value, .. }
+            | Instruction::ToDyn { value, .. }
+            | Instruction::ArrayRepeat { value, .. }
+            | Instruction::Field { value, .. }
+            | Instruction::FieldAddress { base: value, .. }
+            | Instruction::Load { address: value, .. }
+            | Instruction::Move { address: value, .. }
+            | Instruction::Drop { address: value } => vec![value],
+            Instruction::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Instruction::Index { array, index, .. }
+            | Instruction::IndexAddress {
+                base: array, index, ..
+            } => vec![array, index],
+            Instruction::Store { address, value } => vec![address, value],
+            Instruction::NextChar { text, pos, .. } => vec![text, pos],
+            Instruction::Call { args, .. }
+            | Instruction::CallDyn { args, .. }
+            | Instruction::Intrinsic { args, .. }
+            | Instruction::Print { args, .. }
+            | Instruction::Array { elements: args, .. } => args.iter().collect(),
+            Instruction::CallValue { callee, args, .. } => {
+                std::iter::once(callee).chain(args.iter()).collect()
+            }
+            Instruction::MakeFn { env, .. } => env.iter().collect(),
+            Instruction::Struct { fields, .. } => fields.iter().map(|(_, value)| value).collect(),
+            Instruction::Phi { incoming, .. } => incoming.iter().map(|(_, value)| value).collect(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Instruction::Zero { .. }
+            | Instruction::AddressOf { .. }
+            | Instruction::Alloc { .. } => Vec::new(),
+            Instruction::Copy { value, .. }
+            | Instruction::Unary { operand: value, .. }
+            | Instruction::Cast { value, .. }
+            | Instruction::ToDyn { value, .. }
+            | Instruction::ArrayRepeat { value, .. }
+            | Instruction::Field { value, .. }
+            | Instruction::FieldAddress { base: value, .. }
+            | Instruction::Load { address: value, .. }
+            | Instruction::Move { address: value, .. }
+            | Instruction::Drop { address: value } => vec![value],
+            Instruction::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Instruction::Index { array, index, .. }
+            | Instruction::IndexAddress {
+                base: array, index, ..
+            } => vec![array, index],
+            Instruction::Store { address, value } => vec![address, value],
+            Instruction::NextChar { text, pos, .. } => vec![text, pos],
+            Instruction::Call { args, .. }
+            | Instruction::CallDyn { args, .. }
+            | Instruction::Intrinsic { args, .. }
+            | Instruction::Print { args, .. }
+            | Instruction::Array { elements: args, .. } => args.iter_mut().collect(),
+            Instruction::CallValue { callee, args, .. } => {
+                std::iter::once(callee).chain(args.iter_mut()).collect()
+            }
+            Instruction::MakeFn { env, .. } => env.iter_mut().collect(),
+            Instruction::Struct { fields, .. } => {
+                fields.iter_mut().map(|(_, value)| value).collect()
+            }
+            Instruction::Phi { incoming, .. } => {
+                incoming.iter_mut().map(|(_, value)| value).collect()
+            }
+        }
+    }
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => vec![*then_block, *else_block],
+            Terminator::Switch { cases, default, .. } => cases
+                .iter()
+                .map(|(_, target)| *target)
+                .chain(std::iter::once(*default))
+                .collect(),
+            Terminator::Return(_) | Terminator::Unreachable => Vec::new(),
+        }
+    }
+
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Terminator::Branch { condition, .. } => vec![condition],
+            Terminator::Switch { value, .. } => vec![value],
+            Terminator::Return(value) => value.iter().collect(),
+            Terminator::Jump(_) | Terminator::Unreachable => Vec::new(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Terminator::Branch { condition, .. } => vec![condition],
+            Terminator::Switch { value, .. } => vec![value],
+            Terminator::Return(value) => value.iter_mut().collect(),
+            Terminator::Jump(_) | Terminator::Unreachable => Vec::new(),
+        }
+    }
+
+    pub fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => vec![then_block, else_block],
+            Terminator::Switch { cases, default, .. } => cases
+                .iter_mut()
+                .map(|(_, target)| target)
+                .chain(std::iter::once(default))
+                .collect(),
+            Terminator::Return(_) | Terminator::Unreachable => Vec::new(),
+        }
+    }
+}
+
+impl Function {
+    pub fn operand_type(&self, operand: &Operand) -> Type {
+        match operand {
+            Operand::Reg(reg) => self.regs[*reg].ty.clone(),
+            Operand::Const(_, ty) => ty.clone(),
+        }
+    }
+
+    /// The blocks each block can be entered from, each listed once
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut predecessors = vec![Vec::new(); self.blocks.len()];
+        for (id, block) in self.blocks.iter().enumerate() {
+            for target in block.terminator.successors() {
+                if !predecessors[target].contains(&id) {
+                    predecessors[target].push(id);
+                }
+            }
+        }
+        predecessors
+    }
+
+    /// Removes the blocks no path from the first block reaches, keeping the
+    /// others in order, and the phi operands that came from them
+    pub fn remove_unreachable_blocks(&mut self) {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut stack = vec![0];
+        while let Some(block) = stack.pop() {
+            if !std::mem::replace(&mut reachable[block], true) {
+                stack.extend(self.blocks[block].terminator.successors());
+            }
+        }
+        let mut renumbered = Vec::new();
+        let mut count = 0;
+        for &kept in &reachable {
+            renumbered.push(count);
+            count += usize::from(kept);
+        }
+        let blocks = std::mem::take(&mut self.blocks);
+        self.blocks = blocks
+            .into_iter()
+            .zip(&reachable)
+            .filter(|(_, kept)| **kept)
+            .map(|(mut block, _)| {
+                for target in block.terminator.successors_mut() {
+                    *target = renumbered[*target];
+                }
+                for instruction in &mut block.instructions {
+                    if let Instruction::Phi { incoming, .. } = instruction {
+                        incoming.retain(|(from, _)| reachable[*from]);
+                        for (from, _) in incoming {
+                            *from = renumbered[*from];
+                        }
+                    }
+                }
+                block
+            })
+            .collect();
+    }
+}
+
+/// The function a backend's program starts in, which calls main, and for a
+/// main that returns a Result, reports the error and fails if there is one
+pub fn entry(return_type: &Type) -> Function {
+    let mut regs = vec![register(return_type.clone())];
+    let call = Instruction::Call {
+        dest: (*return_type != Type::Void).then_some(0),
+        function: "main".to_string(),
+        args: Vec::new(),
+    };
+    let exit_code =
+        |code| Terminator::Return(Some(Operand::Const(Literal::Number(code), Type::I32)));
+    let blocks = match return_type {
+        Type::Result(_, error) => {
+            regs.push(register(Type::Bool));
+            regs.push(register((**error).clone()));
+            vec![
+                Block {
+                    instructions: vec![
+                        call,
+                        Instruction::Field {
+                            dest: 1,
+                            value: Operand::Reg(0),
+                            field: sema::tag_field(return_type).to_string(),
+                        },
+                    ],
+                    terminator: Terminator::Branch {
+                        condition: Operand::Reg(1),
+                        then_block: 1,
+                        else_block: 2,
+                    },
+                },
+                Block {
+                    instructions: Vec::new(),
+                    terminator: exit_code(0),
+                },
+                Block {
+                    instructions: vec![
+                        Instruction::Field {
+                            dest: 2,
+                            value: Operand::Reg(0),
+                            field: "error".to_string(),
+                        },
+                        Instruction::Print {
+                            function: IoFunction::Eprintln,
+                            pieces: vec![
+                                FormatPiece::Text("Error: ".to_string()),
+                                FormatPiece::Placeholder,
+                            ],
+                            args: vec![Operand::Reg(2)],
+                        },
+                    ],
+                    terminator: exit_code(1),
+                },
+            ]
+        }
+        _ => vec![Block {
+            instructions: vec![call],
+            terminator: exit_code(0),
+        }],
+    };
+    Function {
+        name: "nrt_main".to_string(),
+        source_name: "main".to_string(),
+        params: Vec::new(),
+        env: None,
+        return_type: Type::I32,
+        regs,
+        blocks,
+    }
+}
+
+/// The function a vtable points to for a method, which loads the value its
+/// first argument points to and calls the method with it
+pub fn thunk(ty: &Type, method: &TraitMethod, function: &str) -> Function {
+    let mut regs = vec![register(Type::Ref(Box::new(ty.clone()), false))];
+    regs.extend(method.params.iter().cloned().map(register));
+    let params = (0..regs.len()).collect::<Vec<_>>();
+    let value = regs.len();
+    regs.push(register(ty.clone()));
+    let result = (method.return_type != Type::Void).then_some(regs.len());
+    regs.push(register(method.return_type.clone()));
+    let args = std::iter::once(Operand::Reg(value))
+        .chain(params[1..].iter().map(|&param| Operand::Reg(param)))
+        .collect();
+    Function {
+        name: format!("th_{function}"),
+        source_name: format!("th_{function}"),
+        params,
+        env: None,
+        return_type: method.return_type.clone(),
+        regs,
+        blocks: vec![Block {
+            instructions: vec![
+                Instruction::Load {
+                    dest: value,
+                    address: Operand::Reg(0),
+                },
+                Instruction::Call {
+                    dest: result,
+                    function: function.to_string(),
+                    args,
+                },
+            ],
+            terminator: Terminator::Return(result.map(Operand::Reg)),
+        }],
+    }
+}
+
+fn register(ty: Type) -> Register {
+    Register { ty, name: None }
+}
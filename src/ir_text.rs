@@ -0,0 +1,1239 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use crate::generator::ICInstruction;
+use crate::ir::{Block, BlockId, Function, Instruction, Operand, Reg, Register, Terminator};
+use crate::lexer::{Length, Literal, Span, Type};
+use crate::parser::{BinaryOp, UnaryOp};
+use crate::sema::{FormatPiece, Intrinsic, IoFunction, TraitMethod};
+
+const BINARY_OPS: [(BinaryOp, &str); 13] = [
+    (BinaryOp::Add, "add"),
+    (BinaryOp::Sub, "sub"),
+    (BinaryOp::Mul, "mul"),
+    (BinaryOp::Div, "div"),
+    (BinaryOp::Rem, "rem"),
+    (BinaryOp::Equal, "eq"),
+    (BinaryOp::NotEqual, "ne"),
+    (BinaryOp::Less, "lt"),
+    (BinaryOp::LessEqual, "le"),
+    (BinaryOp::Greater, "gt"),
+    (BinaryOp::GreaterEqual, "ge"),
+    (BinaryOp::And, "and"),
+    (BinaryOp::Or, "or"),
+];
+
+const INTRINSICS: [(Intrinsic, &str); 24] = [
+    (Intrinsic::Exit, "exit"),
+    (Intrinsic::Panic, "panic"),
+    (Intrinsic::StringNew, "string_new"),
+    (Intrinsic::StringFrom, "string_from"),
+    (Intrinsic::StringAsStr, "string_as_str"),
+    (Intrinsic::StringPushStr, "string_push_str"),
+    (Intrinsic::StringPushChar, "string_push_char"),
+    (Intrinsic::StrLen, "str_len"),
+    (Intrinsic::StrSlice, "str_slice"),
+    (Intrinsic::StrConcat, "str_concat"),
+    (Intrinsic::StrEqual, "str_equal"),
+    (Intrinsic::StrCompare, "str_compare"),
+    (Intrinsic::StrParseI64, "str_parse_i64"),
+    (Intrinsic::IntToString, "int_to_string"),
+    (Intrinsic::BoxNew, "box_new"),
+    (Intrinsic::BufferNew, "buffer_new"),
+    (Intrinsic::BufferLen, "buffer_len"),
+    (Intrinsic::BufferPush, "buffer_push"),
+    (Intrinsic::BufferPop, "buffer_pop"),
+    (Intrinsic::Hash, "hash"),
+    (Intrinsic::CharToString, "char_to_string"),
+    (Intrinsic::ArrayLen, "array_len"),
+    (Intrinsic::Unwrap, "unwrap"),
+    (Intrinsic::UnwrapOr, "unwrap_or"),
+];
+
+const IO_FUNCTIONS: [(IoFunction, &str); 3] = [
+    (IoFunction::Print, "print"),
+    (IoFunction::Println, "println"),
+    (IoFunction::Eprintln, "eprintln"),
+];
+
+fn binary_op_name(op: BinaryOp) -> &'static str {
+    BINARY_OPS
+        .iter()
+        .find(|(candidate, _)| *candidate == op)
+        .map_or("?", |(_, name)| name)
+}
+
+fn intrinsic_name(intrinsic: Intrinsic) -> String {
+    let arithmetic = match intrinsic {
+        Intrinsic::Wrapping(op) => Some(("wrapping", op)),
+        Intrinsic::Overflows(op) => Some(("overflows", op)),
+        Intrinsic::Saturating(op) => Some(("saturating", op)),
+        _ => None,
+    };
+    if let Some((overflow, op)) = arithmetic {
+        return format!("{overflow}_{}", binary_op_name(op));
+    }
+    INTRINSICS
+        .iter()
+        .find(|(candidate, _)| *candidate == intrinsic)
+        .map_or("?".to_string(), |(_, name)| name.to_string())
+}
+
+/// Writes a program's intermediate code as text, in the form `parse` reads
+pub fn print(ic: &[ICInstruction]) -> String {
+    let mut text = String::new();
+    for instruction in ic {
+        match instruction {
+            ICInstruction::TraitDecl { name, methods } => {
+                text.push_str(&format!("trait {name} {{{}}}\n", methods_text(methods)));
+            }
+            ICInstruction::StructDecl { name, fields } => {
+                let fields = fields
+                    .iter()
+                    .map(|(field, ty)| format!("{field}: {ty}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                text.push_str(&format!("struct {name} {{{fields}}}\n"));
+            }
+            ICInstruction::ArrayType { element, len } => {
+                text.push_str(&format!("array [{element}; {len}]\n"));
+            }
+            ICInstruction::FnType {
+                params,
+                return_type,
+            } => {
+                let ty = Type::Fn(params.clone(), Box::new(return_type.clone()));
+                text.push_str(&format!("fn_type {ty}\n"));
+            }
+            ICInstruction::Vtable {
+                trait_name,
+                ty,
+                methods,
+            } => {
+                text.push_str(&format!(
+                    "vtable {trait_name} for {ty} {{{}}}\n",
+                    methods_text(methods)
+                ));
+            }
+            ICInstruction::OwnedType(ty) => text.push_str(&format!("owned {ty}\n")),
+            ICInstruction::Function(function) => text.push_str(&function_text(function)),
+        }
+    }
+    text
+}
+
+fn methods_text(methods: &[TraitMethod]) -> String {
+    methods
+        .iter()
+        .map(|method| {
+            let params = method
+                .params
+                .iter()
+                .map(|param| param.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("{}({params}) -> {}; ", method.name, method.return_type)
+        })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+// Writes one function: its registers, then its blocks
+fn function_text(function: &Function) -> String {
+    let mut params: Vec<String> = function
+        .params
+        .iter()
+        .map(|reg| format!("%{reg}"))
+        .collect();
+    if let Some(env) = function.env {
+        params.insert(0, format!("env %{env}"));
+    }
+    let mut text = format!(
+        "fn {} {}({}) -> {} {{\n",
+        function.name,
+        quote(&function.source_name, '"'),
+        params.join(", "),
+        function.return_type
+    );
+    for (reg, register) in function.regs.iter().enumerate() {
+        match &register.name {
+            Some(name) => text.push_str(&format!("    var %{reg} {name}: {}\n", register.ty)),
+            None => text.push_str(&format!("    var %{reg}: {}\n", register.ty)),
+        }
+    }
+    for (id, block) in function.blocks.iter().enumerate() {
+        text.push_str(&format!("b{id}:\n"));
+        for instruction in &block.instructions {
+            text.push_str(&format!("    {}\n", instruction_text(instruction)));
+        }
+        text.push_str(&format!("    {}\n", terminator_text(&block.terminator)));
+    }
+    text.push_str("}\n");
+    text
+}
+
+fn operand_text(operand: &Operand) -> String {
+    match operand {
+        Operand::Reg(reg) => format!("%{reg}"),
+        Operand::Const(literal, ty) => format!("{ty} {}", literal_text(literal)),
+    }
+}
+
+fn operands_text(operands: &[Operand]) -> String {
+    operands
+        .iter()
+        .map(operand_text)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn literal_text(literal: &Literal) -> String {
+    match literal {
+        Literal::Number(n) => n.to_string(),
+        Literal::Bool(b) => b.to_string(),
+        Literal::Char(c) => quote(&c.to_string(), '\''),
+        Literal::String(s) => quote(s, '"'),
+    }
+}
+
+// Quotes text the way Nimra does, escaping the quote, backslashes and
+// anything that is not printable ASCII
+fn quote(text: &str, quote: char) -> String {
+    let mut quoted = quote.to_string();
+    for c in text.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\\' => quoted.push_str("\\\\"),
+            c if c == quote => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            ' '..='~' => quoted.push(c),
+            c => quoted.push_str(&format!("\\u{{{:x}}}", u32::from(c))),
+        }
+    }
+    quoted.push(quote);
+    quoted
+}
+
+fn span_text(span: Span) -> String {
+    format!("@{}:{}", span.line, span.column)
+}
+
+fn instruction_text(instruction: &Instruction) -> String {
+    let text = match instruction {
+        Instruction::Copy { value, .. } => format!("copy {}", operand_text(value)),
+        Instruction::Zero { .. } => "zero".to_string(),
+        Instruction::Binary {
+            op, lhs, rhs, span, ..
+        } => format!(
+            "{} {}, {} {}",
+            binary_op_name(*op),
+            operand_text(lhs),
+            operand_text(rhs),
+            span_text(*span)
+        ),
+        Instruction::Unary {
+            op, operand, span, ..
+        } => {
+            let op = match op {
+                UnaryOp::Neg => "neg",
+                UnaryOp::Not => "not",
+            };
+            format!("{op} {} {}", operand_text(operand), span_text(*span))
+        }
+        Instruction::Cast { value, .. } => format!("cast {}", operand_text(value)),
+        Instruction::Call { function, args, .. } => {
+            format!("call {function}({})", operands_text(args))
+        }
+        Instruction::CallValue { callee, args, .. } => format!(
+            "call_value {}({})",
+            operand_text(callee),
+            operands_text(args)
+        ),
+        Instruction::CallDyn {
+            trait_name,
+            method,
+            args,
+            ..
+        } => format!("call_dyn {trait_name}.{method}({})", operands_text(args)),
+        Instruction::Intrinsic {
+            intrinsic,
+            args,
+            span,
+            ..
+        } => format!(
+            "intrinsic {}({}) {}",
+            intrinsic_name(*intrinsic),
+            operands_text(args),
+            span_text(*span)
+        ),
+        Instruction::Print {
+            function,
+            pieces,
+            args,
+        } => {
+            let function = IO_FUNCTIONS
+                .iter()
+                .find(|(candidate, _)| candidate == function)
+                .map_or("?", |(_, name)| name);
+            let format = pieces
+                .iter()
+                .map(|piece| match piece {
+                    FormatPiece::Text(text) => text.replace('{', "{{").replace('}', "}}"),
+                    FormatPiece::Placeholder => "{}".to_string(),
+                })
+                .collect::<String>();
+            format!(
+                "print {function} {}({})",
+                quote(&format, '"'),
+                operands_text(args)
+            )
+        }
+        Instruction::ToDyn { value, .. } => format!("to_dyn {}", operand_text(value)),
+        Instruction::MakeFn {
+            function,
+            env: None,
+            ..
+        } => format!("make_fn {function}"),
+        Instruction::MakeFn {
+            function,
+            env: Some(env),
+            ..
+        } => format!("make_fn {function}, {}", operand_text(env)),
+        Instruction::Struct { fields, .. } => {
+            let fields = fields
+                .iter()
+                .map(|(field, value)| format!("{field}: {}", operand_text(value)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("struct {{{fields}}}")
+        }
+        Instruction::Array { elements, .. } => format!("array [{}]", operands_text(elements)),
+        Instruction::ArrayRepeat { value, .. } => format!("array_repeat {}", operand_text(value)),
+        Instruction::Field { value, field, .. } => {
+            format!("field {}, {field}", operand_text(value))
+        }
+        Instruction::Index {
+            array, index, span, ..
+        } => format!(
+            "index {}, {} {}",
+            operand_text(array),
+            operand_text(index),
+            span_text(*span)
+        ),
+        Instruction::AddressOf { reg, .. } => format!("address_of %{reg}"),
+        Instruction::FieldAddress { base, field, .. } => {
+            format!("field_address {}, {field}", operand_text(base))
+        }
+        Instruction::IndexAddress {
+            base, index, span, ..
+        } => format!(
+            "index_address {}, {} {}",
+            operand_text(base),
+            operand_text(index),
+            span_text(*span)
+        ),
+        Instruction::Load { address, .. } => format!("load {}", operand_text(address)),
+        Instruction::Store { address, value } => {
+            format!("store {}, {}", operand_text(address), operand_text(value))
+        }
+        Instruction::Alloc { .. } => "alloc".to_string(),
+        Instruction::Move { address, .. } => format!("move {}", operand_text(address)),
+        Instruction::Drop { address } => format!("drop {}", operand_text(address)),
+        Instruction::NextChar { text, pos, .. } => {
+            format!("next_char {}, {}", operand_text(text), operand_text(pos))
+        }
+        Instruction::Phi { incoming, .. } => {
+            let incoming = incoming
+                .iter()
+                .map(|(from, value)| format!("b{from}: {}", operand_text(value)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("phi [{incoming}]")
+        }
+    };
+    match instruction.dest() {
+        Some(dest) => format!("%{dest} = {text}"),
+        None => text,
+    }
+}
+
+fn terminator_text(terminator: &Terminator) -> String {
+    match terminator {
+        Terminator::Jump(target) => format!("jump b{target}"),
+        Terminator::Branch {
+            condition,
+            then_block,
+            else_block,
+        } => format!(
+            "branch {}, b{then_block}, b{else_block}",
+            operand_text(condition)
+        ),
+        Terminator::Switch {
+            value,
+            cases,
+            default,
+        } => {
+            let cases = cases
+                .iter()
+                .map(|(literal, target)| format!("{}: b{target}", literal_text(literal)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("switch {}, [{cases}], b{default}", operand_text(value))
+        }
+        Terminator::Return(None) => "return".to_string(),
+        Terminator::Return(Some(value)) => format!("return {}", operand_text(value)),
+        Terminator::Unreachable => "unreachable".to_string(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// A name, keyword or unsigned number; mangled names start with digits
+    Word(String),
+    Reg(Reg),
+    Str(String),
+    Char(char),
+    Arrow,
+    Punct(char),
+}
+
+// Splits IR text into tokens, each with the line it is on. `//` starts a
+// comment that runs to the end of the line.
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.peek().is_some_and(|c| *c != '\n') {
+                    chars.next();
+                }
+            }
+            '-' if chars.peek() == Some(&'>') => {
+                chars.next();
+                tokens.push((Token::Arrow, line));
+            }
+            '%' => {
+                let mut digits = String::new();
+                while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                    digits.push(*c);
+                    chars.next();
+                }
+                let reg = digits
+                    .parse()
+                    .map_err(|_| format!("Line {line}: Expected a register number after %"))?;
+                tokens.push((Token::Reg(reg), line));
+            }
+            '"' | '\'' => {
+                let mut text = String::new();
+                loop {
+                    let Some(next) = chars.next() else {
+                        return Err(format!("Line {line}: Unterminated quote"));
+                    };
+                    match next {
+                        '\\' => match chars.next() {
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            Some('u') => {
+                                let mut hex = String::new();
+                                if chars.next() != Some('{') {
+                                    return Err(format!("Line {line}: Expected {{ after \\u"));
+                                }
+                                for c in chars.by_ref() {
+                                    if c == '}' {
+                                        break;
+                                    }
+                                    hex.push(c);
+                                }
+                                let c = u32::from_str_radix(&hex, 16)
+                                    .ok()
+                                    .and_then(char::from_u32)
+                                    .ok_or(format!("Line {line}: Bad escape \\u{{{hex}}}"))?;
+                                text.push(c);
+                            }
+                            Some(c) => text.push(c),
+                            None => return Err(format!("Line {line}: Unterminated quote")),
+                        },
+                        quote if quote == c => break,
+                        c => text.push(c),
+                    }
+                }
+                if c == '"' {
+                    tokens.push((Token::Str(text), line));
+                } else {
+                    let mut chars = text.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => tokens.push((Token::Char(c), line)),
+                        _ => return Err(format!("Line {line}: A char holds one character")),
+                    }
+                }
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some(c) = chars
+                    .peek()
+                    .filter(|c| c.is_ascii_alphanumeric() || **c == '_')
+                {
+                    word.push(*c);
+                    chars.next();
+                }
+                tokens.push((Token::Word(word), line));
+            }
+            '(' | ')' | '{' | '}' | '[' | ']' | '<' | '>' | ',' | ':' | ';' | '=' | '&' | '@'
+            | '.' | '-' => tokens.push((Token::Punct(c), line)),
+            c => return Err(format!("Line {line}: Unexpected character {c:?}")),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Reads a program's intermediate code from the text `print` writes
+pub fn parse(text: &str) -> Result<Vec<ICInstruction>, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+    };
+    let mut ic = Vec::new();
+    while parser.pos < parser.tokens.len() {
+        ic.push(parser.item()?);
+    }
+    Ok(ic)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn error(&self, message: &str) -> String {
+        match self.tokens.get(self.pos) {
+            Some((token, line)) => format!("Line {line}: {message}, found {token:?}"),
+            None => format!("{message}, found the end of the text"),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn is_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(found)) if found == word)
+    }
+
+    fn is_punct(&self, c: char) -> bool {
+        self.peek() == Some(&Token::Punct(c))
+    }
+
+    fn expect_punct(&mut self, c: char) -> Result<(), String> {
+        if !self.is_punct(c) {
+            return Err(self.error(&format!("Expected {c:?}")));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn eat_punct(&mut self, c: char) -> bool {
+        let found = self.is_punct(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_word(&mut self, word: &str) -> Result<(), String> {
+        if !self.is_word(word) {
+            return Err(self.error(&format!("Expected {word}")));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn word(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Word(word)) => {
+                let word = word.clone();
+                self.pos += 1;
+                Ok(word)
+            }
+            _ => Err(self.error("Expected a name")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Str(text)) => {
+                let text = text.clone();
+                self.pos += 1;
+                Ok(text)
+            }
+            _ => Err(self.error("Expected a string")),
+        }
+    }
+
+    fn reg(&mut self) -> Result<Reg, String> {
+        match self.peek() {
+            Some(Token::Reg(reg)) => {
+                let reg = *reg;
+                self.pos += 1;
+                Ok(reg)
+            }
+            _ => Err(self.error("Expected a register")),
+        }
+    }
+
+    fn number(&mut self) -> Result<i64, String> {
+        let negative = self.eat_punct('-');
+        let word = self.word()?;
+        // Reading as u64 covers `i64::MIN`, whose magnitude is past `i64::MAX`
+        let n: u64 = word.parse().map_err(|_| format!("Bad number {word}"))?;
+        let n = n as i64;
+        Ok(if negative { n.wrapping_neg() } else { n })
+    }
+
+    fn block_id(&mut self) -> Result<BlockId, String> {
+        let word = self.word()?;
+        word.strip_prefix('b')
+            .and_then(|id| id.parse().ok())
+            .ok_or(format!("Expected a block such as b0, found {word}"))
+    }
+
+    fn span(&mut self) -> Result<Span, String> {
+        self.expect_punct('@')?;
+        let line = self.number()? as usize;
+        self.expect_punct(':')?;
+        let column = self.number()? as usize;
+        Ok(Span { line, column })
+    }
+
+    // Reads items separated by commas until the closing character
+    fn list<T>(
+        &mut self,
+        close: char,
+        mut item: impl FnMut(&mut Self) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let mut items = Vec::new();
+        while !self.eat_punct(close) {
+            if !items.is_empty() {
+                self.expect_punct(',')?;
+            }
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn item(&mut self) -> Result<ICInstruction, String> {
+        let keyword = self.word()?;
+        match keyword.as_str() {
+            "trait" => {
+                let name = self.word()?;
+                self.expect_punct('{')?;
+                let methods = self.methods()?;
+                Ok(ICInstruction::TraitDecl { name, methods })
+            }
+            "struct" => {
+                let name = self.word()?;
+                self.expect_punct('{')?;
+                let fields = self.list('}', |parser| {
+                    let field = parser.word()?;
+                    parser.expect_punct(':')?;
+                    Ok((field, parser.ty()?))
+                })?;
+                Ok(ICInstruction::StructDecl { name, fields })
+            }
+            "array" => match self.ty()? {
+                Type::Array(element, Length::Known(len)) => Ok(ICInstruction::ArrayType {
+                    element: *element,
+                    len,
+                }),
+                ty => Err(format!(
+                    "Expected an array type of known length, found {ty}"
+                )),
+            },
+            "fn_type" => match self.ty()? {
+                Type::Fn(params, return_type) => Ok(ICInstruction::FnType {
+                    params,
+                    return_type: *return_type,
+                }),
+                ty => Err(format!("Expected a function type, found {ty}")),
+            },
+            "vtable" => {
+                let trait_name = self.word()?;
+                self.expect_word("for")?;
+                let ty = self.ty()?;
+                self.expect_punct('{')?;
+                let methods = self.methods()?;
+                Ok(ICInstruction::Vtable {
+                    trait_name,
+                    ty,
+                    methods,
+                })
+            }
+            "owned" => Ok(ICInstruction::OwnedType(self.ty()?)),
+            "fn" => Ok(ICInstruction::Function(self.function()?)),
+            _ => {
+                self.pos -= 1;
+                Err(self.error("Expected a declaration or function"))
+            }
+        }
+    }
+
+    // Reads `name(params) -> type;` methods up to the closing brace
+    fn methods(&mut self) -> Result<Vec<TraitMethod>, String> {
+        let mut methods = Vec::new();
+        while !self.eat_punct('}') {
+            let name = self.word()?;
+            self.expect_punct('(')?;
+            let params = self.list(')', Self::ty)?;
+            if self.next() != Some(Token::Arrow) {
+                self.pos -= 1;
+                return Err(self.error("Expected ->"));
+            }
+            let return_type = self.ty()?;
+            self.expect_punct(';')?;
+            methods.push(TraitMethod {
+                name,
+                params,
+                return_type,
+            });
+        }
+        Ok(methods)
+    }
+
+    fn ty(&mut self) -> Result<Type, String> {
+        if self.eat_punct('&') {
+            let mutable = self.is_word("mut");
+            if mutable {
+                self.pos += 1;
+            }
+            return Ok(Type::Ref(Box::new(self.ty()?), mutable));
+        }
+        if self.eat_punct('[') {
+            let element = self.ty()?;
+            self.expect_punct(';')?;
+            let len = self.word()?;
+            self.expect_punct(']')?;
+            let len = match len.parse() {
+                Ok(len) => Length::Known(len),
+                Err(_) => Length::Const(len),
+            };
+            return Ok(Type::Array(Box::new(element), len));
+        }
+        let name = self.word()?;
+        let ty = match name.as_str() {
+            "void" => Type::Void,
+            "bool" => Type::Bool,
+            "char" => Type::Char,
+            "i8" => Type::I8,
+            "i16" => Type::I16,
+            "i32" => Type::I32,
+            "i64" => Type::I64,
+            "u8" => Type::U8,
+            "u16" => Type::U16,
+            "u32" => Type::U32,
+            "u64" => Type::U64,
+            "str" => Type::Str,
+            "String" => Type::String,
+            "dyn" => Type::Dyn(self.word()?),
+            "fn" => {
+                self.expect_punct('(')?;
+                let params = self.list(')', Self::ty)?;
+                let return_type = if self.peek() == Some(&Token::Arrow) {
+                    self.pos += 1;
+                    self.ty()?
+                } else {
+                    Type::Void
+                };
+                Type::Fn(params, Box::new(return_type))
+            }
+            _ => {
+                let args = if self.eat_punct('<') {
+                    self.list('>', Self::ty)?
+                } else {
+                    Vec::new()
+                };
+                let arg = |i: usize| Box::new(args.get(i).cloned().unwrap_or(Type::Void));
+                match (name.as_str(), args.len()) {
+                    ("Box", 1) => Type::Box(arg(0)),
+                    ("Buffer", 1) => Type::Buffer(arg(0)),
+                    ("Option", 1) => Type::Option(arg(0)),
+                    ("Result", 2) => Type::Result(arg(0), arg(1)),
+                    _ => Type::Struct(name, args),
+                }
+            }
+        };
+        Ok(ty)
+    }
+
+    fn literal(&mut self) -> Result<Literal, String> {
+        match self.peek() {
+            Some(Token::Str(text)) => {
+                let text = text.clone();
+                self.pos += 1;
+                Ok(Literal::String(text))
+            }
+            Some(Token::Char(c)) => {
+                let c = *c;
+                self.pos += 1;
+                Ok(Literal::Char(c))
+            }
+            Some(Token::Word(word)) if word == "true" || word == "false" => {
+                let b = word == "true";
+                self.pos += 1;
+                Ok(Literal::Bool(b))
+            }
+            _ => Ok(Literal::Number(self.number()?)),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        if let Some(Token::Reg(_)) = self.peek() {
+            return Ok(Operand::Reg(self.reg()?));
+        }
+        let ty = self.ty()?;
+        Ok(Operand::Const(self.literal()?, ty))
+    }
+
+    fn operands(&mut self, close: char) -> Result<Vec<Operand>, String> {
+        self.list(close, Self::operand)
+    }
+
+    fn function(&mut self) -> Result<Function, String> {
+        let name = self.word()?;
+        let source_name = self.string()?;
+        self.expect_punct('(')?;
+        let mut env = None;
+        let mut params = Vec::new();
+        while !self.eat_punct(')') {
+            if env.is_some() || !params.is_empty() {
+                self.expect_punct(',')?;
+            }
+            if self.is_word("env") {
+                self.pos += 1;
+                env = Some(self.reg()?);
+            } else {
+                params.push(self.reg()?);
+            }
+        }
+        if self.next() != Some(Token::Arrow) {
+            self.pos -= 1;
+            return Err(self.error("Expected ->"));
+        }
+        let return_type = self.ty()?;
+        self.expect_punct('{')?;
+        let mut regs = Vec::new();
+        while self.is_word("var") {
+            self.pos += 1;
+            let reg = self.reg()?;
+            if reg != regs.len() {
+                return Err(format!(
+                    "Expected register %{} next, found %{reg}",
+                    regs.len()
+                ));
+            }
+            let name = match self.peek() {
+                Some(Token::Word(_)) => Some(self.word()?),
+                _ => None,
+            };
+            self.expect_punct(':')?;
+            let ty = self.ty()?;
+            regs.push(Register { ty, name });
+        }
+        let mut blocks = Vec::new();
+        while !self.eat_punct('}') {
+            let id = self.block_id()?;
+            if id != blocks.len() {
+                return Err(format!(
+                    "Expected block b{} next, found b{id}",
+                    blocks.len()
+                ));
+            }
+            self.expect_punct(':')?;
+            blocks.push(self.block()?);
+        }
+        Ok(Function {
+            name,
+            source_name,
+            params,
+            env,
+            return_type,
+            regs,
+            blocks,
+        })
+    }
+
+    // Reads instructions up to the terminator that ends the block
+    fn block(&mut self) -> Result<Block, String> {
+        let mut instructions = Vec::new();
+        loop {
+            let terminator = match self.peek() {
+                Some(Token::Word(word)) => match word.as_str() {
+                    "jump" | "branch" | "switch" | "return" | "unreachable" => self.terminator()?,
+                    _ => {
+                        instructions.push(self.instruction(None)?);
+                        continue;
+                    }
+                },
+                Some(Token::Reg(_)) => {
+                    let dest = self.reg()?;
+                    self.expect_punct('=')?;
+                    instructions.push(self.instruction(Some(dest))?);
+                    continue;
+                }
+                _ => return Err(self.error("Expected an instruction or terminator")),
+            };
+            return Ok(Block {
+                instructions,
+                terminator,
+            });
+        }
+    }
+
+    fn terminator(&mut self) -> Result<Terminator, String> {
+        let keyword = self.word()?;
+        Ok(match keyword.as_str() {
+            "jump" => Terminator::Jump(self.block_id()?),
+            "branch" => {
+                let condition = self.operand()?;
+                self.expect_punct(',')?;
+                let then_block = self.block_id()?;
+                self.expect_punct(',')?;
+                let else_block = self.block_id()?;
+                Terminator::Branch {
+                    condition,
+                    then_block,
+                    else_block,
+                }
+            }
+            "switch" => {
+                let value = self.operand()?;
+                self.expect_punct(',')?;
+                self.expect_punct('[')?;
+                let cases = self.list(']', |parser| {
+                    let literal = parser.literal()?;
+                    parser.expect_punct(':')?;
+                    Ok((literal, parser.block_id()?))
+                })?;
+                self.expect_punct(',')?;
+                let default = self.block_id()?;
+                Terminator::Switch {
+                    value,
+                    cases,
+                    default,
+                }
+            }
+            // A value follows unless the block or function ends
+            "return" => match self.peek() {
+                Some(Token::Punct('}')) => Terminator::Return(None),
+                Some(Token::Word(word))
+                    if word
+                        .strip_prefix('b')
+                        .is_some_and(|id| id.parse::<usize>().is_ok()) =>
+                {
+                    Terminator::Return(None)
+                }
+                _ => Terminator::Return(Some(self.operand()?)),
+            },
+            _ => Terminator::Unreachable,
+        })
+    }
+
+    fn dest(&self, dest: Option<Reg>, opcode: &str) -> Result<Reg, String> {
+        dest.ok_or(format!("{opcode} needs a register to write"))
+    }
+
+    fn instruction(&mut self, dest: Option<Reg>) -> Result<Instruction, String> {
+        let opcode = self.word()?;
+        if let Some((op, _)) = BINARY_OPS.iter().find(|(_, name)| *name == opcode) {
+            let lhs = self.operand()?;
+            self.expect_punct(',')?;
+            let rhs = self.operand()?;
+            return Ok(Instruction::Binary {
+                dest: self.dest(dest, &opcode)?,
+                op: *op,
+                lhs,
+                rhs,
+                span: self.span()?,
+            });
+        }
+        let instruction = match opcode.as_str() {
+            "copy" => Instruction::Copy {
+                dest: self.dest(dest, &opcode)?,
+                value: self.operand()?,
+            },
+            "zero" => Instruction::Zero {
+                dest: self.dest(dest, &opcode)?,
+            },
+            "neg" | "not" => Instruction::Unary {
+                dest: self.dest(dest, &opcode)?,
+                op: if opcode == "neg" {
+                    UnaryOp::Neg
+                } else {
+                    UnaryOp::Not
+                },
+                operand: self.operand()?,
+                span: self.span()?,
+            },
+            "cast" => Instruction::Cast {
+                dest: self.dest(dest, &opcode)?,
+                value: self.operand()?,
+            },
+            "call" => {
+                let function = self.word()?;
+                self.expect_punct('(')?;
+                Instruction::Call {
+                    dest,
+                    function,
+                    args: self.operands(')')?,
+                }
+            }
+            "call_value" => {
+                let callee = self.operand()?;
+                self.expect_punct('(')?;
+                Instruction::CallValue {
+                    dest,
+                    callee,
+                    args: self.operands(')')?,
+                }
+            }
+            "call_dyn" => {
+                let trait_name = self.word()?;
+                self.expect_punct('.')?;
+                let method = self.word()?;
+                self.expect_punct('(')?;
+                Instruction::CallDyn {
+                    dest,
+                    trait_name,
+                    method,
+                    args: self.operands(')')?,
+                }
+            }
+            "intrinsic" => {
+                let name = self.word()?;
+                let intrinsic = parse_intrinsic(&name)?;
+                self.expect_punct('(')?;
+                Instruction::Intrinsic {
+                    dest,
+                    intrinsic,
+                    args: self.operands(')')?,
+                    span: self.span()?,
+                }
+            }
+            "print" => {
+                let name = self.word()?;
+                let function = IO_FUNCTIONS
+                    .iter()
+                    .find(|(_, candidate)| *candidate == name)
+                    .map(|(function, _)| *function)
+                    .ok_or(format!("Unknown print function {name}"))?;
+                let pieces = parse_format(&self.string()?)?;
+                self.expect_punct('(')?;
+                Instruction::Print {
+                    function,
+                    pieces,
+                    args: self.operands(')')?,
+                }
+            }
+            "to_dyn" => Instruction::ToDyn {
+                dest: self.dest(dest, &opcode)?,
+                value: self.operand()?,
+            },
+            "make_fn" => Instruction::MakeFn {
+                dest: self.dest(dest, &opcode)?,
+                function: self.word()?,
+                env: if self.eat_punct(',') {
+                    Some(self.operand()?)
+                } else {
+                    None
+                },
+            },
+            "struct" => {
+                self.expect_punct('{')?;
+                let fields = self.list('}', |parser| {
+                    let field = parser.word()?;
+                    parser.expect_punct(':')?;
+                    Ok((field, parser.operand()?))
+                })?;
+                Instruction::Struct {
+                    dest: self.dest(dest, &opcode)?,
+                    fields,
+                }
+            }
+            "array" => {
+                self.expect_punct('[')?;
+                Instruction::Array {
+                    dest: self.dest(dest, &opcode)?,
+                    elements: self.operands(']')?,
+                }
+            }
+            "array_repeat" => Instruction::ArrayRepeat {
+                dest: self.dest(dest, &opcode)?,
+                value: self.operand()?,
+            },
+            "field" | "field_address" => {
+                let value = self.operand()?;
+                self.expect_punct(',')?;
+                let field = self.word()?;
+                let dest = self.dest(dest, &opcode)?;
+                if opcode == "field" {
+                    Instruction::Field { dest, value, field }
+                } else {
+                    Instruction::FieldAddress {
+                        dest,
+                        base: value,
+                        field,
+                    }
+                }
+            }
+            "index" | "index_address" => {
+                let array = self.operand()?;
+                self.expect_punct(',')?;
+                let index = self.operand()?;
+                let span = self.span()?;
+                let dest = self.dest(dest, &opcode)?;
+                if opcode == "index" {
+                    Instruction::Index {
+                        dest,
+                        array,
+                        index,
+                        span,
+                    }
+                } else {
+                    Instruction::IndexAddress {
+                        dest,
+                        base: array,
+                        index,
+                        span,
+                    }
+                }
+            }
+            "address_of" => Instruction::AddressOf {
+                dest: self.dest(dest, &opcode)?,
+                reg: self.reg()?,
+            },
+            "load" => Instruction::Load {
+                dest: self.dest(dest, &opcode)?,
+                address: self.operand()?,
+            },
+            "store" => {
+                let address = self.operand()?;
+                self.expect_punct(',')?;
+                Instruction::Store {
+                    address,
+                    value: self.operand()?,
+                }
+            }
+            "alloc" => Instruction::Alloc {
+                dest: self.dest(dest, &opcode)?,
+            },
+            "move" => Instruction::Move {
+                dest: self.dest(dest, &opcode)?,
+                address: self.operand()?,
+            },
+            "drop" => Instruction::Drop {
+                address: self.operand()?,
+            },
+            "next_char" => {
+                let text = self.operand()?;
+                self.expect_punct(',')?;
+                Instruction::NextChar {
+                    dest: self.dest(dest, &opcode)?,
+                    text,
+                    pos: self.operand()?,
+                }
+            }
+            "phi" => {
+                self.expect_punct('[')?;
+                let incoming = self.list(']', |parser| {
+                    let from = parser.block_id()?;
+                    parser.expect_punct(':')?;
+                    Ok((from, parser.operand()?))
+                })?;
+                Instruction::Phi {
+                    dest: self.dest(dest, &opcode)?,
+                    incoming,
+                }
+            }
+            _ => return Err(format!("Unknown instruction {opcode}")),
+        };
+        let writes = instruction.dest().is_some();
+        if dest.is_some() && !writes {
+            return Err(format!("{opcode} does not write a register"));
+        }
+        Ok(instruction)
+    }
+}
+
+fn parse_intrinsic(name: &str) -> Result<Intrinsic, String> {
+    if let Some((intrinsic, _)) = INTRINSICS.iter().find(|(_, candidate)| *candidate == name) {
+        return Ok(*intrinsic);
+    }
+    // Arithmetic is named after how it overflows and its operation
+    let (overflow, op) = name.split_once('_').unwrap_or_default();
+    let op = BINARY_OPS
+        .iter()
+        .find(|(_, candidate)| *candidate == op)
+        .map(|(op, _)| *op);
+    match (overflow, op) {
+        ("wrapping", Some(op)) => return Ok(Intrinsic::Wrapping(op)),
+        ("overflows", Some(op)) => return Ok(Intrinsic::Overflows(op)),
+        ("saturating", Some(op)) => return Ok(Intrinsic::Saturating(op)),
+        _ => {}
+    }
+    Err(format!("Unknown intrinsic {name}"))
+}
+
+// Splits a format string back into text and `{}` placeholders
+fn parse_format(format: &str) -> Result<Vec<FormatPiece>, String> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                text.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                if !text.is_empty() {
+                    pieces.push(FormatPiece::Text(std::mem::take(&mut text)));
+                }
+                pieces.push(FormatPiece::Placeholder);
+            }
+            ('{' | '}', _) => return Err(format!("Unmatched {c} in format {format:?}")),
+            (c, _) => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(FormatPiece::Text(text));
+    }
+    Ok(pieces)
+}
@@ -15,18 +15,164 @@
  * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
  */
 
+use std::fmt;
 use std::iter::Peekable;
 use std::str::Chars;
 
+use crate::diagnostic::{codes, Diagnostic, Fix, Stage};
+
 #[derive(PartialEq, Clone, Debug)]
 pub enum Literal {
     String(String),
     Number(i64),
+    Bool(bool),
+    Char(char),
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Type {
     Void,
+    Bool,
+    Char,
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    Str,
+    String,
+    /// A generic type parameter such as the `T` in `fn max<T: Ord>`
+    Param(std::string::String),
+    /// A struct, with its type arguments if it is generic
+    Struct(std::string::String, Vec<Type>),
+    /// `dyn Trait`, a value of any type implementing the trait
+    Dyn(std::string::String),
+    /// `fn(i32) -> i32`, a function or closure taking and returning these types
+    Fn(Vec<Type>, Box<Type>),
+    /// `[T; N]`, a fixed number of values of one type
+    Array(Box<Type>, Length),
+    /// `&T` or, when mutable, `&mut T`, a borrowed pointer to a value
+    Ref(Box<Type>, bool),
+    /// `Box<T>`, a value on the heap that is freed when its owner goes out of scope
+    Box(Box<Type>),
+    /// `Buffer<T>`, a growable run of values on the heap, which indexing
+    /// checks against its length at run time
+    Buffer(Box<Type>),
+    /// `Option<T>`, `Some` value or `None`, laid out as a struct of a `some`
+    /// flag and the `value`
+    Option(Box<Type>),
+    /// `Result<T, E>`, an `Ok` value or an `Err` error, laid out as a struct
+    /// of an `ok` flag, the `value` and the `error`. Either holds zero when
+    /// the other is set, and `T` may be void.
+    Result(Box<Type>, Box<Type>),
+}
+
+/// The length of an array type: a number, or a constant until semantic
+/// analysis looks it up
+#[derive(Debug, Clone, PartialEq)]
+pub enum Length {
+    Known(u64),
+    Const(std::string::String),
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Length::Known(len) => write!(f, "{len}"),
+            Length::Const(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+impl Type {
+    pub fn is_integer(&self) -> bool {
+        self.is_signed() || matches!(self, Type::U8 | Type::U16 | Type::U32 | Type::U64)
+    }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(self, Type::I8 | Type::I16 | Type::I32 | Type::I64)
+    }
+
+    /// True for `str` and `String`, which coerce to `str` wherever one is expected
+    pub fn is_string(&self) -> bool {
+        matches!(self, Type::Str | Type::String)
+    }
+
+    /// The inclusive range of values an integer type can hold
+    pub fn integer_range(&self) -> Option<(i128, i128)> {
+        let bits = match self {
+            Type::I8 | Type::U8 => 8,
+            Type::I16 | Type::U16 => 16,
+            Type::I32 | Type::U32 => 32,
+            Type::I64 | Type::U64 => 64,
+            _ => return None,
+        };
+        if self.is_signed() {
+            Some((-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1))
+        } else {
+            Some((0, (1i128 << bits) - 1))
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Type::Void => "void",
+            Type::Bool => "bool",
+            Type::Char => "char",
+            Type::I8 => "i8",
+            Type::I16 => "i16",
+            Type::I32 => "i32",
+            Type::I64 => "i64",
+            Type::U8 => "u8",
+            Type::U16 => "u16",
+            Type::U32 => "u32",
+            Type::U64 => "u64",
+            Type::Str => "str",
+            Type::String => "String",
+            Type::Param(name) => name,
+            Type::Struct(name, args) if args.is_empty() => name,
+            Type::Dyn(name) => return write!(f, "dyn {name}"),
+            Type::Array(element, len) => return write!(f, "[{element}; {len}]"),
+            Type::Ref(inner, true) => return write!(f, "&mut {inner}"),
+            Type::Ref(inner, false) => return write!(f, "&{inner}"),
+            Type::Box(inner) => return write!(f, "Box<{inner}>"),
+            Type::Buffer(element) => return write!(f, "Buffer<{element}>"),
+            Type::Option(inner) => return write!(f, "Option<{inner}>"),
+            Type::Result(value, error) => return write!(f, "Result<{value}, {error}>"),
+            Type::Fn(params, return_type) => {
+                let params = params
+                    .iter()
+                    .map(|param| param.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                if **return_type == Type::Void {
+                    return write!(f, "fn({params})");
+                }
+                return write!(f, "fn({params}) -> {return_type}");
+            }
+            Type::Struct(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                return write!(f, "{name}<{args}>");
+            }
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Where a token starts in the source, counted from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
 }
 
 #[derive(PartialEq, Clone, Debug)]
@@ -43,11 +189,65 @@ pub enum Token {
     Unknown(String),
     CloseParen,
     OpenParen,
+    Comma,
+    Let,
+    If,
+    Else,
+    While,
+    For,
+    In,
+    As,
+    Struct,
+    Trait,
+    Impl,
+    Dyn,
+    Move,
+    Const,
+    Match,
+    Mut,
+    Return,
+    Assign,
+    /// `+=`, and likewise for the other compound assignments
+    PlusAssign,
+    MinusAssign,
+    StarAssign,
+    SlashAssign,
+    PercentAssign,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    /// A single `&`, which borrows a value
+    Ampersand,
+    Or,
+    Not,
+    Dot,
+    Colon,
+    DoubleColon,
+    Pipe,
+    Arrow,
+    FatArrow,
+    OpenBracket,
+    CloseBracket,
+    /// `?`, which returns a `None` or `Err` from the function
+    Question,
 }
 
 pub struct Lexer<'a> {
     chars: Peekable<Chars<'a>>,
     tokens: Vec<Token>,
+    spans: Vec<Span>,
+    diagnostics: Vec<Diagnostic>,
+    line: usize,
+    column: usize,
 }
 
 impl<'a> Lexer<'a> {
@@ -55,32 +255,163 @@ impl<'a> Lexer<'a> {
         Lexer {
             chars: input.chars().peekable(),
             tokens: Vec::new(),
+            spans: Vec::new(),
+            diagnostics: Vec::new(),
+            line: 1,
+            column: 1,
         }
     }
 
+    // Consumes one character, keeping track of the line and column
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+            self.column = 1;
+        } else if c.is_some() {
+            self.column += 1;
+        }
+        c
+    }
+
     fn push(&mut self, token: Token) {
         self.tokens.push(token);
-        self.chars.next();
+        self.bump();
     }
 
-    pub fn lex(mut self) -> Vec<Token> {
+    // Pushes `double` if the character after the current one is `second`, otherwise `single`
+    fn push_either(&mut self, second: char, double: Token, single: Token) {
+        self.bump();
+        if self.chars.peek() == Some(&second) {
+            self.push(double);
+        } else {
+            self.tokens.push(single);
+        }
+    }
+
+    // Reads the character after a backslash and returns what it stands for
+    fn escape(&mut self) -> char {
+        match self.bump() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some(c) => c,
+            None => '\\',
+        }
+    }
+
+    /// Splits the input into tokens, with where each starts, or reports
+    /// every part of it that is not a token
+    pub fn lex(mut self) -> Result<(Vec<Token>, Vec<Span>), Vec<Diagnostic>> {
         while let Some(&ch) = self.chars.peek() {
+            let start = Span {
+                line: self.line,
+                column: self.column,
+            };
             match ch {
                 '(' => self.push(Token::OpenParen),
                 ')' => self.push(Token::CloseParen),
                 ';' => self.push(Token::Semicolon),
+                ',' => self.push(Token::Comma),
+                '.' => self.push(Token::Dot),
+                '?' => self.push(Token::Question),
+                '+' => self.push_either('=', Token::PlusAssign, Token::Plus),
+                '-' => {
+                    self.bump();
+                    match self.chars.peek() {
+                        Some('>') => self.push(Token::Arrow),
+                        Some('=') => self.push(Token::MinusAssign),
+                        _ => self.tokens.push(Token::Minus),
+                    }
+                }
+                '*' => self.push_either('=', Token::StarAssign, Token::Star),
+                '%' => self.push_either('=', Token::PercentAssign, Token::Percent),
+                '=' => {
+                    self.bump();
+                    match self.chars.peek() {
+                        Some('=') => self.push(Token::Equal),
+                        Some('>') => self.push(Token::FatArrow),
+                        _ => self.tokens.push(Token::Assign),
+                    }
+                }
+                '[' => self.push(Token::OpenBracket),
+                ']' => self.push(Token::CloseBracket),
+                '!' => self.push_either('=', Token::NotEqual, Token::Not),
+                '<' => self.push_either('=', Token::LessEqual, Token::Less),
+                '>' => self.push_either('=', Token::GreaterEqual, Token::Greater),
+                ':' => self.push_either(':', Token::DoubleColon, Token::Colon),
+                '&' => self.push_either('&', Token::And, Token::Ampersand),
+                '|' => self.push_either('|', Token::Or, Token::Pipe),
+                '/' => {
+                    self.bump();
+                    if self.chars.peek() == Some(&'/') {
+                        while self.chars.peek().is_some_and(|&c| c != '\n') {
+                            self.bump();
+                        }
+                    } else if self.chars.peek() == Some(&'=') {
+                        self.push(Token::SlashAssign);
+                    } else {
+                        self.tokens.push(Token::Slash);
+                    }
+                }
+                '\'' => {
+                    self.bump(); // skip opening quote
+                    let c = match self.bump() {
+                        Some('\\') => self.escape(),
+                        Some(c) => c,
+                        None => '\'',
+                    };
+                    if self.bump() == Some('\'') {
+                        self.tokens.push(Token::Literal(Literal::Char(c)));
+                    } else {
+                        let diagnostic = error(
+                            codes::BAD_CHARACTER_LITERAL,
+                            "Character literal is not one character between single quotes"
+                                .to_string(),
+                            start,
+                        )
+                        .with_note("text of any length goes between double quotes");
+                        self.diagnostics.push(diagnostic);
+                        self.tokens.push(Token::Unknown(format!("'{c}")));
+                    }
+                }
                 '{' => self.push(Token::OpenBrace),
                 '}' => self.push(Token::CloseBrace),
                 '"' => {
-                    self.chars.next(); // skip opening quote
+                    self.bump(); // skip opening quote
                     let mut s = String::new();
+                    let mut closed = false;
                     while let Some(&c) = self.chars.peek() {
                         if c == '"' {
-                            self.chars.next(); // skip closing quote
+                            self.bump(); // skip closing quote
+                            closed = true;
                             break;
                         }
-                        s.push(c);
-                        self.chars.next();
+                        self.bump();
+                        if c == '\\' {
+                            s.push(self.escape());
+                        } else {
+                            s.push(c);
+                        }
+                    }
+                    if !closed {
+                        let end = Span {
+                            line: self.line,
+                            column: self.column,
+                        };
+                        let diagnostic = error(
+                            codes::UNTERMINATED_STRING,
+                            "String literal has no closing quote".to_string(),
+                            start,
+                        )
+                        .with_fix(Fix {
+                            message: "close the string at the end of the input".to_string(),
+                            span: end,
+                            len: 0,
+                            replacement: "\"".to_string(),
+                        });
+                        self.diagnostics.push(diagnostic);
                     }
                     self.tokens.push(Token::Literal(Literal::String(s)));
                 }
@@ -89,14 +420,24 @@ impl<'a> Lexer<'a> {
                     while let Some(&c) = self.chars.peek() {
                         if c.is_ascii_digit() {
                             num.push(c);
-                            self.chars.next();
+                            self.bump();
                         } else {
                             break;
                         }
                     }
-                    if let Ok(n) = num.parse() {
-                        self.tokens.push(Token::Literal(Literal::Number(n)));
+                    // Literals above `i64::MAX` are stored wrapped around, as
+                    // `u64` constants are, which is how sema tells them apart
+                    // from the negative ones it makes
+                    if let Ok(n) = num.parse::<u64>() {
+                        self.tokens.push(Token::Literal(Literal::Number(n as i64)));
                     } else {
+                        let diagnostic = error(
+                            codes::INTEGER_TOO_LARGE,
+                            format!("Integer literal {num} is too large"),
+                            start,
+                        )
+                        .with_note(format!("the largest integer literal is {}", u64::MAX));
+                        self.diagnostics.push(diagnostic);
                         self.tokens.push(Token::Unknown(num));
                     }
                 }
@@ -105,7 +446,7 @@ impl<'a> Lexer<'a> {
                     while let Some(&c) = self.chars.peek() {
                         if c.is_ascii_alphanumeric() || c == '_' {
                             ident.push(c);
-                            self.chars.next();
+                            self.bump();
                         } else {
                             break;
                         }
@@ -114,26 +455,72 @@ impl<'a> Lexer<'a> {
                         "import" => Token::Import,
                         "from" => Token::From,
                         "void" => Token::Type(Type::Void),
+                        "bool" => Token::Type(Type::Bool),
+                        "char" => Token::Type(Type::Char),
+                        "i8" => Token::Type(Type::I8),
+                        "i16" => Token::Type(Type::I16),
+                        "i32" => Token::Type(Type::I32),
+                        "i64" => Token::Type(Type::I64),
+                        "u8" => Token::Type(Type::U8),
+                        "u16" => Token::Type(Type::U16),
+                        "u32" => Token::Type(Type::U32),
+                        "u64" => Token::Type(Type::U64),
+                        "str" => Token::Type(Type::Str),
+                        "String" => Token::Type(Type::String),
+                        "true" => Token::Literal(Literal::Bool(true)),
+                        "false" => Token::Literal(Literal::Bool(false)),
                         "fn" => Token::Fn,
+                        "let" => Token::Let,
+                        "if" => Token::If,
+                        "else" => Token::Else,
+                        "while" => Token::While,
+                        "for" => Token::For,
+                        "in" => Token::In,
+                        "as" => Token::As,
+                        "struct" => Token::Struct,
+                        "trait" => Token::Trait,
+                        "impl" => Token::Impl,
+                        "dyn" => Token::Dyn,
+                        "move" => Token::Move,
+                        "const" => Token::Const,
+                        "match" => Token::Match,
+                        "mut" => Token::Mut,
+                        "return" => Token::Return,
                         _ => Token::Identifier(ident),
                     };
                     self.tokens.push(token);
                 }
                 c if c.is_whitespace() => {
-                    self.chars.next();
+                    self.bump();
                 }
                 _ => {
-                    let mut unknown = String::new();
-                    unknown.push(ch);
-                    self.chars.next();
-                    self.tokens.push(Token::Unknown(unknown));
+                    let diagnostic = error(
+                        codes::UNKNOWN_CHARACTER,
+                        format!("Unknown character {ch:?}"),
+                        start,
+                    );
+                    self.diagnostics.push(diagnostic);
+                    self.bump();
+                    self.tokens.push(Token::Unknown(ch.to_string()));
                 }
             }
+            self.spans.resize(self.tokens.len(), start);
         }
-        self.tokens
+        if !self.diagnostics.is_empty() {
+            return Err(self.diagnostics);
+        }
+        Ok((self.tokens, self.spans))
     }
 }
 
-pub fn lex(input: &str) -> Vec<Token> {
+pub fn lex(input: &str) -> Result<(Vec<Token>, Vec<Span>), Vec<Diagnostic>> {
     Lexer::new(input).lex()
 }
+
+// Reports a token that cannot be lexed, which the parser is left an unknown
+// token for
+fn error(code: &'static str, message: String, span: Span) -> Diagnostic {
+    Diagnostic::error(Stage::Lex, message)
+        .with_code(code)
+        .with_primary(span, "")
+}
@@ -0,0 +1,220 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+// The Nimra compiler as a library, for build tools and editors that embed
+// it. A `Session` holds the options a program is compiled with and has a
+// method for each stage, and `compile_source` runs them all. Nothing in the
+// library prints: errors and warnings come back as diagnostics, and text the
+// options ask for comes back as an artifact. Only the interpreter and the VM
+// print, as the program they run does.
+
+pub mod assemble;
+pub mod bytecode;
+pub mod codegen;
+pub mod compile_c;
+pub mod compile_llvm;
+pub mod consteval;
+pub mod diagnostic;
+pub mod file_handling;
+pub mod generator;
+pub mod interp;
+pub mod ir;
+pub mod ir_text;
+pub mod lexer;
+pub mod llvm;
+pub mod nbc;
+pub mod optimize;
+pub mod options;
+pub mod parser;
+mod runtime;
+pub mod sema;
+mod ssa;
+mod stdlib;
+mod verifier;
+pub mod vm;
+pub mod wasm;
+mod wasm_validator;
+pub mod wat;
+pub mod x86_64;
+
+use std::path::PathBuf;
+
+pub use crate::diagnostic::{Diagnostic, Severity, Stage};
+use crate::generator::ICInstruction;
+use crate::lexer::{Span, Token};
+pub use crate::options::{Backend, Options};
+use crate::parser::ASTNode;
+use crate::sema::TypedProgram;
+
+/// What a backend made of a program
+#[derive(Debug)]
+pub enum Output {
+    /// The file it wrote to `Options::output`
+    File(PathBuf),
+    /// Text the options ask for instead of a build, which is intermediate
+    /// code for `--emit=ic`, LLVM IR, WebAssembly text or disassembled
+    /// bytecode
+    Text(String),
+    /// Bytecode to be run by the VM, which `nimra run` does without writing it
+    Bytecode(bytecode::Program),
+}
+
+/// Everything compiling a program produced
+#[derive(Debug)]
+pub struct Artifacts {
+    pub output: Output,
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// The compilation of one program, which runs each stage on what the stage
+/// before it made and collects the warnings they report
+pub struct Session {
+    pub options: Options,
+    warnings: Vec<Diagnostic>,
+}
+
+impl Session {
+    pub fn new(options: Options) -> Session {
+        Session {
+            options,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// The warnings reported by the stages run so far
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    /// Splits source code into tokens, reporting every character that
+    /// starts none
+    pub fn lex(&self, source: &str) -> Result<(Vec<Token>, Vec<Span>), Vec<Diagnostic>> {
+        lexer::lex(source)
+    }
+
+    pub fn parse(&self, tokens: &Vec<Token>, spans: &[Span]) -> Result<Vec<ASTNode>, Diagnostic> {
+        parser::parse(tokens, spans)
+    }
+
+    /// Type checks a program, keeping the warnings it has
+    pub fn check(&mut self, ast: &[ASTNode]) -> Result<TypedProgram, Diagnostic> {
+        let program = sema::check(ast)?;
+        self.warnings.extend(program.warnings.iter().cloned());
+        Ok(program)
+    }
+
+    /// Lexes, parses and type checks source code
+    pub fn check_source(&mut self, source: &str) -> Result<TypedProgram, Vec<Diagnostic>> {
+        let (tokens, spans) = self.lex(source)?;
+        let ast = self.parse(&tokens, &spans).map_err(|e| vec![e])?;
+        self.check(&ast).map_err(|e| vec![e])
+    }
+
+    /// Lowers a checked program to intermediate code, with its constants folded
+    pub fn lower(&self, program: TypedProgram) -> Result<Vec<ICInstruction>, Diagnostic> {
+        let ic = generator::generate(program)?;
+        consteval::fold(ic, &self.options)
+    }
+
+    /// Reads intermediate code written as text, as `--emit=ic` prints it
+    pub fn parse_ic(&self, text: &str) -> Result<Vec<ICInstruction>, Diagnostic> {
+        ir_text::parse(text).map_err(|e| Diagnostic::error(Stage::IcParse, e))
+    }
+
+    pub fn optimize(&self, ic: Vec<ICInstruction>) -> Result<Vec<ICInstruction>, Diagnostic> {
+        optimize::optimize(ic, &self.options).map_err(|e| Diagnostic::error(Stage::Optimization, e))
+    }
+
+    /// Turns intermediate code into what the options ask for, with the
+    /// backend they choose
+    pub fn build(&self, ic: Vec<ICInstruction>) -> Result<Output, Diagnostic> {
+        let options = &self.options;
+        if options.emit_ic {
+            return Ok(Output::Text(ir_text::print(&ic)));
+        }
+        let generation = |e| Diagnostic::error(Stage::CodeGeneration, e);
+        let output_file = match options.backend {
+            Backend::C => {
+                let c = codegen::codegen(ic, options)?;
+                return compile_c::compile(&c, options).map(Output::File);
+            }
+            Backend::Asm => {
+                let assembly = x86_64::generate(&ic, options).map_err(generation)?;
+                return assemble::assemble(&assembly, options).map(Output::File);
+            }
+            Backend::Bytecode => {
+                let program = bytecode::compile(&ic, options).map_err(generation)?;
+                if options.disassemble {
+                    return Ok(Output::Text(bytecode::disassemble(&program)));
+                }
+                // There is nothing to build, so the VM runs the program as is
+                if options.run {
+                    return Ok(Output::Bytecode(program));
+                }
+                nbc::write(&program, options)
+            }
+            Backend::Llvm => {
+                let module = llvm::generate(&ic, options).map_err(generation)?;
+                if options.emit_llvm {
+                    return Ok(Output::Text(module));
+                }
+                return compile_llvm::compile(&module, options).map(Output::File);
+            }
+            Backend::Wasm => {
+                let module = wasm::generate(&ic, options).map_err(generation)?;
+                if options.emit_wat {
+                    return Ok(Output::Text(module));
+                }
+                wat::assemble(&module, options)
+            }
+        };
+        output_file
+            .map(Output::File)
+            .map_err(|e| Diagnostic::error(Stage::Compilation, e))
+    }
+
+    /// Runs every stage on a program's source, which is intermediate code
+    /// written as text if the input is an `.ic` file
+    pub fn compile(&mut self, source: &str) -> Result<Artifacts, Vec<Diagnostic>> {
+        let output = self.compile_stages(source).map_err(|errors| {
+            let mut diagnostics = self.warnings.clone();
+            diagnostics.extend(errors);
+            diagnostics
+        })?;
+        Ok(Artifacts {
+            output,
+            warnings: self.warnings.clone(),
+        })
+    }
+
+    fn compile_stages(&mut self, source: &str) -> Result<Output, Vec<Diagnostic>> {
+        let ic = if self.options.input.ends_with(".ic") {
+            self.parse_ic(source).map_err(|e| vec![e])?
+        } else {
+            let program = self.check_source(source)?;
+            self.lower(program).map_err(|e| vec![e])?
+        };
+        let ic = self.optimize(ic).map_err(|e| vec![e])?;
+        self.build(ic).map_err(|e| vec![e])
+    }
+}
+
+/// Compiles a program's source with the options given, returning what was
+/// built and any warnings, or every diagnostic if it cannot be compiled
+pub fn compile_source(source: &str, options: Options) -> Result<Artifacts, Vec<Diagnostic>> {
+    Session::new(options).compile(source)
+}
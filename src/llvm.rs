@@ -0,0 +1,1643 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::generator::{self, ICInstruction};
+use crate::ir::{self, Function, Instruction, Operand, Reg, Terminator};
+use crate::lexer::{Length, Literal, Span, Type};
+use crate::options::Options;
+use crate::parser::{BinaryOp, UnaryOp};
+use crate::sema::{self, FormatPiece, Intrinsic, IoFunction, TraitMethod};
+
+/// The types every module uses: a `str`, a `String`, a value of a function
+/// type, a `dyn Trait`, and the frame debug builds keep for backtraces
+const TYPES: &str = "\
+%nrt_str = type { ptr, i64 }
+%nrt_string = type { ptr, i64, i64 }
+%nrt_fn = type { ptr, ptr, ptr }
+%nrt_dyn = type { ptr, ptr }
+%nrt_frame = type { ptr, ptr }
+";
+
+/// What generated code calls in the C runtime and library, declared the way
+/// a C compiler passes them on x86-64: a struct of two words is passed as two
+/// arguments and returned in two registers, and a bigger one is returned
+/// through a pointer. An `nrt_location` is its file and then its line and
+/// column packed into one word.
+const RUNTIME: &str = "\
+@stdout = external global ptr
+@stderr = external global ptr
+@nrt_frame_top = external global ptr
+declare void @nrt_panic(ptr, i64, ptr, i64) noreturn
+declare void @nrt_panic_message(ptr, i64, ptr) noreturn
+declare i64 @nrt_check_index(i64, i64, ptr, i64)
+declare i64 @nrt_hash_u64(i64)
+declare i64 @nrt_hash_str(ptr, i64)
+declare ptr @nrt_alloc(i64)
+declare ptr @nrt_box_new(i64)
+declare void @nrt_box_free(ptr)
+declare i64 @nrt_buffer_len(ptr)
+declare ptr @nrt_buffer_push(ptr, i64)
+declare i64 @nrt_buffer_pop(ptr, ptr, i64)
+declare void @nrt_buffer_free(ptr)
+declare i32 @nrt_str_next_char(ptr, i64, ptr)
+declare void @nrt_string_from(ptr sret(%nrt_string), ptr, i64)
+declare void @nrt_string_push_str(ptr, ptr, i64)
+declare void @nrt_string_push_char(ptr, i32)
+declare void @nrt_string_free(ptr)
+declare %nrt_str @nrt_str_slice(ptr, i64, i64, i64, ptr, i64)
+declare void @nrt_str_concat(ptr sret(%nrt_string), ptr, i64, ptr, i64)
+declare zeroext i1 @nrt_str_equal(ptr, i64, ptr, i64)
+declare i32 @nrt_str_compare(ptr, i64, ptr, i64)
+declare i64 @nrt_str_parse_i64(ptr, i64, ptr, i64)
+declare void @nrt_i64_to_string(ptr sret(%nrt_string), i64)
+declare void @nrt_u64_to_string(ptr sret(%nrt_string), i64)
+declare void @nrt_char_to_string(ptr sret(%nrt_string), i32)
+declare void @exit(i32) noreturn
+declare i32 @fputs(ptr, ptr)
+declare i64 @fwrite(ptr, i64, i64, ptr)
+declare i32 @fprintf(ptr, ptr, ...)
+";
+
+/// What integer arithmetic does when its result does not fit in its type
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Overflow {
+    Panic,
+    Wrap,
+    Saturate,
+}
+
+/// Generates a module of textual LLVM IR that links against the C runtime.
+/// Every register lives in a stack slot, which LLVM's optimizer turns into
+/// SSA values and phis. Debug builds also describe each function and the
+/// source position of its code as debug info.
+pub fn generate(ic: &[ICInstruction], options: &Options) -> Result<String, String> {
+    Module::new(ic, options).generate()
+}
+
+struct Module<'a> {
+    ic: &'a [ICInstruction],
+    structs: HashMap<String, Vec<(String, Type)>>,
+    traits: HashMap<String, Vec<TraitMethod>>,
+    returns: HashMap<String, Type>,
+    owned: Vec<Type>,
+    debug: bool,
+    optimized: bool,
+    file: String,
+    globals: String,
+    /// The global holding each NUL-terminated string
+    strings: HashMap<String, String>,
+    /// The LLVM intrinsics used, declared once each
+    intrinsics: BTreeSet<String>,
+    /// Debug info, numbered by position
+    metadata: Vec<String>,
+    /// The metadata describing each source position in each function
+    locations: HashMap<(usize, usize, usize), usize>,
+}
+
+impl<'a> Module<'a> {
+    fn new(ic: &'a [ICInstruction], options: &Options) -> Module<'a> {
+        let mut structs = HashMap::new();
+        let mut traits = HashMap::new();
+        let mut returns = HashMap::new();
+        let mut owned = Vec::new();
+        for item in ic {
+            match item {
+                ICInstruction::StructDecl { name, fields } => {
+                    structs.insert(name.clone(), fields.clone());
+                }
+                ICInstruction::TraitDecl { name, methods } => {
+                    traits.insert(name.clone(), methods.clone());
+                }
+                ICInstruction::Function(function) => {
+                    returns.insert(function.name.clone(), function.return_type.clone());
+                }
+                ICInstruction::OwnedType(ty) => owned.push(ty.clone()),
+                _ => {}
+            }
+        }
+        Module {
+            ic,
+            structs,
+            traits,
+            returns,
+            owned,
+            debug: !options.release,
+            optimized: options.opt_level > 0,
+            file: options.input.clone(),
+            globals: String::new(),
+            strings: HashMap::new(),
+            intrinsics: BTreeSet::new(),
+            metadata: Vec::new(),
+            locations: HashMap::new(),
+        }
+    }
+
+    fn generate(&mut self) -> Result<String, String> {
+        if self.debug {
+            let file = escape_string(&self.file);
+            let optimized = self.optimized;
+            self.metadata = vec![
+                format!("distinct !DICompileUnit(language: DW_LANG_C, file: !1, producer: \"nimra\", isOptimized: {optimized}, runtimeVersion: 0, emissionKind: FullDebug)"),
+                format!("!DIFile(filename: \"{file}\", directory: \".\")"),
+                "!{i32 7, !\"Dwarf Version\", i32 4}".to_string(),
+                "!{i32 2, !\"Debug Info Version\", i32 3}".to_string(),
+                "!DISubroutineType(types: !5)".to_string(),
+                "!{}".to_string(),
+            ];
+        }
+        let mut types = String::from(TYPES);
+        let mut functions = String::new();
+        let ic = self.ic;
+        let mut main = None;
+        for item in ic {
+            match item {
+                ICInstruction::StructDecl { name, fields } => {
+                    let fields = fields
+                        .iter()
+                        .map(|(_, ty)| self.ty(ty))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    types.push_str(&format!("%s_{name} = type {{ {fields} }}\n"));
+                }
+                ICInstruction::Function(function) => {
+                    if function.name == "main" {
+                        main = Some(function.return_type.clone());
+                    }
+                    let name = format!("fn_{}", function.name);
+                    functions.push_str(&self.function(function, &name, self.debug)?);
+                }
+                ICInstruction::Vtable {
+                    trait_name,
+                    ty,
+                    methods,
+                } => functions.push_str(&self.vtable(trait_name, ty, methods)?),
+                ICInstruction::OwnedType(ty) => functions.push_str(&self.drop_glue(ty)?),
+                _ => {}
+            }
+        }
+        let main = main.ok_or("There is no main function")?;
+        functions.push_str(&self.function(&ir::entry(&main), "main", false)?);
+        let mut module = format!(
+            "source_filename = \"{}\"\n\n{types}\n{}\n{RUNTIME}",
+            escape_string(&self.file),
+            self.globals
+        );
+        for intrinsic in &self.intrinsics {
+            module.push_str(intrinsic);
+            module.push('\n');
+        }
+        module.push('\n');
+        module.push_str(&functions);
+        if self.debug {
+            module.push_str("!llvm.dbg.cu = !{!0}\n!llvm.module.flags = !{!2, !3}\n");
+            for (id, node) in self.metadata.iter().enumerate() {
+                module.push_str(&format!("!{id} = {node}\n"));
+            }
+        }
+        Ok(module)
+    }
+
+    // A function, labelled `name`. Nimra functions in debug builds keep a
+    // frame for backtraces and are described in the debug info.
+    fn function(&mut self, function: &Function, name: &str, debug: bool) -> Result<String, String> {
+        let scope = debug.then(|| {
+            let linkage = escape_string(name);
+            let source = escape_string(&function.source_name);
+            self.metadata.push(format!(
+                "distinct !DISubprogram(name: \"{source}\", linkageName: \"{linkage}\", scope: !1, file: !1, type: !4, spFlags: DISPFlagDefinition{}, unit: !0)",
+                if self.optimized { " | DISPFlagOptimized" } else { "" }
+            ));
+            self.metadata.len() - 1
+        });
+        FunctionGen::new(self, function, scope).generate(function, name)
+    }
+
+    /// Emits the vtable for `ty` as a `dyn Trait`, and the functions in it,
+    /// which take a pointer to the value and call the method with the value
+    fn vtable(
+        &mut self,
+        trait_name: &str,
+        ty: &Type,
+        methods: &[TraitMethod],
+    ) -> Result<String, String> {
+        let mut code = String::new();
+        let mut entries = Vec::new();
+        for method in self.traits.get(trait_name).cloned().unwrap_or_default() {
+            if !methods.iter().any(|m| m.name == method.name) {
+                entries.push("ptr null".to_string());
+                continue;
+            }
+            let function = generator::mangle_method(trait_name, ty, &method.name);
+            let label = format!("th_{function}");
+            code.push_str(&self.function(&ir::thunk(ty, &method, &function), &label, false)?);
+            entries.push(format!("ptr @{label}"));
+        }
+        self.globals.push_str(&format!(
+            "@{} = internal constant [{} x ptr] [{}]\n",
+            vtable_name(trait_name, ty),
+            entries.len(),
+            entries.join(", ")
+        ));
+        Ok(code)
+    }
+
+    /// Emits the function that frees the heap memory the value a pointer
+    /// points to owns
+    fn drop_glue(&mut self, ty: &Type) -> Result<String, String> {
+        let mut body = String::new();
+        match ty {
+            Type::Box(inner) => {
+                body.push_str("  %box = load ptr, ptr %place\n");
+                if self.is_owned(inner) {
+                    body.push_str(&format!(
+                        "  %set = icmp ne ptr %box, null\n  br i1 %set, label %inner, label %free\ninner:\n  call void @dr_{}(ptr %box)\n  br label %free\nfree:\n",
+                        generator::mangle(inner)
+                    ));
+                }
+                body.push_str("  call void @nrt_box_free(ptr %box)\n");
+            }
+            Type::String => body.push_str("  call void @nrt_string_free(ptr %place)\n"),
+            // Buffers only hold values that own nothing
+            Type::Buffer(_) => body.push_str(
+                "  %items = load ptr, ptr %place\n  call void @nrt_buffer_free(ptr %items)\n",
+            ),
+            Type::Array(element, Length::Known(len)) if self.is_owned(element) => {
+                let array = self.ty(ty);
+                body.push_str(&format!(
+                    "  br label %head\nhead:\n  %i = phi i64 [ 0, %entry ], [ %next, %body ]\n  %more = icmp ult i64 %i, {len}\n  br i1 %more, label %body, label %done\nbody:\n  %element = getelementptr {array}, ptr %place, i64 0, i64 %i\n  call void @dr_{}(ptr %element)\n  %next = add i64 %i, 1\n  br label %head\ndone:\n",
+                    generator::mangle(element)
+                ));
+            }
+            Type::Struct(..) | Type::Option(_) | Type::Result(..) => {
+                let name = self.ty(ty);
+                let fields = self.fields(ty)?;
+                for (index, (field, field_type)) in fields.iter().enumerate() {
+                    if self.is_owned(field_type) {
+                        body.push_str(&format!(
+                            "  %f_{field} = getelementptr {name}, ptr %place, i32 0, i32 {index}\n  call void @dr_{}(ptr %f_{field})\n",
+                            generator::mangle(field_type)
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(format!(
+            "define hidden void @dr_{}(ptr %place) {{\nentry:\n{body}  ret void\n}}\n\n",
+            generator::mangle(ty)
+        ))
+    }
+
+    fn is_owned(&self, ty: &Type) -> bool {
+        self.owned.contains(ty)
+    }
+
+    // The fields of a struct, `Option` or `Result`, in order
+    fn fields(&self, ty: &Type) -> Result<Vec<(String, Type)>, String> {
+        self.structs
+            .get(&generator::mangle(ty))
+            .cloned()
+            .ok_or_else(|| format!("{ty} is not a struct"))
+    }
+
+    // The position and type of a field of a struct, `Option` or `Result`
+    fn field(&self, ty: &Type, name: &str) -> Result<(usize, Type), String> {
+        self.fields(ty)?
+            .into_iter()
+            .enumerate()
+            .find(|(_, (field, _))| field == name)
+            .map(|(index, (_, field_type))| (index, field_type))
+            .ok_or_else(|| format!("{ty} has no field {name}"))
+    }
+
+    fn ty(&self, ty: &Type) -> String {
+        let name = match ty {
+            Type::Void => "{}",
+            Type::Bool => "i1",
+            Type::I8 | Type::U8 => "i8",
+            Type::I16 | Type::U16 => "i16",
+            Type::I32 | Type::U32 | Type::Char => "i32",
+            Type::I64 | Type::U64 => "i64",
+            Type::Str => "%nrt_str",
+            Type::String => "%nrt_string",
+            Type::Struct(..) | Type::Option(_) | Type::Result(..) => {
+                return format!("%s_{}", generator::mangle(ty))
+            }
+            Type::Dyn(_) => "%nrt_dyn",
+            Type::Fn(..) => "%nrt_fn",
+            Type::Array(element, Length::Known(len)) => {
+                return format!("[{len} x {}]", self.ty(element))
+            }
+            Type::Array(..) | Type::Ref(..) | Type::Box(_) | Type::Buffer(_) => "ptr",
+            Type::Param(name) => unreachable!("type parameter {} survived monomorphization", name),
+        };
+        name.to_string()
+    }
+
+    // The type a function returns, which is `void` rather than an empty struct
+    fn return_ty(&self, ty: &Type) -> String {
+        if *ty == Type::Void {
+            "void".to_string()
+        } else {
+            self.ty(ty)
+        }
+    }
+
+    fn constant(&mut self, literal: &Literal, ty: &Type) -> String {
+        match literal {
+            Literal::Number(n) => canonical(*n, ty).to_string(),
+            Literal::Bool(b) => b.to_string(),
+            Literal::Char(c) => u32::from(*c).to_string(),
+            Literal::String(s) => {
+                let text = self.string(s);
+                format!("{{ ptr {text}, i64 {} }}", s.len())
+            }
+        }
+    }
+
+    // A global holding text followed by a NUL, as C functions take it
+    fn string(&mut self, text: &str) -> String {
+        if let Some(name) = self.strings.get(text) {
+            return name.clone();
+        }
+        let name = format!("@.str.{}", self.strings.len());
+        self.globals.push_str(&format!(
+            "{name} = private unnamed_addr constant [{} x i8] c\"{}\\00\"\n",
+            text.len() + 1,
+            escape_string(text)
+        ));
+        self.strings.insert(text.to_string(), name.clone());
+        name
+    }
+
+    // Declares an LLVM overflow intrinsic, and returns its name
+    fn overflow_intrinsic(&mut self, op: BinaryOp, ty: &Type) -> String {
+        let sign = if ty.is_signed() { 's' } else { 'u' };
+        let op = match op {
+            BinaryOp::Add => "add",
+            BinaryOp::Sub => "sub",
+            _ => "mul",
+        };
+        let t = self.ty(ty);
+        let name = format!("@llvm.{sign}{op}.with.overflow.{t}");
+        self.intrinsics
+            .insert(format!("declare {{ {t}, i1 }} {name}({t}, {t})"));
+        name
+    }
+
+    // The metadata of a source position in a function
+    fn location(&mut self, span: Span, scope: usize) -> usize {
+        let key = (span.line, span.column, scope);
+        if let Some(id) = self.locations.get(&key) {
+            return *id;
+        }
+        self.metadata.push(format!(
+            "!DILocation(line: {}, column: {}, scope: !{scope})",
+            span.line, span.column
+        ));
+        let id = self.metadata.len() - 1;
+        self.locations.insert(key, id);
+        id
+    }
+}
+
+struct FunctionGen<'m, 'a> {
+    module: &'m mut Module<'a>,
+    regs: Vec<Type>,
+    return_type: Type,
+    /// The stack slots, which go at the start of the entry block
+    slots: String,
+    body: String,
+    temps: usize,
+    labels: usize,
+    /// The block code is being generated in, which phis name
+    block: String,
+    /// The function's debug info, and the source position of the code
+    scope: Option<usize>,
+    span: Span,
+}
+
+impl<'m, 'a> FunctionGen<'m, 'a> {
+    fn new(module: &'m mut Module<'a>, function: &Function, scope: Option<usize>) -> Self {
+        FunctionGen {
+            module,
+            regs: function.regs.iter().map(|reg| reg.ty.clone()).collect(),
+            return_type: function.return_type.clone(),
+            slots: String::new(),
+            body: String::new(),
+            temps: 0,
+            labels: 0,
+            block: "entry".to_string(),
+            scope,
+            span: Span { line: 0, column: 0 },
+        }
+    }
+
+    fn generate(mut self, function: &Function, name: &str) -> Result<String, String> {
+        let mut params = Vec::new();
+        if function.env.is_some() {
+            params.push("ptr %env".to_string());
+        }
+        for param in &function.params {
+            params.push(format!("{} %p{param}", self.reg_ty(*param)));
+        }
+        for reg in 0..self.regs.len() {
+            let ty = self.reg_ty(reg);
+            self.slots.push_str(&format!("  %r{reg} = alloca {ty}\n"));
+            let value = if function.params.contains(&reg) {
+                format!("%p{reg}")
+            } else if function.env == Some(reg) {
+                "%env".to_string()
+            } else {
+                "zeroinitializer".to_string()
+            };
+            self.emit(&format!("store {ty} {value}, ptr %r{reg}"));
+        }
+        if self.scope.is_some() {
+            // Pushes a frame, which each return pops
+            self.slots.push_str("  %frame = alloca %nrt_frame\n");
+            let source = self.module.string(&function.source_name);
+            self.emit("%caller = load ptr, ptr @nrt_frame_top");
+            self.emit(&format!("store ptr {source}, ptr %frame"));
+            let link = self.temp("getelementptr %nrt_frame, ptr %frame, i32 0, i32 1");
+            self.emit(&format!("store ptr %caller, ptr {link}"));
+            self.emit("store ptr %frame, ptr @nrt_frame_top");
+        }
+        self.emit("br label %b0");
+        for (id, block) in function.blocks.iter().enumerate() {
+            self.label(&format!("b{id}"));
+            for instruction in &block.instructions {
+                self.instruction(instruction)?;
+            }
+            self.terminator(&block.terminator)?;
+        }
+        // Hidden rather than internal, since LLVM 14 crashes promoting the
+        // pointer arguments of internal functions to values
+        let linkage = if name == "main" { "" } else { "hidden " };
+        let debug = self
+            .scope
+            .map_or(String::new(), |scope| format!(" !dbg !{scope}"));
+        Ok(format!(
+            "define {linkage}{} @{name}({}){debug} {{\nentry:\n{}{}}}\n\n",
+            self.module.return_ty(&function.return_type),
+            params.join(", "),
+            self.slots,
+            self.body
+        ))
+    }
+
+    // Emits an instruction, at the current source position in debug builds
+    fn emit(&mut self, instruction: &str) {
+        self.body.push_str("  ");
+        self.body.push_str(instruction);
+        if let Some(scope) = self.scope {
+            let location = self.module.location(self.span, scope);
+            self.body.push_str(&format!(", !dbg !{location}"));
+        }
+        self.body.push('\n');
+    }
+
+    fn fresh(&mut self) -> String {
+        self.temps += 1;
+        format!("%t{}", self.temps)
+    }
+
+    // Emits an instruction that computes a value, and returns its name
+    fn temp(&mut self, expression: &str) -> String {
+        let name = self.fresh();
+        self.emit(&format!("{name} = {expression}"));
+        name
+    }
+
+    fn new_label(&mut self) -> String {
+        self.labels += 1;
+        format!("l{}", self.labels)
+    }
+
+    fn label(&mut self, label: &str) {
+        self.body.push_str(&format!("{label}:\n"));
+        self.block = label.to_string();
+    }
+
+    // A stack slot for a value that has to be in memory, such as one a
+    // runtime function writes
+    fn slot(&mut self, ty: &str) -> String {
+        let name = self.fresh();
+        self.slots.push_str(&format!("  {name} = alloca {ty}\n"));
+        name
+    }
+
+    fn reg_ty(&self, reg: Reg) -> String {
+        self.module.ty(&self.regs[reg])
+    }
+
+    fn operand_type(&self, operand: &Operand) -> Type {
+        match operand {
+            Operand::Reg(reg) => self.regs[*reg].clone(),
+            Operand::Const(_, ty) => ty.clone(),
+        }
+    }
+
+    fn operand(&mut self, operand: &Operand) -> String {
+        match operand {
+            Operand::Reg(reg) => {
+                let ty = self.reg_ty(*reg);
+                self.temp(&format!("load {ty}, ptr %r{reg}"))
+            }
+            Operand::Const(literal, ty) => self.module.constant(literal, ty),
+        }
+    }
+
+    // An operand with its type, as arguments are written
+    fn typed(&mut self, operand: &Operand) -> String {
+        let ty = self.module.ty(&self.operand_type(operand));
+        format!("{ty} {}", self.operand(operand))
+    }
+
+    fn set(&mut self, reg: Reg, value: &str) {
+        let ty = self.reg_ty(reg);
+        self.emit(&format!("store {ty} {value}, ptr %r{reg}"));
+    }
+
+    fn set_dest(&mut self, dest: Option<Reg>, value: &str) {
+        if let Some(dest) = dest {
+            self.set(dest, value);
+        }
+    }
+
+    // The file, and the line and column packed into a word, of an `nrt_location`
+    fn location(&mut self, span: Span) -> String {
+        // Code from a standard module written in Nimra has no place in the source
+        if span.line == 0 {
+            return "ptr null, i64 0".to_string();
+        }
+        let file = self.module.string(&self.module.file.clone());
+        format!(
+            "ptr {file}, i64 {}",
+            span.line as u64 | (span.column as u64) << 32
+        )
+    }
+
+    fn panic(&mut self, span: Span, message: &str) {
+        let message = self.module.string(message);
+        let location = self.location(span);
+        self.emit(&format!(
+            "call void @nrt_panic_message({location}, ptr {message})"
+        ));
+        self.emit("unreachable");
+    }
+
+    // Panics if `condition` holds, and carries on otherwise
+    fn panic_if(&mut self, condition: &str, span: Span, message: &str) {
+        let (fail, pass) = (self.new_label(), self.new_label());
+        self.emit(&format!("br i1 {condition}, label %{fail}, label %{pass}"));
+        self.label(&fail);
+        self.panic(span, message);
+        self.label(&pass);
+    }
+
+    // Converts an integer, bool or char to another such type the way C does
+    fn convert(&mut self, value: &str, from: &Type, to: &Type) -> String {
+        let (from_bits, to_bits) = (bits(from), bits(to));
+        if *to == Type::Bool && from_bits > 1 {
+            let ty = self.module.ty(from);
+            return self.temp(&format!("icmp ne {ty} {value}, 0"));
+        }
+        if from_bits == to_bits || from_bits == 0 || to_bits == 0 {
+            return value.to_string();
+        }
+        let (from_ty, to_ty) = (self.module.ty(from), self.module.ty(to));
+        let conversion = if to_bits < from_bits {
+            "trunc"
+        } else if from.is_signed() {
+            "sext"
+        } else {
+            "zext"
+        };
+        self.temp(&format!("{conversion} {from_ty} {value} to {to_ty}"))
+    }
+
+    fn size_of(&mut self, ty: &Type) -> String {
+        let ty = self.module.ty(ty);
+        let end = self.temp(&format!("getelementptr {ty}, ptr null, i32 1"));
+        self.temp(&format!("ptrtoint ptr {end} to i64"))
+    }
+
+    // The pointer and length of a `str` or `String`
+    fn text(&mut self, value: &str, ty: &Type) -> (String, String) {
+        let ty = self.module.ty(ty);
+        let pointer = self.temp(&format!("extractvalue {ty} {value}, 0"));
+        let len = self.temp(&format!("extractvalue {ty} {value}, 1"));
+        (pointer, len)
+    }
+
+    // Calls a runtime function that returns a `String` through a pointer
+    fn string_call(&mut self, function: &str, args: &str) -> String {
+        let result = self.slot("%nrt_string");
+        self.emit(&format!(
+            "call void @{function}(ptr sret(%nrt_string) {result}, {args})"
+        ));
+        self.temp(&format!("load %nrt_string, ptr {result}"))
+    }
+
+    // Checks an index against a length, returning it as a 64-bit integer
+    fn check_index(&mut self, index: &Operand, len: &str, span: Span) -> String {
+        let ty = self.operand_type(index);
+        let value = self.operand(index);
+        let index = self.convert(&value, &ty, &Type::U64);
+        let location = self.location(span);
+        self.temp(&format!(
+            "call i64 @nrt_check_index(i64 {index}, i64 {len}, {location})"
+        ))
+    }
+
+    // The address of an element of the array `array` points to, or of a buffer
+    fn element_address(
+        &mut self,
+        base: &str,
+        ty: &Type,
+        index: &Operand,
+        span: Span,
+    ) -> Result<String, String> {
+        match ty {
+            Type::Buffer(element) => {
+                let len = self.temp(&format!("call i64 @nrt_buffer_len(ptr {base})"));
+                let index = self.check_index(index, &len, span);
+                let element = self.module.ty(element);
+                Ok(self.temp(&format!("getelementptr {element}, ptr {base}, i64 {index}")))
+            }
+            Type::Array(_, Length::Known(len)) => {
+                let index = self.check_index(index, &len.to_string(), span);
+                let array = self.module.ty(ty);
+                Ok(self.temp(&format!(
+                    "getelementptr {array}, ptr {base}, i64 0, i64 {index}"
+                )))
+            }
+            ty => Err(format!("Cannot index into a value of type {ty}")),
+        }
+    }
+
+    fn terminator(&mut self, terminator: &Terminator) -> Result<(), String> {
+        match terminator {
+            Terminator::Jump(target) => self.emit(&format!("br label %b{target}")),
+            Terminator::Branch {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                let condition = self.operand(condition);
+                self.emit(&format!(
+                    "br i1 {condition}, label %b{then_block}, label %b{else_block}"
+                ));
+            }
+            Terminator::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                let ty = self.operand_type(value);
+                let value = self.typed(value);
+                let cases = cases
+                    .iter()
+                    .map(|(literal, target)| {
+                        let case = self.module.constant(literal, &ty);
+                        format!("{} {case}, label %b{target}", self.module.ty(&ty))
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                self.emit(&format!("switch {value}, label %b{default} [ {cases} ]"));
+            }
+            Terminator::Return(value) => {
+                let value = match value {
+                    Some(value) if self.return_type != Type::Void => self.typed(value),
+                    _ => "void".to_string(),
+                };
+                if self.scope.is_some() {
+                    self.emit("store ptr %caller, ptr @nrt_frame_top");
+                }
+                self.emit(&format!("ret {value}"));
+            }
+            Terminator::Unreachable => self.emit("unreachable"),
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) -> Result<(), String> {
+        match instruction {
+            Instruction::Binary { span, .. }
+            | Instruction::Unary { span, .. }
+            | Instruction::Intrinsic { span, .. }
+            | Instruction::Index { span, .. }
+            | Instruction::IndexAddress { span, .. } => self.span = *span,
+            _ => {}
+        }
+        match instruction {
+            Instruction::Copy { dest, value } => {
+                let value = self.operand(value);
+                self.set(*dest, &value);
+            }
+            Instruction::Zero { dest } => self.set(*dest, "zeroinitializer"),
+            Instruction::Binary {
+                dest,
+                op,
+                lhs,
+                rhs,
+                span,
+            } => {
+                let ty = self.operand_type(lhs);
+                let (lhs, rhs) = (self.operand(lhs), self.operand(rhs));
+                let value = if ty.is_integer()
+                    && matches!(
+                        op,
+                        BinaryOp::Add
+                            | BinaryOp::Sub
+                            | BinaryOp::Mul
+                            | BinaryOp::Div
+                            | BinaryOp::Rem
+                    ) {
+                    let overflow = if self.module.debug {
+                        Overflow::Panic
+                    } else {
+                        Overflow::Wrap
+                    };
+                    self.arithmetic(*op, &ty, &lhs, &rhs, overflow, *span)
+                } else {
+                    self.compare(*op, &ty, &lhs, &rhs)?
+                };
+                self.set(*dest, &value);
+            }
+            Instruction::Unary {
+                dest,
+                op,
+                operand,
+                span,
+            } => {
+                let ty = self.operand_type(operand);
+                let value = self.operand(operand);
+                let result = match op {
+                    UnaryOp::Not => self.temp(&format!("xor i1 {value}, true")),
+                    UnaryOp::Neg if self.module.debug => {
+                        let function = self.module.overflow_intrinsic(BinaryOp::Sub, &ty);
+                        let t = self.module.ty(&ty);
+                        let pair = self.temp(&format!(
+                            "call {{ {t}, i1 }} {function}({t} 0, {t} {value})"
+                        ));
+                        let overflowed =
+                            self.temp(&format!("extractvalue {{ {t}, i1 }} {pair}, 1"));
+                        self.panic_if(&overflowed, *span, "attempt to negate with overflow");
+                        self.temp(&format!("extractvalue {{ {t}, i1 }} {pair}, 0"))
+                    }
+                    UnaryOp::Neg => {
+                        let t = self.module.ty(&ty);
+                        self.temp(&format!("sub {t} 0, {value}"))
+                    }
+                };
+                self.set(*dest, &result);
+            }
+            Instruction::Cast { dest, value } => {
+                let from = self.operand_type(value);
+                let value = self.operand(value);
+                let value = self.convert(&value, &from, &self.regs[*dest].clone());
+                self.set(*dest, &value);
+            }
+            Instruction::Call {
+                dest,
+                function,
+                args,
+            } => {
+                let return_type = self
+                    .module
+                    .returns
+                    .get(function)
+                    .cloned()
+                    .ok_or_else(|| format!("Call to unknown function {function}"))?;
+                let args = args.iter().map(|arg| self.typed(arg)).collect::<Vec<_>>();
+                let call = format!(
+                    "call {} @fn_{function}({})",
+                    self.module.return_ty(&return_type),
+                    args.join(", ")
+                );
+                self.call(&call, *dest, &return_type);
+            }
+            Instruction::CallValue { dest, callee, args } => {
+                let Type::Fn(_, return_type) = self.operand_type(callee) else {
+                    return Err(format!(
+                        "Cannot call a value of type {}",
+                        self.operand_type(callee)
+                    ));
+                };
+                let callee = self.operand(callee);
+                let args = args.iter().map(|arg| self.typed(arg)).collect::<Vec<_>>();
+                let returns = self.module.return_ty(&return_type);
+                // A closure that captures variables takes them first
+                let with_env = self.temp(&format!("extractvalue %nrt_fn {callee}, 1"));
+                let is_closure = self.temp(&format!("icmp ne ptr {with_env}, null"));
+                let (closure, plain, done) = (self.new_label(), self.new_label(), self.new_label());
+                self.emit(&format!(
+                    "br i1 {is_closure}, label %{closure}, label %{plain}"
+                ));
+                self.label(&closure);
+                let env = self.temp(&format!("extractvalue %nrt_fn {callee}, 2"));
+                let env_args = std::iter::once(format!("ptr {env}"))
+                    .chain(args.iter().cloned())
+                    .collect::<Vec<_>>();
+                let call = format!("call {returns} {with_env}({})", env_args.join(", "));
+                self.call(&call, *dest, &return_type);
+                self.emit(&format!("br label %{done}"));
+                self.label(&plain);
+                let function = self.temp(&format!("extractvalue %nrt_fn {callee}, 0"));
+                let call = format!("call {returns} {function}({})", args.join(", "));
+                self.call(&call, *dest, &return_type);
+                self.emit(&format!("br label %{done}"));
+                self.label(&done);
+            }
+            Instruction::CallDyn {
+                dest,
+                trait_name,
+                method,
+                args,
+            } => {
+                let methods = self
+                    .module
+                    .traits
+                    .get(trait_name)
+                    .cloned()
+                    .unwrap_or_default();
+                let (index, signature) = methods
+                    .iter()
+                    .enumerate()
+                    .find(|(_, m)| m.name == *method)
+                    .ok_or_else(|| format!("Trait {trait_name} has no method {method}"))?;
+                let (receiver, rest) = args
+                    .split_first()
+                    .ok_or_else(|| format!("Call to {method} with no receiver"))?;
+                let receiver = self.operand(receiver);
+                let data = self.temp(&format!("extractvalue %nrt_dyn {receiver}, 0"));
+                let vtable = self.temp(&format!("extractvalue %nrt_dyn {receiver}, 1"));
+                let entry = self.temp(&format!("getelementptr ptr, ptr {vtable}, i64 {index}"));
+                let function = self.temp(&format!("load ptr, ptr {entry}"));
+                let args = std::iter::once(format!("ptr {data}"))
+                    .chain(rest.iter().map(|arg| self.typed(arg)))
+                    .collect::<Vec<_>>();
+                let call = format!(
+                    "call {} {function}({})",
+                    self.module.return_ty(&signature.return_type),
+                    args.join(", ")
+                );
+                self.call(&call, *dest, &signature.return_type);
+            }
+            Instruction::Intrinsic {
+                dest,
+                intrinsic,
+                args,
+                span,
+            } => self.intrinsic(*dest, *intrinsic, args, *span)?,
+            Instruction::Print {
+                function,
+                pieces,
+                args,
+            } => self.print(*function, pieces, args)?,
+            Instruction::ToDyn { dest, value } => {
+                let Type::Dyn(trait_name) = self.regs[*dest].clone() else {
+                    return Err(format!("Cannot convert a value to {}", self.regs[*dest]));
+                };
+                let ty = self.operand_type(value);
+                let value = self.typed(value);
+                // The value is moved to the heap
+                let size = self.size_of(&ty);
+                let data = self.temp(&format!("call ptr @nrt_alloc(i64 {size})"));
+                self.emit(&format!("store {value}, ptr {data}"));
+                let object = self.temp(&format!(
+                    "insertvalue %nrt_dyn zeroinitializer, ptr {data}, 0"
+                ));
+                let object = self.temp(&format!(
+                    "insertvalue %nrt_dyn {object}, ptr @{}, 1",
+                    vtable_name(&trait_name, &ty)
+                ));
+                self.set(*dest, &object);
+            }
+            Instruction::MakeFn {
+                dest,
+                function,
+                env,
+            } => {
+                let value = match env {
+                    None => self.temp(&format!(
+                        "insertvalue %nrt_fn zeroinitializer, ptr @fn_{function}, 0"
+                    )),
+                    Some(env) => {
+                        let env = self.operand(env);
+                        let value = self.temp(&format!(
+                            "insertvalue %nrt_fn zeroinitializer, ptr @fn_{function}, 1"
+                        ));
+                        self.temp(&format!("insertvalue %nrt_fn {value}, ptr {env}, 2"))
+                    }
+                };
+                self.set(*dest, &value);
+            }
+            Instruction::Struct { dest, fields } => {
+                let ty = self.regs[*dest].clone();
+                let name = self.module.ty(&ty);
+                let mut value = "zeroinitializer".to_string();
+                for (field, operand) in fields {
+                    let (index, _) = self.module.field(&ty, field)?;
+                    let field = self.typed(operand);
+                    value = self.temp(&format!("insertvalue {name} {value}, {field}, {index}"));
+                }
+                self.set(*dest, &value);
+            }
+            Instruction::Array { dest, elements } => {
+                let ty = self.reg_ty(*dest);
+                let mut value = "zeroinitializer".to_string();
+                for (index, element) in elements.iter().enumerate() {
+                    let element = self.typed(element);
+                    value = self.temp(&format!("insertvalue {ty} {value}, {element}, {index}"));
+                }
+                self.set(*dest, &value);
+            }
+            Instruction::ArrayRepeat { dest, value } => {
+                let Type::Array(_, Length::Known(len)) = self.regs[*dest] else {
+                    return Err(format!("Cannot repeat a value into {}", self.regs[*dest]));
+                };
+                let array = self.reg_ty(*dest);
+                let value = self.typed(value);
+                // Stores the value into each element in turn
+                let (from, head, body, done) = (
+                    self.block.clone(),
+                    self.new_label(),
+                    self.new_label(),
+                    self.new_label(),
+                );
+                let (index, next) = (self.fresh(), self.fresh());
+                self.emit(&format!("br label %{head}"));
+                self.label(&head);
+                self.emit(&format!(
+                    "{index} = phi i64 [ 0, %{from} ], [ {next}, %{body} ]"
+                ));
+                let more = self.temp(&format!("icmp ult i64 {index}, {len}"));
+                self.emit(&format!("br i1 {more}, label %{body}, label %{done}"));
+                self.label(&body);
+                let element = self.temp(&format!(
+                    "getelementptr {array}, ptr %r{dest}, i64 0, i64 {index}"
+                ));
+                self.emit(&format!("store {value}, ptr {element}"));
+                self.emit(&format!("{next} = add i64 {index}, 1"));
+                self.emit(&format!("br label %{head}"));
+                self.label(&done);
+            }
+            Instruction::Field { dest, value, field } => {
+                let ty = self.operand_type(value);
+                let (index, _) = self.module.field(&ty, field)?;
+                let value = self.typed(value);
+                let field = self.temp(&format!("extractvalue {value}, {index}"));
+                self.set(*dest, &field);
+            }
+            Instruction::Index {
+                dest,
+                array,
+                index,
+                span,
+            } => {
+                let ty = self.operand_type(array);
+                // An array is indexed where its register keeps it
+                let base = match array {
+                    Operand::Reg(reg) if matches!(ty, Type::Array(..)) => format!("%r{reg}"),
+                    array => self.operand(array),
+                };
+                let address = self.element_address(&base, &ty, index, *span)?;
+                let element = self.reg_ty(*dest);
+                let value = self.temp(&format!("load {element}, ptr {address}"));
+                self.set(*dest, &value);
+            }
+            Instruction::AddressOf { dest, reg } => self.set(*dest, &format!("%r{reg}")),
+            Instruction::FieldAddress { dest, base, field } => {
+                let ty = pointee(&self.operand_type(base));
+                let (index, _) = self.module.field(&ty, field)?;
+                let name = self.module.ty(&ty);
+                let base = self.operand(base);
+                let address = self.temp(&format!(
+                    "getelementptr {name}, ptr {base}, i32 0, i32 {index}"
+                ));
+                self.set(*dest, &address);
+            }
+            Instruction::IndexAddress {
+                dest,
+                base,
+                index,
+                span,
+            } => {
+                let ty = pointee(&self.operand_type(base));
+                let base = self.operand(base);
+                let address = self.element_address(&base, &ty, index, *span)?;
+                self.set(*dest, &address);
+            }
+            Instruction::Load { dest, address } => {
+                let ty = self.reg_ty(*dest);
+                let address = self.operand(address);
+                let value = self.temp(&format!("load {ty}, ptr {address}"));
+                self.set(*dest, &value);
+            }
+            Instruction::Store { address, value } => {
+                let address = self.operand(address);
+                let value = self.typed(value);
+                self.emit(&format!("store {value}, ptr {address}"));
+            }
+            Instruction::Alloc { dest } => {
+                let size = self.size_of(&pointee(&self.regs[*dest].clone()));
+                let address = self.temp(&format!("call ptr @nrt_alloc(i64 {size})"));
+                self.set(*dest, &address);
+            }
+            Instruction::Move { dest, address } => {
+                let ty = self.reg_ty(*dest);
+                let address = self.operand(address);
+                let value = self.temp(&format!("load {ty}, ptr {address}"));
+                self.emit(&format!("store {ty} zeroinitializer, ptr {address}"));
+                self.set(*dest, &value);
+            }
+            Instruction::Drop { address } => {
+                let ty = pointee(&self.operand_type(address));
+                let address = self.operand(address);
+                self.emit(&format!(
+                    "call void @dr_{}(ptr {address})",
+                    generator::mangle(&ty)
+                ));
+            }
+            Instruction::NextChar { dest, text, pos } => {
+                let ty = self.operand_type(text);
+                let text = self.operand(text);
+                let (pointer, len) = self.text(&text, &ty);
+                let pos = self.operand(pos);
+                let c = self.temp(&format!(
+                    "call i32 @nrt_str_next_char(ptr {pointer}, i64 {len}, ptr {pos})"
+                ));
+                self.set(*dest, &c);
+            }
+            Instruction::Phi { .. } => {
+                return Err("Phis must be removed before code generation".to_string())
+            }
+        }
+        Ok(())
+    }
+
+    // Emits a call, storing what it returns in `dest` if there is one
+    fn call(&mut self, call: &str, dest: Option<Reg>, return_type: &Type) {
+        match dest {
+            Some(dest) if *return_type != Type::Void => {
+                let value = self.temp(call);
+                self.set(dest, &value);
+            }
+            _ => self.emit(call),
+        }
+    }
+
+    fn compare(&mut self, op: BinaryOp, ty: &Type, lhs: &str, rhs: &str) -> Result<String, String> {
+        let signed = ty.is_signed();
+        let condition = match op {
+            BinaryOp::Equal => "eq",
+            BinaryOp::NotEqual => "ne",
+            BinaryOp::Less if signed => "slt",
+            BinaryOp::Less => "ult",
+            BinaryOp::LessEqual if signed => "sle",
+            BinaryOp::LessEqual => "ule",
+            BinaryOp::Greater if signed => "sgt",
+            BinaryOp::Greater => "ugt",
+            BinaryOp::GreaterEqual if signed => "sge",
+            BinaryOp::GreaterEqual => "uge",
+            op => return Err(format!("Cannot apply {op:?} to values of type {ty}")),
+        };
+        if bits(ty) == 0 {
+            return Err(format!("Cannot compare values of type {ty}"));
+        }
+        let t = self.module.ty(ty);
+        Ok(self.temp(&format!("icmp {condition} {t} {lhs}, {rhs}")))
+    }
+
+    /// Integer arithmetic, which panics on division by zero whatever
+    /// overflow does
+    fn arithmetic(
+        &mut self,
+        op: BinaryOp,
+        ty: &Type,
+        lhs: &str,
+        rhs: &str,
+        overflow: Overflow,
+        span: Span,
+    ) -> String {
+        let t = self.module.ty(ty);
+        let signed = ty.is_signed();
+        if let BinaryOp::Div | BinaryOp::Rem = op {
+            let (zero_message, overflow_message, instruction) = if op == BinaryOp::Div {
+                (
+                    "attempt to divide by zero",
+                    "attempt to divide with overflow",
+                    if signed { "sdiv" } else { "udiv" },
+                )
+            } else {
+                (
+                    "attempt to calculate the remainder with a divisor of zero",
+                    "attempt to calculate the remainder with overflow",
+                    if signed { "srem" } else { "urem" },
+                )
+            };
+            let zero = self.temp(&format!("icmp eq {t} {rhs}, 0"));
+            self.panic_if(&zero, span, zero_message);
+            if signed {
+                let min = ty.integer_range().map_or(0, |(min, _)| min);
+                let is_min = self.temp(&format!("icmp eq {t} {lhs}, {min}"));
+                let is_minus_one = self.temp(&format!("icmp eq {t} {rhs}, -1"));
+                let overflowed = self.temp(&format!("and i1 {is_min}, {is_minus_one}"));
+                self.panic_if(&overflowed, span, overflow_message);
+            }
+            return self.temp(&format!("{instruction} {t} {lhs}, {rhs}"));
+        }
+        if overflow == Overflow::Wrap {
+            let instruction = match op {
+                BinaryOp::Add => "add",
+                BinaryOp::Sub => "sub",
+                _ => "mul",
+            };
+            return self.temp(&format!("{instruction} {t} {lhs}, {rhs}"));
+        }
+        let function = self.module.overflow_intrinsic(op, ty);
+        let pair = self.temp(&format!(
+            "call {{ {t}, i1 }} {function}({t} {lhs}, {t} {rhs})"
+        ));
+        let result = self.temp(&format!("extractvalue {{ {t}, i1 }} {pair}, 0"));
+        let overflowed = self.temp(&format!("extractvalue {{ {t}, i1 }} {pair}, 1"));
+        if overflow == Overflow::Panic {
+            let message = match op {
+                BinaryOp::Add => "attempt to add with overflow",
+                BinaryOp::Sub => "attempt to subtract with overflow",
+                _ => "attempt to multiply with overflow",
+            };
+            self.panic_if(&overflowed, span, message);
+            return result;
+        }
+        // Overflow only goes past max when adding a positive number, and so on
+        let (min, max) = ty.integer_range().unwrap_or((0, 0));
+        let (min, max) = (canonical(min as i64, ty), canonical(max as i64, ty));
+        let greater = if signed { "sgt" } else { "ugt" };
+        let b_positive = self.temp(&format!("icmp {greater} {t} {rhs}, 0"));
+        let to_max = match op {
+            BinaryOp::Add => b_positive,
+            BinaryOp::Sub => self.temp(&format!("xor i1 {b_positive}, true")),
+            _ => {
+                let a_positive = self.temp(&format!("icmp {greater} {t} {lhs}, 0"));
+                self.temp(&format!("icmp eq i1 {a_positive}, {b_positive}"))
+            }
+        };
+        let saturated = self.temp(&format!("select i1 {to_max}, {t} {max}, {t} {min}"));
+        self.temp(&format!(
+            "select i1 {overflowed}, {t} {saturated}, {t} {result}"
+        ))
+    }
+
+    /// Whether integer arithmetic overflows or divides by zero, as an `i1`
+    fn overflows(&mut self, op: BinaryOp, ty: &Type, lhs: &str, rhs: &str) -> String {
+        let t = self.module.ty(ty);
+        if let BinaryOp::Div | BinaryOp::Rem = op {
+            let zero = self.temp(&format!("icmp eq {t} {rhs}, 0"));
+            if !ty.is_signed() {
+                return zero;
+            }
+            let min = ty.integer_range().map_or(0, |(min, _)| min);
+            let is_min = self.temp(&format!("icmp eq {t} {lhs}, {min}"));
+            let is_minus_one = self.temp(&format!("icmp eq {t} {rhs}, -1"));
+            let overflowed = self.temp(&format!("and i1 {is_min}, {is_minus_one}"));
+            return self.temp(&format!("or i1 {zero}, {overflowed}"));
+        }
+        let function = self.module.overflow_intrinsic(op, ty);
+        let pair = self.temp(&format!(
+            "call {{ {t}, i1 }} {function}({t} {lhs}, {t} {rhs})"
+        ));
+        self.temp(&format!("extractvalue {{ {t}, i1 }} {pair}, 1"))
+    }
+
+    fn intrinsic(
+        &mut self,
+        dest: Option<Reg>,
+        intrinsic: Intrinsic,
+        args: &[Operand],
+        span: Span,
+    ) -> Result<(), String> {
+        let types = args
+            .iter()
+            .map(|arg| self.operand_type(arg))
+            .collect::<Vec<_>>();
+        let first = types.first().cloned().unwrap_or(Type::Void);
+        let value = match intrinsic {
+            Intrinsic::Exit => {
+                let code = self.operand(&args[0]);
+                let code = self.convert(&code, &first, &Type::I32);
+                self.emit(&format!("call void @exit(i32 {code})"));
+                return Ok(());
+            }
+            Intrinsic::Panic => {
+                let message = self.operand(&args[0]);
+                let (pointer, len) = self.text(&message, &first);
+                let location = self.location(span);
+                self.emit(&format!(
+                    "call void @nrt_panic({location}, ptr {pointer}, i64 {len})"
+                ));
+                return Ok(());
+            }
+            Intrinsic::Unwrap | Intrinsic::UnwrapOr => {
+                return self.unwrap(dest, intrinsic, args, &first, span)
+            }
+            Intrinsic::StringNew => "zeroinitializer".to_string(),
+            Intrinsic::StringFrom => {
+                let s = self.operand(&args[0]);
+                let (pointer, len) = self.text(&s, &first);
+                self.string_call("nrt_string_from", &format!("ptr {pointer}, i64 {len}"))
+            }
+            Intrinsic::StringAsStr => {
+                let s = self.operand(&args[0]);
+                let (pointer, len) = self.text(&s, &first);
+                let value = self.temp(&format!(
+                    "insertvalue %nrt_str zeroinitializer, ptr {pointer}, 0"
+                ));
+                self.temp(&format!("insertvalue %nrt_str {value}, i64 {len}, 1"))
+            }
+            Intrinsic::StringPushStr => {
+                let place = self.operand(&args[0]);
+                let s = self.operand(&args[1]);
+                let (pointer, len) = self.text(&s, &types[1]);
+                self.emit(&format!(
+                    "call void @nrt_string_push_str(ptr {place}, ptr {pointer}, i64 {len})"
+                ));
+                return Ok(());
+            }
+            Intrinsic::StringPushChar => {
+                let place = self.operand(&args[0]);
+                let c = self.operand(&args[1]);
+                self.emit(&format!(
+                    "call void @nrt_string_push_char(ptr {place}, i32 {c})"
+                ));
+                return Ok(());
+            }
+            Intrinsic::StrLen => {
+                let s = self.operand(&args[0]);
+                self.text(&s, &first).1
+            }
+            Intrinsic::StrSlice => {
+                let s = self.operand(&args[0]);
+                let (pointer, len) = self.text(&s, &first);
+                let start = self.operand(&args[1]);
+                let start = self.convert(&start, &types[1], &Type::U64);
+                let end = self.operand(&args[2]);
+                let end = self.convert(&end, &types[2], &Type::U64);
+                let location = self.location(span);
+                self.temp(&format!(
+                    "call %nrt_str @nrt_str_slice(ptr {pointer}, i64 {len}, i64 {start}, i64 {end}, {location})"
+                ))
+            }
+            Intrinsic::StrConcat | Intrinsic::StrEqual | Intrinsic::StrCompare => {
+                let a = self.operand(&args[0]);
+                let (a_pointer, a_len) = self.text(&a, &first);
+                let b = self.operand(&args[1]);
+                let (b_pointer, b_len) = self.text(&b, &types[1]);
+                let args = format!("ptr {a_pointer}, i64 {a_len}, ptr {b_pointer}, i64 {b_len}");
+                match intrinsic {
+                    Intrinsic::StrConcat => self.string_call("nrt_str_concat", &args),
+                    Intrinsic::StrEqual => {
+                        self.temp(&format!("call zeroext i1 @nrt_str_equal({args})"))
+                    }
+                    _ => self.temp(&format!("call i32 @nrt_str_compare({args})")),
+                }
+            }
+            Intrinsic::StrParseI64 => {
+                let s = self.operand(&args[0]);
+                let (pointer, len) = self.text(&s, &first);
+                let location = self.location(span);
+                self.temp(&format!(
+                    "call i64 @nrt_str_parse_i64(ptr {pointer}, i64 {len}, {location})"
+                ))
+            }
+            Intrinsic::IntToString => {
+                let n = self.operand(&args[0]);
+                let (function, wide) = if first.is_signed() {
+                    ("nrt_i64_to_string", Type::I64)
+                } else {
+                    ("nrt_u64_to_string", Type::U64)
+                };
+                let n = self.convert(&n, &first, &wide);
+                self.string_call(function, &format!("i64 {n}"))
+            }
+            Intrinsic::CharToString => {
+                let c = self.operand(&args[0]);
+                self.string_call("nrt_char_to_string", &format!("i32 {c}"))
+            }
+            Intrinsic::BoxNew => {
+                let size = self.size_of(&first);
+                let value = self.typed(&args[0]);
+                let address = self.temp(&format!("call ptr @nrt_box_new(i64 {size})"));
+                self.emit(&format!("store {value}, ptr {address}"));
+                address
+            }
+            Intrinsic::BufferNew => "null".to_string(),
+            Intrinsic::BufferLen => {
+                let items = self.operand(&args[0]);
+                self.temp(&format!("call i64 @nrt_buffer_len(ptr {items})"))
+            }
+            Intrinsic::BufferPush => {
+                let element = buffer_element(&pointee(&first))?;
+                let place = self.operand(&args[0]);
+                let value = self.typed(&args[1]);
+                let size = self.size_of(&element);
+                let items = self.temp(&format!("load ptr, ptr {place}"));
+                let items = self.temp(&format!(
+                    "call ptr @nrt_buffer_push(ptr {items}, i64 {size})"
+                ));
+                self.emit(&format!("store ptr {items}, ptr {place}"));
+                let len = self.temp(&format!("call i64 @nrt_buffer_len(ptr {items})"));
+                let last = self.temp(&format!("sub i64 {len}, 1"));
+                let t = self.module.ty(&element);
+                let address = self.temp(&format!("getelementptr {t}, ptr {items}, i64 {last}"));
+                self.emit(&format!("store {value}, ptr {address}"));
+                return Ok(());
+            }
+            Intrinsic::BufferPop => {
+                let element = buffer_element(&pointee(&first))?;
+                let place = self.operand(&args[0]);
+                let items = self.temp(&format!("load ptr, ptr {place}"));
+                let location = self.location(span);
+                let index = self.temp(&format!(
+                    "call i64 @nrt_buffer_pop(ptr {items}, {location})"
+                ));
+                let t = self.module.ty(&element);
+                let address = self.temp(&format!("getelementptr {t}, ptr {items}, i64 {index}"));
+                self.temp(&format!("load {t}, ptr {address}"))
+            }
+            Intrinsic::Hash => {
+                let value = self.operand(&args[0]);
+                if first.is_string() {
+                    let (pointer, len) = self.text(&value, &first);
+                    self.temp(&format!("call i64 @nrt_hash_str(ptr {pointer}, i64 {len})"))
+                } else {
+                    let value = self.convert(&value, &first, &Type::U64);
+                    self.temp(&format!("call i64 @nrt_hash_u64(i64 {value})"))
+                }
+            }
+            Intrinsic::ArrayLen => {
+                return Err("The length of an array is known before code generation".to_string())
+            }
+            Intrinsic::Checked(_) => {
+                return Err("Checked arithmetic is lowered before code generation".to_string())
+            }
+            Intrinsic::Overflows(op) => {
+                let (lhs, rhs) = (self.operand(&args[0]), self.operand(&args[1]));
+                self.overflows(op, &first, &lhs, &rhs)
+            }
+            Intrinsic::Wrapping(op) | Intrinsic::Saturating(op) => {
+                let overflow = match intrinsic {
+                    Intrinsic::Wrapping(_) => Overflow::Wrap,
+                    _ => Overflow::Saturate,
+                };
+                let (lhs, rhs) = (self.operand(&args[0]), self.operand(&args[1]));
+                self.arithmetic(op, &first, &lhs, &rhs, overflow, span)
+            }
+        };
+        self.set_dest(dest, &value);
+        Ok(())
+    }
+
+    /// Takes the value out of an `Option` or `Result`. `unwrap` panics if
+    /// there is none, and `unwrap_or` returns a default instead, dropping
+    /// whichever of the value, the error and the default it does not return.
+    fn unwrap(
+        &mut self,
+        dest: Option<Reg>,
+        intrinsic: Intrinsic,
+        args: &[Operand],
+        ty: &Type,
+        span: Span,
+    ) -> Result<(), String> {
+        let (value_type, error_type, message) = match ty {
+            Type::Option(value) => ((**value).clone(), None, "called unwrap on None"),
+            Type::Result(value, error) => (
+                (**value).clone(),
+                Some((**error).clone()),
+                "called unwrap on an Err",
+            ),
+            ty => return Err(format!("Cannot unwrap a value of type {ty}")),
+        };
+        let (tag, _) = self.module.field(ty, sema::tag_field(ty))?;
+        let name = self.module.ty(ty);
+        let wrapped = self.operand(&args[0]);
+        let is_set = self.temp(&format!("extractvalue {name} {wrapped}, {tag}"));
+        let take = |gen: &mut Self, field: &str| -> Result<String, String> {
+            let (index, _) = gen.module.field(ty, field)?;
+            Ok(gen.temp(&format!("extractvalue {name} {wrapped}, {index}")))
+        };
+        if intrinsic == Intrinsic::Unwrap {
+            let missing = self.temp(&format!("xor i1 {is_set}, true"));
+            self.panic_if(&missing, span, message);
+            if value_type != Type::Void {
+                let value = take(self, "value")?;
+                self.set_dest(dest, &value);
+            }
+            return Ok(());
+        }
+        let default = self.operand(&args[1]);
+        let (set, unset, done) = (self.new_label(), self.new_label(), self.new_label());
+        self.emit(&format!("br i1 {is_set}, label %{set}, label %{unset}"));
+        self.label(&set);
+        self.drop_value(&default, &value_type);
+        let value = take(self, "value")?;
+        self.set_dest(dest, &value);
+        self.emit(&format!("br label %{done}"));
+        self.label(&unset);
+        if let Some(error_type) = error_type {
+            let error = take(self, "error")?;
+            self.drop_value(&error, &error_type);
+        }
+        self.set_dest(dest, &default);
+        self.emit(&format!("br label %{done}"));
+        self.label(&done);
+        Ok(())
+    }
+
+    // Frees what a value owns, if it owns anything
+    fn drop_value(&mut self, value: &str, ty: &Type) {
+        if !self.module.is_owned(ty) {
+            return;
+        }
+        let t = self.module.ty(ty);
+        let place = self.slot(&t);
+        self.emit(&format!("store {t} {value}, ptr {place}"));
+        self.emit(&format!(
+            "call void @dr_{}(ptr {place})",
+            generator::mangle(ty)
+        ));
+    }
+
+    /// Lowers `print`, `println` and `eprintln` to one write per piece.
+    /// Text and strings are written with `fwrite`, which unlike a `%s`
+    /// conversion does not stop at a NUL byte in them.
+    fn print(
+        &mut self,
+        function: IoFunction,
+        pieces: &[FormatPiece],
+        args: &[Operand],
+    ) -> Result<(), String> {
+        let stream = if function == IoFunction::Eprintln {
+            "@stderr"
+        } else {
+            "@stdout"
+        };
+        let stream = self.temp(&format!("load ptr, ptr {stream}"));
+        let newline = if function == IoFunction::Print {
+            ""
+        } else {
+            "\n"
+        };
+        // Text is written once it is known where it ends
+        let mut text = String::new();
+        let mut args = args.iter();
+        for piece in pieces {
+            let arg = match piece {
+                FormatPiece::Text(piece) => {
+                    text.push_str(piece);
+                    continue;
+                }
+                FormatPiece::Placeholder => args
+                    .next()
+                    .ok_or(format!("{function:?} has too few arguments"))?,
+            };
+            if !text.is_empty() {
+                self.write_text(&std::mem::take(&mut text), &stream);
+            }
+            let ty = self.operand_type(arg);
+            let value = self.operand(arg);
+            match ty {
+                Type::Bool => {
+                    let (yes, no) = (self.module.string("true"), self.module.string("false"));
+                    let text = self.temp(&format!("select i1 {value}, ptr {yes}, ptr {no}"));
+                    self.emit(&format!("call i32 @fputs(ptr {text}, ptr {stream})"));
+                }
+                // A char is written from a string of its own, freed right after
+                Type::Char => {
+                    let string = self.slot("%nrt_string");
+                    self.emit(&format!(
+                        "call void @nrt_char_to_string(ptr sret(%nrt_string) {string}, i32 {value})"
+                    ));
+                    let text = self.temp(&format!("load %nrt_string, ptr {string}"));
+                    let (pointer, len) = self.text(&text, &Type::String);
+                    self.emit(&format!(
+                        "call i64 @fwrite(ptr {pointer}, i64 1, i64 {len}, ptr {stream})"
+                    ));
+                    self.emit(&format!("call void @nrt_string_free(ptr {string})"));
+                }
+                Type::Str | Type::String => {
+                    let (pointer, len) = self.text(&value, &ty);
+                    self.emit(&format!(
+                        "call i64 @fwrite(ptr {pointer}, i64 1, i64 {len}, ptr {stream})"
+                    ));
+                }
+                ty if ty.is_integer() => {
+                    let (conversion, wide) = match (ty.is_signed(), bits(&ty)) {
+                        (true, 64) => ("%ld", Type::I64),
+                        (true, _) => ("%d", Type::I32),
+                        (false, 64) => ("%lu", Type::U64),
+                        (false, _) => ("%u", Type::U32),
+                    };
+                    // Variadic arguments are promoted to int
+                    let value = self.convert(&value, &ty, &wide);
+                    let format = self.module.string(conversion);
+                    let wide = self.module.ty(&wide);
+                    self.emit(&format!(
+                        "call i32 (ptr, ptr, ...) @fprintf(ptr {stream}, ptr {format}, {wide} {value})"
+                    ));
+                }
+                ty => return Err(format!("Cannot print a value of type {ty}")),
+            }
+        }
+        text.push_str(newline);
+        if !text.is_empty() {
+            self.write_text(&text, &stream);
+        }
+        Ok(())
+    }
+
+    // Writes literal text, which may hold NUL bytes, to a stream
+    fn write_text(&mut self, text: &str, stream: &str) {
+        let pointer = self.module.string(text);
+        self.emit(&format!(
+            "call i64 @fwrite(ptr {pointer}, i64 1, i64 {}, ptr {stream})",
+            text.len()
+        ));
+    }
+}
+
+fn vtable_name(trait_name: &str, ty: &Type) -> String {
+    format!(
+        "vi_{}{trait_name}{}",
+        trait_name.len(),
+        generator::mangle(ty)
+    )
+}
+
+/// The type a reference or box points to
+fn pointee(ty: &Type) -> Type {
+    match ty {
+        Type::Ref(inner, _) | Type::Box(inner) => (**inner).clone(),
+        ty => ty.clone(),
+    }
+}
+
+fn buffer_element(ty: &Type) -> Result<Type, String> {
+    match ty {
+        Type::Buffer(element) => Ok((**element).clone()),
+        ty => Err(format!("{ty} is not a buffer")),
+    }
+}
+
+/// How many bits an integer, bool or char takes, or 0 for anything else
+fn bits(ty: &Type) -> u32 {
+    match ty {
+        Type::Bool => 1,
+        Type::I8 | Type::U8 => 8,
+        Type::I16 | Type::U16 => 16,
+        Type::I32 | Type::U32 | Type::Char => 32,
+        Type::I64 | Type::U64 => 64,
+        _ => 0,
+    }
+}
+
+/// An integer constant as LLVM reads it for its type: truncated to its bits
+/// and sign-extended from them
+fn canonical(n: i64, ty: &Type) -> i64 {
+    match bits(ty) {
+        bits @ 1..=63 => (n << (64 - bits)) >> (64 - bits),
+        _ => n,
+    }
+}
+
+/// Escapes text for a string constant or metadata string, which have `\XX`
+/// hex escapes
+fn escape_string(s: &str) -> String {
+    let mut escaped = String::new();
+    for byte in s.bytes() {
+        match byte {
+            b' '..=b'~' if byte != b'"' && byte != b'\\' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\{byte:02X}")),
+        }
+    }
+    escaped
+}
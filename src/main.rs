@@ -15,51 +15,153 @@
  * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
  */
 
-mod codegen;
-mod compile_c;
-mod file_handling;
-mod generator;
-mod lexer;
-mod parser;
+extern crate nimra;
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use nimra::options::{self, Backend, ErrorFormat, Options};
+use nimra::{bytecode, file_handling, interp, nbc, vm, wat, Diagnostic, Output, Session};
 
 fn main() {
-    let code = match file_handling::get_code() {
-        Ok(code) => code,
+    let options = match options::parse_args() {
+        Ok(options) => options,
         Err(e) => {
             eprintln!("{e}");
-            return;
+            process::exit(1);
         }
     };
-    let tokens = lexer::lex(code.as_str());
-    let ast = parser::parse(&tokens);
-    let ic = match generator::generate(ast) {
-        Ok(ic) => ic,
-        Err(e) => {
-            eprintln!("IC generation error: {e}");
+    // Bytecode is already compiled, so it is only run or disassembled
+    if options.input.ends_with(".nbc") {
+        let program = match nbc::read(&options.input) {
+            Ok(program) => program,
+            Err(e) => {
+                eprintln!("Bytecode error: {e}");
+                process::exit(1);
+            }
+        };
+        if options.disassemble {
+            print!("{}", bytecode::disassemble(&program));
             return;
         }
-    };
-    let c = match codegen::codegen(ic) {
-        Ok(c) => c,
+        run_bytecode(&program);
+        return;
+    }
+    let code = match file_handling::get_code(&options.input) {
+        Ok(code) => code,
         Err(e) => {
-            eprintln!("Code generation error: {e}");
-            return;
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    };
+    // WebAssembly text is only assembled, which checks modules written by hand
+    if options.input.ends_with(".wat") {
+        finish(wat::assemble(&code, &options), &options);
+        return;
+    }
+    let mut session = Session::new(options);
+    if session.options.interp {
+        if session.options.input.ends_with(".ic") {
+            eprintln!("Error: The interpreter runs Nimra source, not intermediate code");
+            process::exit(1);
+        }
+        let program = session.check_source(&code);
+        report(session.warnings(), &code, &session.options);
+        let program = match program {
+            Ok(program) => program,
+            Err(diagnostics) => {
+                report(&diagnostics, &code, &session.options);
+                process::exit(1);
+            }
+        };
+        // Code sure to fail does not build, so it is not run either
+        if let Err(diagnostic) = session.lower(program.clone()) {
+            report(&[diagnostic], &code, &session.options);
+            process::exit(1);
+        }
+        match interp::run(&program, &session.options) {
+            Ok(code) => process::exit(code),
+            Err(e) => {
+                eprintln!("Interpreter error: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    let artifacts = match session.compile(&code) {
+        Ok(artifacts) => artifacts,
+        Err(diagnostics) => {
+            report(&diagnostics, &code, &session.options);
+            process::exit(1);
         }
     };
-    let output_file = match compile_c::compile(c.as_str()) {
+    report(&artifacts.warnings, &code, &session.options);
+    match artifacts.output {
+        Output::File(path) => finish(Ok(path), &session.options),
+        Output::Text(text) => print!("{text}"),
+        Output::Bytecode(program) => run_bytecode(&program),
+    }
+}
+
+// Prints diagnostics in the format the options ask for, quoting the source
+// they point at for people or as one JSON object a line for tools
+fn report(diagnostics: &[Diagnostic], source: &str, options: &Options) {
+    for diagnostic in diagnostics {
+        match options.error_format {
+            ErrorFormat::Human => eprint!("{}", diagnostic.render(&options.input, source)),
+            ErrorFormat::Json => eprintln!("{}", diagnostic.to_json(&options.input)),
+        }
+    }
+}
+
+// Reports where the output went, or runs it for `nimra run`
+fn finish(output_file: Result<PathBuf, String>, options: &Options) {
+    let output_file = match output_file {
         Ok(path) => path,
         Err(e) => {
             eprintln!("Compilation error: {e}");
-            return;
+            process::exit(1);
         }
     };
+    if options.run {
+        let mut command = if options.backend == Backend::Wasm || options.input.ends_with(".wat") {
+            // WASI programs only see the environment they are given
+            let mut command = Command::new("wasmtime");
+            if let Ok(backtrace) = env::var("NIMRA_BACKTRACE") {
+                command.arg(format!("--env=NIMRA_BACKTRACE={backtrace}"));
+            }
+            command.arg(&output_file);
+            command
+        } else {
+            // A bare file name would be looked up on the PATH
+            Command::new(Path::new(".").join(&output_file))
+        };
+        match command.status() {
+            Ok(status) => process::exit(status.code().unwrap_or(1)),
+            Err(e) => {
+                eprintln!("Failed to run {}: {e}", output_file.display());
+                process::exit(1);
+            }
+        }
+    }
     let output_file_result_string = output_file.into_os_string().into_string();
     let output_file_real_string = match output_file_result_string {
         Ok(s) => s,
         Err(_) => {
             eprintln!("ERROR Output file result string");
-            return;
+            process::exit(1);
         }
     };
     println!("{output_file_real_string}");
 }
+
+// Runs bytecode in the VM and exits with the program's exit code
+fn run_bytecode(program: &bytecode::Program) {
+    match vm::run(program) {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("VM error: {e}");
+            process::exit(1);
+        }
+    }
+}
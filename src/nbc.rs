@@ -0,0 +1,1185 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::convert::TryFrom;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::bytecode::{Arg, Code, Constant, Kind, Op, Piece, Program, Shape};
+use crate::lexer::Span;
+use crate::options::Options;
+use crate::parser::BinaryOp;
+use crate::sema::{Intrinsic, IoFunction};
+
+/// The first bytes of every `.nbc` file
+pub const MAGIC: &[u8; 4] = b"\0nbc";
+
+/// Bumped whenever the encoding of a program or the meaning of an op changes
+pub const VERSION: u64 = 2;
+
+const KINDS: [Kind; 13] = [
+    Kind::I8,
+    Kind::I16,
+    Kind::I32,
+    Kind::I64,
+    Kind::U8,
+    Kind::U16,
+    Kind::U32,
+    Kind::U64,
+    Kind::Bool,
+    Kind::Char,
+    Kind::Str,
+    Kind::String,
+    Kind::Other,
+];
+
+const BINARY_OPS: [BinaryOp; 13] = [
+    BinaryOp::Add,
+    BinaryOp::Sub,
+    BinaryOp::Mul,
+    BinaryOp::Div,
+    BinaryOp::Rem,
+    BinaryOp::Equal,
+    BinaryOp::NotEqual,
+    BinaryOp::Less,
+    BinaryOp::LessEqual,
+    BinaryOp::Greater,
+    BinaryOp::GreaterEqual,
+    BinaryOp::And,
+    BinaryOp::Or,
+];
+
+const IO_FUNCTIONS: [IoFunction; 3] =
+    [IoFunction::Print, IoFunction::Println, IoFunction::Eprintln];
+
+/// The intrinsics without an operator, numbered by their place here. Those
+/// with one come after them, in the order wrapping, checked, saturating.
+const INTRINSICS: [Intrinsic; 24] = [
+    Intrinsic::Exit,
+    Intrinsic::Panic,
+    Intrinsic::StringNew,
+    Intrinsic::StringFrom,
+    Intrinsic::StringAsStr,
+    Intrinsic::StringPushStr,
+    Intrinsic::StringPushChar,
+    Intrinsic::StrLen,
+    Intrinsic::StrSlice,
+    Intrinsic::StrConcat,
+    Intrinsic::StrEqual,
+    Intrinsic::StrCompare,
+    Intrinsic::StrParseI64,
+    Intrinsic::IntToString,
+    Intrinsic::BoxNew,
+    Intrinsic::BufferNew,
+    Intrinsic::BufferLen,
+    Intrinsic::BufferPush,
+    Intrinsic::BufferPop,
+    Intrinsic::Hash,
+    Intrinsic::CharToString,
+    Intrinsic::ArrayLen,
+    Intrinsic::Unwrap,
+    Intrinsic::UnwrapOr,
+];
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    // Unsigned LEB128: seven bits at a time, lowest first, with the top bit
+    // of each byte but the last set
+    fn number(&mut self, mut n: u64) {
+        while n >= 0x80 {
+            self.bytes.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        self.bytes.push(n as u8);
+    }
+
+    // Zigzag, so that small negative numbers are short too
+    fn signed(&mut self, n: i64) {
+        self.number(((n << 1) ^ (n >> 63)) as u64);
+    }
+
+    fn optional(&mut self, n: Option<u32>) {
+        self.number(n.map_or(0, |n| u64::from(n) + 1));
+    }
+
+    fn list<T>(&mut self, items: &[T], mut item: impl FnMut(&mut Writer, &T)) {
+        self.number(items.len() as u64);
+        for each in items {
+            item(self, each);
+        }
+    }
+
+    fn string(&mut self, text: &str) {
+        self.number(text.len() as u64);
+        self.bytes.extend_from_slice(text.as_bytes());
+    }
+
+    fn arg(&mut self, arg: Arg) {
+        self.number(match arg {
+            Arg::Reg(reg) => u64::from(reg) << 1,
+            Arg::Const(id) => u64::from(id) << 1 | 1,
+        });
+    }
+
+    fn args(&mut self, args: &[Arg]) {
+        self.list(args, |w, arg| w.arg(*arg));
+    }
+
+    fn span(&mut self, span: Span) {
+        self.number(span.line as u64);
+        self.number(span.column as u64);
+    }
+
+    fn entry<T: PartialEq>(&mut self, table: &[T], item: &T) {
+        let index = table.iter().position(|found| found == item).unwrap_or(0);
+        self.bytes.push(index as u8);
+    }
+
+    fn intrinsic(&mut self, intrinsic: Intrinsic) {
+        let (code, op) = match intrinsic {
+            Intrinsic::Wrapping(op) => (INTRINSICS.len(), op),
+            Intrinsic::Overflows(op) => (INTRINSICS.len() + 1, op),
+            Intrinsic::Saturating(op) => (INTRINSICS.len() + 2, op),
+            intrinsic => {
+                self.entry(&INTRINSICS, &intrinsic);
+                return;
+            }
+        };
+        self.bytes.push(code as u8);
+        self.entry(&BINARY_OPS, &op);
+    }
+
+    fn op(&mut self, op: &Op) {
+        match op {
+            Op::Copy { dest, value } => {
+                self.bytes.push(0);
+                self.number(u64::from(*dest));
+                self.arg(*value);
+            }
+            Op::Zero { dest } => {
+                self.bytes.push(1);
+                self.number(u64::from(*dest));
+            }
+            Op::Binary {
+                dest,
+                op,
+                kind,
+                lhs,
+                rhs,
+                at,
+            } => {
+                self.bytes.push(2);
+                self.number(u64::from(*dest));
+                self.entry(&BINARY_OPS, op);
+                self.entry(&KINDS, kind);
+                self.arg(*lhs);
+                self.arg(*rhs);
+                self.span(*at);
+            }
+            Op::Neg {
+                dest,
+                kind,
+                value,
+                at,
+            } => {
+                self.bytes.push(3);
+                self.number(u64::from(*dest));
+                self.entry(&KINDS, kind);
+                self.arg(*value);
+                self.span(*at);
+            }
+            Op::Not { dest, value } => {
+                self.bytes.push(4);
+                self.number(u64::from(*dest));
+                self.arg(*value);
+            }
+            Op::Cast { dest, kind, value } => {
+                self.bytes.push(5);
+                self.number(u64::from(*dest));
+                self.entry(&KINDS, kind);
+                self.arg(*value);
+            }
+            Op::Call {
+                dest,
+                function,
+                args,
+            } => {
+                self.bytes.push(6);
+                self.optional(*dest);
+                self.number(u64::from(*function));
+                self.args(args);
+            }
+            Op::CallValue { dest, callee, args } => {
+                self.bytes.push(7);
+                self.optional(*dest);
+                self.arg(*callee);
+                self.args(args);
+            }
+            Op::CallDyn { dest, method, args } => {
+                self.bytes.push(8);
+                self.optional(*dest);
+                self.number(u64::from(*method));
+                self.args(args);
+            }
+            Op::Intrinsic {
+                dest,
+                intrinsic,
+                kind,
+                args,
+                at,
+            } => {
+                self.bytes.push(9);
+                self.optional(*dest);
+                self.intrinsic(*intrinsic);
+                self.entry(&KINDS, kind);
+                self.args(args);
+                self.span(*at);
+            }
+            Op::Unwrap {
+                dest,
+                value,
+                result,
+                at,
+            } => {
+                self.bytes.push(10);
+                self.optional(*dest);
+                self.arg(*value);
+                self.bytes.push(u8::from(*result));
+                self.span(*at);
+            }
+            Op::UnwrapOr {
+                dest,
+                value,
+                default,
+            } => {
+                self.bytes.push(11);
+                self.number(u64::from(*dest));
+                self.arg(*value);
+                self.arg(*default);
+            }
+            Op::Print {
+                function,
+                pieces,
+                args,
+            } => {
+                self.bytes.push(12);
+                self.entry(&IO_FUNCTIONS, function);
+                self.list(pieces, |w, piece| {
+                    w.optional(match piece {
+                        Piece::Text(text) => Some(*text),
+                        Piece::Placeholder => None,
+                    })
+                });
+                self.list(args, |w, (arg, kind)| {
+                    w.arg(*arg);
+                    w.entry(&KINDS, kind);
+                });
+            }
+            Op::ToDyn {
+                dest,
+                vtable,
+                value,
+            } => {
+                self.bytes.push(13);
+                self.number(u64::from(*dest));
+                self.number(u64::from(*vtable));
+                self.arg(*value);
+            }
+            Op::MakeFn {
+                dest,
+                function,
+                env,
+            } => {
+                self.bytes.push(14);
+                self.number(u64::from(*dest));
+                self.number(u64::from(*function));
+                match env {
+                    Some(env) => {
+                        self.bytes.push(1);
+                        self.arg(*env);
+                    }
+                    None => self.bytes.push(0),
+                }
+            }
+            Op::Struct { dest, fields } => {
+                self.bytes.push(15);
+                self.number(u64::from(*dest));
+                self.list(fields, |w, (field, value)| {
+                    w.number(u64::from(*field));
+                    w.arg(*value);
+                });
+            }
+            Op::Array { dest, elements } => {
+                self.bytes.push(16);
+                self.number(u64::from(*dest));
+                self.args(elements);
+            }
+            Op::ArrayRepeat { dest, len, value } => {
+                self.bytes.push(17);
+                self.number(u64::from(*dest));
+                self.number(*len);
+                self.arg(*value);
+            }
+            Op::Field { dest, value, field } => {
+                self.bytes.push(18);
+                self.number(u64::from(*dest));
+                self.arg(*value);
+                self.number(u64::from(*field));
+            }
+            Op::Index {
+                dest,
+                array,
+                index,
+                at,
+            } => {
+                self.bytes.push(19);
+                self.number(u64::from(*dest));
+                self.arg(*array);
+                self.arg(*index);
+                self.span(*at);
+            }
+            Op::Local { reg } => {
+                self.bytes.push(20);
+                self.number(u64::from(*reg));
+            }
+            Op::FieldAddress { dest, base, field } => {
+                self.bytes.push(21);
+                self.number(u64::from(*dest));
+                self.arg(*base);
+                self.number(u64::from(*field));
+            }
+            Op::IndexAddress {
+                dest,
+                base,
+                index,
+                at,
+            } => {
+                self.bytes.push(22);
+                self.number(u64::from(*dest));
+                self.arg(*base);
+                self.arg(*index);
+                self.span(*at);
+            }
+            Op::Load { dest, address } => {
+                self.bytes.push(23);
+                self.number(u64::from(*dest));
+                self.arg(*address);
+            }
+            Op::Store { address, value } => {
+                self.bytes.push(24);
+                self.arg(*address);
+                self.arg(*value);
+            }
+            Op::Alloc { dest, shape } => {
+                self.bytes.push(25);
+                self.number(u64::from(*dest));
+                self.number(u64::from(*shape));
+            }
+            Op::Move { dest, address } => {
+                self.bytes.push(26);
+                self.number(u64::from(*dest));
+                self.arg(*address);
+            }
+            Op::NextChar { dest, text, pos } => {
+                self.bytes.push(27);
+                self.number(u64::from(*dest));
+                self.arg(*text);
+                self.arg(*pos);
+            }
+            Op::Jump { target } => {
+                self.bytes.push(28);
+                self.number(u64::from(*target));
+            }
+            Op::Branch {
+                condition,
+                then_target,
+                else_target,
+            } => {
+                self.bytes.push(29);
+                self.arg(*condition);
+                self.number(u64::from(*then_target));
+                self.number(u64::from(*else_target));
+            }
+            Op::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                self.bytes.push(30);
+                self.arg(*value);
+                self.list(cases, |w, (key, target)| {
+                    w.signed(*key);
+                    w.number(u64::from(*target));
+                });
+                self.number(u64::from(*default));
+            }
+            Op::Return { value } => {
+                self.bytes.push(31);
+                match value {
+                    Some(value) => {
+                        self.bytes.push(1);
+                        self.arg(*value);
+                    }
+                    None => self.bytes.push(0),
+                }
+            }
+            Op::Unreachable => self.bytes.push(32),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.bytes.get(self.pos).ok_or("The file ends too soon")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn number(&mut self) -> Result<u64, String> {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(format!("A number at byte {} is too long", self.pos))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let n = self.number()?;
+        u32::try_from(n).map_err(|_| format!("{n} at byte {} is too large", self.pos))
+    }
+
+    fn signed(&mut self) -> Result<i64, String> {
+        let n = self.number()?;
+        Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+    }
+
+    fn optional(&mut self) -> Result<Option<u32>, String> {
+        Ok(match self.u32()? {
+            0 => None,
+            n => Some(n - 1),
+        })
+    }
+
+    fn flag(&mut self) -> Result<bool, String> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(format!(
+                "Expected 0 or 1 at byte {} but found {byte}",
+                self.pos
+            )),
+        }
+    }
+
+    fn list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Reader<'a>) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let len = self.number()?;
+        // Every item takes at least a byte, which bounds what a bad length allocates
+        let mut items = Vec::with_capacity(len.min((self.bytes.len() - self.pos) as u64) as usize);
+        for _ in 0..len {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.number()? as usize;
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or("The file ends too soon")?;
+        let text = std::str::from_utf8(&self.bytes[self.pos..end])
+            .map_err(|_| format!("The string at byte {} is not UTF-8", self.pos))?;
+        self.pos = end;
+        Ok(text.to_string())
+    }
+
+    fn arg(&mut self) -> Result<Arg, String> {
+        let n = self.u32()?;
+        Ok(if n & 1 == 1 {
+            Arg::Const(n >> 1)
+        } else {
+            Arg::Reg(n >> 1)
+        })
+    }
+
+    fn args(&mut self) -> Result<Vec<Arg>, String> {
+        self.list(Reader::arg)
+    }
+
+    fn span(&mut self) -> Result<Span, String> {
+        Ok(Span {
+            line: self.number()? as usize,
+            column: self.number()? as usize,
+        })
+    }
+
+    fn entry<T: Copy>(&mut self, table: &[T], what: &str) -> Result<T, String> {
+        let byte = self.byte()?;
+        table
+            .get(usize::from(byte))
+            .copied()
+            .ok_or_else(|| format!("Unknown {what} {byte} at byte {}", self.pos))
+    }
+
+    fn kind(&mut self) -> Result<Kind, String> {
+        self.entry(&KINDS, "kind")
+    }
+
+    fn intrinsic(&mut self) -> Result<Intrinsic, String> {
+        let code = usize::from(self.byte()?);
+        if let Some(intrinsic) = INTRINSICS.get(code) {
+            return Ok(*intrinsic);
+        }
+        let op = self.entry(&BINARY_OPS, "operator")?;
+        match code - INTRINSICS.len() {
+            0 => Ok(Intrinsic::Wrapping(op)),
+            1 => Ok(Intrinsic::Overflows(op)),
+            2 => Ok(Intrinsic::Saturating(op)),
+            _ => Err(format!("Unknown intrinsic {code} at byte {}", self.pos)),
+        }
+    }
+
+    fn op(&mut self) -> Result<Op, String> {
+        Ok(match self.byte()? {
+            0 => Op::Copy {
+                dest: self.u32()?,
+                value: self.arg()?,
+            },
+            1 => Op::Zero { dest: self.u32()? },
+            2 => Op::Binary {
+                dest: self.u32()?,
+                op: self.entry(&BINARY_OPS, "operator")?,
+                kind: self.kind()?,
+                lhs: self.arg()?,
+                rhs: self.arg()?,
+                at: self.span()?,
+            },
+            3 => Op::Neg {
+                dest: self.u32()?,
+                kind: self.kind()?,
+                value: self.arg()?,
+                at: self.span()?,
+            },
+            4 => Op::Not {
+                dest: self.u32()?,
+                value: self.arg()?,
+            },
+            5 => Op::Cast {
+                dest: self.u32()?,
+                kind: self.kind()?,
+                value: self.arg()?,
+            },
+            6 => Op::Call {
+                dest: self.optional()?,
+                function: self.u32()?,
+                args: self.args()?,
+            },
+            7 => Op::CallValue {
+                dest: self.optional()?,
+                callee: self.arg()?,
+                args: self.args()?,
+            },
+            8 => Op::CallDyn {
+                dest: self.optional()?,
+                method: self.u32()?,
+                args: self.args()?,
+            },
+            9 => Op::Intrinsic {
+                dest: self.optional()?,
+                intrinsic: self.intrinsic()?,
+                kind: self.kind()?,
+                args: self.args()?,
+                at: self.span()?,
+            },
+            10 => Op::Unwrap {
+                dest: self.optional()?,
+                value: self.arg()?,
+                result: self.flag()?,
+                at: self.span()?,
+            },
+            11 => Op::UnwrapOr {
+                dest: self.u32()?,
+                value: self.arg()?,
+                default: self.arg()?,
+            },
+            12 => Op::Print {
+                function: self.entry(&IO_FUNCTIONS, "print function")?,
+                pieces: self.list(|r| {
+                    Ok(match r.optional()? {
+                        Some(text) => Piece::Text(text),
+                        None => Piece::Placeholder,
+                    })
+                })?,
+                args: self.list(|r| Ok((r.arg()?, r.kind()?)))?,
+            },
+            13 => Op::ToDyn {
+                dest: self.u32()?,
+                vtable: self.u32()?,
+                value: self.arg()?,
+            },
+            14 => Op::MakeFn {
+                dest: self.u32()?,
+                function: self.u32()?,
+                env: if self.flag()? {
+                    Some(self.arg()?)
+                } else {
+                    None
+                },
+            },
+            15 => Op::Struct {
+                dest: self.u32()?,
+                fields: self.list(|r| Ok((r.u32()?, r.arg()?)))?,
+            },
+            16 => Op::Array {
+                dest: self.u32()?,
+                elements: self.args()?,
+            },
+            17 => Op::ArrayRepeat {
+                dest: self.u32()?,
+                len: self.number()?,
+                value: self.arg()?,
+            },
+            18 => Op::Field {
+                dest: self.u32()?,
+                value: self.arg()?,
+                field: self.u32()?,
+            },
+            19 => Op::Index {
+                dest: self.u32()?,
+                array: self.arg()?,
+                index: self.arg()?,
+                at: self.span()?,
+            },
+            20 => Op::Local { reg: self.u32()? },
+            21 => Op::FieldAddress {
+                dest: self.u32()?,
+                base: self.arg()?,
+                field: self.u32()?,
+            },
+            22 => Op::IndexAddress {
+                dest: self.u32()?,
+                base: self.arg()?,
+                index: self.arg()?,
+                at: self.span()?,
+            },
+            23 => Op::Load {
+                dest: self.u32()?,
+                address: self.arg()?,
+            },
+            24 => Op::Store {
+                address: self.arg()?,
+                value: self.arg()?,
+            },
+            25 => Op::Alloc {
+                dest: self.u32()?,
+                shape: self.u32()?,
+            },
+            26 => Op::Move {
+                dest: self.u32()?,
+                address: self.arg()?,
+            },
+            27 => Op::NextChar {
+                dest: self.u32()?,
+                text: self.arg()?,
+                pos: self.arg()?,
+            },
+            28 => Op::Jump {
+                target: self.u32()?,
+            },
+            29 => Op::Branch {
+                condition: self.arg()?,
+                then_target: self.u32()?,
+                else_target: self.u32()?,
+            },
+            30 => Op::Switch {
+                value: self.arg()?,
+                cases: self.list(|r| Ok((r.signed()?, r.u32()?)))?,
+                default: self.u32()?,
+            },
+            31 => Op::Return {
+                value: if self.flag()? {
+                    Some(self.arg()?)
+                } else {
+                    None
+                },
+            },
+            32 => Op::Unreachable,
+            opcode => return Err(format!("Unknown opcode {opcode} at byte {}", self.pos)),
+        })
+    }
+}
+
+/// Encodes a program in the `.nbc` format, which is laid out as follows.
+///
+/// Numbers are unsigned LEB128: seven bits a byte, lowest first, with the
+/// top bit set on every byte but the last. Signed numbers are zigzag encoded
+/// first, so `0, -1, 1, -2` become `0, 1, 2, 3`. An optional number is 0
+/// when there is none and the number plus one otherwise. A string is its
+/// length in bytes and then its UTF-8, and a list its length and then its
+/// items. Strings, constants, shapes, functions and vtables are referred to
+/// by their index in their table.
+///
+/// - the magic bytes `\0nbc` and the format version, a number
+/// - flags, a number whose lowest bit is set in debug builds
+/// - the source file named in panic locations, a string
+/// - the index of `main`, and the kind of the error it returns as an
+///   optional number
+/// - the strings, a list of strings
+/// - the constants, a list of a tag byte and its value: 0 and a signed
+///   integer, 1 and a byte that is 0 or 1, 2 and a char as a number, or 3
+///   and the index of a string
+/// - the shapes, a list of a tag byte in the order `Shape` declares them,
+///   followed for a struct by the list of its fields' shapes and for an
+///   array by the shape of its elements and its length. A shape is only
+///   made of shapes before it.
+/// - the vtables, a list of lists of functions
+/// - the functions, a list of their name and source name, the list of
+///   their parameter registers, their environment register as an optional
+///   number, the list of the shapes of their registers and the list of
+///   their ops
+///
+/// An op is its opcode, its position in `Op`, followed by its fields in
+/// the order they are declared. A register is a number; an argument is a
+/// register times two, or a constant times two plus one; a span is its
+/// line and column; kinds, operators and print functions are bytes giving
+/// their position in the tables above, and an intrinsic without an operator
+/// is likewise a byte, while one with an operator is a byte past the end of
+/// that table and the operator. A `bool`, and whether an optional argument
+/// is there, is a byte that is 0 or 1.
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut w = Writer {
+        bytes: MAGIC.to_vec(),
+    };
+    w.number(VERSION);
+    w.number(u64::from(program.debug));
+    w.string(&program.file);
+    w.number(u64::from(program.main));
+    w.optional(
+        program
+            .main_error
+            .map(|kind| KINDS.iter().position(|found| *found == kind).unwrap_or(0) as u32),
+    );
+    w.list(&program.strings, |w, text| w.string(text));
+    w.list(&program.constants, |w, constant| match constant {
+        Constant::Int(n) => {
+            w.bytes.push(0);
+            w.signed(*n);
+        }
+        Constant::Bool(b) => {
+            w.bytes.push(1);
+            w.bytes.push(u8::from(*b));
+        }
+        Constant::Char(c) => {
+            w.bytes.push(2);
+            w.number(u64::from(u32::from(*c)));
+        }
+        Constant::Str(id) => {
+            w.bytes.push(3);
+            w.number(u64::from(*id));
+        }
+    });
+    w.list(&program.shapes, |w, shape| {
+        let tag = match shape {
+            Shape::Void => 0,
+            Shape::Int => 1,
+            Shape::Bool => 2,
+            Shape::Char => 3,
+            Shape::Str => 4,
+            Shape::String => 5,
+            Shape::Null => 6,
+            Shape::Struct(fields) => {
+                w.bytes.push(7);
+                w.list(fields, |w, field| w.number(u64::from(*field)));
+                return;
+            }
+            Shape::Array(element, len) => {
+                w.bytes.push(8);
+                w.number(u64::from(*element));
+                w.number(*len);
+                return;
+            }
+        };
+        w.bytes.push(tag);
+    });
+    w.list(&program.vtables, |w, functions| {
+        w.list(functions, |w, function| w.number(u64::from(*function)));
+    });
+    w.list(&program.functions, |w, code| {
+        w.number(u64::from(code.name));
+        w.number(u64::from(code.source_name));
+        w.list(&code.params, |w, reg| w.number(u64::from(*reg)));
+        w.optional(code.env);
+        w.list(&code.regs, |w, shape| w.number(u64::from(*shape)));
+        w.list(&code.ops, |w, op| w.op(op));
+    });
+    w.bytes
+}
+
+/// Decodes a program from the `.nbc` format `encode` describes, checking
+/// that everything it refers to is there, so that a damaged file is
+/// reported rather than run
+pub fn decode(bytes: &[u8]) -> Result<Program, String> {
+    if !bytes.starts_with(MAGIC) {
+        return Err("Not a Nimra bytecode file".to_string());
+    }
+    let mut r = Reader {
+        bytes,
+        pos: MAGIC.len(),
+    };
+    let version = r.number()?;
+    if version != VERSION {
+        return Err(format!(
+            "The file is bytecode version {version}, but this is version {VERSION}"
+        ));
+    }
+    let debug = r.number()? & 1 == 1;
+    let file = r.string()?;
+    let main = r.u32()?;
+    let main_error = match r.optional()? {
+        Some(kind) => Some(
+            *KINDS
+                .get(kind as usize)
+                .ok_or_else(|| format!("Unknown kind {kind}"))?,
+        ),
+        None => None,
+    };
+    let strings = r.list(Reader::string)?;
+    let constants = r.list(|r| {
+        Ok(match r.byte()? {
+            0 => Constant::Int(r.signed()?),
+            1 => Constant::Bool(r.flag()?),
+            2 => {
+                let code = r.u32()?;
+                Constant::Char(char::from_u32(code).ok_or_else(|| format!("{code} is not a char"))?)
+            }
+            3 => Constant::Str(r.u32()?),
+            tag => return Err(format!("Unknown constant tag {tag} at byte {}", r.pos)),
+        })
+    })?;
+    let shapes = r.list(|r| {
+        Ok(match r.byte()? {
+            0 => Shape::Void,
+            1 => Shape::Int,
+            2 => Shape::Bool,
+            3 => Shape::Char,
+            4 => Shape::Str,
+            5 => Shape::String,
+            6 => Shape::Null,
+            7 => Shape::Struct(r.list(Reader::u32)?),
+            8 => Shape::Array(r.u32()?, r.number()?),
+            tag => return Err(format!("Unknown shape tag {tag} at byte {}", r.pos)),
+        })
+    })?;
+    let vtables = r.list(|r| r.list(Reader::u32))?;
+    let functions = r.list(|r| {
+        Ok(Code {
+            name: r.u32()?,
+            source_name: r.u32()?,
+            params: r.list(Reader::u32)?,
+            env: r.optional()?,
+            regs: r.list(Reader::u32)?,
+            ops: r.list(Reader::op)?,
+        })
+    })?;
+    if r.pos != bytes.len() {
+        return Err(format!("Unexpected bytes after the end, at byte {}", r.pos));
+    }
+    let program = Program {
+        debug,
+        file,
+        strings,
+        constants,
+        shapes,
+        functions,
+        vtables,
+        main,
+        main_error,
+    };
+    check(&program)?;
+    Ok(program)
+}
+
+// Checks that every index in a program is in range, so the VM can index
+// its tables without checking. Registers are checked when a function is called.
+fn check(program: &Program) -> Result<(), String> {
+    let in_range = |what: &str, index: u32, len: usize| {
+        if (index as usize) < len {
+            Ok(())
+        } else {
+            Err(format!("There is no {what} {index}"))
+        }
+    };
+    let function_count = program.functions.len();
+    let shape_count = program.shapes.len();
+    in_range("function", program.main, function_count)?;
+    for constant in &program.constants {
+        if let Constant::Str(id) = constant {
+            in_range("string", *id, program.strings.len())?;
+        }
+    }
+    // A shape is made of those before it, so none contains itself
+    for (id, shape) in program.shapes.iter().enumerate() {
+        match shape {
+            Shape::Struct(fields) => {
+                for field in fields {
+                    in_range("shape", *field, id)?;
+                }
+            }
+            Shape::Array(element, _) => in_range("shape", *element, id)?,
+            _ => {}
+        }
+    }
+    for function in program.vtables.iter().flatten() {
+        in_range("function", *function, function_count)?;
+    }
+    for code in &program.functions {
+        in_range("string", code.name, program.strings.len())?;
+        in_range("string", code.source_name, program.strings.len())?;
+        let regs = code.regs.len();
+        for reg in code.params.iter().chain(&code.env) {
+            in_range("register", *reg, regs)?;
+        }
+        for shape in &code.regs {
+            in_range("shape", *shape, shape_count)?;
+        }
+        for op in &code.ops {
+            check_op(program, op, regs, code.ops.len())?;
+        }
+        if !matches!(
+            code.ops.last(),
+            Some(
+                Op::Jump { .. }
+                    | Op::Branch { .. }
+                    | Op::Switch { .. }
+                    | Op::Return { .. }
+                    | Op::Unreachable
+            )
+        ) {
+            return Err("A function does not end with a jump or return".to_string());
+        }
+    }
+    Ok(())
+}
+
+fn check_op(program: &Program, op: &Op, regs: usize, len: usize) -> Result<(), String> {
+    let reg = |reg: u32| {
+        if (reg as usize) < regs {
+            Ok(())
+        } else {
+            Err(format!("There is no register r{reg}"))
+        }
+    };
+    let arg = |arg: &Arg| match arg {
+        Arg::Reg(r) => reg(*r),
+        Arg::Const(id) if (*id as usize) < program.constants.len() => Ok(()),
+        Arg::Const(id) => Err(format!("There is no constant {id}")),
+    };
+    let target = |target: u32| {
+        if (target as usize) < len {
+            Ok(())
+        } else {
+            Err(format!("A jump goes past the end, to {target}"))
+        }
+    };
+    let mut args: Vec<&Arg> = Vec::new();
+    let mut dests: Vec<u32> = Vec::new();
+    match op {
+        Op::Copy { dest, value }
+        | Op::Not { dest, value }
+        | Op::Neg { dest, value, .. }
+        | Op::Cast { dest, value, .. }
+        | Op::ArrayRepeat { dest, value, .. }
+        | Op::Field { dest, value, .. }
+        | Op::FieldAddress {
+            dest, base: value, ..
+        }
+        | Op::Load {
+            dest,
+            address: value,
+        }
+        | Op::Move {
+            dest,
+            address: value,
+        } => {
+            dests.push(*dest);
+            args.push(value);
+        }
+        Op::Zero { dest } | Op::Local { reg: dest } => dests.push(*dest),
+        Op::Alloc { dest, shape } => {
+            dests.push(*dest);
+            if *shape as usize >= program.shapes.len() {
+                return Err(format!("There is no shape {shape}"));
+            }
+        }
+        Op::Binary { dest, lhs, rhs, .. }
+        | Op::Index {
+            dest,
+            array: lhs,
+            index: rhs,
+            ..
+        }
+        | Op::IndexAddress {
+            dest,
+            base: lhs,
+            index: rhs,
+            ..
+        }
+        | Op::UnwrapOr {
+            dest,
+            value: lhs,
+            default: rhs,
+        }
+        | Op::NextChar {
+            dest,
+            text: lhs,
+            pos: rhs,
+        } => {
+            dests.push(*dest);
+            args.extend([lhs, rhs]);
+        }
+        Op::Call {
+            dest,
+            function,
+            args: a,
+        } => {
+            dests.extend(dest);
+            args.extend(a);
+            if *function as usize >= program.functions.len() {
+                return Err(format!("There is no function {function}"));
+            }
+        }
+        Op::CallValue {
+            dest,
+            callee,
+            args: a,
+        } => {
+            dests.extend(dest);
+            args.push(callee);
+            args.extend(a);
+        }
+        Op::CallDyn { dest, args: a, .. } | Op::Intrinsic { dest, args: a, .. } => {
+            dests.extend(dest);
+            args.extend(a);
+        }
+        Op::Unwrap { dest, value, .. } => {
+            dests.extend(dest);
+            args.push(value);
+        }
+        Op::Print {
+            pieces, args: a, ..
+        } => {
+            args.extend(a.iter().map(|(arg, _)| arg));
+            for piece in pieces {
+                if let Piece::Text(text) = piece {
+                    if *text as usize >= program.strings.len() {
+                        return Err(format!("There is no string {text}"));
+                    }
+                }
+            }
+        }
+        Op::ToDyn {
+            dest,
+            vtable,
+            value,
+        } => {
+            dests.push(*dest);
+            args.push(value);
+            if *vtable as usize >= program.vtables.len() {
+                return Err(format!("There is no vtable {vtable}"));
+            }
+        }
+        Op::MakeFn {
+            dest,
+            function,
+            env,
+        } => {
+            dests.push(*dest);
+            args.extend(env);
+            if *function as usize >= program.functions.len() {
+                return Err(format!("There is no function {function}"));
+            }
+        }
+        Op::Struct { dest, fields } => {
+            dests.push(*dest);
+            args.extend(fields.iter().map(|(_, value)| value));
+        }
+        Op::Array { dest, elements } => {
+            dests.push(*dest);
+            args.extend(elements);
+        }
+        Op::Store { address, value } => args.extend([address, value]),
+        Op::Jump { target: to } => target(*to)?,
+        Op::Branch {
+            condition,
+            then_target,
+            else_target,
+        } => {
+            args.push(condition);
+            target(*then_target)?;
+            target(*else_target)?;
+        }
+        Op::Switch {
+            value,
+            cases,
+            default,
+        } => {
+            args.push(value);
+            for (_, to) in cases {
+                target(*to)?;
+            }
+            target(*default)?;
+        }
+        Op::Return { value } => args.extend(value),
+        Op::Unreachable => {}
+    }
+    for dest in dests {
+        reg(dest)?;
+    }
+    for a in args {
+        arg(a)?;
+    }
+    Ok(())
+}
+
+/// Writes a program to the `.nbc` file the options name
+pub fn write(program: &Program, options: &Options) -> Result<PathBuf, String> {
+    fs::write(&options.output, encode(program))
+        .map_err(|e| format!("Failed to write {}: {e}", options.output))?;
+    Ok(PathBuf::from(&options.output))
+}
+
+/// Reads a program from a `.nbc` file
+pub fn read(path: &str) -> Result<Program, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Unable to read {path}: {e}"))?;
+    decode(&bytes)
+}
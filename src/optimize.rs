@@ -0,0 +1,655 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::collections::HashMap;
+
+use crate::consteval;
+use crate::generator::ICInstruction;
+use crate::ir::{Block, BlockId, Function, Instruction, Operand, Reg, Terminator};
+use crate::lexer::Literal;
+use crate::options::Options;
+use crate::parser::{BinaryOp, UnaryOp};
+use crate::sema::Intrinsic;
+use crate::ssa;
+use crate::verifier::{self, Signatures};
+
+/// An optimization of one function in SSA form, which says whether it
+/// changed anything. Debug builds panic where release builds wrap around,
+/// so what an instruction can do depends on which this is.
+type Pass = fn(&mut Function, debug: bool) -> bool;
+
+/// Rounds of the passes a function gets before they are given up on, in
+/// case they keep undoing each other
+const MAX_ROUNDS: usize = 16;
+
+/// Runs the passes an `-O` level asks for over every function
+pub struct PassManager {
+    /// Each pass and the name a verifier error reports it by
+    passes: Vec<(&'static str, Pass)>,
+    /// Functions with at most this many instructions are inlined
+    inline_limit: usize,
+    /// How many times calls are inlined, each reaching one call deeper
+    inline_rounds: usize,
+    debug: bool,
+}
+
+impl PassManager {
+    /// `-O0` runs nothing, `-O1` the passes that clean up within a function,
+    /// and `-O2` and `-O3` also inline small functions, `-O3` more of them
+    /// and more deeply
+    pub fn new(options: &Options) -> Self {
+        let passes: Vec<(&'static str, Pass)> = if options.opt_level == 0 {
+            Vec::new()
+        } else {
+            vec![
+                ("constant propagation", propagate_constants),
+                ("copy propagation", propagate_copies),
+                ("CFG simplification", simplify_cfg),
+                ("dead code elimination", eliminate_dead_code),
+            ]
+        };
+        let (inline_limit, inline_rounds) = match options.opt_level {
+            0 | 1 => (0, 0),
+            2 => (16, 1),
+            _ => (48, 2),
+        };
+        PassManager {
+            passes,
+            inline_limit,
+            inline_rounds,
+            debug: !options.release,
+        }
+    }
+
+    /// Runs the passes, checking the code is well formed before they start
+    /// and after each of them, so a broken pass is caught where it breaks it
+    pub fn run(&self, ic: &mut [ICInstruction]) -> Result<(), String> {
+        verifier::verify(ic, false)?;
+        if self.passes.is_empty() {
+            return Ok(());
+        }
+        let signatures = verifier::signatures(ic);
+        for function in functions(ic) {
+            ssa::to_ssa(function);
+            verify_after(function, &signatures, "SSA construction")?;
+            self.run_passes(function, &signatures)?;
+        }
+        for _ in 0..self.inline_rounds {
+            let callees: HashMap<String, Function> = functions(ic)
+                .filter(|function| self.is_inlinable(function))
+                .map(|function| (function.name.clone(), function.clone()))
+                .collect();
+            for function in functions(ic) {
+                if inline_calls(function, &callees) {
+                    verify_after(function, &signatures, "inlining")?;
+                    self.run_passes(function, &signatures)?;
+                }
+            }
+        }
+        for function in functions(ic) {
+            ssa::from_ssa(function);
+        }
+        verifier::verify(ic, false).map_err(|e| format!("After leaving SSA form: {e}"))
+    }
+
+    fn run_passes(&self, function: &mut Function, signatures: &Signatures) -> Result<(), String> {
+        for _ in 0..MAX_ROUNDS {
+            let mut changed = false;
+            for (name, pass) in &self.passes {
+                if pass(function, self.debug) {
+                    changed = true;
+                    verify_after(function, signatures, name)?;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Whether calls to a function can be replaced with its body. A closure is
+    // called through a value, and a function calling itself would only be
+    // inlined into itself.
+    fn is_inlinable(&self, function: &Function) -> bool {
+        let instructions = function.blocks.iter().flat_map(|block| &block.instructions);
+        function.env.is_none()
+            && function.name != "main"
+            && instructions.clone().count() <= self.inline_limit
+            && !instructions.clone().any(
+                |instruction| matches!(instruction, Instruction::Call { function: callee, .. } if *callee == function.name),
+            )
+    }
+}
+
+fn functions(ic: &mut [ICInstruction]) -> impl Iterator<Item = &mut Function> {
+    ic.iter_mut().filter_map(|instruction| match instruction {
+        ICInstruction::Function(function) => Some(function),
+        _ => None,
+    })
+}
+
+// Verifies a function in SSA form after a step of the optimizer changed it
+fn verify_after(function: &Function, signatures: &Signatures, step: &str) -> Result<(), String> {
+    verifier::verify_function(function, signatures, true).map_err(|e| format!("After {step}: {e}"))
+}
+
+/// Optimizes every function at the `-O` level the options ask for
+pub fn optimize(
+    mut ic: Vec<ICInstruction>,
+    options: &Options,
+) -> Result<Vec<ICInstruction>, String> {
+    PassManager::new(options).run(&mut ic)?;
+    Ok(ic)
+}
+
+// Whether an instruction does nothing but write its destination, so it can
+// be removed when nothing reads that
+fn is_pure(instruction: &Instruction, function: &Function, debug: bool) -> bool {
+    match instruction {
+        Instruction::Copy { .. }
+        | Instruction::Zero { .. }
+        | Instruction::Phi { .. }
+        | Instruction::Cast { .. }
+        | Instruction::ToDyn { .. }
+        | Instruction::MakeFn { .. }
+        | Instruction::Struct { .. }
+        | Instruction::Array { .. }
+        | Instruction::ArrayRepeat { .. }
+        | Instruction::Field { .. }
+        | Instruction::AddressOf { .. }
+        | Instruction::FieldAddress { .. }
+        | Instruction::Load { .. }
+        | Instruction::Alloc { .. } => true,
+        // Integer arithmetic panics on overflow in debug builds, and
+        // division by zero in any build
+        Instruction::Binary { dest, op, .. } => {
+            !function.regs[*dest].ty.is_integer()
+                || match op {
+                    BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul => !debug,
+                    BinaryOp::Div | BinaryOp::Rem => false,
+                    _ => true,
+                }
+        }
+        Instruction::Unary { op, .. } => *op == UnaryOp::Not || !debug,
+        Instruction::Intrinsic { intrinsic, .. } => matches!(
+            intrinsic,
+            Intrinsic::Wrapping(_) | Intrinsic::Saturating(_) | Intrinsic::StrLen
+        ),
+        _ => false,
+    }
+}
+
+// Replaces every read of a register with another operand
+fn replace_uses(function: &mut Function, reg: Reg, value: &Operand) {
+    for block in &mut function.blocks {
+        let operands = block
+            .instructions
+            .iter_mut()
+            .flat_map(Instruction::operands_mut)
+            .chain(block.terminator.operands_mut());
+        for operand in operands {
+            if *operand == Operand::Reg(reg) {
+                *operand = value.clone();
+            }
+        }
+    }
+}
+
+// Forgets the values a block's phis take from a predecessor that no longer
+// jumps to it
+fn remove_incoming(block: &mut Block, from: BlockId) {
+    for instruction in &mut block.instructions {
+        if let Instruction::Phi { incoming, .. } = instruction {
+            incoming.retain(|(predecessor, _)| *predecessor != from);
+        }
+    }
+}
+
+// The one value a phi takes on every path but the ones where it keeps its own
+fn phi_value(dest: Reg, incoming: &[(BlockId, Operand)]) -> Option<&Operand> {
+    let mut values = incoming
+        .iter()
+        .map(|(_, value)| value)
+        .filter(|value| **value != Operand::Reg(dest));
+    let first = values.next()?;
+    values.all(|value| value == first).then_some(first)
+}
+
+/// Works out instructions whose operands are constants at compile time, and
+/// turns branches on constants into jumps. What would overflow or panic is
+/// left for the program to do when it runs.
+fn propagate_constants(function: &mut Function, _debug: bool) -> bool {
+    let promotable = ssa::promotable(function);
+    let mut constants: HashMap<Reg, Operand> = HashMap::new();
+    let mut changed = false;
+    loop {
+        let mut found = false;
+        for block in &mut function.blocks {
+            for instruction in &mut block.instructions {
+                for operand in instruction.operands_mut() {
+                    if let Operand::Reg(reg) = operand {
+                        if let Some(value) = constants.get(reg) {
+                            *operand = value.clone();
+                            changed = true;
+                        }
+                    }
+                }
+                let Some(dest) = instruction.dest() else {
+                    continue;
+                };
+                if !promotable[dest] || constants.contains_key(&dest) {
+                    continue;
+                }
+                let ty = function.regs[dest].ty.clone();
+                let value = match &*instruction {
+                    Instruction::Phi { incoming, .. } => match phi_value(dest, incoming) {
+                        Some(value @ Operand::Const(..)) => value.clone(),
+                        _ => continue,
+                    },
+                    Instruction::Copy {
+                        value: value @ Operand::Const(..),
+                        ..
+                    } => value.clone(),
+                    other => match consteval::fold_instruction(other, &ty) {
+                        Ok(Some(value)) => Operand::Const(value.to_literal(), ty),
+                        _ => continue,
+                    },
+                };
+                *instruction = Instruction::Copy {
+                    dest,
+                    value: value.clone(),
+                };
+                constants.insert(dest, value);
+                found = true;
+                changed = true;
+            }
+            for operand in block.terminator.operands_mut() {
+                if let Operand::Reg(reg) = operand {
+                    if let Some(value) = constants.get(reg) {
+                        *operand = value.clone();
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !found {
+            break;
+        }
+    }
+    for id in 0..function.blocks.len() {
+        let target = match &function.blocks[id].terminator {
+            Terminator::Branch {
+                condition: Operand::Const(Literal::Bool(condition), _),
+                then_block,
+                else_block,
+            } => {
+                if *condition {
+                    *then_block
+                } else {
+                    *else_block
+                }
+            }
+            Terminator::Switch {
+                value: Operand::Const(value, _),
+                cases,
+                default,
+            } => cases
+                .iter()
+                .find(|(case, _)| case == value)
+                .map_or(*default, |(_, target)| *target),
+            _ => continue,
+        };
+        for successor in function.blocks[id].terminator.successors() {
+            if successor != target {
+                remove_incoming(&mut function.blocks[successor], id);
+            }
+        }
+        function.blocks[id].terminator = Terminator::Jump(target);
+        changed = true;
+    }
+    changed
+}
+
+/// Replaces registers that are copies of another value, including phis that
+/// take the same value on every path, with that value
+fn propagate_copies(function: &mut Function, _debug: bool) -> bool {
+    let promotable = ssa::promotable(function);
+    let mut changed = false;
+    // Each copy is removed before the next is looked for, so a chain of them
+    // ends at the value they all copy
+    while let Some((block, index, dest, value)) = find_copy(function, &promotable) {
+        function.blocks[block].instructions.remove(index);
+        replace_uses(function, dest, &value);
+        changed = true;
+    }
+    changed
+}
+
+// A copy of a value into a register that holds nothing else. A register
+// whose address is taken can change without being written, so it is never
+// copied from.
+fn find_copy(function: &Function, promotable: &[bool]) -> Option<(BlockId, usize, Reg, Operand)> {
+    for (id, block) in function.blocks.iter().enumerate() {
+        for (index, instruction) in block.instructions.iter().enumerate() {
+            let (dest, value) = match instruction {
+                Instruction::Copy { dest, value } => (*dest, value),
+                Instruction::Phi { dest, incoming } => match phi_value(*dest, incoming) {
+                    Some(value) => (*dest, value),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let copyable = match value {
+                Operand::Reg(reg) => promotable[*reg],
+                Operand::Const(..) => true,
+            };
+            if promotable[dest]
+                && copyable
+                && function.operand_type(value) == function.regs[dest].ty
+            {
+                return Some((id, index, dest, value.clone()));
+            }
+        }
+    }
+    None
+}
+
+/// Turns branches with one target into jumps, removes blocks nothing jumps
+/// to, merges a block into the one before it when that is the only way in,
+/// and skips blocks that only jump on
+fn simplify_cfg(function: &mut Function, _debug: bool) -> bool {
+    let blocks = function.blocks.len();
+    let mut changed = false;
+    for block in &mut function.blocks {
+        let mut successors = block.terminator.successors();
+        successors.dedup();
+        if successors.len() == 1 && !matches!(block.terminator, Terminator::Jump(_)) {
+            block.terminator = Terminator::Jump(successors[0]);
+            changed = true;
+        }
+    }
+    function.remove_unreachable_blocks();
+    while let Some((block, next)) = find_merge(function) {
+        let merged = std::mem::replace(
+            &mut function.blocks[next],
+            Block {
+                instructions: Vec::new(),
+                terminator: Terminator::Unreachable,
+            },
+        );
+        // The block's phis have one value, from the block it is merged into
+        let instructions = merged
+            .instructions
+            .into_iter()
+            .map(|instruction| match instruction {
+                Instruction::Phi { dest, incoming } => match incoming.into_iter().next() {
+                    Some((_, value)) => Instruction::Copy { dest, value },
+                    None => Instruction::Zero { dest },
+                },
+                instruction => instruction,
+            });
+        function.blocks[block].instructions.extend(instructions);
+        for successor in merged.terminator.successors() {
+            for instruction in &mut function.blocks[successor].instructions {
+                if let Instruction::Phi { incoming, .. } = instruction {
+                    for (from, _) in incoming {
+                        if *from == next {
+                            *from = block;
+                        }
+                    }
+                }
+            }
+        }
+        function.blocks[block].terminator = merged.terminator;
+        function.remove_unreachable_blocks();
+        changed = true;
+    }
+    for id in 1..function.blocks.len() {
+        let (Terminator::Jump(target), true) = (
+            &function.blocks[id].terminator,
+            function.blocks[id].instructions.is_empty(),
+        ) else {
+            continue;
+        };
+        let target = *target;
+        let has_phis = function.blocks[target]
+            .instructions
+            .iter()
+            .any(|instruction| matches!(instruction, Instruction::Phi { .. }));
+        if target == id || has_phis {
+            continue;
+        }
+        for block in &mut function.blocks {
+            for successor in block.terminator.successors_mut() {
+                if *successor == id {
+                    *successor = target;
+                    changed = true;
+                }
+            }
+        }
+    }
+    function.remove_unreachable_blocks();
+    changed || function.blocks.len() != blocks
+}
+
+// A block that jumps to one only it jumps to, which can be merged into it
+fn find_merge(function: &Function) -> Option<(BlockId, BlockId)> {
+    let predecessors = function.predecessors();
+    function
+        .blocks
+        .iter()
+        .enumerate()
+        .find_map(|(id, block)| match block.terminator {
+            Terminator::Jump(next) if next != id && next != 0 && predecessors[next] == [id] => {
+                Some((id, next))
+            }
+            _ => None,
+        })
+}
+
+/// Removes instructions that do nothing but write registers nothing reads,
+/// including phis that only feed each other around a loop
+fn eliminate_dead_code(function: &mut Function, debug: bool) -> bool {
+    let mut writes: Vec<Vec<(BlockId, usize)>> = vec![Vec::new(); function.regs.len()];
+    let mut live_instructions = Vec::new();
+    for (id, block) in function.blocks.iter().enumerate() {
+        for (index, instruction) in block.instructions.iter().enumerate() {
+            if let Some(dest) = instruction.dest() {
+                writes[dest].push((id, index));
+            }
+            if !is_pure(instruction, function, debug) {
+                live_instructions.push((id, index));
+            }
+        }
+    }
+    let mut live = vec![false; function.regs.len()];
+    let mut worklist: Vec<Reg> = function
+        .blocks
+        .iter()
+        .flat_map(|block| block.terminator.operands())
+        .filter_map(|operand| match operand {
+            Operand::Reg(reg) => Some(*reg),
+            Operand::Const(..) => None,
+        })
+        .collect();
+    let read = |instruction: &Instruction| {
+        let mut regs: Vec<Reg> = instruction
+            .operands()
+            .into_iter()
+            .filter_map(|operand| match operand {
+                Operand::Reg(reg) => Some(*reg),
+                Operand::Const(..) => None,
+            })
+            .collect();
+        if let Instruction::AddressOf { reg, .. } = instruction {
+            regs.push(*reg);
+        }
+        regs
+    };
+    for (id, index) in &live_instructions {
+        worklist.extend(read(&function.blocks[*id].instructions[*index]));
+    }
+    while let Some(reg) = worklist.pop() {
+        if std::mem::replace(&mut live[reg], true) {
+            continue;
+        }
+        for (id, index) in &writes[reg] {
+            worklist.extend(read(&function.blocks[*id].instructions[*index]));
+        }
+    }
+    let dead: Vec<Vec<bool>> = function
+        .blocks
+        .iter()
+        .map(|block| {
+            block
+                .instructions
+                .iter()
+                .map(|instruction| {
+                    instruction
+                        .dest()
+                        .is_some_and(|dest| !live[dest] && is_pure(instruction, function, debug))
+                })
+                .collect()
+        })
+        .collect();
+    let mut changed = false;
+    for (block, dead) in function.blocks.iter_mut().zip(dead) {
+        changed |= dead.contains(&true);
+        let mut dead = dead.into_iter();
+        block.instructions.retain(|_| !dead.next().unwrap_or(false));
+    }
+    changed
+}
+
+/// Replaces calls to the given functions with their bodies. The call's
+/// block is split in two around the callee's blocks, and the values it
+/// returns are joined by a phi.
+fn inline_calls(function: &mut Function, callees: &HashMap<String, Function>) -> bool {
+    let mut changed = false;
+    let mut block = 0;
+    while block < function.blocks.len() {
+        let call = function.blocks[block]
+            .instructions
+            .iter()
+            .position(|instruction| match instruction {
+                Instruction::Call {
+                    function: callee, ..
+                } => *callee != function.name && callees.contains_key(callee),
+                _ => false,
+            });
+        let Some(index) = call else {
+            block += 1;
+            continue;
+        };
+        inline_call(function, block, index, callees);
+        changed = true;
+    }
+    changed
+}
+
+fn inline_call(
+    function: &mut Function,
+    block: BlockId,
+    index: usize,
+    callees: &HashMap<String, Function>,
+) {
+    let rest = function.blocks[block].instructions.split_off(index + 1);
+    let Some(Instruction::Call {
+        dest,
+        function: callee,
+        args,
+    }) = function.blocks[block].instructions.pop()
+    else {
+        unreachable!("Inlined an instruction that is not a call");
+    };
+    let callee = &callees[&callee];
+    let reg_offset = function.regs.len();
+    let block_offset = function.blocks.len();
+    let after = block_offset + callee.blocks.len();
+    function.regs.extend(callee.regs.iter().cloned());
+    for (param, value) in callee.params.iter().zip(args) {
+        function.blocks[block].instructions.push(Instruction::Copy {
+            dest: param + reg_offset,
+            value,
+        });
+    }
+    let terminator = std::mem::replace(
+        &mut function.blocks[block].terminator,
+        Terminator::Jump(block_offset),
+    );
+    for successor in terminator.successors() {
+        for instruction in &mut function.blocks[successor].instructions {
+            if let Instruction::Phi { incoming, .. } = instruction {
+                for (from, _) in incoming {
+                    if *from == block {
+                        *from = after;
+                    }
+                }
+            }
+        }
+    }
+    let mut returned = Vec::new();
+    for (id, callee_block) in callee.blocks.iter().enumerate() {
+        let mut inlined = callee_block.clone();
+        for instruction in &mut inlined.instructions {
+            if let Some(dest) = instruction.dest_mut() {
+                *dest += reg_offset;
+            }
+            if let Instruction::AddressOf { reg, .. } = instruction {
+                *reg += reg_offset;
+            }
+            if let Instruction::Phi { incoming, .. } = instruction {
+                for (from, _) in incoming.iter_mut() {
+                    *from += block_offset;
+                }
+            }
+            for operand in instruction.operands_mut() {
+                if let Operand::Reg(reg) = operand {
+                    *reg += reg_offset;
+                }
+            }
+        }
+        for operand in inlined.terminator.operands_mut() {
+            if let Operand::Reg(reg) = operand {
+                *reg += reg_offset;
+            }
+        }
+        for target in inlined.terminator.successors_mut() {
+            *target += block_offset;
+        }
+        if let Terminator::Return(value) = inlined.terminator {
+            if let Some(value) = value {
+                returned.push((block_offset + id, value));
+            }
+            inlined.terminator = Terminator::Jump(after);
+        }
+        function.blocks.push(inlined);
+    }
+    let mut instructions = Vec::new();
+    if let (Some(dest), false) = (dest, returned.is_empty()) {
+        instructions.push(Instruction::Phi {
+            dest,
+            incoming: returned,
+        });
+    }
+    instructions.extend(rest);
+    function.blocks.push(Block {
+        instructions,
+        terminator,
+    });
+}
@@ -0,0 +1,188 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::env;
+
+/// What the compiler turns intermediate code into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// C, which gcc compiles
+    C,
+    /// x86-64 assembly, which `as` assembles and `ld` links without a C library
+    Asm,
+    /// Bytecode that the VM runs, written as a `.nbc` file
+    Bytecode,
+    /// Textual LLVM IR, which clang or llc compiles
+    Llvm,
+    /// A WebAssembly module for WASI, written as a `.wasm` file
+    Wasm,
+}
+
+/// How errors and warnings are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// With the lines of source they point at, for people
+    Human,
+    /// As one JSON object a line, for tools such as CI jobs
+    Json,
+}
+
+/// Settings taken from the command line
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub input: String,
+    /// Release builds leave out debug-only checks such as panic backtraces
+    pub release: bool,
+    /// Where the executable is written; `a.out` by default, or `a.wasm` for
+    /// a WebAssembly module
+    pub output: String,
+    /// How much the compiler optimizes, from `-O0` to `-O3`. Debug builds
+    /// default to 0 and release builds to 2.
+    pub opt_level: u8,
+    /// `--emit=ic` prints the optimized intermediate code as text instead
+    /// of building an executable
+    pub emit_ic: bool,
+    /// `--emit=llvm` prints the LLVM IR the LLVM backend generates instead
+    /// of building an executable
+    pub emit_llvm: bool,
+    /// `--emit=wat` prints the WebAssembly text the WebAssembly backend
+    /// generates instead of building a module
+    pub emit_wat: bool,
+    /// Chosen with `--backend=c`, `--backend=asm`, `--backend=bytecode`,
+    /// `--backend=llvm` or `--backend=wasm`; C by default
+    pub backend: Backend,
+    /// `nimra run` runs the program once it is built, exiting with its exit code
+    pub run: bool,
+    /// `--interp` runs the program with the interpreter instead of building it
+    pub interp: bool,
+    /// `nimra disasm` prints the program's bytecode instead of building it
+    pub disassemble: bool,
+    /// Chosen with `--error-format=human` or `--error-format=json`; human by default
+    pub error_format: ErrorFormat,
+}
+
+impl Options {
+    /// The options `nimra <input>` compiles with: an unoptimized debug build
+    /// by the C backend, written to `a.out`
+    pub fn new(input: &str) -> Options {
+        Options {
+            input: input.to_string(),
+            release: false,
+            output: "./a.out".to_string(),
+            opt_level: 0,
+            emit_ic: false,
+            emit_llvm: false,
+            emit_wat: false,
+            backend: Backend::C,
+            run: false,
+            interp: false,
+            disassemble: false,
+            error_format: ErrorFormat::Human,
+        }
+    }
+}
+
+pub fn parse_args() -> Result<Options, String> {
+    let mut input = None;
+    let mut release = false;
+    let mut output = None;
+    let mut opt_level = None;
+    let mut emit_ic = false;
+    let mut emit_llvm = false;
+    let mut emit_wat = false;
+    let mut backend = Backend::C;
+    let mut args = env::args().skip(1).peekable();
+    let run = args.next_if(|arg| arg == "run").is_some();
+    let disassemble = !run && args.next_if(|arg| arg == "disasm").is_some();
+    let mut interp = false;
+    let mut error_format = ErrorFormat::Human;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--release" => release = true,
+            "--emit=ic" => emit_ic = true,
+            "--emit=llvm" => emit_llvm = true,
+            "--emit=wat" => emit_wat = true,
+            "--interp" => interp = true,
+            "--backend=c" => backend = Backend::C,
+            "--backend=asm" => backend = Backend::Asm,
+            "--backend=bytecode" => backend = Backend::Bytecode,
+            "--backend=llvm" => backend = Backend::Llvm,
+            "--backend=wasm" => backend = Backend::Wasm,
+            "--error-format=human" => error_format = ErrorFormat::Human,
+            "--error-format=json" => error_format = ErrorFormat::Json,
+            flag if flag.starts_with("--error-format=") => {
+                return Err(format!(
+                    "Error: Unknown error format {}",
+                    &flag["--error-format=".len()..]
+                ));
+            }
+            flag if flag.starts_with("--backend=") => {
+                return Err(format!(
+                    "Error: Unknown backend {}",
+                    &flag["--backend=".len()..]
+                ));
+            }
+            "-o" => output = Some(args.next().ok_or("Error: -o needs a path after it")?),
+            "-O0" => opt_level = Some(0),
+            "-O1" => opt_level = Some(1),
+            "-O2" => opt_level = Some(2),
+            "-O3" => opt_level = Some(3),
+            flag if flag.starts_with("-O") => {
+                return Err(format!("Error: Unknown optimization level {flag}"));
+            }
+            flag if flag.starts_with("--") => {
+                return Err(format!("Error: Unknown option {flag}"));
+            }
+            _ if input.is_some() => {
+                return Err("Error: Please provide exactly one input file".to_string());
+            }
+            _ => input = Some(arg),
+        }
+    }
+    let input = input.ok_or("Error: Please provide the file path to compile")?;
+    let backend = if disassemble {
+        Backend::Bytecode
+    } else if emit_llvm {
+        Backend::Llvm
+    } else if emit_wat {
+        Backend::Wasm
+    } else {
+        backend
+    };
+    // A module is not an executable, so it gets a name that says so
+    let output = output.unwrap_or_else(|| {
+        if backend == Backend::Wasm || input.ends_with(".wat") {
+            "./a.wasm".to_string()
+        } else {
+            "./a.out".to_string()
+        }
+    });
+    Ok(Options {
+        input,
+        release,
+        output,
+        opt_level: opt_level.unwrap_or(if release { 2 } else { 0 }),
+        emit_ic,
+        emit_llvm,
+        emit_wat,
+        backend,
+        run: run || interp,
+        interp,
+        disassemble,
+        error_format,
+    })
+}
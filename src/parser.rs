@@ -15,7 +15,63 @@
  * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
  */
 
-use crate::lexer::{Literal, Token, Type};
+use crate::diagnostic::{codes, Diagnostic, Fix, Stage};
+use crate::lexer::{Length, Literal, Span, Token, Type};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+/// A generic parameter such as `T: Ord + Display`
+#[derive(Debug, Clone)]
+pub struct TypeParam {
+    pub name: String,
+    pub bounds: Vec<String>,
+}
+
+/// What a closure runs: `|x| x + 1` or `|x| { ... }`
+#[derive(Debug, Clone)]
+pub enum ClosureBody {
+    Expr(Box<ASTNode>),
+    Block(Vec<ASTNode>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// `_`, which matches anything
+    Wildcard,
+    /// A constant expression the value must equal
+    Value(ASTNode),
+    /// `Some(x)`, `None`, `Ok(_)` or `Err(e)`, with the name the value inside
+    /// is bound to, if it is bound
+    Variant(String, Option<String>),
+}
+
+/// `pattern | pattern => body` in a `match`
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub patterns: Vec<Pattern>,
+    pub body: Vec<ASTNode>,
+}
 
 #[derive(Debug, Clone)]
 pub enum ASTNode {
@@ -24,44 +80,200 @@ pub enum ASTNode {
         module: String,
         name: String,
     },
+    /// `span` is where the function's name is
     FnDecl {
         name: String,
+        type_params: Vec<TypeParam>,
         args: Vec<ASTNode>,
         body: Vec<ASTNode>,
         return_type: Type,
+        span: Span,
+    },
+    StructDecl {
+        name: String,
+        type_params: Vec<TypeParam>,
+        fields: Vec<(String, Type)>,
+    },
+    /// Each method is a `FnDecl` with an empty body
+    TraitDecl {
+        name: String,
+        methods: Vec<ASTNode>,
+    },
+    /// `span` is where the trait's name is
+    Impl {
+        trait_name: String,
+        ty: Type,
+        methods: Vec<ASTNode>,
+        span: Span,
     },
     FnCall {
         function: String,
         args: Vec<ASTNode>,
+        span: Span,
+    },
+    /// `mutable` is where `mut` is, if the parameter has it, and `span` is
+    /// where its name is
+    Param {
+        name: String,
+        param_type: Type,
+        mutable: Option<Span>,
+        span: Span,
+    },
+    /// `let mut name: type = value;`, where the value may be left for a later
+    /// assignment. `mutable` and `span` are as for a `Param`.
+    Let {
+        name: String,
+        var_type: Option<Type>,
+        value: Option<Box<ASTNode>>,
+        mutable: Option<Span>,
+        span: Span,
+    },
+    /// `target = value`, where `span` is where the `=` is
+    Assign {
+        target: Box<ASTNode>,
+        value: Box<ASTNode>,
+        span: Span,
+    },
+    /// `target op= value`
+    CompoundAssign {
+        op: BinaryOp,
+        target: Box<ASTNode>,
+        value: Box<ASTNode>,
+        span: Span,
+    },
+    If {
+        condition: Box<ASTNode>,
+        then_body: Vec<ASTNode>,
+        else_body: Vec<ASTNode>,
+    },
+    While {
+        condition: Box<ASTNode>,
+        body: Vec<ASTNode>,
+    },
+    For {
+        var: String,
+        iterable: Box<ASTNode>,
+        body: Vec<ASTNode>,
+    },
+    Identifier(String),
+    BinaryOp {
+        op: BinaryOp,
+        lhs: Box<ASTNode>,
+        rhs: Box<ASTNode>,
+        span: Span,
+    },
+    UnaryOp {
+        op: UnaryOp,
+        operand: Box<ASTNode>,
+        span: Span,
+    },
+    Cast {
+        value: Box<ASTNode>,
+        target: Type,
+    },
+    MethodCall {
+        receiver: Box<ASTNode>,
+        method: String,
+        args: Vec<ASTNode>,
+        span: Span,
+    },
+    AssocCall {
+        ty: Type,
+        function: String,
+        args: Vec<ASTNode>,
+    },
+    StructLiteral {
+        name: String,
+        fields: Vec<(String, ASTNode)>,
+    },
+    FieldAccess {
+        receiver: Box<ASTNode>,
+        field: String,
+    },
+    /// `move |x: i32| -> i32 { ... }`; parameter types may be left to inference
+    Closure {
+        params: Vec<(String, Option<Type>)>,
+        return_type: Option<Type>,
+        body: ClosureBody,
+        by_value: bool,
+    },
+    /// A call of anything other than a plain name, such as `make_adder(1)(2)`
+    Call {
+        callee: Box<ASTNode>,
+        args: Vec<ASTNode>,
+    },
+    /// `const NAME: type = value;`, at top level or in a block
+    Const {
+        name: String,
+        ty: Type,
+        value: Box<ASTNode>,
+    },
+    ArrayLiteral(Vec<ASTNode>),
+    /// `[value; len]`
+    ArrayRepeat {
+        value: Box<ASTNode>,
+        len: Length,
+    },
+    Index {
+        receiver: Box<ASTNode>,
+        index: Box<ASTNode>,
+        span: Span,
+    },
+    /// `span` is where the `match` keyword is
+    Match {
+        scrutinee: Box<ASTNode>,
+        arms: Vec<MatchArm>,
+        span: Span,
+    },
+    /// `&value` or `&mut value`
+    Borrow {
+        value: Box<ASTNode>,
+        mutable: bool,
+    },
+    /// `*reference`
+    Deref(Box<ASTNode>),
+    /// `value?`
+    Try(Box<ASTNode>),
+    /// `return value` or a bare `return` from a void function
+    Return {
+        value: Option<Box<ASTNode>>,
+        span: Span,
     },
 }
 
 pub struct Parser<'a> {
     tokens: &'a Vec<Token>,
+    spans: &'a [Span],
     pos: usize,
     ast: Vec<ASTNode>,
+    // Set while parsing an `if`, `while` or `for` header, where `{` opens the body
+    no_struct_literal: bool,
+    // How to fix the error being returned, when it is a missing token
+    fix: Option<Fix>,
 }
 
-pub fn parse(tokens: &Vec<Token>) -> Vec<ASTNode> {
-    let mut parser = Parser::new(tokens);
-    match parser.parse() {
-        Ok(ast) => ast.clone(),
-        Err(e) => {
-            eprintln!("Parse error: {e}");
-            Vec::new()
-        }
-    }
+pub fn parse(tokens: &Vec<Token>, spans: &[Span]) -> Result<Vec<ASTNode>, Diagnostic> {
+    let mut parser = Parser::new(tokens, spans);
+    let ast = parser.parse().cloned();
+    ast.map_err(|e| parser.diagnostic(e))
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(tokens: &'a Vec<Token>) -> Self {
+    pub fn new(tokens: &'a Vec<Token>, spans: &'a [Span]) -> Self {
         let pos = 0;
         let ast = Vec::new();
-        Parser { tokens, pos, ast }
+        Parser {
+            tokens,
+            spans,
+            pos,
+            ast,
+            no_struct_literal: false,
+            fix: None,
+        }
     }
 
     pub fn parse(&mut self) -> Result<&Vec<ASTNode>, String> {
-        while let Ok(true) = self.parse_token() {}
+        while self.parse_token()? {}
         Ok(&self.ast)
     }
 
@@ -72,181 +284,48 @@ impl<'a> Parser<'a> {
                 Token::Literal(_) => Err("Unexpected literal".to_string()),
                 Token::Semicolon => Err("Unexpected semicolon".to_string()),
                 Token::Import => {
-                    let name: &Token = self
-                        .tokens
-                        .get(self.pos)
-                        .ok_or("Unexpected EOF: expected identifier after import")?;
-                    if !matches!(*name, Token::Identifier(_)) {
-                        return Err("Second word of import is not identifier".to_string());
-                    }
-                    self.pos += 1;
-                    let from: &Token = self
-                        .tokens
-                        .get(self.pos)
-                        .ok_or("Unexpected EOF: expected 'from' after import identifier")?;
-                    if !matches!(*from, Token::From) {
-                        return Err("Third word of import is not from".to_string());
-                    }
-                    self.pos += 1;
-                    let module: &Token = self
-                        .tokens
-                        .get(self.pos)
-                        .ok_or("Unexpected EOF: expected module identifier after from")?;
-                    if !matches!(*module, Token::Identifier(_)) {
-                        return Err("Fourth word of import is not identifier".to_string());
-                    }
-                    self.pos += 1;
-                    let semicolon: &Token = self
-                        .tokens
-                        .get(self.pos)
-                        .ok_or("Unexpected EOF: expected semicolon after import statement")?;
-                    if !matches!(semicolon, Token::Semicolon) {
-                        return Err("Last character of import is not semicolon".to_string());
-                    }
-                    self.pos += 1;
-                    if let Token::Identifier(module) = module {
-                        if let Token::Identifier(name) = name {
-                            self.ast.push(ASTNode::Import {
-                                module: module.to_string(),
-                                name: name.to_string(),
-                            })
-                        }
-                    }
+                    let name = self.expect_identifier("name to import after import")?;
+                    self.expect(Token::From, "from after the imported name")?;
+                    let module = self.expect_identifier("module name after from")?;
+                    self.expect(Token::Semicolon, "semicolon after import")?;
+                    self.ast.push(ASTNode::Import { module, name });
                     Ok(true)
                 }
                 Token::From => Err("Unexpected from".to_string()),
                 Token::Type(return_type) => {
-                    let fn_token = self
-                        .tokens
-                        .get(self.pos)
-                        .ok_or("Unexpected EOF: expected fn after type")?;
-                    if !matches!(fn_token, Token::Fn) {
-                        return Err("fn expected after type".to_string());
-                    }
-                    self.pos += 1;
-                    let fn_name = self
-                        .tokens
-                        .get(self.pos)
-                        .ok_or("Unexpected EOF: expected function name after fn")?;
-                    if !matches!(fn_name, Token::Identifier(_)) {
-                        return Err("Function name expected after fn".to_string());
-                    }
-                    self.pos += 1;
-                    let open_paren = self
-                        .tokens
-                        .get(self.pos)
-                        .ok_or("Unexpected EOF: expected open paren after function name")?;
-                    if !matches!(open_paren, Token::OpenParen) {
-                        return Err("Open paren expected after function name".to_string());
-                    }
-                    self.pos += 1;
-                    let close_paren = self
-                        .tokens
-                        .get(self.pos)
-                        .ok_or("Unexpected EOF: expected close paren after open paren")?;
-                    if !matches!(close_paren, Token::CloseParen) {
-                        return Err("Close paren expected after open paren".to_string());
-                    }
-                    self.pos += 1;
-                    let open_brace = self
-                        .tokens
-                        .get(self.pos)
-                        .ok_or("Unexpected EOF: expected open brace after close paren")?;
-                    if !matches!(open_brace, Token::OpenBrace) {
-                        return Err("Expected open brace".to_string());
-                    }
-                    self.pos += 1;
-                    let mut fn_tokens = Vec::new();
-                    let mut close_brace_passed = false;
-                    while !close_brace_passed {
-                        if let Some(fn_token) = self.tokens.get(self.pos) {
-                            self.pos += 1;
-                            if *fn_token != Token::CloseBrace {
-                                fn_tokens.push(fn_token.clone());
-                            } else {
-                                close_brace_passed = true
-                            }
-                        } else {
-                            return Err(
-                                "Unexpected EOF: expected close brace in function body".to_string()
-                            );
-                        }
-                    }
-                    let fn_ast = parse(&fn_tokens);
-                    if let Token::Identifier(fn_name_str) = fn_name {
-                        self.ast.push(ASTNode::FnDecl {
-                            name: fn_name_str.clone(),
-                            args: Vec::new(),
-                            body: fn_ast,
-                            return_type: return_type.clone(),
-                        });
-                    }
+                    let fn_decl = self.parse_fn_decl(return_type.clone())?;
+                    self.ast.push(fn_decl);
                     Ok(true)
                 }
-                Token::Fn => Err("Unexpected fn".to_string()),
-                Token::Identifier(ident) => {
-                    let open_paren = self
-                        .tokens
-                        .get(self.pos)
-                        .ok_or("Unexpected EOF: expected open paren after identifier")?;
-                    if !matches!(*open_paren, Token::OpenParen) {
-                        return Err("Expected open paren".to_string());
-                    }
-                    self.pos += 1;
-                    let close_paren_or_literal = self.tokens.get(self.pos).ok_or(
-                        "Unexpected EOF: expected close paren or literal after open paren",
-                    )?;
-                    self.pos += 1;
-                    if !matches!(*close_paren_or_literal, Token::CloseParen) {
-                        let mut continue_execution = false;
-                        if matches!(*close_paren_or_literal, Token::Literal(_)) {
-                            let close_paren = self
-                                .tokens
-                                .get(self.pos)
-                                .ok_or("Unexpected EOF: expected close paren after literal")?;
-                            self.pos += 1;
-                            if !matches!(*close_paren, Token::CloseParen) {
-                                return Err("Expected close paren".to_string());
-                            }
-                            continue_execution = true
-                        }
-                        if !continue_execution {
-                            return Err("Expected close paren or literal".to_string());
-                        }
-                    } else {
-                        let close_paren_or_literal: &Token = self
-                            .tokens
-                            .get(self.pos)
-                            .ok_or("Unexpected EOF: expected literal after close paren")?;
-                        self.pos += 1;
-                        if !matches!(*close_paren_or_literal, Token::Literal(_)) {
-                            return Err("Expected literal".to_string());
-                        }
-                    }
-                    let semicolon = self
-                        .tokens
-                        .get(self.pos)
-                        .ok_or("Unexpected EOF: expected semicolon after function call")?;
-                    self.pos += 1;
-                    if !matches!(semicolon, Token::Semicolon) {
-                        return Err("Expected semicolon".to_string());
-                    }
-                    if let Token::Literal(literal) = close_paren_or_literal {
-                        match literal {
-                            Literal::String(s) => {
-                                self.ast.push(ASTNode::FnCall {
-                                    function: ident.to_string(),
-                                    args: vec![ASTNode::Literal(Literal::String(s.clone()))],
-                                });
-                            }
-                            Literal::Number(n) => {
-                                self.ast.push(ASTNode::FnCall {
-                                    function: ident.to_string(),
-                                    args: vec![ASTNode::Literal(Literal::Number(*n))],
-                                });
-                            }
-                        }
-                    }
+                Token::Struct => {
+                    let struct_decl = self.parse_struct_decl()?;
+                    self.ast.push(struct_decl);
+                    Ok(true)
+                }
+                Token::Trait => {
+                    let trait_decl = self.parse_trait_decl()?;
+                    self.ast.push(trait_decl);
+                    Ok(true)
+                }
+                Token::Impl => {
+                    let impl_block = self.parse_impl()?;
+                    self.ast.push(impl_block);
+                    Ok(true)
+                }
+                Token::Const => {
+                    let const_decl = self.parse_const()?;
+                    self.ast.push(const_decl);
+                    Ok(true)
+                }
+                Token::Fn if self.peek() != Some(&Token::OpenParen) => {
+                    Err("Unexpected fn".to_string())
+                }
+                Token::Fn | Token::Identifier(_) | Token::Ampersand => {
+                    // A struct, type parameter, function type or reference as the return type
+                    self.pos -= 1;
+                    let return_type = self.parse_type()?;
+                    let fn_decl = self.parse_fn_decl(return_type)?;
+                    self.ast.push(fn_decl);
                     Ok(true)
                 }
                 Token::OpenBrace => Err("Unexpected open brace".to_string()),
@@ -254,9 +333,808 @@ impl<'a> Parser<'a> {
                 Token::Unknown(_) => Err("Syntax error".to_string()),
                 Token::CloseParen => Err("Unexpected close paren".to_string()),
                 Token::OpenParen => Err("Unexpected open paren".to_string()),
+                token => Err(format!("Unexpected {token:?} at top level")),
             }
         } else {
             Ok(false)
         }
     }
+
+    // Where the next token starts, or the last token if the input has ended
+    fn span(&self) -> Span {
+        self.spans
+            .get(self.pos)
+            .or(self.spans.last())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    // Consumes the next token, naming what was expected if the input ends here
+    fn next(&mut self, expected: &str) -> Result<&'a Token, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or(format!("Unexpected EOF: expected {expected}"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected_token: Token, expected: &str) -> Result<(), String> {
+        let token = self.next(expected)?;
+        if *token != expected_token {
+            // Punctuation that is missing can be put in before what was found
+            let replacement = match expected_token {
+                Token::Semicolon => Some(";"),
+                Token::Comma => Some(","),
+                Token::Colon => Some(":"),
+                Token::CloseParen => Some(")"),
+                Token::CloseBracket => Some("]"),
+                Token::CloseBrace => Some("}"),
+                _ => None,
+            };
+            self.fix = replacement.map(|replacement| Fix {
+                message: "insert the missing token".to_string(),
+                span: self.spans.get(self.pos - 1).copied().unwrap_or_default(),
+                len: 0,
+                replacement: replacement.to_string(),
+            });
+            return Err(format!("Expected {expected}, found {token:?}"));
+        }
+        Ok(())
+    }
+
+    // Makes an error a diagnostic pointing at the token it was found at,
+    // which is the last one read, or at the end of the input
+    fn diagnostic(&mut self, message: String) -> Diagnostic {
+        let span = self
+            .spans
+            .get(self.pos.saturating_sub(1))
+            .or(self.spans.last())
+            .copied()
+            .unwrap_or_default();
+        let diagnostic = if message.starts_with("Unexpected EOF") {
+            Diagnostic::error(Stage::Parse, message)
+                .with_code(codes::UNEXPECTED_END)
+                .with_primary(span, "the input ends after this")
+        } else {
+            Diagnostic::error(Stage::Parse, message)
+                .with_code(codes::UNEXPECTED_TOKEN)
+                .with_primary(span, "")
+        };
+        match self.fix.take() {
+            Some(fix) => diagnostic.with_fix(fix),
+            None => diagnostic,
+        }
+    }
+
+    // Consumes the next token if it is `token`
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Consumes a `mut`, giving where it is if there is one
+    fn mutable(&mut self) -> Option<Span> {
+        let span = self.span();
+        self.eat(&Token::Mut).then_some(span)
+    }
+
+    fn expect_identifier(&mut self, expected: &str) -> Result<String, String> {
+        match self.next(expected)? {
+            Token::Identifier(name) => Ok(name.clone()),
+            token => Err(format!("Expected {expected}, found {token:?}")),
+        }
+    }
+
+    fn parse_type(&mut self) -> Result<Type, String> {
+        match self.next("type")? {
+            Token::Type(ty) => Ok(ty.clone()),
+            Token::Dyn => Ok(Type::Dyn(self.expect_identifier("trait name after dyn")?)),
+            Token::OpenBracket => {
+                let element = self.parse_type()?;
+                self.expect(Token::Semicolon, "semicolon after array element type")?;
+                let len = self.parse_length()?;
+                self.expect(Token::CloseBracket, "close bracket after array length")?;
+                Ok(Type::Array(Box::new(element), len))
+            }
+            Token::Ampersand => {
+                let mutable = self.eat(&Token::Mut);
+                Ok(Type::Ref(Box::new(self.parse_type()?), mutable))
+            }
+            Token::Fn => {
+                self.expect(Token::OpenParen, "open paren after fn")?;
+                let mut params = Vec::new();
+                while !self.eat(&Token::CloseParen) {
+                    if !params.is_empty() {
+                        self.expect(Token::Comma, "comma between parameter types")?;
+                    }
+                    params.push(self.parse_type()?);
+                }
+                let return_type = if self.eat(&Token::Arrow) {
+                    self.parse_type()?
+                } else {
+                    Type::Void
+                };
+                Ok(Type::Fn(params, Box::new(return_type)))
+            }
+            // Semantic analysis tells type parameters apart from structs
+            Token::Identifier(name) => {
+                let mut args = Vec::new();
+                if self.eat(&Token::Less) {
+                    while !self.eat(&Token::Greater) {
+                        if !args.is_empty() {
+                            self.expect(Token::Comma, "comma between type arguments")?;
+                        }
+                        args.push(self.parse_type()?);
+                    }
+                }
+                Ok(Type::Struct(name.clone(), args))
+            }
+            token => Err(format!("Expected type, found {token:?}")),
+        }
+    }
+
+    // Parses the length of an array: a number or the name of a constant
+    fn parse_length(&mut self) -> Result<Length, String> {
+        match self.next("array length")? {
+            Token::Literal(Literal::Number(len)) if *len >= 0 => Ok(Length::Known(*len as u64)),
+            Token::Identifier(name) => Ok(Length::Const(name.clone())),
+            token => Err(format!("Expected array length, found {token:?}")),
+        }
+    }
+
+    // Parses `NAME: type = value;` after the const keyword
+    fn parse_const(&mut self) -> Result<ASTNode, String> {
+        let name = self.expect_identifier("constant name after const")?;
+        self.expect(Token::Colon, "colon after constant name")?;
+        let ty = self.parse_type()?;
+        self.expect(Token::Assign, "= after constant type")?;
+        let value = Box::new(self.parse_expr()?);
+        self.expect(Token::Semicolon, "semicolon after constant")?;
+        Ok(ASTNode::Const { name, ty, value })
+    }
+
+    // Parses an optional `<T: Bound + Bound, U>` list after a function or struct name
+    fn parse_type_params(&mut self) -> Result<Vec<TypeParam>, String> {
+        let mut type_params = Vec::new();
+        if !self.eat(&Token::Less) {
+            return Ok(type_params);
+        }
+        while !self.eat(&Token::Greater) {
+            if !type_params.is_empty() {
+                self.expect(Token::Comma, "comma between type parameters")?;
+            }
+            let name = self.expect_identifier("type parameter name")?;
+            let mut bounds = Vec::new();
+            if self.eat(&Token::Colon) {
+                bounds.push(self.expect_identifier("bound after colon")?);
+                while self.eat(&Token::Plus) {
+                    bounds.push(self.expect_identifier("bound after +")?);
+                }
+            }
+            type_params.push(TypeParam { name, bounds });
+        }
+        Ok(type_params)
+    }
+
+    // Parses `struct Name<T> { field: type, ... }` after the struct keyword
+    fn parse_struct_decl(&mut self) -> Result<ASTNode, String> {
+        let name = self.expect_identifier("struct name after struct")?;
+        let type_params = self.parse_type_params()?;
+        self.expect(Token::OpenBrace, "open brace after struct name")?;
+        let mut fields = Vec::new();
+        while !self.eat(&Token::CloseBrace) {
+            let field = self.expect_identifier("field name")?;
+            self.expect(Token::Colon, "colon after field name")?;
+            fields.push((field, self.parse_type()?));
+            if !self.eat(&Token::Comma) {
+                self.expect(Token::CloseBrace, "comma or close brace after field")?;
+                break;
+            }
+        }
+        Ok(ASTNode::StructDecl {
+            name,
+            type_params,
+            fields,
+        })
+    }
+
+    // Parses `trait Name { type fn method(self, ...); ... }` after the trait keyword
+    fn parse_trait_decl(&mut self) -> Result<ASTNode, String> {
+        let name = self.expect_identifier("trait name after trait")?;
+        self.expect(Token::OpenBrace, "open brace after trait name")?;
+        let mut methods = Vec::new();
+        while !self.eat(&Token::CloseBrace) {
+            let return_type = self.parse_type()?;
+            methods.push(self.parse_fn_signature(return_type)?);
+        }
+        Ok(ASTNode::TraitDecl { name, methods })
+    }
+
+    // Parses `impl Trait for Type { methods }` after the impl keyword
+    fn parse_impl(&mut self) -> Result<ASTNode, String> {
+        let span = self.span();
+        let trait_name = self.expect_identifier("trait name after impl")?;
+        self.expect(Token::For, "for after trait name")?;
+        let ty = self.parse_type()?;
+        self.expect(Token::OpenBrace, "open brace after impl type")?;
+        let mut methods = Vec::new();
+        while !self.eat(&Token::CloseBrace) {
+            let return_type = self.parse_type()?;
+            methods.push(self.parse_fn_decl(return_type)?);
+        }
+        Ok(ASTNode::Impl {
+            trait_name,
+            ty,
+            methods,
+            span,
+        })
+    }
+
+    // Parses a trait method, which ends in a semicolon instead of a body
+    fn parse_fn_signature(&mut self, return_type: Type) -> Result<ASTNode, String> {
+        let (name, span, type_params, args) = self.parse_fn_head()?;
+        self.expect(Token::Semicolon, "semicolon after trait method")?;
+        Ok(ASTNode::FnDecl {
+            name,
+            type_params,
+            args,
+            body: Vec::new(),
+            return_type,
+            span,
+        })
+    }
+
+    // Parses `fn name<T>(arg: type, ...)`, where the first parameter may be a
+    // bare `self`, and gives where the name is along with it
+    fn parse_fn_head(&mut self) -> Result<(String, Span, Vec<TypeParam>, Vec<ASTNode>), String> {
+        self.expect(Token::Fn, "fn after type")?;
+        let fn_span = self.span();
+        let name = self.expect_identifier("function name after fn")?;
+        let type_params = self.parse_type_params()?;
+        self.expect(Token::OpenParen, "open paren after function name")?;
+        let mut args = Vec::new();
+        while !self.eat(&Token::CloseParen) {
+            if !args.is_empty() {
+                self.expect(Token::Comma, "comma between parameters")?;
+            }
+            let mutable = self.mutable();
+            let span = self.span();
+            let name = self.expect_identifier("parameter name")?;
+            let param_type = if name == "self" && self.peek() != Some(&Token::Colon) {
+                Type::Struct("Self".to_string(), Vec::new())
+            } else {
+                self.expect(Token::Colon, "colon after parameter name")?;
+                self.parse_type()?
+            };
+            args.push(ASTNode::Param {
+                name,
+                param_type,
+                mutable,
+                span,
+            });
+        }
+        Ok((name, fn_span, type_params, args))
+    }
+
+    // Parses `fn name(arg: type, ...) { ... }` after the return type
+    fn parse_fn_decl(&mut self, return_type: Type) -> Result<ASTNode, String> {
+        let (name, span, type_params, args) = self.parse_fn_head()?;
+        let body = self.parse_block()?;
+        Ok(ASTNode::FnDecl {
+            name,
+            type_params,
+            args,
+            body,
+            return_type,
+            span,
+        })
+    }
+
+    fn parse_block(&mut self) -> Result<Vec<ASTNode>, String> {
+        self.expect(Token::OpenBrace, "open brace")?;
+        let mut body = Vec::new();
+        while !self.eat(&Token::CloseBrace) {
+            if self.peek().is_none() {
+                return Err("Unexpected EOF: expected close brace".to_string());
+            }
+            body.push(self.parse_statement()?);
+        }
+        Ok(body)
+    }
+
+    fn parse_statement(&mut self) -> Result<ASTNode, String> {
+        match self.peek() {
+            Some(Token::Let) => {
+                self.pos += 1;
+                let mutable = self.mutable();
+                let span = self.span();
+                let name = self.expect_identifier("variable name after let")?;
+                let var_type = if self.eat(&Token::Colon) {
+                    Some(self.parse_type()?)
+                } else {
+                    None
+                };
+                let value = if self.eat(&Token::Assign) {
+                    Some(Box::new(self.parse_expr()?))
+                } else {
+                    None
+                };
+                self.expect(Token::Semicolon, "semicolon after let statement")?;
+                Ok(ASTNode::Let {
+                    name,
+                    var_type,
+                    value,
+                    mutable,
+                    span,
+                })
+            }
+            Some(Token::If) => self.parse_if(),
+            Some(Token::Const) => {
+                self.pos += 1;
+                self.parse_const()
+            }
+            Some(Token::Match) => self.parse_match(),
+            Some(Token::While) => {
+                self.pos += 1;
+                let condition = Box::new(self.parse_condition()?);
+                let body = self.parse_block()?;
+                Ok(ASTNode::While { condition, body })
+            }
+            Some(Token::For) => {
+                self.pos += 1;
+                let var = self.expect_identifier("loop variable after for")?;
+                self.expect(Token::In, "in after loop variable")?;
+                let iterable = Box::new(self.parse_condition()?);
+                let body = self.parse_block()?;
+                Ok(ASTNode::For {
+                    var,
+                    iterable,
+                    body,
+                })
+            }
+            _ => {
+                let statement = self.parse_simple_statement()?;
+                self.expect(Token::Semicolon, "semicolon after statement")?;
+                Ok(statement)
+            }
+        }
+    }
+
+    // Parses a return, or an expression or an assignment to it, without the semicolon
+    fn parse_simple_statement(&mut self) -> Result<ASTNode, String> {
+        if self.peek() == Some(&Token::Return) {
+            let span = self.span();
+            self.pos += 1;
+            let value = match self.peek() {
+                Some(Token::Semicolon | Token::Comma | Token::CloseBrace) => None,
+                _ => Some(Box::new(self.parse_expr()?)),
+            };
+            return Ok(ASTNode::Return { value, span });
+        }
+        let expr = self.parse_expr()?;
+        let span = self.span();
+        if self.eat(&Token::Assign) {
+            return Ok(ASTNode::Assign {
+                target: Box::new(expr),
+                value: Box::new(self.parse_expr()?),
+                span,
+            });
+        }
+        let op = match self.peek() {
+            Some(Token::PlusAssign) => BinaryOp::Add,
+            Some(Token::MinusAssign) => BinaryOp::Sub,
+            Some(Token::StarAssign) => BinaryOp::Mul,
+            Some(Token::SlashAssign) => BinaryOp::Div,
+            Some(Token::PercentAssign) => BinaryOp::Rem,
+            _ => return Ok(expr),
+        };
+        self.pos += 1;
+        Ok(ASTNode::CompoundAssign {
+            op,
+            target: Box::new(expr),
+            value: Box::new(self.parse_expr()?),
+            span,
+        })
+    }
+
+    fn parse_if(&mut self) -> Result<ASTNode, String> {
+        self.expect(Token::If, "if")?;
+        let condition = Box::new(self.parse_condition()?);
+        let then_body = self.parse_block()?;
+        let else_body = if self.eat(&Token::Else) {
+            if self.peek() == Some(&Token::If) {
+                vec![self.parse_if()?]
+            } else {
+                self.parse_block()?
+            }
+        } else {
+            Vec::new()
+        };
+        Ok(ASTNode::If {
+            condition,
+            then_body,
+            else_body,
+        })
+    }
+
+    fn parse_match(&mut self) -> Result<ASTNode, String> {
+        let span = self.span();
+        self.expect(Token::Match, "match")?;
+        let scrutinee = Box::new(self.parse_condition()?);
+        self.expect(Token::OpenBrace, "open brace after match value")?;
+        let mut arms = Vec::new();
+        while !self.eat(&Token::CloseBrace) {
+            let mut patterns = Vec::new();
+            loop {
+                match self.peek() {
+                    Some(Token::Identifier(name)) if name == "_" => {
+                        self.pos += 1;
+                        patterns.push(Pattern::Wildcard);
+                    }
+                    Some(Token::Identifier(name)) if is_variant(name) => {
+                        self.pos += 1;
+                        patterns.push(self.parse_variant_pattern(name)?);
+                    }
+                    _ => patterns.push(Pattern::Value(self.parse_expr()?)),
+                }
+                if !self.eat(&Token::Pipe) {
+                    break;
+                }
+            }
+            self.expect(Token::FatArrow, "=> after match pattern")?;
+            // An arm is a block, or a single statement without its semicolon
+            let body = if self.peek() == Some(&Token::OpenBrace) {
+                let body = self.parse_block()?;
+                self.eat(&Token::Comma);
+                body
+            } else {
+                let statement = self.parse_simple_statement()?;
+                if self.peek() != Some(&Token::CloseBrace) {
+                    self.expect(Token::Comma, "comma after match arm")?;
+                }
+                vec![statement]
+            };
+            arms.push(MatchArm { patterns, body });
+        }
+        Ok(ASTNode::Match {
+            scrutinee,
+            arms,
+            span,
+        })
+    }
+
+    // Parses `(name)` or `(_)` after `Some`, `Ok` or `Err` in a pattern
+    fn parse_variant_pattern(&mut self, name: &str) -> Result<Pattern, String> {
+        if name == "None" {
+            return Ok(Pattern::Variant(name.to_string(), None));
+        }
+        self.expect(Token::OpenParen, &format!("open paren after {name}"))?;
+        let binding = self.expect_identifier(&format!("name to bind in {name}"))?;
+        self.expect(
+            Token::CloseParen,
+            &format!("close paren after {name}'s binding"),
+        )?;
+        let binding = (binding != "_").then_some(binding);
+        Ok(Pattern::Variant(name.to_string(), binding))
+    }
+
+    pub fn parse_expr(&mut self) -> Result<ASTNode, String> {
+        self.parse_binary(0)
+    }
+
+    // Parses the expression before a block, where `Name {` cannot start a struct literal
+    fn parse_condition(&mut self) -> Result<ASTNode, String> {
+        let no_struct_literal = std::mem::replace(&mut self.no_struct_literal, true);
+        let condition = self.parse_expr();
+        self.no_struct_literal = no_struct_literal;
+        condition
+    }
+
+    // Parses an expression nested in brackets, where struct literals are allowed again
+    fn parse_nested_expr(&mut self) -> Result<ASTNode, String> {
+        let no_struct_literal = std::mem::replace(&mut self.no_struct_literal, false);
+        let expr = self.parse_expr();
+        self.no_struct_literal = no_struct_literal;
+        expr
+    }
+
+    // Precedence climbing over the binary operators, loosest binding first
+    fn parse_binary(&mut self, level: usize) -> Result<ASTNode, String> {
+        const LEVELS: [&[(Token, BinaryOp)]; 5] = [
+            &[(Token::Or, BinaryOp::Or)],
+            &[(Token::And, BinaryOp::And)],
+            &[
+                (Token::Equal, BinaryOp::Equal),
+                (Token::NotEqual, BinaryOp::NotEqual),
+                (Token::Less, BinaryOp::Less),
+                (Token::LessEqual, BinaryOp::LessEqual),
+                (Token::Greater, BinaryOp::Greater),
+                (Token::GreaterEqual, BinaryOp::GreaterEqual),
+            ],
+            &[(Token::Plus, BinaryOp::Add), (Token::Minus, BinaryOp::Sub)],
+            &[
+                (Token::Star, BinaryOp::Mul),
+                (Token::Slash, BinaryOp::Div),
+                (Token::Percent, BinaryOp::Rem),
+            ],
+        ];
+        if level == LEVELS.len() {
+            return self.parse_cast();
+        }
+        let span = self.span();
+        let mut lhs = self.parse_binary(level + 1)?;
+        while let Some(&(_, op)) = LEVELS[level]
+            .iter()
+            .find(|(token, _)| self.peek() == Some(token))
+        {
+            self.pos += 1;
+            let rhs = self.parse_binary(level + 1)?;
+            lhs = ASTNode::BinaryOp {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+                span,
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn parse_cast(&mut self) -> Result<ASTNode, String> {
+        let mut value = self.parse_unary()?;
+        while self.eat(&Token::As) {
+            let target = self.parse_type()?;
+            value = ASTNode::Cast {
+                value: Box::new(value),
+                target,
+            };
+        }
+        Ok(value)
+    }
+
+    fn parse_unary(&mut self) -> Result<ASTNode, String> {
+        let span = self.span();
+        let op = match self.peek() {
+            Some(Token::Minus) => UnaryOp::Neg,
+            Some(Token::Not) => UnaryOp::Not,
+            Some(Token::Ampersand) => {
+                self.pos += 1;
+                let mutable = self.eat(&Token::Mut);
+                return Ok(ASTNode::Borrow {
+                    value: Box::new(self.parse_unary()?),
+                    mutable,
+                });
+            }
+            Some(Token::Star) => {
+                self.pos += 1;
+                return Ok(ASTNode::Deref(Box::new(self.parse_unary()?)));
+            }
+            _ => return self.parse_postfix(),
+        };
+        self.pos += 1;
+        Ok(ASTNode::UnaryOp {
+            op,
+            operand: Box::new(self.parse_unary()?),
+            span,
+        })
+    }
+
+    fn parse_postfix(&mut self) -> Result<ASTNode, String> {
+        let span = self.span();
+        let mut expr = self.parse_primary()?;
+        loop {
+            if self.peek() == Some(&Token::OpenParen) {
+                let args = self.parse_args()?;
+                expr = ASTNode::Call {
+                    callee: Box::new(expr),
+                    args,
+                };
+                continue;
+            }
+            if self.peek() == Some(&Token::OpenBracket) {
+                let span = self.span();
+                self.pos += 1;
+                let index = Box::new(self.parse_nested_expr()?);
+                self.expect(Token::CloseBracket, "close bracket after index")?;
+                expr = ASTNode::Index {
+                    receiver: Box::new(expr),
+                    index,
+                    span,
+                };
+                continue;
+            }
+            if self.eat(&Token::Question) {
+                expr = ASTNode::Try(Box::new(expr));
+                continue;
+            }
+            if !self.eat(&Token::Dot) {
+                break;
+            }
+            let name = self.expect_identifier("field or method name after dot")?;
+            if self.peek() != Some(&Token::OpenParen) {
+                expr = ASTNode::FieldAccess {
+                    receiver: Box::new(expr),
+                    field: name,
+                };
+                continue;
+            }
+            let args = self.parse_args()?;
+            expr = ASTNode::MethodCall {
+                receiver: Box::new(expr),
+                method: name,
+                args,
+                span,
+            };
+        }
+        Ok(expr)
+    }
+
+    fn parse_primary(&mut self) -> Result<ASTNode, String> {
+        let span = self.span();
+        match self.next("expression")? {
+            Token::Literal(literal) => Ok(ASTNode::Literal(literal.clone())),
+            Token::Identifier(ident) => {
+                if self.peek() == Some(&Token::OpenParen) {
+                    let args = self.parse_args()?;
+                    Ok(ASTNode::FnCall {
+                        function: ident.clone(),
+                        args,
+                        span,
+                    })
+                } else if self.peek() == Some(&Token::OpenBrace) && !self.no_struct_literal {
+                    self.parse_struct_literal(ident)
+                } else if self.eat(&Token::DoubleColon) {
+                    // Such as `Box::new`; semantic analysis resolves the type
+                    Ok(ASTNode::AssocCall {
+                        ty: Type::Struct(ident.clone(), Vec::new()),
+                        function: self.parse_assoc_function()?,
+                        args: self.parse_args()?,
+                    })
+                } else {
+                    Ok(ASTNode::Identifier(ident.clone()))
+                }
+            }
+            Token::Type(ty) => {
+                self.expect(Token::DoubleColon, ":: after type name")?;
+                let function = self.parse_assoc_function()?;
+                let args = self.parse_args()?;
+                Ok(ASTNode::AssocCall {
+                    ty: ty.clone(),
+                    function,
+                    args,
+                })
+            }
+            Token::OpenParen => {
+                let expr = self.parse_nested_expr()?;
+                self.expect(Token::CloseParen, "close paren")?;
+                Ok(expr)
+            }
+            Token::OpenBracket => self.parse_array(),
+            // `move` closures capture by value instead of by reference
+            Token::Move => match self.next("closure after move")? {
+                Token::Pipe => self.parse_closure(false, true),
+                Token::Or => self.parse_closure(true, true),
+                token => Err(format!("Expected closure after move, found {token:?}")),
+            },
+            Token::Pipe => self.parse_closure(false, false),
+            // `||` is a closure without parameters
+            Token::Or => self.parse_closure(true, false),
+            token => Err(format!("Expected expression, found {token:?}")),
+        }
+    }
+
+    // Parses the name after `Type::`
+    fn parse_assoc_function(&mut self) -> Result<String, String> {
+        // `from` is a keyword, but `String::from` still needs to parse
+        match self.next("function name after ::")? {
+            Token::Identifier(name) => Ok(name.clone()),
+            Token::From => Ok("from".to_string()),
+            token => Err(format!("Expected function name after ::, found {token:?}")),
+        }
+    }
+
+    // Parses `[a, b, c]` or `[value; len]` after the open bracket
+    fn parse_array(&mut self) -> Result<ASTNode, String> {
+        let mut elements = Vec::new();
+        while !self.eat(&Token::CloseBracket) {
+            elements.push(self.parse_nested_expr()?);
+            if elements.len() == 1 && self.eat(&Token::Semicolon) {
+                let len = self.parse_length()?;
+                self.expect(Token::CloseBracket, "close bracket after array length")?;
+                return Ok(ASTNode::ArrayRepeat {
+                    value: Box::new(elements.remove(0)),
+                    len,
+                });
+            }
+            if !self.eat(&Token::Comma) {
+                self.expect(Token::CloseBracket, "comma or close bracket after element")?;
+                break;
+            }
+        }
+        Ok(ASTNode::ArrayLiteral(elements))
+    }
+
+    // Parses a closure after its opening `|`, or after `||` if it takes no parameters
+    fn parse_closure(&mut self, no_params: bool, by_value: bool) -> Result<ASTNode, String> {
+        let mut params = Vec::new();
+        if !no_params {
+            while !self.eat(&Token::Pipe) {
+                if !params.is_empty() {
+                    self.expect(Token::Comma, "comma between closure parameters")?;
+                }
+                let name = self.expect_identifier("closure parameter name")?;
+                let param_type = if self.eat(&Token::Colon) {
+                    Some(self.parse_type()?)
+                } else {
+                    None
+                };
+                params.push((name, param_type));
+            }
+        }
+        let return_type = if self.eat(&Token::Arrow) {
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+        // A closure with a declared return type needs a block, as in Rust
+        let body = if return_type.is_some() || self.peek() == Some(&Token::OpenBrace) {
+            let no_struct_literal = std::mem::replace(&mut self.no_struct_literal, false);
+            let block = self.parse_block();
+            self.no_struct_literal = no_struct_literal;
+            ClosureBody::Block(block?)
+        } else {
+            ClosureBody::Expr(Box::new(self.parse_expr()?))
+        };
+        Ok(ASTNode::Closure {
+            params,
+            return_type,
+            body,
+            by_value,
+        })
+    }
+
+    // Parses a parenthesised, comma separated argument list
+    fn parse_args(&mut self) -> Result<Vec<ASTNode>, String> {
+        self.expect(Token::OpenParen, "open paren")?;
+        let mut args = Vec::new();
+        while !self.eat(&Token::CloseParen) {
+            if !args.is_empty() {
+                self.expect(Token::Comma, "comma or close paren")?;
+            }
+            args.push(self.parse_nested_expr()?);
+        }
+        Ok(args)
+    }
+
+    // Parses `{ field: value, ... }` after a struct name
+    fn parse_struct_literal(&mut self, name: &str) -> Result<ASTNode, String> {
+        self.expect(Token::OpenBrace, "open brace after struct name")?;
+        let mut fields = Vec::new();
+        while !self.eat(&Token::CloseBrace) {
+            let field = self.expect_identifier("field name")?;
+            self.expect(Token::Colon, "colon after field name")?;
+            fields.push((field, self.parse_nested_expr()?));
+            if !self.eat(&Token::Comma) {
+                self.expect(Token::CloseBrace, "comma or close brace after field")?;
+                break;
+            }
+        }
+        Ok(ASTNode::StructLiteral {
+            name: name.to_string(),
+            fields,
+        })
+    }
+}
+
+// Whether `name` is a variant of `Option` or `Result`
+fn is_variant(name: &str) -> bool {
+    matches!(name, "Some" | "None" | "Ok" | "Err")
 }
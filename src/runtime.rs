@@ -0,0 +1,81 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+/// Bumped whenever the runtime's C interface or behaviour changes
+pub const VERSION: u32 = 8;
+
+const HEADER: &str = include_str!("runtime/nimra.h");
+
+/// The whole runtime of the assembly backend, which is small enough to link every time
+pub const ASSEMBLY: &str = include_str!("runtime/x86_64.s");
+
+/// The fields the WebAssembly backend splices into every module it generates
+pub const WASM: &str = include_str!("runtime/wasm.wat");
+
+/// A separately compiled piece of the runtime, included only when generated code uses it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Part {
+    Core,
+    Alloc,
+    Utf8,
+    String,
+}
+
+impl Part {
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Part::Core => "core.c",
+            Part::Alloc => "alloc.c",
+            Part::Utf8 => "utf8.c",
+            Part::String => "string.c",
+        }
+    }
+
+    pub fn source(self) -> &'static str {
+        match self {
+            Part::Core => include_str!("runtime/core.c"),
+            Part::Alloc => include_str!("runtime/alloc.c"),
+            Part::Utf8 => include_str!("runtime/utf8.c"),
+            Part::String => include_str!("runtime/string.c"),
+        }
+    }
+
+    fn dependencies(self) -> &'static [Part] {
+        match self {
+            Part::Core => &[],
+            Part::Alloc | Part::Utf8 => &[Part::Core],
+            Part::String => &[Part::Core, Part::Alloc, Part::Utf8],
+        }
+    }
+}
+
+/// The header every part and the generated code include, stamped with the runtime version
+pub fn header() -> String {
+    format!("#define NRT_VERSION {VERSION}\n{HEADER}")
+}
+
+/// Adds everything `parts` depend on, without duplicates and in a stable order
+pub fn resolve(parts: &[Part]) -> Vec<Part> {
+    let mut resolved: Vec<Part> = parts
+        .iter()
+        .flat_map(|part| part.dependencies().iter().chain([part]))
+        .copied()
+        .collect();
+    resolved.sort();
+    resolved.dedup();
+    resolved
+}
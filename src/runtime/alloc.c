@@ -0,0 +1,114 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+/* Memory for values whose size is only known at run time */
+
+#include <stdlib.h>
+
+#include "nimra.h"
+
+/*
+ * Every block starts with a link to the previously allocated one, so all
+ * blocks stay reachable from nrt_heap until the program exits. Nothing is
+ * freed before then: these values have no owner that could free them.
+ */
+typedef struct nrt_block {
+    struct nrt_block *next;
+    max_align_t align;
+} nrt_block;
+
+static nrt_block *nrt_heap;
+
+void *nrt_alloc(uint64_t size) {
+    nrt_block *block = malloc(offsetof(nrt_block, align) + (size_t)size);
+    if (block == NULL) {
+        nrt_location unknown = {NULL, 0, 0};
+        nrt_panic_message(unknown, "out of memory");
+    }
+    block->next = nrt_heap;
+    nrt_heap = block;
+    return &block->align;
+}
+
+/* Boxes have an owner, which frees them with nrt_box_free when it is dropped */
+void *nrt_box_new(uint64_t size) {
+    void *box = malloc((size_t)size);
+    if (box == NULL) {
+        nrt_location unknown = {NULL, 0, 0};
+        nrt_panic_message(unknown, "out of memory");
+    }
+    return box;
+}
+
+void nrt_box_free(void *box) {
+    free(box);
+}
+
+/*
+ * A buffer is a pointer to its first element, which follows a header with the
+ * number of elements and the room there is for them. The empty buffer is NULL,
+ * so making one allocates nothing. Like a box, a buffer has an owner, which
+ * frees it with nrt_buffer_free.
+ */
+typedef struct {
+    uint64_t len;
+    uint64_t capacity;
+    max_align_t items[];
+} nrt_buffer;
+
+static nrt_buffer *nrt_buffer_header(void *items) {
+    return (nrt_buffer *)((char *)items - offsetof(nrt_buffer, items));
+}
+
+uint64_t nrt_buffer_len(void *items) {
+    return items == NULL ? 0 : nrt_buffer_header(items)->len;
+}
+
+/* Adds an element of the given size to the end, and returns where the elements now are */
+void *nrt_buffer_push(void *items, uint64_t size) {
+    nrt_buffer *buffer = items == NULL ? NULL : nrt_buffer_header(items);
+    uint64_t len = buffer == NULL ? 0 : buffer->len;
+    uint64_t capacity = buffer == NULL ? 0 : buffer->capacity;
+    if (len == capacity) {
+        nrt_location unknown = {NULL, 0, 0};
+        capacity = capacity == 0 ? 4 : capacity * 2;
+        if (size != 0 && capacity > (SIZE_MAX - sizeof(nrt_buffer)) / size) {
+            nrt_panic_message(unknown, "capacity overflow");
+        }
+        buffer = realloc(buffer, sizeof(nrt_buffer) + (size_t)(capacity * size));
+        if (buffer == NULL) {
+            nrt_panic_message(unknown, "out of memory");
+        }
+        buffer->capacity = capacity;
+    }
+    buffer->len = len + 1;
+    return buffer->items;
+}
+
+/* Removes the last element, and returns its index */
+uint64_t nrt_buffer_pop(void *items, nrt_location at) {
+    if (nrt_buffer_len(items) == 0) {
+        nrt_panic_message(at, "pop from an empty buffer");
+    }
+    return --nrt_buffer_header(items)->len;
+}
+
+void nrt_buffer_free(void *items) {
+    if (items != NULL) {
+        free(nrt_buffer_header(items));
+    }
+}
@@ -0,0 +1,93 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+/* Pieces every other part of the runtime relies on */
+
+#include <inttypes.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "nimra.h"
+
+const uint32_t nrt_version = NRT_VERSION;
+
+/* The innermost frame; debug builds push one on entry to every function */
+nrt_frame *nrt_frame_top;
+
+void nrt_frame_leave(nrt_frame *frame) {
+    nrt_frame_top = frame->caller;
+}
+
+static void nrt_print_backtrace(void) {
+    if (nrt_frame_top == NULL) {
+        return;
+    }
+    const char *setting = getenv("NIMRA_BACKTRACE");
+    if (setting == NULL || strcmp(setting, "0") == 0) {
+        fputs("note: run with `NIMRA_BACKTRACE=1` to display a backtrace\n", stderr);
+        return;
+    }
+    fputs("stack backtrace:\n", stderr);
+    unsigned depth = 0;
+    for (nrt_frame *frame = nrt_frame_top; frame != NULL; frame = frame->caller) {
+        fprintf(stderr, "%4u: %s\n", depth++, frame->function);
+    }
+}
+
+void nrt_panic(nrt_location at, nrt_str message) {
+    fflush(stdout);
+    if (at.file == NULL) {
+        fputs("panicked: ", stderr);
+    } else {
+        fprintf(stderr, "panicked at %s:%" PRIu32 ":%" PRIu32 ": ", at.file, at.line, at.column);
+    }
+    fprintf(stderr, "%.*s\n", (int)message.len, message.ptr);
+    nrt_print_backtrace();
+    exit(101);
+}
+
+void nrt_panic_message(nrt_location at, const char *message) {
+    nrt_str str = {message, strlen(message)};
+    nrt_panic(at, str);
+}
+
+uint64_t nrt_check_index(uint64_t index, uint64_t len, nrt_location at) {
+    if (index >= len) {
+        char message[96];
+        snprintf(message, sizeof message,
+                 "index out of bounds: the len is %" PRIu64 " but the index is %" PRIu64, len, index);
+        nrt_panic_message(at, message);
+    }
+    return index;
+}
+
+/* The finalizer of SplitMix64, which spreads every input bit over the whole result */
+uint64_t nrt_hash_u64(uint64_t value) {
+    value = (value ^ (value >> 30)) * UINT64_C(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)) * UINT64_C(0x94d049bb133111eb);
+    return value ^ (value >> 31);
+}
+
+/* 64-bit FNV-1a */
+uint64_t nrt_hash_str(nrt_str s) {
+    uint64_t hash = UINT64_C(0xcbf29ce484222325);
+    for (uint64_t i = 0; i < s.len; i++) {
+        hash = (hash ^ (uint8_t)s.ptr[i]) * UINT64_C(0x100000001b3);
+    }
+    return hash;
+}
@@ -0,0 +1,218 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+/*
+ * Types and functions the generated C code calls into. The compiler
+ * prepends `#define NRT_VERSION <n>` when it writes this header out.
+ */
+
+#ifndef NIMRA_H
+#define NIMRA_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/* Immutable view of UTF-8 bytes */
+typedef struct {
+    const char *ptr;
+    uint64_t len;
+} nrt_str;
+
+/* Owned, growable UTF-8 buffer */
+typedef struct {
+    char *ptr;
+    uint64_t len;
+    uint64_t cap;
+} nrt_string;
+
+/* Where in the Nimra source a panic comes from; file is NULL if unknown */
+typedef struct {
+    const char *file;
+    uint32_t line;
+    uint32_t column;
+} nrt_location;
+
+/* One entry of the call stack kept by debug builds for backtraces */
+typedef struct nrt_frame {
+    const char *function;
+    struct nrt_frame *caller;
+} nrt_frame;
+
+/* A character encoded as NUL-terminated UTF-8, which is len bytes long
+   since the character itself may be NUL */
+typedef struct {
+    char bytes[5];
+    uint8_t len;
+} nrt_utf8;
+
+/* core.c */
+extern const uint32_t nrt_version;
+extern nrt_frame *nrt_frame_top;
+void nrt_frame_leave(nrt_frame *frame);
+[[gnu::noreturn]] void nrt_panic(nrt_location at, nrt_str message);
+[[gnu::noreturn]] void nrt_panic_message(nrt_location at, const char *message);
+uint64_t nrt_check_index(uint64_t index, uint64_t len, nrt_location at);
+uint64_t nrt_hash_u64(uint64_t value);
+uint64_t nrt_hash_str(nrt_str s);
+
+/* alloc.c */
+void *nrt_alloc(uint64_t size);
+void *nrt_box_new(uint64_t size);
+void nrt_box_free(void *box);
+uint64_t nrt_buffer_len(void *items);
+void *nrt_buffer_push(void *items, uint64_t size);
+uint64_t nrt_buffer_pop(void *items, nrt_location at);
+void nrt_buffer_free(void *items);
+
+/* utf8.c */
+nrt_utf8 nrt_char_encode(uint32_t c);
+uint32_t nrt_str_next_char(nrt_str s, uint64_t *pos);
+
+/* string.c */
+
+nrt_string nrt_string_new(void);
+nrt_string nrt_string_from(nrt_str s);
+nrt_str nrt_string_as_str(nrt_string s);
+void nrt_string_push_str(nrt_string *s, nrt_str other);
+void nrt_string_push_char(nrt_string *s, uint32_t c);
+void nrt_string_free(nrt_string *s);
+
+nrt_str nrt_str_slice(nrt_str s, uint64_t start, uint64_t end, nrt_location at);
+nrt_string nrt_str_concat(nrt_str a, nrt_str b);
+bool nrt_str_equal(nrt_str a, nrt_str b);
+int32_t nrt_str_compare(nrt_str a, nrt_str b);
+int64_t nrt_str_parse_i64(nrt_str s, nrt_location at);
+
+nrt_string nrt_i64_to_string(int64_t n);
+nrt_string nrt_u64_to_string(uint64_t n);
+nrt_string nrt_char_to_string(uint32_t c);
+
+/*
+ * Integer arithmetic with defined overflow behaviour. Plain C arithmetic on
+ * signed integers is undefined on overflow, so everything goes through the
+ * overflow builtins, which compute the wrapped result and report overflow.
+ *
+ * nrt_<op>_<type>            panics on overflow or division by zero
+ * nrt_overflows_<op>_<type>  says whether it would overflow or divide by zero
+ * nrt_wrapping_<op>_<type>   wraps around on overflow
+ * nrt_saturating_<op>_<type> clamps to the bounds of the type on overflow
+ */
+#define NRT_OVERFLOWING_OP(name, type, op, min, max, saturated)                             \
+    static inline type nrt_##op##_##name(type a, type b, nrt_location at) {                 \
+        type result;                                                                        \
+        if (__builtin_##op##_overflow(a, b, &result)) {                                     \
+            nrt_panic_message(at, NRT_OVERFLOW_MESSAGE_##op);                               \
+        }                                                                                   \
+        return result;                                                                      \
+    }                                                                                       \
+    static inline bool nrt_overflows_##op##_##name(type a, type b) {                        \
+        type result;                                                                        \
+        return __builtin_##op##_overflow(a, b, &result);                                    \
+    }                                                                                       \
+    static inline type nrt_wrapping_##op##_##name(type a, type b) {                         \
+        type result;                                                                        \
+        __builtin_##op##_overflow(a, b, &result);                                           \
+        return result;                                                                      \
+    }                                                                                       \
+    static inline type nrt_saturating_##op##_##name(type a, type b) {                       \
+        type result;                                                                        \
+        if (__builtin_##op##_overflow(a, b, &result)) {                                     \
+            return (saturated) ? (max) : (min);                                             \
+        }                                                                                   \
+        return result;                                                                      \
+    }
+
+#define NRT_OVERFLOW_MESSAGE_add "attempt to add with overflow"
+#define NRT_OVERFLOW_MESSAGE_sub "attempt to subtract with overflow"
+#define NRT_OVERFLOW_MESSAGE_mul "attempt to multiply with overflow"
+
+/* Overflow only goes past max when adding a positive number, and so on */
+#define NRT_INTEGER_OPS(name, type, min, max)                                               \
+    NRT_OVERFLOWING_OP(name, type, add, min, max, b > 0)                                    \
+    NRT_OVERFLOWING_OP(name, type, sub, min, max, !(b > 0))                                 \
+    NRT_OVERFLOWING_OP(name, type, mul, min, max, (a > 0) == (b > 0))
+
+#define NRT_SIGNED_OPS(name, type, min, max)                                                \
+    NRT_INTEGER_OPS(name, type, min, max)                                                   \
+    static inline type nrt_div_##name(type a, type b, nrt_location at) {                    \
+        if (b == 0) {                                                                       \
+            nrt_panic_message(at, "attempt to divide by zero");                             \
+        }                                                                                   \
+        if (a == (min) && b == -1) {                                                        \
+            nrt_panic_message(at, "attempt to divide with overflow");                       \
+        }                                                                                   \
+        return (type)(a / b);                                                               \
+    }                                                                                       \
+    static inline type nrt_rem_##name(type a, type b, nrt_location at) {                    \
+        if (b == 0) {                                                                       \
+            nrt_panic_message(at, "attempt to calculate the remainder with a divisor of zero"); \
+        }                                                                                   \
+        if (a == (min) && b == -1) {                                                        \
+            nrt_panic_message(at, "attempt to calculate the remainder with overflow");      \
+        }                                                                                   \
+        return (type)(a % b);                                                               \
+    }                                                                                       \
+    static inline bool nrt_overflows_div_##name(type a, type b) {                           \
+        return b == 0 || (a == (min) && b == -1);                                           \
+    }                                                                                       \
+    static inline bool nrt_overflows_rem_##name(type a, type b) {                           \
+        return b == 0 || (a == (min) && b == -1);                                           \
+    }                                                                                       \
+    static inline type nrt_neg_##name(type a, nrt_location at) {                            \
+        if (a == (min)) {                                                                   \
+            nrt_panic_message(at, "attempt to negate with overflow");                       \
+        }                                                                                   \
+        return nrt_wrapping_sub_##name(0, a);                                               \
+    }                                                                                       \
+    static inline type nrt_wrapping_neg_##name(type a) {                                    \
+        return nrt_wrapping_sub_##name(0, a);                                               \
+    }
+
+#define NRT_UNSIGNED_OPS(name, type, max)                                                   \
+    NRT_INTEGER_OPS(name, type, 0, max)                                                     \
+    static inline type nrt_div_##name(type a, type b, nrt_location at) {                    \
+        if (b == 0) {                                                                       \
+            nrt_panic_message(at, "attempt to divide by zero");                             \
+        }                                                                                   \
+        return (type)(a / b);                                                               \
+    }                                                                                       \
+    static inline type nrt_rem_##name(type a, type b, nrt_location at) {                    \
+        if (b == 0) {                                                                       \
+            nrt_panic_message(at, "attempt to calculate the remainder with a divisor of zero"); \
+        }                                                                                   \
+        return (type)(a % b);                                                               \
+    }                                                                                       \
+    static inline bool nrt_overflows_div_##name(type a, type b) {                           \
+        (void)a;                                                                            \
+        return b == 0;                                                                      \
+    }                                                                                       \
+    static inline bool nrt_overflows_rem_##name(type a, type b) {                           \
+        (void)a;                                                                            \
+        return b == 0;                                                                      \
+    }
+
+NRT_SIGNED_OPS(i8, int8_t, INT8_MIN, INT8_MAX)
+NRT_SIGNED_OPS(i16, int16_t, INT16_MIN, INT16_MAX)
+NRT_SIGNED_OPS(i32, int32_t, INT32_MIN, INT32_MAX)
+NRT_SIGNED_OPS(i64, int64_t, INT64_MIN, INT64_MAX)
+NRT_UNSIGNED_OPS(u8, uint8_t, UINT8_MAX)
+NRT_UNSIGNED_OPS(u16, uint16_t, UINT16_MAX)
+NRT_UNSIGNED_OPS(u32, uint32_t, UINT32_MAX)
+NRT_UNSIGNED_OPS(u64, uint64_t, UINT64_MAX)
+
+#endif
@@ -0,0 +1,165 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+/* String support for the str and String types */
+
+#include <inttypes.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "nimra.h"
+
+nrt_string nrt_string_new(void) {
+    nrt_string s = {NULL, 0, 0};
+    return s;
+}
+
+nrt_string nrt_string_from(nrt_str s) {
+    nrt_string string = nrt_string_new();
+    nrt_string_push_str(&string, s);
+    return string;
+}
+
+nrt_str nrt_string_as_str(nrt_string s) {
+    nrt_str str = {s.ptr, s.len};
+    return str;
+}
+
+static void nrt_string_reserve(nrt_string *s, uint64_t extra) {
+    if (s->len + extra <= s->cap) {
+        return;
+    }
+    uint64_t cap = s->cap == 0 ? 16 : s->cap * 2;
+    while (cap < s->len + extra) {
+        cap *= 2;
+    }
+    char *ptr = realloc(s->ptr, (size_t)cap);
+    if (ptr == NULL) {
+        nrt_location unknown = {NULL, 0, 0};
+        nrt_panic_message(unknown, "out of memory");
+    }
+    s->ptr = ptr;
+    s->cap = cap;
+}
+
+/* A string has an owner, which frees it with nrt_string_free when it is dropped */
+void nrt_string_free(nrt_string *s) {
+    free(s->ptr);
+}
+
+void nrt_string_push_str(nrt_string *s, nrt_str other) {
+    if (other.len == 0) {
+        return;
+    }
+    nrt_string_reserve(s, other.len);
+    memcpy(s->ptr + s->len, other.ptr, (size_t)other.len);
+    s->len += other.len;
+}
+
+void nrt_string_push_char(nrt_string *s, uint32_t c) {
+    nrt_utf8 utf8 = nrt_char_encode(c);
+    nrt_str encoded = {utf8.bytes, utf8.len};
+    nrt_string_push_str(s, encoded);
+}
+
+static bool nrt_is_char_boundary(nrt_str s, uint64_t index) {
+    return index == s.len || (index < s.len && ((unsigned char)s.ptr[index] & 0xC0) != 0x80);
+}
+
+nrt_str nrt_str_slice(nrt_str s, uint64_t start, uint64_t end, nrt_location at) {
+    if (start > end || end > s.len) {
+        nrt_panic_message(at, "string slice out of bounds");
+    }
+    if (!nrt_is_char_boundary(s, start) || !nrt_is_char_boundary(s, end)) {
+        nrt_panic_message(at, "string slice is not on a char boundary");
+    }
+    nrt_str slice = {s.ptr + start, end - start};
+    return slice;
+}
+
+nrt_string nrt_str_concat(nrt_str a, nrt_str b) {
+    nrt_string s = nrt_string_new();
+    nrt_string_reserve(&s, a.len + b.len);
+    nrt_string_push_str(&s, a);
+    nrt_string_push_str(&s, b);
+    return s;
+}
+
+bool nrt_str_equal(nrt_str a, nrt_str b) {
+    return a.len == b.len && (a.len == 0 || memcmp(a.ptr, b.ptr, (size_t)a.len) == 0);
+}
+
+int32_t nrt_str_compare(nrt_str a, nrt_str b) {
+    uint64_t shorter = a.len < b.len ? a.len : b.len;
+    int order = shorter == 0 ? 0 : memcmp(a.ptr, b.ptr, (size_t)shorter);
+    if (order != 0) {
+        return order < 0 ? -1 : 1;
+    }
+    return a.len == b.len ? 0 : (a.len < b.len ? -1 : 1);
+}
+
+int64_t nrt_str_parse_i64(nrt_str s, nrt_location at) {
+    uint64_t i = 0;
+    bool negative = s.len > 0 && (s.ptr[0] == '-' || s.ptr[0] == '+');
+    if (negative) {
+        negative = s.ptr[0] == '-';
+        i = 1;
+    }
+    if (i == s.len) {
+        nrt_panic_message(at, "cannot parse an empty string as an integer");
+    }
+    /* Accumulate negatively so INT64_MIN parses without overflow */
+    int64_t value = 0;
+    for (; i < s.len; i++) {
+        if (s.ptr[i] < '0' || s.ptr[i] > '9') {
+            nrt_panic_message(at, "invalid digit in integer");
+        }
+        int64_t digit = s.ptr[i] - '0';
+        if (value < (INT64_MIN + digit) / 10) {
+            nrt_panic_message(at, "integer does not fit in i64");
+        }
+        value = value * 10 - digit;
+    }
+    if (!negative) {
+        if (value == INT64_MIN) {
+            nrt_panic_message(at, "integer does not fit in i64");
+        }
+        value = -value;
+    }
+    return value;
+}
+
+nrt_string nrt_i64_to_string(int64_t n) {
+    char buffer[24];
+    int len = snprintf(buffer, sizeof buffer, "%" PRId64, n);
+    nrt_str digits = {buffer, (uint64_t)len};
+    return nrt_string_from(digits);
+}
+
+nrt_string nrt_u64_to_string(uint64_t n) {
+    char buffer[24];
+    int len = snprintf(buffer, sizeof buffer, "%" PRIu64, n);
+    nrt_str digits = {buffer, (uint64_t)len};
+    return nrt_string_from(digits);
+}
+
+nrt_string nrt_char_to_string(uint32_t c) {
+    nrt_string s = nrt_string_new();
+    nrt_string_push_char(&s, c);
+    return s;
+}
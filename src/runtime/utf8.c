@@ -0,0 +1,65 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+/* Encoding and decoding of chars */
+
+#include "nimra.h"
+
+nrt_utf8 nrt_char_encode(uint32_t c) {
+    nrt_utf8 utf8 = {{0}, 0};
+    if (c < 0x80) {
+        utf8.bytes[0] = (char)c;
+        utf8.len = 1;
+    } else if (c < 0x800) {
+        utf8.bytes[0] = (char)(0xC0 | c >> 6);
+        utf8.bytes[1] = (char)(0x80 | (c & 0x3F));
+        utf8.len = 2;
+    } else if (c < 0x10000) {
+        utf8.bytes[0] = (char)(0xE0 | c >> 12);
+        utf8.bytes[1] = (char)(0x80 | (c >> 6 & 0x3F));
+        utf8.bytes[2] = (char)(0x80 | (c & 0x3F));
+        utf8.len = 3;
+    } else {
+        utf8.bytes[0] = (char)(0xF0 | c >> 18);
+        utf8.bytes[1] = (char)(0x80 | (c >> 12 & 0x3F));
+        utf8.bytes[2] = (char)(0x80 | (c >> 6 & 0x3F));
+        utf8.bytes[3] = (char)(0x80 | (c & 0x3F));
+        utf8.len = 4;
+    }
+    return utf8;
+}
+
+/* Decodes the character starting at *pos and advances *pos past it */
+uint32_t nrt_str_next_char(nrt_str s, uint64_t *pos) {
+    const unsigned char *bytes = (const unsigned char *)s.ptr + *pos;
+    uint64_t remaining = s.len - *pos;
+    uint32_t c = bytes[0];
+    uint64_t width = 1;
+    if (c >= 0xF0 && remaining >= 4) {
+        c = (c & 0x07u) << 18 | (bytes[1] & 0x3Fu) << 12 | (bytes[2] & 0x3Fu) << 6 |
+            (bytes[3] & 0x3Fu);
+        width = 4;
+    } else if (c >= 0xE0 && remaining >= 3) {
+        c = (c & 0x0Fu) << 12 | (bytes[1] & 0x3Fu) << 6 | (bytes[2] & 0x3Fu);
+        width = 3;
+    } else if (c >= 0xC0 && remaining >= 2) {
+        c = (c & 0x1Fu) << 6 | (bytes[1] & 0x3Fu);
+        width = 2;
+    }
+    *pos += width;
+    return c;
+}
@@ -0,0 +1,1555 @@
+;; Copyright (C) 2025 Vihaan Krishnan
+;;
+;; This program is free software: you can redistribute it and/or modify
+;; it under the terms of the GNU Affero General Public License as published by
+;; the Free Software Foundation, either version 3 of the License, or
+;; (at your option) any later version.
+;;
+;; This program is distributed in the hope that it will be useful,
+;; but WITHOUT ANY WARRANTY; without even the implied warranty of
+;; MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+;; GNU Affero General Public License for more details.
+;;
+;; You should have received a copy of the GNU Affero General Public License
+;; along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+
+;; The runtime of the WebAssembly backend, which is spliced into every module
+;; it generates: output goes through WASI's fd_write, memory comes from
+;; memory.grow, and the program starts at _start, which calls the generated
+;; nrt_main. Every function behaves like the C runtime's function of the same
+;; name, except that a str is passed as its pointer and length, a String is
+;; returned through the address it goes to, and a location is passed by
+;; address, as a file name (or 0 if unknown) followed by a 32-bit line and
+;; column.
+;;
+;; Memory starts with the stack, which grows down from 0x800000 so that
+;; overflowing it traps. The runtime's data follows, then the generated
+;; module's from 0x802000, and then the heap, which the generated module
+;; starts with $nrt_heap_next and $nrt_heap_end.
+;;
+;;   0x800000  messages, 64 bytes apart
+;;   0x800800  the blocks freed, by size class
+;;   0x800900  scratch space for system calls
+;;   0x800920  digits of a number being formatted, which end at 0x800940
+;;   0x800940  a char being encoded
+;;   0x800A00  a location that is unknown
+;;   0x801000  standard output, which is buffered until it fills up or the
+;;             program ends
+
+(import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+(import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+(import "wasi_snapshot_preview1" "environ_sizes_get" (func $environ_sizes_get (param i32 i32) (result i32)))
+(import "wasi_snapshot_preview1" "environ_get" (func $environ_get (param i32 i32) (result i32)))
+
+;; The innermost frame; debug builds push one on entry to every function
+(global $nrt_frame_top (mut i32) (i32.const 0))
+(global $nrt_out_len (mut i32) (i32.const 0))
+
+(data (i32.const 0x800000) "panicked at ")
+(data (i32.const 0x800040) "panicked: ")
+(data (i32.const 0x800080) ": \n")
+(data (i32.const 0x8000C0) "true")
+(data (i32.const 0x800100) "false")
+(data (i32.const 0x800140) "note: run with `NIMRA_BACKTRACE=1` to display a backtrace\n")
+(data (i32.const 0x800180) "stack backtrace:\n")
+(data (i32.const 0x8001C0) "NIMRA_BACKTRACE=")
+(data (i32.const 0x800200) "index out of bounds: the len is ")
+(data (i32.const 0x800240) " but the index is ")
+(data (i32.const 0x800280) "out of memory")
+(data (i32.const 0x8002C0) "capacity overflow")
+(data (i32.const 0x800300) "pop from an empty buffer")
+(data (i32.const 0x800340) "string slice out of bounds")
+(data (i32.const 0x800380) "string slice is not on a char boundary")
+(data (i32.const 0x8003C0) "cannot parse an empty string as an integer")
+(data (i32.const 0x800400) "invalid digit in integer")
+(data (i32.const 0x800440) "integer does not fit in i64")
+
+;; void nrt_exit(int32_t code), which flushes standard output first
+(func $nrt_exit (param $code i32)
+  call $nrt_flush
+  local.get $code
+  call $proc_exit
+  unreachable
+)
+
+;; Writes all of len bytes at ptr to fd, giving up if the system call fails
+(func $nrt_write_all (param $fd i32) (param $ptr i32) (param $len i32)
+  (local $written i32)
+  block $done
+    loop $more
+      local.get $len
+      i32.eqz
+      br_if $done
+      i32.const 0x800900
+      local.get $ptr
+      i32.store
+      i32.const 0x800904
+      local.get $len
+      i32.store
+      local.get $fd
+      i32.const 0x800900
+      i32.const 1
+      i32.const 0x800908
+      call $fd_write
+      br_if $done
+      i32.const 0x800908
+      i32.load
+      local.tee $written
+      i32.eqz
+      br_if $done
+      local.get $ptr
+      local.get $written
+      i32.add
+      local.set $ptr
+      local.get $len
+      local.get $written
+      i32.sub
+      local.set $len
+      br $more
+    end
+  end
+)
+
+(func $nrt_flush
+  i32.const 1
+  i32.const 0x801000
+  global.get $nrt_out_len
+  call $nrt_write_all
+  i32.const 0
+  global.set $nrt_out_len
+)
+
+;; void nrt_write(int32_t fd, const char *ptr, uint32_t len)
+(func $nrt_write (param $fd i32) (param $ptr i32) (param $len i32)
+  local.get $fd
+  i32.const 1
+  i32.ne
+  if
+    local.get $fd
+    local.get $ptr
+    local.get $len
+    call $nrt_write_all
+    return
+  end
+  global.get $nrt_out_len
+  local.get $len
+  i32.add
+  i32.const 4096
+  i32.gt_u
+  if
+    call $nrt_flush
+    local.get $len
+    i32.const 4096
+    i32.ge_u
+    if
+      i32.const 1
+      local.get $ptr
+      local.get $len
+      call $nrt_write_all
+      return
+    end
+  end
+  global.get $nrt_out_len
+  i32.const 0x801000
+  i32.add
+  local.get $ptr
+  local.get $len
+  memory.copy
+  global.get $nrt_out_len
+  local.get $len
+  i32.add
+  global.set $nrt_out_len
+)
+
+;; Writes the digits of n so they end at end, and returns where they start
+(func $nrt_format_u64 (param $n i64) (param $end i32) (result i32)
+  loop $digit
+    local.get $end
+    i32.const 1
+    i32.sub
+    local.tee $end
+    local.get $n
+    i64.const 10
+    i64.rem_u
+    i32.wrap_i64
+    i32.const 48
+    i32.add
+    i32.store8
+    local.get $n
+    i64.const 10
+    i64.div_u
+    local.tee $n
+    i64.eqz
+    i32.eqz
+    br_if $digit
+  end
+  local.get $end
+)
+
+;; nrt_format_u64 for a signed number
+(func $nrt_format_i64 (param $n i64) (param $end i32) (result i32)
+  (local $start i32)
+  local.get $n
+  i64.const 0
+  i64.lt_s
+  if (result i32)
+    i64.const 0
+    local.get $n
+    i64.sub
+    local.get $end
+    call $nrt_format_u64
+    i32.const 1
+    i32.sub
+    local.tee $start
+    i32.const 45
+    i32.store8
+    local.get $start
+  else
+    local.get $n
+    local.get $end
+    call $nrt_format_u64
+  end
+)
+
+;; void nrt_print_u64(int32_t fd, uint64_t n)
+(func $nrt_print_u64 (param $fd i32) (param $n i64)
+  (local $start i32)
+  local.get $fd
+  local.get $n
+  i32.const 0x800940
+  call $nrt_format_u64
+  local.tee $start
+  i32.const 0x800940
+  local.get $start
+  i32.sub
+  call $nrt_write
+)
+
+;; void nrt_print_i64(int32_t fd, int64_t n)
+(func $nrt_print_i64 (param $fd i32) (param $n i64)
+  (local $start i32)
+  local.get $fd
+  local.get $n
+  i32.const 0x800940
+  call $nrt_format_i64
+  local.tee $start
+  i32.const 0x800940
+  local.get $start
+  i32.sub
+  call $nrt_write
+)
+
+;; void nrt_print_bool(int32_t fd, bool b)
+(func $nrt_print_bool (param $fd i32) (param $b i32)
+  local.get $fd
+  i32.const 0x8000C0
+  i32.const 0x800100
+  local.get $b
+  select
+  i32.const 4
+  i32.const 5
+  local.get $b
+  select
+  call $nrt_write
+)
+
+;; Encodes the char c as UTF-8 at out, and returns how many bytes it took
+(func $nrt_char_encode (param $c i32) (param $out i32) (result i32)
+  (local $len i32)
+  local.get $c
+  i32.const 0x80
+  i32.lt_u
+  if
+    local.get $out
+    local.get $c
+    i32.store8
+    i32.const 1
+    return
+  end
+  local.get $c
+  i32.const 0x800
+  i32.lt_u
+  if
+    local.get $out
+    local.get $c
+    i32.const 6
+    i32.shr_u
+    i32.const 0xC0
+    i32.or
+    i32.store8
+    i32.const 2
+    local.set $len
+  else
+    local.get $c
+    i32.const 0x10000
+    i32.lt_u
+    if
+      local.get $out
+      local.get $c
+      i32.const 12
+      i32.shr_u
+      i32.const 0xE0
+      i32.or
+      i32.store8
+      i32.const 3
+      local.set $len
+    else
+      local.get $out
+      local.get $c
+      i32.const 18
+      i32.shr_u
+      i32.const 0xF0
+      i32.or
+      i32.store8
+      local.get $out
+      local.get $c
+      i32.const 12
+      i32.shr_u
+      i32.const 0x3F
+      i32.and
+      i32.const 0x80
+      i32.or
+      i32.store8 offset=1
+      i32.const 4
+      local.set $len
+    end
+    ;; The second last byte of a longer encoding holds the next six bits
+    local.get $out
+    local.get $len
+    i32.add
+    i32.const 2
+    i32.sub
+    local.get $c
+    i32.const 6
+    i32.shr_u
+    i32.const 0x3F
+    i32.and
+    i32.const 0x80
+    i32.or
+    i32.store8
+  end
+  ;; The last byte of every multi-byte encoding holds the low six bits
+  local.get $out
+  local.get $len
+  i32.add
+  i32.const 1
+  i32.sub
+  local.get $c
+  i32.const 0x3F
+  i32.and
+  i32.const 0x80
+  i32.or
+  i32.store8
+  local.get $len
+)
+
+;; void nrt_print_char(int32_t fd, uint32_t c)
+(func $nrt_print_char (param $fd i32) (param $c i32)
+  local.get $fd
+  i32.const 0x800940
+  local.get $c
+  i32.const 0x800940
+  call $nrt_char_encode
+  call $nrt_write
+)
+
+;; uint32_t nrt_strlen(const char *s)
+(func $nrt_strlen (param $s i32) (result i32)
+  (local $end i32)
+  local.get $s
+  local.set $end
+  block $done
+    loop $more
+      local.get $end
+      i32.load8_u
+      i32.eqz
+      br_if $done
+      local.get $end
+      i32.const 1
+      i32.add
+      local.set $end
+      br $more
+    end
+  end
+  local.get $end
+  local.get $s
+  i32.sub
+)
+
+;; Writes a NUL-terminated string to standard error
+(func $nrt_write_name (param $name i32)
+  i32.const 2
+  local.get $name
+  local.get $name
+  call $nrt_strlen
+  call $nrt_write
+)
+
+;; Flushes standard output and starts a panic message at a location
+(func $nrt_panic_begin (param $at i32)
+  call $nrt_flush
+  local.get $at
+  i32.load
+  i32.eqz
+  if
+    i32.const 2
+    i32.const 0x800040
+    i32.const 10
+    call $nrt_write
+    return
+  end
+  i32.const 2
+  i32.const 0x800000
+  i32.const 12
+  call $nrt_write
+  local.get $at
+  i32.load
+  call $nrt_write_name
+  i32.const 2
+  i32.const 0x800080
+  i32.const 1
+  call $nrt_write
+  i32.const 2
+  local.get $at
+  i64.load32_u offset=4
+  call $nrt_print_u64
+  i32.const 2
+  i32.const 0x800080
+  i32.const 1
+  call $nrt_write
+  i32.const 2
+  local.get $at
+  i64.load32_u offset=8
+  call $nrt_print_u64
+  i32.const 2
+  i32.const 0x800080
+  i32.const 2
+  call $nrt_write
+)
+
+;; Ends a panic message, prints the backtrace and exits
+(func $nrt_panic_end
+  i32.const 2
+  i32.const 0x800082
+  i32.const 1
+  call $nrt_write
+  call $nrt_print_backtrace
+  i32.const 101
+  call $proc_exit
+  unreachable
+)
+
+;; void nrt_panic(const nrt_location *at, const char *ptr, uint32_t len)
+(func $nrt_panic (param $at i32) (param $ptr i32) (param $len i32)
+  local.get $at
+  call $nrt_panic_begin
+  i32.const 2
+  local.get $ptr
+  local.get $len
+  call $nrt_write
+  call $nrt_panic_end
+)
+
+;; Whether NIMRA_BACKTRACE is set to anything but 0
+(func $nrt_backtrace_enabled (result i32)
+  (local $count i32)
+  (local $pointers i32)
+  (local $variable i32)
+  (local $i i32)
+  i32.const 0x800910
+  i32.const 0x800914
+  call $environ_sizes_get
+  if
+    i32.const 0
+    return
+  end
+  i32.const 0x800910
+  i32.load
+  local.tee $count
+  i32.const 4
+  i32.mul
+  i32.const 0x800914
+  i32.load
+  i32.add
+  call $nrt_alloc
+  local.tee $pointers
+  local.get $pointers
+  local.get $count
+  i32.const 4
+  i32.mul
+  i32.add
+  call $environ_get
+  if
+    i32.const 0
+    return
+  end
+  block $missing
+    loop $next
+      local.get $count
+      i32.eqz
+      br_if $missing
+      local.get $count
+      i32.const 1
+      i32.sub
+      local.set $count
+      local.get $pointers
+      local.get $count
+      i32.const 4
+      i32.mul
+      i32.add
+      i32.load
+      local.set $variable
+      i32.const 0
+      local.set $i
+      block $different
+        loop $byte
+          local.get $i
+          i32.const 16
+          i32.eq
+          if
+            ;; Found it, so its value follows
+            local.get $variable
+            i32.load8_u offset=16
+            i32.const 48
+            i32.ne
+            local.get $variable
+            i32.load8_u offset=17
+            i32.const 0
+            i32.ne
+            i32.or
+            return
+          end
+          local.get $variable
+          local.get $i
+          i32.add
+          i32.load8_u
+          local.get $i
+          i32.load8_u offset=0x8001C0
+          i32.ne
+          br_if $different
+          local.get $i
+          i32.const 1
+          i32.add
+          local.set $i
+          br $byte
+        end
+      end
+      br $next
+    end
+  end
+  i32.const 0
+)
+
+(func $nrt_print_backtrace
+  (local $frame i32)
+  (local $depth i64)
+  (local $start i32)
+  global.get $nrt_frame_top
+  local.tee $frame
+  i32.eqz
+  if
+    return
+  end
+  call $nrt_backtrace_enabled
+  i32.eqz
+  if
+    i32.const 2
+    i32.const 0x800140
+    i32.const 58
+    call $nrt_write
+    return
+  end
+  i32.const 2
+  i32.const 0x800180
+  i32.const 17
+  call $nrt_write
+  ;; Each frame is printed as its depth, right-aligned in four columns, and its function
+  loop $frames
+    local.get $depth
+    i32.const 0x800940
+    call $nrt_format_u64
+    local.set $start
+    block $aligned
+      loop $pad
+        local.get $start
+        i32.const 0x80093C
+        i32.le_u
+        br_if $aligned
+        local.get $start
+        i32.const 1
+        i32.sub
+        local.tee $start
+        i32.const 32
+        i32.store8
+        br $pad
+      end
+    end
+    i32.const 2
+    local.get $start
+    i32.const 0x800940
+    local.get $start
+    i32.sub
+    call $nrt_write
+    i32.const 2
+    i32.const 0x800080
+    i32.const 2
+    call $nrt_write
+    local.get $frame
+    i32.load
+    call $nrt_write_name
+    i32.const 2
+    i32.const 0x800082
+    i32.const 1
+    call $nrt_write
+    local.get $depth
+    i64.const 1
+    i64.add
+    local.set $depth
+    local.get $frame
+    i32.load offset=4
+    local.tee $frame
+    br_if $frames
+  end
+)
+
+;; uint32_t nrt_check_index(uint64_t index, uint64_t len, const nrt_location *at)
+(func $nrt_check_index (param $index i64) (param $len i64) (param $at i32) (result i32)
+  local.get $index
+  local.get $len
+  i64.lt_u
+  if
+    local.get $index
+    i32.wrap_i64
+    return
+  end
+  local.get $at
+  call $nrt_panic_begin
+  i32.const 2
+  i32.const 0x800200
+  i32.const 32
+  call $nrt_write
+  i32.const 2
+  local.get $len
+  call $nrt_print_u64
+  i32.const 2
+  i32.const 0x800240
+  i32.const 18
+  call $nrt_write
+  i32.const 2
+  local.get $index
+  call $nrt_print_u64
+  call $nrt_panic_end
+  unreachable
+)
+
+;; The finalizer of SplitMix64, which spreads every input bit over the whole result
+(func $nrt_hash_u64 (param $x i64) (result i64)
+  local.get $x
+  local.get $x
+  i64.const 30
+  i64.shr_u
+  i64.xor
+  i64.const 0xbf58476d1ce4e5b9
+  i64.mul
+  local.tee $x
+  local.get $x
+  i64.const 27
+  i64.shr_u
+  i64.xor
+  i64.const 0x94d049bb133111eb
+  i64.mul
+  local.tee $x
+  local.get $x
+  i64.const 31
+  i64.shr_u
+  i64.xor
+)
+
+;; 64-bit FNV-1a
+(func $nrt_hash_str (param $ptr i32) (param $len i32) (result i64)
+  (local $hash i64)
+  i64.const 0xcbf29ce484222325
+  local.set $hash
+  block $done
+    loop $byte
+      local.get $len
+      i32.eqz
+      br_if $done
+      local.get $hash
+      local.get $ptr
+      i64.load8_u
+      i64.xor
+      i64.const 0x100000001b3
+      i64.mul
+      local.set $hash
+      local.get $ptr
+      i32.const 1
+      i32.add
+      local.set $ptr
+      local.get $len
+      i32.const 1
+      i32.sub
+      local.set $len
+      br $byte
+    end
+  end
+  local.get $hash
+)
+
+;; void *nrt_alloc(uint32_t size)
+;;
+;; Blocks come in powers of two from 16 bytes, each starting with an 8-byte
+;; header holding its size class, so the memory after it is 8-byte aligned.
+;; A freed block goes on the list for its class, linked through the second
+;; word of its header, and is handed out again before any new memory.
+(func $nrt_alloc (param $size i32) (result i32)
+  (local $class i32)
+  (local $block i32)
+  (local $list i32)
+  (local $grow i32)
+  local.get $size
+  i32.const 0x3FFFFFF8
+  i32.gt_u
+  if
+    i32.const 0x800A00
+    i32.const 0x800280
+    i32.const 13
+    call $nrt_panic
+  end
+  i32.const 32
+  local.get $size
+  i32.const 7
+  i32.add
+  i32.clz
+  i32.sub
+  local.tee $class
+  i32.const 4
+  local.get $class
+  i32.const 4
+  i32.gt_u
+  select
+  local.tee $class
+  i32.const 4
+  i32.mul
+  i32.const 0x800800
+  i32.add
+  local.tee $list
+  i32.load
+  local.tee $block
+  if
+    local.get $list
+    local.get $block
+    i32.load offset=4
+    i32.store
+  else
+    global.get $nrt_heap_next
+    local.tee $block
+    i32.const 1
+    local.get $class
+    i32.shl
+    i32.add
+    global.get $nrt_heap_end
+    i32.sub
+    local.tee $grow
+    i32.const 0
+    i32.gt_s
+    if
+      ;; Grow memory by at least a megabyte
+      local.get $grow
+      i32.const 0x100000
+      local.get $grow
+      i32.const 0x100000
+      i32.gt_u
+      select
+      i32.const 0xFFFF
+      i32.add
+      i32.const 16
+      i32.shr_u
+      local.tee $grow
+      memory.grow
+      i32.const -1
+      i32.eq
+      if
+        i32.const 0x800A00
+        i32.const 0x800280
+        i32.const 13
+        call $nrt_panic
+      end
+      global.get $nrt_heap_end
+      local.get $grow
+      i32.const 16
+      i32.shl
+      i32.add
+      global.set $nrt_heap_end
+    end
+    local.get $block
+    i32.const 1
+    local.get $class
+    i32.shl
+    i32.add
+    global.set $nrt_heap_next
+    local.get $block
+    local.get $class
+    i32.store
+  end
+  local.get $block
+  i32.const 8
+  i32.add
+)
+
+;; void nrt_free(void *p), which does nothing to a null pointer
+(func $nrt_free (param $p i32)
+  (local $list i32)
+  local.get $p
+  i32.eqz
+  if
+    return
+  end
+  local.get $p
+  i32.const 8
+  i32.sub
+  local.tee $p
+  local.get $p
+  i32.load
+  i32.const 4
+  i32.mul
+  i32.const 0x800800
+  i32.add
+  local.tee $list
+  i32.load
+  i32.store offset=4
+  local.get $list
+  local.get $p
+  i32.store
+)
+
+;; A buffer is a pointer to its first element, which follows a header with the
+;; number of elements and the room there is for them. The empty buffer is 0.
+
+;; uint32_t nrt_buffer_len(void *items)
+(func $nrt_buffer_len (param $items i32) (result i32)
+  local.get $items
+  if (result i32)
+    local.get $items
+    i32.const 8
+    i32.sub
+    i32.load
+  else
+    i32.const 0
+  end
+)
+
+;; Adds an element of the given size to the end, and returns where the elements now are
+(func $nrt_buffer_push (param $items i32) (param $size i32) (result i32)
+  (local $len i32)
+  (local $capacity i32)
+  (local $bytes i64)
+  (local $grown i32)
+  local.get $items
+  if
+    local.get $items
+    i32.const 8
+    i32.sub
+    i32.load
+    local.set $len
+    local.get $items
+    i32.const 4
+    i32.sub
+    i32.load
+    local.set $capacity
+  end
+  local.get $len
+  local.get $capacity
+  i32.eq
+  if
+    local.get $capacity
+    i32.const 2
+    i32.mul
+    i32.const 4
+    local.get $capacity
+    select
+    local.tee $capacity
+    i64.extend_i32_u
+    local.get $size
+    i64.extend_i32_u
+    i64.mul
+    local.tee $bytes
+    i64.const 0x3FFFFFF0
+    i64.gt_u
+    if
+      i32.const 0x800A00
+      i32.const 0x8002C0
+      i32.const 17
+      call $nrt_panic
+    end
+    local.get $bytes
+    i32.wrap_i64
+    i32.const 8
+    i32.add
+    call $nrt_alloc
+    i32.const 8
+    i32.add
+    local.tee $grown
+    local.get $items
+    local.get $len
+    local.get $size
+    i32.mul
+    memory.copy
+    local.get $items
+    if
+      local.get $items
+      i32.const 8
+      i32.sub
+      call $nrt_free
+    end
+    local.get $grown
+    local.tee $items
+    i32.const 4
+    i32.sub
+    local.get $capacity
+    i32.store
+  end
+  local.get $items
+  i32.const 8
+  i32.sub
+  local.get $len
+  i32.const 1
+  i32.add
+  i32.store
+  local.get $items
+)
+
+;; Removes the last element, and returns its index
+(func $nrt_buffer_pop (param $items i32) (param $at i32) (result i32)
+  (local $len i32)
+  local.get $items
+  if
+    local.get $items
+    i32.const 8
+    i32.sub
+    i32.load
+    local.tee $len
+    if
+      local.get $items
+      i32.const 8
+      i32.sub
+      local.get $len
+      i32.const 1
+      i32.sub
+      local.tee $len
+      i32.store
+      local.get $len
+      return
+    end
+  end
+  local.get $at
+  i32.const 0x800300
+  i32.const 24
+  call $nrt_panic
+  unreachable
+)
+
+(func $nrt_buffer_free (param $items i32)
+  local.get $items
+  if
+    local.get $items
+    i32.const 8
+    i32.sub
+    call $nrt_free
+  end
+)
+
+;; Decodes the character of a str starting at the offset at pos, a uint64_t,
+;; and advances the offset past it
+(func $nrt_str_next_char (param $ptr i32) (param $len i32) (param $pos i32) (result i32)
+  (local $left i32)
+  (local $c i32)
+  (local $size i32)
+  local.get $len
+  local.get $pos
+  i32.load
+  local.tee $size
+  i32.sub
+  local.set $left
+  local.get $ptr
+  local.get $size
+  i32.add
+  local.tee $ptr
+  i32.load8_u
+  local.set $c
+  i32.const 1
+  local.set $size
+  block $decoded
+    local.get $c
+    i32.const 0xF0
+    i32.ge_u
+    local.get $left
+    i32.const 4
+    i32.ge_u
+    i32.and
+    if
+      local.get $c
+      i32.const 0x07
+      i32.and
+      i32.const 18
+      i32.shl
+      local.get $ptr
+      i32.load8_u offset=1
+      i32.const 0x3F
+      i32.and
+      i32.const 12
+      i32.shl
+      i32.or
+      local.get $ptr
+      i32.load8_u offset=2
+      i32.const 0x3F
+      i32.and
+      i32.const 6
+      i32.shl
+      i32.or
+      local.get $ptr
+      i32.load8_u offset=3
+      i32.const 0x3F
+      i32.and
+      i32.or
+      local.set $c
+      i32.const 4
+      local.set $size
+      br $decoded
+    end
+    local.get $c
+    i32.const 0xE0
+    i32.ge_u
+    local.get $left
+    i32.const 3
+    i32.ge_u
+    i32.and
+    if
+      local.get $c
+      i32.const 0x0F
+      i32.and
+      i32.const 12
+      i32.shl
+      local.get $ptr
+      i32.load8_u offset=1
+      i32.const 0x3F
+      i32.and
+      i32.const 6
+      i32.shl
+      i32.or
+      local.get $ptr
+      i32.load8_u offset=2
+      i32.const 0x3F
+      i32.and
+      i32.or
+      local.set $c
+      i32.const 3
+      local.set $size
+      br $decoded
+    end
+    local.get $c
+    i32.const 0xC0
+    i32.ge_u
+    local.get $left
+    i32.const 2
+    i32.ge_u
+    i32.and
+    if
+      local.get $c
+      i32.const 0x1F
+      i32.and
+      i32.const 6
+      i32.shl
+      local.get $ptr
+      i32.load8_u offset=1
+      i32.const 0x3F
+      i32.and
+      i32.or
+      local.set $c
+      i32.const 2
+      local.set $size
+    end
+  end
+  local.get $pos
+  local.get $pos
+  i64.load
+  local.get $size
+  i64.extend_i32_u
+  i64.add
+  i64.store
+  local.get $c
+)
+
+;; Makes room for extra more bytes in the string at s
+(func $nrt_string_reserve (param $s i32) (param $extra i32)
+  (local $needed i32)
+  (local $capacity i32)
+  (local $bytes i32)
+  local.get $s
+  i32.load offset=4
+  local.get $extra
+  i32.add
+  local.tee $needed
+  local.get $s
+  i32.load offset=8
+  local.tee $capacity
+  i32.le_u
+  if
+    return
+  end
+  local.get $capacity
+  i32.const 4
+  local.get $capacity
+  select
+  local.set $capacity
+  loop $double
+    local.get $capacity
+    i32.const 2
+    i32.mul
+    local.tee $capacity
+    local.get $needed
+    i32.lt_u
+    br_if $double
+  end
+  local.get $capacity
+  call $nrt_alloc
+  local.tee $bytes
+  local.get $s
+  i32.load
+  local.get $s
+  i32.load offset=4
+  memory.copy
+  local.get $s
+  i32.load
+  call $nrt_free
+  local.get $s
+  local.get $bytes
+  i32.store
+  local.get $s
+  local.get $capacity
+  i32.store offset=8
+)
+
+;; void nrt_string_push_str(nrt_string *s, nrt_str other)
+(func $nrt_string_push_str (param $s i32) (param $ptr i32) (param $len i32)
+  local.get $len
+  i32.eqz
+  if
+    return
+  end
+  local.get $s
+  local.get $len
+  call $nrt_string_reserve
+  local.get $s
+  i32.load
+  local.get $s
+  i32.load offset=4
+  i32.add
+  local.get $ptr
+  local.get $len
+  memory.copy
+  local.get $s
+  local.get $s
+  i32.load offset=4
+  local.get $len
+  i32.add
+  i32.store offset=4
+)
+
+;; void nrt_string_push_char(nrt_string *s, uint32_t c)
+(func $nrt_string_push_char (param $s i32) (param $c i32)
+  local.get $s
+  i32.const 0x800944
+  local.get $c
+  i32.const 0x800944
+  call $nrt_char_encode
+  call $nrt_string_push_str
+)
+
+;; nrt_string nrt_string_from(nrt_str s)
+(func $nrt_string_from (param $out i32) (param $ptr i32) (param $len i32)
+  local.get $out
+  i32.const 0
+  i32.const 12
+  memory.fill
+  local.get $out
+  local.get $ptr
+  local.get $len
+  call $nrt_string_push_str
+)
+
+;; nrt_string nrt_char_to_string(uint32_t c)
+(func $nrt_char_to_string (param $out i32) (param $c i32)
+  local.get $out
+  i32.const 0
+  i32.const 12
+  memory.fill
+  local.get $out
+  local.get $c
+  call $nrt_string_push_char
+)
+
+;; nrt_string nrt_u64_to_string(uint64_t n)
+(func $nrt_u64_to_string (param $out i32) (param $n i64)
+  (local $start i32)
+  local.get $out
+  local.get $n
+  i32.const 0x800940
+  call $nrt_format_u64
+  local.tee $start
+  i32.const 0x800940
+  local.get $start
+  i32.sub
+  call $nrt_string_from
+)
+
+;; nrt_string nrt_i64_to_string(int64_t n)
+(func $nrt_i64_to_string (param $out i32) (param $n i64)
+  (local $start i32)
+  local.get $out
+  local.get $n
+  i32.const 0x800940
+  call $nrt_format_i64
+  local.tee $start
+  i32.const 0x800940
+  local.get $start
+  i32.sub
+  call $nrt_string_from
+)
+
+;; Whether the byte at an offset of a str continues a char rather than
+;; starting one; the end of the str starts none
+(func $nrt_inside_char (param $ptr i32) (param $len i32) (param $offset i32) (result i32)
+  local.get $offset
+  local.get $len
+  i32.eq
+  if
+    i32.const 0
+    return
+  end
+  local.get $ptr
+  local.get $offset
+  i32.add
+  i32.load8_u
+  i32.const 0xC0
+  i32.and
+  i32.const 0x80
+  i32.eq
+)
+
+;; nrt_str nrt_str_slice(nrt_str s, uint64_t start, uint64_t end, const nrt_location *at)
+(func $nrt_str_slice (param $out i32) (param $ptr i32) (param $len i32) (param $start i64) (param $end i64) (param $at i32)
+  local.get $start
+  local.get $end
+  i64.gt_u
+  local.get $end
+  local.get $len
+  i64.extend_i32_u
+  i64.gt_u
+  i32.or
+  if
+    local.get $at
+    i32.const 0x800340
+    i32.const 26
+    call $nrt_panic
+  end
+  local.get $ptr
+  local.get $len
+  local.get $start
+  i32.wrap_i64
+  call $nrt_inside_char
+  local.get $ptr
+  local.get $len
+  local.get $end
+  i32.wrap_i64
+  call $nrt_inside_char
+  i32.or
+  if
+    local.get $at
+    i32.const 0x800380
+    i32.const 38
+    call $nrt_panic
+  end
+  local.get $out
+  local.get $ptr
+  local.get $start
+  i32.wrap_i64
+  i32.add
+  i32.store
+  local.get $out
+  local.get $end
+  local.get $start
+  i64.sub
+  i32.wrap_i64
+  i32.store offset=4
+)
+
+;; nrt_string nrt_str_concat(nrt_str a, nrt_str b)
+(func $nrt_str_concat (param $out i32) (param $a i32) (param $a_len i32) (param $b i32) (param $b_len i32)
+  local.get $out
+  i32.const 0
+  i32.const 12
+  memory.fill
+  local.get $out
+  local.get $a_len
+  local.get $b_len
+  i32.add
+  call $nrt_string_reserve
+  local.get $out
+  local.get $a
+  local.get $a_len
+  call $nrt_string_push_str
+  local.get $out
+  local.get $b
+  local.get $b_len
+  call $nrt_string_push_str
+)
+
+;; int32_t nrt_str_compare(nrt_str a, nrt_str b)
+(func $nrt_str_compare (param $a i32) (param $a_len i32) (param $b i32) (param $b_len i32) (result i32)
+  (local $i i32)
+  (local $x i32)
+  (local $y i32)
+  block $prefix
+    loop $byte
+      local.get $i
+      local.get $a_len
+      i32.eq
+      local.get $i
+      local.get $b_len
+      i32.eq
+      i32.or
+      br_if $prefix
+      local.get $a
+      local.get $i
+      i32.add
+      i32.load8_u
+      local.tee $x
+      local.get $b
+      local.get $i
+      i32.add
+      i32.load8_u
+      local.tee $y
+      i32.ne
+      if
+        i32.const -1
+        i32.const 1
+        local.get $x
+        local.get $y
+        i32.lt_u
+        select
+        return
+      end
+      local.get $i
+      i32.const 1
+      i32.add
+      local.set $i
+      br $byte
+    end
+  end
+  ;; One is a prefix of the other, so the shorter comes first
+  local.get $a_len
+  local.get $b_len
+  i32.gt_u
+  local.get $a_len
+  local.get $b_len
+  i32.lt_u
+  i32.sub
+)
+
+;; bool nrt_str_equal(nrt_str a, nrt_str b)
+(func $nrt_str_equal (param $a i32) (param $a_len i32) (param $b i32) (param $b_len i32) (result i32)
+  local.get $a
+  local.get $a_len
+  local.get $b
+  local.get $b_len
+  call $nrt_str_compare
+  i32.eqz
+)
+
+;; int64_t nrt_str_parse_i64(nrt_str s, const nrt_location *at)
+(func $nrt_str_parse_i64 (param $ptr i32) (param $len i32) (param $at i32) (result i64)
+  (local $i i32)
+  (local $negative i32)
+  (local $value i64)
+  (local $digit i64)
+  local.get $len
+  if
+    local.get $ptr
+    i32.load8_u
+    i32.const 45
+    i32.eq
+    local.tee $negative
+    local.get $ptr
+    i32.load8_u
+    i32.const 43
+    i32.eq
+    i32.or
+    local.set $i
+  end
+  local.get $i
+  local.get $len
+  i32.eq
+  if
+    local.get $at
+    i32.const 0x8003C0
+    i32.const 42
+    call $nrt_panic
+  end
+  ;; Accumulate negatively so INT64_MIN parses without overflow
+  block $done
+    loop $next
+      local.get $i
+      local.get $len
+      i32.eq
+      br_if $done
+      local.get $ptr
+      local.get $i
+      i32.add
+      i64.load8_u
+      i64.const 48
+      i64.sub
+      local.tee $digit
+      i64.const 9
+      i64.gt_u
+      if
+        local.get $at
+        i32.const 0x800400
+        i32.const 24
+        call $nrt_panic
+      end
+      local.get $value
+      i64.const -922337203685477580
+      i64.lt_s
+      local.get $value
+      i64.const 10
+      i64.mul
+      local.tee $value
+      i64.const -9223372036854775808
+      local.get $digit
+      i64.add
+      i64.lt_s
+      i32.or
+      if
+        local.get $at
+        i32.const 0x800440
+        i32.const 27
+        call $nrt_panic
+      end
+      local.get $value
+      local.get $digit
+      i64.sub
+      local.set $value
+      local.get $i
+      i32.const 1
+      i32.add
+      local.set $i
+      br $next
+    end
+  end
+  local.get $negative
+  if
+    local.get $value
+    return
+  end
+  local.get $value
+  i64.const -9223372036854775808
+  i64.eq
+  if
+    local.get $at
+    i32.const 0x800440
+    i32.const 27
+    call $nrt_panic
+  end
+  i64.const 0
+  local.get $value
+  i64.sub
+)
+
+;; Whether 64-bit arithmetic overflows, for the generated code's checks
+
+(func $nrt_add_overflows_i64 (param $a i64) (param $b i64) (result i32)
+  (local $sum i64)
+  local.get $a
+  local.get $b
+  i64.add
+  local.set $sum
+  local.get $a
+  local.get $sum
+  i64.xor
+  local.get $b
+  local.get $sum
+  i64.xor
+  i64.and
+  i64.const 0
+  i64.lt_s
+)
+
+(func $nrt_sub_overflows_i64 (param $a i64) (param $b i64) (result i32)
+  local.get $a
+  local.get $b
+  i64.xor
+  local.get $a
+  local.get $a
+  local.get $b
+  i64.sub
+  i64.xor
+  i64.and
+  i64.const 0
+  i64.lt_s
+)
+
+(func $nrt_mul_overflows_i64 (param $a i64) (param $b i64) (result i32)
+  local.get $a
+  i64.eqz
+  if
+    i32.const 0
+    return
+  end
+  local.get $a
+  i64.const -1
+  i64.eq
+  if
+    local.get $b
+    i64.const -9223372036854775808
+    i64.eq
+    return
+  end
+  local.get $a
+  local.get $b
+  i64.mul
+  local.get $a
+  i64.div_s
+  local.get $b
+  i64.ne
+)
+
+(func $nrt_add_overflows_u64 (param $a i64) (param $b i64) (result i32)
+  local.get $a
+  local.get $b
+  i64.add
+  local.get $a
+  i64.lt_u
+)
+
+(func $nrt_sub_overflows_u64 (param $a i64) (param $b i64) (result i32)
+  local.get $a
+  local.get $b
+  i64.lt_u
+)
+
+(func $nrt_mul_overflows_u64 (param $a i64) (param $b i64) (result i32)
+  local.get $a
+  i64.eqz
+  if
+    i32.const 0
+    return
+  end
+  local.get $a
+  local.get $b
+  i64.mul
+  local.get $a
+  i64.div_u
+  local.get $b
+  i64.ne
+)
@@ -0,0 +1,1052 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+/*
+ * The runtime of the x86-64 backend, which links without a C library:
+ * output goes through the write system call, memory comes from mmap, and
+ * the program starts at _start, which calls the generated nrt_main. Every
+ * function follows the System V calling convention and behaves like the
+ * C runtime's function of the same name, except that a location is passed
+ * by pointer, as a file name (or 0 if unknown) followed by a 32-bit line
+ * and column.
+ */
+
+    .set NRT_OUT_SIZE, 4096
+    .set NRT_SYS_WRITE, 1
+    .set NRT_SYS_MMAP, 9
+    .set NRT_SYS_EXIT_GROUP, 231
+    .set NRT_CHUNK_SIZE, 0x100000
+
+    .section .bss
+    .balign 16
+    .globl nrt_frame_top
+/* The innermost frame; debug builds push one on entry to every function */
+nrt_frame_top: .zero 8
+nrt_envp: .zero 8
+/* Standard output is buffered until it fills up or the program ends */
+nrt_out_len: .zero 8
+nrt_out_buf: .zero NRT_OUT_SIZE
+/* Memory not yet handed out by nrt_alloc, and the blocks freed, by size class */
+nrt_heap_next: .zero 8
+nrt_heap_end: .zero 8
+nrt_free_lists: .zero 8 * 64
+
+    .section .rodata
+nrt_no_location: .quad 0
+    .long 0, 0
+nrt_m_panicked_at: .ascii "panicked at "
+    .set nrt_m_panicked_at_len, . - nrt_m_panicked_at
+nrt_m_panicked: .ascii "panicked: "
+    .set nrt_m_panicked_len, . - nrt_m_panicked
+nrt_m_colon: .ascii ": "
+nrt_m_newline: .ascii "\n"
+nrt_m_true: .ascii "true"
+nrt_m_false: .ascii "false"
+nrt_m_note: .ascii "note: run with `NIMRA_BACKTRACE=1` to display a backtrace\n"
+    .set nrt_m_note_len, . - nrt_m_note
+nrt_m_backtrace: .ascii "stack backtrace:\n"
+    .set nrt_m_backtrace_len, . - nrt_m_backtrace
+nrt_m_backtrace_var: .ascii "NIMRA_BACKTRACE="
+    .set nrt_m_backtrace_var_len, . - nrt_m_backtrace_var
+nrt_m_index_len: .ascii "index out of bounds: the len is "
+    .set nrt_m_index_len_len, . - nrt_m_index_len
+nrt_m_index_index: .ascii " but the index is "
+    .set nrt_m_index_index_len, . - nrt_m_index_index
+nrt_m_oom: .ascii "out of memory"
+    .set nrt_m_oom_len, . - nrt_m_oom
+nrt_m_capacity: .ascii "capacity overflow"
+    .set nrt_m_capacity_len, . - nrt_m_capacity
+nrt_m_pop: .ascii "pop from an empty buffer"
+    .set nrt_m_pop_len, . - nrt_m_pop
+nrt_m_slice_bounds: .ascii "string slice out of bounds"
+    .set nrt_m_slice_bounds_len, . - nrt_m_slice_bounds
+nrt_m_slice_boundary: .ascii "string slice is not on a char boundary"
+    .set nrt_m_slice_boundary_len, . - nrt_m_slice_boundary
+nrt_m_parse_empty: .ascii "cannot parse an empty string as an integer"
+    .set nrt_m_parse_empty_len, . - nrt_m_parse_empty
+nrt_m_parse_digit: .ascii "invalid digit in integer"
+    .set nrt_m_parse_digit_len, . - nrt_m_parse_digit
+nrt_m_parse_range: .ascii "integer does not fit in i64"
+    .set nrt_m_parse_range_len, . - nrt_m_parse_range
+
+    .text
+
+    .globl _start
+_start:
+    xorl %ebp, %ebp
+    /* The environment follows the arguments and the null that ends them */
+    movq (%rsp), %rax
+    leaq 16(%rsp,%rax,8), %rcx
+    movq %rcx, nrt_envp(%rip)
+    andq $-16, %rsp
+    call nrt_main
+    movl %eax, %edi
+    call nrt_exit
+
+/* void nrt_exit(int32_t code), which flushes standard output first */
+    .globl nrt_exit
+nrt_exit:
+    pushq %rbx
+    movl %edi, %ebx
+    call nrt_flush
+    movl %ebx, %edi
+    movl $NRT_SYS_EXIT_GROUP, %eax
+    syscall
+
+/* Writes all of len bytes at ptr to fd, giving up if the system call fails */
+nrt_write_all:
+    testq %rdx, %rdx
+    jz 2f
+    movl $NRT_SYS_WRITE, %eax
+    syscall
+    testq %rax, %rax
+    jle 2f
+    addq %rax, %rsi
+    subq %rax, %rdx
+    jmp nrt_write_all
+2:  ret
+
+nrt_flush:
+    movq nrt_out_len(%rip), %rdx
+    movq $0, nrt_out_len(%rip)
+    movl $1, %edi
+    leaq nrt_out_buf(%rip), %rsi
+    jmp nrt_write_all
+
+/* void nrt_write(int32_t fd, const char *ptr, uint64_t len) */
+    .globl nrt_write
+nrt_write:
+    cmpl $1, %edi
+    jne nrt_write_all
+    movq nrt_out_len(%rip), %rax
+    leaq (%rax,%rdx), %rcx
+    cmpq $NRT_OUT_SIZE, %rcx
+    jbe 1f
+    pushq %rsi
+    pushq %rdx
+    subq $8, %rsp
+    call nrt_flush
+    addq $8, %rsp
+    popq %rdx
+    popq %rsi
+    movl $1, %edi
+    cmpq $NRT_OUT_SIZE, %rdx
+    jae nrt_write_all
+    xorl %eax, %eax
+1:  leaq nrt_out_buf(%rip), %rdi
+    addq %rax, %rdi
+    addq %rdx, %rax
+    movq %rax, nrt_out_len(%rip)
+    movq %rdx, %rcx
+    rep movsb
+    ret
+
+/* Writes the digits of n so they end at end, and returns where they start */
+nrt_format_u64:
+    movq %rdi, %rax
+    movl $10, %ecx
+1:  xorl %edx, %edx
+    divq %rcx
+    addb $48, %dl
+    decq %rsi
+    movb %dl, (%rsi)
+    testq %rax, %rax
+    jnz 1b
+    movq %rsi, %rax
+    ret
+
+/* nrt_format_u64 for a signed number */
+nrt_format_i64:
+    pushq %rdi
+    movq %rdi, %rax
+    negq %rax
+    testq %rdi, %rdi
+    cmovsq %rax, %rdi
+    call nrt_format_u64
+    popq %rdi
+    testq %rdi, %rdi
+    jns 1f
+    decq %rax
+    movb $45, (%rax)
+1:  ret
+
+/* void nrt_print_u64(int32_t fd, uint64_t n) */
+    .globl nrt_print_u64
+nrt_print_u64:
+    pushq %rbx
+    subq $32, %rsp
+    movl %edi, %ebx
+    movq %rsi, %rdi
+    leaq 32(%rsp), %rsi
+    call nrt_format_u64
+    jmp nrt_print_formatted
+
+/* void nrt_print_i64(int32_t fd, int64_t n) */
+    .globl nrt_print_i64
+nrt_print_i64:
+    pushq %rbx
+    subq $32, %rsp
+    movl %edi, %ebx
+    movq %rsi, %rdi
+    leaq 32(%rsp), %rsi
+    call nrt_format_i64
+    /* Shared by the number printers, whose digits start at rax */
+nrt_print_formatted:
+    leaq 32(%rsp), %rdx
+    subq %rax, %rdx
+    movq %rax, %rsi
+    movl %ebx, %edi
+    call nrt_write
+    addq $32, %rsp
+    popq %rbx
+    ret
+
+/* void nrt_print_bool(int32_t fd, bool b) */
+    .globl nrt_print_bool
+nrt_print_bool:
+    testb %sil, %sil
+    jz 1f
+    leaq nrt_m_true(%rip), %rsi
+    movl $4, %edx
+    jmp nrt_write
+1:  leaq nrt_m_false(%rip), %rsi
+    movl $5, %edx
+    jmp nrt_write
+
+/* Encodes the char c as UTF-8 at out, and returns how many bytes it took */
+nrt_char_encode:
+    cmpl $0x80, %edi
+    jae 1f
+    movb %dil, (%rsi)
+    movl $1, %eax
+    ret
+1:  cmpl $0x800, %edi
+    jae 2f
+    movl %edi, %eax
+    shrl $6, %eax
+    orl $0xC0, %eax
+    movb %al, (%rsi)
+    movl $2, %eax
+    jmp 4f
+2:  cmpl $0x10000, %edi
+    jae 3f
+    movl %edi, %eax
+    shrl $12, %eax
+    orl $0xE0, %eax
+    movb %al, (%rsi)
+    movl %edi, %eax
+    shrl $6, %eax
+    andl $0x3F, %eax
+    orl $0x80, %eax
+    movb %al, 1(%rsi)
+    movl $3, %eax
+    jmp 4f
+3:  movl %edi, %eax
+    shrl $18, %eax
+    orl $0xF0, %eax
+    movb %al, (%rsi)
+    movl %edi, %eax
+    shrl $12, %eax
+    andl $0x3F, %eax
+    orl $0x80, %eax
+    movb %al, 1(%rsi)
+    movl %edi, %eax
+    shrl $6, %eax
+    andl $0x3F, %eax
+    orl $0x80, %eax
+    movb %al, 2(%rsi)
+    movl $4, %eax
+    /* The last byte of every multi-byte encoding holds the low six bits */
+4:  movl %edi, %ecx
+    andl $0x3F, %ecx
+    orl $0x80, %ecx
+    movb %cl, -1(%rsi,%rax)
+    ret
+
+/* void nrt_print_char(int32_t fd, uint32_t c) */
+    .globl nrt_print_char
+nrt_print_char:
+    subq $24, %rsp
+    movl %edi, 16(%rsp)
+    movl %esi, %edi
+    movq %rsp, %rsi
+    call nrt_char_encode
+    movq %rax, %rdx
+    movq %rsp, %rsi
+    movl 16(%rsp), %edi
+    call nrt_write
+    addq $24, %rsp
+    ret
+
+/* uint64_t nrt_strlen(const char *s) */
+nrt_strlen:
+    movq %rdi, %rax
+1:  cmpb $0, (%rax)
+    je 2f
+    incq %rax
+    jmp 1b
+2:  subq %rdi, %rax
+    ret
+
+/* Flushes standard output and starts a panic message at a location */
+nrt_panic_begin:
+    pushq %rbx
+    movq %rdi, %rbx
+    call nrt_flush
+    cmpq $0, (%rbx)
+    jne 1f
+    movl $2, %edi
+    leaq nrt_m_panicked(%rip), %rsi
+    movl $nrt_m_panicked_len, %edx
+    call nrt_write
+    popq %rbx
+    ret
+1:  movl $2, %edi
+    leaq nrt_m_panicked_at(%rip), %rsi
+    movl $nrt_m_panicked_at_len, %edx
+    call nrt_write
+    movq (%rbx), %rdi
+    call nrt_strlen
+    movq %rax, %rdx
+    movq (%rbx), %rsi
+    movl $2, %edi
+    call nrt_write
+    movl $2, %edi
+    leaq nrt_m_colon(%rip), %rsi
+    movl $1, %edx
+    call nrt_write
+    movl $2, %edi
+    movl 8(%rbx), %esi
+    call nrt_print_u64
+    movl $2, %edi
+    leaq nrt_m_colon(%rip), %rsi
+    movl $1, %edx
+    call nrt_write
+    movl $2, %edi
+    movl 12(%rbx), %esi
+    call nrt_print_u64
+    movl $2, %edi
+    leaq nrt_m_colon(%rip), %rsi
+    movl $2, %edx
+    call nrt_write
+    popq %rbx
+    ret
+
+/* Ends a panic message, prints the backtrace and exits */
+nrt_panic_end:
+    subq $8, %rsp
+    movl $2, %edi
+    leaq nrt_m_newline(%rip), %rsi
+    movl $1, %edx
+    call nrt_write
+    call nrt_print_backtrace
+    movl $101, %edi
+    movl $NRT_SYS_EXIT_GROUP, %eax
+    syscall
+
+/* void nrt_panic(const nrt_location *at, const char *ptr, uint64_t len) */
+    .globl nrt_panic
+nrt_panic:
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    movq %rsi, %r12
+    movq %rdx, %r13
+    call nrt_panic_begin
+    movl $2, %edi
+    movq %r12, %rsi
+    movq %r13, %rdx
+    call nrt_write
+    call nrt_panic_end
+
+/* Whether NIMRA_BACKTRACE is set to anything but 0 */
+nrt_backtrace_enabled:
+    movq nrt_envp(%rip), %r8
+1:  movq (%r8), %rsi
+    testq %rsi, %rsi
+    jz 4f
+    leaq nrt_m_backtrace_var(%rip), %rdi
+    movl $nrt_m_backtrace_var_len, %ecx
+    repe cmpsb
+    je 2f
+    addq $8, %r8
+    jmp 1b
+2:  cmpb $48, (%rsi)
+    jne 3f
+    cmpb $0, 1(%rsi)
+    je 4f
+3:  movl $1, %eax
+    ret
+4:  xorl %eax, %eax
+    ret
+
+nrt_print_backtrace:
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    subq $32, %rsp
+    movq nrt_frame_top(%rip), %rbx
+    testq %rbx, %rbx
+    jz 4f
+    call nrt_backtrace_enabled
+    testl %eax, %eax
+    jnz 1f
+    movl $2, %edi
+    leaq nrt_m_note(%rip), %rsi
+    movl $nrt_m_note_len, %edx
+    call nrt_write
+    jmp 4f
+1:  movl $2, %edi
+    leaq nrt_m_backtrace(%rip), %rsi
+    movl $nrt_m_backtrace_len, %edx
+    call nrt_write
+    xorl %r12d, %r12d
+    /* Each frame is printed as its depth, right-aligned in four columns, and its function */
+2:  movq %r12, %rdi
+    leaq 32(%rsp), %rsi
+    call nrt_format_u64
+    leaq 28(%rsp), %rcx
+3:  cmpq %rcx, %rax
+    jbe 5f
+    decq %rax
+    movb $32, (%rax)
+    jmp 3b
+5:  leaq 32(%rsp), %rdx
+    subq %rax, %rdx
+    movq %rax, %rsi
+    movl $2, %edi
+    call nrt_write
+    movl $2, %edi
+    leaq nrt_m_colon(%rip), %rsi
+    movl $2, %edx
+    call nrt_write
+    movq (%rbx), %rdi
+    call nrt_strlen
+    movq %rax, %rdx
+    movq (%rbx), %rsi
+    movl $2, %edi
+    call nrt_write
+    movl $2, %edi
+    leaq nrt_m_newline(%rip), %rsi
+    movl $1, %edx
+    call nrt_write
+    incq %r12
+    movq 8(%rbx), %rbx
+    testq %rbx, %rbx
+    jnz 2b
+4:  addq $32, %rsp
+    popq %r13
+    popq %r12
+    popq %rbx
+    ret
+
+/* uint64_t nrt_check_index(uint64_t index, uint64_t len, const nrt_location *at) */
+    .globl nrt_check_index
+nrt_check_index:
+    cmpq %rsi, %rdi
+    jae 1f
+    movq %rdi, %rax
+    ret
+1:  pushq %rbx
+    pushq %r12
+    pushq %r13
+    movq %rdi, %rbx
+    movq %rsi, %r12
+    movq %rdx, %rdi
+    call nrt_panic_begin
+    movl $2, %edi
+    leaq nrt_m_index_len(%rip), %rsi
+    movl $nrt_m_index_len_len, %edx
+    call nrt_write
+    movl $2, %edi
+    movq %r12, %rsi
+    call nrt_print_u64
+    movl $2, %edi
+    leaq nrt_m_index_index(%rip), %rsi
+    movl $nrt_m_index_index_len, %edx
+    call nrt_write
+    movl $2, %edi
+    movq %rbx, %rsi
+    call nrt_print_u64
+    call nrt_panic_end
+
+/* The finalizer of SplitMix64, which spreads every input bit over the whole result */
+    .globl nrt_hash_u64
+nrt_hash_u64:
+    movq %rdi, %rax
+    shrq $30, %rax
+    xorq %rdi, %rax
+    movabsq $0xbf58476d1ce4e5b9, %rcx
+    imulq %rcx, %rax
+    movq %rax, %rdx
+    shrq $27, %rdx
+    xorq %rdx, %rax
+    movabsq $0x94d049bb133111eb, %rcx
+    imulq %rcx, %rax
+    movq %rax, %rdx
+    shrq $31, %rdx
+    xorq %rdx, %rax
+    ret
+
+/* 64-bit FNV-1a */
+    .globl nrt_hash_str
+nrt_hash_str:
+    movabsq $0xcbf29ce484222325, %rax
+    movabsq $0x100000001b3, %rcx
+1:  testq %rsi, %rsi
+    jz 2f
+    movzbl (%rdi), %edx
+    xorq %rdx, %rax
+    imulq %rcx, %rax
+    incq %rdi
+    decq %rsi
+    jmp 1b
+2:  ret
+
+/*
+ * void *nrt_alloc(uint64_t size)
+ *
+ * Blocks come in powers of two from 32 bytes, each starting with a 16-byte
+ * header holding its size class, so the memory after it is 16-byte aligned.
+ * A freed block goes on the list for its class, linked through the second
+ * word of its header, and is handed out again before any new memory.
+ */
+    .globl nrt_alloc
+nrt_alloc:
+    movq %rdi, %rax
+    shrq $46, %rax
+    jnz 5f
+    leaq 15(%rdi), %rax
+    bsrq %rax, %rcx
+    incl %ecx
+    cmpl $5, %ecx
+    jae 1f
+    movl $5, %ecx
+1:  leaq nrt_free_lists(%rip), %rdx
+    movq (%rdx,%rcx,8), %rax
+    testq %rax, %rax
+    jz 2f
+    movq 8(%rax), %rsi
+    movq %rsi, (%rdx,%rcx,8)
+    jmp 4f
+2:  movl $1, %esi
+    shlq %cl, %rsi
+    movq nrt_heap_next(%rip), %rax
+    leaq (%rax,%rsi), %r8
+    cmpq nrt_heap_end(%rip), %r8
+    jbe 3f
+    /* Map a new chunk, leaving what was left of the last one unused */
+    pushq %rcx
+    pushq %rsi
+    movl $NRT_CHUNK_SIZE, %eax
+    cmpq %rax, %rsi
+    cmovbq %rax, %rsi
+    xorl %edi, %edi
+    movl $3, %edx
+    movl $0x22, %r10d
+    movq $-1, %r8
+    xorl %r9d, %r9d
+    movl $NRT_SYS_MMAP, %eax
+    syscall
+    cmpq $-4096, %rax
+    ja 5f
+    leaq (%rax,%rsi), %r8
+    movq %r8, nrt_heap_end(%rip)
+    popq %rsi
+    popq %rcx
+    leaq (%rax,%rsi), %r8
+3:  movq %r8, nrt_heap_next(%rip)
+    movq %rcx, (%rax)
+4:  addq $16, %rax
+    ret
+5:  andq $-16, %rsp
+    leaq nrt_no_location(%rip), %rdi
+    leaq nrt_m_oom(%rip), %rsi
+    movl $nrt_m_oom_len, %edx
+    call nrt_panic
+
+/* void nrt_free(void *p), which does nothing to a null pointer */
+    .globl nrt_free
+nrt_free:
+    testq %rdi, %rdi
+    jz 1f
+    subq $16, %rdi
+    movq (%rdi), %rcx
+    leaq nrt_free_lists(%rip), %rdx
+    movq (%rdx,%rcx,8), %rax
+    movq %rax, 8(%rdi)
+    movq %rdi, (%rdx,%rcx,8)
+1:  ret
+
+/*
+ * A buffer is a pointer to its first element, which follows a header with the
+ * number of elements and the room there is for them. The empty buffer is 0.
+ */
+
+/* uint64_t nrt_buffer_len(void *items) */
+    .globl nrt_buffer_len
+nrt_buffer_len:
+    xorl %eax, %eax
+    testq %rdi, %rdi
+    jz 1f
+    movq -16(%rdi), %rax
+1:  ret
+
+/* Adds an element of the given size to the end, and returns where the elements now are */
+    .globl nrt_buffer_push
+nrt_buffer_push:
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq %rdi, %rbx
+    movq %rsi, %r12
+    xorl %r13d, %r13d
+    xorl %r14d, %r14d
+    testq %rbx, %rbx
+    jz 1f
+    movq -16(%rbx), %r13
+    movq -8(%rbx), %r14
+1:  cmpq %r14, %r13
+    jne 3f
+    addq %r14, %r14
+    jnz 2f
+    movl $4, %r14d
+2:  movq %r14, %rax
+    mulq %r12
+    jo 5f
+    leaq 16(%rax), %rdi
+    call nrt_alloc
+    movq %rax, %r15
+    movq %r13, %rcx
+    imulq %r12, %rcx
+    leaq 16(%r15), %rdi
+    movq %rbx, %rsi
+    rep movsb
+    testq %rbx, %rbx
+    jz 4f
+    leaq -16(%rbx), %rdi
+    call nrt_free
+4:  leaq 16(%r15), %rbx
+    movq %r14, -8(%rbx)
+3:  leaq 1(%r13), %rax
+    movq %rax, -16(%rbx)
+    movq %rbx, %rax
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    ret
+5:  leaq nrt_no_location(%rip), %rdi
+    leaq nrt_m_capacity(%rip), %rsi
+    movl $nrt_m_capacity_len, %edx
+    call nrt_panic
+
+/* Removes the last element, and returns its index */
+    .globl nrt_buffer_pop
+nrt_buffer_pop:
+    testq %rdi, %rdi
+    jz 1f
+    movq -16(%rdi), %rax
+    testq %rax, %rax
+    jz 1f
+    decq %rax
+    movq %rax, -16(%rdi)
+    ret
+1:  movq %rsi, %rdi
+    leaq nrt_m_pop(%rip), %rsi
+    movl $nrt_m_pop_len, %edx
+    subq $8, %rsp
+    call nrt_panic
+
+/* The address of an element, after checking the index against the length */
+    .globl nrt_buffer_at
+nrt_buffer_at:
+    pushq %rbx
+    pushq %r12
+    subq $8, %rsp
+    movq %rdi, %rbx
+    movq %rdx, %r12
+    call nrt_buffer_len
+    movq %rsi, %rdi
+    movq %rax, %rsi
+    movq %rcx, %rdx
+    call nrt_check_index
+    imulq %r12, %rax
+    addq %rbx, %rax
+    addq $8, %rsp
+    popq %r12
+    popq %rbx
+    ret
+
+    .globl nrt_buffer_free
+nrt_buffer_free:
+    testq %rdi, %rdi
+    jz 1f
+    subq $16, %rdi
+    jmp nrt_free
+1:  ret
+
+/* Decodes the character starting at *pos and advances *pos past it */
+    .globl nrt_str_next_char
+nrt_str_next_char:
+    movq (%rdx), %r8
+    movq %rsi, %r9
+    subq %r8, %r9
+    addq %r8, %rdi
+    movzbl (%rdi), %eax
+    movl $1, %ecx
+    cmpl $0xF0, %eax
+    jb 1f
+    cmpq $4, %r9
+    jb 1f
+    andl $0x07, %eax
+    shll $18, %eax
+    movzbl 1(%rdi), %esi
+    andl $0x3F, %esi
+    shll $12, %esi
+    orl %esi, %eax
+    movzbl 2(%rdi), %esi
+    andl $0x3F, %esi
+    shll $6, %esi
+    orl %esi, %eax
+    movzbl 3(%rdi), %esi
+    andl $0x3F, %esi
+    orl %esi, %eax
+    movl $4, %ecx
+    jmp 3f
+1:  cmpl $0xE0, %eax
+    jb 2f
+    cmpq $3, %r9
+    jb 2f
+    andl $0x0F, %eax
+    shll $12, %eax
+    movzbl 1(%rdi), %esi
+    andl $0x3F, %esi
+    shll $6, %esi
+    orl %esi, %eax
+    movzbl 2(%rdi), %esi
+    andl $0x3F, %esi
+    orl %esi, %eax
+    movl $3, %ecx
+    jmp 3f
+2:  cmpl $0xC0, %eax
+    jb 3f
+    cmpq $2, %r9
+    jb 3f
+    andl $0x1F, %eax
+    shll $6, %eax
+    movzbl 1(%rdi), %esi
+    andl $0x3F, %esi
+    orl %esi, %eax
+    movl $2, %ecx
+3:  addq %rcx, (%rdx)
+    ret
+
+/* Makes room for extra more bytes in the string at rdi */
+nrt_string_reserve:
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    movq %rdi, %rbx
+    movq 8(%rbx), %r12
+    addq %rsi, %r12
+    cmpq 16(%rbx), %r12
+    jbe 3f
+    movq 16(%rbx), %r13
+    testq %r13, %r13
+    jnz 1f
+    movl $8, %r13d
+1:  addq %r13, %r13
+    cmpq %r12, %r13
+    jb 1b
+    movq %r13, %rdi
+    call nrt_alloc
+    movq %rax, %r12
+    movq %rax, %rdi
+    movq (%rbx), %rsi
+    movq 8(%rbx), %rcx
+    rep movsb
+    movq (%rbx), %rdi
+    call nrt_free
+    movq %r12, (%rbx)
+    movq %r13, 16(%rbx)
+3:  popq %r13
+    popq %r12
+    popq %rbx
+    ret
+
+/* void nrt_string_push_str(nrt_string *s, nrt_str other) */
+    .globl nrt_string_push_str
+nrt_string_push_str:
+    testq %rdx, %rdx
+    jz 1f
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    movq %rdi, %rbx
+    movq %rsi, %r12
+    movq %rdx, %r13
+    movq %rdx, %rsi
+    call nrt_string_reserve
+    movq (%rbx), %rdi
+    addq 8(%rbx), %rdi
+    movq %r12, %rsi
+    movq %r13, %rcx
+    rep movsb
+    addq %r13, 8(%rbx)
+    popq %r13
+    popq %r12
+    popq %rbx
+1:  ret
+
+/* void nrt_string_push_char(nrt_string *s, uint32_t c) */
+    .globl nrt_string_push_char
+nrt_string_push_char:
+    pushq %rbx
+    subq $16, %rsp
+    movq %rdi, %rbx
+    movl %esi, %edi
+    movq %rsp, %rsi
+    call nrt_char_encode
+    movq %rbx, %rdi
+    movq %rsp, %rsi
+    movq %rax, %rdx
+    call nrt_string_push_str
+    addq $16, %rsp
+    popq %rbx
+    ret
+
+/* nrt_string nrt_string_from(nrt_str s) */
+    .globl nrt_string_from
+nrt_string_from:
+    pushq %rbx
+    movq %rdi, %rbx
+    movq $0, (%rdi)
+    movq $0, 8(%rdi)
+    movq $0, 16(%rdi)
+    call nrt_string_push_str
+    movq %rbx, %rax
+    popq %rbx
+    ret
+
+/* nrt_string nrt_char_to_string(uint32_t c) */
+    .globl nrt_char_to_string
+nrt_char_to_string:
+    pushq %rbx
+    movq %rdi, %rbx
+    movq $0, (%rdi)
+    movq $0, 8(%rdi)
+    movq $0, 16(%rdi)
+    call nrt_string_push_char
+    movq %rbx, %rax
+    popq %rbx
+    ret
+
+/* nrt_string nrt_u64_to_string(uint64_t n) */
+    .globl nrt_u64_to_string
+nrt_u64_to_string:
+    pushq %rbx
+    subq $32, %rsp
+    movq %rdi, %rbx
+    movq %rsi, %rdi
+    leaq 32(%rsp), %rsi
+    call nrt_format_u64
+    jmp nrt_string_from_formatted
+
+/* nrt_string nrt_i64_to_string(int64_t n) */
+    .globl nrt_i64_to_string
+nrt_i64_to_string:
+    pushq %rbx
+    subq $32, %rsp
+    movq %rdi, %rbx
+    movq %rsi, %rdi
+    leaq 32(%rsp), %rsi
+    call nrt_format_i64
+    /* Shared by the number converters, whose digits start at rax */
+nrt_string_from_formatted:
+    leaq 32(%rsp), %rdx
+    subq %rax, %rdx
+    movq %rax, %rsi
+    movq %rbx, %rdi
+    call nrt_string_from
+    movq %rbx, %rax
+    addq $32, %rsp
+    popq %rbx
+    ret
+
+/* nrt_str nrt_str_slice(nrt_str s, uint64_t start, uint64_t end, const nrt_location *at) */
+    .globl nrt_str_slice
+nrt_str_slice:
+    cmpq %rcx, %rdx
+    ja 3f
+    cmpq %rsi, %rcx
+    ja 3f
+    cmpq %rsi, %rdx
+    je 1f
+    movzbl (%rdi,%rdx), %eax
+    andl $0xC0, %eax
+    cmpl $0x80, %eax
+    je 4f
+1:  cmpq %rsi, %rcx
+    je 2f
+    movzbl (%rdi,%rcx), %eax
+    andl $0xC0, %eax
+    cmpl $0x80, %eax
+    je 4f
+2:  leaq (%rdi,%rdx), %rax
+    subq %rdx, %rcx
+    movq %rcx, %rdx
+    ret
+3:  movq %r8, %rdi
+    leaq nrt_m_slice_bounds(%rip), %rsi
+    movl $nrt_m_slice_bounds_len, %edx
+    subq $8, %rsp
+    call nrt_panic
+4:  movq %r8, %rdi
+    leaq nrt_m_slice_boundary(%rip), %rsi
+    movl $nrt_m_slice_boundary_len, %edx
+    subq $8, %rsp
+    call nrt_panic
+
+/* nrt_string nrt_str_concat(nrt_str a, nrt_str b) */
+    .globl nrt_str_concat
+nrt_str_concat:
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq %rdi, %rbx
+    movq %rsi, %r12
+    movq %rdx, %r13
+    movq %rcx, %r14
+    movq %r8, %r15
+    movq $0, (%rdi)
+    movq $0, 8(%rdi)
+    movq $0, 16(%rdi)
+    leaq (%r13,%r15), %rsi
+    call nrt_string_reserve
+    movq %rbx, %rdi
+    movq %r12, %rsi
+    movq %r13, %rdx
+    call nrt_string_push_str
+    movq %rbx, %rdi
+    movq %r14, %rsi
+    movq %r15, %rdx
+    call nrt_string_push_str
+    movq %rbx, %rax
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    ret
+
+/* bool nrt_str_equal(nrt_str a, nrt_str b) */
+    .globl nrt_str_equal
+nrt_str_equal:
+    xorl %eax, %eax
+    cmpq %rsi, %rcx
+    jne 1f
+    movl $1, %eax
+    testq %rcx, %rcx
+    jz 1f
+    movq %rdx, %rsi
+    repe cmpsb
+    sete %al
+1:  ret
+
+/* int32_t nrt_str_compare(nrt_str a, nrt_str b) */
+    .globl nrt_str_compare
+nrt_str_compare:
+    movq %rsi, %r8
+    movq %rcx, %r9
+    movq %r8, %rcx
+    cmpq %r9, %rcx
+    cmovaq %r9, %rcx
+    movq %rdx, %rsi
+    testq %rcx, %rcx
+    jz 1f
+    /* This compares b with a, so a comes first where b is above it */
+    repe cmpsb
+    je 1f
+    movl $1, %eax
+    movl $-1, %edx
+    cmoval %edx, %eax
+    ret
+1:  xorl %eax, %eax
+    cmpq %r9, %r8
+    je 2f
+    movl $1, %eax
+    movl $-1, %edx
+    cmovbl %edx, %eax
+2:  ret
+
+/* int64_t nrt_str_parse_i64(nrt_str s, const nrt_location *at) */
+    .globl nrt_str_parse_i64
+nrt_str_parse_i64:
+    movq %rdx, %r10
+    xorl %ecx, %ecx
+    xorl %r8d, %r8d
+    testq %rsi, %rsi
+    jz 1f
+    movzbl (%rdi), %eax
+    cmpl $45, %eax
+    jne 2f
+    movl $1, %r8d
+    movl $1, %ecx
+    jmp 1f
+2:  cmpl $43, %eax
+    jne 1f
+    movl $1, %ecx
+1:  cmpq %rsi, %rcx
+    je 5f
+    /* Accumulate negatively so INT64_MIN parses without overflow */
+    xorl %eax, %eax
+3:  cmpq %rsi, %rcx
+    je 4f
+    movzbl (%rdi,%rcx), %edx
+    subl $48, %edx
+    cmpl $9, %edx
+    ja 6f
+    imulq $10, %rax, %rax
+    jo 7f
+    subq %rdx, %rax
+    jo 7f
+    incq %rcx
+    jmp 3b
+4:  testl %r8d, %r8d
+    jnz 8f
+    negq %rax
+    jo 7f
+8:  ret
+5:  leaq nrt_m_parse_empty(%rip), %rsi
+    movl $nrt_m_parse_empty_len, %edx
+    jmp 9f
+6:  leaq nrt_m_parse_digit(%rip), %rsi
+    movl $nrt_m_parse_digit_len, %edx
+    jmp 9f
+7:  leaq nrt_m_parse_range(%rip), %rsi
+    movl $nrt_m_parse_range_len, %edx
+9:  movq %r10, %rdi
+    subq $8, %rsp
+    call nrt_panic
+
+    .section .note.GNU-stack, "", @progbits
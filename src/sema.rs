@@ -0,0 +1,4781 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::consteval;
+use crate::diagnostic::{codes, Diagnostic, Fix, Stage};
+use crate::lexer::{Length, Literal, Span, Type};
+use crate::parser::{ASTNode, BinaryOp, ClosureBody, MatchArm, Pattern, TypeParam, UnaryOp};
+use crate::stdlib;
+
+/// Functions every program can call without an import
+const PRELUDE: [&str; 4] = ["panic", "Some", "Ok", "Err"];
+
+/// Functions and types each built-in module exports
+const MODULES: [(&str, &[&str]); 3] = [
+    ("os", &["exit"]),
+    ("io", &["print", "println", "eprintln"]),
+    ("collections", &["Vec", "Map"]),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IoFunction {
+    Print,
+    Println,
+    Eprintln,
+}
+
+/// Traits a type parameter can be bounded by
+#[derive(Debug, Clone, PartialEq)]
+enum Bound {
+    /// `==` and `!=`
+    Eq,
+    /// The comparison operators; implies `Eq`
+    Ord,
+    /// The arithmetic operators
+    Num,
+    /// Printable with `{}`
+    Display,
+    /// Has a `hash` method, for use as a map key
+    Hash,
+    /// A trait declared in the program
+    Trait(String),
+}
+
+impl Bound {
+    // Looks up one of the built-in traits
+    fn builtin(name: &str) -> Option<Bound> {
+        match name {
+            "Eq" => Some(Bound::Eq),
+            "Ord" => Some(Bound::Ord),
+            "Num" => Some(Bound::Num),
+            "Display" => Some(Bound::Display),
+            "Hash" => Some(Bound::Hash),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Bound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bound::Trait(name) => write!(f, "{name}"),
+            bound => write!(f, "{bound:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum FormatPiece {
+    Text(String),
+    Placeholder,
+}
+
+/// Operations backed by the runtime rather than plain C operators
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Intrinsic {
+    Exit,
+    Panic,
+    StringNew,
+    StringFrom,
+    StringAsStr,
+    StringPushStr,
+    StringPushChar,
+    StrLen,
+    StrSlice,
+    StrConcat,
+    StrEqual,
+    StrCompare,
+    StrParseI64,
+    IntToString,
+    /// `Box::new`, which moves its argument to the heap
+    BoxNew,
+    /// `Buffer::new`, an empty buffer
+    BufferNew,
+    BufferLen,
+    BufferPush,
+    BufferPop,
+    Hash,
+    CharToString,
+    ArrayLen,
+    /// Integer arithmetic that wraps around on overflow
+    Wrapping(BinaryOp),
+    /// Integer arithmetic that gives `None` on overflow or division by zero
+    Checked(BinaryOp),
+    /// Whether integer arithmetic overflows or divides by zero, which is what
+    /// `Checked` is lowered to
+    Overflows(BinaryOp),
+    /// Integer arithmetic that clamps to the type's bounds on overflow
+    Saturating(BinaryOp),
+    /// The value of a `Some` or `Ok`; panics on `None` or `Err`
+    Unwrap,
+    /// The value of a `Some` or `Ok`, or the default given
+    UnwrapOr,
+}
+
+#[derive(Debug, Clone)]
+pub struct TypedExpr {
+    pub kind: TypedExprKind,
+    pub ty: Type,
+}
+
+#[derive(Debug, Clone)]
+pub enum TypedExprKind {
+    Literal(Literal),
+    Variable(String),
+    Binary {
+        op: BinaryOp,
+        lhs: Box<TypedExpr>,
+        rhs: Box<TypedExpr>,
+        span: Span,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<TypedExpr>,
+        span: Span,
+    },
+    Cast(Box<TypedExpr>),
+    /// `type_args` is empty unless `function` is generic, and `span` is
+    /// where the call is
+    Call {
+        function: String,
+        type_args: Vec<Type>,
+        args: Vec<TypedExpr>,
+        span: Span,
+    },
+    /// `span` is where the intrinsic panics from, if it can
+    Intrinsic {
+        intrinsic: Intrinsic,
+        args: Vec<TypedExpr>,
+        span: Span,
+    },
+    Io {
+        function: IoFunction,
+        pieces: Vec<FormatPiece>,
+        args: Vec<TypedExpr>,
+    },
+    /// A trait method; `args` starts with the receiver
+    TraitCall {
+        trait_name: String,
+        method: String,
+        args: Vec<TypedExpr>,
+    },
+    /// Turns a value into a `dyn Trait` of the expression's type
+    ToDyn(Box<TypedExpr>),
+    StructLiteral(Vec<(String, TypedExpr)>),
+    Field {
+        receiver: Box<TypedExpr>,
+        field: String,
+    },
+    /// A named function used as a value
+    Function(String),
+    /// The body returns the closure's return type, which is part of the expression's type
+    Closure {
+        params: Vec<(String, Type)>,
+        captures: Vec<Capture>,
+        body: Vec<TypedStmt>,
+    },
+    /// A call through a value of a function type
+    CallValue {
+        callee: Box<TypedExpr>,
+        args: Vec<TypedExpr>,
+    },
+    ArrayLiteral(Vec<TypedExpr>),
+    /// An array with every element set to one value; the length is part of the type
+    ArrayRepeat(Box<TypedExpr>),
+    /// `span` is where indexing out of bounds panics from
+    Index {
+        array: Box<TypedExpr>,
+        index: Box<TypedExpr>,
+        span: Span,
+    },
+    /// `&place` or `&mut place`; the type says which
+    Ref(Box<TypedExpr>),
+    Deref(Box<TypedExpr>),
+    /// Reads a place whose value owns heap memory, leaving it empty so that
+    /// dropping it frees nothing
+    Move(Box<TypedExpr>),
+    /// `value?`, which returns the `None` or `Err` in `value` from the function,
+    /// whose return type is `returns`; the generator lowers it to a check
+    Try {
+        value: Box<TypedExpr>,
+        returns: Type,
+    },
+}
+
+/// A variable from outside a closure that the closure uses
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capture {
+    pub name: String,
+    pub ty: Type,
+    /// Closures capture by reference unless they are `move` closures
+    pub by_ref: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum TypedStmt {
+    /// A variable without a value is assigned before it is used
+    Let {
+        name: String,
+        var_type: Type,
+        value: Option<TypedExpr>,
+    },
+    Assign {
+        target: TypedExpr,
+        value: TypedExpr,
+    },
+    /// `target op= value` on integers; `target` is evaluated once
+    CompoundAssign {
+        op: BinaryOp,
+        target: TypedExpr,
+        value: TypedExpr,
+        span: Span,
+    },
+    Expr(TypedExpr),
+    Return(Option<TypedExpr>),
+    If {
+        condition: TypedExpr,
+        then_body: Vec<TypedStmt>,
+        else_body: Vec<TypedStmt>,
+    },
+    While {
+        condition: TypedExpr,
+        body: Vec<TypedStmt>,
+    },
+    /// Iterates over the chars of a `str` or the elements of an array
+    For {
+        var: String,
+        iterable: TypedExpr,
+        body: Vec<TypedStmt>,
+    },
+    /// Runs the first arm with a pattern equal to the scrutinee
+    Match {
+        scrutinee: TypedExpr,
+        arms: Vec<TypedArm>,
+    },
+    /// Frees the heap memory a place owns; the generator inserts these
+    Drop(TypedExpr),
+}
+
+/// An arm of a `match`; its patterns are literals. In a match on an `Option`
+/// or `Result`, a pattern is `true` for `Some` or `Ok` and `false` otherwise.
+#[derive(Debug, Clone)]
+pub struct TypedArm {
+    pub patterns: Vec<TypedExpr>,
+    /// Whether the arm has a `_` pattern
+    pub catch_all: bool,
+    /// The variable an `Option` or `Result` pattern binds the value inside to
+    pub binding: Option<String>,
+    pub body: Vec<TypedStmt>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TypedFunction {
+    pub name: String,
+    /// Generic functions are monomorphized by the generator
+    pub type_params: Vec<String>,
+    pub params: Vec<(String, Type)>,
+    pub return_type: Type,
+    pub body: Vec<TypedStmt>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TypedStruct {
+    pub name: String,
+    pub type_params: Vec<String>,
+    pub fields: Vec<(String, Type)>,
+}
+
+/// A trait method's signature, without its `self` receiver. `Self` in it
+/// is the type parameter `Self`.
+#[derive(Debug, Clone)]
+pub struct TraitMethod {
+    pub name: String,
+    pub params: Vec<Type>,
+    pub return_type: Type,
+}
+
+impl TraitMethod {
+    /// Methods that mention `Self` cannot go in a vtable, since a `dyn Trait`
+    /// does not know its type
+    pub fn is_dispatchable(&self) -> bool {
+        let self_type = vec!["Self".to_string()];
+        !self
+            .params
+            .iter()
+            .chain([&self.return_type])
+            .any(|ty| mentions_unbound(ty, &self_type, &HashMap::new()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TypedTrait {
+    pub name: String,
+    pub methods: Vec<TraitMethod>,
+}
+
+/// The methods of an `impl Trait for Type` block, each taking `self` first
+#[derive(Debug, Clone)]
+pub struct TypedImpl {
+    pub trait_name: String,
+    pub ty: Type,
+    pub methods: Vec<TypedFunction>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TypedProgram {
+    pub structs: Vec<TypedStruct>,
+    pub traits: Vec<TypedTrait>,
+    pub impls: Vec<TypedImpl>,
+    pub functions: Vec<TypedFunction>,
+    /// Problems that do not stop the program from compiling, such as unreachable code
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// What a name in a block scope stands for
+#[derive(Clone)]
+enum Binding {
+    /// A variable's unique name and type
+    Variable(String, Type),
+    /// A local constant's value, as a literal
+    Const(TypedExpr),
+}
+
+/// A variable of the function being checked
+struct Variable {
+    unique: String,
+    name: String,
+    /// Where its name is where it is declared
+    span: Span,
+    /// Where `mut` is, if it is declared with it
+    mutable: Option<Span>,
+    /// Whether it is assigned after being initialized; a `mut` variable must be
+    mutated: bool,
+    /// Whether it was declared without a value
+    deferred: bool,
+    /// How many loops and closures it was declared in. A variable declared
+    /// without a value is only initialized by an assignment at the same depth,
+    /// since one in a loop or closure may run more than once.
+    loop_depth: usize,
+    /// The index of the scope it was declared in
+    scope: usize,
+}
+
+/// A borrow of a variable that is still live
+struct Borrow {
+    /// The unique name of the variable borrowed
+    root: String,
+    mutable: bool,
+    /// Where the code that borrows is
+    span: Span,
+    /// The scope whose end the borrow lasts until, or None for a temporary
+    /// borrow that ends with its statement
+    scope: Option<usize>,
+}
+
+/// What definite-assignment analysis knows at a point in a function, about
+/// the variables declared without a value and the values moved out
+#[derive(Clone, Default)]
+struct InitState {
+    /// Those that may not have been assigned yet
+    uninitialized: HashSet<String>,
+    /// Those that may have been assigned already
+    assigned: HashSet<String>,
+    /// The paths of the places that may have been moved out of, such as `a`
+    /// or `a.b`, each starting with a variable's unique name, and where
+    moved: HashMap<String, Span>,
+}
+
+impl InitState {
+    // What is known after one of several paths was taken
+    fn join(mut self, other: InitState) -> InitState {
+        self.uninitialized.extend(other.uninitialized);
+        self.assigned.extend(other.assigned);
+        self.moved.extend(other.moved);
+        self
+    }
+}
+
+/// A closure being checked, and what it has captured so far
+struct ClosureScope {
+    /// The index of the closure's own scope; variables from scopes below it are captures
+    depth: usize,
+    captures: Vec<Capture>,
+    by_value: bool,
+}
+
+/// The types a closure's context expects, where they are known
+struct ClosureHint {
+    params: Vec<Option<Type>>,
+    return_type: Option<Type>,
+}
+
+#[derive(Clone)]
+struct FnSignature {
+    type_params: Vec<(String, Vec<Bound>)>,
+    params: Vec<Type>,
+    return_type: Type,
+}
+
+pub struct Sema {
+    functions: HashMap<String, FnSignature>,
+    structs: HashMap<String, TypedStruct>,
+    traits: HashMap<String, TypedTrait>,
+    impls: Vec<TypedImpl>,
+    /// The type `Self` stands for inside an impl
+    self_type: Option<Type>,
+    /// The type parameters of the function being checked, with their bounds
+    type_params: Vec<(String, Vec<Bound>)>,
+    imports: HashMap<String, String>,
+    /// Top-level constants, as literals
+    consts: HashMap<String, TypedExpr>,
+    scopes: Vec<HashMap<String, Binding>>,
+    binding_counts: HashMap<String, usize>,
+    /// The variables of the function being checked, in the order they are declared
+    variables: Vec<Variable>,
+    loop_depth: usize,
+    init: InitState,
+    borrows: Vec<Borrow>,
+    /// Where the borrows of the statement being checked start in `borrows`
+    statement_start: usize,
+    /// The variables whose values may hold a borrow, with the local variables
+    /// they may point into
+    borrowed_from: HashMap<String, Vec<String>>,
+    /// The variables a closure captures by reference, which are never moved
+    /// since the closure may use them later
+    captured: HashSet<String>,
+    /// The structs of the standard modules written in Nimra. A method of one
+    /// is the module's function named after the struct and the method.
+    module_structs: HashMap<String, String>,
+    return_type: Type,
+    closures: Vec<ClosureScope>,
+    warnings: Vec<Diagnostic>,
+    /// Where the innermost expression being checked that has a place in the
+    /// source is, which an error stopping the check points at
+    span: Span,
+}
+
+impl Sema {
+    pub fn new() -> Self {
+        Sema {
+            functions: HashMap::new(),
+            structs: HashMap::new(),
+            traits: HashMap::new(),
+            impls: Vec::new(),
+            self_type: None,
+            type_params: Vec::new(),
+            imports: HashMap::new(),
+            consts: HashMap::new(),
+            scopes: Vec::new(),
+            binding_counts: HashMap::new(),
+            variables: Vec::new(),
+            loop_depth: 0,
+            init: InitState::default(),
+            borrows: Vec::new(),
+            statement_start: 0,
+            borrowed_from: HashMap::new(),
+            captured: HashSet::new(),
+            module_structs: HashMap::new(),
+            return_type: Type::Void,
+            closures: Vec::new(),
+            warnings: Vec::new(),
+            span: Span::default(),
+        }
+    }
+
+    pub fn check(&mut self, ast: &[ASTNode]) -> Result<TypedProgram, Diagnostic> {
+        self.check_program(ast).map_err(|diagnostic| {
+            // An error that does not point anywhere itself points at what was
+            // being checked when it was found
+            if diagnostic.labels.iter().any(|label| label.primary) {
+                return diagnostic;
+            }
+            let message = label(diagnostic.code);
+            diagnostic.with_primary(self.span, message)
+        })
+    }
+
+    fn check_program(&mut self, ast: &[ASTNode]) -> Result<TypedProgram, Diagnostic> {
+        // Modules written in Nimra are checked along with the program, ahead of it
+        let mut loaded: Vec<&str> = Vec::new();
+        let mut modules = Vec::new();
+        for node in ast {
+            let ASTNode::Import { module, .. } = node else {
+                continue;
+            };
+            if loaded.contains(&module.as_str()) {
+                continue;
+            }
+            loaded.push(module);
+            let items = match stdlib::load(module) {
+                Some(items) => items.map_err(|e| prefixed(format!("In module {module}"), e))?,
+                None => Vec::new(),
+            };
+            for item in items {
+                if let ASTNode::StructDecl { name, .. } = &item {
+                    self.module_structs.insert(name.clone(), module.clone());
+                }
+                modules.push(item);
+            }
+        }
+        let program: Vec<ASTNode> = modules.into_iter().chain(ast.iter().cloned()).collect();
+        let ast = &program[..];
+        // Struct and trait names come first so everything else can refer to any of them
+        for node in ast {
+            match node {
+                ASTNode::StructDecl {
+                    name, type_params, ..
+                } => self.declare_struct(name, type_params)?,
+                ASTNode::TraitDecl { name, .. } => {
+                    if self.traits.contains_key(name) || Bound::builtin(name).is_some() {
+                        return Err(error(
+                            codes::DUPLICATE_DEFINITION,
+                            format!("Trait {name} is declared twice"),
+                        ));
+                    }
+                    self.traits.insert(
+                        name.clone(),
+                        TypedTrait {
+                            name: name.clone(),
+                            methods: Vec::new(),
+                        },
+                    );
+                }
+                _ => {}
+            }
+        }
+        // Constants are evaluated in order, before any type that uses them as an array length
+        for node in ast {
+            if let ASTNode::Const { name, ty, value } = node {
+                if self.consts.contains_key(name) {
+                    return Err(error(
+                        codes::DUPLICATE_DEFINITION,
+                        format!("Constant {name} is declared twice"),
+                    ));
+                }
+                let value = self.check_const(name, ty, value)?;
+                self.consts.insert(name.clone(), value);
+            }
+        }
+        for node in ast {
+            match node {
+                ASTNode::Import { module, name } => self.declare_import(module, name)?,
+                ASTNode::StructDecl { name, fields, .. } => self.define_struct(name, fields)?,
+                ASTNode::TraitDecl { name, methods } => self.define_trait(name, methods)?,
+                ASTNode::Impl { .. } | ASTNode::Const { .. } => {}
+                ASTNode::FnDecl {
+                    name,
+                    type_params,
+                    args,
+                    return_type,
+                    span,
+                    ..
+                } => self.declare_function(name, type_params, args, return_type, *span)?,
+                _ => return Err(
+                    error(codes::INVALID_OPERATION, "Only imports, constants, structs, traits, impls and functions may appear at top level")
+                ),
+            }
+        }
+        // Impls come after every trait is defined
+        let mut impl_signatures = Vec::new();
+        for node in ast {
+            if let ASTNode::Impl {
+                trait_name,
+                ty,
+                methods,
+                span,
+            } = node
+            {
+                impl_signatures.push(self.declare_impl(trait_name, ty, methods, *span)?);
+            }
+        }
+        if !self.functions.contains_key("main") {
+            return Err(error(codes::NO_MAIN, "No main function")
+                .with_note("a program starts by calling `void fn main()`"));
+        }
+        let mut functions = Vec::new();
+        for node in ast {
+            if let ASTNode::FnDecl {
+                name,
+                args,
+                body,
+                span,
+                ..
+            } = node
+            {
+                let signature = self.functions[name].clone();
+                functions.push(self.check_function(name, signature, args, body, *span)?);
+            }
+        }
+        let impl_nodes = ast.iter().filter_map(|node| match node {
+            ASTNode::Impl { methods, .. } => Some(methods),
+            _ => None,
+        });
+        for (index, (methods, signatures)) in impl_nodes.zip(impl_signatures).enumerate() {
+            self.self_type = Some(self.impls[index].ty.clone());
+            let mut typed_methods = Vec::new();
+            for (method, signature) in methods.iter().zip(signatures) {
+                if let ASTNode::FnDecl {
+                    name,
+                    args,
+                    body,
+                    span,
+                    ..
+                } = method
+                {
+                    typed_methods.push(self.check_function(name, signature, args, body, *span)?);
+                }
+            }
+            self.self_type = None;
+            self.impls[index].methods = typed_methods;
+        }
+        let mut structs = Vec::new();
+        let mut traits = Vec::new();
+        for node in ast {
+            match node {
+                ASTNode::StructDecl { name, .. } => structs.push(self.structs[name].clone()),
+                ASTNode::TraitDecl { name, .. } => traits.push(self.traits[name].clone()),
+                _ => {}
+            }
+        }
+        Ok(TypedProgram {
+            structs,
+            traits,
+            impls: self.impls.clone(),
+            functions,
+            warnings: std::mem::take(&mut self.warnings),
+        })
+    }
+
+    fn define_trait(&mut self, name: &str, methods: &[ASTNode]) -> Result<(), Diagnostic> {
+        let mut typed_methods: Vec<TraitMethod> = Vec::new();
+        for method in methods {
+            let ASTNode::FnDecl {
+                name: method,
+                type_params,
+                args,
+                return_type,
+                ..
+            } = method
+            else {
+                continue;
+            };
+            if typed_methods.iter().any(|other| other.name == *method) {
+                return Err(error(
+                    codes::DUPLICATE_DEFINITION,
+                    format!("Method {method} of trait {name} is declared twice"),
+                ));
+            }
+            if !type_params.is_empty() {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    format!("Method {method} of trait {name} cannot be generic"),
+                ));
+            }
+            if !matches!(args.first(), Some(ASTNode::Param { name, .. }) if name == "self") {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    format!(
+                        "Method {method} of trait {name} must take self as its first parameter"
+                    ),
+                ));
+            }
+            self.self_type = Some(Type::Param("Self".to_string()));
+            let mut params = Vec::new();
+            for arg in &args[1..] {
+                if let ASTNode::Param { param_type, .. } = arg {
+                    params.push(self.resolve_type(param_type));
+                }
+            }
+            let return_type = self.resolve_type(return_type);
+            self.self_type = None;
+            typed_methods.push(TraitMethod {
+                name: method.clone(),
+                params: params.into_iter().collect::<Result<_, _>>()?,
+                return_type: return_type?,
+            });
+        }
+        if let Some(definition) = self.traits.get_mut(name) {
+            definition.methods = typed_methods;
+        }
+        Ok(())
+    }
+
+    // Checks an impl against its trait and returns the signature of each method
+    fn declare_impl(
+        &mut self,
+        trait_name: &str,
+        ty: &Type,
+        methods: &[ASTNode],
+        span: Span,
+    ) -> Result<Vec<FnSignature>, Diagnostic> {
+        let Some(definition) = self.traits.get(trait_name).cloned() else {
+            // With a single module the orphan rule comes down to this
+            if Bound::builtin(trait_name).is_some() {
+                return Err(error(codes::UNSATISFIED_BOUND, format!(
+                    "{trait_name} is built in; only traits declared in this program can be implemented"
+                )));
+            }
+            return Err(error(
+                codes::UNKNOWN_ITEM,
+                format!("Unknown trait {trait_name}"),
+            ));
+        };
+        let ty = self.resolve_type(ty)?;
+        if matches!(ty, Type::Void | Type::Dyn(_)) {
+            return Err(error(
+                codes::UNSATISFIED_BOUND,
+                format!("Cannot implement {trait_name} for {ty}"),
+            ));
+        }
+        if self
+            .impls
+            .iter()
+            .any(|other| other.trait_name == trait_name && other.ty == ty)
+        {
+            return Err(error(
+                codes::DUPLICATE_DEFINITION,
+                format!("Conflicting implementations of {trait_name} for {ty}"),
+            )
+            .with_primary(span, "implemented again here"));
+        }
+        self.self_type = Some(ty.clone());
+        let signatures = self.impl_signatures(&definition, &ty, methods);
+        self.self_type = None;
+        self.impls.push(TypedImpl {
+            trait_name: trait_name.to_string(),
+            ty,
+            methods: Vec::new(),
+        });
+        signatures
+    }
+
+    fn impl_signatures(
+        &mut self,
+        definition: &TypedTrait,
+        ty: &Type,
+        methods: &[ASTNode],
+    ) -> Result<Vec<FnSignature>, Diagnostic> {
+        let trait_name = &definition.name;
+        let self_binding = HashMap::from([("Self".to_string(), ty.clone())]);
+        let mut signatures = Vec::new();
+        let mut implemented: Vec<&str> = Vec::new();
+        for method in methods {
+            let ASTNode::FnDecl {
+                name,
+                type_params,
+                args,
+                return_type,
+                ..
+            } = method
+            else {
+                continue;
+            };
+            let Some(expected) = definition.methods.iter().find(|m| m.name == *name) else {
+                return Err(error(
+                    codes::UNKNOWN_ITEM,
+                    format!("{name} is not a method of trait {trait_name}"),
+                ));
+            };
+            if implemented.contains(&name.as_str()) {
+                return Err(error(
+                    codes::DUPLICATE_DEFINITION,
+                    format!("Method {name} is implemented twice for {ty}"),
+                ));
+            }
+            implemented.push(name);
+            let mut params = Vec::new();
+            for arg in args {
+                if let ASTNode::Param { param_type, .. } = arg {
+                    params.push(self.resolve_type(param_type)?);
+                }
+            }
+            let return_type = self.resolve_type(return_type)?;
+            let takes_self =
+                matches!(args.first(), Some(ASTNode::Param { name, .. }) if name == "self");
+            let expected_params: Vec<Type> = expected
+                .params
+                .iter()
+                .map(|param| substitute(param, &self_binding))
+                .collect();
+            if !type_params.is_empty()
+                || !takes_self
+                || params[1..] != expected_params[..]
+                || return_type != substitute(&expected.return_type, &self_binding)
+            {
+                return Err(error(
+                    codes::MISMATCHED_TYPES,
+                    format!(
+                    "Method {name} of the impl of {trait_name} for {ty} does not match the trait"
+                ),
+                ));
+            }
+            signatures.push(FnSignature {
+                type_params: Vec::new(),
+                params,
+                return_type,
+            });
+        }
+        if let Some(missing) = definition
+            .methods
+            .iter()
+            .find(|m| !implemented.contains(&m.name.as_str()))
+        {
+            return Err(error(
+                codes::MISSING_ITEM,
+                format!(
+                    "Missing method {} in the impl of {trait_name} for {ty}",
+                    missing.name
+                ),
+            ));
+        }
+        Ok(signatures)
+    }
+
+    fn declare_struct(&mut self, name: &str, type_params: &[TypeParam]) -> Result<(), Diagnostic> {
+        if self.structs.contains_key(name) {
+            return Err(error(
+                codes::DUPLICATE_DEFINITION,
+                format!("Struct {name} is declared twice"),
+            ));
+        }
+        if matches!(name, "Box" | "Buffer" | "Option" | "Result") {
+            return Err(error(
+                codes::INVALID_TYPE,
+                format!("{name} is a built-in type"),
+            ));
+        }
+        if let Some(param) = type_params.iter().find(|param| !param.bounds.is_empty()) {
+            return Err(error(
+                codes::INVALID_TYPE,
+                format!(
+                    "Type parameter {} of struct {name} cannot have bounds",
+                    param.name
+                ),
+            ));
+        }
+        self.structs.insert(
+            name.to_string(),
+            TypedStruct {
+                name: name.to_string(),
+                type_params: type_params.iter().map(|param| param.name.clone()).collect(),
+                fields: Vec::new(),
+            },
+        );
+        Ok(())
+    }
+
+    fn define_struct(&mut self, name: &str, fields: &[(String, Type)]) -> Result<(), Diagnostic> {
+        self.type_params = self.structs[name]
+            .type_params
+            .iter()
+            .map(|param| (param.clone(), Vec::new()))
+            .collect();
+        let mut typed_fields: Vec<(String, Type)> = Vec::new();
+        for (field, ty) in fields {
+            if typed_fields.iter().any(|(other, _)| other == field) {
+                return Err(error(
+                    codes::DUPLICATE_DEFINITION,
+                    format!("Field {field} of struct {name} is declared twice"),
+                ));
+            }
+            let ty = self.resolve_type(ty)?;
+            if ty == Type::Void {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    format!("Field {field} of struct {name} cannot have type void"),
+                ));
+            }
+            if contains_ref(&ty) {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    format!("Field {field} of struct {name} cannot hold a reference"),
+                ));
+            }
+            typed_fields.push((field.clone(), ty));
+        }
+        self.type_params.clear();
+        if let Some(definition) = self.structs.get_mut(name) {
+            definition.fields = typed_fields;
+        }
+        Ok(())
+    }
+
+    // Turns a type as written into one that refers to a declared struct or
+    // a type parameter in scope
+    fn resolve_type(&self, ty: &Type) -> Result<Type, Diagnostic> {
+        if let Type::Array(element, len) = ty {
+            let element = self.resolve_type(element)?;
+            if element == Type::Void {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    format!("{ty} cannot have elements of type void"),
+                ));
+            }
+            let len = match len {
+                Length::Known(len) => *len,
+                Length::Const(name) => self.array_length(name)?,
+            };
+            if len == 0 {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    "Arrays must have at least one element",
+                ));
+            }
+            return Ok(Type::Array(Box::new(element), Length::Known(len)));
+        }
+        if let Type::Ref(inner, mutable) = ty {
+            let inner = self.resolve_type(inner)?;
+            if inner == Type::Void {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    "Cannot have a reference to void",
+                ));
+            }
+            return Ok(Type::Ref(Box::new(inner), *mutable));
+        }
+        if let Type::Fn(params, return_type) = ty {
+            let params = params
+                .iter()
+                .map(|param| self.resolve_type(param))
+                .collect::<Result<Vec<_>, _>>()?;
+            if params.contains(&Type::Void) {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    format!("{ty} cannot take a parameter of type void"),
+                ));
+            }
+            return Ok(Type::Fn(params, Box::new(self.resolve_type(return_type)?)));
+        }
+        let Type::Struct(name, args) = ty else {
+            if let Type::Dyn(name) = ty {
+                if !self.traits.contains_key(name) {
+                    return Err(error(codes::UNKNOWN_ITEM, format!("Unknown trait {name}")));
+                }
+            }
+            return Ok(ty.clone());
+        };
+        if name == "Self" && args.is_empty() {
+            return self.self_type.clone().ok_or(error(
+                codes::INVALID_TYPE,
+                "Self can only be used in an impl",
+            ));
+        }
+        if args.is_empty() && self.type_params.iter().any(|(param, _)| param == name) {
+            return Ok(Type::Param(name.clone()));
+        }
+        if name == "Box" {
+            let [inner] = &args[..] else {
+                return Err(error(
+                    codes::WRONG_ARGUMENT_COUNT,
+                    format!("Box takes 1 type argument(s) but {} were given", args.len()),
+                ));
+            };
+            let inner = self.resolve_type(inner)?;
+            if inner == Type::Void {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    "Cannot box a value of type void",
+                ));
+            }
+            if contains_ref(&inner) {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    format!("{ty} cannot hold a reference"),
+                ));
+            }
+            return Ok(Type::Box(Box::new(inner)));
+        }
+        if name == "Option" {
+            let [inner] = &args[..] else {
+                return Err(error(
+                    codes::WRONG_ARGUMENT_COUNT,
+                    format!(
+                        "Option takes 1 type argument(s) but {} were given",
+                        args.len()
+                    ),
+                ));
+            };
+            let inner = self.resolve_type(inner)?;
+            if inner == Type::Void {
+                return Err(error(codes::INVALID_TYPE, "Cannot have an Option of void"));
+            }
+            return Ok(Type::Option(Box::new(inner)));
+        }
+        if name == "Result" {
+            let [value, error_type] = &args[..] else {
+                return Err(error(
+                    codes::WRONG_ARGUMENT_COUNT,
+                    format!(
+                        "Result takes 2 type argument(s) but {} were given",
+                        args.len()
+                    ),
+                ));
+            };
+            let error_type = self.resolve_type(error_type)?;
+            if error_type == Type::Void {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    "The error type of a Result cannot be void",
+                ));
+            }
+            return Ok(Type::Result(
+                Box::new(self.resolve_type(value)?),
+                Box::new(error_type),
+            ));
+        }
+        if name == "Buffer" {
+            let [element] = &args[..] else {
+                return Err(error(
+                    codes::WRONG_ARGUMENT_COUNT,
+                    format!(
+                        "Buffer takes 1 type argument(s) but {} were given",
+                        args.len()
+                    ),
+                ));
+            };
+            let element = self.resolve_type(element)?;
+            self.check_buffer_element(&element)?;
+            return Ok(Type::Buffer(Box::new(element)));
+        }
+        let definition = self
+            .structs
+            .get(name)
+            .ok_or(error(codes::UNKNOWN_ITEM, format!("Unknown type {name}")))?;
+        if definition.type_params.len() != args.len() {
+            return Err(error(
+                codes::WRONG_ARGUMENT_COUNT,
+                format!(
+                    "{name} takes {} type argument(s) but {} were given",
+                    definition.type_params.len(),
+                    args.len()
+                ),
+            ));
+        }
+        let args: Vec<Type> = args
+            .iter()
+            .map(|arg| self.resolve_type(arg))
+            .collect::<Result<_, _>>()?;
+        if args.iter().any(contains_ref) {
+            return Err(error(
+                codes::INVALID_TYPE,
+                format!("Type arguments of {name} cannot hold references"),
+            ));
+        }
+        // The containers of standard modules keep their elements in buffers
+        if self.module_structs.contains_key(name)
+            && args.iter().any(|arg| needs_drop(arg, &self.structs))
+        {
+            return Err(error(
+                codes::INVALID_TYPE,
+                format!("{ty} cannot hold values that own heap memory"),
+            ));
+        }
+        Ok(Type::Struct(name.clone(), args))
+    }
+
+    // Buffers copy their elements around as they grow, so the elements must
+    // be plain values
+    fn check_buffer_element(&self, element: &Type) -> Result<(), Diagnostic> {
+        if *element == Type::Void {
+            return Err(error(
+                codes::INVALID_TYPE,
+                "Buffers cannot have elements of type void",
+            ));
+        }
+        if contains_ref(element) {
+            return Err(error(
+                codes::INVALID_TYPE,
+                format!("Buffer<{element}> cannot hold a reference"),
+            ));
+        }
+        if needs_drop(element, &self.structs) {
+            return Err(error(
+                codes::INVALID_TYPE,
+                format!("Buffer<{element}> cannot hold values that own heap memory"),
+            ));
+        }
+        Ok(())
+    }
+
+    // Looks up the constant an array type uses as its length
+    fn array_length(&self, name: &str) -> Result<u64, Diagnostic> {
+        let value = match self.scopes.iter().rev().find_map(|scope| scope.get(name)) {
+            Some(Binding::Const(value)) => value,
+            Some(Binding::Variable(..)) => {
+                return Err(error(
+                    codes::NOT_CONSTANT,
+                    format!("Array length {name} is a variable, not a constant"),
+                ))
+            }
+            None => self.consts.get(name).ok_or(error(
+                codes::NOT_CONSTANT,
+                format!("Unknown constant {name}"),
+            ))?,
+        };
+        match value.kind {
+            TypedExprKind::Literal(Literal::Number(len))
+                if value.ty.is_integer() && (len >= 0 || !value.ty.is_signed()) =>
+            {
+                Ok(len as u64)
+            }
+            _ => Err(error(
+                codes::NOT_CONSTANT,
+                format!("Array length {name} must be a non-negative integer"),
+            )),
+        }
+    }
+
+    // Checks a constant's value and evaluates it to a literal
+    fn check_const(
+        &mut self,
+        name: &str,
+        ty: &Type,
+        value: &ASTNode,
+    ) -> Result<TypedExpr, Diagnostic> {
+        let ty = self.resolve_type(ty)?;
+        if !(ty.is_integer() || matches!(ty, Type::Bool | Type::Char | Type::Str)) {
+            return Err(error(
+                codes::NOT_CONSTANT,
+                format!("Constant {name} cannot have type {ty}"),
+            ));
+        }
+        let value = self.check_expr_as(value, &ty)?;
+        let value = consteval::eval(&value)
+            .map_err(|e| error(codes::NOT_CONSTANT, format!("In constant {name}: {e}")))?;
+        Ok(TypedExpr {
+            kind: TypedExprKind::Literal(value.to_literal()),
+            ty,
+        })
+    }
+
+    fn satisfies(&self, ty: &Type, bound: &Bound) -> bool {
+        match (ty, bound) {
+            (Type::Param(name), _) => self
+                .type_params
+                .iter()
+                .find(|(param, _)| param == name)
+                .is_some_and(|(_, bounds)| {
+                    bounds
+                        .iter()
+                        .any(|b| b == bound || *b == Bound::Ord && *bound == Bound::Eq)
+                }),
+            (Type::Dyn(name), Bound::Trait(trait_name)) => name == trait_name,
+            (ty, Bound::Trait(trait_name)) => self
+                .impls
+                .iter()
+                .any(|other| other.trait_name == *trait_name && other.ty == *ty),
+            (ty, bound) => self.satisfies_builtin(ty, bound),
+        }
+    }
+
+    fn satisfies_builtin(&self, ty: &Type, bound: &Bound) -> bool {
+        match ty {
+            ty if ty.is_integer() => true,
+            Type::Char | Type::Str | Type::String => *bound != Bound::Num,
+            Type::Bool => matches!(bound, Bound::Eq | Bound::Display | Bound::Hash),
+            _ => false,
+        }
+    }
+
+    fn require(&self, ty: &Type, bound: &Bound) -> Result<(), Diagnostic> {
+        if self.satisfies(ty, bound) {
+            Ok(())
+        } else {
+            Err(error(
+                codes::UNSATISFIED_BOUND,
+                format!("{ty} does not implement {bound}"),
+            ))
+        }
+    }
+
+    fn declare_import(&mut self, module: &str, name: &str) -> Result<(), Diagnostic> {
+        let (_, exports) = MODULES.iter().find(|(m, _)| *m == module).ok_or(error(
+            codes::UNKNOWN_ITEM,
+            format!("Unknown module {module}"),
+        ))?;
+        if !exports.contains(&name) {
+            return Err(error(
+                codes::UNKNOWN_ITEM,
+                format!("Module {module} does not export {name}"),
+            ));
+        }
+        self.imports.insert(name.to_string(), module.to_string());
+        Ok(())
+    }
+
+    fn declare_function(
+        &mut self,
+        name: &str,
+        type_params: &[TypeParam],
+        args: &[ASTNode],
+        return_type: &Type,
+        span: Span,
+    ) -> Result<(), Diagnostic> {
+        if self.functions.contains_key(name) {
+            return Err(error(
+                codes::DUPLICATE_DEFINITION,
+                format!("Function {name} is declared twice"),
+            )
+            .with_primary(span, label(codes::DUPLICATE_DEFINITION)));
+        }
+        let mut bounded = Vec::new();
+        for param in type_params {
+            if bounded.iter().any(|(other, _)| *other == param.name) {
+                return Err(error(
+                    codes::DUPLICATE_DEFINITION,
+                    format!("Type parameter {} of {name} is declared twice", param.name),
+                ));
+            }
+            let mut bounds = Vec::new();
+            for bound in &param.bounds {
+                bounds.push(match Bound::builtin(bound) {
+                    Some(bound) => bound,
+                    None if self.traits.contains_key(bound) => Bound::Trait(bound.clone()),
+                    None => {
+                        return Err(error(codes::UNKNOWN_ITEM, format!("Unknown bound {bound}")))
+                    }
+                });
+            }
+            bounded.push((param.name.clone(), bounds));
+        }
+        self.type_params = bounded;
+        let params = args
+            .iter()
+            .map(|arg| match arg {
+                ASTNode::Param { param_type, .. } => self.resolve_type(param_type),
+                _ => Ok(Type::Void),
+            })
+            .collect::<Result<_, _>>();
+        let return_type = self.resolve_type(return_type);
+        let type_params = std::mem::take(&mut self.type_params);
+        if name == "main" {
+            // A main that returns an error prints it and exits with status 1
+            let returns_error = matches!(&return_type, Ok(Type::Result(value, error))
+                if **value == Type::Void && self.satisfies(error, &Bound::Display));
+            if !type_params.is_empty()
+                || !args.is_empty()
+                || !(returns_error || return_type == Ok(Type::Void))
+            {
+                return Err(
+                    error(codes::INVALID_TYPE, "main must be declared as `void fn main()` or `Result<void, E> fn main()` with a printable E")
+                );
+            }
+        }
+        self.functions.insert(
+            name.to_string(),
+            FnSignature {
+                type_params,
+                params: params?,
+                return_type: return_type?,
+            },
+        );
+        Ok(())
+    }
+
+    fn check_function(
+        &mut self,
+        name: &str,
+        signature: FnSignature,
+        args: &[ASTNode],
+        body: &[ASTNode],
+        span: Span,
+    ) -> Result<TypedFunction, Diagnostic> {
+        let param_types = signature.params.clone();
+        let return_type = signature.return_type.clone();
+        self.type_params = signature.type_params;
+        self.binding_counts.clear();
+        self.variables.clear();
+        self.init = InitState::default();
+        self.borrows.clear();
+        self.borrowed_from.clear();
+        self.captured.clear();
+        self.return_type = return_type.clone();
+        self.scopes.push(HashMap::new());
+        let mut params = Vec::new();
+        for (arg, param_type) in args.iter().zip(param_types) {
+            if let ASTNode::Param {
+                name,
+                mutable,
+                span,
+                ..
+            } = arg
+            {
+                if param_type == Type::Void {
+                    return Err(error(
+                        codes::INVALID_TYPE,
+                        format!("Parameter {name} cannot have type void"),
+                    ));
+                }
+                params.push((
+                    self.bind(name, param_type.clone(), *mutable, *span),
+                    param_type,
+                ));
+            }
+        }
+        let warned = self.warnings.len();
+        let body = self.check_block_in_scope(body).and_then(|body| {
+            if return_type != Type::Void && !diverges(&body) {
+                return Err(error(codes::MISSING_RETURN, "Not all paths return a value")
+                    .with_primary(span, label(codes::MISSING_RETURN)));
+            }
+            let unneeded = self.variables.iter().find(|v| !v.mutated).and_then(|v| {
+                let mutable = v.mutable?;
+                // `mut` goes with the space after it, up to the name
+                let len = if mutable.line == v.span.line {
+                    v.span.column - mutable.column
+                } else {
+                    "mut ".len()
+                };
+                let message = format!("Variable {} does not need to be mutable", v.name);
+                Some(
+                    error(codes::UNNEEDED_MUT, message)
+                        .with_primary(mutable, format!("{} is never changed", v.name))
+                        .with_fix(Fix {
+                            message: "remove the `mut`".to_string(),
+                            span: mutable,
+                            len,
+                            replacement: String::new(),
+                        }),
+                )
+            });
+            match unneeded {
+                Some(diagnostic) => Err(diagnostic),
+                None => Ok(body),
+            }
+        });
+        self.scopes.pop();
+        for warning in &mut self.warnings[warned..] {
+            warning.message = format!("In function {name}: {}", warning.message);
+        }
+        let type_params = std::mem::take(&mut self.type_params);
+        Ok(TypedFunction {
+            name: name.to_string(),
+            type_params: type_params.into_iter().map(|(param, _)| param).collect(),
+            params,
+            return_type,
+            body: body.map_err(|e| prefixed(format!("In function {name}"), e))?,
+        })
+    }
+
+    // Introduces a binding in the innermost scope and returns its unique name.
+    // Shadowing bindings are renamed `<n>_<name>`, which no identifier can spell.
+    fn bind(&mut self, name: &str, ty: Type, mutable: Option<Span>, span: Span) -> String {
+        let count = self.binding_counts.entry(name.to_string()).or_insert(0);
+        *count += 1;
+        let unique = if *count == 1 {
+            name.to_string()
+        } else {
+            format!("{count}_{name}")
+        };
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), Binding::Variable(unique.clone(), ty));
+        }
+        self.variables.push(Variable {
+            unique: unique.clone(),
+            name: name.to_string(),
+            span,
+            mutable,
+            mutated: false,
+            deferred: false,
+            loop_depth: self.loop_depth,
+            scope: self.scopes.len() - 1,
+        });
+        unique
+    }
+
+    fn binding(&self, name: &str) -> Option<&Binding> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    // Reads a local variable or constant, which must be initialized
+    fn lookup(&mut self, name: &str) -> Result<TypedExpr, Diagnostic> {
+        let value = self.resolve_variable(name)?;
+        match &value.kind {
+            TypedExprKind::Variable(unique) if self.init.uninitialized.contains(unique) => {
+                Err(error(
+                    codes::UNINITIALIZED_VARIABLE,
+                    format!("Variable {name} is used before it is initialized"),
+                ))
+            }
+            TypedExprKind::Variable(unique) => {
+                match self.borrows.iter().find(|b| b.root == *unique && b.mutable) {
+                    Some(live) => Err(error(
+                        codes::BORROW_CONFLICT,
+                        format!("Cannot use {name} while it is borrowed as mutable"),
+                    )
+                    .with_secondary(live.span, "borrowed here")),
+                    None => Ok(value),
+                }
+            }
+            _ => Ok(value),
+        }
+    }
+
+    // Records an assignment to a variable. The first assignment to one
+    // declared without a value initializes it; any other needs it to be `mut`.
+    fn assign(&mut self, name: &str, unique: &str) -> Result<(), Diagnostic> {
+        let initializes =
+            self.init.uninitialized.contains(unique) && !self.init.assigned.contains(unique);
+        if let Some(live) = self.borrows.iter().find(|borrow| borrow.root == unique) {
+            return Err(error(
+                codes::BORROW_CONFLICT,
+                format!("Cannot assign to {name} while it is borrowed"),
+            )
+            .with_secondary(live.span, "borrowed here"));
+        }
+        let loop_depth = self.loop_depth;
+        let variable = self.variable(name, unique)?;
+        if !(initializes && variable.loop_depth == loop_depth) {
+            if variable.mutable.is_none() && variable.deferred {
+                return Err(immutable(
+                    format!("Cannot assign twice to immutable variable {name}"),
+                    variable,
+                ));
+            }
+            if variable.mutable.is_none() {
+                return Err(immutable(
+                    format!("Cannot assign to immutable variable {name}"),
+                    variable,
+                ));
+            }
+            variable.mutated = true;
+        }
+        self.init.uninitialized.remove(unique);
+        self.init.assigned.insert(unique.to_string());
+        Ok(())
+    }
+
+    // Records that a variable is changed in place, which needs it to be `mut`
+    fn mutate(&mut self, name: &str, unique: &str) -> Result<(), Diagnostic> {
+        if let Some(live) = self.borrows.iter().find(|borrow| borrow.root == unique) {
+            return Err(error(
+                codes::BORROW_CONFLICT,
+                format!("Cannot mutate {name} while it is borrowed"),
+            )
+            .with_secondary(live.span, "borrowed here"));
+        }
+        let variable = self.variable(name, unique)?;
+        if variable.mutable.is_none() {
+            return Err(immutable(
+                format!("Cannot mutate immutable variable {name}"),
+                variable,
+            ));
+        }
+        variable.mutated = true;
+        Ok(())
+    }
+
+    fn variable(&mut self, name: &str, unique: &str) -> Result<&mut Variable, Diagnostic> {
+        self.variables
+            .iter_mut()
+            .find(|variable| variable.unique == unique)
+            .ok_or(error(
+                codes::UNKNOWN_VARIABLE,
+                format!("Unknown variable {name}"),
+            ))
+    }
+
+    // The name in scope closest to an unknown one, if it is close enough to
+    // be a misspelling of it
+    fn similar_name(&self, name: &str) -> Option<String> {
+        let limit = (name.chars().count() / 3).max(1);
+        self.scopes
+            .iter()
+            .flat_map(|scope| scope.keys())
+            .map(|candidate| (edit_distance(name, candidate), candidate))
+            .filter(|(distance, _)| *distance <= limit)
+            .min()
+            .map(|(_, candidate)| candidate.clone())
+    }
+
+    // Finds a local variable or constant. A variable becomes a capture of
+    // every closure entered since it was bound.
+    fn resolve_variable(&mut self, name: &str) -> Result<TypedExpr, Diagnostic> {
+        let (depth, (unique, ty)) = match self
+            .scopes
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(depth, scope)| Some((depth, scope.get(name)?.clone())))
+            .ok_or_else(|| {
+                let diagnostic = error(codes::UNKNOWN_VARIABLE, format!("Unknown variable {name}"));
+                match self.similar_name(name) {
+                    Some(similar) => {
+                        diagnostic.with_note(format!("a variable named {similar} is in scope"))
+                    }
+                    None => diagnostic,
+                }
+            })? {
+            (_, Binding::Const(value)) => return Ok(value),
+            (depth, Binding::Variable(unique, ty)) => (depth, (unique, ty)),
+        };
+        for closure in self.closures.iter_mut().filter(|c| c.depth > depth) {
+            if !closure
+                .captures
+                .iter()
+                .any(|capture| capture.name == unique)
+            {
+                closure.captures.push(Capture {
+                    name: unique.clone(),
+                    ty: ty.clone(),
+                    by_ref: !closure.by_value,
+                });
+                if !closure.by_value {
+                    self.captured.insert(unique.clone());
+                }
+            }
+        }
+        Ok(TypedExpr {
+            kind: TypedExprKind::Variable(unique),
+            ty,
+        })
+    }
+
+    // Leaves the innermost scope, ending the borrows that last until its end
+    fn pop_scope(&mut self) {
+        self.scopes.pop();
+        let depth = self.scopes.len();
+        self.borrows
+            .retain(|borrow| borrow.scope.is_none_or(|scope| scope < depth));
+    }
+
+    // Records a borrow of a variable. A variable is borrowed either mutably
+    // once or shared any number of times.
+    fn borrow(&mut self, unique: &str, mutable: bool) -> Result<(), Diagnostic> {
+        self.check_borrow(unique, mutable)?;
+        self.borrows.push(Borrow {
+            root: unique.to_string(),
+            mutable,
+            span: self.span,
+            scope: None,
+        });
+        Ok(())
+    }
+
+    fn check_borrow(&self, unique: &str, mutable: bool) -> Result<(), Diagnostic> {
+        if let Some(live) = self
+            .borrows
+            .iter()
+            .find(|borrow| borrow.root == unique && (mutable || borrow.mutable))
+        {
+            let name = self.variable_name(unique);
+            return Err(error(
+                codes::BORROW_CONFLICT,
+                if live.mutable && mutable {
+                    format!("Cannot borrow {name} as mutable more than once at a time")
+                } else if live.mutable {
+                    format!("Cannot borrow {name} while it is borrowed as mutable")
+                } else {
+                    format!("Cannot borrow {name} as mutable while it is borrowed")
+                },
+            )
+            .with_secondary(live.span, "borrowed here"));
+        }
+        Ok(())
+    }
+
+    // Ends the temporary borrows of the statement being checked
+    fn end_temporaries(&mut self) {
+        let mut i = 0;
+        let start = self.statement_start;
+        self.borrows.retain(|borrow| {
+            i += 1;
+            i <= start || borrow.scope.is_some()
+        });
+    }
+
+    // Makes the temporary borrows of the statement being checked last as long
+    // as the variable `holder`, whose new value may keep them
+    fn hold_borrows(&mut self, holder: &str) -> Result<(), Diagnostic> {
+        let scope = self.variable_scope(holder);
+        for i in self.statement_start..self.borrows.len() {
+            if self.borrows[i].scope.is_some() {
+                continue;
+            }
+            if self.variable_scope(&self.borrows[i].root) > scope {
+                let name = self.variable_name(&self.borrows[i].root);
+                return Err(error(
+                    codes::DANGLING_REFERENCE,
+                    format!("{name} does not live long enough"),
+                ));
+            }
+            self.borrows[i].scope = Some(scope);
+        }
+        Ok(())
+    }
+
+    // Moves a value out of the place it is read from, if it owns heap memory.
+    // The place cannot be used again until it is assigned a new value.
+    fn move_out(&mut self, value: TypedExpr) -> Result<TypedExpr, Diagnostic> {
+        if !needs_drop(&value.ty, &self.structs) {
+            return Ok(value);
+        }
+        let Some(path) = move_path(&value) else {
+            return Ok(value);
+        };
+        let mut segments = path.split('.');
+        let root = segments.next().unwrap_or_default().to_string();
+        let name = self.variable_name(&root);
+        for segment in segments {
+            match segment {
+                "[]" => {
+                    return Err(error(
+                        codes::CANNOT_MOVE,
+                        format!("Cannot move out of an element of {name}"),
+                    ))
+                }
+                "*" => {
+                    return Err(error(
+                        codes::CANNOT_MOVE,
+                        format!(
+                            "Cannot move out of {}, which is behind a reference or box",
+                            self.path_name(&path)
+                        ),
+                    ))
+                }
+                _ => {}
+            }
+        }
+        if let Some(live) = self.borrows.iter().find(|borrow| borrow.root == root) {
+            return Err(error(
+                codes::BORROW_CONFLICT,
+                format!("Cannot move {name} while it is borrowed"),
+            )
+            .with_secondary(live.span, "borrowed here"));
+        }
+        if self.captured.contains(&root) {
+            return Err(error(
+                codes::CANNOT_MOVE,
+                format!("Cannot move {name}, since a closure captures it by reference"),
+            ));
+        }
+        if let Some(closure) = self.closures.last() {
+            if self.variable_scope(&root) < closure.depth {
+                return Err(error(
+                    codes::CANNOT_MOVE,
+                    format!("Cannot move captured variable {name} out of a closure"),
+                ));
+            }
+        }
+        self.init.moved.insert(path, self.span);
+        Ok(TypedExpr {
+            ty: value.ty.clone(),
+            kind: TypedExprKind::Move(Box::new(value)),
+        })
+    }
+
+    // Checks that no part of the place at `path` was moved out of
+    fn check_not_moved(&self, path: &str) -> Result<(), Diagnostic> {
+        let Some(moved) = self
+            .init
+            .moved
+            .keys()
+            .find(|moved| within(path, moved) || within(moved, path))
+        else {
+            return Ok(());
+        };
+        Err(error(
+            codes::USE_AFTER_MOVE,
+            if within(path, moved) {
+                format!("Cannot use {} after it was moved", self.path_name(moved))
+            } else {
+                format!(
+                    "Cannot use {} after {} was moved",
+                    self.path_name(path),
+                    self.path_name(moved)
+                )
+            },
+        ))
+    }
+
+    // Records that the place at `path` holds a value again, after it is assigned
+    fn reinitialize(&mut self, path: &str) -> Result<(), Diagnostic> {
+        if let Some(moved) = self
+            .init
+            .moved
+            .keys()
+            .find(|moved| path != moved.as_str() && within(path, moved))
+        {
+            return Err(error(
+                codes::USE_AFTER_MOVE,
+                format!(
+                    "Cannot assign to {} after {} was moved",
+                    self.path_name(path),
+                    self.path_name(moved)
+                ),
+            ));
+        }
+        self.init.moved.retain(|moved, _| !within(moved, path));
+        Ok(())
+    }
+
+    // Writes a place's path the way it appears in the source
+    fn path_name(&self, path: &str) -> String {
+        let mut segments = path.split('.');
+        let root = segments.next().unwrap_or_default();
+        segments.fold(self.variable_name(root), |name, segment| match segment {
+            "*" => format!("(*{name})"),
+            "[]" => format!("{name}[..]"),
+            field => format!("{name}.{field}"),
+        })
+    }
+
+    fn variable_name(&self, unique: &str) -> String {
+        self.variables
+            .iter()
+            .find(|variable| variable.unique == unique)
+            .map_or(unique.to_string(), |variable| variable.name.clone())
+    }
+
+    fn variable_scope(&self, unique: &str) -> usize {
+        self.variables
+            .iter()
+            .find(|variable| variable.unique == unique)
+            .map_or(0, |variable| variable.scope)
+    }
+
+    // Whether a value of type `ty` can hold a reference, directly or in a closure
+    fn may_borrow(&self, ty: &Type) -> bool {
+        match ty {
+            Type::Ref(..) | Type::Str | Type::Fn(..) | Type::Dyn(_) => true,
+            Type::Array(element, _)
+            | Type::Box(element)
+            | Type::Buffer(element)
+            | Type::Option(element) => self.may_borrow(element),
+            Type::Result(value, error) => self.may_borrow(value) || self.may_borrow(error),
+            Type::Struct(name, _) => self.structs[name].fields.iter().any(|(field, _)| {
+                self.field_type(ty, field)
+                    .is_ok_and(|field_type| self.may_borrow(&field_type))
+            }),
+            _ => false,
+        }
+    }
+
+    // The local variables that a value may point into
+    fn roots(&self, expr: &TypedExpr) -> Vec<String> {
+        if !self.may_borrow(&expr.ty) {
+            return Vec::new();
+        }
+        match &expr.kind {
+            TypedExprKind::Ref(place) => self.place_roots(place),
+            // A `str` of a `String` points into it like a reference does
+            TypedExprKind::Intrinsic {
+                intrinsic: Intrinsic::StringAsStr,
+                args,
+                ..
+            } => self.place_roots(&args[0]),
+            TypedExprKind::Variable(unique) => {
+                self.borrowed_from.get(unique).cloned().unwrap_or_default()
+            }
+            TypedExprKind::Closure { captures, .. } => captures
+                .iter()
+                .flat_map(|capture| self.borrowed_from.get(&capture.name))
+                .flatten()
+                .cloned()
+                .collect(),
+            kind => operands(kind)
+                .into_iter()
+                .flat_map(|operand| self.roots(operand))
+                .collect(),
+        }
+    }
+
+    // Whether a value that may hold a reference looks into a `String` that
+    // only lasts until the end of its block
+    fn borrows_temporary(&self, expr: &TypedExpr) -> bool {
+        if !self.may_borrow(&expr.ty) {
+            return false;
+        }
+        match &expr.kind {
+            TypedExprKind::Intrinsic {
+                intrinsic: Intrinsic::StringAsStr,
+                args,
+                ..
+            } => !is_place(&args[0]),
+            kind => operands(kind)
+                .into_iter()
+                .any(|operand| self.borrows_temporary(operand)),
+        }
+    }
+
+    // The local variables that a borrow of `place` points into
+    fn place_roots(&self, place: &TypedExpr) -> Vec<String> {
+        match &place.kind {
+            TypedExprKind::Variable(unique) => vec![unique.clone()],
+            TypedExprKind::Field { receiver, .. } => self.place_roots(receiver),
+            TypedExprKind::Index { array, .. } => self.place_roots(array),
+            // What a box holds belongs to the box's owner
+            TypedExprKind::Deref(owner) if matches!(owner.ty, Type::Box(_)) => {
+                self.place_roots(owner)
+            }
+            TypedExprKind::Deref(reference) => self.roots(reference),
+            _ => Vec::new(),
+        }
+    }
+
+    fn check_block(&mut self, body: &[ASTNode]) -> Result<Vec<TypedStmt>, Diagnostic> {
+        self.scopes.push(HashMap::new());
+        let block = self.check_block_in_scope(body);
+        self.pop_scope();
+        block
+    }
+
+    fn check_block_in_scope(&mut self, body: &[ASTNode]) -> Result<Vec<TypedStmt>, Diagnostic> {
+        let mut stmts = Vec::new();
+        let mut reported = false;
+        let outer_span = self.span;
+        for (i, stmt) in body.iter().enumerate() {
+            // Code after a statement that never finishes is still checked, but
+            // only warned about once
+            if !reported && i > 0 && diverges(&stmts) {
+                let message = format!("Unreachable code after {}", describe_ending(&body[i - 1]));
+                let warning =
+                    Diagnostic::warning(Stage::Semantic, codes::UNREACHABLE_CODE, message)
+                        .with_primary(node_span(stmt), "this is never run")
+                        .with_secondary(node_span(&body[i - 1]), "nothing after this runs");
+                self.warnings.push(warning);
+                reported = true;
+            }
+            // Local constants are replaced by their values, so they leave no statement
+            if let ASTNode::Const { name, ty, value } = stmt {
+                let value = self.check_const(name, ty, value)?;
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.insert(name.clone(), Binding::Const(value));
+                }
+                continue;
+            }
+            let outer = std::mem::replace(&mut self.statement_start, self.borrows.len());
+            self.span = node_span(stmt);
+            let checked = self.check_stmt(stmt);
+            self.end_temporaries();
+            self.statement_start = outer;
+            stmts.push(checked?);
+        }
+        self.span = outer_span;
+        Ok(stmts)
+    }
+
+    fn check_stmt(&mut self, node: &ASTNode) -> Result<TypedStmt, Diagnostic> {
+        match node {
+            ASTNode::Let {
+                name,
+                var_type,
+                value,
+                mutable,
+                span,
+            } => {
+                let declared = match var_type {
+                    Some(ty) => Some(self.resolve_type(ty)?),
+                    None => None,
+                };
+                let value = match (value, &declared) {
+                    (Some(value), Some(ty)) => Some(self.check_expr_as(value, ty)?),
+                    (Some(value), None) => Some(self.check_expr(value, None)?),
+                    (None, _) => None,
+                };
+                let var_type = match (&value, declared) {
+                    (Some(value), _) => value.ty.clone(),
+                    (None, Some(ty)) => ty,
+                    (None, None) => {
+                        return Err(error(
+                            codes::TYPE_NEEDED,
+                            format!("Variable {name} needs a type or a value"),
+                        ))
+                    }
+                };
+                if var_type == Type::Void {
+                    return Err(error(
+                        codes::INVALID_TYPE,
+                        format!("Variable {name} cannot have type void"),
+                    ));
+                }
+                let name = self.bind(name, var_type.clone(), *mutable, *span);
+                if let Some(value) = value.as_ref().filter(|_| self.may_borrow(&var_type)) {
+                    self.hold_borrows(&name)?;
+                    self.borrowed_from.insert(name.clone(), self.roots(value));
+                }
+                if value.is_none() {
+                    if let Some(variable) = self.variables.last_mut() {
+                        variable.deferred = true;
+                    }
+                    self.init.uninitialized.insert(name.clone());
+                }
+                Ok(TypedStmt::Let {
+                    name,
+                    var_type,
+                    value,
+                })
+            }
+            ASTNode::Assign { target, value, .. } => {
+                // Assigning to a variable itself does not read it, so it may be uninitialized
+                let variable = match &**target {
+                    ASTNode::Identifier(name)
+                        if matches!(self.binding(name), Some(Binding::Variable(..))) =>
+                    {
+                        Some(name)
+                    }
+                    _ => None,
+                };
+                // Assigning to a place that was moved out of gives it a value again
+                let target = match variable {
+                    Some(name) => self.resolve_variable(name)?,
+                    None => self.mutable_place(target)?,
+                };
+                let value = self.check_expr_as(value, &target.ty)?;
+                if let Some(path) = move_path(&target) {
+                    self.reinitialize(&path)?;
+                }
+                if self.borrows_temporary(&value) {
+                    return Err(error(
+                        codes::TEMPORARY_NEEDS_VARIABLE,
+                        "A temporary String must be stored in a variable before it is borrowed",
+                    ));
+                }
+                match borrow_root(&target).filter(|_| self.may_borrow(&value.ty)) {
+                    Some(holder) => {
+                        self.hold_borrows(&holder)?;
+                        let roots = self.roots(&value);
+                        self.borrowed_from.entry(holder).or_default().extend(roots);
+                    }
+                    None => self.end_temporaries(),
+                }
+                if let (Some(name), TypedExprKind::Variable(unique)) = (variable, &target.kind) {
+                    self.assign(name, unique)?;
+                }
+                Ok(TypedStmt::Assign { target, value })
+            }
+            ASTNode::CompoundAssign {
+                op,
+                target,
+                value,
+                span,
+            } => {
+                let target = self.check_place(target)?;
+                if target.ty == Type::String && *op == BinaryOp::Add {
+                    let value = self.check_expr_as(value, &Type::Str)?;
+                    return Ok(TypedStmt::Expr(intrinsic_call(
+                        Intrinsic::StringPushStr,
+                        vec![target, value],
+                        Type::Void,
+                        *span,
+                    )));
+                }
+                if !target.ty.is_integer() && !self.satisfies(&target.ty, &Bound::Num) {
+                    return Err(error(
+                        codes::INVALID_OPERATION,
+                        format!("Operator {op:?} is not defined for {}", target.ty),
+                    ));
+                }
+                let value = self.check_expr_as(value, &target.ty)?;
+                Ok(TypedStmt::CompoundAssign {
+                    op: *op,
+                    target,
+                    value,
+                    span: *span,
+                })
+            }
+            ASTNode::If {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                let condition = self.check_expr_as(condition, &Type::Bool)?;
+                self.end_temporaries();
+                let before = self.init.clone();
+                let then_body = self.check_block(then_body)?;
+                let after_then = std::mem::replace(&mut self.init, before);
+                let else_body = self.check_block(else_body)?;
+                let after_else = std::mem::take(&mut self.init);
+                self.init = join_paths(vec![
+                    (after_then, diverges(&then_body)),
+                    (after_else, diverges(&else_body)),
+                ]);
+                Ok(TypedStmt::If {
+                    condition,
+                    then_body,
+                    else_body,
+                })
+            }
+            ASTNode::While { condition, body } => {
+                let condition = self.check_expr_as(condition, &Type::Bool)?;
+                if has_try(&condition) {
+                    return Err(error(
+                        codes::INVALID_TRY,
+                        "Cannot use ? in the condition of a while loop",
+                    ));
+                }
+                if views_temporary(&condition) {
+                    return Err(
+                        error(codes::TEMPORARY_NEEDS_VARIABLE, "A temporary String must be stored in a variable before it is used in the condition of a while loop")
+                    );
+                }
+                self.end_temporaries();
+                let body = self.check_loop_body(|sema| sema.check_block(body))?;
+                Ok(TypedStmt::While { condition, body })
+            }
+            ASTNode::For {
+                var,
+                iterable,
+                body,
+            } => {
+                let iterable = self.check_operand(iterable)?;
+                let (iterable, item_type) = match self.buffer_of(auto_deref(iterable.clone())) {
+                    // A buffer in a place is looked through rather than moved, and
+                    // stays borrowed until the loop ends
+                    Some(buffer) => {
+                        let Type::Buffer(element) = buffer.ty.clone() else {
+                            unreachable!("buffer_of returned a {}", buffer.ty);
+                        };
+                        if let Some(root) = borrow_root(&buffer) {
+                            self.borrow(&root, false)?;
+                        }
+                        (buffer, *element)
+                    }
+                    // So is a string, as a `str`
+                    None if iterable.ty == Type::String => (self.view(iterable)?, Type::Char),
+                    None => {
+                        let iterable = self.move_out(iterable)?;
+                        self.end_temporaries();
+                        match iterable.ty.clone() {
+                            Type::Array(element, _) => (iterable, *element),
+                            ty if ty.is_string() => (coerce(iterable, &Type::Str)?, Type::Char),
+                            ty => {
+                                return Err(error(
+                                    codes::INVALID_OPERATION,
+                                    format!("Cannot iterate over {ty}"),
+                                ))
+                            }
+                        }
+                    }
+                };
+                self.scopes.push(HashMap::new());
+                let var = self.bind(var, item_type, None, Span::default());
+                let body = self.check_loop_body(|sema| sema.check_block_in_scope(body));
+                self.pop_scope();
+                Ok(TypedStmt::For {
+                    var,
+                    iterable,
+                    body: body?,
+                })
+            }
+            ASTNode::Match {
+                scrutinee,
+                arms,
+                span,
+            } => self.check_match(scrutinee, arms, *span),
+            ASTNode::Return { value, .. } => {
+                let return_type = self.return_type.clone();
+                match (value, &return_type) {
+                    (None, Type::Void) => Ok(TypedStmt::Return(None)),
+                    (None, ty) => Err(error(
+                        codes::MISMATCHED_TYPES,
+                        format!("Expected a return value of type {ty}"),
+                    )),
+                    (Some(_), Type::Void) => Err(error(
+                        codes::MISMATCHED_TYPES,
+                        "Cannot return a value from a void function",
+                    )),
+                    (Some(value), ty) => {
+                        let value = self.check_expr_as(value, ty)?;
+                        self.check_escape(&value)?;
+                        Ok(TypedStmt::Return(Some(value)))
+                    }
+                }
+            }
+            _ => Ok(TypedStmt::Expr(self.check_expr(node, None)?)),
+        }
+    }
+
+    // Checks that a returned value does not point into a local variable of the
+    // function or closure returning it
+    fn check_escape(&self, value: &TypedExpr) -> Result<(), Diagnostic> {
+        if self.borrows_temporary(value) {
+            return Err(error(
+                codes::DANGLING_REFERENCE,
+                "Cannot return a reference to a temporary String",
+            ));
+        }
+        let depth = self.closures.last().map_or(0, |closure| closure.depth);
+        match self
+            .roots(value)
+            .iter()
+            .find(|root| self.variable_scope(root) >= depth)
+        {
+            Some(root) => Err(error(
+                codes::DANGLING_REFERENCE,
+                format!(
+                    "Cannot return a reference to local variable {}",
+                    self.variable_name(root)
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // Checks the body of a loop, which may run any number of times, so no
+    // variable it assigns is initialized after it
+    fn check_loop_body(
+        &mut self,
+        check: impl FnOnce(&mut Sema) -> Result<Vec<TypedStmt>, Diagnostic>,
+    ) -> Result<Vec<TypedStmt>, Diagnostic> {
+        let before = self.init.clone();
+        let declared = self.variables.len();
+        self.loop_depth += 1;
+        let body = check(self);
+        self.loop_depth -= 1;
+        let after = std::mem::replace(&mut self.init, before);
+        self.init.assigned.extend(after.assigned);
+        let body = body?;
+        // A value moved out of in one iteration is gone in the next, unless
+        // the iteration assigns it again
+        if !diverges(&body) {
+            let outside = after.moved.iter().find_map(|(path, span)| {
+                let root = path.split('.').next();
+                let variable = self.variables[..declared]
+                    .iter()
+                    .find(|variable| root == Some(&variable.unique))?;
+                (!self.init.moved.contains_key(path)).then_some((path, *span, variable.span))
+            });
+            if let Some((path, span, declared)) = outside {
+                let name = self.path_name(path);
+                return Err(error(codes::CANNOT_MOVE, format!(
+                    "Cannot move {name} in a loop unless it is assigned again before the next iteration"
+                ))
+                .with_primary(span, format!("{name} is moved here, and again in the next iteration"))
+                .with_secondary(declared, "declared outside the loop"));
+            }
+        }
+        Ok(body)
+    }
+
+    // Checks a `match` on an integer, bool or char. Patterns are constants,
+    // each matched at most once, and together they must cover every value.
+    // `span` is where the `match` keyword is.
+    fn check_match(
+        &mut self,
+        scrutinee: &ASTNode,
+        arms: &[MatchArm],
+        span: Span,
+    ) -> Result<TypedStmt, Diagnostic> {
+        let scrutinee = self.check_operand(scrutinee)?;
+        if let Type::Option(_) | Type::Result(..) = auto_deref(scrutinee.clone()).ty {
+            return self.check_variant_match(auto_deref(scrutinee), arms, span);
+        }
+        let scrutinee = self.move_out(scrutinee)?;
+        self.end_temporaries();
+        let ty = scrutinee.ty.clone();
+        let values = match ty.integer_range() {
+            Some((min, max)) => (max - min + 1) as u128,
+            None if ty == Type::Bool => 2,
+            // Every code point except the surrogates
+            None if ty == Type::Char => 0x110000 - 0x800,
+            None => {
+                return Err(error(
+                    codes::INVALID_OPERATION,
+                    format!("Cannot match on a value of type {ty}"),
+                ))
+            }
+        };
+        let mut seen = Vec::new();
+        let mut exhaustive = false;
+        let mut typed_arms = Vec::new();
+        let before = self.init.clone();
+        let mut paths = Vec::new();
+        for arm in arms {
+            if exhaustive {
+                return Err(error(
+                    codes::UNREACHABLE_PATTERN,
+                    "Match arm is unreachable",
+                ));
+            }
+            let mut patterns = Vec::new();
+            let mut catch_all = false;
+            for pattern in &arm.patterns {
+                let Pattern::Value(node) = pattern else {
+                    catch_all = true;
+                    continue;
+                };
+                let value = self.check_expr_as(node, &ty)?;
+                let value = consteval::eval(&value)
+                    .map_err(|e| error(codes::NOT_CONSTANT, format!("Invalid pattern: {e}")))?;
+                if seen.contains(&value) {
+                    return Err(error(
+                        codes::UNREACHABLE_PATTERN,
+                        format!("Pattern {value} is matched twice"),
+                    ));
+                }
+                patterns.push(TypedExpr {
+                    kind: TypedExprKind::Literal(value.to_literal()),
+                    ty: ty.clone(),
+                });
+                seen.push(value);
+            }
+            exhaustive = catch_all || seen.len() as u128 == values;
+            self.init = before.clone();
+            let body = self.check_block(&arm.body)?;
+            paths.push((std::mem::take(&mut self.init), diverges(&body)));
+            typed_arms.push(TypedArm {
+                patterns,
+                catch_all,
+                binding: None,
+                body,
+            });
+        }
+        self.init = join_paths(paths);
+        if !exhaustive {
+            return Err(error(
+                codes::NON_EXHAUSTIVE_MATCH,
+                format!("Match on {ty} is not exhaustive; add a `_` arm"),
+            )
+            .with_primary(span, label(codes::NON_EXHAUSTIVE_MATCH)));
+        }
+        Ok(TypedStmt::Match {
+            scrutinee,
+            arms: typed_arms,
+        })
+    }
+
+    // Checks a `match` on an `Option` or `Result`, whose patterns are its
+    // variants. An arm with a single pattern can bind the value inside to a
+    // name, which moves it out, so then the scrutinee is moved into the match.
+    fn check_variant_match(
+        &mut self,
+        scrutinee: TypedExpr,
+        arms: &[MatchArm],
+        span: Span,
+    ) -> Result<TypedStmt, Diagnostic> {
+        let ty = scrutinee.ty.clone();
+        let variants = match &ty {
+            Type::Option(value) => [
+                ("Some", true, (**value).clone()),
+                ("None", false, Type::Void),
+            ],
+            Type::Result(value, error) => [
+                ("Ok", true, (**value).clone()),
+                ("Err", false, (**error).clone()),
+            ],
+            _ => unreachable!("check_variant_match on {}", ty),
+        };
+        let binds = arms
+            .iter()
+            .flat_map(|arm| &arm.patterns)
+            .any(|pattern| matches!(pattern, Pattern::Variant(_, Some(_))));
+        let scrutinee = if binds {
+            self.move_out(scrutinee)?
+        } else {
+            scrutinee
+        };
+        let roots = self.roots(&scrutinee);
+        self.end_temporaries();
+        let mut seen = Vec::new();
+        let mut exhaustive = false;
+        let mut typed_arms = Vec::new();
+        let before = self.init.clone();
+        let mut paths = Vec::new();
+        for arm in arms {
+            if exhaustive {
+                return Err(error(
+                    codes::UNREACHABLE_PATTERN,
+                    "Match arm is unreachable",
+                ));
+            }
+            let mut patterns = Vec::new();
+            let mut catch_all = false;
+            let mut binding = None;
+            for pattern in &arm.patterns {
+                let (variant, bound) = match pattern {
+                    Pattern::Wildcard => {
+                        catch_all = true;
+                        continue;
+                    }
+                    Pattern::Value(_) => {
+                        return Err(error(
+                            codes::MISMATCHED_TYPES,
+                            format!(
+                                "Patterns on {ty} must be {} or {}",
+                                variants[0].0, variants[1].0
+                            ),
+                        ))
+                    }
+                    Pattern::Variant(variant, bound) => (variant, bound),
+                };
+                let Some((_, tag, payload)) = variants.iter().find(|(name, ..)| name == variant)
+                else {
+                    return Err(error(
+                        codes::UNKNOWN_ITEM,
+                        format!("{variant} is not a variant of {ty}"),
+                    ));
+                };
+                if seen.contains(tag) {
+                    return Err(error(
+                        codes::UNREACHABLE_PATTERN,
+                        format!("Pattern {variant} is matched twice"),
+                    ));
+                }
+                if let Some(name) = bound {
+                    if arm.patterns.len() > 1 {
+                        return Err(error(
+                            codes::INVALID_TYPE,
+                            format!(
+                            "A pattern that binds {name} cannot be combined with other patterns"
+                        ),
+                        ));
+                    }
+                    if *payload == Type::Void {
+                        return Err(error(
+                            codes::INVALID_TYPE,
+                            format!("{variant} of {ty} holds no value to bind"),
+                        ));
+                    }
+                    binding = Some((name, payload.clone()));
+                }
+                patterns.push(TypedExpr {
+                    kind: TypedExprKind::Literal(Literal::Bool(*tag)),
+                    ty: Type::Bool,
+                });
+                seen.push(*tag);
+            }
+            exhaustive = catch_all || seen.len() == variants.len();
+            self.init = before.clone();
+            self.scopes.push(HashMap::new());
+            let binding = binding.map(|(name, payload)| {
+                let unique = self.bind(name, payload.clone(), None, Span::default());
+                if self.may_borrow(&payload) {
+                    self.borrowed_from.insert(unique.clone(), roots.clone());
+                }
+                unique
+            });
+            let body = self.check_block_in_scope(&arm.body);
+            self.pop_scope();
+            let body = body?;
+            paths.push((std::mem::take(&mut self.init), diverges(&body)));
+            typed_arms.push(TypedArm {
+                patterns,
+                catch_all,
+                binding,
+                body,
+            });
+        }
+        self.init = join_paths(paths);
+        if let Some((missing, ..)) = variants
+            .iter()
+            .find(|(_, tag, _)| !exhaustive && !seen.contains(tag))
+        {
+            return Err(error(
+                codes::NON_EXHAUSTIVE_MATCH,
+                format!("Match on {ty} does not cover {missing}"),
+            )
+            .with_primary(span, format!("{missing} is not matched")));
+        }
+        Ok(TypedStmt::Match {
+            scrutinee,
+            arms: typed_arms,
+        })
+    }
+
+    // Checks an expression that is mutated in place, which must not have been
+    // moved out of
+    fn check_place(&mut self, node: &ASTNode) -> Result<TypedExpr, Diagnostic> {
+        let place = self.mutable_place(node)?;
+        if let Some(path) = move_path(&place) {
+            self.check_not_moved(&path)?;
+        }
+        Ok(place)
+    }
+
+    // Checks an expression that is assigned to or mutated in place
+    fn mutable_place(&mut self, node: &ASTNode) -> Result<TypedExpr, Diagnostic> {
+        match node {
+            ASTNode::Identifier(name) => {
+                let place = self.check_name(name)?;
+                match &place.kind {
+                    TypedExprKind::Variable(unique) => {
+                        self.mutate(name, unique)?;
+                        Ok(place)
+                    }
+                    TypedExprKind::Literal(_) => Err(error(
+                        codes::ASSIGN_TO_IMMUTABLE,
+                        format!("Cannot assign to constant {name}"),
+                    )),
+                    _ => Err(error(
+                        codes::ASSIGN_TO_IMMUTABLE,
+                        format!("Cannot assign to function {name}"),
+                    )),
+                }
+            }
+            ASTNode::FieldAccess { receiver, field } => {
+                let receiver = self.check_place_receiver(receiver)?;
+                self.check_field(receiver, field)
+            }
+            ASTNode::Index {
+                receiver,
+                index,
+                span,
+            } => {
+                let array = self.check_place_receiver(receiver)?;
+                self.check_index(array, index, *span)
+            }
+            ASTNode::Deref(node) => {
+                let reference = self.check_operand(node)?;
+                match reference.ty.clone() {
+                    Type::Ref(inner, true) => Ok(deref(reference, *inner)),
+                    Type::Ref(..) => Err(error(
+                        codes::ASSIGN_TO_IMMUTABLE,
+                        "Cannot mutate a value behind a & reference",
+                    )),
+                    // What a box holds changes with the box
+                    Type::Box(inner) => Ok(deref(self.mutable_place(node)?, *inner)),
+                    ty => Err(error(
+                        codes::INVALID_OPERATION,
+                        format!("Cannot dereference a value of type {ty}"),
+                    )),
+                }
+            }
+            _ => Err(error(
+                codes::INVALID_OPERATION,
+                "Only variables, their fields and array elements can be assigned to",
+            )),
+        }
+    }
+
+    // Checks what a field or element that is changed belongs to. That changes
+    // too, unless it is reached through a `&mut` reference.
+    fn check_place_receiver(&mut self, node: &ASTNode) -> Result<TypedExpr, Diagnostic> {
+        let mut receiver = match node {
+            ASTNode::Identifier(name) => match self.check_name(name)? {
+                reference @ TypedExpr {
+                    ty: Type::Ref(..), ..
+                } => reference,
+                _ => self.mutable_place(node)?,
+            },
+            _ => self.mutable_place(node)?,
+        };
+        loop {
+            receiver = match receiver.ty.clone() {
+                Type::Ref(inner, true) | Type::Box(inner) => deref(receiver, *inner),
+                Type::Ref(..) => {
+                    return Err(error(
+                        codes::ASSIGN_TO_IMMUTABLE,
+                        "Cannot mutate a value behind a & reference",
+                    ))
+                }
+                _ => return Ok(receiver),
+            };
+        }
+    }
+
+    // Checks a name used as a value: a local variable or constant, a
+    // top-level constant, or a function
+    fn check_name(&mut self, name: &str) -> Result<TypedExpr, Diagnostic> {
+        if self.scopes.iter().all(|scope| !scope.contains_key(name)) {
+            if let Some(value) = self.consts.get(name) {
+                return Ok(value.clone());
+            }
+            if let Some(signature) = self.functions.get(name) {
+                return function_value(name, signature);
+            }
+        }
+        self.lookup(name)
+    }
+
+    // Checks an expression that is only looked through, such as the receiver of
+    // a field access, so it is not moved out of and a `&mut` reference named by
+    // it is not reborrowed
+    fn check_operand(&mut self, node: &ASTNode) -> Result<TypedExpr, Diagnostic> {
+        let value = self.check_path(node)?;
+        if let Some(path) = move_path(&value) {
+            self.check_not_moved(&path)?;
+        }
+        Ok(value)
+    }
+
+    // Checks a place whose value is read, without moving out of it yet
+    fn check_read(&mut self, node: &ASTNode) -> Result<TypedExpr, Diagnostic> {
+        let value = self.check_operand(node)?;
+        // Copying a `&mut` reference reborrows it, so the variable is not
+        // used again while the copy is live
+        if let (TypedExprKind::Variable(unique), Type::Ref(_, true)) = (&value.kind, &value.ty) {
+            self.borrow(&unique.clone(), true)?;
+        }
+        Ok(value)
+    }
+
+    // Looks at a `String` as a `str`, and leaves any other value as it is. A
+    // `String` in a place is borrowed for as long as the `str` is used, and a
+    // temporary one is kept by the generator until the end of the block.
+    fn view(&mut self, value: TypedExpr) -> Result<TypedExpr, Diagnostic> {
+        if value.ty != Type::String {
+            return Ok(value);
+        }
+        if let Some(root) = borrow_root(&value).filter(|_| is_place(&value)) {
+            self.borrow(&root, false)?;
+        }
+        coerce(value, &Type::Str)
+    }
+
+    // Checks an operand without checking whether it was moved out of, since
+    // only the whole place it names matters
+    fn check_path(&mut self, node: &ASTNode) -> Result<TypedExpr, Diagnostic> {
+        match node {
+            ASTNode::Identifier(name) => self.check_name(name),
+            ASTNode::FieldAccess { receiver, field } => {
+                let receiver = auto_deref(self.check_path(receiver)?);
+                self.check_temporary(&receiver)?;
+                self.check_field(receiver, field)
+            }
+            ASTNode::Index {
+                receiver,
+                index,
+                span,
+            } => {
+                let array = auto_deref(self.check_path(receiver)?);
+                self.check_temporary(&array)?;
+                self.check_index(array, index, *span)
+            }
+            ASTNode::Deref(reference) => {
+                let reference = self.check_path(reference)?;
+                self.check_temporary(&reference)?;
+                match reference.ty.clone() {
+                    Type::Ref(inner, _) | Type::Box(inner) => Ok(deref(reference, *inner)),
+                    ty => Err(error(
+                        codes::INVALID_OPERATION,
+                        format!("Cannot dereference a value of type {ty}"),
+                    )),
+                }
+            }
+            _ => self.check_expr(node, None),
+        }
+    }
+
+    // The buffer that a `for` loop or indexing goes through: a buffer itself,
+    // or the elements of a Vec
+    fn buffer_of(&self, value: TypedExpr) -> Option<TypedExpr> {
+        match &value.ty {
+            Type::Buffer(_) => Some(value),
+            Type::Struct(name, _) if name == "Vec" && self.module_structs.contains_key(name) => {
+                self.check_field(value, "items").ok()
+            }
+            _ => None,
+        }
+    }
+
+    // Checks that a value that is only looked through is not a temporary that
+    // owns heap memory, since nothing would be left to drop it. A `String` is
+    // only ever looked through as a `str`, which keeps it until it is dropped.
+    fn check_temporary(&self, value: &TypedExpr) -> Result<(), Diagnostic> {
+        if !is_place(value) && value.ty != Type::String && needs_drop(&value.ty, &self.structs) {
+            return Err(error(
+                codes::TEMPORARY_NEEDS_VARIABLE,
+                format!(
+                    "A temporary {} must be stored in a variable before it is used here",
+                    value.ty
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_field(&self, receiver: TypedExpr, field: &str) -> Result<TypedExpr, Diagnostic> {
+        let ty = self.field_type(&receiver.ty, field)?;
+        Ok(TypedExpr {
+            kind: TypedExprKind::Field {
+                receiver: Box::new(receiver),
+                field: field.to_string(),
+            },
+            ty,
+        })
+    }
+
+    fn check_index(
+        &mut self,
+        array: TypedExpr,
+        index: &ASTNode,
+        span: Span,
+    ) -> Result<TypedExpr, Diagnostic> {
+        let array = self.buffer_of(array.clone()).unwrap_or(array);
+        let (Type::Array(element, _) | Type::Buffer(element)) = array.ty.clone() else {
+            return Err(error(
+                codes::INVALID_OPERATION,
+                format!("Cannot index into a value of type {}", array.ty),
+            ));
+        };
+        let index = self.check_expr_as(index, &Type::U64)?;
+        Ok(TypedExpr {
+            kind: TypedExprKind::Index {
+                array: Box::new(array),
+                index: Box::new(index),
+                span,
+            },
+            ty: *element,
+        })
+    }
+
+    fn check_expr_as(&mut self, node: &ASTNode, ty: &Type) -> Result<TypedExpr, Diagnostic> {
+        let expr = self.check_expr(node, Some(ty))?;
+        if let Type::Dyn(trait_name) = ty {
+            if expr.ty != *ty {
+                self.require(&expr.ty, &Bound::Trait(trait_name.clone()))?;
+                return Ok(TypedExpr {
+                    kind: TypedExprKind::ToDyn(Box::new(expr)),
+                    ty: ty.clone(),
+                });
+            }
+        }
+        coerce(expr, ty)
+    }
+
+    // `expected` only guides literal typing; callers still coerce the result
+    // Checks an expression, leaving `span` at the innermost expression with a
+    // place in the source if it is not valid
+    fn check_expr(
+        &mut self,
+        node: &ASTNode,
+        expected: Option<&Type>,
+    ) -> Result<TypedExpr, Diagnostic> {
+        let outer = self.span;
+        let span = node_span(node);
+        if span.line > 0 {
+            self.span = span;
+        }
+        let checked = self.check_expr_kind(node, expected)?;
+        self.span = outer;
+        Ok(checked)
+    }
+
+    fn check_expr_kind(
+        &mut self,
+        node: &ASTNode,
+        expected: Option<&Type>,
+    ) -> Result<TypedExpr, Diagnostic> {
+        match node {
+            ASTNode::Literal(literal) => check_literal(literal, expected),
+            ASTNode::Identifier(name) if name == "None" && self.binding(name).is_none() => {
+                self.check_variant(name, &[], expected)
+            }
+            ASTNode::Try(value) => self.check_try(value, expected),
+            ASTNode::Identifier(_)
+            | ASTNode::FieldAccess { .. }
+            | ASTNode::Index { .. }
+            | ASTNode::Deref(_) => {
+                let value = self.check_read(node)?;
+                // A `String` that is only looked at as a `str` stays where it is
+                if value.ty == Type::String && expected == Some(&Type::Str) {
+                    return self.view(value);
+                }
+                self.move_out(value)
+            }
+            ASTNode::UnaryOp { op, operand, span } => {
+                if let (UnaryOp::Neg, ASTNode::Literal(Literal::Number(n))) = (op, &**operand) {
+                    return check_number(-i128::from(*n as u64), expected);
+                }
+                let operand = self.check_expr(operand, expected)?;
+                match op {
+                    UnaryOp::Neg => {
+                        if let TypedExprKind::Literal(Literal::Number(n)) = operand.kind {
+                            if operand.ty.is_signed() {
+                                return check_number(-i128::from(n), Some(&operand.ty));
+                            }
+                        }
+                        if !operand.ty.is_signed() {
+                            return Err(error(
+                                codes::INVALID_OPERATION,
+                                format!("Cannot negate a value of type {}", operand.ty),
+                            ));
+                        }
+                    }
+                    UnaryOp::Not => {
+                        if operand.ty != Type::Bool {
+                            return Err(error(
+                                codes::INVALID_OPERATION,
+                                format!("Cannot apply ! to a value of type {}", operand.ty),
+                            ));
+                        }
+                    }
+                }
+                Ok(TypedExpr {
+                    ty: operand.ty.clone(),
+                    kind: TypedExprKind::Unary {
+                        op: *op,
+                        operand: Box::new(operand),
+                        span: *span,
+                    },
+                })
+            }
+            ASTNode::BinaryOp { op, lhs, rhs, span } => {
+                self.check_binary(*op, lhs, rhs, expected, *span)
+            }
+            ASTNode::Cast { value, target } => {
+                let target = &self.resolve_type(target)?;
+                let value = self.check_expr(value, Some(target))?;
+                let allowed = (value.ty.is_integer() || value.ty == Type::Bool)
+                    && target.is_integer()
+                    || value.ty == Type::Char && target.is_integer()
+                    || value.ty == Type::U8 && *target == Type::Char
+                    || value.ty == *target;
+                if !allowed {
+                    return Err(error(
+                        codes::INVALID_OPERATION,
+                        format!("Cannot cast {} to {target}", value.ty),
+                    ));
+                }
+                Ok(TypedExpr {
+                    kind: TypedExprKind::Cast(Box::new(value)),
+                    ty: target.clone(),
+                })
+            }
+            ASTNode::FnCall {
+                function,
+                args,
+                span,
+            } => self.check_call(function, args, expected, *span),
+            ASTNode::MethodCall {
+                receiver,
+                method,
+                args,
+                span,
+            } => self.check_method_call(receiver, method, args, *span),
+            ASTNode::AssocCall { ty, function, args } => match (ty, function.as_str()) {
+                (Type::String, "new") => self.intrinsic(
+                    Intrinsic::StringNew,
+                    &[],
+                    args,
+                    Type::String,
+                    Span::default(),
+                ),
+                (Type::String, "from") => self.intrinsic(
+                    Intrinsic::StringFrom,
+                    &[Type::Str],
+                    args,
+                    Type::String,
+                    Span::default(),
+                ),
+                (Type::Struct(name, type_args), "new") if name == "Box" && type_args.is_empty() => {
+                    let [value] = &args[..] else {
+                        return Err(error(
+                            codes::WRONG_ARGUMENT_COUNT,
+                            format!("Box::new takes 1 argument(s) but {} were given", args.len()),
+                        ));
+                    };
+                    let value = match expected {
+                        Some(Type::Box(inner)) => self.check_expr_as(value, inner)?,
+                        _ => self.check_expr(value, None)?,
+                    };
+                    if value.ty == Type::Void {
+                        return Err(error(
+                            codes::INVALID_TYPE,
+                            "Cannot box a value of type void",
+                        ));
+                    }
+                    let ty = Type::Box(Box::new(value.ty.clone()));
+                    if contains_ref(&value.ty) {
+                        return Err(error(
+                            codes::INVALID_TYPE,
+                            format!("{ty} cannot hold a reference"),
+                        ));
+                    }
+                    Ok(intrinsic_call(
+                        Intrinsic::BoxNew,
+                        vec![value],
+                        ty,
+                        Span::default(),
+                    ))
+                }
+                (Type::Struct(name, type_args), "new")
+                    if name == "Buffer" && type_args.is_empty() =>
+                {
+                    let Some(ty @ Type::Buffer(element)) = expected else {
+                        return Err(
+                            error(codes::TYPE_NEEDED, "Cannot infer the element type of Buffer::new; give the variable a type")
+                        );
+                    };
+                    self.check_buffer_element(element)?;
+                    self.intrinsic(Intrinsic::BufferNew, &[], args, ty.clone(), Span::default())
+                }
+                // The associated functions of a standard module's struct are
+                // the module's functions named after it
+                (Type::Struct(name, type_args), function)
+                    if type_args.is_empty() && self.module_structs.contains_key(name) =>
+                {
+                    let Some(module_function) = self.module_method(ty, function) else {
+                        return Err(error(
+                            codes::UNKNOWN_ITEM,
+                            format!("{ty} has no associated function {function}"),
+                        ));
+                    };
+                    self.check_call(&module_function, args, expected, Span::default())
+                }
+                _ => Err(error(
+                    codes::UNKNOWN_ITEM,
+                    format!("{ty} has no associated function {function}"),
+                )),
+            },
+            ASTNode::StructLiteral { name, fields } => {
+                self.check_struct_literal(name, fields, expected)
+            }
+            ASTNode::Closure {
+                params,
+                return_type,
+                body,
+                by_value,
+            } => {
+                let hint = match expected {
+                    Some(Type::Fn(params, return_type)) => Some(ClosureHint {
+                        params: params.iter().cloned().map(Some).collect(),
+                        return_type: Some((**return_type).clone()),
+                    }),
+                    _ => None,
+                };
+                self.check_closure(params, return_type, body, *by_value, hint)
+            }
+            ASTNode::Call { callee, args } => self.check_value_call(callee, args),
+            ASTNode::ArrayLiteral(elements) => self.check_array_literal(elements, expected),
+            ASTNode::ArrayRepeat { value, len } => {
+                let value = match expected {
+                    Some(Type::Array(element, _)) => self.check_expr_as(value, element)?,
+                    _ => self.check_expr(value, None)?,
+                };
+                if needs_drop(&value.ty, &self.structs) {
+                    return Err(error(
+                        codes::INVALID_TYPE,
+                        format!(
+                            "Cannot repeat a value of type {}, since it owns heap memory",
+                            value.ty
+                        ),
+                    ));
+                }
+                let ty =
+                    self.resolve_type(&Type::Array(Box::new(value.ty.clone()), len.clone()))?;
+                Ok(TypedExpr {
+                    kind: TypedExprKind::ArrayRepeat(Box::new(value)),
+                    ty,
+                })
+            }
+            ASTNode::Borrow { value, mutable } => {
+                // A conflicting borrow is reported as such, before checking
+                // the place uses or mutates the variable
+                if let Some(Binding::Variable(unique, _)) =
+                    base_name(value).and_then(|name| self.binding(name))
+                {
+                    self.check_borrow(&unique.clone(), *mutable)?;
+                }
+                let place = match &**value {
+                    ASTNode::Identifier(_)
+                    | ASTNode::FieldAccess { .. }
+                    | ASTNode::Index { .. }
+                    | ASTNode::Deref(_)
+                        if *mutable =>
+                    {
+                        self.check_place(value)?
+                    }
+                    value => self.check_operand(value)?,
+                };
+                if !is_place(&place) {
+                    return Err(error(
+                        codes::INVALID_OPERATION,
+                        "Only variables, their fields and array elements can be borrowed",
+                    ));
+                }
+                if let Some(root) = borrow_root(&place) {
+                    self.borrow(&root, *mutable)?;
+                }
+                Ok(TypedExpr {
+                    ty: Type::Ref(Box::new(place.ty.clone()), *mutable),
+                    kind: TypedExprKind::Ref(Box::new(place)),
+                })
+            }
+            _ => Err(error(
+                codes::INVALID_OPERATION,
+                format!("Expected expression, found {node:?}"),
+            )),
+        }
+    }
+
+    fn check_array_literal(
+        &mut self,
+        elements: &[ASTNode],
+        expected: Option<&Type>,
+    ) -> Result<TypedExpr, Diagnostic> {
+        if elements.is_empty() {
+            return Err(error(
+                codes::INVALID_TYPE,
+                "Arrays must have at least one element",
+            ));
+        }
+        let mut element_type = match expected {
+            Some(Type::Array(element, _)) => Some((**element).clone()),
+            _ => None,
+        };
+        // Type the non-literal elements first so bare literals can take their type
+        let mut typed: Vec<Option<TypedExpr>> = vec![None; elements.len()];
+        let (literals, others): (Vec<_>, Vec<_>) = elements
+            .iter()
+            .enumerate()
+            .partition(|(_, element)| is_number_literal(element));
+        for (i, element) in others.into_iter().chain(literals) {
+            let value = match &element_type {
+                Some(ty) => self.check_expr_as(element, ty)?,
+                None => self.check_expr(element, None)?,
+            };
+            element_type.get_or_insert(value.ty.clone());
+            typed[i] = Some(value);
+        }
+        let elements: Vec<TypedExpr> = typed.into_iter().flatten().collect();
+        let ty = Type::Array(
+            Box::new(element_type.unwrap_or(Type::Void)),
+            Length::Known(elements.len() as u64),
+        );
+        Ok(TypedExpr {
+            ty: self.resolve_type(&ty)?,
+            kind: TypedExprKind::ArrayLiteral(elements),
+        })
+    }
+
+    fn check_closure(
+        &mut self,
+        params: &[(String, Option<Type>)],
+        return_type: &Option<Type>,
+        body: &ClosureBody,
+        by_value: bool,
+        hint: Option<ClosureHint>,
+    ) -> Result<TypedExpr, Diagnostic> {
+        let hint = hint.filter(|hint| hint.params.len() == params.len());
+        let mut param_types = Vec::new();
+        for (i, (name, declared)) in params.iter().enumerate() {
+            let ty = match declared {
+                Some(ty) => self.resolve_type(ty)?,
+                None => hint
+                    .as_ref()
+                    .and_then(|hint| hint.params[i].clone())
+                    .ok_or(error(
+                        codes::TYPE_NEEDED,
+                        format!("Cannot infer the type of closure parameter {name}"),
+                    ))?,
+            };
+            if ty == Type::Void {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    format!("Parameter {name} cannot have type void"),
+                ));
+            }
+            param_types.push(ty);
+        }
+        let return_type = match return_type {
+            Some(ty) => Some(self.resolve_type(ty)?),
+            None => hint.and_then(|hint| hint.return_type),
+        };
+        self.scopes.push(HashMap::new());
+        self.closures.push(ClosureScope {
+            depth: self.scopes.len() - 1,
+            captures: Vec::new(),
+            by_value,
+        });
+        let typed_params = params
+            .iter()
+            .zip(&param_types)
+            .map(|((name, _), ty)| {
+                (
+                    self.bind(name, ty.clone(), None, Span::default()),
+                    ty.clone(),
+                )
+            })
+            .collect();
+        // The closure may run any number of times, or never, like a loop body
+        let before = self.init.clone();
+        self.loop_depth += 1;
+        let checked = self.check_closure_body(body, return_type);
+        self.loop_depth -= 1;
+        self.init = before;
+        let closure = self.closures.pop();
+        self.pop_scope();
+        let (body, return_type) = checked?;
+        // A `move` closure takes ownership of what it captures
+        for capture in closure.iter().flat_map(|closure| &closure.captures) {
+            if !capture.by_ref {
+                self.move_out(TypedExpr {
+                    kind: TypedExprKind::Variable(capture.name.clone()),
+                    ty: capture.ty.clone(),
+                })?;
+            }
+        }
+        Ok(TypedExpr {
+            kind: TypedExprKind::Closure {
+                params: typed_params,
+                captures: closure.map(|closure| closure.captures).unwrap_or_default(),
+                body,
+            },
+            ty: Type::Fn(param_types, Box::new(return_type)),
+        })
+    }
+
+    // Checks what a closure runs, and works out what it returns unless that is known.
+    // A block returns void unless its return type is declared or expected.
+    fn check_closure_body(
+        &mut self,
+        body: &ClosureBody,
+        return_type: Option<Type>,
+    ) -> Result<(Vec<TypedStmt>, Type), Diagnostic> {
+        match body {
+            ClosureBody::Expr(value) => {
+                // A `?` in the body returns from the closure
+                let returns = return_type.clone().unwrap_or(Type::Void);
+                let outer = std::mem::replace(&mut self.return_type, returns);
+                let value = match &return_type {
+                    Some(ty) => self.check_expr_as(value, ty),
+                    None => self.check_expr(value, None),
+                };
+                self.return_type = outer;
+                let value = value?;
+                let ty = value.ty.clone();
+                self.check_escape(&value)?;
+                if ty == Type::Void {
+                    Ok((vec![TypedStmt::Expr(value)], ty))
+                } else {
+                    Ok((vec![TypedStmt::Return(Some(value))], ty))
+                }
+            }
+            ClosureBody::Block(body) => {
+                let return_type = return_type.unwrap_or(Type::Void);
+                let outer = std::mem::replace(&mut self.return_type, return_type.clone());
+                let body = self.check_block_in_scope(body);
+                self.return_type = outer;
+                let body = body?;
+                if return_type != Type::Void && !diverges(&body) {
+                    return Err(error(
+                        codes::MISSING_RETURN,
+                        "Not all paths of the closure return a value",
+                    ));
+                }
+                Ok((body, return_type))
+            }
+        }
+    }
+
+    fn check_value_call(
+        &mut self,
+        callee: &ASTNode,
+        args: &[ASTNode],
+    ) -> Result<TypedExpr, Diagnostic> {
+        let callee = self.check_expr(callee, None)?;
+        let Type::Fn(params, return_type) = callee.ty.clone() else {
+            return Err(error(
+                codes::INVALID_OPERATION,
+                format!("Cannot call a value of type {}", callee.ty),
+            ));
+        };
+        let args = self.check_args(&callee.ty.to_string(), &params, args)?;
+        Ok(TypedExpr {
+            kind: TypedExprKind::CallValue {
+                callee: Box::new(callee),
+                args,
+            },
+            ty: *return_type,
+        })
+    }
+
+    // Checks an operand of an operator. A place is not moved out of here,
+    // since a string in one is only looked at.
+    fn check_binary_operand(
+        &mut self,
+        node: &ASTNode,
+        expected: Option<&Type>,
+    ) -> Result<TypedExpr, Diagnostic> {
+        match node {
+            ASTNode::Identifier(name) if name != "None" || self.binding(name).is_some() => {}
+            ASTNode::FieldAccess { .. } | ASTNode::Index { .. } | ASTNode::Deref(_) => {}
+            _ => return self.check_expr(node, expected),
+        }
+        let outer = self.span;
+        let span = node_span(node);
+        if span.line > 0 {
+            self.span = span;
+        }
+        let value = self.check_read(node)?;
+        self.span = outer;
+        Ok(value)
+    }
+
+    fn check_binary(
+        &mut self,
+        op: BinaryOp,
+        lhs: &ASTNode,
+        rhs: &ASTNode,
+        expected: Option<&Type>,
+        span: Span,
+    ) -> Result<TypedExpr, Diagnostic> {
+        let is_comparison = matches!(
+            op,
+            BinaryOp::Equal
+                | BinaryOp::NotEqual
+                | BinaryOp::Less
+                | BinaryOp::LessEqual
+                | BinaryOp::Greater
+                | BinaryOp::GreaterEqual
+        );
+        if matches!(op, BinaryOp::And | BinaryOp::Or) {
+            let lhs = self.check_expr_as(lhs, &Type::Bool)?;
+            let rhs = self.check_expr_as(rhs, &Type::Bool)?;
+            // The right side is not always evaluated, so it has nowhere to
+            // check the `?` or keep a temporary string ahead of
+            let symbol = if op == BinaryOp::And { "&&" } else { "||" };
+            if has_try(&rhs) {
+                return Err(error(
+                    codes::INVALID_TRY,
+                    format!("Cannot use ? on the right of {symbol}"),
+                ));
+            }
+            if views_temporary(&rhs) {
+                return Err(error(codes::TEMPORARY_NEEDS_VARIABLE, format!(
+                    "A temporary String must be stored in a variable before it is used on the right of {symbol}"
+                )));
+            }
+            return Ok(binary(op, lhs, rhs, Type::Bool, span));
+        }
+        let expected = if is_comparison { None } else { expected };
+        // Type the non-literal side first so a bare literal can take its type
+        let (lhs, rhs) = if is_number_literal(lhs) && !is_number_literal(rhs) {
+            let rhs = self.check_binary_operand(rhs, expected)?;
+            (self.check_binary_operand(lhs, Some(&rhs.ty))?, rhs)
+        } else {
+            let lhs = self.check_binary_operand(lhs, expected)?;
+            (lhs.clone(), self.check_binary_operand(rhs, Some(&lhs.ty))?)
+        };
+        if lhs.ty.is_string() && rhs.ty.is_string() {
+            let lhs = self.view(lhs)?;
+            let rhs = self.view(rhs)?;
+            return string_binary(op, lhs, rhs, span);
+        }
+        let lhs = self.move_out(lhs)?;
+        let rhs = self.move_out(rhs)?;
+        if let Type::Param(_) = lhs.ty {
+            if lhs.ty != rhs.ty {
+                return Err(error(
+                    codes::MISMATCHED_TYPES,
+                    format!(
+                        "Mismatched operand types {} and {} for {op:?}",
+                        lhs.ty, rhs.ty
+                    ),
+                ));
+            }
+            let (bound, ty) = match op {
+                BinaryOp::Equal | BinaryOp::NotEqual => (Bound::Eq, Type::Bool),
+                _ if is_comparison => (Bound::Ord, Type::Bool),
+                _ => (Bound::Num, lhs.ty.clone()),
+            };
+            self.require(&lhs.ty, &bound)?;
+            return Ok(binary(op, lhs, rhs, ty, span));
+        }
+        if lhs.ty != rhs.ty {
+            return Err(error(
+                codes::MISMATCHED_TYPES,
+                format!(
+                    "Mismatched operand types {} and {} for {op:?}",
+                    lhs.ty, rhs.ty
+                ),
+            ));
+        }
+        if is_comparison {
+            let ordered = lhs.ty.is_integer() || lhs.ty == Type::Char;
+            let equatable = ordered || lhs.ty == Type::Bool;
+            if !(ordered || equatable && matches!(op, BinaryOp::Equal | BinaryOp::NotEqual)) {
+                return Err(error(
+                    codes::INVALID_OPERATION,
+                    format!("Cannot compare values of type {}", lhs.ty),
+                ));
+            }
+            return Ok(binary(op, lhs, rhs, Type::Bool, span));
+        }
+        if !lhs.ty.is_integer() {
+            return Err(error(
+                codes::INVALID_OPERATION,
+                format!("Operator {op:?} is not defined for {}", lhs.ty),
+            ));
+        }
+        let ty = lhs.ty.clone();
+        Ok(binary(op, lhs, rhs, ty, span))
+    }
+
+    fn check_call(
+        &mut self,
+        function: &str,
+        args: &[ASTNode],
+        expected: Option<&Type>,
+        span: Span,
+    ) -> Result<TypedExpr, Diagnostic> {
+        // Variables of function type shadow functions
+        if self.scopes.iter().any(|scope| scope.contains_key(function)) {
+            return self.check_value_call(&ASTNode::Identifier(function.to_string()), args);
+        }
+        if function == "panic" {
+            return self.intrinsic(Intrinsic::Panic, &[Type::Str], args, Type::Void, span);
+        }
+        if PRELUDE.contains(&function) {
+            return self.check_variant(function, args, expected);
+        }
+        if let Some(module) = self.imports.get(function) {
+            return match (module.as_str(), function) {
+                ("os", "exit") => {
+                    self.intrinsic(Intrinsic::Exit, &[Type::I32], args, Type::Void, span)
+                }
+                ("io", "print") => self.check_io(IoFunction::Print, args),
+                ("io", "println") => self.check_io(IoFunction::Println, args),
+                ("io", _) => self.check_io(IoFunction::Eprintln, args),
+                _ => Err(error(
+                    codes::UNKNOWN_ITEM,
+                    format!("Unknown function {function}"),
+                )),
+            };
+        }
+        if let Some((module, _)) = MODULES
+            .iter()
+            .find(|(_, exports)| exports.contains(&function))
+        {
+            // Imports can go first in the file
+            return Err(
+                error(codes::UNKNOWN_ITEM, format!("{function} is not imported")).with_fix(Fix {
+                    message: format!("import it from {module}"),
+                    span: Span { line: 1, column: 1 },
+                    len: 0,
+                    replacement: format!("import {function} from {module};\n"),
+                }),
+            );
+        }
+        let Some(signature) = self.functions.get(function) else {
+            return Err(error(
+                codes::UNKNOWN_ITEM,
+                format!("Unknown function {function}"),
+            ));
+        };
+        let type_params = signature.type_params.clone();
+        let params = signature.params.clone();
+        let return_type = signature.return_type.clone();
+        let display = self.function_display(function);
+        if params.len() != args.len() {
+            return Err(error(
+                codes::WRONG_ARGUMENT_COUNT,
+                format!(
+                    "{display} takes {} argument(s) but {} were given",
+                    params.len(),
+                    args.len()
+                ),
+            ));
+        }
+        let names: Vec<String> = type_params.iter().map(|(name, _)| name.clone()).collect();
+        let mut bindings = HashMap::new();
+        let args: Vec<&ASTNode> = args.iter().collect();
+        let hint = expected.map(|expected| (&return_type, expected));
+        let args = self.check_generic_args(&names, &params, &args, hint, &mut bindings)?;
+        let mut type_args = Vec::new();
+        for (name, bounds) in &type_params {
+            let ty = bindings.get(name).ok_or(error(
+                codes::TYPE_NEEDED,
+                format!("Cannot infer type parameter {name} of {display}"),
+            ))?;
+            for bound in bounds {
+                self.require(ty, bound)?;
+            }
+            if needs_drop(ty, &self.structs) {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    format!(
+                    "Type parameter {name} of {display} cannot be {ty}, since it owns heap memory"
+                ),
+                ));
+            }
+            type_args.push(ty.clone());
+        }
+        Ok(TypedExpr {
+            kind: TypedExprKind::Call {
+                function: function.to_string(),
+                type_args,
+                args,
+                span: self.span,
+            },
+            ty: substitute(&return_type, &bindings),
+        })
+    }
+
+    // Checks `Some(value)`, `None`, `Ok(value)` or `Err(error)`, which is the
+    // struct an enum is laid out as. Only `Some` can work out its own type;
+    // the others take theirs from the type expected of them.
+    fn check_variant(
+        &mut self,
+        variant: &str,
+        args: &[ASTNode],
+        expected: Option<&Type>,
+    ) -> Result<TypedExpr, Diagnostic> {
+        let (ty, payload) = match (variant, expected) {
+            ("Some", _) => {
+                let [value] = args else {
+                    return Err(error(
+                        codes::WRONG_ARGUMENT_COUNT,
+                        format!("Some takes 1 argument(s) but {} were given", args.len()),
+                    ));
+                };
+                let value = match expected {
+                    Some(Type::Option(inner)) => self.check_expr_as(value, inner)?,
+                    _ => self.check_expr(value, None)?,
+                };
+                if value.ty == Type::Void {
+                    return Err(error(codes::INVALID_TYPE, "Cannot have an Option of void"));
+                }
+                (
+                    Type::Option(Box::new(value.ty.clone())),
+                    Some(("value", value)),
+                )
+            }
+            ("None", Some(ty @ Type::Option(_))) => (ty.clone(), None),
+            ("Ok" | "Err", Some(ty @ Type::Result(value, error))) => {
+                let (field, payload) = match variant {
+                    "Ok" => ("value", value),
+                    _ => ("error", error),
+                };
+                let params: &[Type] = match **payload {
+                    Type::Void => &[],
+                    _ => std::slice::from_ref(&**payload),
+                };
+                let payload = self.check_args(variant, params, args)?.pop();
+                (ty.clone(), payload.map(|payload| (field, payload)))
+            }
+            _ => {
+                return Err(error(
+                    codes::TYPE_NEEDED,
+                    format!("Cannot infer the type of {variant}; give the variable a type"),
+                ))
+            }
+        };
+        let flag = TypedExpr {
+            kind: TypedExprKind::Literal(Literal::Bool(matches!(variant, "Some" | "Ok"))),
+            ty: Type::Bool,
+        };
+        let fields = std::iter::once((tag_field(&ty), flag))
+            .chain(payload)
+            .map(|(field, value)| (field.to_string(), value))
+            .collect();
+        Ok(TypedExpr {
+            kind: TypedExprKind::StructLiteral(fields),
+            ty,
+        })
+    }
+
+    // Checks `value?`, which gives the value inside an `Option` or `Result`,
+    // or returns its `None` or `Err` from the function. The function must
+    // return the same kind of enum, and a `Result` with the same error type.
+    fn check_try(
+        &mut self,
+        node: &ASTNode,
+        expected: Option<&Type>,
+    ) -> Result<TypedExpr, Diagnostic> {
+        let returns = self.return_type.clone();
+        let hint = match (&returns, expected) {
+            (Type::Option(_), Some(ty)) => Some(Type::Option(Box::new(ty.clone()))),
+            (Type::Result(_, error), Some(ty)) => {
+                Some(Type::Result(Box::new(ty.clone()), error.clone()))
+            }
+            _ => None,
+        };
+        let value = self.check_expr(node, hint.as_ref())?;
+        let ty = match (&value.ty, &returns) {
+            (Type::Option(inner), Type::Option(_)) => (**inner).clone(),
+            (Type::Result(inner, error), Type::Result(_, returned)) if error == returned => {
+                // The error is returned, so it must not point into a local
+                if self.may_borrow(error) {
+                    self.check_escape(&value)?;
+                }
+                (**inner).clone()
+            }
+            (Type::Result(_, thrown), Type::Result(_, returned)) => {
+                return Err(error(
+                    codes::INVALID_TRY,
+                    format!(
+                    "Cannot use ? to return an error of type {thrown} as one of type {returned}"
+                ),
+                ))
+            }
+            (Type::Option(_), _) => {
+                return Err(error(
+                    codes::INVALID_TRY,
+                    format!(
+                        "Cannot use ? on {} in a function that returns {returns}, not an Option",
+                        value.ty
+                    ),
+                ))
+            }
+            (Type::Result(..), _) => {
+                return Err(error(
+                    codes::INVALID_TRY,
+                    format!(
+                        "Cannot use ? on {} in a function that returns {returns}, not a Result",
+                        value.ty
+                    ),
+                ))
+            }
+            (ty, _) => {
+                return Err(error(
+                    codes::INVALID_TRY,
+                    format!("Cannot use ? on a value of type {ty}"),
+                ))
+            }
+        };
+        Ok(TypedExpr {
+            kind: TypedExprKind::Try {
+                value: Box::new(value),
+                returns,
+            },
+            ty,
+        })
+    }
+
+    // Checks arguments against parameters that may mention the type
+    // parameters `names`, binding each one to the type it is used with.
+    // `hint` pairs the declared result type with the type the caller expects.
+    fn check_generic_args(
+        &mut self,
+        names: &[String],
+        params: &[Type],
+        args: &[&ASTNode],
+        hint: Option<(&Type, &Type)>,
+        bindings: &mut HashMap<String, Type>,
+    ) -> Result<Vec<TypedExpr>, Diagnostic> {
+        let mut typed: Vec<Option<TypedExpr>> = vec![None; args.len()];
+        // Literals go after the other arguments so they can take their type
+        // from them, or failing that from the type the caller expects.
+        // Closures go last, to take their parameter types from everything else.
+        let (closures, rest): (Vec<usize>, Vec<usize>) =
+            (0..args.len()).partition(|&i| matches!(args[i], ASTNode::Closure { .. }));
+        let (literals, others): (Vec<usize>, Vec<usize>) =
+            rest.into_iter().partition(|&i| is_number_literal(args[i]));
+        let hint_position = others.len();
+        let apply_hint = |bindings: &mut HashMap<String, Type>| {
+            if let Some((declared, expected)) = hint {
+                let mut hinted = bindings.clone();
+                if unify(declared, expected, names, &mut hinted) {
+                    *bindings = hinted;
+                }
+            }
+        };
+        for (position, i) in others
+            .into_iter()
+            .chain(literals)
+            .chain(closures)
+            .enumerate()
+        {
+            if position == hint_position {
+                apply_hint(bindings);
+            }
+            let param = &params[i];
+            let arg = if let ASTNode::Closure {
+                params: closure_params,
+                return_type,
+                body,
+                by_value,
+            } = args[i]
+            {
+                let known = |ty: Type| (!mentions_unbound(&ty, names, bindings)).then_some(ty);
+                let hint = match substitute(param, bindings) {
+                    Type::Fn(params, return_type) => Some(ClosureHint {
+                        params: params.into_iter().map(known).collect(),
+                        return_type: known(*return_type),
+                    }),
+                    _ => None,
+                };
+                self.check_closure(closure_params, return_type, body, *by_value, hint)?
+            } else if mentions_unbound(param, names, bindings) {
+                self.check_expr(args[i], None)?
+            } else {
+                self.check_expr_as(args[i], &substitute(param, bindings))?
+            };
+            if !unify(param, &arg.ty, names, bindings) {
+                return Err(error(
+                    codes::MISMATCHED_TYPES,
+                    format!("Expected {}, found {}", substitute(param, bindings), arg.ty),
+                ));
+            }
+            typed[i] = Some(arg);
+        }
+        // Type parameters the arguments leave open may still be given by the result
+        if hint_position == args.len() {
+            apply_hint(bindings);
+        }
+        Ok(typed.into_iter().flatten().collect())
+    }
+
+    fn check_struct_literal(
+        &mut self,
+        name: &str,
+        fields: &[(String, ASTNode)],
+        expected: Option<&Type>,
+    ) -> Result<TypedExpr, Diagnostic> {
+        let definition = self
+            .structs
+            .get(name)
+            .cloned()
+            .ok_or(error(codes::UNKNOWN_ITEM, format!("Unknown struct {name}")))?;
+        for (i, (field, _)) in fields.iter().enumerate() {
+            if fields[..i].iter().any(|(other, _)| other == field) {
+                return Err(error(
+                    codes::DUPLICATE_DEFINITION,
+                    format!("Field {field} is given twice"),
+                ));
+            }
+        }
+        if let Some((missing, _)) = definition
+            .fields
+            .iter()
+            .find(|(field, _)| !fields.iter().any(|(given, _)| given == field))
+        {
+            return Err(error(
+                codes::MISSING_ITEM,
+                format!("Missing field {missing} in {name}"),
+            ));
+        }
+        let mut params = Vec::new();
+        for (field, _) in fields {
+            let (_, ty) = definition
+                .fields
+                .iter()
+                .find(|(declared, _)| declared == field)
+                .ok_or(error(
+                    codes::UNKNOWN_ITEM,
+                    format!("Struct {name} has no field {field}"),
+                ))?;
+            params.push(ty.clone());
+        }
+        let mut bindings = HashMap::new();
+        let declared = Type::Struct(
+            name.to_string(),
+            definition
+                .type_params
+                .iter()
+                .cloned()
+                .map(Type::Param)
+                .collect(),
+        );
+        let hint = expected.map(|expected| (&declared, expected));
+        let values: Vec<&ASTNode> = fields.iter().map(|(_, value)| value).collect();
+        let values = self.check_generic_args(
+            &definition.type_params,
+            &params,
+            &values,
+            hint,
+            &mut bindings,
+        )?;
+        let type_args: Vec<Type> = definition
+            .type_params
+            .iter()
+            .map(|param| {
+                bindings.get(param).cloned().ok_or(error(
+                    codes::TYPE_NEEDED,
+                    format!("Cannot infer type parameter {param} of {name}"),
+                ))
+            })
+            .collect::<Result<_, _>>()?;
+        if type_args.iter().any(contains_ref) {
+            return Err(error(
+                codes::INVALID_TYPE,
+                format!("Type arguments of {name} cannot hold references"),
+            ));
+        }
+        Ok(TypedExpr {
+            kind: TypedExprKind::StructLiteral(
+                fields
+                    .iter()
+                    .map(|(field, _)| field.clone())
+                    .zip(values)
+                    .collect(),
+            ),
+            ty: Type::Struct(name.to_string(), type_args),
+        })
+    }
+
+    fn field_type(&self, ty: &Type, field: &str) -> Result<Type, Diagnostic> {
+        let Type::Struct(name, args) = ty else {
+            return Err(error(
+                codes::UNKNOWN_ITEM,
+                format!("{ty} has no field {field}"),
+            ));
+        };
+        let definition = &self.structs[name];
+        let (_, field_type) = definition
+            .fields
+            .iter()
+            .find(|(declared, _)| declared == field)
+            .ok_or(error(
+                codes::UNKNOWN_ITEM,
+                format!("{ty} has no field {field}"),
+            ))?;
+        let bindings = definition
+            .type_params
+            .iter()
+            .cloned()
+            .zip(args.iter().cloned())
+            .collect();
+        Ok(substitute(field_type, &bindings))
+    }
+
+    fn check_args(
+        &mut self,
+        function: &str,
+        params: &[Type],
+        args: &[ASTNode],
+    ) -> Result<Vec<TypedExpr>, Diagnostic> {
+        if params.len() != args.len() {
+            return Err(error(
+                codes::WRONG_ARGUMENT_COUNT,
+                format!(
+                    "{function} takes {} argument(s) but {} were given",
+                    params.len(),
+                    args.len()
+                ),
+            ));
+        }
+        args.iter()
+            .zip(params)
+            .map(|(arg, param)| self.check_expr_as(arg, param))
+            .collect()
+    }
+
+    fn intrinsic(
+        &mut self,
+        intrinsic: Intrinsic,
+        params: &[Type],
+        args: &[ASTNode],
+        ty: Type,
+        span: Span,
+    ) -> Result<TypedExpr, Diagnostic> {
+        let args = self.check_args(&format!("{intrinsic:?}"), params, args)?;
+        Ok(intrinsic_call(intrinsic, args, ty, span))
+    }
+
+    fn check_io(
+        &mut self,
+        function: IoFunction,
+        args: &[ASTNode],
+    ) -> Result<TypedExpr, Diagnostic> {
+        let Some(ASTNode::Literal(Literal::String(format_string))) = args.first() else {
+            return Err(error(
+                codes::BAD_FORMAT_STRING,
+                format!("{function:?} expects a string literal format string"),
+            ));
+        };
+        let pieces = parse_format_string(format_string)?;
+        let placeholders = pieces
+            .iter()
+            .filter(|piece| matches!(piece, FormatPiece::Placeholder))
+            .count();
+        if placeholders != args.len() - 1 {
+            return Err(error(codes::BAD_FORMAT_STRING, format!(
+                "{function:?} format string has {placeholders} placeholder(s) but {} argument(s) were given",
+                args.len() - 1
+            )));
+        }
+        let mut values = Vec::new();
+        for arg in &args[1..] {
+            // A temporary box is checked before it is looked through, since
+            // looking through it turns it into a place
+            let value = self.check_operand(arg)?;
+            self.check_temporary(&value)?;
+            let value = auto_deref(value);
+            if !self.satisfies(&value.ty, &Bound::Display) {
+                return Err(error(
+                    codes::INVALID_OPERATION,
+                    format!("Cannot print a value of type {}", value.ty),
+                ));
+            }
+            values.push(self.view(value)?);
+        }
+        Ok(TypedExpr {
+            kind: TypedExprKind::Io {
+                function,
+                pieces,
+                args: values,
+            },
+            ty: Type::Void,
+        })
+    }
+
+    fn check_method_call(
+        &mut self,
+        receiver: &ASTNode,
+        method: &str,
+        args: &[ASTNode],
+        span: Span,
+    ) -> Result<TypedExpr, Diagnostic> {
+        let receiver_node = receiver;
+        let receiver = auto_deref(self.check_operand(receiver)?);
+        let ty = receiver.ty.clone();
+        // Asking which variant an enum holds reads its flag
+        let negated = match (&ty, method) {
+            (Type::Option(_), "is_some") | (Type::Result(..), "is_ok") => Some(false),
+            (Type::Option(_), "is_none") | (Type::Result(..), "is_err") => Some(true),
+            _ => None,
+        };
+        if let Some(negated) = negated {
+            self.check_args(method, &[], args)?;
+            self.check_temporary(&receiver)?;
+            let flag = TypedExpr {
+                kind: TypedExprKind::Field {
+                    receiver: Box::new(receiver),
+                    field: tag_field(&ty).to_string(),
+                },
+                ty: Type::Bool,
+            };
+            if !negated {
+                return Ok(flag);
+            }
+            return Ok(TypedExpr {
+                kind: TypedExprKind::Unary {
+                    op: UnaryOp::Not,
+                    operand: Box::new(flag),
+                    span,
+                },
+                ty: Type::Bool,
+            });
+        }
+        let builtin = match (&ty, method) {
+            (Type::String, "push_str") => {
+                Some((Intrinsic::StringPushStr, vec![Type::Str], Type::Void))
+            }
+            (Type::String, "push") => {
+                Some((Intrinsic::StringPushChar, vec![Type::Char], Type::Void))
+            }
+            (Type::String, "as_str") => Some((Intrinsic::StringAsStr, vec![], Type::Str)),
+            (ty, "len") if ty.is_string() => Some((Intrinsic::StrLen, vec![], Type::U64)),
+            (Type::Array(..), "len") => Some((Intrinsic::ArrayLen, vec![], Type::U64)),
+            (ty, "slice") if ty.is_string() => {
+                Some((Intrinsic::StrSlice, vec![Type::U64, Type::U64], Type::Str))
+            }
+            (ty, "to_string") if ty.is_string() => {
+                Some((Intrinsic::StringFrom, vec![], Type::String))
+            }
+            (ty, "parse_i64") if ty.is_string() => {
+                Some((Intrinsic::StrParseI64, vec![], Type::I64))
+            }
+            (ty, "to_string") if ty.is_integer() => {
+                Some((Intrinsic::IntToString, vec![], Type::String))
+            }
+            (Type::Char, "to_string") => Some((Intrinsic::CharToString, vec![], Type::String)),
+            (Type::Buffer(_), "len") => Some((Intrinsic::BufferLen, vec![], Type::U64)),
+            (Type::Buffer(element), "push") => {
+                Some((Intrinsic::BufferPush, vec![(**element).clone()], Type::Void))
+            }
+            (Type::Buffer(element), "pop") => {
+                Some((Intrinsic::BufferPop, vec![], (**element).clone()))
+            }
+            (Type::Option(value) | Type::Result(value, _), "unwrap") => {
+                Some((Intrinsic::Unwrap, vec![], (**value).clone()))
+            }
+            (Type::Option(value) | Type::Result(value, _), "unwrap_or")
+                if **value != Type::Void =>
+            {
+                Some((
+                    Intrinsic::UnwrapOr,
+                    vec![(**value).clone()],
+                    (**value).clone(),
+                ))
+            }
+            (ty, "hash") if self.satisfies(ty, &Bound::Hash) => {
+                Some((Intrinsic::Hash, vec![], Type::U64))
+            }
+            (ty, method) if ty.is_integer() => integer_method(method).map(|intrinsic| {
+                let return_type = match intrinsic {
+                    Intrinsic::Checked(_) => Type::Option(Box::new(ty.clone())),
+                    _ => ty.clone(),
+                };
+                (intrinsic, vec![ty.clone()], return_type)
+            }),
+            _ => None,
+        };
+        let Some((intrinsic, params, return_type)) = builtin else {
+            if let Some(function) = self.module_method(&ty, method) {
+                return self.check_module_method(receiver_node, receiver, &function, args);
+            }
+            return self.check_trait_call(receiver, method, args);
+        };
+        // Taking the value out of an enum consumes it, so it may be a temporary
+        let consumes = matches!(intrinsic, Intrinsic::Unwrap | Intrinsic::UnwrapOr);
+        if !consumes {
+            self.check_temporary(&receiver)?;
+        }
+        let receiver = match intrinsic {
+            _ if consumes => self.move_out(receiver)?,
+            // The string stays borrowed while the argument is worked out, so
+            // the argument cannot look into it
+            Intrinsic::StringPushStr | Intrinsic::StringPushChar => {
+                let place = self.check_place_receiver(receiver_node)?;
+                if let Some(root) = borrow_root(&place) {
+                    self.borrow(&root, true)?;
+                }
+                place
+            }
+            Intrinsic::BufferPush | Intrinsic::BufferPop => {
+                self.check_place_receiver(receiver_node)?
+            }
+            Intrinsic::StringAsStr => {
+                self.check_args(method, &params, args)?;
+                return self.view(receiver);
+            }
+            // A string is hashed as the text it holds
+            Intrinsic::Hash if ty == Type::String => self.view(receiver)?,
+            Intrinsic::IntToString
+            | Intrinsic::CharToString
+            | Intrinsic::ArrayLen
+            | Intrinsic::BufferLen
+            | Intrinsic::Hash
+            | Intrinsic::Wrapping(_)
+            | Intrinsic::Checked(_)
+            | Intrinsic::Saturating(_) => receiver,
+            _ => self.view(receiver)?,
+        };
+        let mut typed_args = vec![receiver];
+        typed_args.extend(self.check_args(method, &params, args)?);
+        Ok(intrinsic_call(intrinsic, typed_args, return_type, span))
+    }
+
+    // The function of a standard module that is a method of the struct `ty`,
+    // named like `vec_push` for `Vec`'s `push`
+    fn module_method(&self, ty: &Type, method: &str) -> Option<String> {
+        let Type::Struct(name, _) = ty else {
+            return None;
+        };
+        let module = self.module_structs.get(name)?;
+        let function = stdlib::function_name(module, &format!("{}_{method}", name.to_lowercase()));
+        self.functions.contains_key(&function).then_some(function)
+    }
+
+    // What a function is called in messages, which for a method of a standard
+    // module's struct is the method, like `Vec::push`
+    fn function_display(&self, function: &str) -> String {
+        self.module_structs
+            .iter()
+            .find_map(|(name, module)| {
+                let item = function
+                    .strip_prefix(&format!("{}{module}", module.len()))?
+                    .trim_start_matches(|c: char| c.is_ascii_digit());
+                let method = item.strip_prefix(&format!("{}_", name.to_lowercase()))?;
+                Some(format!("{name}::{method}"))
+            })
+            .unwrap_or_else(|| function.to_string())
+    }
+
+    // Checks a call of a standard module's function as a method. The receiver
+    // is borrowed the way the function's first parameter takes it, and its
+    // type gives the function's type arguments.
+    fn check_module_method(
+        &mut self,
+        receiver_node: &ASTNode,
+        receiver: TypedExpr,
+        function: &str,
+        args: &[ASTNode],
+    ) -> Result<TypedExpr, Diagnostic> {
+        let signature = self.functions[function].clone();
+        let receiver = match signature.params.first() {
+            Some(Type::Ref(_, mutable)) => {
+                self.check_temporary(&receiver)?;
+                let place = if *mutable {
+                    self.check_place_receiver(receiver_node)?
+                } else {
+                    receiver
+                };
+                if let Some(root) = borrow_root(&place) {
+                    self.borrow(&root, *mutable)?;
+                }
+                TypedExpr {
+                    ty: Type::Ref(Box::new(place.ty.clone()), *mutable),
+                    kind: TypedExprKind::Ref(Box::new(place)),
+                }
+            }
+            _ => self.move_out(receiver)?,
+        };
+        let names: Vec<String> = signature
+            .type_params
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut bindings = HashMap::new();
+        if !unify(&signature.params[0], &receiver.ty, &names, &mut bindings) {
+            return Err(error(
+                codes::MISMATCHED_TYPES,
+                format!("Expected {}, found {}", signature.params[0], receiver.ty),
+            ));
+        }
+        let params: Vec<Type> = signature.params[1..]
+            .iter()
+            .map(|param| substitute(param, &bindings))
+            .collect();
+        let display = self.function_display(function);
+        let mut typed_args = vec![receiver];
+        typed_args.extend(self.check_args(&display, &params, args)?);
+        let mut type_args = Vec::new();
+        for (name, bounds) in &signature.type_params {
+            let ty = bindings.get(name).ok_or(error(
+                codes::TYPE_NEEDED,
+                format!("Cannot infer type parameter {name} of {display}"),
+            ))?;
+            for bound in bounds {
+                self.require(ty, bound)?;
+            }
+            type_args.push(ty.clone());
+        }
+        Ok(TypedExpr {
+            kind: TypedExprKind::Call {
+                function: function.to_string(),
+                type_args,
+                args: typed_args,
+                span: self.span,
+            },
+            ty: substitute(&signature.return_type, &bindings),
+        })
+    }
+
+    // Resolves `receiver.method(args)` to a method of a trait the receiver implements
+    fn check_trait_call(
+        &mut self,
+        receiver: TypedExpr,
+        method: &str,
+        args: &[ASTNode],
+    ) -> Result<TypedExpr, Diagnostic> {
+        let ty = receiver.ty.clone();
+        let candidates: Vec<String> = match &ty {
+            Type::Param(name) => self
+                .type_params
+                .iter()
+                .filter(|(param, _)| param == name)
+                .flat_map(|(_, bounds)| bounds)
+                .filter_map(|bound| match bound {
+                    Bound::Trait(trait_name) => Some(trait_name.clone()),
+                    _ => None,
+                })
+                .collect(),
+            Type::Dyn(trait_name) => vec![trait_name.clone()],
+            ty => self
+                .impls
+                .iter()
+                .filter(|other| other.ty == *ty)
+                .map(|other| other.trait_name.clone())
+                .collect(),
+        };
+        let found: Vec<(String, TraitMethod)> = candidates
+            .iter()
+            .filter_map(|trait_name| {
+                let definition = &self.traits[trait_name];
+                let found = definition.methods.iter().find(|m| m.name == method)?;
+                Some((trait_name.clone(), found.clone()))
+            })
+            .collect();
+        let (trait_name, signature) = match &found[..] {
+            [] => {
+                return Err(error(
+                    codes::UNKNOWN_ITEM,
+                    format!("{ty} has no method {method}"),
+                ))
+            }
+            [found] => found.clone(),
+            [(first, _), (second, _), ..] => {
+                return Err(error(
+                    codes::INVALID_OPERATION,
+                    format!(
+                        "Method {method} of {ty} is ambiguous between traits {first} and {second}"
+                    ),
+                ))
+            }
+        };
+        if let Type::Dyn(_) = ty {
+            if !signature.is_dispatchable() {
+                return Err(error(
+                    codes::INVALID_TYPE,
+                    format!("Method {method} mentions Self, so it cannot be called on {ty}"),
+                ));
+            }
+        }
+        let self_binding = HashMap::from([("Self".to_string(), ty)]);
+        let params: Vec<Type> = signature
+            .params
+            .iter()
+            .map(|param| substitute(param, &self_binding))
+            .collect();
+        // The method takes the receiver by value
+        let mut typed_args = vec![self.move_out(receiver)?];
+        typed_args.extend(self.check_args(method, &params, args)?);
+        Ok(TypedExpr {
+            kind: TypedExprKind::TraitCall {
+                trait_name,
+                method: method.to_string(),
+                args: typed_args,
+            },
+            ty: substitute(&signature.return_type, &self_binding),
+        })
+    }
+}
+
+impl Default for Sema {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether control never reaches the end of `stmts`, because every path
+/// through them returns, exits or panics
+pub fn diverges(stmts: &[TypedStmt]) -> bool {
+    stmts.iter().any(|stmt| match stmt {
+        TypedStmt::Return(_) => true,
+        // There is no `break`, so only a return or exit leaves `while true`
+        TypedStmt::While {
+            condition:
+                TypedExpr {
+                    kind: TypedExprKind::Literal(Literal::Bool(true)),
+                    ..
+                },
+            ..
+        } => true,
+        TypedStmt::Expr(TypedExpr {
+            kind:
+                TypedExprKind::Intrinsic {
+                    intrinsic: Intrinsic::Exit | Intrinsic::Panic,
+                    ..
+                },
+            ..
+        }) => true,
+        TypedStmt::If {
+            then_body,
+            else_body,
+            ..
+        } => diverges(then_body) && diverges(else_body),
+        TypedStmt::Match { arms, .. } => arms.iter().all(|arm| diverges(&arm.body)),
+        _ => false,
+    })
+}
+
+/// Calls `f` on every expression in `stmts`, outermost first
+pub fn visit_exprs(stmts: &[TypedStmt], f: &mut dyn FnMut(&TypedExpr)) {
+    fn visit_expr(expr: &TypedExpr, f: &mut dyn FnMut(&TypedExpr)) {
+        f(expr);
+        match &expr.kind {
+            TypedExprKind::Literal(_) | TypedExprKind::Variable(_) | TypedExprKind::Function(_) => {
+            }
+            TypedExprKind::Closure { body, .. } => visit_exprs(body, f),
+            TypedExprKind::CallValue { callee, args } => {
+                visit_expr(callee, f);
+                for arg in args {
+                    visit_expr(arg, f);
+                }
+            }
+            TypedExprKind::Binary { lhs, rhs, .. } => {
+                visit_expr(lhs, f);
+                visit_expr(rhs, f);
+            }
+            TypedExprKind::Unary { operand, .. } => visit_expr(operand, f),
+            TypedExprKind::Cast(value)
+            | TypedExprKind::ToDyn(value)
+            | TypedExprKind::ArrayRepeat(value)
+            | TypedExprKind::Ref(value)
+            | TypedExprKind::Deref(value)
+            | TypedExprKind::Move(value)
+            | TypedExprKind::Try { value, .. }
+            | TypedExprKind::Field {
+                receiver: value, ..
+            } => visit_expr(value, f),
+            TypedExprKind::StructLiteral(fields) => {
+                for (_, value) in fields {
+                    visit_expr(value, f);
+                }
+            }
+            TypedExprKind::Index { array, index, .. } => {
+                visit_expr(array, f);
+                visit_expr(index, f);
+            }
+            TypedExprKind::Call { args, .. }
+            | TypedExprKind::TraitCall { args, .. }
+            | TypedExprKind::Intrinsic { args, .. }
+            | TypedExprKind::Io { args, .. }
+            | TypedExprKind::ArrayLiteral(args) => {
+                for arg in args {
+                    visit_expr(arg, f);
+                }
+            }
+        }
+    }
+    for stmt in stmts {
+        match stmt {
+            TypedStmt::Let { value, .. } => {
+                if let Some(value) = value {
+                    visit_expr(value, f);
+                }
+            }
+            TypedStmt::Assign { target, value }
+            | TypedStmt::CompoundAssign { target, value, .. } => {
+                visit_expr(target, f);
+                visit_expr(value, f);
+            }
+            TypedStmt::Expr(expr) | TypedStmt::Return(Some(expr)) | TypedStmt::Drop(expr) => {
+                visit_expr(expr, f)
+            }
+            TypedStmt::Return(None) => {}
+            TypedStmt::If {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                visit_expr(condition, f);
+                visit_exprs(then_body, f);
+                visit_exprs(else_body, f);
+            }
+            TypedStmt::While { condition, body } => {
+                visit_expr(condition, f);
+                visit_exprs(body, f);
+            }
+            TypedStmt::For { iterable, body, .. } => {
+                visit_expr(iterable, f);
+                visit_exprs(body, f);
+            }
+            TypedStmt::Match { scrutinee, arms } => {
+                visit_expr(scrutinee, f);
+                for arm in arms {
+                    visit_exprs(&arm.body, f);
+                }
+            }
+        }
+    }
+}
+
+// Where a node is in the source, if the parser recorded it
+fn node_span(node: &ASTNode) -> Span {
+    match node {
+        ASTNode::FnCall { span, .. }
+        | ASTNode::CompoundAssign { span, .. }
+        | ASTNode::BinaryOp { span, .. }
+        | ASTNode::UnaryOp { span, .. }
+        | ASTNode::MethodCall { span, .. }
+        | ASTNode::Index { span, .. }
+        | ASTNode::Let { span, .. }
+        | ASTNode::Assign { span, .. }
+        | ASTNode::Return { span, .. } => *span,
+        _ => Span::default(),
+    }
+}
+
+// How many single character edits turn one name into another
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// Names the statement that code after it is unreachable because of, for warnings
+fn describe_ending(node: &ASTNode) -> String {
+    let (what, span) = match node {
+        ASTNode::Return { span, .. } => ("the return".to_string(), span),
+        ASTNode::FnCall { function, span, .. } => (format!("the call to {function}"), span),
+        _ => return "a statement that never finishes".to_string(),
+    };
+    // Code from a standard module has no place in the source
+    if span.line == 0 {
+        return what;
+    }
+    format!("{what} on line {}", span.line)
+}
+
+// A semantic error with its code. What it points at is left to `Sema::check`
+// unless the place that finds it knows better.
+fn error(code: &'static str, message: impl Into<String>) -> Diagnostic {
+    Diagnostic::error(Stage::Semantic, message).with_code(code)
+}
+
+// An error for changing a variable that is not `mut`, which points at where
+// it is declared and fixes that by making it `mut`
+fn immutable(message: String, variable: &Variable) -> Diagnostic {
+    error(codes::ASSIGN_TO_IMMUTABLE, message)
+        .with_secondary(variable.span, format!("{} is declared here", variable.name))
+        .with_fix(Fix {
+            message: format!("make {} mutable", variable.name),
+            span: variable.span,
+            len: 0,
+            replacement: "mut ".to_string(),
+        })
+}
+
+// Says where an error was found, ahead of what it is
+fn prefixed(place: String, mut diagnostic: Diagnostic) -> Diagnostic {
+    diagnostic.message = format!("{place}: {}", diagnostic.message);
+    diagnostic
+}
+
+// What the label of an error says when it points at the code that was being
+// checked when the error was found
+fn label(code: &str) -> &'static str {
+    match code {
+        codes::UNKNOWN_VARIABLE => "not found in this scope",
+        codes::DUPLICATE_DEFINITION => "declared again here",
+        codes::UNKNOWN_ITEM => "not found",
+        codes::MISSING_ITEM => "incomplete",
+        codes::MISMATCHED_TYPES => "has the wrong type",
+        codes::INVALID_TYPE => "type not allowed here",
+        codes::UNSATISFIED_BOUND => "trait not implemented",
+        codes::WRONG_ARGUMENT_COUNT => "wrong number of arguments",
+        codes::USE_AFTER_MOVE => "value used after a move",
+        codes::CANNOT_MOVE => "cannot be moved",
+        codes::BORROW_CONFLICT => "conflicts with a borrow",
+        codes::ASSIGN_TO_IMMUTABLE => "cannot be changed",
+        codes::UNINITIALIZED_VARIABLE => "used before it has a value",
+        codes::TYPE_NEEDED => "type needed here",
+        codes::DANGLING_REFERENCE => "outlives what it refers to",
+        codes::MISSING_RETURN => "can finish without returning",
+        codes::NON_EXHAUSTIVE_MATCH => "some values are not matched",
+        codes::UNREACHABLE_PATTERN => "never matches",
+        codes::TEMPORARY_NEEDS_VARIABLE => "temporary used here",
+        codes::BAD_FORMAT_STRING => "in this format string",
+        codes::LITERAL_OUT_OF_RANGE => "out of range",
+        codes::NOT_CONSTANT => "not known at compile time",
+        codes::INVALID_TRY => "`?` used here",
+        _ => "not supported",
+    }
+}
+
+// What is known after a branch, from what is known at the end of each of its
+// paths and whether the path diverges. Paths that diverge never get there.
+fn join_paths(paths: Vec<(InitState, bool)>) -> InitState {
+    let all_diverge = paths.iter().all(|(_, diverges)| *diverges);
+    paths
+        .into_iter()
+        .filter(|(_, diverges)| all_diverge || !diverges)
+        .map(|(state, _)| state)
+        .reduce(InitState::join)
+        .unwrap_or_default()
+}
+
+/// Maps `wrapping_add`, `checked_div` and friends to their intrinsic
+fn integer_method(method: &str) -> Option<Intrinsic> {
+    let (mode, op) = method.split_once('_')?;
+    let op = match op {
+        "add" => BinaryOp::Add,
+        "sub" => BinaryOp::Sub,
+        "mul" => BinaryOp::Mul,
+        "div" => BinaryOp::Div,
+        "rem" => BinaryOp::Rem,
+        _ => return None,
+    };
+    match (mode, op) {
+        ("checked", op) => Some(Intrinsic::Checked(op)),
+        (_, BinaryOp::Div | BinaryOp::Rem) => None,
+        ("wrapping", op) => Some(Intrinsic::Wrapping(op)),
+        ("saturating", op) => Some(Intrinsic::Saturating(op)),
+        _ => None,
+    }
+}
+
+fn check_literal(literal: &Literal, expected: Option<&Type>) -> Result<TypedExpr, Diagnostic> {
+    let ty = match literal {
+        Literal::String(_) => Type::Str,
+        Literal::Bool(_) => Type::Bool,
+        Literal::Char(_) => Type::Char,
+        // The lexer stores literals above `i64::MAX` wrapped around
+        Literal::Number(n) => return check_number(i128::from(*n as u64), expected),
+    };
+    Ok(TypedExpr {
+        kind: TypedExprKind::Literal(literal.clone()),
+        ty,
+    })
+}
+
+// Checks an integer literal, or a negated one, against the type expected of
+// it. `u64` values above `i64::MAX` are stored wrapped around.
+fn check_number(n: i128, expected: Option<&Type>) -> Result<TypedExpr, Diagnostic> {
+    let ty = match expected {
+        Some(ty) if ty.is_integer() => ty.clone(),
+        _ => Type::I32,
+    };
+    if let Some((min, max)) = ty.integer_range() {
+        if n < min || n > max {
+            return Err(error(
+                codes::LITERAL_OUT_OF_RANGE,
+                format!("Literal {n} does not fit in {ty}"),
+            ));
+        }
+    }
+    Ok(TypedExpr {
+        kind: TypedExprKind::Literal(Literal::Number(n as i64)),
+        ty,
+    })
+}
+
+fn is_number_literal(node: &ASTNode) -> bool {
+    match node {
+        ASTNode::Literal(Literal::Number(_)) => true,
+        ASTNode::UnaryOp {
+            op: UnaryOp::Neg,
+            operand,
+            ..
+        } => is_number_literal(operand),
+        _ => false,
+    }
+}
+
+// Converts `expr` to `ty`, allowing only the implicit `String` to `str` and
+// `&mut T` to `&T` coercions
+fn coerce(expr: TypedExpr, ty: &Type) -> Result<TypedExpr, Diagnostic> {
+    if expr.ty == *ty {
+        return Ok(expr);
+    }
+    if let (Type::Ref(inner, true), Type::Ref(target, false)) = (&expr.ty, ty) {
+        if inner == target {
+            return Ok(TypedExpr {
+                ty: ty.clone(),
+                ..expr
+            });
+        }
+    }
+    if expr.ty == Type::String && *ty == Type::Str {
+        return Ok(intrinsic_call(
+            Intrinsic::StringAsStr,
+            vec![expr],
+            Type::Str,
+            Span::default(),
+        ));
+    }
+    Err(error(
+        codes::MISMATCHED_TYPES,
+        format!("Expected {ty}, found {}", expr.ty),
+    ))
+}
+
+/// Lowers an operator on two strings to the runtime functions behind it
+pub fn string_binary(
+    op: BinaryOp,
+    lhs: TypedExpr,
+    rhs: TypedExpr,
+    span: Span,
+) -> Result<TypedExpr, Diagnostic> {
+    let args = vec![coerce(lhs, &Type::Str)?, coerce(rhs, &Type::Str)?];
+    match op {
+        BinaryOp::Add => Ok(intrinsic_call(
+            Intrinsic::StrConcat,
+            args,
+            Type::String,
+            span,
+        )),
+        BinaryOp::Equal | BinaryOp::NotEqual => {
+            let equal = intrinsic_call(Intrinsic::StrEqual, args, Type::Bool, span);
+            if op == BinaryOp::Equal {
+                Ok(equal)
+            } else {
+                Ok(TypedExpr {
+                    kind: TypedExprKind::Unary {
+                        op: UnaryOp::Not,
+                        operand: Box::new(equal),
+                        span,
+                    },
+                    ty: Type::Bool,
+                })
+            }
+        }
+        BinaryOp::Less | BinaryOp::LessEqual | BinaryOp::Greater | BinaryOp::GreaterEqual => {
+            let ordering = intrinsic_call(Intrinsic::StrCompare, args, Type::I32, span);
+            let zero = TypedExpr {
+                kind: TypedExprKind::Literal(Literal::Number(0)),
+                ty: Type::I32,
+            };
+            Ok(binary(op, ordering, zero, Type::Bool, span))
+        }
+        _ => Err(error(
+            codes::INVALID_OPERATION,
+            format!("Operator {op:?} is not defined for strings"),
+        )),
+    }
+}
+
+/// Replaces the type parameters in `ty` that `bindings` has a type for
+pub fn substitute(ty: &Type, bindings: &HashMap<String, Type>) -> Type {
+    match ty {
+        Type::Param(name) => bindings.get(name).cloned().unwrap_or(ty.clone()),
+        Type::Struct(name, args) => Type::Struct(
+            name.clone(),
+            args.iter().map(|arg| substitute(arg, bindings)).collect(),
+        ),
+        Type::Fn(params, return_type) => Type::Fn(
+            params
+                .iter()
+                .map(|param| substitute(param, bindings))
+                .collect(),
+            Box::new(substitute(return_type, bindings)),
+        ),
+        Type::Array(element, len) => {
+            Type::Array(Box::new(substitute(element, bindings)), len.clone())
+        }
+        Type::Ref(inner, mutable) => Type::Ref(Box::new(substitute(inner, bindings)), *mutable),
+        Type::Box(inner) => Type::Box(Box::new(substitute(inner, bindings))),
+        Type::Buffer(element) => Type::Buffer(Box::new(substitute(element, bindings))),
+        Type::Option(value) => Type::Option(Box::new(substitute(value, bindings))),
+        Type::Result(value, error) => Type::Result(
+            Box::new(substitute(value, bindings)),
+            Box::new(substitute(error, bindings)),
+        ),
+        ty => ty.clone(),
+    }
+}
+
+// Whether `ty` mentions one of the type parameters `names` that is not bound yet
+fn mentions_unbound(ty: &Type, names: &[String], bindings: &HashMap<String, Type>) -> bool {
+    match ty {
+        Type::Param(name) => names.contains(name) && !bindings.contains_key(name),
+        Type::Struct(_, args) => args
+            .iter()
+            .any(|arg| mentions_unbound(arg, names, bindings)),
+        Type::Fn(params, return_type) => params
+            .iter()
+            .chain([&**return_type])
+            .any(|ty| mentions_unbound(ty, names, bindings)),
+        Type::Array(element, _)
+        | Type::Ref(element, _)
+        | Type::Box(element)
+        | Type::Buffer(element)
+        | Type::Option(element) => mentions_unbound(element, names, bindings),
+        Type::Result(value, error) => {
+            mentions_unbound(value, names, bindings) || mentions_unbound(error, names, bindings)
+        }
+        _ => false,
+    }
+}
+
+// Matches `param` against `actual`, binding the type parameters `names`
+fn unify(
+    param: &Type,
+    actual: &Type,
+    names: &[String],
+    bindings: &mut HashMap<String, Type>,
+) -> bool {
+    match (param, actual) {
+        (Type::Param(name), _) if names.contains(name) => match bindings.get(name) {
+            Some(bound) => bound == actual,
+            None => {
+                bindings.insert(name.clone(), actual.clone());
+                true
+            }
+        },
+        (Type::Struct(name, args), Type::Struct(actual_name, actual_args)) => {
+            name == actual_name
+                && args.len() == actual_args.len()
+                && args
+                    .iter()
+                    .zip(actual_args)
+                    .all(|(arg, actual)| unify(arg, actual, names, bindings))
+        }
+        (Type::Fn(params, return_type), Type::Fn(actual_params, actual_return)) => {
+            params.len() == actual_params.len()
+                && params
+                    .iter()
+                    .chain([&**return_type])
+                    .zip(actual_params.iter().chain([&**actual_return]))
+                    .all(|(param, actual)| unify(param, actual, names, bindings))
+        }
+        (Type::Array(element, len), Type::Array(actual_element, actual_len)) => {
+            len == actual_len && unify(element, actual_element, names, bindings)
+        }
+        // A `&mut T` is accepted where a `&T` is expected
+        (Type::Ref(inner, mutable), Type::Ref(actual_inner, actual_mutable)) => {
+            (*actual_mutable || !mutable) && unify(inner, actual_inner, names, bindings)
+        }
+        (Type::Box(inner), Type::Box(actual_inner))
+        | (Type::Buffer(inner), Type::Buffer(actual_inner))
+        | (Type::Option(inner), Type::Option(actual_inner)) => {
+            unify(inner, actual_inner, names, bindings)
+        }
+        (Type::Result(value, error), Type::Result(actual_value, actual_error)) => {
+            unify(value, actual_value, names, bindings)
+                && unify(error, actual_error, names, bindings)
+        }
+        _ => param == actual,
+    }
+}
+
+// Whether a value of type `ty` holds a reference itself, rather than in a closure
+fn contains_ref(ty: &Type) -> bool {
+    match ty {
+        Type::Ref(..) => true,
+        Type::Array(element, _)
+        | Type::Box(element)
+        | Type::Buffer(element)
+        | Type::Option(element) => contains_ref(element),
+        Type::Result(value, error) => contains_ref(value) || contains_ref(error),
+        Type::Struct(_, args) => args.iter().any(contains_ref),
+        _ => false,
+    }
+}
+
+/// Whether a value of type `ty` owns heap memory, so that it is moved rather
+/// than copied and dropped when its owner goes out of scope
+pub fn needs_drop(ty: &Type, structs: &HashMap<String, TypedStruct>) -> bool {
+    match ty {
+        Type::String | Type::Box(_) | Type::Buffer(_) => true,
+        Type::Array(element, _) | Type::Option(element) => needs_drop(element, structs),
+        Type::Result(value, error) => needs_drop(value, structs) || needs_drop(error, structs),
+        Type::Struct(name, args) => structs.get(name).is_some_and(|definition| {
+            let bindings = definition
+                .type_params
+                .iter()
+                .cloned()
+                .zip(args.iter().cloned())
+                .collect();
+            definition
+                .fields
+                .iter()
+                .any(|(_, field)| needs_drop(&substitute(field, &bindings), structs))
+        }),
+        _ => false,
+    }
+}
+
+/// The field of an `Option` or `Result` that says whether it holds `Some` or `Ok`
+pub fn tag_field(ty: &Type) -> &'static str {
+    match ty {
+        Type::Result(..) => "ok",
+        _ => "some",
+    }
+}
+
+/// The fields of the struct an `Option` or `Result` is laid out as: its flag,
+/// then the value of each variant that holds one
+pub fn variant_fields(ty: &Type) -> Vec<(String, Type)> {
+    let payloads = match ty {
+        Type::Option(value) => vec![("value", &**value)],
+        Type::Result(value, error) => vec![("value", &**value), ("error", &**error)],
+        _ => return Vec::new(),
+    };
+    std::iter::once((tag_field(ty), &Type::Bool))
+        .chain(payloads)
+        .filter(|(_, ty)| **ty != Type::Void)
+        .map(|(field, ty)| (field.to_string(), ty.clone()))
+        .collect()
+}
+
+// Whether an expression uses `?` outside of a closure
+fn has_try(expr: &TypedExpr) -> bool {
+    matches!(expr.kind, TypedExprKind::Try { .. }) || operands(&expr.kind).into_iter().any(has_try)
+}
+
+// Whether an expression looks at a `String` that nothing owns as a `str`.
+// The generator keeps such a string in a variable ahead of the statement.
+fn views_temporary(expr: &TypedExpr) -> bool {
+    match &expr.kind {
+        TypedExprKind::Intrinsic {
+            intrinsic: Intrinsic::StringAsStr,
+            args,
+            ..
+        } if !is_place(&args[0]) => true,
+        kind => operands(kind).into_iter().any(views_temporary),
+    }
+}
+
+// The path of the place an expression names, as move checking tracks it:
+// the variable's unique name, then `.field`, `.[]` for an element or `.*`
+// for what a reference or box points to
+fn move_path(place: &TypedExpr) -> Option<String> {
+    match &place.kind {
+        TypedExprKind::Variable(unique) => Some(unique.clone()),
+        TypedExprKind::Field { receiver, field } => {
+            Some(format!("{}.{field}", move_path(receiver)?))
+        }
+        TypedExprKind::Index { array, .. } => Some(format!("{}.[]", move_path(array)?)),
+        TypedExprKind::Deref(reference) => Some(format!("{}.*", move_path(reference)?)),
+        _ => None,
+    }
+}
+
+// Whether the place at `path` is part of the place at `other`, or is it
+fn within(path: &str, other: &str) -> bool {
+    path.strip_prefix(other)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+fn deref(reference: TypedExpr, ty: Type) -> TypedExpr {
+    TypedExpr {
+        kind: TypedExprKind::Deref(Box::new(reference)),
+        ty,
+    }
+}
+
+// Follows references and boxes to the value they point to, for field access,
+// indexing, method calls and printing
+fn auto_deref(expr: TypedExpr) -> TypedExpr {
+    match expr.ty.clone() {
+        Type::Ref(inner, _) | Type::Box(inner) => auto_deref(deref(expr, *inner)),
+        _ => expr,
+    }
+}
+
+// The variable a place is written in terms of, such as `a` in `a.b[0]`
+fn base_name(node: &ASTNode) -> Option<&str> {
+    match node {
+        ASTNode::Identifier(name) => Some(name),
+        ASTNode::FieldAccess { receiver, .. }
+        | ASTNode::Index { receiver, .. }
+        | ASTNode::Deref(receiver) => base_name(receiver),
+        _ => None,
+    }
+}
+
+/// Whether an expression names memory that can be borrowed
+pub fn is_place(expr: &TypedExpr) -> bool {
+    match &expr.kind {
+        TypedExprKind::Variable(_) | TypedExprKind::Deref(_) => true,
+        TypedExprKind::Field { receiver, .. } => is_place(receiver),
+        TypedExprKind::Index { array, .. } => is_place(array),
+        _ => false,
+    }
+}
+
+// The variable a place belongs to, which is what borrowing it borrows. A place
+// behind a reference belongs to the variable holding the reference.
+fn borrow_root(place: &TypedExpr) -> Option<String> {
+    match &place.kind {
+        TypedExprKind::Variable(unique) => Some(unique.clone()),
+        TypedExprKind::Field { receiver, .. } => borrow_root(receiver),
+        TypedExprKind::Index { array, .. } => borrow_root(array),
+        TypedExprKind::Deref(reference) => borrow_root(reference),
+        _ => None,
+    }
+}
+
+// The expressions an expression is made of, apart from a closure's body
+fn operands(kind: &TypedExprKind) -> Vec<&TypedExpr> {
+    match kind {
+        TypedExprKind::Literal(_)
+        | TypedExprKind::Variable(_)
+        | TypedExprKind::Function(_)
+        | TypedExprKind::Closure { .. } => Vec::new(),
+        TypedExprKind::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+        TypedExprKind::Unary { operand, .. }
+        | TypedExprKind::Cast(operand)
+        | TypedExprKind::ToDyn(operand)
+        | TypedExprKind::ArrayRepeat(operand)
+        | TypedExprKind::Ref(operand)
+        | TypedExprKind::Deref(operand)
+        | TypedExprKind::Move(operand)
+        | TypedExprKind::Try { value: operand, .. }
+        | TypedExprKind::Field {
+            receiver: operand, ..
+        } => vec![operand],
+        TypedExprKind::Call { args, .. }
+        | TypedExprKind::Intrinsic { args, .. }
+        | TypedExprKind::Io { args, .. }
+        | TypedExprKind::TraitCall { args, .. }
+        | TypedExprKind::ArrayLiteral(args) => args.iter().collect(),
+        TypedExprKind::StructLiteral(fields) => fields.iter().map(|(_, value)| value).collect(),
+        TypedExprKind::CallValue { callee, args } => {
+            std::iter::once(&**callee).chain(args).collect()
+        }
+        TypedExprKind::Index { array, index, .. } => vec![array, index],
+    }
+}
+
+// A named function as a value of function type
+fn function_value(name: &str, signature: &FnSignature) -> Result<TypedExpr, Diagnostic> {
+    if name == "main" {
+        return Err(error(
+            codes::INVALID_OPERATION,
+            "main cannot be used as a value",
+        ));
+    }
+    if !signature.type_params.is_empty() {
+        return Err(error(
+            codes::INVALID_OPERATION,
+            format!("Generic function {name} cannot be used as a value"),
+        ));
+    }
+    Ok(TypedExpr {
+        kind: TypedExprKind::Function(name.to_string()),
+        ty: Type::Fn(
+            signature.params.clone(),
+            Box::new(signature.return_type.clone()),
+        ),
+    })
+}
+
+fn intrinsic_call(intrinsic: Intrinsic, args: Vec<TypedExpr>, ty: Type, span: Span) -> TypedExpr {
+    TypedExpr {
+        kind: TypedExprKind::Intrinsic {
+            intrinsic,
+            args,
+            span,
+        },
+        ty,
+    }
+}
+
+fn binary(op: BinaryOp, lhs: TypedExpr, rhs: TypedExpr, ty: Type, span: Span) -> TypedExpr {
+    TypedExpr {
+        kind: TypedExprKind::Binary {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+            span,
+        },
+        ty,
+    }
+}
+
+/// Splits a format string into text and `{}` placeholders; `{{` and `}}` are literal braces
+fn parse_format_string(format_string: &str) -> Result<Vec<FormatPiece>, Diagnostic> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = format_string.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                text.push(c);
+                chars.next();
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                if !text.is_empty() {
+                    pieces.push(FormatPiece::Text(std::mem::take(&mut text)));
+                }
+                pieces.push(FormatPiece::Placeholder);
+            }
+            ('{', _) => {
+                return Err(error(
+                    codes::BAD_FORMAT_STRING,
+                    "Unclosed `{` in format string",
+                ))
+            }
+            ('}', _) => {
+                return Err(error(
+                    codes::BAD_FORMAT_STRING,
+                    "Unmatched `}` in format string",
+                ))
+            }
+            _ => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(FormatPiece::Text(text));
+    }
+    Ok(pieces)
+}
+
+pub fn check(ast: &[ASTNode]) -> Result<TypedProgram, Diagnostic> {
+    Sema::new().check(ast)
+}
@@ -0,0 +1,307 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use crate::ir::{BlockId, Function, Instruction, Operand, Reg, Register};
+
+/// The dominator tree of a function whose blocks are all reachable
+pub struct Dominators {
+    /// The immediate dominator of each block; the first block is its own
+    idom: Vec<BlockId>,
+}
+
+impl Dominators {
+    /// Finds the dominators with the iterative algorithm of Cooper, Harvey
+    /// and Kennedy, which walks the blocks in reverse postorder until the
+    /// immediate dominators stop changing
+    pub fn new(function: &Function) -> Self {
+        let order = reverse_postorder(function);
+        let mut position = vec![usize::MAX; function.blocks.len()];
+        for (i, block) in order.iter().enumerate() {
+            position[*block] = i;
+        }
+        let predecessors = function.predecessors();
+        let mut idom: Vec<Option<BlockId>> = vec![None; function.blocks.len()];
+        idom[0] = Some(0);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in order.iter().skip(1) {
+                let mut processed = predecessors[block]
+                    .iter()
+                    .copied()
+                    .filter(|predecessor| idom[*predecessor].is_some());
+                let Some(first) = processed.next() else {
+                    continue;
+                };
+                let new_idom = processed.fold(first, |mut a, mut b| {
+                    while a != b {
+                        while position[a] > position[b] {
+                            a = idom[a].unwrap_or(0);
+                        }
+                        while position[b] > position[a] {
+                            b = idom[b].unwrap_or(0);
+                        }
+                    }
+                    a
+                });
+                if idom[block] != Some(new_idom) {
+                    idom[block] = Some(new_idom);
+                    changed = true;
+                }
+            }
+        }
+        Dominators {
+            idom: idom.into_iter().map(|idom| idom.unwrap_or(0)).collect(),
+        }
+    }
+
+    /// Whether every path from the first block to `b` goes through `a`
+    pub fn dominates(&self, a: BlockId, mut b: BlockId) -> bool {
+        while b != a {
+            if b == 0 {
+                return false;
+            }
+            b = self.idom[b];
+        }
+        true
+    }
+
+    /// The blocks each block immediately dominates
+    pub fn children(&self) -> Vec<Vec<BlockId>> {
+        let mut children = vec![Vec::new(); self.idom.len()];
+        for (block, idom) in self.idom.iter().enumerate().skip(1) {
+            children[*idom].push(block);
+        }
+        children
+    }
+
+    /// The dominance frontier of each block: the blocks it does not strictly
+    /// dominate but dominates a predecessor of
+    pub fn frontiers(&self, function: &Function) -> Vec<Vec<BlockId>> {
+        let mut frontiers = vec![Vec::new(); self.idom.len()];
+        for (block, predecessors) in function.predecessors().iter().enumerate() {
+            if predecessors.len() < 2 {
+                continue;
+            }
+            for &predecessor in predecessors {
+                let mut runner = predecessor;
+                while runner != self.idom[block] {
+                    if !frontiers[runner].contains(&block) {
+                        frontiers[runner].push(block);
+                    }
+                    if runner == 0 {
+                        break;
+                    }
+                    runner = self.idom[runner];
+                }
+            }
+        }
+        frontiers
+    }
+}
+
+/// The blocks reachable from the first block, each before its successors
+/// except along loops
+pub fn reverse_postorder(function: &Function) -> Vec<BlockId> {
+    let mut visited = vec![false; function.blocks.len()];
+    let mut order = Vec::new();
+    // Each entry is a block and how many of its successors have been visited
+    let mut stack = vec![(0, 0)];
+    visited[0] = true;
+    while let Some((block, next)) = stack.pop() {
+        let successors = function.blocks[block].terminator.successors();
+        if let Some(&successor) = successors.get(next) {
+            stack.push((block, next + 1));
+            if !std::mem::replace(&mut visited[successor], true) {
+                stack.push((successor, 0));
+            }
+        } else {
+            order.push(block);
+        }
+    }
+    order.reverse();
+    order
+}
+
+/// Which registers can be put in SSA form: those whose address is never
+/// taken, so that they only change when an instruction writes them
+pub fn promotable(function: &Function) -> Vec<bool> {
+    let mut promotable = vec![true; function.regs.len()];
+    for block in &function.blocks {
+        for instruction in &block.instructions {
+            if let Instruction::AddressOf { reg, .. } = instruction {
+                promotable[*reg] = false;
+            }
+        }
+    }
+    promotable
+}
+
+/// Puts a function in SSA form, where every promotable register is written
+/// by one instruction. Each write gets a register of its own, and a phi
+/// joins them where paths meet. A register read before it is written keeps
+/// standing for its zero value.
+pub fn to_ssa(function: &mut Function) {
+    function.remove_unreachable_blocks();
+    let promotable = promotable(function);
+    let dominators = Dominators::new(function);
+    let frontiers = dominators.frontiers(function);
+    let mut written_in = vec![Vec::new(); function.regs.len()];
+    for (id, block) in function.blocks.iter().enumerate() {
+        for instruction in &block.instructions {
+            if let Some(dest) = instruction.dest().filter(|dest| promotable[*dest]) {
+                if !written_in[dest].contains(&id) {
+                    written_in[dest].push(id);
+                }
+            }
+        }
+    }
+    // The register each phi at the start of a block joins the writes of
+    let mut joined = vec![Vec::new(); function.blocks.len()];
+    for (reg, blocks) in written_in.iter().enumerate() {
+        let mut has_phi = vec![false; function.blocks.len()];
+        let mut worklist = blocks.clone();
+        while let Some(block) = worklist.pop() {
+            for &frontier in &frontiers[block] {
+                if !std::mem::replace(&mut has_phi[frontier], true) {
+                    joined[frontier].push(reg);
+                    if !blocks.contains(&frontier) {
+                        worklist.push(frontier);
+                    }
+                }
+            }
+        }
+    }
+    for (block, regs) in function.blocks.iter_mut().zip(&joined) {
+        let phis = regs.iter().map(|reg| Instruction::Phi {
+            dest: *reg,
+            incoming: Vec::new(),
+        });
+        block.instructions.splice(0..0, phis);
+    }
+    let mut renamer = Renamer {
+        promotable,
+        joined,
+        children: dominators.children(),
+        current: (0..function.regs.len()).map(|reg| vec![reg]).collect(),
+    };
+    renamer.rename(function, 0);
+}
+
+struct Renamer {
+    promotable: Vec<bool>,
+    joined: Vec<Vec<Reg>>,
+    children: Vec<Vec<BlockId>>,
+    /// The registers holding the value of each original register, innermost last
+    current: Vec<Vec<Reg>>,
+}
+
+impl Renamer {
+    fn value(&self, reg: Reg) -> Reg {
+        self.current[reg].last().copied().unwrap_or(reg)
+    }
+
+    fn rename_operand(&self, operand: &mut Operand) {
+        if let Operand::Reg(reg) = operand {
+            if self.promotable[*reg] {
+                *reg = self.value(*reg);
+            }
+        }
+    }
+
+    // Renames the registers of a block and of the blocks it dominates
+    fn rename(&mut self, function: &mut Function, block: BlockId) {
+        let mut written = Vec::new();
+        for instruction in &mut function.blocks[block].instructions {
+            if !matches!(instruction, Instruction::Phi { .. }) {
+                for operand in instruction.operands_mut() {
+                    self.rename_operand(operand);
+                }
+            }
+            if let Some(dest) = instruction
+                .dest_mut()
+                .filter(|dest| self.promotable[**dest])
+            {
+                let original = *dest;
+                let renamed = function.regs.len();
+                function.regs.push(Register {
+                    ty: function.regs[original].ty.clone(),
+                    name: function.regs[original].name.clone(),
+                });
+                self.promotable.push(true);
+                self.current.push(Vec::new());
+                self.current[original].push(renamed);
+                written.push(original);
+                *dest = renamed;
+            }
+        }
+        for operand in function.blocks[block].terminator.operands_mut() {
+            self.rename_operand(operand);
+        }
+        let successors = function.blocks[block].terminator.successors();
+        for successor in successors {
+            let values: Vec<Reg> = self.joined[successor]
+                .iter()
+                .map(|reg| self.value(*reg))
+                .collect();
+            let phis = function.blocks[successor].instructions.iter_mut();
+            for (phi, value) in phis.zip(values) {
+                if let Instruction::Phi { incoming, .. } = phi {
+                    if !incoming.iter().any(|(from, _)| *from == block) {
+                        incoming.push((block, Operand::Reg(value)));
+                    }
+                }
+            }
+        }
+        for child in self.children[block].clone() {
+            self.rename(function, child);
+        }
+        for original in written {
+            self.current[original].pop();
+        }
+    }
+}
+
+/// Takes a function out of SSA form. Each phi gets a register of its own
+/// that its predecessors copy their values to as they end, which it is
+/// then copied from, so phis that swap values still read the old ones.
+pub fn from_ssa(function: &mut Function) {
+    for block in 0..function.blocks.len() {
+        for index in 0..function.blocks[block].instructions.len() {
+            let Instruction::Phi { dest, incoming } = &function.blocks[block].instructions[index]
+            else {
+                continue;
+            };
+            let (dest, incoming) = (*dest, incoming.clone());
+            let joined = function.regs.len();
+            function.regs.push(Register {
+                ty: function.regs[dest].ty.clone(),
+                name: None,
+            });
+            for (from, value) in incoming {
+                function.blocks[from].instructions.push(Instruction::Copy {
+                    dest: joined,
+                    value,
+                });
+            }
+            function.blocks[block].instructions[index] = Instruction::Copy {
+                dest,
+                value: Operand::Reg(joined),
+            };
+        }
+    }
+}
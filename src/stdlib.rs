@@ -0,0 +1,57 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use crate::diagnostic::Diagnostic;
+use crate::lexer::{self, Span, Token};
+use crate::parser::{self, ASTNode};
+
+/// Each module written in Nimra, with its source
+const SOURCES: [(&str, &str); 1] = [("collections", include_str!("std/collections.nimra"))];
+
+/// Parses the module called `name`, if it is written in Nimra. Its code has
+/// no place in the program's source, so it is given no spans, and panics in
+/// it report no location. Its functions are renamed by `function_name`.
+pub fn load(name: &str) -> Option<Result<Vec<ASTNode>, Diagnostic>> {
+    let (_, source) = SOURCES.iter().find(|(module, _)| *module == name)?;
+    let (mut tokens, _) = match lexer::lex(source) {
+        Ok(lexed) => lexed,
+        Err(mut diagnostics) => return Some(Err(diagnostics.swap_remove(0))),
+    };
+    let functions: Vec<String> = tokens
+        .windows(2)
+        .filter_map(|pair| match pair {
+            [Token::Fn, Token::Identifier(function)] => Some(function.clone()),
+            _ => None,
+        })
+        .collect();
+    for token in &mut tokens {
+        if let Token::Identifier(identifier) = token {
+            if functions.contains(identifier) {
+                *identifier = function_name(name, identifier);
+            }
+        }
+    }
+    let spans = vec![Span::default(); tokens.len()];
+    Some(parser::parse(&tokens, &spans))
+}
+
+/// The name the function `name` of a module has in the program, which starts
+/// with a digit as no identifier can. The program's own functions never clash
+/// with it, and only reach it as a method of one of the module's structs.
+pub fn function_name(module: &str, name: &str) -> String {
+    format!("{}{module}{}{name}", module.len(), name.len())
+}
@@ -0,0 +1,385 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::collections::HashMap;
+
+use crate::generator::ICInstruction;
+use crate::ir::{BlockId, Function, Instruction, Operand, Reg, Terminator};
+use crate::lexer::Type;
+use crate::parser::BinaryOp;
+use crate::ssa::{self, Dominators};
+
+/// The parameter and return types of every function, which calls are
+/// checked against
+pub type Signatures = HashMap<String, (Vec<Type>, Type)>;
+
+pub fn signatures(ic: &[ICInstruction]) -> Signatures {
+    functions(ic)
+        .map(|function| {
+            let params = function
+                .params
+                .iter()
+                .filter_map(|reg| function.regs.get(*reg))
+                .map(|register| register.ty.clone())
+                .collect();
+            (
+                function.name.clone(),
+                (params, function.return_type.clone()),
+            )
+        })
+        .collect()
+}
+
+fn functions(ic: &[ICInstruction]) -> impl Iterator<Item = &Function> {
+    ic.iter().filter_map(|instruction| match instruction {
+        ICInstruction::Function(function) => Some(function),
+        _ => None,
+    })
+}
+
+/// Checks that every function of a program is well formed, as
+/// `verify_function` does
+pub fn verify(ic: &[ICInstruction], ssa: bool) -> Result<(), String> {
+    let signatures = signatures(ic);
+    for function in functions(ic) {
+        verify_function(function, &signatures, ssa)?;
+    }
+    Ok(())
+}
+
+/// Checks that a function is well formed: its blocks and registers exist,
+/// its instructions are given values of the types they expect, and every
+/// register written once is written before it is read. In SSA form, every
+/// register whose address is not taken is also written at most once.
+pub fn verify_function(
+    function: &Function,
+    signatures: &Signatures,
+    ssa: bool,
+) -> Result<(), String> {
+    check_function(function, signatures, ssa)
+        .map_err(|e| format!("In function {}: {e}", function.name))
+}
+
+fn check_function(function: &Function, signatures: &Signatures, ssa: bool) -> Result<(), String> {
+    let verifier = Verifier { function };
+    if function.blocks.is_empty() {
+        return Err("The function has no blocks".to_string());
+    }
+    for reg in function.params.iter().chain(&function.env) {
+        verifier.reg(*reg)?;
+    }
+    for (id, block) in function.blocks.iter().enumerate() {
+        for (index, instruction) in block.instructions.iter().enumerate() {
+            verifier
+                .instruction(instruction, signatures)
+                .map_err(|e| format!("b{id}, instruction {index}: {e}"))?;
+        }
+        verifier
+            .terminator(&block.terminator)
+            .map_err(|e| format!("b{id}, terminator: {e}"))?;
+    }
+    verifier.phis()?;
+    verifier.dominance(ssa)
+}
+
+struct Verifier<'a> {
+    function: &'a Function,
+}
+
+impl Verifier<'_> {
+    fn reg(&self, reg: Reg) -> Result<&Type, String> {
+        self.function
+            .regs
+            .get(reg)
+            .map(|register| &register.ty)
+            .ok_or(format!("Register %{reg} is not declared"))
+    }
+
+    fn block(&self, block: BlockId) -> Result<(), String> {
+        if block >= self.function.blocks.len() {
+            return Err(format!("Block b{block} does not exist"));
+        }
+        Ok(())
+    }
+
+    fn operand(&self, operand: &Operand) -> Result<Type, String> {
+        match operand {
+            Operand::Reg(reg) => self.reg(*reg).cloned(),
+            Operand::Const(_, ty) => Ok(ty.clone()),
+        }
+    }
+
+    // Checks that a value has the type it is expected to
+    fn expect(&self, what: &str, operand: &Operand, expected: &Type) -> Result<(), String> {
+        let found = self.operand(operand)?;
+        if !fits(&found, expected) {
+            return Err(format!("{what} should be {expected}, found {found}"));
+        }
+        Ok(())
+    }
+
+    // The type a value must be the address of. A box is the address of
+    // the value it holds.
+    fn pointee(&self, address: &Operand) -> Result<Type, String> {
+        match self.operand(address)? {
+            Type::Ref(inner, _) | Type::Box(inner) => Ok(*inner),
+            ty => Err(format!("Expected an address, found {ty}")),
+        }
+    }
+
+    fn instruction(
+        &self,
+        instruction: &Instruction,
+        signatures: &Signatures,
+    ) -> Result<(), String> {
+        for operand in instruction.operands() {
+            self.operand(operand)?;
+        }
+        let dest = match instruction.dest() {
+            Some(dest) => Some(self.reg(dest)?.clone()),
+            None => None,
+        };
+        let dest_type = dest.clone().unwrap_or(Type::Void);
+        match instruction {
+            Instruction::Copy { value, .. } => self.expect("The value copied", value, &dest_type),
+            Instruction::Phi { incoming, .. } => {
+                for (from, value) in incoming {
+                    self.block(*from)?;
+                    self.expect(&format!("The value from b{from}"), value, &dest_type)?;
+                }
+                Ok(())
+            }
+            Instruction::Binary { op, lhs, rhs, .. } => {
+                let lhs_type = self.operand(lhs)?;
+                self.expect("The right operand", rhs, &lhs_type)?;
+                let result = match op {
+                    BinaryOp::Equal
+                    | BinaryOp::NotEqual
+                    | BinaryOp::Less
+                    | BinaryOp::LessEqual
+                    | BinaryOp::Greater
+                    | BinaryOp::GreaterEqual => Type::Bool,
+                    _ => lhs_type,
+                };
+                if result != dest_type {
+                    return Err(format!("The result should be {dest_type}, found {result}"));
+                }
+                Ok(())
+            }
+            Instruction::Unary { operand, .. } => self.expect("The operand", operand, &dest_type),
+            Instruction::Call { function, args, .. } => {
+                let (params, return_type) = signatures
+                    .get(function)
+                    .ok_or(format!("Function {function} does not exist"))?;
+                self.call(args, params)?;
+                if dest.is_some() && dest_type != *return_type {
+                    return Err(format!(
+                        "{function} returns {return_type}, which is written to a {dest_type}"
+                    ));
+                }
+                Ok(())
+            }
+            Instruction::CallValue { callee, args, .. } => match self.operand(callee)? {
+                Type::Fn(params, _) => self.call(args, &params),
+                ty => Err(format!("Expected a function to call, found {ty}")),
+            },
+            Instruction::AddressOf { reg, .. } => match &dest_type {
+                Type::Ref(inner, _) if **inner == *self.reg(*reg)? => Ok(()),
+                _ => Err(format!(
+                    "The address of %{reg} should be a reference to {}, found {dest_type}",
+                    self.reg(*reg)?
+                )),
+            },
+            Instruction::Load { address, .. } | Instruction::Move { address, .. } => {
+                let pointee = self.pointee(address)?;
+                if pointee != dest_type {
+                    return Err(format!("Read a {pointee} into a {dest_type}"));
+                }
+                Ok(())
+            }
+            Instruction::Store { address, value } => {
+                self.expect("The value stored", value, &self.pointee(address)?)
+            }
+            Instruction::Drop { address } => self.pointee(address).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    fn call(&self, args: &[Operand], params: &[Type]) -> Result<(), String> {
+        if args.len() != params.len() {
+            return Err(format!(
+                "Expected {} arguments, found {}",
+                params.len(),
+                args.len()
+            ));
+        }
+        for (i, (arg, param)) in args.iter().zip(params).enumerate() {
+            self.expect(&format!("Argument {}", i + 1), arg, param)?;
+        }
+        Ok(())
+    }
+
+    fn terminator(&self, terminator: &Terminator) -> Result<(), String> {
+        for target in terminator.successors() {
+            self.block(target)?;
+        }
+        for operand in terminator.operands() {
+            self.operand(operand)?;
+        }
+        match terminator {
+            Terminator::Branch { condition, .. } => {
+                self.expect("The condition", condition, &Type::Bool)
+            }
+            Terminator::Return(Some(value)) => {
+                self.expect("The value returned", value, &self.function.return_type)
+            }
+            Terminator::Return(None) if self.function.return_type != Type::Void => Err(format!(
+                "Returns nothing from a function returning {}",
+                self.function.return_type
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Checks that phis start their blocks and have one value from each
+    // predecessor
+    fn phis(&self) -> Result<(), String> {
+        let predecessors = self.function.predecessors();
+        for (id, block) in self.function.blocks.iter().enumerate() {
+            let mut phis = true;
+            for instruction in &block.instructions {
+                let Instruction::Phi { incoming, .. } = instruction else {
+                    phis = false;
+                    continue;
+                };
+                if !phis {
+                    return Err(format!("b{id} has a phi after other instructions"));
+                }
+                let mut from: Vec<BlockId> = incoming.iter().map(|(from, _)| *from).collect();
+                from.sort_unstable();
+                let mut expected = predecessors[id].clone();
+                expected.sort_unstable();
+                if from != expected {
+                    return Err(format!(
+                        "A phi in b{id} has values from {}, but the block is entered from {}",
+                        blocks_text(&from),
+                        blocks_text(&expected)
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Checks that a register written once, other than a parameter, is
+    // written before any path reaches a read of it. A register that is
+    // never written stands for its zero value, so can be read anywhere.
+    fn dominance(&self, ssa: bool) -> Result<(), String> {
+        let function = self.function;
+        let mut defs: Vec<Vec<(BlockId, usize)>> = vec![Vec::new(); function.regs.len()];
+        for reg in function.params.iter().chain(&function.env) {
+            defs[*reg].push((0, 0));
+        }
+        for (id, block) in function.blocks.iter().enumerate() {
+            for (index, instruction) in block.instructions.iter().enumerate() {
+                if let Some(dest) = instruction.dest() {
+                    // A write counts from the instruction after it
+                    defs[dest].push((id, index + 1));
+                }
+            }
+        }
+        if ssa {
+            let promotable = ssa::promotable(function);
+            for (reg, defs) in defs.iter().enumerate() {
+                if promotable[reg] && defs.len() > 1 {
+                    return Err(format!(
+                        "%{reg} is written {} times in SSA form",
+                        defs.len()
+                    ));
+                }
+            }
+        }
+        let dominators = Dominators::new(function);
+        let mut reachable = vec![false; function.blocks.len()];
+        for block in ssa::reverse_postorder(function) {
+            reachable[block] = true;
+        }
+        // Whether the write of a register comes before a read at a place
+        let defined_at = |reg: Reg, block: BlockId, index: usize| match defs[reg].as_slice() {
+            [(def_block, def_index)] if *def_block == block => *def_index <= index,
+            [(def_block, _)] => dominators.dominates(*def_block, block),
+            _ => true,
+        };
+        for (id, block) in function.blocks.iter().enumerate() {
+            if !reachable[id] {
+                continue;
+            }
+            let end = block.instructions.len();
+            for (index, instruction) in block.instructions.iter().enumerate() {
+                if let Instruction::Phi { incoming, .. } = instruction {
+                    // A phi reads its value as the block it comes from ends
+                    for (from, value) in incoming {
+                        if let Operand::Reg(reg) = value {
+                            let from_end = function.blocks[*from].instructions.len();
+                            if reachable[*from] && !defined_at(*reg, *from, from_end) {
+                                return Err(format!(
+                                    "%{reg} is read in a phi in b{id} before it is written"
+                                ));
+                            }
+                        }
+                    }
+                    continue;
+                }
+                for operand in instruction.operands() {
+                    if let Operand::Reg(reg) = operand {
+                        if !defined_at(*reg, id, index) {
+                            return Err(format!("%{reg} is read in b{id} before it is written"));
+                        }
+                    }
+                }
+            }
+            for operand in block.terminator.operands() {
+                if let Operand::Reg(reg) = operand {
+                    if !defined_at(*reg, id, end) {
+                        return Err(format!("%{reg} is read in b{id} before it is written"));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Whether a value of one type can be used where another is expected, as
+// a mutable reference can be where a shared one is
+fn fits(found: &Type, expected: &Type) -> bool {
+    match (found, expected) {
+        (Type::Ref(found, true), Type::Ref(expected, false)) => found == expected,
+        _ => found == expected,
+    }
+}
+
+fn blocks_text(blocks: &[BlockId]) -> String {
+    if blocks.is_empty() {
+        return "nowhere".to_string();
+    }
+    blocks
+        .iter()
+        .map(|block| format!("b{block}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
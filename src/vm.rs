@@ -0,0 +1,991 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::env;
+use std::io::{self, BufWriter, Stdout, Write};
+use std::mem;
+use std::rc::Rc;
+use std::thread;
+
+use crate::bytecode::{Arg, Constant, Kind, Op, Piece, Program, Shape};
+use crate::interp::{self, Overflow};
+use crate::lexer::Span;
+use crate::parser::BinaryOp;
+use crate::sema::{Intrinsic, IoFunction};
+
+/// Calls are kept on a stack of their own, so the VM only needs a large
+/// stack of its own to free long chains of boxes, which recurses
+const STACK_SIZE: usize = 1 << 28;
+
+/// How deep calls may nest before the program is stopped, as a compiled
+/// program would be when it runs out of stack
+const MAX_DEPTH: usize = 1 << 20;
+
+/// A register's value, or anything a pointer can point to
+type Cell = Rc<RefCell<Value>>;
+
+type Items = Rc<RefCell<Vec<Value>>>;
+
+/// A value while the program runs. Integers of every type are held in 64
+/// bits, sign-extended if their type is signed.
+#[derive(Clone)]
+enum Value {
+    Void,
+    Int(i64),
+    Bool(bool),
+    Char(char),
+    /// The text between two byte offsets of a buffer
+    Str(Rc<RefCell<String>>, usize, usize),
+    /// The first bytes of a buffer, which is only ever appended to. Copies
+    /// share the buffer until one of them grows.
+    String(Rc<RefCell<String>>, usize),
+    /// The fields of a struct, `Option` or `Result`, copied when changed
+    /// while shared
+    Struct(Rc<Vec<Value>>),
+    Array(Rc<Vec<Value>>),
+    /// A reference or box
+    Pointer(Rc<Place>),
+    Buffer(Items),
+    /// A function, and the environment it is called with if it is a
+    /// closure that captures variables
+    Fn(u32, Option<Rc<Value>>),
+    /// A `dyn Trait`: its vtable and the value it holds
+    Dyn(Rc<(u32, Value)>),
+    /// The zero value of a pointer, buffer, function or `dyn Trait`
+    Null,
+}
+
+/// Where a pointer points: a cell or an element of a buffer, then the
+/// fields and array elements inside it
+struct Place {
+    root: Root,
+    path: Vec<u32>,
+}
+
+#[derive(Clone)]
+enum Root {
+    Cell(Cell),
+    Element(Items, usize),
+}
+
+/// Why the program stopped
+enum Halt {
+    /// It exits with this code, from `exit` or a panic
+    Exit(i32),
+    /// Something the bytecode compiler should have ruled out
+    Error(String),
+}
+
+type Outcome<T> = Result<T, Halt>;
+
+/// A call waiting for the one it made to return
+struct Frame {
+    function: u32,
+    pc: usize,
+    regs: Vec<Value>,
+    /// Where the caller wants the value returned
+    dest: Option<u32>,
+}
+
+struct Vm<'p> {
+    program: &'p Program,
+    constants: Vec<Value>,
+    /// The zero value of each shape
+    zeros: Vec<Value>,
+    /// The function running, where it is, and its registers
+    function: u32,
+    pc: usize,
+    regs: Vec<Value>,
+    callers: Vec<Frame>,
+    stdout: BufWriter<Stdout>,
+}
+
+impl<'p> Vm<'p> {
+    fn new(program: &'p Program) -> Vm<'p> {
+        let constants = program
+            .constants
+            .iter()
+            .map(|constant| match constant {
+                Constant::Int(n) => Value::Int(*n),
+                Constant::Bool(b) => Value::Bool(*b),
+                Constant::Char(c) => Value::Char(*c),
+                Constant::Str(id) => {
+                    let text = program.strings[*id as usize].clone();
+                    let len = text.len();
+                    Value::Str(Rc::new(RefCell::new(text)), 0, len)
+                }
+            })
+            .collect();
+        // Shapes only refer to those before them
+        let mut zeros: Vec<Value> = Vec::new();
+        for shape in &program.shapes {
+            let zero = match shape {
+                Shape::Void => Value::Void,
+                Shape::Int => Value::Int(0),
+                Shape::Bool => Value::Bool(false),
+                Shape::Char => Value::Char('\0'),
+                Shape::Str => Value::Str(Rc::default(), 0, 0),
+                Shape::String => Value::String(Rc::default(), 0),
+                Shape::Null => Value::Null,
+                Shape::Struct(fields) => Value::Struct(Rc::new(
+                    fields
+                        .iter()
+                        .map(|field| zeros[*field as usize].clone())
+                        .collect(),
+                )),
+                Shape::Array(element, len) => Value::Array(Rc::new(vec![
+                    zeros[*element as usize]
+                        .clone();
+                    *len as usize
+                ])),
+            };
+            zeros.push(zero);
+        }
+        Vm {
+            program,
+            constants,
+            zeros,
+            function: program.main,
+            pc: 0,
+            regs: Vec::new(),
+            callers: Vec::new(),
+            stdout: BufWriter::new(io::stdout()),
+        }
+    }
+
+    // Runs `main`, returning the code the program exits with
+    fn run(mut self) -> Result<i32, String> {
+        let code = match self.execute() {
+            Ok(result) => self.finish(&result),
+            Err(halt) => Err(halt),
+        };
+        let code = match code {
+            Ok(code) | Err(Halt::Exit(code)) => code,
+            Err(Halt::Error(e)) => return Err(e),
+        };
+        match self.flush() {
+            Ok(()) => Ok(code),
+            Err(Halt::Error(e)) => Err(e),
+            Err(Halt::Exit(code)) => Ok(code),
+        }
+    }
+
+    // A `main` that returns a Result prints the error and fails if there is one
+    fn finish(&mut self, result: &Value) -> Outcome<i32> {
+        let Some(kind) = self.program.main_error else {
+            return Ok(0);
+        };
+        let Value::Struct(fields) = result else {
+            return Err(error("main did not return a Result"));
+        };
+        if let Some(Value::Bool(true)) = fields.first() {
+            return Ok(0);
+        }
+        let error = fields
+            .last()
+            .ok_or_else(|| error("main returned a Result with no error"))?;
+        let line = format!("Error: {}\n", display(error, kind)?);
+        self.eprint(&line)?;
+        Ok(1)
+    }
+
+    // Runs the program from the start of `main` until it returns
+    fn execute(&mut self) -> Outcome<Value> {
+        let program = self.program;
+        self.regs = self.frame(program.main, None, Vec::new())?;
+        loop {
+            let code = &program.functions[self.function as usize];
+            let op = code
+                .ops
+                .get(self.pc)
+                .ok_or_else(|| error("Ran past the end of a function"))?;
+            self.pc += 1;
+            match op {
+                Op::Copy { dest, value } => self.set(*dest, self.get(*value)),
+                Op::Zero { dest } => {
+                    let zero = self.zeros[code.regs[*dest as usize] as usize].clone();
+                    self.set(*dest, zero);
+                }
+                Op::Binary {
+                    dest,
+                    op,
+                    kind,
+                    lhs,
+                    rhs,
+                    at,
+                } => {
+                    let value = self.binary(*op, *kind, *lhs, *rhs, *at)?;
+                    self.set(*dest, value);
+                }
+                Op::Neg {
+                    dest,
+                    kind,
+                    value,
+                    at,
+                } => {
+                    let n = kind.widen(self.int(*value)?);
+                    let range = range(*kind)?;
+                    match interp::negate(n, range, !program.debug) {
+                        Ok(n) => self.set(*dest, Value::Int(n as i64)),
+                        Err(message) => return Err(self.panic(*at, message)),
+                    }
+                }
+                Op::Not { dest, value } => {
+                    let b = self.bool(*value)?;
+                    self.set(*dest, Value::Bool(!b));
+                }
+                Op::Cast { dest, kind, value } => {
+                    let value = cast(self.get(*value), *kind);
+                    self.set(*dest, value);
+                }
+                Op::Call {
+                    dest,
+                    function,
+                    args,
+                } => {
+                    let args = self.gets(args);
+                    self.enter(*function, None, args, *dest)?;
+                }
+                Op::CallValue { dest, callee, args } => {
+                    let Value::Fn(function, env) = self.get(*callee) else {
+                        return Err(error("Called a function value that was never set"));
+                    };
+                    let args = self.gets(args);
+                    self.enter(function, env.map(|env| (*env).clone()), args, *dest)?;
+                }
+                Op::CallDyn { dest, method, args } => {
+                    let mut args = self.gets(args);
+                    let Some(Value::Dyn(object)) = args.first().cloned() else {
+                        return Err(error("Called a method of a value that is not dyn"));
+                    };
+                    let (vtable, value) = &*object;
+                    let function = *program.vtables[*vtable as usize]
+                        .get(*method as usize)
+                        .ok_or_else(|| error("A vtable is missing a method"))?;
+                    args[0] = value.clone();
+                    self.enter(function, None, args, *dest)?;
+                }
+                Op::Intrinsic {
+                    dest,
+                    intrinsic,
+                    kind,
+                    args,
+                    at,
+                } => {
+                    let args = self.gets(args);
+                    let value = self.intrinsic(*intrinsic, *kind, &args, *at)?;
+                    if let Some(dest) = dest {
+                        self.set(*dest, value);
+                    }
+                }
+                Op::Unwrap {
+                    dest,
+                    value,
+                    result,
+                    at,
+                } => {
+                    let fields = fields(self.get(*value))?;
+                    if !is_set(&fields)? {
+                        let message = if *result {
+                            "called unwrap on an Err"
+                        } else {
+                            "called unwrap on None"
+                        };
+                        return Err(self.panic(*at, message));
+                    }
+                    if let Some(dest) = dest {
+                        self.set(*dest, payload(&fields)?);
+                    }
+                }
+                Op::UnwrapOr {
+                    dest,
+                    value,
+                    default,
+                } => {
+                    let fields = fields(self.get(*value))?;
+                    let value = if is_set(&fields)? {
+                        payload(&fields)?
+                    } else {
+                        self.get(*default)
+                    };
+                    self.set(*dest, value);
+                }
+                Op::Print {
+                    function,
+                    pieces,
+                    args,
+                } => self.print(*function, pieces, args)?,
+                Op::ToDyn {
+                    dest,
+                    vtable,
+                    value,
+                } => {
+                    let object = Value::Dyn(Rc::new((*vtable, self.get(*value))));
+                    self.set(*dest, object);
+                }
+                Op::MakeFn {
+                    dest,
+                    function,
+                    env,
+                } => {
+                    let env = env.map(|env| Rc::new(self.get(env)));
+                    self.set(*dest, Value::Fn(*function, env));
+                }
+                Op::Struct { dest, fields } => {
+                    let mut value = self.zeros[code.regs[*dest as usize] as usize].clone();
+                    let Value::Struct(items) = &mut value else {
+                        return Err(error("Built a struct in a register that is not one"));
+                    };
+                    let items = Rc::make_mut(items);
+                    for (field, arg) in fields {
+                        *items
+                            .get_mut(*field as usize)
+                            .ok_or_else(|| error("A struct has too few fields"))? = self.get(*arg);
+                    }
+                    self.set(*dest, value);
+                }
+                Op::Array { dest, elements } => {
+                    let elements = self.gets(elements);
+                    self.set(*dest, Value::Array(Rc::new(elements)));
+                }
+                Op::ArrayRepeat { dest, len, value } => {
+                    let elements = vec![self.get(*value); *len as usize];
+                    self.set(*dest, Value::Array(Rc::new(elements)));
+                }
+                Op::Field { dest, value, field } => {
+                    let value = fields(self.get(*value))?
+                        .get(*field as usize)
+                        .cloned()
+                        .ok_or_else(|| error("A struct has too few fields"))?;
+                    self.set(*dest, value);
+                }
+                Op::Index {
+                    dest,
+                    array,
+                    index,
+                    at,
+                } => {
+                    let index = self.int(*index)? as u64;
+                    let element = match self.get(*array) {
+                        Value::Array(items) => {
+                            let index = self.check_index(index, items.len(), *at)?;
+                            items[index].clone()
+                        }
+                        Value::Buffer(items) => {
+                            let items = items.borrow();
+                            let index = self.check_index(index, items.len(), *at)?;
+                            items[index].clone()
+                        }
+                        Value::Null => return Err(self.check_index(index, 0, *at).unwrap_err()),
+                        _ => return Err(error("Indexed into a value that is not an array")),
+                    };
+                    self.set(*dest, element);
+                }
+                Op::Local { reg } => {
+                    let value = mem::replace(&mut self.regs[*reg as usize], Value::Void);
+                    self.set(*reg, cell(value));
+                }
+                Op::FieldAddress { dest, base, field } => {
+                    let place = pointer(self.get(*base))?;
+                    let mut path = place.path.clone();
+                    path.push(*field);
+                    let address = Value::Pointer(Rc::new(Place {
+                        root: place.root.clone(),
+                        path,
+                    }));
+                    self.set(*dest, address);
+                }
+                Op::IndexAddress {
+                    dest,
+                    base,
+                    index,
+                    at,
+                } => {
+                    let index = self.int(*index)? as u64;
+                    let place = match self.get(*base) {
+                        Value::Pointer(place) => {
+                            let len = access(&place, |array| match array {
+                                Value::Array(items) => Ok(items.len()),
+                                _ => Err(error("Indexed into a value that is not an array")),
+                            })??;
+                            let index = self.check_index(index, len, *at)?;
+                            let mut path = place.path.clone();
+                            path.push(index as u32);
+                            Place {
+                                root: place.root.clone(),
+                                path,
+                            }
+                        }
+                        Value::Buffer(items) => {
+                            let len = items.borrow().len();
+                            let index = self.check_index(index, len, *at)?;
+                            Place {
+                                root: Root::Element(items, index),
+                                path: Vec::new(),
+                            }
+                        }
+                        Value::Null => return Err(self.check_index(index, 0, *at).unwrap_err()),
+                        _ => return Err(error("Indexed into a value that is not an array")),
+                    };
+                    self.set(*dest, Value::Pointer(Rc::new(place)));
+                }
+                Op::Load { dest, address } => {
+                    let value = access(&*pointer(self.get(*address))?, |value| value.clone())?;
+                    self.set(*dest, value);
+                }
+                Op::Store { address, value } => {
+                    let value = self.get(*value);
+                    access(&*pointer(self.get(*address))?, |slot| *slot = value)?;
+                }
+                Op::Alloc { dest, shape } => {
+                    let value = cell(self.zeros[*shape as usize].clone());
+                    self.set(*dest, value);
+                }
+                Op::Move { dest, address } => {
+                    let zero = self.zeros[code.regs[*dest as usize] as usize].clone();
+                    let value = access(&*pointer(self.get(*address))?, |slot| {
+                        mem::replace(slot, zero)
+                    })?;
+                    self.set(*dest, value);
+                }
+                Op::NextChar { dest, text, pos } => {
+                    let Value::Str(buffer, start, end) = self.get(*text) else {
+                        return Err(error("Decoded a char of a value that is not a str"));
+                    };
+                    let pos = pointer(self.get(*pos))?;
+                    let offset = match access(&pos, |value| value.clone())? {
+                        Value::Int(n) => n as usize,
+                        _ => return Err(error("A char offset is not an integer")),
+                    };
+                    let c = buffer
+                        .borrow()
+                        .get(start + offset..end)
+                        .and_then(|rest| rest.chars().next())
+                        .ok_or_else(|| error("Decoded a char past the end of a str"))?;
+                    let next = Value::Int((offset + c.len_utf8()) as i64);
+                    access(&pos, |value| *value = next)?;
+                    self.set(*dest, Value::Char(c));
+                }
+                Op::Jump { target } => self.pc = *target as usize,
+                Op::Branch {
+                    condition,
+                    then_target,
+                    else_target,
+                } => {
+                    let target = if self.bool(*condition)? {
+                        then_target
+                    } else {
+                        else_target
+                    };
+                    self.pc = *target as usize;
+                }
+                Op::Switch {
+                    value,
+                    cases,
+                    default,
+                } => {
+                    let key = match self.get(*value) {
+                        Value::Int(n) => n,
+                        Value::Bool(b) => i64::from(b),
+                        Value::Char(c) => i64::from(u32::from(c)),
+                        _ => return Err(error("Switched on a value that is not a number")),
+                    };
+                    let target = cases
+                        .iter()
+                        .find(|(case, _)| *case == key)
+                        .map_or(default, |(_, target)| target);
+                    self.pc = *target as usize;
+                }
+                Op::Return { value } => {
+                    let value = value.map_or(Value::Void, |value| self.get(value));
+                    let Some(caller) = self.callers.pop() else {
+                        return Ok(value);
+                    };
+                    self.function = caller.function;
+                    self.pc = caller.pc;
+                    self.regs = caller.regs;
+                    if let Some(dest) = caller.dest {
+                        self.set(dest, value);
+                    }
+                }
+                Op::Unreachable => return Err(error("Reached code that cannot be reached")),
+            }
+        }
+    }
+
+    // Calls a function, saving where the caller was
+    fn enter(
+        &mut self,
+        function: u32,
+        env: Option<Value>,
+        args: Vec<Value>,
+        dest: Option<u32>,
+    ) -> Outcome<()> {
+        if self.callers.len() >= MAX_DEPTH {
+            return Err(error("The program overflowed its stack"));
+        }
+        let regs = self.frame(function, env, args)?;
+        self.callers.push(Frame {
+            function: self.function,
+            pc: self.pc,
+            regs: mem::replace(&mut self.regs, regs),
+            dest,
+        });
+        self.function = function;
+        self.pc = 0;
+        Ok(())
+    }
+
+    // The registers a function starts with: zeros, apart from its
+    // environment and parameters
+    fn frame(&self, function: u32, env: Option<Value>, args: Vec<Value>) -> Outcome<Vec<Value>> {
+        let code = &self.program.functions[function as usize];
+        let mut regs: Vec<Value> = code
+            .regs
+            .iter()
+            .map(|shape| self.zeros[*shape as usize].clone())
+            .collect();
+        match (code.env, env) {
+            (Some(reg), Some(env)) => regs[reg as usize] = env,
+            (None, None) => {}
+            _ => return Err(error("Called a closure without its environment")),
+        }
+        if args.len() != code.params.len() {
+            return Err(error(
+                "Called a function with the wrong number of arguments",
+            ));
+        }
+        for (param, arg) in code.params.iter().zip(args) {
+            regs[*param as usize] = arg;
+        }
+        Ok(regs)
+    }
+
+    fn get(&self, arg: Arg) -> Value {
+        match arg {
+            Arg::Reg(reg) => self.regs[reg as usize].clone(),
+            Arg::Const(id) => self.constants[id as usize].clone(),
+        }
+    }
+
+    fn gets(&self, args: &[Arg]) -> Vec<Value> {
+        args.iter().map(|arg| self.get(*arg)).collect()
+    }
+
+    fn set(&mut self, reg: u32, value: Value) {
+        self.regs[reg as usize] = value;
+    }
+
+    fn int(&self, arg: Arg) -> Outcome<i64> {
+        match self.get(arg) {
+            Value::Int(n) => Ok(n),
+            _ => Err(error("Expected an integer")),
+        }
+    }
+
+    fn bool(&self, arg: Arg) -> Outcome<bool> {
+        match self.get(arg) {
+            Value::Bool(b) => Ok(b),
+            _ => Err(error("Expected a bool")),
+        }
+    }
+
+    fn binary(&mut self, op: BinaryOp, kind: Kind, lhs: Arg, rhs: Arg, at: Span) -> Outcome<Value> {
+        let (lhs, rhs) = (self.get(lhs), self.get(rhs));
+        let ordering = match (op, &lhs, &rhs) {
+            (
+                BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem,
+                Value::Int(a),
+                Value::Int(b),
+            ) => {
+                return self.arithmetic(op, kind, *a, *b, Overflow::Plain, at);
+            }
+            (_, Value::Int(a), Value::Int(b)) => kind.widen(*a).cmp(&kind.widen(*b)),
+            (_, Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (_, Value::Char(a), Value::Char(b)) => a.cmp(b),
+            _ => text(&lhs)?.cmp(&text(&rhs)?),
+        };
+        Ok(Value::Bool(match op {
+            BinaryOp::Equal => ordering == Ordering::Equal,
+            BinaryOp::NotEqual => ordering != Ordering::Equal,
+            BinaryOp::Less => ordering == Ordering::Less,
+            BinaryOp::LessEqual => ordering != Ordering::Greater,
+            BinaryOp::Greater => ordering == Ordering::Greater,
+            BinaryOp::GreaterEqual => ordering != Ordering::Less,
+            _ => return Err(error(&format!("Operator {op:?} is not defined here"))),
+        }))
+    }
+
+    // Integer arithmetic, panicking with the messages the runtime uses
+    fn arithmetic(
+        &mut self,
+        op: BinaryOp,
+        kind: Kind,
+        a: i64,
+        b: i64,
+        overflow: Overflow,
+        at: Span,
+    ) -> Outcome<Value> {
+        let (a, b) = (kind.widen(a), kind.widen(b));
+        match interp::arithmetic(op, a, b, range(kind)?, overflow, !self.program.debug) {
+            Ok(n) => Ok(Value::Int(n as i64)),
+            Err(message) => Err(self.panic(at, message)),
+        }
+    }
+
+    fn intrinsic(
+        &mut self,
+        intrinsic: Intrinsic,
+        kind: Kind,
+        args: &[Value],
+        at: Span,
+    ) -> Outcome<Value> {
+        Ok(match (intrinsic, args) {
+            (Intrinsic::Exit, [Value::Int(code)]) => {
+                self.flush()?;
+                return Err(Halt::Exit(*code as i32));
+            }
+            (Intrinsic::Panic, [message]) => return Err(self.panic(at, &text(message)?)),
+            (Intrinsic::StringNew, []) => string(String::new()),
+            (Intrinsic::StringFrom, [s]) => string(text(s)?),
+            (Intrinsic::StringAsStr, [Value::String(buffer, len)]) => {
+                Value::Str(buffer.clone(), 0, *len)
+            }
+            (Intrinsic::StringPushStr, [Value::Pointer(place), s]) => {
+                push_str(place, &text(s)?)?;
+                Value::Void
+            }
+            (Intrinsic::StringPushChar, [Value::Pointer(place), Value::Char(c)]) => {
+                push_str(place, c.encode_utf8(&mut [0; 4]))?;
+                Value::Void
+            }
+            (Intrinsic::StrLen, [s]) => Value::Int(text(s)?.len() as i64),
+            (
+                Intrinsic::StrSlice,
+                [Value::Str(buffer, start, end), Value::Int(from), Value::Int(to)],
+            ) => {
+                let (from, to) = (*from as u64, *to as u64);
+                if from > to || to > (end - start) as u64 {
+                    return Err(self.panic(at, "string slice out of bounds"));
+                }
+                let (from, to) = (start + from as usize, start + to as usize);
+                let on_boundary = {
+                    let buffer = buffer.borrow();
+                    let view = &buffer[..*end];
+                    view.is_char_boundary(from) && view.is_char_boundary(to)
+                };
+                if !on_boundary {
+                    return Err(self.panic(at, "string slice is not on a char boundary"));
+                }
+                Value::Str(buffer.clone(), from, to)
+            }
+            (Intrinsic::StrConcat, [a, b]) => string(text(a)? + &text(b)?),
+            (Intrinsic::StrEqual, [a, b]) => Value::Bool(text(a)? == text(b)?),
+            (Intrinsic::StrCompare, [a, b]) => Value::Int(text(a)?.cmp(&text(b)?) as i64),
+            (Intrinsic::StrParseI64, [s]) => match interp::parse_i64(&text(s)?) {
+                Ok(n) => Value::Int(n),
+                Err(message) => return Err(self.panic(at, message)),
+            },
+            (Intrinsic::IntToString, [Value::Int(n)]) if kind.is_signed() => string(n.to_string()),
+            (Intrinsic::IntToString, [Value::Int(n)]) => string((*n as u64).to_string()),
+            (Intrinsic::CharToString, [Value::Char(c)]) => string(c.to_string()),
+            (Intrinsic::BoxNew, [value]) => cell(value.clone()),
+            (Intrinsic::BufferNew, []) => Value::Null,
+            (Intrinsic::BufferLen, [Value::Buffer(items)]) => {
+                Value::Int(items.borrow().len() as i64)
+            }
+            (Intrinsic::BufferLen, [Value::Null]) => Value::Int(0),
+            (Intrinsic::BufferPush, [Value::Pointer(place), value]) => {
+                let items = access(place, |buffer| {
+                    if let Value::Null = buffer {
+                        *buffer = Value::Buffer(Rc::default());
+                    }
+                    match buffer {
+                        Value::Buffer(items) => Ok(items.clone()),
+                        _ => Err(error("Pushed to a buffer that is not one")),
+                    }
+                })??;
+                items.borrow_mut().push(value.clone());
+                Value::Void
+            }
+            (Intrinsic::BufferPop, [Value::Pointer(place)]) => {
+                let popped = access(place, |buffer| match buffer {
+                    Value::Buffer(items) => items.borrow_mut().pop(),
+                    _ => None,
+                })?;
+                match popped {
+                    Some(value) => value,
+                    None => return Err(self.panic(at, "pop from an empty buffer")),
+                }
+            }
+            (Intrinsic::Hash, [value]) => Value::Int(hash(value)? as i64),
+            (Intrinsic::Overflows(op), [Value::Int(a), Value::Int(b)]) => {
+                let (a, b) = (kind.widen(*a), kind.widen(*b));
+                let result = interp::arithmetic(op, a, b, range(kind)?, Overflow::Checked, false);
+                Value::Bool(result.is_err())
+            }
+            (Intrinsic::Wrapping(op), [Value::Int(a), Value::Int(b)]) => {
+                self.arithmetic(op, kind, *a, *b, Overflow::Wrapping, at)?
+            }
+            (Intrinsic::Saturating(op), [Value::Int(a), Value::Int(b)]) => {
+                self.arithmetic(op, kind, *a, *b, Overflow::Saturating, at)?
+            }
+            _ => return Err(error(&format!("{intrinsic:?} cannot take these arguments"))),
+        })
+    }
+
+    fn check_index(&mut self, index: u64, len: usize, at: Span) -> Outcome<usize> {
+        if index < len as u64 {
+            Ok(index as usize)
+        } else {
+            Err(self.panic(
+                at,
+                &format!("index out of bounds: the len is {len} but the index is {index}"),
+            ))
+        }
+    }
+
+    fn print(
+        &mut self,
+        function: IoFunction,
+        pieces: &[Piece],
+        args: &[(Arg, Kind)],
+    ) -> Outcome<()> {
+        let mut line = String::new();
+        let mut args = args.iter();
+        for piece in pieces {
+            match piece {
+                Piece::Text(text) => line.push_str(&self.program.strings[*text as usize]),
+                Piece::Placeholder => {
+                    let (arg, kind) = args
+                        .next()
+                        .ok_or_else(|| error(&format!("{function:?} has too few arguments")))?;
+                    line.push_str(&display(&self.get(*arg), *kind)?);
+                }
+            }
+        }
+        if function != IoFunction::Print {
+            line.push('\n');
+        }
+        if function == IoFunction::Eprintln {
+            self.eprint(&line)
+        } else {
+            self.stdout
+                .write_all(line.as_bytes())
+                .map_err(|e| error(&format!("Failed to write output: {e}")))
+        }
+    }
+
+    fn eprint(&mut self, text: &str) -> Outcome<()> {
+        self.flush()?;
+        io::stderr()
+            .write_all(text.as_bytes())
+            .map_err(|e| error(&format!("Failed to write output: {e}")))
+    }
+
+    fn flush(&mut self) -> Outcome<()> {
+        self.stdout
+            .flush()
+            .map_err(|e| error(&format!("Failed to write output: {e}")))
+    }
+
+    // Reports a panic the way compiled programs do, with a backtrace in
+    // debug builds if `NIMRA_BACKTRACE` asks for one
+    fn panic(&mut self, span: Span, message: &str) -> Halt {
+        let mut report = if span.line == 0 {
+            format!("panicked: {message}\n")
+        } else {
+            format!(
+                "panicked at {}:{}:{}: {message}\n",
+                self.program.file, span.line, span.column
+            )
+        };
+        if self.program.debug {
+            match env::var_os("NIMRA_BACKTRACE") {
+                Some(setting) if setting != "0" => {
+                    report.push_str("stack backtrace:\n");
+                    let functions = std::iter::once(self.function)
+                        .chain(self.callers.iter().rev().map(|frame| frame.function));
+                    for (depth, function) in functions.enumerate() {
+                        let code = &self.program.functions[function as usize];
+                        let name = &self.program.strings[code.source_name as usize];
+                        report.push_str(&format!("{depth:4}: {name}\n"));
+                    }
+                }
+                _ => report.push_str("note: run with `NIMRA_BACKTRACE=1` to display a backtrace\n"),
+            }
+        }
+        match self.eprint(&report) {
+            Ok(()) => Halt::Exit(101),
+            Err(halt) => halt,
+        }
+    }
+}
+
+fn error(message: &str) -> Halt {
+    Halt::Error(message.to_string())
+}
+
+fn range(kind: Kind) -> Outcome<(i128, i128)> {
+    kind.range()
+        .ok_or_else(|| error(&format!("{kind:?} is not an integer kind")))
+}
+
+fn cell(value: Value) -> Value {
+    Value::Pointer(Rc::new(Place {
+        root: Root::Cell(Rc::new(RefCell::new(value))),
+        path: Vec::new(),
+    }))
+}
+
+fn string(text: String) -> Value {
+    let len = text.len();
+    Value::String(Rc::new(RefCell::new(text)), len)
+}
+
+fn pointer(value: Value) -> Outcome<Rc<Place>> {
+    match value {
+        Value::Pointer(place) => Ok(place),
+        Value::Null => Err(error("Followed a pointer that was never set")),
+        _ => Err(error("Followed a value that is not a pointer")),
+    }
+}
+
+fn fields(value: Value) -> Outcome<Rc<Vec<Value>>> {
+    match value {
+        Value::Struct(fields) => Ok(fields),
+        _ => Err(error("Expected a struct")),
+    }
+}
+
+// Whether an `Option` or `Result` holds `Some` or `Ok`; its flag comes first
+fn is_set(fields: &[Value]) -> Outcome<bool> {
+    match fields.first() {
+        Some(Value::Bool(set)) => Ok(*set),
+        _ => Err(error("An enum has no flag")),
+    }
+}
+
+// The value of a `Some` or `Ok`, which comes after its flag
+fn payload(fields: &[Value]) -> Outcome<Value> {
+    fields
+        .get(1)
+        .cloned()
+        .ok_or_else(|| error("An enum has no value"))
+}
+
+// Runs `f` on the value at a place
+fn access<R>(place: &Place, f: impl FnOnce(&mut Value) -> R) -> Outcome<R> {
+    match &place.root {
+        Root::Cell(cell) => Ok(f(walk(&mut cell.borrow_mut(), &place.path)?)),
+        Root::Element(items, index) => {
+            let mut items = items.borrow_mut();
+            let element = items
+                .get_mut(*index)
+                .ok_or_else(|| error("A reference outlived its element"))?;
+            Ok(f(walk(element, &place.path)?))
+        }
+    }
+}
+
+// Follows a place's path from the value it starts at, copying any struct or
+// array on the way that is shared, since it is about to change
+fn walk<'v>(mut value: &'v mut Value, path: &[u32]) -> Outcome<&'v mut Value> {
+    for step in path {
+        value = match value {
+            Value::Struct(items) | Value::Array(items) => {
+                Rc::make_mut(items).get_mut(*step as usize)
+            }
+            _ => None,
+        }
+        .ok_or_else(|| error("A place is not in the value it names"))?;
+    }
+    Ok(value)
+}
+
+// Appends to the string at a place, first giving it a buffer of its own if
+// a copy of it has grown the one they shared
+fn push_str(place: &Place, text: &str) -> Outcome<()> {
+    access(place, |value| {
+        let Value::String(buffer, len) = value else {
+            return Err(error("Pushed to a string that is not one"));
+        };
+        if buffer.borrow().len() != *len {
+            let own = buffer.borrow()[..*len].to_string();
+            *buffer = Rc::new(RefCell::new(own));
+        }
+        buffer.borrow_mut().push_str(text);
+        *len += text.len();
+        Ok(())
+    })?
+}
+
+fn text(value: &Value) -> Outcome<String> {
+    match value {
+        Value::Str(buffer, start, end) => Ok(buffer.borrow()[*start..*end].to_string()),
+        Value::String(buffer, len) => Ok(buffer.borrow()[..*len].to_string()),
+        _ => Err(error("Expected a string")),
+    }
+}
+
+fn display(value: &Value, kind: Kind) -> Outcome<String> {
+    match value {
+        Value::Int(n) if kind.is_signed() => Ok(n.to_string()),
+        Value::Int(n) => Ok((*n as u64).to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        Value::Char(c) => Ok(c.to_string()),
+        value => text(value),
+    }
+}
+
+fn cast(value: Value, kind: Kind) -> Value {
+    match value {
+        Value::Int(n) if kind == Kind::Char => Value::Char(char::from(n as u8)),
+        Value::Int(n) => Value::Int(kind.truncate(n)),
+        Value::Bool(b) if kind.range().is_some() => Value::Int(i64::from(b)),
+        Value::Char(c) if kind.range().is_some() => {
+            Value::Int(kind.truncate(i64::from(u32::from(c))))
+        }
+        value => value,
+    }
+}
+
+fn hash(value: &Value) -> Outcome<u64> {
+    Ok(match value {
+        Value::Int(n) => interp::hash_u64(*n as u64),
+        Value::Bool(b) => interp::hash_u64(u64::from(*b)),
+        Value::Char(c) => interp::hash_u64(u64::from(u32::from(*c))),
+        value => text(value)?.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        }),
+    })
+}
+
+/// Runs a program compiled to bytecode and returns the code it exits with.
+/// It prints, panics and exits just like the program would once compiled.
+pub fn run(program: &Program) -> Result<i32, String> {
+    thread::scope(|scope| {
+        thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(scope, || Vm::new(program).run())
+            .map_err(|e| format!("Failed to start the VM: {e}"))?
+            .join()
+            .map_err(|_| "The VM crashed".to_string())?
+    })
+}
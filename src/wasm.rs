@@ -0,0 +1,2092 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::collections::HashMap;
+
+use crate::generator::{self, ICInstruction};
+use crate::ir::{self, BlockId, Function, Instruction, Operand, Reg, Terminator};
+use crate::lexer::{Length, Literal, Span, Type};
+use crate::options::Options;
+use crate::parser::{BinaryOp, UnaryOp};
+use crate::runtime;
+use crate::sema::{self, FormatPiece, Intrinsic, IoFunction, TraitMethod};
+
+/// Where the stack starts, which grows down towards address 0
+const STACK_TOP: u32 = 0x80_0000;
+
+/// Where the generated data starts, after the runtime's
+const DATA_START: u32 = 0x80_2000;
+
+/// How much heap the module starts with; the runtime grows it as it needs
+const HEAP_SIZE: u32 = 0x10_0000;
+
+const PAGE_SIZE: u32 = 0x1_0000;
+
+/// Where a value lives in linear memory
+#[derive(Debug, Clone)]
+enum Mem {
+    /// At an offset into the function's frame
+    Frame(u32),
+    /// At an address in the data
+    Data(u32),
+    /// At an offset from the address in a local
+    Local(&'static str, u32),
+}
+
+impl Mem {
+    fn at(&self, offset: u32) -> Mem {
+        match self {
+            Mem::Frame(base) => Mem::Frame(base + offset),
+            Mem::Data(base) => Mem::Data(base + offset),
+            Mem::Local(local, base) => Mem::Local(local, base + offset),
+        }
+    }
+}
+
+/// A value generated code reads or writes
+#[derive(Debug, Clone)]
+enum Value {
+    Imm(i64),
+    Mem(Mem),
+    /// A value of a type with no size, which takes no code to move
+    Void,
+}
+
+/// What a call goes to
+enum Target {
+    Function(String),
+    /// The function whose table index is stored here
+    Table(Mem),
+    /// An entry of the vtable whose address is stored here
+    Vtable(Mem, usize),
+}
+
+/// What a runtime function returns
+enum Returns {
+    Nothing,
+    I32,
+    U32,
+    I64,
+}
+
+/// What integer arithmetic does when its result does not fit in its type
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Overflow {
+    Panic,
+    Wrap,
+    Saturate,
+}
+
+/// Generates a WebAssembly module in the text format, which imports what it
+/// needs from WASI and includes the runtime in `runtime/wasm.wat`. Every
+/// register lives in the function's frame in linear memory, on a stack the
+/// module keeps itself. A scalar is passed and returned as an i64, sign or
+/// zero extended as its type is, and anything bigger is passed as its
+/// address and returned through an address the caller passes first.
+/// Function values and vtables hold indices into the module's table.
+pub fn generate(ic: &[ICInstruction], options: &Options) -> Result<String, String> {
+    Program::new(ic, options).generate()
+}
+
+struct Program<'a> {
+    ic: &'a [ICInstruction],
+    structs: HashMap<String, Vec<(String, Type)>>,
+    traits: HashMap<String, Vec<TraitMethod>>,
+    returns: HashMap<String, Type>,
+    owned: Vec<Type>,
+    debug: bool,
+    file: String,
+    functions: String,
+    /// The generated data, which is placed at `DATA_START`
+    data: Vec<u8>,
+    strings: HashMap<String, u32>,
+    names: HashMap<String, u32>,
+    locations: HashMap<(usize, usize), u32>,
+    vtables: HashMap<String, u32>,
+    /// The functions in the table, from index 1 so that 0 is none
+    table: Vec<String>,
+    /// The signatures `call_indirect` uses, each declared as a type
+    types: Vec<String>,
+}
+
+impl<'a> Program<'a> {
+    fn new(ic: &'a [ICInstruction], options: &Options) -> Program<'a> {
+        let mut structs = HashMap::new();
+        let mut traits = HashMap::new();
+        let mut returns = HashMap::new();
+        let mut owned = Vec::new();
+        for item in ic {
+            match item {
+                ICInstruction::StructDecl { name, fields } => {
+                    structs.insert(name.clone(), fields.clone());
+                }
+                ICInstruction::TraitDecl { name, methods } => {
+                    traits.insert(name.clone(), methods.clone());
+                }
+                ICInstruction::Function(function) => {
+                    returns.insert(function.name.clone(), function.return_type.clone());
+                }
+                ICInstruction::OwnedType(ty) => owned.push(ty.clone()),
+                _ => {}
+            }
+        }
+        Program {
+            ic,
+            structs,
+            traits,
+            returns,
+            owned,
+            debug: !options.release,
+            file: options.input.clone(),
+            functions: String::new(),
+            data: Vec::new(),
+            strings: HashMap::new(),
+            names: HashMap::new(),
+            locations: HashMap::new(),
+            vtables: HashMap::new(),
+            table: Vec::new(),
+            types: Vec::new(),
+        }
+    }
+
+    fn generate(&mut self) -> Result<String, String> {
+        let ic = self.ic;
+        let mut main = None;
+        for item in ic {
+            match item {
+                ICInstruction::Function(function) => {
+                    if function.name == "main" {
+                        main = Some(function.return_type.clone());
+                    }
+                    self.function(function, &format!("fn_{}", function.name), self.debug)?;
+                }
+                ICInstruction::Vtable {
+                    trait_name,
+                    ty,
+                    methods,
+                } => self.vtable(trait_name, ty, methods)?,
+                ICInstruction::OwnedType(ty) => self.drop_glue(ty),
+                _ => {}
+            }
+        }
+        let main = main.ok_or("There is no main function")?;
+        self.function(&ir::entry(&main), "nrt_main", false)?;
+
+        let heap = (DATA_START + self.data.len() as u32).next_multiple_of(16);
+        let pages = (heap + HEAP_SIZE).div_ceil(PAGE_SIZE);
+        let mut module = format!("(module\n{}\n", runtime::WASM);
+        for (id, signature) in self.types.iter().enumerate() {
+            module.push_str(&format!("(type $t{id} (func{signature}))\n"));
+        }
+        module.push_str(&format!(
+            "(memory (export \"memory\") {pages})\n(table {} funcref)\n(global $nrt_sp (mut i32) (i32.const {STACK_TOP}))\n(global $nrt_heap_next (mut i32) (i32.const {heap}))\n(global $nrt_heap_end (mut i32) (i32.const {}))\n\n",
+            self.table.len() + 1,
+            pages * PAGE_SIZE
+        ));
+        module.push_str(&self.functions);
+        module.push_str(
+            "(func $_start (export \"_start\")\n  call $nrt_main\n  i32.wrap_i64\n  call $nrt_exit\n)\n",
+        );
+        if !self.table.is_empty() {
+            let functions = self
+                .table
+                .iter()
+                .map(|function| format!("${function}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            module.push_str(&format!("(elem (i32.const 1) func {functions})\n"));
+        }
+        if !self.data.is_empty() {
+            module.push_str(&format!(
+                "(data (i32.const {DATA_START}) \"{}\")\n",
+                escape_bytes(&self.data)
+            ));
+        }
+        module.push_str(")\n");
+        Ok(module)
+    }
+
+    fn function(&mut self, function: &Function, name: &str, frame: bool) -> Result<(), String> {
+        let code = FunctionGen::new(self, function, frame).generate(name)?;
+        self.functions.push_str(&code);
+        Ok(())
+    }
+
+    /// Fills in the vtable for `ty` as a `dyn Trait` with the table indices
+    /// of the functions in it, which take a pointer to the value and call the
+    /// method with the value
+    fn vtable(
+        &mut self,
+        trait_name: &str,
+        ty: &Type,
+        methods: &[TraitMethod],
+    ) -> Result<(), String> {
+        let address = self.vtable_address(trait_name, ty);
+        let trait_methods = self.traits.get(trait_name).cloned().unwrap_or_default();
+        for (slot, method) in trait_methods.iter().enumerate() {
+            if !methods.iter().any(|m| m.name == method.name) {
+                continue;
+            }
+            let function = generator::mangle_method(trait_name, ty, &method.name);
+            let label = format!("th_{function}");
+            self.function(&ir::thunk(ty, method, &function), &label, false)?;
+            let index = self.table_index(&label);
+            let at = (address - DATA_START) as usize + 4 * slot;
+            self.data[at..at + 4].copy_from_slice(&index.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    // The address of the vtable for `ty` as a `dyn Trait`, which is
+    // reserved the first time it is asked for and filled in by `vtable`
+    fn vtable_address(&mut self, trait_name: &str, ty: &Type) -> u32 {
+        let name = vtable_name(trait_name, ty);
+        if let Some(address) = self.vtables.get(&name) {
+            return *address;
+        }
+        let len = self.traits.get(trait_name).map_or(0, Vec::len);
+        let address = self.reserve(4, &vec![0; 4 * len]);
+        self.vtables.insert(name, address);
+        address
+    }
+
+    /// Emits the function that frees the heap memory the value at the
+    /// address it is passed owns
+    fn drop_glue(&mut self, ty: &Type) {
+        let mut code = format!("(func $dr_{} (param $place i32)\n", generator::mangle(ty));
+        match ty {
+            Type::Box(inner) => {
+                if self.is_owned(inner) {
+                    code.push_str(&format!(
+                        "  local.get $place\n  i32.load\n  if\n    local.get $place\n    i32.load\n    call $dr_{}\n  end\n",
+                        generator::mangle(inner)
+                    ));
+                }
+                code.push_str("  local.get $place\n  i32.load\n  call $nrt_free\n");
+            }
+            Type::String => code.push_str("  local.get $place\n  i32.load\n  call $nrt_free\n"),
+            Type::Buffer(_) => {
+                code.push_str("  local.get $place\n  i32.load\n  call $nrt_buffer_free\n")
+            }
+            Type::Array(element, Length::Known(len)) if self.is_owned(element) && *len > 0 => {
+                code.push_str(&format!(
+                    "  (local $left i32)\n  i32.const {len}\n  local.set $left\n  loop $element\n    local.get $place\n    call $dr_{}\n    local.get $place\n    i32.const {}\n    i32.add\n    local.set $place\n    local.get $left\n    i32.const 1\n    i32.sub\n    local.tee $left\n    br_if $element\n  end\n",
+                    generator::mangle(element),
+                    self.size(element)
+                ));
+            }
+            Type::Struct(..) | Type::Option(_) | Type::Result(..) => {
+                for (_, offset, field) in self.struct_layout(ty).0 {
+                    if self.is_owned(&field) {
+                        code.push_str(&format!(
+                            "  local.get $place\n  i32.const {offset}\n  i32.add\n  call $dr_{}\n",
+                            generator::mangle(&field)
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+        code.push_str(")\n\n");
+        self.functions.push_str(&code);
+    }
+
+    fn is_owned(&self, ty: &Type) -> bool {
+        self.owned.contains(ty)
+    }
+
+    fn size(&self, ty: &Type) -> u32 {
+        self.layout(ty).0
+    }
+
+    /// The size and alignment of a type in 32-bit linear memory, where a
+    /// pointer takes four bytes and a 64-bit integer eight
+    fn layout(&self, ty: &Type) -> (u32, u32) {
+        match ty {
+            Type::Void => (0, 1),
+            Type::Bool | Type::I8 | Type::U8 => (1, 1),
+            Type::I16 | Type::U16 => (2, 2),
+            Type::I32 | Type::U32 | Type::Char => (4, 4),
+            Type::Ref(..) | Type::Box(_) | Type::Buffer(_) => (4, 4),
+            Type::I64 | Type::U64 => (8, 8),
+            Type::Str | Type::Dyn(_) => (8, 4),
+            Type::String | Type::Fn(..) => (12, 4),
+            Type::Array(element, len) => {
+                let (size, align) = self.layout(element);
+                let len = match len {
+                    Length::Known(len) => *len as u32,
+                    Length::Const(_) => 0,
+                };
+                (size * len, align)
+            }
+            Type::Struct(..) | Type::Option(_) | Type::Result(..) => {
+                let (_, size, align) = self.struct_layout(ty);
+                (size, align)
+            }
+            Type::Param(name) => unreachable!("type parameter {} outlived monomorphization", name),
+        }
+    }
+
+    /// The name, offset and type of each field of a struct, `Option` or
+    /// `Result`, and the size and alignment of the whole
+    fn struct_layout(&self, ty: &Type) -> (Vec<(String, u32, Type)>, u32, u32) {
+        let mut fields = Vec::new();
+        let mut offset = 0;
+        let mut align = 1;
+        for (name, field) in self
+            .structs
+            .get(&generator::mangle(ty))
+            .into_iter()
+            .flatten()
+        {
+            let (field_size, field_align) = self.layout(field);
+            offset = u32::next_multiple_of(offset, field_align);
+            fields.push((name.clone(), offset, field.clone()));
+            offset += field_size;
+            align = align.max(field_align);
+        }
+        (fields, offset.next_multiple_of(align), align)
+    }
+
+    fn field(&self, ty: &Type, name: &str) -> Result<(u32, Type), String> {
+        self.struct_layout(ty)
+            .0
+            .into_iter()
+            .find(|(field, _, _)| field == name)
+            .map(|(_, offset, ty)| (offset, ty))
+            .ok_or_else(|| format!("{ty} has no field {name}"))
+    }
+
+    /// Results that are not scalars are written wherever the caller passes
+    /// the address of first
+    fn returns_in_memory(&self, ty: &Type) -> bool {
+        !is_scalar(ty) && self.size(ty) > 0
+    }
+
+    /// The parameters and result of a function taking and returning values
+    /// of these types, as a type use writes them
+    fn signature(&self, params: &[Type], result: &Type) -> String {
+        let mut types = Vec::new();
+        if self.returns_in_memory(result) {
+            types.push("i32");
+        }
+        for ty in params {
+            if is_scalar(ty) {
+                types.push("i64");
+            } else if self.size(ty) > 0 {
+                types.push("i32");
+            }
+        }
+        let mut signature = String::new();
+        if !types.is_empty() {
+            signature.push_str(&format!(" (param {})", types.join(" ")));
+        }
+        if is_scalar(result) {
+            signature.push_str(" (result i64)");
+        }
+        signature
+    }
+
+    // The type `call_indirect` names for a signature, declared the first
+    // time it is used
+    fn type_name(&mut self, signature: String) -> String {
+        let id = match self.types.iter().position(|ty| *ty == signature) {
+            Some(id) => id,
+            None => {
+                self.types.push(signature);
+                self.types.len() - 1
+            }
+        };
+        format!("$t{id}")
+    }
+
+    // The index of a function in the table, which is added the first time
+    fn table_index(&mut self, function: &str) -> u32 {
+        let position = match self.table.iter().position(|f| f == function) {
+            Some(position) => position,
+            None => {
+                self.table.push(function.to_string());
+                self.table.len() - 1
+            }
+        };
+        position as u32 + 1
+    }
+
+    // Appends bytes to the data, aligned to `align`, and returns their address
+    fn reserve(&mut self, align: usize, bytes: &[u8]) -> u32 {
+        let start = self.data.len().next_multiple_of(align);
+        self.data.resize(start, 0);
+        self.data.extend_from_slice(bytes);
+        DATA_START + start as u32
+    }
+
+    /// The address of a `str` for the text, as a pointer and a length, which
+    /// the text follows
+    fn string(&mut self, text: &str) -> u32 {
+        if let Some(address) = self.strings.get(text) {
+            return *address;
+        }
+        let address = DATA_START + self.data.len().next_multiple_of(4) as u32;
+        let mut record = Vec::new();
+        record.extend_from_slice(&(address + 8).to_le_bytes());
+        record.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        record.extend_from_slice(text.as_bytes());
+        self.reserve(4, &record);
+        self.strings.insert(text.to_string(), address);
+        address
+    }
+
+    /// The address of a location as the runtime takes it: the file name,
+    /// which is 0 for code from a standard module written in Nimra, then the
+    /// line and column
+    fn location(&mut self, span: Span) -> u32 {
+        if let Some(address) = self.locations.get(&(span.line, span.column)) {
+            return *address;
+        }
+        let file = if span.line == 0 {
+            0
+        } else {
+            self.name(&self.file.clone())
+        };
+        let mut record = Vec::new();
+        record.extend_from_slice(&file.to_le_bytes());
+        record.extend_from_slice(&(span.line as u32).to_le_bytes());
+        record.extend_from_slice(&(span.column as u32).to_le_bytes());
+        let address = self.reserve(4, &record);
+        self.locations.insert((span.line, span.column), address);
+        address
+    }
+
+    /// The address of a null-terminated copy of `name`
+    fn name(&mut self, name: &str) -> u32 {
+        if let Some(address) = self.names.get(name) {
+            return *address;
+        }
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.push(0);
+        let address = self.reserve(1, &bytes);
+        self.names.insert(name.to_string(), address);
+        address
+    }
+}
+
+fn vtable_name(trait_name: &str, ty: &Type) -> String {
+    format!(
+        "vi_{}{trait_name}{}",
+        trait_name.len(),
+        generator::mangle(ty)
+    )
+}
+
+/// Types that fit in an i64 and are copied as a whole
+fn is_scalar(ty: &Type) -> bool {
+    ty.is_integer()
+        || matches!(
+            ty,
+            Type::Bool | Type::Char | Type::Ref(..) | Type::Box(_) | Type::Buffer(_)
+        )
+}
+
+/// The type a reference or box points to
+fn pointee(ty: &Type) -> Type {
+    match ty {
+        Type::Ref(inner, _) | Type::Box(inner) => (**inner).clone(),
+        ty => ty.clone(),
+    }
+}
+
+/// The type of a pointer generated code reads, such as the data of a `dyn Trait`
+fn pointer() -> Type {
+    Type::Ref(Box::new(Type::Void), false)
+}
+
+/// An integer as its type holds it once sign or zero extended to 64 bits
+fn canonical(n: i64, ty: &Type) -> i64 {
+    match ty {
+        Type::I8 => i64::from(n as i8),
+        Type::I16 => i64::from(n as i16),
+        Type::I32 => i64::from(n as i32),
+        Type::U8 => i64::from(n as u8),
+        Type::U16 => i64::from(n as u16),
+        Type::U32 | Type::Char => i64::from(n as u32),
+        _ => n,
+    }
+}
+
+/// Escapes bytes for a data segment's string, which has `\XX` hex escapes
+fn escape_bytes(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    for &byte in bytes {
+        match byte {
+            b' '..=b'~' if byte != b'"' && byte != b'\\' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\{byte:02x}")),
+        }
+    }
+    escaped
+}
+
+// The offset a load or store adds to its address, as the text format writes it
+fn memarg(offset: u32) -> String {
+    if offset == 0 {
+        String::new()
+    } else {
+        format!(" offset={offset}")
+    }
+}
+
+struct FunctionGen<'p, 'a> {
+    program: &'p mut Program<'a>,
+    function: &'p Function,
+    homes: Vec<Value>,
+    /// Bytes of the frame, whose start is in $fp
+    frame_size: u32,
+    /// The registers come first in the frame, and are cleared on entry
+    homes_size: u32,
+    frame_record: Option<u32>,
+    returns_in_memory: bool,
+    code: String,
+    /// How deeply the next instruction is nested in blocks
+    depth: usize,
+}
+
+impl<'p, 'a> FunctionGen<'p, 'a> {
+    fn new(
+        program: &'p mut Program<'a>,
+        function: &'p Function,
+        frame: bool,
+    ) -> FunctionGen<'p, 'a> {
+        let returns_in_memory = program.returns_in_memory(&function.return_type);
+        let mut generator = FunctionGen {
+            program,
+            function,
+            homes: Vec::new(),
+            frame_size: 0,
+            homes_size: 0,
+            frame_record: None,
+            returns_in_memory,
+            code: String::new(),
+            depth: 1,
+        };
+        for reg in &function.regs {
+            let size = generator.program.size(&reg.ty);
+            let home = if size == 0 {
+                Value::Void
+            } else {
+                Value::Mem(generator.slot(size))
+            };
+            generator.homes.push(home);
+        }
+        generator.homes_size = generator.frame_size;
+        if frame {
+            generator.frame_record = Some(generator.frame_size);
+            generator.slot(8);
+        }
+        generator
+    }
+
+    // Reserves a new part of the frame, a multiple of eight bytes long
+    fn slot(&mut self, size: u32) -> Mem {
+        let offset = self.frame_size;
+        self.frame_size += size.next_multiple_of(8);
+        Mem::Frame(offset)
+    }
+
+    fn generate(mut self, name: &str) -> Result<String, String> {
+        let function = self.function;
+        let blocks = function.blocks.len();
+        // Each block follows the end of a block of its own, so a jump sets
+        // $label and goes back to the br_table, and a jump to the block
+        // that comes next falls through
+        self.emit("loop $dispatch");
+        for id in (0..blocks).rev() {
+            self.emit(&format!("block $b{id}"));
+        }
+        let targets = (0..blocks)
+            .map(|id| format!("$b{id}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.emit("local.get $label");
+        self.emit(&format!("br_table {targets} $b{}", blocks - 1));
+        for (id, block) in function.blocks.iter().enumerate() {
+            self.emit("end");
+            for instruction in &block.instructions {
+                self.instruction(instruction)?;
+            }
+            self.terminator(&block.terminator, id)?;
+        }
+        self.emit("end");
+        self.emit("unreachable");
+        let body = std::mem::take(&mut self.code);
+        self.enter();
+
+        let mut params = Vec::new();
+        if self.returns_in_memory {
+            params.push("(param $result i32)".to_string());
+        }
+        for &param in function.env.iter().chain(&function.params) {
+            let ty = &function.regs[param].ty;
+            if is_scalar(ty) {
+                params.push(format!("(param $p{param} i64)"));
+            } else if self.program.size(ty) > 0 {
+                params.push(format!("(param $p{param} i32)"));
+            }
+        }
+        if is_scalar(&function.return_type) {
+            params.push("(result i64)".to_string());
+        }
+        Ok(format!(
+            "(func ${name} {}\n  (local $sp i32) (local $fp i32) (local $label i32) (local $a i32) (local $b i32) (local $c i32) (local $x i64) (local $y i64) (local $v i64)\n{}{body})\n\n",
+            params.join(" "),
+            self.code
+        ))
+    }
+
+    // Emits an instruction, indented by how deeply it is nested
+    fn emit(&mut self, instruction: &str) {
+        if instruction == "end" || instruction == "else" {
+            self.depth -= 1;
+        }
+        for _ in 0..self.depth {
+            self.code.push_str("  ");
+        }
+        self.code.push_str(instruction);
+        self.code.push('\n');
+        let opens = ["block", "loop", "if", "else"]
+            .iter()
+            .any(|keyword| instruction.split(' ').next() == Some(*keyword));
+        if opens {
+            self.depth += 1;
+        }
+    }
+
+    // Reserves the frame and clears the registers in it, so one read before
+    // it is written is zero, takes the arguments, and pushes the function's
+    // frame in debug builds
+    fn enter(&mut self) {
+        let frame = self.frame_size.next_multiple_of(16);
+        self.emit("global.get $nrt_sp");
+        self.emit("local.tee $sp");
+        self.emit(&format!("i32.const {frame}"));
+        self.emit("i32.sub");
+        self.emit("local.tee $fp");
+        self.emit("global.set $nrt_sp");
+        if self.homes_size > 0 {
+            self.emit("local.get $fp");
+            self.emit("i32.const 0");
+            self.emit(&format!("i32.const {}", self.homes_size));
+            self.emit("memory.fill");
+        }
+        let function = self.function;
+        for &param in function.env.iter().chain(&function.params) {
+            let ty = function.regs[param].ty.clone();
+            let Value::Mem(home) = self.homes[param].clone() else {
+                continue;
+            };
+            if is_scalar(&ty) {
+                self.store(&home, &ty, |gen| gen.emit(&format!("local.get $p{param}")));
+            } else {
+                self.address(&home);
+                self.emit(&format!("local.get $p{param}"));
+                self.emit(&format!("i32.const {}", self.program.size(&ty)));
+                self.emit("memory.copy");
+            }
+        }
+        if let Some(record) = self.frame_record {
+            let name = self.program.name(&function.source_name);
+            self.emit("local.get $fp");
+            self.emit(&format!("i32.const {name}"));
+            self.emit(&format!("i32.store{}", memarg(record)));
+            self.emit("local.get $fp");
+            self.emit("global.get $nrt_frame_top");
+            self.emit(&format!("i32.store{}", memarg(record + 4)));
+            self.emit("local.get $fp");
+            self.emit(&format!("i32.const {record}"));
+            self.emit("i32.add");
+            self.emit("global.set $nrt_frame_top");
+        }
+    }
+
+    fn operand(&mut self, operand: &Operand) -> Result<Value, String> {
+        match operand {
+            Operand::Reg(reg) => Ok(self.homes[*reg].clone()),
+            Operand::Const(Literal::Number(n), ty) => Ok(Value::Imm(canonical(*n, ty))),
+            Operand::Const(Literal::Bool(b), _) => Ok(Value::Imm(i64::from(*b))),
+            Operand::Const(Literal::Char(c), _) => Ok(Value::Imm(i64::from(u32::from(*c)))),
+            Operand::Const(Literal::String(s), Type::Str) => {
+                Ok(Value::Mem(Mem::Data(self.program.string(s))))
+            }
+            Operand::Const(literal, ty) => {
+                Err(format!("Cannot use {literal:?} as a constant of type {ty}"))
+            }
+        }
+    }
+
+    fn operand_type(&self, operand: &Operand) -> Type {
+        self.function.operand_type(operand)
+    }
+
+    fn reg_type(&self, reg: Reg) -> Type {
+        self.function.regs[reg].ty.clone()
+    }
+
+    // Where a register lives, if it takes any memory
+    fn home(&self, reg: Reg) -> Option<Mem> {
+        match &self.homes[reg] {
+            Value::Mem(mem) => Some(mem.clone()),
+            _ => None,
+        }
+    }
+
+    // Pushes the base address of memory, and returns the offset to add to it
+    fn base(&mut self, mem: &Mem) -> u32 {
+        match mem {
+            Mem::Frame(offset) => {
+                self.emit("local.get $fp");
+                *offset
+            }
+            Mem::Data(address) => {
+                self.emit(&format!("i32.const {address}"));
+                0
+            }
+            Mem::Local(local, offset) => {
+                self.emit(&format!("local.get {local}"));
+                *offset
+            }
+        }
+    }
+
+    // Pushes the address of memory
+    fn address(&mut self, mem: &Mem) {
+        let offset = self.base(mem);
+        if offset > 0 {
+            self.emit(&format!("i32.const {offset}"));
+            self.emit("i32.add");
+        }
+    }
+
+    // Pushes a scalar as an i64, sign or zero extended as its type is
+    fn load(&mut self, value: &Value, ty: &Type) {
+        match value {
+            Value::Imm(n) => self.emit(&format!("i64.const {n}")),
+            Value::Mem(mem) => {
+                let offset = self.base(mem);
+                let instruction = match (self.program.size(ty), ty.is_signed()) {
+                    (1, true) => "i64.load8_s",
+                    (1, false) => "i64.load8_u",
+                    (2, true) => "i64.load16_s",
+                    (2, false) => "i64.load16_u",
+                    (4, true) => "i64.load32_s",
+                    (4, false) => "i64.load32_u",
+                    _ => "i64.load",
+                };
+                self.emit(&format!("{instruction}{}", memarg(offset)));
+            }
+            Value::Void => self.emit("i64.const 0"),
+        }
+    }
+
+    // Pushes a scalar as an i32, as a pointer, length or char is passed to the runtime
+    fn load_i32(&mut self, value: &Value, ty: &Type) {
+        self.load(value, ty);
+        self.emit("i32.wrap_i64");
+    }
+
+    // Stores the i64 `value` pushes as a scalar of `ty`, keeping only the
+    // bytes the type has
+    fn store(&mut self, dest: &Mem, ty: &Type, value: impl FnOnce(&mut Self)) {
+        let size = self.program.size(ty);
+        if size == 0 {
+            return;
+        }
+        let offset = self.base(dest);
+        value(self);
+        let instruction = match size {
+            1 => "i64.store8",
+            2 => "i64.store16",
+            4 => "i64.store32",
+            _ => "i64.store",
+        };
+        self.emit(&format!("{instruction}{}", memarg(offset)));
+    }
+
+    // Stores the i64 on top of the stack as a scalar of `ty`
+    fn store_top(&mut self, dest: &Mem, ty: &Type) {
+        self.emit("local.set $v");
+        self.store(dest, ty, |gen| gen.emit("local.get $v"));
+    }
+
+    // Stores the i32 `value` is as a pointer or table index
+    fn store_i32(&mut self, dest: &Mem, value: &str) {
+        let offset = self.base(dest);
+        self.emit(value);
+        self.emit(&format!("i32.store{}", memarg(offset)));
+    }
+
+    fn copy(&mut self, source: &Value, dest: &Mem, ty: &Type) {
+        let size = self.program.size(ty);
+        if size == 0 {
+            return;
+        }
+        if is_scalar(ty) {
+            self.store(dest, ty, |gen| gen.load(source, ty));
+            return;
+        }
+        self.address(dest);
+        self.address(&memory(source));
+        self.emit(&format!("i32.const {size}"));
+        self.emit("memory.copy");
+    }
+
+    fn zero(&mut self, dest: &Mem, size: u32) {
+        if size == 0 {
+            return;
+        }
+        self.address(dest);
+        self.emit("i32.const 0");
+        self.emit(&format!("i32.const {size}"));
+        self.emit("memory.fill");
+    }
+
+    // Loads a pointer into a local, and returns the memory it points to
+    fn deref(&mut self, address: &Value, local: &'static str) -> Mem {
+        self.load_i32(address, &pointer());
+        self.emit(&format!("local.set {local}"));
+        Mem::Local(local, 0)
+    }
+
+    // Pushes the pointer and length of a `str` or `String`, which both start with them
+    fn text(&mut self, value: &Value) {
+        let mem = memory(value);
+        let offset = self.base(&mem);
+        self.emit(&format!("i32.load{}", memarg(offset)));
+        let offset = self.base(&mem);
+        self.emit(&format!("i32.load{}", memarg(offset + 4)));
+    }
+
+    // Pushes the address a `String` a runtime function returns goes to,
+    // which is a new part of the frame if it goes nowhere
+    fn result(&mut self, home: Option<Mem>, ty: &Type) {
+        let home = match home {
+            Some(home) => home,
+            None => self.slot(self.program.size(ty)),
+        };
+        self.address(&home);
+    }
+
+    // Calls a function, passing each scalar argument as an i64 and anything
+    // bigger by address, and stores its result in `dest` if there is one
+    fn call(
+        &mut self,
+        target: Target,
+        args: &[(Value, Type)],
+        return_type: &Type,
+        dest: Option<Mem>,
+    ) {
+        let hidden_result = self.program.returns_in_memory(return_type);
+        if hidden_result {
+            self.result(dest.clone(), return_type);
+        }
+        for (value, ty) in args {
+            if is_scalar(ty) {
+                self.load(value, ty);
+            } else if self.program.size(ty) > 0 {
+                self.address(&memory(value));
+            }
+        }
+        let types: Vec<Type> = args.iter().map(|(_, ty)| ty.clone()).collect();
+        match target {
+            Target::Function(name) => self.emit(&format!("call ${name}")),
+            Target::Table(mem) => {
+                let offset = self.base(&mem);
+                self.emit(&format!("i32.load{}", memarg(offset)));
+                let signature = self.program.signature(&types, return_type);
+                let ty = self.program.type_name(signature);
+                self.emit(&format!("call_indirect (type {ty})"));
+            }
+            Target::Vtable(mem, index) => {
+                let offset = self.base(&mem);
+                self.emit(&format!("i32.load{}", memarg(offset)));
+                self.emit(&format!("i32.load{}", memarg(4 * index as u32)));
+                let signature = self.program.signature(&types, return_type);
+                let ty = self.program.type_name(signature);
+                self.emit(&format!("call_indirect (type {ty})"));
+            }
+        }
+        if !is_scalar(return_type) {
+            return;
+        }
+        match dest {
+            Some(dest) => self.store_top(&dest, return_type),
+            None => self.emit("drop"),
+        }
+    }
+
+    fn panic(&mut self, span: Span, message: &str) {
+        let location = self.program.location(span);
+        let address = self.program.string(message);
+        self.emit(&format!("i32.const {location}"));
+        self.emit(&format!("i32.const {}", address + 8));
+        self.emit(&format!("i32.const {}", message.len()));
+        self.emit("call $nrt_panic");
+    }
+
+    // Panics if the i32 on top of the stack is not 0
+    fn panic_if(&mut self, span: Span, message: &str) {
+        self.emit("if");
+        self.panic(span, message);
+        self.emit("end");
+    }
+
+    fn location(&mut self, span: Span) -> String {
+        format!("i32.const {}", self.program.location(span))
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) -> Result<(), String> {
+        match instruction {
+            Instruction::Copy { dest, value } => {
+                let value = self.operand(value)?;
+                let ty = self.reg_type(*dest);
+                if let Some(dest) = self.home(*dest) {
+                    self.copy(&value, &dest, &ty);
+                }
+            }
+            Instruction::Zero { dest } => {
+                let size = self.program.size(&self.reg_type(*dest));
+                if let Some(dest) = self.home(*dest) {
+                    self.zero(&dest, size);
+                }
+            }
+            Instruction::Binary {
+                dest,
+                op,
+                lhs,
+                rhs,
+                span,
+            } => {
+                let ty = self.operand_type(lhs);
+                if !is_scalar(&ty) {
+                    return Err(format!("Cannot apply {op:?} to a value of type {ty}"));
+                }
+                let lhs = self.operand(lhs)?;
+                let rhs = self.operand(rhs)?;
+                self.load(&lhs, &ty);
+                self.emit("local.set $x");
+                self.load(&rhs, &ty);
+                self.emit("local.set $y");
+                let dest_type = self.reg_type(*dest);
+                let comparison = match op {
+                    BinaryOp::Equal => Some("i64.eq"),
+                    BinaryOp::NotEqual => Some("i64.ne"),
+                    BinaryOp::Less if ty.is_signed() => Some("i64.lt_s"),
+                    BinaryOp::LessEqual if ty.is_signed() => Some("i64.le_s"),
+                    BinaryOp::Greater if ty.is_signed() => Some("i64.gt_s"),
+                    BinaryOp::GreaterEqual if ty.is_signed() => Some("i64.ge_s"),
+                    BinaryOp::Less => Some("i64.lt_u"),
+                    BinaryOp::LessEqual => Some("i64.le_u"),
+                    BinaryOp::Greater => Some("i64.gt_u"),
+                    BinaryOp::GreaterEqual => Some("i64.ge_u"),
+                    _ => None,
+                };
+                if let Some(comparison) = comparison {
+                    self.emit("local.get $x");
+                    self.emit("local.get $y");
+                    self.emit(comparison);
+                    self.emit("i64.extend_i32_u");
+                    self.emit("local.set $x");
+                } else if let BinaryOp::And | BinaryOp::Or = op {
+                    self.emit("local.get $x");
+                    self.emit("local.get $y");
+                    self.emit(if *op == BinaryOp::And {
+                        "i64.and"
+                    } else {
+                        "i64.or"
+                    });
+                    self.emit("local.set $x");
+                } else {
+                    let overflow = if self.program.debug {
+                        Overflow::Panic
+                    } else {
+                        Overflow::Wrap
+                    };
+                    self.arithmetic(*op, &ty, overflow, *span)?;
+                }
+                if let Some(dest) = self.home(*dest) {
+                    self.store(&dest, &dest_type, |gen| gen.emit("local.get $x"));
+                }
+            }
+            Instruction::Unary {
+                dest,
+                op,
+                operand,
+                span,
+            } => {
+                let ty = self.reg_type(*dest);
+                let operand_type = self.operand_type(operand);
+                let operand = self.operand(operand)?;
+                self.load(&operand, &operand_type);
+                match op {
+                    UnaryOp::Not => {
+                        self.emit("i64.eqz");
+                        self.emit("i64.extend_i32_u");
+                    }
+                    UnaryOp::Neg => {
+                        self.emit("local.set $x");
+                        if self.program.debug {
+                            let (min, _) = ty
+                                .integer_range()
+                                .ok_or_else(|| format!("Cannot negate a value of type {ty}"))?;
+                            self.emit("local.get $x");
+                            self.emit(&format!("i64.const {}", canonical(min as i64, &ty)));
+                            self.emit("i64.eq");
+                            self.panic_if(*span, "attempt to negate with overflow");
+                        }
+                        self.emit("i64.const 0");
+                        self.emit("local.get $x");
+                        self.emit("i64.sub");
+                    }
+                }
+                if let Some(dest) = self.home(*dest) {
+                    self.store_top(&dest, &ty);
+                } else {
+                    self.emit("drop");
+                }
+            }
+            Instruction::Cast { dest, value } => {
+                let ty = self.reg_type(*dest);
+                let value_type = self.operand_type(value);
+                let value = self.operand(value)?;
+                let Some(dest) = self.home(*dest) else {
+                    return Ok(());
+                };
+                self.store(&dest, &ty, |gen| {
+                    gen.load(&value, &value_type);
+                    if ty == Type::Bool && value_type != Type::Bool {
+                        gen.emit("i64.const 0");
+                        gen.emit("i64.ne");
+                        gen.emit("i64.extend_i32_u");
+                    }
+                });
+            }
+            Instruction::Call {
+                dest,
+                function,
+                args,
+            } => {
+                let return_type = self
+                    .program
+                    .returns
+                    .get(function)
+                    .cloned()
+                    .ok_or_else(|| format!("Call to unknown function {function}"))?;
+                let args = self.arguments(args)?;
+                let dest = dest.and_then(|dest| self.home(dest));
+                self.call(
+                    Target::Function(format!("fn_{function}")),
+                    &args,
+                    &return_type,
+                    dest,
+                );
+            }
+            Instruction::CallValue { dest, callee, args } => {
+                let Type::Fn(_, return_type) = self.operand_type(callee) else {
+                    return Err(format!(
+                        "Cannot call a value of type {}",
+                        self.operand_type(callee)
+                    ));
+                };
+                let callee = memory(&self.operand(callee)?);
+                let args = self.arguments(args)?;
+                let dest = dest.and_then(|dest| self.home(dest));
+                // A closure with an environment takes it before its arguments
+                let offset = self.base(&callee);
+                self.emit(&format!("i32.load{}", memarg(offset + 4)));
+                self.emit("if");
+                let with_env: Vec<(Value, Type)> =
+                    std::iter::once((Value::Mem(callee.at(8)), pointer()))
+                        .chain(args.iter().cloned())
+                        .collect();
+                self.call(
+                    Target::Table(callee.at(4)),
+                    &with_env,
+                    &return_type,
+                    dest.clone(),
+                );
+                self.emit("else");
+                self.call(Target::Table(callee), &args, &return_type, dest);
+                self.emit("end");
+            }
+            Instruction::CallDyn {
+                dest,
+                trait_name,
+                method,
+                args,
+            } => {
+                let methods = self
+                    .program
+                    .traits
+                    .get(trait_name)
+                    .cloned()
+                    .unwrap_or_default();
+                let Some(index) = methods.iter().position(|m| m.name == *method) else {
+                    return Err(format!("dyn {trait_name} has no method {method}"));
+                };
+                let Some((receiver, args)) = args.split_first() else {
+                    return Err(format!("Call to {method} has no receiver"));
+                };
+                let receiver = memory(&self.operand(receiver)?);
+                let args: Vec<(Value, Type)> =
+                    std::iter::once((Value::Mem(receiver.clone()), pointer()))
+                        .chain(self.arguments(args)?)
+                        .collect();
+                let dest = dest.and_then(|dest| self.home(dest));
+                self.call(
+                    Target::Vtable(receiver.at(4), index),
+                    &args,
+                    &methods[index].return_type,
+                    dest,
+                );
+            }
+            Instruction::Intrinsic {
+                dest,
+                intrinsic,
+                args,
+                span,
+            } => self.intrinsic(*dest, *intrinsic, args, *span)?,
+            Instruction::Print {
+                function,
+                pieces,
+                args,
+            } => self.print(*function, pieces, args)?,
+            Instruction::ToDyn { dest, value } => {
+                let Type::Dyn(trait_name) = self.reg_type(*dest) else {
+                    return Err(format!(
+                        "Cannot convert a value to {}",
+                        self.reg_type(*dest)
+                    ));
+                };
+                let ty = self.operand_type(value);
+                let value = self.operand(value)?;
+                let size = self.program.size(&ty);
+                self.emit(&format!("i32.const {size}"));
+                self.emit("call $nrt_alloc");
+                self.emit("local.set $a");
+                let vtable = self.program.vtable_address(&trait_name, &ty);
+                if let Some(dest) = self.home(*dest) {
+                    self.store_i32(&dest, "local.get $a");
+                    self.store_i32(&dest.at(4), &format!("i32.const {vtable}"));
+                }
+                self.copy(&value, &Mem::Local("$a", 0), &ty);
+            }
+            Instruction::MakeFn {
+                dest,
+                function,
+                env,
+            } => {
+                let index = self.program.table_index(&format!("fn_{function}"));
+                let Some(dest) = self.home(*dest) else {
+                    return Ok(());
+                };
+                match env {
+                    Some(env) => {
+                        let ty = self.operand_type(env);
+                        let env = self.operand(env)?;
+                        self.store_i32(&dest, "i32.const 0");
+                        self.store_i32(&dest.at(4), &format!("i32.const {index}"));
+                        self.copy(&env, &dest.at(8), &ty);
+                    }
+                    None => {
+                        self.store_i32(&dest, &format!("i32.const {index}"));
+                        self.store_i32(&dest.at(4), "i32.const 0");
+                        self.store_i32(&dest.at(8), "i32.const 0");
+                    }
+                }
+            }
+            Instruction::Struct { dest, fields } => {
+                let ty = self.reg_type(*dest);
+                let Some(dest) = self.home(*dest) else {
+                    return Ok(());
+                };
+                self.zero(&dest, self.program.size(&ty));
+                for (field, value) in fields {
+                    let (offset, field_type) = self.program.field(&ty, field)?;
+                    let value = self.operand(value)?;
+                    self.copy(&value, &dest.at(offset), &field_type);
+                }
+            }
+            Instruction::Array { dest, elements } => {
+                let Type::Array(element, _) = self.reg_type(*dest) else {
+                    return Err(format!(
+                        "Cannot build an array of type {}",
+                        self.reg_type(*dest)
+                    ));
+                };
+                let size = self.program.size(&element);
+                let Some(dest) = self.home(*dest) else {
+                    return Ok(());
+                };
+                for (i, value) in elements.iter().enumerate() {
+                    let value = self.operand(value)?;
+                    self.copy(&value, &dest.at(i as u32 * size), &element);
+                }
+            }
+            Instruction::ArrayRepeat { dest, value } => {
+                let Type::Array(element, Length::Known(len)) = self.reg_type(*dest) else {
+                    return Err(format!(
+                        "Cannot build an array of type {}",
+                        self.reg_type(*dest)
+                    ));
+                };
+                let size = self.program.size(&element);
+                if let (Some(dest), true) = (self.home(*dest), len > 0 && size > 0) {
+                    let value = self.operand(value)?;
+                    self.address(&dest);
+                    self.emit("local.set $b");
+                    self.emit(&format!("i32.const {len}"));
+                    self.emit("local.set $c");
+                    self.emit("loop $repeat");
+                    self.copy(&value, &Mem::Local("$b", 0), &element);
+                    self.emit("local.get $b");
+                    self.emit(&format!("i32.const {size}"));
+                    self.emit("i32.add");
+                    self.emit("local.set $b");
+                    self.emit("local.get $c");
+                    self.emit("i32.const 1");
+                    self.emit("i32.sub");
+                    self.emit("local.tee $c");
+                    self.emit("br_if $repeat");
+                    self.emit("end");
+                }
+            }
+            Instruction::Field { dest, value, field } => {
+                let ty = self.operand_type(value);
+                let (offset, field_type) = self.program.field(&ty, field)?;
+                let value = memory(&self.operand(value)?);
+                if let Some(dest) = self.home(*dest) {
+                    self.copy(&Value::Mem(value.at(offset)), &dest, &field_type);
+                }
+            }
+            Instruction::Index {
+                dest,
+                array,
+                index,
+                span,
+            } => {
+                let ty = self.reg_type(*dest);
+                let element = self.element_address(array, index, *span)?;
+                if let Some(dest) = self.home(*dest) {
+                    self.copy(&Value::Mem(element), &dest, &ty);
+                }
+            }
+            Instruction::AddressOf { dest, reg } => {
+                let home = memory(&self.homes[*reg]);
+                if let Some(dest) = self.home(*dest) {
+                    self.store(&dest, &pointer(), |gen| {
+                        gen.address(&home);
+                        gen.emit("i64.extend_i32_u");
+                    });
+                }
+            }
+            Instruction::FieldAddress { dest, base, field } => {
+                let ty = pointee(&self.operand_type(base));
+                let (offset, _) = self.program.field(&ty, field)?;
+                let base = self.operand(base)?;
+                if let Some(dest) = self.home(*dest) {
+                    self.store(&dest, &pointer(), |gen| {
+                        gen.load(&base, &pointer());
+                        if offset > 0 {
+                            gen.emit(&format!("i64.const {offset}"));
+                            gen.emit("i64.add");
+                        }
+                    });
+                }
+            }
+            Instruction::IndexAddress {
+                dest,
+                base,
+                index,
+                span,
+            } => {
+                self.element_address(base, index, *span)?;
+                if let Some(dest) = self.home(*dest) {
+                    self.store(&dest, &pointer(), |gen| {
+                        gen.emit("local.get $a");
+                        gen.emit("i64.extend_i32_u");
+                    });
+                }
+            }
+            Instruction::Load { dest, address } => {
+                let ty = self.reg_type(*dest);
+                let address = self.operand(address)?;
+                if let Some(dest) = self.home(*dest) {
+                    let source = self.deref(&address, "$b");
+                    self.copy(&Value::Mem(source), &dest, &ty);
+                }
+            }
+            Instruction::Store { address, value } => {
+                let ty = self.operand_type(value);
+                let address = self.operand(address)?;
+                let value = self.operand(value)?;
+                let dest = self.deref(&address, "$b");
+                self.copy(&value, &dest, &ty);
+            }
+            Instruction::Alloc { dest } => {
+                let size = self.program.size(&pointee(&self.reg_type(*dest)));
+                self.emit(&format!("i32.const {size}"));
+                self.emit("call $nrt_alloc");
+                self.emit("i64.extend_i32_u");
+                match self.home(*dest) {
+                    Some(dest) => self.store_top(&dest, &pointer()),
+                    None => self.emit("drop"),
+                }
+            }
+            Instruction::Move { dest, address } => {
+                let ty = self.reg_type(*dest);
+                let address = self.operand(address)?;
+                let place = self.deref(&address, "$b");
+                if let Some(dest) = self.home(*dest) {
+                    self.copy(&Value::Mem(place.clone()), &dest, &ty);
+                }
+                let size = self.program.size(&ty);
+                self.zero(&place, size);
+            }
+            Instruction::Drop { address } => {
+                let ty = pointee(&self.operand_type(address));
+                let address = self.operand(address)?;
+                self.load_i32(&address, &pointer());
+                self.emit(&format!("call $dr_{}", generator::mangle(&ty)));
+            }
+            Instruction::NextChar { dest, text, pos } => {
+                let text = self.operand(text)?;
+                let pos = self.operand(pos)?;
+                self.text(&text);
+                self.load_i32(&pos, &pointer());
+                self.emit("call $nrt_str_next_char");
+                self.emit("i64.extend_i32_u");
+                match self.home(*dest) {
+                    Some(dest) => self.store_top(&dest, &Type::Char),
+                    None => self.emit("drop"),
+                }
+            }
+            Instruction::Phi { .. } => {
+                return Err("Phis must be removed before code generation".to_string())
+            }
+        }
+        Ok(())
+    }
+
+    fn intrinsic(
+        &mut self,
+        dest: Option<Reg>,
+        intrinsic: Intrinsic,
+        args: &[Operand],
+        span: Span,
+    ) -> Result<(), String> {
+        let return_type = dest.map_or(Type::Void, |dest| self.reg_type(dest));
+        let arg_types: Vec<Type> = args.iter().map(|arg| self.operand_type(arg)).collect();
+        let values: Vec<Value> = args
+            .iter()
+            .map(|arg| self.operand(arg))
+            .collect::<Result<_, _>>()?;
+        let home = dest.and_then(|dest| self.home(dest));
+        // Each arm pushes the arguments of a runtime function
+        let (function, result) = match intrinsic {
+            Intrinsic::Exit => {
+                self.load_i32(&values[0], &arg_types[0]);
+                ("nrt_exit", Returns::Nothing)
+            }
+            Intrinsic::Panic => {
+                let location = self.location(span);
+                self.emit(&location);
+                self.text(&values[0]);
+                ("nrt_panic", Returns::Nothing)
+            }
+            Intrinsic::StringNew | Intrinsic::BufferNew => {
+                if let Some(home) = home {
+                    self.zero(&home, self.program.size(&return_type));
+                }
+                return Ok(());
+            }
+            Intrinsic::StringFrom => {
+                self.result(home, &Type::String);
+                self.text(&values[0]);
+                self.emit("call $nrt_string_from");
+                return Ok(());
+            }
+            Intrinsic::StringPushStr => {
+                self.load_i32(&values[0], &arg_types[0]);
+                self.text(&values[1]);
+                ("nrt_string_push_str", Returns::Nothing)
+            }
+            Intrinsic::StringPushChar => {
+                self.load_i32(&values[0], &arg_types[0]);
+                self.load_i32(&values[1], &arg_types[1]);
+                ("nrt_string_push_char", Returns::Nothing)
+            }
+            Intrinsic::StringAsStr | Intrinsic::StrLen => {
+                // A String starts with the str it holds, which starts with
+                // its pointer
+                let (offset, ty) = if intrinsic == Intrinsic::StrLen {
+                    (4, Type::U32)
+                } else {
+                    (0, Type::Str)
+                };
+                if let Some(home) = home {
+                    let value = Value::Mem(memory(&values[0]).at(offset));
+                    if intrinsic == Intrinsic::StrLen {
+                        self.store(&home, &return_type, |gen| gen.load(&value, &ty));
+                    } else {
+                        self.copy(&value, &home, &ty);
+                    }
+                }
+                return Ok(());
+            }
+            Intrinsic::StrSlice => {
+                self.result(home, &Type::Str);
+                self.text(&values[0]);
+                self.load(&values[1], &arg_types[1]);
+                self.load(&values[2], &arg_types[2]);
+                let location = self.location(span);
+                self.emit(&location);
+                self.emit("call $nrt_str_slice");
+                return Ok(());
+            }
+            Intrinsic::StrParseI64 => {
+                self.text(&values[0]);
+                let location = self.location(span);
+                self.emit(&location);
+                ("nrt_str_parse_i64", Returns::I64)
+            }
+            Intrinsic::StrConcat => {
+                self.result(home, &Type::String);
+                self.text(&values[0]);
+                self.text(&values[1]);
+                self.emit("call $nrt_str_concat");
+                return Ok(());
+            }
+            Intrinsic::StrEqual | Intrinsic::StrCompare => {
+                self.text(&values[0]);
+                self.text(&values[1]);
+                if intrinsic == Intrinsic::StrEqual {
+                    ("nrt_str_equal", Returns::U32)
+                } else {
+                    ("nrt_str_compare", Returns::I32)
+                }
+            }
+            Intrinsic::IntToString => {
+                self.result(home, &Type::String);
+                self.load(&values[0], &arg_types[0]);
+                let function = if arg_types[0].is_signed() {
+                    "call $nrt_i64_to_string"
+                } else {
+                    "call $nrt_u64_to_string"
+                };
+                self.emit(function);
+                return Ok(());
+            }
+            Intrinsic::CharToString => {
+                self.result(home, &Type::String);
+                self.load_i32(&values[0], &arg_types[0]);
+                self.emit("call $nrt_char_to_string");
+                return Ok(());
+            }
+            Intrinsic::BufferLen => {
+                self.load_i32(&values[0], &arg_types[0]);
+                ("nrt_buffer_len", Returns::U32)
+            }
+            Intrinsic::Hash => match &arg_types[0] {
+                Type::Str | Type::String => {
+                    self.text(&values[0]);
+                    ("nrt_hash_str", Returns::I64)
+                }
+                ty => {
+                    self.load(&values[0], ty);
+                    ("nrt_hash_u64", Returns::I64)
+                }
+            },
+            Intrinsic::ArrayLen => {
+                return Err("The length of an array is known before code generation".to_string())
+            }
+            Intrinsic::BoxNew => {
+                let size = self.program.size(&arg_types[0]);
+                self.emit(&format!("i32.const {size}"));
+                self.emit("call $nrt_alloc");
+                self.emit("local.set $a");
+                if let Some(home) = home {
+                    self.store(&home, &pointer(), |gen| {
+                        gen.emit("local.get $a");
+                        gen.emit("i64.extend_i32_u");
+                    });
+                }
+                self.copy(&values[0], &Mem::Local("$a", 0), &arg_types[0]);
+                return Ok(());
+            }
+            Intrinsic::BufferPush => {
+                let element = buffer_element(&arg_types[0])?;
+                let size = self.program.size(&element);
+                let place = self.deref(&values[0], "$b");
+                self.base(&place);
+                self.load_i32(&Value::Mem(place.clone()), &pointer());
+                self.emit(&format!("i32.const {size}"));
+                self.emit("call $nrt_buffer_push");
+                self.emit("local.tee $a");
+                self.emit("i32.store");
+                // The element goes last, at (len - 1) * size
+                self.emit("local.get $a");
+                self.emit("call $nrt_buffer_len");
+                self.emit("i32.const 1");
+                self.emit("i32.sub");
+                self.emit(&format!("i32.const {size}"));
+                self.emit("i32.mul");
+                self.emit("local.get $a");
+                self.emit("i32.add");
+                self.emit("local.set $a");
+                self.copy(&values[1], &Mem::Local("$a", 0), &element);
+                return Ok(());
+            }
+            Intrinsic::BufferPop => {
+                let element = buffer_element(&arg_types[0])?;
+                let size = self.program.size(&element);
+                let place = self.deref(&values[0], "$b");
+                self.load_i32(&Value::Mem(place.clone()), &pointer());
+                let location = self.location(span);
+                self.emit(&location);
+                self.emit("call $nrt_buffer_pop");
+                self.emit(&format!("i32.const {size}"));
+                self.emit("i32.mul");
+                self.load_i32(&Value::Mem(place), &pointer());
+                self.emit("i32.add");
+                self.emit("local.set $a");
+                if let Some(home) = home {
+                    self.copy(&Value::Mem(Mem::Local("$a", 0)), &home, &element);
+                }
+                return Ok(());
+            }
+            Intrinsic::Checked(_) => {
+                return Err("Checked arithmetic is lowered before code generation".to_string())
+            }
+            Intrinsic::Overflows(op) | Intrinsic::Wrapping(op) | Intrinsic::Saturating(op) => {
+                self.load(&values[0], &arg_types[0]);
+                self.emit("local.set $x");
+                self.load(&values[1], &arg_types[1]);
+                self.emit("local.set $y");
+                match intrinsic {
+                    Intrinsic::Overflows(_) => {
+                        self.overflows(op, &arg_types[0])?;
+                        self.emit("i64.extend_i32_u");
+                        self.emit("local.set $x");
+                    }
+                    Intrinsic::Wrapping(_) => {
+                        self.arithmetic(op, &arg_types[0], Overflow::Wrap, span)?
+                    }
+                    _ => self.arithmetic(op, &arg_types[0], Overflow::Saturate, span)?,
+                }
+                if let Some(home) = home {
+                    self.store(&home, &return_type, |gen| gen.emit("local.get $x"));
+                }
+                return Ok(());
+            }
+            Intrinsic::Unwrap | Intrinsic::UnwrapOr => {
+                return self.unwrap(intrinsic, &values, &arg_types, home, span)
+            }
+        };
+        self.emit(&format!("call ${function}"));
+        match result {
+            Returns::Nothing => return Ok(()),
+            Returns::I32 => self.emit("i64.extend_i32_s"),
+            Returns::U32 => self.emit("i64.extend_i32_u"),
+            Returns::I64 => {}
+        }
+        match home {
+            Some(home) => self.store_top(&home, &return_type),
+            None => self.emit("drop"),
+        }
+        Ok(())
+    }
+
+    // Takes the value out of an Option or Result: Unwrap panics if there is
+    // none, and UnwrapOr returns the default instead, dropping whichever of
+    // the value, the error and the default it does not return
+    fn unwrap(
+        &mut self,
+        intrinsic: Intrinsic,
+        values: &[Value],
+        arg_types: &[Type],
+        home: Option<Mem>,
+        span: Span,
+    ) -> Result<(), String> {
+        let ty = &arg_types[0];
+        let value = memory(&values[0]);
+        let (tag, _) = self.program.field(ty, sema::tag_field(ty))?;
+        let inner = match ty {
+            Type::Option(inner) | Type::Result(inner, _) => (**inner).clone(),
+            ty => return Err(format!("Cannot unwrap a value of type {ty}")),
+        };
+        let (inner_offset, _) = self.program.field(ty, "value")?;
+        let offset = self.base(&value.at(tag));
+        self.emit(&format!("i32.load8_u{}", memarg(offset)));
+        if intrinsic == Intrinsic::Unwrap {
+            let message = match ty {
+                Type::Result(..) => "called unwrap on an Err",
+                _ => "called unwrap on None",
+            };
+            self.emit("i32.eqz");
+            self.panic_if(span, message);
+        } else {
+            self.emit("if");
+            if self.program.is_owned(&inner) {
+                self.drop_value(&values[1], &inner);
+            }
+            if let Some(home) = &home {
+                self.copy(&Value::Mem(value.at(inner_offset)), home, &inner);
+            }
+            self.emit("else");
+            if let Type::Result(_, error) = ty {
+                if self.program.is_owned(error) {
+                    let (offset, _) = self.program.field(ty, "error")?;
+                    self.drop_value(&Value::Mem(value.at(offset)), error);
+                }
+            }
+            if let Some(home) = &home {
+                self.copy(&values[1], home, &inner);
+            }
+            self.emit("end");
+            return Ok(());
+        }
+        if let Some(home) = &home {
+            self.copy(&Value::Mem(value.at(inner_offset)), home, &inner);
+        }
+        Ok(())
+    }
+
+    // Calls the drop glue for a value, which takes its address
+    fn drop_value(&mut self, value: &Value, ty: &Type) {
+        let mem = match value {
+            Value::Mem(mem) => mem.clone(),
+            value => {
+                let slot = self.slot(self.program.size(ty));
+                self.copy(value, &slot, ty);
+                slot
+            }
+        };
+        self.address(&mem);
+        self.emit(&format!("call $dr_{}", generator::mangle(ty)));
+    }
+
+    // Writes the text of a print in as few writes as it can, and each
+    // argument with the runtime function for its type
+    fn print(
+        &mut self,
+        function: IoFunction,
+        pieces: &[FormatPiece],
+        args: &[Operand],
+    ) -> Result<(), String> {
+        let fd = if function == IoFunction::Eprintln {
+            2
+        } else {
+            1
+        };
+        let mut text = String::new();
+        let mut args = args.iter();
+        for piece in pieces {
+            match piece {
+                FormatPiece::Text(piece) => text.push_str(piece),
+                FormatPiece::Placeholder => {
+                    let Some(arg) = args.next() else {
+                        return Err(format!("{function:?} has too few arguments"));
+                    };
+                    self.write(fd, &std::mem::take(&mut text));
+                    let ty = self.operand_type(arg);
+                    let value = self.operand(arg)?;
+                    self.emit(&format!("i32.const {fd}"));
+                    let function = match &ty {
+                        Type::Bool => {
+                            self.load_i32(&value, &ty);
+                            "nrt_print_bool"
+                        }
+                        Type::Char => {
+                            self.load_i32(&value, &ty);
+                            "nrt_print_char"
+                        }
+                        Type::Str | Type::String => {
+                            self.text(&value);
+                            "nrt_write"
+                        }
+                        ty if ty.is_signed() => {
+                            self.load(&value, ty);
+                            "nrt_print_i64"
+                        }
+                        ty if ty.is_integer() => {
+                            self.load(&value, ty);
+                            "nrt_print_u64"
+                        }
+                        ty => return Err(format!("Cannot print a value of type {ty}")),
+                    };
+                    self.emit(&format!("call ${function}"));
+                }
+            }
+        }
+        if function != IoFunction::Print {
+            text.push('\n');
+        }
+        self.write(fd, &text);
+        Ok(())
+    }
+
+    fn write(&mut self, fd: i32, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let address = self.program.string(text);
+        self.emit(&format!("i32.const {fd}"));
+        self.emit(&format!("i32.const {}", address + 8));
+        self.emit(&format!("i32.const {}", text.len()));
+        self.emit("call $nrt_write");
+    }
+
+    fn arguments(&mut self, args: &[Operand]) -> Result<Vec<(Value, Type)>, String> {
+        args.iter()
+            .map(|arg| Ok((self.operand(arg)?, self.operand_type(arg))))
+            .collect()
+    }
+
+    // Where an element of an array, or of the array or buffer a pointer is
+    // to, is, after checking the index against the length. The address is
+    // in $a.
+    fn element_address(
+        &mut self,
+        base: &Operand,
+        index: &Operand,
+        span: Span,
+    ) -> Result<Mem, String> {
+        let ty = self.operand_type(base);
+        let base = self.operand(base)?;
+        let index_type = self.operand_type(index);
+        let index = self.operand(index)?;
+        let location = self.location(span);
+        let (element, len) = match &ty {
+            Type::Buffer(element) => {
+                self.load_i32(&base, &ty);
+                self.emit("local.set $a");
+                self.load(&index, &index_type);
+                self.emit("local.get $a");
+                self.emit("call $nrt_buffer_len");
+                self.emit("i64.extend_i32_u");
+                ((**element).clone(), None)
+            }
+            Type::Array(element, Length::Known(len)) => {
+                self.address(&memory(&base));
+                self.emit("local.set $a");
+                ((**element).clone(), Some(*len))
+            }
+            Type::Ref(inner, _) => match &**inner {
+                Type::Array(element, Length::Known(len)) => {
+                    self.load_i32(&base, &ty);
+                    self.emit("local.set $a");
+                    ((**element).clone(), Some(*len))
+                }
+                _ => return Err(format!("Cannot index into a value of type {ty}")),
+            },
+            _ => return Err(format!("Cannot index into a value of type {ty}")),
+        };
+        if let Some(len) = len {
+            self.load(&index, &index_type);
+            self.emit(&format!("i64.const {len}"));
+        }
+        self.emit(&location);
+        self.emit("call $nrt_check_index");
+        self.emit(&format!("i32.const {}", self.program.size(&element)));
+        self.emit("i32.mul");
+        self.emit("local.get $a");
+        self.emit("i32.add");
+        self.emit("local.set $a");
+        Ok(Mem::Local("$a", 0))
+    }
+
+    fn terminator(&mut self, terminator: &Terminator, id: BlockId) -> Result<(), String> {
+        match terminator {
+            Terminator::Jump(target) => self.jump(*target, id),
+            Terminator::Branch {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                let condition = self.operand(condition)?;
+                self.load_i32(&condition, &Type::Bool);
+                // Falling through to the block that comes next where it can
+                if *then_block == id + 1 {
+                    self.emit("i32.eqz");
+                    self.emit("if");
+                    self.go_to(*else_block);
+                    self.emit("end");
+                } else {
+                    self.emit("if");
+                    self.go_to(*then_block);
+                    self.emit("end");
+                    self.jump(*else_block, id);
+                }
+            }
+            Terminator::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                let ty = self.operand_type(value);
+                let value = self.operand(value)?;
+                self.load(&value, &ty);
+                self.emit("local.set $x");
+                for (literal, target) in cases {
+                    let Value::Imm(case) =
+                        self.operand(&Operand::Const(literal.clone(), ty.clone()))?
+                    else {
+                        return Err(format!("Cannot switch on a value of type {ty}"));
+                    };
+                    self.emit("local.get $x");
+                    self.emit(&format!("i64.const {case}"));
+                    self.emit("i64.eq");
+                    self.emit("if");
+                    self.go_to(*target);
+                    self.emit("end");
+                }
+                self.jump(*default, id);
+            }
+            Terminator::Return(value) => {
+                let return_type = self.function.return_type.clone();
+                if let Some(value) = value {
+                    let value = self.operand(value)?;
+                    if self.returns_in_memory {
+                        self.copy(&value, &Mem::Local("$result", 0), &return_type);
+                    } else if is_scalar(&return_type) {
+                        self.load(&value, &return_type);
+                    }
+                } else if is_scalar(&return_type) {
+                    self.emit("i64.const 0");
+                }
+                if let Some(record) = self.frame_record {
+                    self.emit("local.get $fp");
+                    self.emit(&format!("i32.load{}", memarg(record + 4)));
+                    self.emit("global.set $nrt_frame_top");
+                }
+                self.emit("local.get $sp");
+                self.emit("global.set $nrt_sp");
+                self.emit("return");
+            }
+            Terminator::Unreachable => self.emit("unreachable"),
+        }
+        Ok(())
+    }
+
+    // Jumps to a block, unless it comes next
+    fn jump(&mut self, target: BlockId, from: BlockId) {
+        if target != from + 1 {
+            self.go_to(target);
+        }
+    }
+
+    fn go_to(&mut self, target: BlockId) {
+        self.emit(&format!("i32.const {target}"));
+        self.emit("local.set $label");
+        self.emit("br $dispatch");
+    }
+
+    // Computes $x op $y into $x for integers of `ty`, both sign or zero
+    // extended to 64 bits
+    fn arithmetic(
+        &mut self,
+        op: BinaryOp,
+        ty: &Type,
+        overflow: Overflow,
+        span: Span,
+    ) -> Result<(), String> {
+        let (min, max) = ty
+            .integer_range()
+            .ok_or_else(|| format!("Cannot apply {op:?} to a value of type {ty}"))?;
+        let (min, max) = (canonical(min as i64, ty), canonical(max as i64, ty));
+        let signed = ty.is_signed();
+        if matches!(op, BinaryOp::Div | BinaryOp::Rem) {
+            // Division by zero panics even when overflow wraps around
+            let divide = op == BinaryOp::Div;
+            self.emit("local.get $y");
+            self.emit("i64.eqz");
+            self.panic_if(
+                span,
+                if divide {
+                    "attempt to divide by zero"
+                } else {
+                    "attempt to calculate the remainder with a divisor of zero"
+                },
+            );
+            if signed {
+                self.emit("local.get $y");
+                self.emit("i64.const -1");
+                self.emit("i64.eq");
+                self.emit("local.get $x");
+                self.emit(&format!("i64.const {min}"));
+                self.emit("i64.eq");
+                self.emit("i32.and");
+                self.panic_if(
+                    span,
+                    if divide {
+                        "attempt to divide with overflow"
+                    } else {
+                        "attempt to calculate the remainder with overflow"
+                    },
+                );
+            }
+            let instruction = match (divide, signed) {
+                (true, true) => "i64.div_s",
+                (true, false) => "i64.div_u",
+                (false, true) => "i64.rem_s",
+                (false, false) => "i64.rem_u",
+            };
+            self.emit("local.get $x");
+            self.emit("local.get $y");
+            self.emit(instruction);
+            self.emit("local.set $x");
+            return Ok(());
+        }
+        let (instruction, message) = match op {
+            BinaryOp::Add => ("i64.add", "attempt to add with overflow"),
+            BinaryOp::Sub => ("i64.sub", "attempt to subtract with overflow"),
+            BinaryOp::Mul => ("i64.mul", "attempt to multiply with overflow"),
+            op => return Err(format!("Cannot apply {op:?} to a value of type {ty}")),
+        };
+        if overflow != Overflow::Wrap {
+            self.overflows(op, ty)?;
+            if overflow == Overflow::Panic {
+                self.panic_if(span, message);
+            } else {
+                // Overflow only goes past max when adding a positive number,
+                // and so on
+                self.emit("if");
+                self.emit(&format!("i64.const {min}"));
+                self.emit(&format!("i64.const {max}"));
+                let positive = if signed { "i64.gt_s" } else { "i64.ne" };
+                if op == BinaryOp::Mul {
+                    self.emit("local.get $x");
+                    self.emit("i64.const 0");
+                    self.emit(positive);
+                }
+                self.emit("local.get $y");
+                self.emit("i64.const 0");
+                self.emit(positive);
+                match op {
+                    BinaryOp::Mul => self.emit("i32.ne"),
+                    BinaryOp::Add => self.emit("i32.eqz"),
+                    _ => {}
+                }
+                self.emit("select");
+                self.emit("local.set $x");
+                self.emit("else");
+                self.operate(instruction);
+                self.emit("end");
+                return Ok(());
+            }
+        }
+        self.operate(instruction);
+        Ok(())
+    }
+
+    // Pushes whether integer arithmetic on $x and $y overflows or divides by
+    // zero
+    fn overflows(&mut self, op: BinaryOp, ty: &Type) -> Result<(), String> {
+        let (min, _) = ty
+            .integer_range()
+            .ok_or_else(|| format!("Cannot apply {op:?} to a value of type {ty}"))?;
+        let signed = ty.is_signed();
+        if matches!(op, BinaryOp::Div | BinaryOp::Rem) {
+            self.emit("local.get $y");
+            self.emit("i64.eqz");
+            if signed {
+                self.emit("local.get $y");
+                self.emit("i64.const -1");
+                self.emit("i64.eq");
+                self.emit("local.get $x");
+                self.emit(&format!("i64.const {}", canonical(min as i64, ty)));
+                self.emit("i64.eq");
+                self.emit("i32.and");
+                self.emit("i32.or");
+            }
+            return Ok(());
+        }
+        let (instruction, name) = match op {
+            BinaryOp::Add => ("i64.add", "add"),
+            BinaryOp::Sub => ("i64.sub", "sub"),
+            BinaryOp::Mul => ("i64.mul", "mul"),
+            op => return Err(format!("Cannot apply {op:?} to a value of type {ty}")),
+        };
+        // A narrower result is exact in 64 bits, so it overflowed if it
+        // changes when narrowed
+        self.emit("local.get $x");
+        self.emit("local.get $y");
+        if self.program.size(ty) == 8 {
+            let sign = if signed { "i64" } else { "u64" };
+            self.emit(&format!("call $nrt_{name}_overflows_{sign}"));
+        } else {
+            self.emit(instruction);
+            self.emit("local.tee $v");
+            self.emit("local.get $v");
+            self.normalize(ty);
+            self.emit("i64.ne");
+        }
+        Ok(())
+    }
+
+    fn operate(&mut self, instruction: &str) {
+        self.emit("local.get $x");
+        self.emit("local.get $y");
+        self.emit(instruction);
+        self.emit("local.set $x");
+    }
+
+    // Sign or zero extends the low bytes of the i64 on top of the stack that
+    // hold a value of `ty`
+    fn normalize(&mut self, ty: &Type) {
+        match (self.program.size(ty), ty.is_signed()) {
+            (1, true) => self.emit("i64.extend8_s"),
+            (2, true) => self.emit("i64.extend16_s"),
+            (4, true) => self.emit("i64.extend32_s"),
+            (1, false) => {
+                self.emit("i64.const 0xFF");
+                self.emit("i64.and");
+            }
+            (2, false) => {
+                self.emit("i64.const 0xFFFF");
+                self.emit("i64.and");
+            }
+            (4, false) => {
+                self.emit("i64.const 0xFFFFFFFF");
+                self.emit("i64.and");
+            }
+            _ => {}
+        }
+    }
+}
+
+fn buffer_element(ty: &Type) -> Result<Type, String> {
+    match pointee(ty) {
+        Type::Buffer(element) => Ok(*element),
+        ty => Err(format!("{ty} is not a buffer")),
+    }
+}
+
+// A value that lives in memory; one without a size is never read or written
+fn memory(value: &Value) -> Mem {
+    match value {
+        Value::Mem(mem) => mem.clone(),
+        _ => Mem::Frame(0),
+    }
+}
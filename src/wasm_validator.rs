@@ -0,0 +1,503 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::collections::HashSet;
+
+use crate::wat::{ExportKind, FuncType, Instr, Limits, Module, ValType};
+
+/// The most pages a 32-bit memory can have
+const MAX_PAGES: u32 = 1 << 16;
+
+const PAGE_SIZE: u64 = 1 << 16;
+
+/// Checks that a module is valid as the WebAssembly specification defines it,
+/// so that any runtime will accept it, and that its element and data
+/// segments fit in the table and memory, so that instantiating it succeeds
+pub fn validate(module: &Module) -> Result<(), String> {
+    for import in &module.imports {
+        if module.types.get(import.ty as usize).is_none() {
+            return Err(format!(
+                "Import {}.{} has an unknown type {}",
+                import.module, import.name, import.ty
+            ));
+        }
+    }
+    for (index, function) in module.functions.iter().enumerate() {
+        let index = (module.imports.len() + index) as u32;
+        let ty = module.types.get(function.ty as usize).ok_or_else(|| {
+            format!(
+                "Function {} has an unknown type {}",
+                module.function_name(index),
+                function.ty
+            )
+        })?;
+        if ty.results.len() > 1 {
+            return Err(format!(
+                "Function {} returns more than one value",
+                module.function_name(index)
+            ));
+        }
+        let mut locals = ty.params.clone();
+        locals.extend(&function.locals);
+        FunctionValidator::new(module, &locals, &ty.results)
+            .validate(&function.body)
+            .map_err(|e| format!("Function {}: {e}", module.function_name(index)))?;
+    }
+    if let Some(table) = module.table {
+        check_limits(table, u32::MAX, "The table")?;
+    }
+    if let Some(memory) = module.memory {
+        check_limits(memory, MAX_PAGES, "The memory")?;
+    }
+    for (index, global) in module.globals.iter().enumerate() {
+        let ty = constant_type(module, &global.init).map_err(|e| format!("Global {index}: {e}"))?;
+        if ty != global.ty {
+            return Err(format!(
+                "Global {index} of type {:?} starts as a {ty:?}",
+                global.ty
+            ));
+        }
+    }
+    let mut names = HashSet::new();
+    for export in &module.exports {
+        if !names.insert(&export.name) {
+            return Err(format!("{} is exported twice", export.name));
+        }
+        let exists = match export.kind {
+            ExportKind::Func => (export.index as usize) < module.function_count(),
+            ExportKind::Table => export.index == 0 && module.table.is_some(),
+            ExportKind::Memory => export.index == 0 && module.memory.is_some(),
+            ExportKind::Global => (export.index as usize) < module.globals.len(),
+        };
+        if !exists {
+            return Err(format!(
+                "Export {} refers to a {:?} that does not exist",
+                export.name, export.kind
+            ));
+        }
+    }
+    for (index, element) in module.elements.iter().enumerate() {
+        let table = module
+            .table
+            .ok_or_else(|| format!("Element segment {index} needs a table"))?;
+        let offset = segment_offset(module, &element.offset)
+            .map_err(|e| format!("Element segment {index}: {e}"))?;
+        if let Some(function) = element
+            .functions
+            .iter()
+            .find(|function| **function as usize >= module.function_count())
+        {
+            return Err(format!(
+                "Element segment {index} refers to an unknown function {function}"
+            ));
+        }
+        if offset + element.functions.len() as u64 > u64::from(table.min) {
+            return Err(format!("Element segment {index} does not fit in the table"));
+        }
+    }
+    for (index, data) in module.data.iter().enumerate() {
+        let memory = module
+            .memory
+            .ok_or_else(|| format!("Data segment {index} needs a memory"))?;
+        let offset = segment_offset(module, &data.offset)
+            .map_err(|e| format!("Data segment {index}: {e}"))?;
+        if offset + data.bytes.len() as u64 > u64::from(memory.min) * PAGE_SIZE {
+            return Err(format!("Data segment {index} does not fit in memory"));
+        }
+    }
+    Ok(())
+}
+
+fn check_limits(limits: Limits, largest: u32, what: &str) -> Result<(), String> {
+    if limits.min > largest || limits.max.is_some_and(|max| max > largest) {
+        return Err(format!("{what} is too large"));
+    }
+    if limits.max.is_some_and(|max| max < limits.min) {
+        return Err(format!("{what} has a maximum below its minimum"));
+    }
+    Ok(())
+}
+
+// The type of a constant expression, which is a constant or an immutable global
+fn constant_type(module: &Module, instruction: &Instr) -> Result<ValType, String> {
+    match instruction {
+        Instr::I32Const(_) => Ok(ValType::I32),
+        Instr::I64Const(_) => Ok(ValType::I64),
+        Instr::GlobalGet(global) => match module.globals.get(*global as usize) {
+            Some(global) if !global.mutable => Ok(global.ty),
+            Some(_) => Err("A constant cannot read a mutable global".to_string()),
+            None => Err(format!("Unknown global {global}")),
+        },
+        _ => Err("Expected a constant expression".to_string()),
+    }
+}
+
+// Where a segment starts, which only a constant puts anywhere
+fn segment_offset(module: &Module, offset: &Instr) -> Result<u64, String> {
+    if constant_type(module, offset)? != ValType::I32 {
+        return Err("The offset must be an i32".to_string());
+    }
+    match offset {
+        Instr::I32Const(offset) => Ok(u64::from(*offset as u32)),
+        _ => Err("Only a constant offset can be checked".to_string()),
+    }
+}
+
+// A block, loop, if or function whose instructions are being checked
+struct Frame {
+    kind: Kind,
+    /// What a branch to the frame takes from the stack
+    label_types: Vec<ValType>,
+    /// What the frame leaves on the stack at its end
+    end_types: Vec<ValType>,
+    /// The height of the stack when the frame started
+    height: usize,
+    /// After an unconditional branch, the stack is whatever it needs to be
+    unreachable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Block,
+    Loop,
+    If,
+    Else,
+    Function,
+}
+
+// Checks a function body by the algorithm in the appendix of the specification
+struct FunctionValidator<'m> {
+    module: &'m Module,
+    locals: &'m [ValType],
+    /// The types on the stack, or None for a value of any type
+    stack: Vec<Option<ValType>>,
+    frames: Vec<Frame>,
+}
+
+impl<'m> FunctionValidator<'m> {
+    fn new(module: &'m Module, locals: &'m [ValType], results: &[ValType]) -> Self {
+        FunctionValidator {
+            module,
+            locals,
+            stack: Vec::new(),
+            frames: vec![Frame {
+                kind: Kind::Function,
+                label_types: results.to_vec(),
+                end_types: results.to_vec(),
+                height: 0,
+                unreachable: false,
+            }],
+        }
+    }
+
+    fn validate(mut self, body: &[Instr]) -> Result<(), String> {
+        for (position, instruction) in body.iter().enumerate() {
+            if self.frames.is_empty() {
+                return Err(format!(
+                    "Instruction {position} comes after the end of the function"
+                ));
+            }
+            self.instruction(instruction)
+                .map_err(|e| format!("Instruction {position} ({}): {e}", describe(instruction)))?;
+        }
+        if !self.frames.is_empty() {
+            return Err("The function has no end".to_string());
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, ty: ValType) {
+        self.stack.push(Some(ty));
+    }
+
+    fn pop(&mut self) -> Result<Option<ValType>, String> {
+        let frame = self.frames.last().ok_or("No enclosing block")?;
+        if self.stack.len() == frame.height {
+            if frame.unreachable {
+                return Ok(None);
+            }
+            return Err("The stack is empty".to_string());
+        }
+        Ok(self.stack.pop().flatten())
+    }
+
+    fn pop_expecting(&mut self, expected: ValType) -> Result<(), String> {
+        match self.pop()? {
+            Some(actual) if actual != expected => Err(format!(
+                "Expected {expected:?} on the stack, found {actual:?}"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn pop_all(&mut self, types: &[ValType]) -> Result<(), String> {
+        for ty in types.iter().rev() {
+            self.pop_expecting(*ty)?;
+        }
+        Ok(())
+    }
+
+    fn push_frame(&mut self, kind: Kind, results: Vec<ValType>) {
+        self.frames.push(Frame {
+            kind,
+            label_types: if kind == Kind::Loop {
+                Vec::new()
+            } else {
+                results.clone()
+            },
+            end_types: results,
+            height: self.stack.len(),
+            unreachable: false,
+        });
+    }
+
+    fn pop_frame(&mut self) -> Result<Frame, String> {
+        let end_types = self
+            .frames
+            .last()
+            .ok_or("No block to end")?
+            .end_types
+            .clone();
+        self.pop_all(&end_types)?;
+        let frame = self.frames.pop().ok_or("No block to end")?;
+        if self.stack.len() != frame.height {
+            return Err(format!(
+                "{} values are left on the stack at the end of a block",
+                self.stack.len() - frame.height
+            ));
+        }
+        Ok(frame)
+    }
+
+    fn mark_unreachable(&mut self) -> Result<(), String> {
+        let frame = self.frames.last_mut().ok_or("No enclosing block")?;
+        self.stack.truncate(frame.height);
+        frame.unreachable = true;
+        Ok(())
+    }
+
+    fn label(&self, depth: u32) -> Result<Vec<ValType>, String> {
+        self.frames
+            .len()
+            .checked_sub(depth as usize + 1)
+            .map(|index| self.frames[index].label_types.clone())
+            .ok_or_else(|| format!("There is no block {depth} levels out"))
+    }
+
+    fn call(&mut self, ty: &FuncType) -> Result<(), String> {
+        self.pop_all(&ty.params)?;
+        for result in &ty.results {
+            self.push(*result);
+        }
+        Ok(())
+    }
+
+    fn needs_memory(&self) -> Result<(), String> {
+        match self.module.memory {
+            Some(_) => Ok(()),
+            None => Err("The module has no memory".to_string()),
+        }
+    }
+
+    fn instruction(&mut self, instruction: &Instr) -> Result<(), String> {
+        match instruction {
+            Instr::Block(ty) => self.push_frame(Kind::Block, ty.results()),
+            Instr::Loop(ty) => self.push_frame(Kind::Loop, ty.results()),
+            Instr::If(ty) => {
+                self.pop_expecting(ValType::I32)?;
+                self.push_frame(Kind::If, ty.results());
+            }
+            Instr::Else => {
+                let frame = self.pop_frame()?;
+                if frame.kind != Kind::If {
+                    return Err("else without an if".to_string());
+                }
+                self.push_frame(Kind::Else, frame.end_types);
+            }
+            Instr::End => {
+                let frame = self.pop_frame()?;
+                // An if with no else leaves what it started with
+                if frame.kind == Kind::If && !frame.end_types.is_empty() {
+                    return Err("An if with a result needs an else".to_string());
+                }
+                if frame.kind != Kind::Function {
+                    for ty in frame.end_types {
+                        self.push(ty);
+                    }
+                }
+            }
+            Instr::Br(depth) => {
+                let types = self.label(*depth)?;
+                self.pop_all(&types)?;
+                self.mark_unreachable()?;
+            }
+            Instr::BrIf(depth) => {
+                self.pop_expecting(ValType::I32)?;
+                let types = self.label(*depth)?;
+                self.pop_all(&types)?;
+                for ty in types {
+                    self.push(ty);
+                }
+            }
+            Instr::BrTable(targets, default) => {
+                self.pop_expecting(ValType::I32)?;
+                let types = self.label(*default)?;
+                for target in targets {
+                    if self.label(*target)? != types {
+                        return Err("The targets of br_table take different values".to_string());
+                    }
+                }
+                self.pop_all(&types)?;
+                self.mark_unreachable()?;
+            }
+            Instr::Call(function) => {
+                let ty = self
+                    .module
+                    .function_type(*function)
+                    .ok_or_else(|| format!("Unknown function {function}"))?
+                    .clone();
+                self.call(&ty)?;
+            }
+            Instr::CallIndirect(ty) => {
+                if self.module.table.is_none() {
+                    return Err("The module has no table".to_string());
+                }
+                let ty = self
+                    .module
+                    .types
+                    .get(*ty as usize)
+                    .ok_or_else(|| format!("Unknown type {ty}"))?
+                    .clone();
+                self.pop_expecting(ValType::I32)?;
+                self.call(&ty)?;
+            }
+            Instr::LocalGet(local) => {
+                let ty = self.local(*local)?;
+                self.push(ty);
+            }
+            Instr::LocalSet(local) => {
+                let ty = self.local(*local)?;
+                self.pop_expecting(ty)?;
+            }
+            Instr::LocalTee(local) => {
+                let ty = self.local(*local)?;
+                self.pop_expecting(ty)?;
+                self.push(ty);
+            }
+            Instr::GlobalGet(global) => {
+                let ty = self.global(*global)?.0;
+                self.push(ty);
+            }
+            Instr::GlobalSet(global) => {
+                let (ty, mutable) = self.global(*global)?;
+                if !mutable {
+                    return Err(format!("Global {global} is immutable"));
+                }
+                self.pop_expecting(ty)?;
+            }
+            Instr::I32Const(_) => self.push(ValType::I32),
+            Instr::I64Const(_) => self.push(ValType::I64),
+            Instr::Memory(op, memarg) => {
+                self.needs_memory()?;
+                if op.access.is_some_and(|natural| memarg.align > natural) {
+                    return Err("The alignment is larger than the access".to_string());
+                }
+                self.pop_all(op.params)?;
+                for ty in op.results {
+                    self.push(*ty);
+                }
+            }
+            Instr::Plain(op) => match op.name {
+                "unreachable" => self.mark_unreachable()?,
+                "nop" => {}
+                "return" => {
+                    let results = self.frames[0].label_types.clone();
+                    self.pop_all(&results)?;
+                    self.mark_unreachable()?;
+                }
+                "drop" => {
+                    self.pop()?;
+                }
+                "select" => {
+                    self.pop_expecting(ValType::I32)?;
+                    let second = self.pop()?;
+                    let first = self.pop()?;
+                    match (first, second) {
+                        (Some(first), Some(second)) if first != second => {
+                            return Err(
+                                "select chooses between values of different types".to_string()
+                            )
+                        }
+                        (first, second) => match first.or(second) {
+                            Some(ty) => self.push(ty),
+                            None => self.stack.push(None),
+                        },
+                    }
+                }
+                name => {
+                    if name.starts_with("memory.") {
+                        self.needs_memory()?;
+                    }
+                    self.pop_all(op.params)?;
+                    for ty in op.results {
+                        self.push(*ty);
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn local(&self, local: u32) -> Result<ValType, String> {
+        self.locals
+            .get(local as usize)
+            .copied()
+            .ok_or_else(|| format!("Unknown local {local}"))
+    }
+
+    fn global(&self, global: u32) -> Result<(ValType, bool), String> {
+        self.module
+            .globals
+            .get(global as usize)
+            .map(|global| (global.ty, global.mutable))
+            .ok_or_else(|| format!("Unknown global {global}"))
+    }
+}
+
+// An instruction as errors name it
+fn describe(instruction: &Instr) -> String {
+    match instruction {
+        Instr::Block(_) => "block".to_string(),
+        Instr::Loop(_) => "loop".to_string(),
+        Instr::If(_) => "if".to_string(),
+        Instr::Else => "else".to_string(),
+        Instr::End => "end".to_string(),
+        Instr::Br(depth) => format!("br {depth}"),
+        Instr::BrIf(depth) => format!("br_if {depth}"),
+        Instr::BrTable(..) => "br_table".to_string(),
+        Instr::Call(function) => format!("call {function}"),
+        Instr::CallIndirect(ty) => format!("call_indirect {ty}"),
+        Instr::LocalGet(local) => format!("local.get {local}"),
+        Instr::LocalSet(local) => format!("local.set {local}"),
+        Instr::LocalTee(local) => format!("local.tee {local}"),
+        Instr::GlobalGet(global) => format!("global.get {global}"),
+        Instr::GlobalSet(global) => format!("global.set {global}"),
+        Instr::I32Const(value) => format!("i32.const {value}"),
+        Instr::I64Const(value) => format!("i64.const {value}"),
+        Instr::Memory(op, _) | Instr::Plain(op) => op.name.to_string(),
+    }
+}
@@ -0,0 +1,1351 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::options::Options;
+use crate::wasm_validator;
+
+/// The value types Nimra code uses, which are only integers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+}
+
+impl ValType {
+    fn code(self) -> u8 {
+        match self {
+            ValType::I32 => 0x7F,
+            ValType::I64 => 0x7E,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+/// What a block, loop or if leaves on the stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockType {
+    Empty,
+    Value(ValType),
+}
+
+impl BlockType {
+    pub fn results(self) -> Vec<ValType> {
+        match self {
+            BlockType::Empty => Vec::new(),
+            BlockType::Value(ty) => vec![ty],
+        }
+    }
+}
+
+/// The offset a load or store adds to its address, and the alignment it
+/// promises as a power of two
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemArg {
+    pub align: u32,
+    pub offset: u32,
+}
+
+/// An instruction with no immediates other than a memory access's, along with
+/// its encoding and type. The types of `drop`, `select`, `return` and
+/// `unreachable` depend on the stack, so the validator treats them specially.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Op {
+    pub name: &'static str,
+    code: &'static [u8],
+    pub params: &'static [ValType],
+    pub results: &'static [ValType],
+    /// The natural alignment of a load or store, as a power of two
+    pub access: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instr {
+    Block(BlockType),
+    Loop(BlockType),
+    If(BlockType),
+    Else,
+    End,
+    /// Branches count the enclosing blocks outwards from 0
+    Br(u32),
+    BrIf(u32),
+    BrTable(Vec<u32>, u32),
+    Call(u32),
+    /// Calls the function at an index in the table, which has the type given
+    CallIndirect(u32),
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    I32Const(i32),
+    I64Const(i64),
+    Memory(&'static Op, MemArg),
+    Plain(&'static Op),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub min: u32,
+    pub max: Option<u32>,
+}
+
+/// An imported function
+#[derive(Debug, Clone)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub ty: u32,
+    pub id: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Func {
+    pub ty: u32,
+    pub locals: Vec<ValType>,
+    /// Ends with the `end` of the function
+    pub body: Vec<Instr>,
+    pub id: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Global {
+    pub ty: ValType,
+    pub mutable: bool,
+    pub init: Instr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportKind {
+    Func,
+    Table,
+    Memory,
+    Global,
+}
+
+#[derive(Debug, Clone)]
+pub struct Export {
+    pub name: String,
+    pub kind: ExportKind,
+    pub index: u32,
+}
+
+/// Functions put in the table when the module is instantiated
+#[derive(Debug, Clone)]
+pub struct Element {
+    pub offset: Instr,
+    pub functions: Vec<u32>,
+}
+
+/// Bytes put in memory when the module is instantiated
+#[derive(Debug, Clone)]
+pub struct Data {
+    pub offset: Instr,
+    pub bytes: Vec<u8>,
+}
+
+/// A WebAssembly module with at most one table and one memory. Functions
+/// are numbered with the imports first.
+#[derive(Debug, Clone, Default)]
+pub struct Module {
+    pub types: Vec<FuncType>,
+    pub imports: Vec<Import>,
+    pub functions: Vec<Func>,
+    pub table: Option<Limits>,
+    pub memory: Option<Limits>,
+    pub globals: Vec<Global>,
+    pub exports: Vec<Export>,
+    pub elements: Vec<Element>,
+    pub data: Vec<Data>,
+}
+
+impl Module {
+    /// The type of a function, counting imports
+    pub fn function_type(&self, index: u32) -> Option<&FuncType> {
+        let index = index as usize;
+        let ty = match self.imports.get(index) {
+            Some(import) => import.ty,
+            None => self.functions.get(index - self.imports.len())?.ty,
+        };
+        self.types.get(ty as usize)
+    }
+
+    pub fn function_count(&self) -> usize {
+        self.imports.len() + self.functions.len()
+    }
+
+    /// What errors call a function: its name if it has one
+    pub fn function_name(&self, index: u32) -> String {
+        let index = index as usize;
+        let id = match self.imports.get(index) {
+            Some(import) => import.id.clone(),
+            None => self
+                .functions
+                .get(index - self.imports.len())
+                .and_then(|function| function.id.clone()),
+        };
+        id.unwrap_or_else(|| index.to_string())
+    }
+}
+
+const I32: ValType = ValType::I32;
+const I64: ValType = ValType::I64;
+
+macro_rules! ops {
+    ($($name:literal [$($code:literal),*] [$($param:ident),*] -> [$($result:ident),*] $(@ $access:literal)?;)*) => {
+        &[$(Op {
+            name: $name,
+            code: &[$($code),*],
+            params: &[$($param),*],
+            results: &[$($result),*],
+            access: ops!(@access $($access)?),
+        }),*]
+    };
+    (@access) => { None };
+    (@access $access:literal) => { Some($access) };
+}
+
+/// Every instruction `Instr::Plain` and `Instr::Memory` can hold
+pub const OPS: &[Op] = ops! {
+    "unreachable" [0x00] [] -> [];
+    "nop" [0x01] [] -> [];
+    "return" [0x0F] [] -> [];
+    "drop" [0x1A] [] -> [];
+    "select" [0x1B] [] -> [];
+    "i32.load" [0x28] [I32] -> [I32] @2;
+    "i64.load" [0x29] [I32] -> [I64] @3;
+    "i32.load8_s" [0x2C] [I32] -> [I32] @0;
+    "i32.load8_u" [0x2D] [I32] -> [I32] @0;
+    "i32.load16_s" [0x2E] [I32] -> [I32] @1;
+    "i32.load16_u" [0x2F] [I32] -> [I32] @1;
+    "i64.load8_s" [0x30] [I32] -> [I64] @0;
+    "i64.load8_u" [0x31] [I32] -> [I64] @0;
+    "i64.load16_s" [0x32] [I32] -> [I64] @1;
+    "i64.load16_u" [0x33] [I32] -> [I64] @1;
+    "i64.load32_s" [0x34] [I32] -> [I64] @2;
+    "i64.load32_u" [0x35] [I32] -> [I64] @2;
+    "i32.store" [0x36] [I32, I32] -> [] @2;
+    "i64.store" [0x37] [I32, I64] -> [] @3;
+    "i32.store8" [0x3A] [I32, I32] -> [] @0;
+    "i32.store16" [0x3B] [I32, I32] -> [] @1;
+    "i64.store8" [0x3C] [I32, I64] -> [] @0;
+    "i64.store16" [0x3D] [I32, I64] -> [] @1;
+    "i64.store32" [0x3E] [I32, I64] -> [] @2;
+    "memory.size" [0x3F, 0x00] [] -> [I32];
+    "memory.grow" [0x40, 0x00] [I32] -> [I32];
+    "memory.copy" [0xFC, 0x0A, 0x00, 0x00] [I32, I32, I32] -> [];
+    "memory.fill" [0xFC, 0x0B, 0x00] [I32, I32, I32] -> [];
+    "i32.eqz" [0x45] [I32] -> [I32];
+    "i32.eq" [0x46] [I32, I32] -> [I32];
+    "i32.ne" [0x47] [I32, I32] -> [I32];
+    "i32.lt_s" [0x48] [I32, I32] -> [I32];
+    "i32.lt_u" [0x49] [I32, I32] -> [I32];
+    "i32.gt_s" [0x4A] [I32, I32] -> [I32];
+    "i32.gt_u" [0x4B] [I32, I32] -> [I32];
+    "i32.le_s" [0x4C] [I32, I32] -> [I32];
+    "i32.le_u" [0x4D] [I32, I32] -> [I32];
+    "i32.ge_s" [0x4E] [I32, I32] -> [I32];
+    "i32.ge_u" [0x4F] [I32, I32] -> [I32];
+    "i64.eqz" [0x50] [I64] -> [I32];
+    "i64.eq" [0x51] [I64, I64] -> [I32];
+    "i64.ne" [0x52] [I64, I64] -> [I32];
+    "i64.lt_s" [0x53] [I64, I64] -> [I32];
+    "i64.lt_u" [0x54] [I64, I64] -> [I32];
+    "i64.gt_s" [0x55] [I64, I64] -> [I32];
+    "i64.gt_u" [0x56] [I64, I64] -> [I32];
+    "i64.le_s" [0x57] [I64, I64] -> [I32];
+    "i64.le_u" [0x58] [I64, I64] -> [I32];
+    "i64.ge_s" [0x59] [I64, I64] -> [I32];
+    "i64.ge_u" [0x5A] [I64, I64] -> [I32];
+    "i32.clz" [0x67] [I32] -> [I32];
+    "i32.ctz" [0x68] [I32] -> [I32];
+    "i32.popcnt" [0x69] [I32] -> [I32];
+    "i32.add" [0x6A] [I32, I32] -> [I32];
+    "i32.sub" [0x6B] [I32, I32] -> [I32];
+    "i32.mul" [0x6C] [I32, I32] -> [I32];
+    "i32.div_s" [0x6D] [I32, I32] -> [I32];
+    "i32.div_u" [0x6E] [I32, I32] -> [I32];
+    "i32.rem_s" [0x6F] [I32, I32] -> [I32];
+    "i32.rem_u" [0x70] [I32, I32] -> [I32];
+    "i32.and" [0x71] [I32, I32] -> [I32];
+    "i32.or" [0x72] [I32, I32] -> [I32];
+    "i32.xor" [0x73] [I32, I32] -> [I32];
+    "i32.shl" [0x74] [I32, I32] -> [I32];
+    "i32.shr_s" [0x75] [I32, I32] -> [I32];
+    "i32.shr_u" [0x76] [I32, I32] -> [I32];
+    "i32.rotl" [0x77] [I32, I32] -> [I32];
+    "i32.rotr" [0x78] [I32, I32] -> [I32];
+    "i64.clz" [0x79] [I64] -> [I64];
+    "i64.ctz" [0x7A] [I64] -> [I64];
+    "i64.popcnt" [0x7B] [I64] -> [I64];
+    "i64.add" [0x7C] [I64, I64] -> [I64];
+    "i64.sub" [0x7D] [I64, I64] -> [I64];
+    "i64.mul" [0x7E] [I64, I64] -> [I64];
+    "i64.div_s" [0x7F] [I64, I64] -> [I64];
+    "i64.div_u" [0x80] [I64, I64] -> [I64];
+    "i64.rem_s" [0x81] [I64, I64] -> [I64];
+    "i64.rem_u" [0x82] [I64, I64] -> [I64];
+    "i64.and" [0x83] [I64, I64] -> [I64];
+    "i64.or" [0x84] [I64, I64] -> [I64];
+    "i64.xor" [0x85] [I64, I64] -> [I64];
+    "i64.shl" [0x86] [I64, I64] -> [I64];
+    "i64.shr_s" [0x87] [I64, I64] -> [I64];
+    "i64.shr_u" [0x88] [I64, I64] -> [I64];
+    "i64.rotl" [0x89] [I64, I64] -> [I64];
+    "i64.rotr" [0x8A] [I64, I64] -> [I64];
+    "i32.wrap_i64" [0xA7] [I64] -> [I32];
+    "i64.extend_i32_s" [0xAC] [I32] -> [I64];
+    "i64.extend_i32_u" [0xAD] [I32] -> [I64];
+    "i32.extend8_s" [0xC0] [I32] -> [I32];
+    "i32.extend16_s" [0xC1] [I32] -> [I32];
+    "i64.extend8_s" [0xC2] [I64] -> [I64];
+    "i64.extend16_s" [0xC3] [I64] -> [I64];
+    "i64.extend32_s" [0xC4] [I64] -> [I64];
+};
+
+fn op(name: &str) -> Option<&'static Op> {
+    OPS.iter().find(|op| op.name == name)
+}
+
+/// Checks the text of a module, and writes it in the binary format
+pub fn assemble(wat: &str, options: &Options) -> Result<PathBuf, String> {
+    let module = parse(wat)?;
+    wasm_validator::validate(&module)?;
+    fs::write(&options.output, encode(&module))
+        .map_err(|e| format!("Failed to write {}: {e}", options.output))?;
+    Ok(Path::new(&options.output).to_path_buf())
+}
+
+// A parenthesized list, or an atom or string in one, with the line it starts on
+#[derive(Debug, Clone)]
+enum Sexp {
+    Atom(String, usize),
+    Str(Vec<u8>, usize),
+    List(Vec<Sexp>, usize),
+}
+
+impl Sexp {
+    fn line(&self) -> usize {
+        match self {
+            Sexp::Atom(_, line) | Sexp::Str(_, line) | Sexp::List(_, line) => *line,
+        }
+    }
+
+    fn atom(&self) -> Option<&str> {
+        match self {
+            Sexp::Atom(atom, _) => Some(atom),
+            _ => None,
+        }
+    }
+
+    // The items of a list that starts with `keyword`
+    fn form(&self, keyword: &str) -> Option<&[Sexp]> {
+        match self {
+            Sexp::List(items, _) if items.first().and_then(Sexp::atom) == Some(keyword) => {
+                Some(&items[1..])
+            }
+            _ => None,
+        }
+    }
+}
+
+fn error(line: usize, message: &str) -> String {
+    format!("Line {line}: {message}")
+}
+
+// Splits text into lists, atoms and strings, dropping comments
+fn read(text: &str) -> Result<Vec<Sexp>, String> {
+    let bytes = text.as_bytes();
+    let mut stack: Vec<(Vec<Sexp>, usize)> = vec![(Vec::new(), 1)];
+    let mut line = 1;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\n' => {
+                line += 1;
+                i += 1;
+            }
+            b' ' | b'\t' | b'\r' => i += 1,
+            b';' if bytes.get(i + 1) == Some(&b';') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'(' if bytes.get(i + 1) == Some(&b';') => {
+                let start = line;
+                let mut depth = 0;
+                loop {
+                    match (bytes.get(i), bytes.get(i + 1)) {
+                        (Some(b'('), Some(b';')) => {
+                            depth += 1;
+                            i += 2;
+                        }
+                        (Some(b';'), Some(b')')) => {
+                            depth -= 1;
+                            i += 2;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        (Some(b'\n'), _) => {
+                            line += 1;
+                            i += 1;
+                        }
+                        (Some(_), _) => i += 1,
+                        (None, _) => return Err(error(start, "Unterminated block comment")),
+                    }
+                }
+            }
+            b'(' => {
+                stack.push((Vec::new(), line));
+                i += 1;
+            }
+            b')' => {
+                let (items, start) = stack.pop().ok_or_else(|| error(line, "Unmatched )"))?;
+                let parent = stack.last_mut().ok_or_else(|| error(line, "Unmatched )"))?;
+                parent.0.push(Sexp::List(items, start));
+                i += 1;
+            }
+            b'"' => {
+                let start = line;
+                let mut string = Vec::new();
+                i += 1;
+                loop {
+                    match bytes.get(i) {
+                        None | Some(b'\n') => return Err(error(start, "Unterminated string")),
+                        Some(b'"') => break,
+                        Some(b'\\') => {
+                            let escape = bytes.get(i + 1).copied().unwrap_or(b' ');
+                            i += 2;
+                            match escape {
+                                b'n' => string.push(b'\n'),
+                                b't' => string.push(b'\t'),
+                                b'r' => string.push(b'\r'),
+                                b'"' | b'\'' | b'\\' => string.push(escape),
+                                _ => {
+                                    let hex = text
+                                        .get(i - 1..i + 1)
+                                        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                                        .ok_or_else(|| error(line, "Bad escape in string"))?;
+                                    string.push(hex);
+                                    i += 1;
+                                }
+                            }
+                        }
+                        Some(&byte) => {
+                            string.push(byte);
+                            i += 1;
+                        }
+                    }
+                }
+                i += 1;
+                stack
+                    .last_mut()
+                    .ok_or_else(|| error(line, "Unmatched )"))?
+                    .0
+                    .push(Sexp::Str(string, start));
+            }
+            _ => {
+                let start = i;
+                while i < bytes.len() && !b" \t\r\n()\";".contains(&bytes[i]) {
+                    i += 1;
+                }
+                stack
+                    .last_mut()
+                    .ok_or_else(|| error(line, "Unmatched )"))?
+                    .0
+                    .push(Sexp::Atom(text[start..i].to_string(), line));
+            }
+        }
+    }
+    let (items, _) = stack.pop().ok_or_else(|| error(line, "Unmatched )"))?;
+    if !stack.is_empty() {
+        return Err(error(line, "Unclosed ("));
+    }
+    Ok(items)
+}
+
+// Names of what a module defines, which it may refer to before defining them
+#[derive(Default)]
+struct Names {
+    types: HashMap<String, u32>,
+    functions: HashMap<String, u32>,
+    globals: HashMap<String, u32>,
+}
+
+/// Parses a module in the text format. Instructions are written one after
+/// another rather than folded, except in constant expressions.
+pub fn parse(text: &str) -> Result<Module, String> {
+    let items = read(text)?;
+    let [module] = &items[..] else {
+        return Err("Expected exactly one module".to_string());
+    };
+    let fields = module
+        .form("module")
+        .ok_or_else(|| error(module.line(), "Expected a module"))?;
+    let mut module = Module::default();
+    let mut names = Names::default();
+
+    // Types, function signatures and names come first, since code may refer
+    // to anything in the module
+    let mut signatures = Vec::new();
+    let mut globals = 0;
+    for field in fields {
+        if let Some(items) = field.form("type") {
+            let (id, rest) = split_id(items);
+            let ty = match rest {
+                [func] => func
+                    .form("func")
+                    .map(|func| signature(func, &mut None))
+                    .ok_or_else(|| error(field.line(), "Expected a function type"))??,
+                _ => return Err(error(field.line(), "Expected a function type")),
+            };
+            define(&mut names.types, id, module.types.len(), field.line())?;
+            module.types.push(ty);
+        }
+    }
+    for field in fields {
+        if let Some(items) = field.form("import") {
+            if !module.functions.is_empty() || !signatures.is_empty() {
+                return Err(error(field.line(), "Imports must come before functions"));
+            }
+            let [Sexp::Str(module_name, _), Sexp::Str(name, _), func] = items else {
+                return Err(error(
+                    field.line(),
+                    "Expected a module, a name and a function",
+                ));
+            };
+            let func = func
+                .form("func")
+                .ok_or_else(|| error(field.line(), "Only functions can be imported"))?;
+            let (id, rest) = split_id(func);
+            let ty = type_use(rest, &names, &mut module.types, &mut None, field.line())?;
+            define(&mut names.functions, id, module.imports.len(), field.line())?;
+            module.imports.push(Import {
+                module: utf8(module_name, field.line())?,
+                name: utf8(name, field.line())?,
+                ty,
+                id: id.map(str::to_string),
+            });
+        } else if let Some(items) = field.form("func") {
+            let (id, rest) = split_id(items);
+            let mut locals = Some(HashMap::new());
+            let rest = take_exports(rest, &mut Vec::new())?;
+            let ty = type_use(rest, &names, &mut module.types, &mut locals, field.line())?;
+            define(
+                &mut names.functions,
+                id,
+                module.imports.len() + signatures.len(),
+                field.line(),
+            )?;
+            signatures.push((ty, locals.unwrap_or_default()));
+        } else if let Some(items) = field.form("global") {
+            let (id, _) = split_id(items);
+            define(&mut names.globals, id, globals, field.line())?;
+            globals += 1;
+        }
+    }
+
+    let mut signatures = signatures.into_iter();
+    for field in fields {
+        let line = field.line();
+        let Sexp::List(items, _) = field else {
+            return Err(error(line, "Expected a module field"));
+        };
+        let keyword = items.first().and_then(Sexp::atom).unwrap_or("");
+        let items = &items[1..];
+        match keyword {
+            "type" | "import" => {}
+            "func" => {
+                let (id, rest) = split_id(items);
+                let index = (module.imports.len() + module.functions.len()) as u32;
+                let mut exports = Vec::new();
+                let rest = take_exports(rest, &mut exports)?;
+                for name in exports {
+                    module.exports.push(Export {
+                        name,
+                        kind: ExportKind::Func,
+                        index,
+                    });
+                }
+                let (ty, mut locals) = signatures
+                    .next()
+                    .ok_or_else(|| error(line, "Function without a signature"))?;
+                let mut rest = skip_signature(rest);
+                let mut local_types = Vec::new();
+                while let Some(local) = rest.first().and_then(|item| item.form("local")) {
+                    let (local_id, types) = split_id(local);
+                    let first = module.types[ty as usize].params.len() + local_types.len();
+                    define(&mut locals, local_id, first, line)?;
+                    for ty in types {
+                        local_types.push(val_type(ty)?);
+                    }
+                    rest = &rest[1..];
+                }
+                let body = instructions(rest, &names, &locals)?;
+                module.functions.push(Func {
+                    ty,
+                    locals: local_types,
+                    body,
+                    id: id.map(str::to_string),
+                });
+            }
+            "table" => {
+                let (_, rest) = split_id(items);
+                let mut exports = Vec::new();
+                let rest = take_exports(rest, &mut exports)?;
+                let [limits @ .., Sexp::Atom(kind, _)] = rest else {
+                    return Err(error(line, "Expected a table's limits and type"));
+                };
+                if kind != "funcref" {
+                    return Err(error(line, "Tables can only hold funcref"));
+                }
+                if module.table.is_some() {
+                    return Err(error(line, "A module has at most one table"));
+                }
+                module.table = Some(self::limits(limits, line)?);
+                for name in exports {
+                    module.exports.push(Export {
+                        name,
+                        kind: ExportKind::Table,
+                        index: 0,
+                    });
+                }
+            }
+            "memory" => {
+                let (_, rest) = split_id(items);
+                let mut exports = Vec::new();
+                let rest = take_exports(rest, &mut exports)?;
+                if module.memory.is_some() {
+                    return Err(error(line, "A module has at most one memory"));
+                }
+                module.memory = Some(limits(rest, line)?);
+                for name in exports {
+                    module.exports.push(Export {
+                        name,
+                        kind: ExportKind::Memory,
+                        index: 0,
+                    });
+                }
+            }
+            "global" => {
+                let (_, rest) = split_id(items);
+                let index = module.globals.len() as u32;
+                let mut exports = Vec::new();
+                let rest = take_exports(rest, &mut exports)?;
+                let [ty, init] = rest else {
+                    return Err(error(line, "Expected a global's type and value"));
+                };
+                let (ty, mutable) = match ty.form("mut") {
+                    Some([ty]) => (val_type(ty)?, true),
+                    Some(_) => return Err(error(line, "Expected a global's type")),
+                    None => (val_type(ty)?, false),
+                };
+                module.globals.push(Global {
+                    ty,
+                    mutable,
+                    init: constant(init, &names)?,
+                });
+                for name in exports {
+                    module.exports.push(Export {
+                        name,
+                        kind: ExportKind::Global,
+                        index,
+                    });
+                }
+            }
+            "export" => {
+                let [Sexp::Str(name, _), target] = items else {
+                    return Err(error(line, "Expected an export's name and what it exports"));
+                };
+                let (kind, index) = if let Some([function]) = target.form("func") {
+                    (ExportKind::Func, index(function, &names.functions)?)
+                } else if let Some([global]) = target.form("global") {
+                    (ExportKind::Global, index(global, &names.globals)?)
+                } else if target.form("memory").is_some() {
+                    (ExportKind::Memory, 0)
+                } else if target.form("table").is_some() {
+                    (ExportKind::Table, 0)
+                } else {
+                    return Err(error(line, "Unknown kind of export"));
+                };
+                module.exports.push(Export {
+                    name: utf8(name, line)?,
+                    kind,
+                    index,
+                });
+            }
+            "elem" => {
+                let [offset, functions @ ..] = items else {
+                    return Err(error(line, "Expected an element segment's offset"));
+                };
+                let functions = match functions {
+                    [Sexp::Atom(func, _), rest @ ..] if func == "func" => rest,
+                    functions => functions,
+                };
+                module.elements.push(Element {
+                    offset: constant(offset, &names)?,
+                    functions: functions
+                        .iter()
+                        .map(|function| index(function, &names.functions))
+                        .collect::<Result<_, _>>()?,
+                });
+            }
+            "data" => {
+                let [offset, strings @ ..] = items else {
+                    return Err(error(line, "Expected a data segment's offset"));
+                };
+                let mut bytes = Vec::new();
+                for string in strings {
+                    match string {
+                        Sexp::Str(string, _) => bytes.extend_from_slice(string),
+                        _ => return Err(error(string.line(), "Expected a string")),
+                    }
+                }
+                module.data.push(Data {
+                    offset: constant(offset, &names)?,
+                    bytes,
+                });
+            }
+            keyword => return Err(error(line, &format!("Unknown module field {keyword}"))),
+        }
+    }
+    Ok(module)
+}
+
+// Splits off the `$name` a definition may start with
+fn split_id(items: &[Sexp]) -> (Option<&str>, &[Sexp]) {
+    match items.first().and_then(Sexp::atom) {
+        Some(id) if id.starts_with('$') => (Some(id), &items[1..]),
+        _ => (None, items),
+    }
+}
+
+fn define(
+    names: &mut HashMap<String, u32>,
+    id: Option<&str>,
+    index: usize,
+    line: usize,
+) -> Result<(), String> {
+    if let Some(id) = id {
+        if names.insert(id.to_string(), index as u32).is_some() {
+            return Err(error(line, &format!("{id} is defined twice")));
+        }
+    }
+    Ok(())
+}
+
+// Takes the `(export "name")` forms a definition starts with
+fn take_exports<'s>(
+    mut items: &'s [Sexp],
+    exports: &mut Vec<String>,
+) -> Result<&'s [Sexp], String> {
+    while let Some(export) = items.first().and_then(|item| item.form("export")) {
+        match export {
+            [Sexp::Str(name, line)] => exports.push(utf8(name, *line)?),
+            _ => return Err(error(items[0].line(), "Expected the name of an export")),
+        }
+        items = &items[1..];
+    }
+    Ok(items)
+}
+
+fn utf8(bytes: &[u8], line: usize) -> Result<String, String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| error(line, "Names must be UTF-8"))
+}
+
+fn val_type(item: &Sexp) -> Result<ValType, String> {
+    match item.atom() {
+        Some("i32") => Ok(ValType::I32),
+        Some("i64") => Ok(ValType::I64),
+        _ => Err(error(item.line(), "Expected i32 or i64")),
+    }
+}
+
+// The parameters and results of a function type, naming the parameters in
+// `locals` if there is one to name them in
+fn signature(
+    items: &[Sexp],
+    locals: &mut Option<HashMap<String, u32>>,
+) -> Result<FuncType, String> {
+    let mut ty = FuncType {
+        params: Vec::new(),
+        results: Vec::new(),
+    };
+    for item in items {
+        if let Some(params) = item.form("param") {
+            let (id, types) = split_id(params);
+            if let (Some(locals), Some(id)) = (locals.as_mut(), id) {
+                define(locals, Some(id), ty.params.len(), item.line())?;
+            } else if id.is_some() && locals.is_none() {
+                return Err(error(item.line(), "Parameters of a type cannot be named"));
+            }
+            if id.is_some() && types.len() != 1 {
+                return Err(error(item.line(), "A named parameter has one type"));
+            }
+            for param in types {
+                ty.params.push(val_type(param)?);
+            }
+        } else if let Some(results) = item.form("result") {
+            for result in results {
+                ty.results.push(val_type(result)?);
+            }
+        } else {
+            return Err(error(item.line(), "Expected a parameter or result"));
+        }
+    }
+    Ok(ty)
+}
+
+// The items of a definition after its type use
+fn skip_signature(items: &[Sexp]) -> &[Sexp] {
+    let count = items
+        .iter()
+        .take_while(|item| {
+            item.form("type").is_some()
+                || item.form("param").is_some()
+                || item.form("result").is_some()
+        })
+        .count();
+    &items[count..]
+}
+
+// The index of the type of a function: the type it names, or the first type
+// equal to the signature it spells out, which is added if there is none
+fn type_use(
+    items: &[Sexp],
+    names: &Names,
+    types: &mut Vec<FuncType>,
+    locals: &mut Option<HashMap<String, u32>>,
+    line: usize,
+) -> Result<u32, String> {
+    let (named, items) = match items.first().and_then(|item| item.form("type")) {
+        Some([ty]) => (Some(index(ty, &names.types)?), &items[1..]),
+        Some(_) => return Err(error(line, "Expected a type")),
+        None => (None, items),
+    };
+    let count = items
+        .iter()
+        .take_while(|item| item.form("param").is_some() || item.form("result").is_some())
+        .count();
+    let ty = signature(&items[..count], locals)?;
+    match named {
+        Some(named) => {
+            let declared = types
+                .get(named as usize)
+                .ok_or_else(|| error(line, "Unknown type"))?;
+            if count > 0 && *declared != ty {
+                return Err(error(line, "Signature does not match its type"));
+            }
+            if count == 0 {
+                if let Some(locals) = locals {
+                    locals.clear();
+                }
+            }
+            Ok(named)
+        }
+        None => Ok(match types.iter().position(|other| *other == ty) {
+            Some(position) => position as u32,
+            None => {
+                types.push(ty);
+                types.len() as u32 - 1
+            }
+        }),
+    }
+}
+
+fn index(item: &Sexp, names: &HashMap<String, u32>) -> Result<u32, String> {
+    match item.atom() {
+        Some(id) if id.starts_with('$') => names
+            .get(id)
+            .copied()
+            .ok_or_else(|| error(item.line(), &format!("Unknown name {id}"))),
+        Some(number) => number
+            .parse()
+            .map_err(|_| error(item.line(), &format!("Expected an index, found {number}"))),
+        None => Err(error(item.line(), "Expected an index")),
+    }
+}
+
+fn limits(items: &[Sexp], line: usize) -> Result<Limits, String> {
+    let number = |item: &Sexp| {
+        item.atom()
+            .and_then(|atom| atom.parse().ok())
+            .ok_or_else(|| error(line, "Expected a limit"))
+    };
+    match items {
+        [min] => Ok(Limits {
+            min: number(min)?,
+            max: None,
+        }),
+        [min, max] => Ok(Limits {
+            min: number(min)?,
+            max: Some(number(max)?),
+        }),
+        _ => Err(error(line, "Expected a minimum and an optional maximum")),
+    }
+}
+
+// A constant expression, which is a single folded instruction
+fn constant(item: &Sexp, names: &Names) -> Result<Instr, String> {
+    if let Some([value]) = item.form("i32.const") {
+        return Ok(Instr::I32Const(integer(value, 32)? as i32));
+    }
+    if let Some([value]) = item.form("i64.const") {
+        return Ok(Instr::I64Const(integer(value, 64)? as i64));
+    }
+    if let Some([global]) = item.form("global.get") {
+        return Ok(Instr::GlobalGet(index(global, &names.globals)?));
+    }
+    Err(error(item.line(), "Expected a constant expression"))
+}
+
+// An integer literal of `bits` bits, which may be written signed or unsigned
+fn integer(item: &Sexp, bits: u32) -> Result<i128, String> {
+    let text = item
+        .atom()
+        .ok_or_else(|| error(item.line(), "Expected a number"))?
+        .replace('_', "");
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(&text)),
+    };
+    let magnitude = match digits.strip_prefix("0x") {
+        Some(hex) => i128::from_str_radix(hex, 16),
+        None => digits.parse::<i128>(),
+    }
+    .map_err(|_| error(item.line(), &format!("Bad number {text}")))?;
+    let value = if negative { -magnitude } else { magnitude };
+    if value < -(1 << (bits - 1)) || value >= 1 << bits {
+        return Err(error(
+            item.line(),
+            &format!("{text} does not fit in {bits} bits"),
+        ));
+    }
+    Ok(value)
+}
+
+// The instructions of a function body, and the `end` that closes it
+fn instructions(
+    items: &[Sexp],
+    names: &Names,
+    locals: &HashMap<String, u32>,
+) -> Result<Vec<Instr>, String> {
+    let mut body = Vec::new();
+    // The label of each block that is open, innermost last
+    let mut labels: Vec<Option<String>> = Vec::new();
+    let mut i = 0;
+    let label = |item: &Sexp, labels: &[Option<String>]| -> Result<u32, String> {
+        match item.atom() {
+            Some(id) if id.starts_with('$') => labels
+                .iter()
+                .rev()
+                .position(|label| label.as_deref() == Some(id))
+                .map(|depth| depth as u32)
+                .ok_or_else(|| error(item.line(), &format!("Unknown label {id}"))),
+            _ => index(item, &HashMap::new()),
+        }
+    };
+    while i < items.len() {
+        let item = &items[i];
+        let line = item.line();
+        let name = item
+            .atom()
+            .ok_or_else(|| error(line, "Folded instructions are not supported"))?;
+        i += 1;
+        let operand = |i: &mut usize| {
+            let operand = items
+                .get(*i)
+                .ok_or_else(|| error(line, &format!("{name} needs an operand")))?;
+            *i += 1;
+            Ok::<_, String>(operand)
+        };
+        let instruction = match name {
+            "block" | "loop" | "if" => {
+                let id = match items.get(i).and_then(Sexp::atom) {
+                    Some(id) if id.starts_with('$') => {
+                        i += 1;
+                        Some(id.to_string())
+                    }
+                    _ => None,
+                };
+                let ty = match items.get(i).and_then(|item| item.form("result")) {
+                    Some([ty]) => {
+                        i += 1;
+                        BlockType::Value(val_type(ty)?)
+                    }
+                    Some(_) => return Err(error(line, "A block has at most one result")),
+                    None => BlockType::Empty,
+                };
+                labels.push(id);
+                match name {
+                    "block" => Instr::Block(ty),
+                    "loop" => Instr::Loop(ty),
+                    _ => Instr::If(ty),
+                }
+            }
+            "else" => Instr::Else,
+            "end" => {
+                if labels.pop().is_none() {
+                    return Err(error(line, "end without a block"));
+                }
+                Instr::End
+            }
+            "br" => Instr::Br(label(operand(&mut i)?, &labels)?),
+            "br_if" => Instr::BrIf(label(operand(&mut i)?, &labels)?),
+            "br_table" => {
+                let mut targets = Vec::new();
+                while let Some(target) = items.get(i).filter(|item| {
+                    item.atom()
+                        .is_some_and(|atom| atom.starts_with('$') || atom.parse::<u32>().is_ok())
+                }) {
+                    targets.push(label(target, &labels)?);
+                    i += 1;
+                }
+                let default = targets
+                    .pop()
+                    .ok_or_else(|| error(line, "br_table needs a default label"))?;
+                Instr::BrTable(targets, default)
+            }
+            "call" => Instr::Call(index(operand(&mut i)?, &names.functions)?),
+            "call_indirect" => match operand(&mut i)?.form("type") {
+                Some([ty]) => Instr::CallIndirect(index(ty, &names.types)?),
+                _ => return Err(error(line, "call_indirect needs a (type ...)")),
+            },
+            "local.get" => Instr::LocalGet(index(operand(&mut i)?, locals)?),
+            "local.set" => Instr::LocalSet(index(operand(&mut i)?, locals)?),
+            "local.tee" => Instr::LocalTee(index(operand(&mut i)?, locals)?),
+            "global.get" => Instr::GlobalGet(index(operand(&mut i)?, &names.globals)?),
+            "global.set" => Instr::GlobalSet(index(operand(&mut i)?, &names.globals)?),
+            "i32.const" => Instr::I32Const(integer(operand(&mut i)?, 32)? as i32),
+            "i64.const" => Instr::I64Const(integer(operand(&mut i)?, 64)? as i64),
+            name => {
+                let op =
+                    op(name).ok_or_else(|| error(line, &format!("Unknown instruction {name}")))?;
+                match op.access {
+                    Some(natural) => {
+                        let mut memarg = MemArg {
+                            align: natural,
+                            offset: 0,
+                        };
+                        while let Some(atom) = items.get(i).and_then(Sexp::atom) {
+                            if let Some(offset) = atom.strip_prefix("offset=") {
+                                memarg.offset = match offset.strip_prefix("0x") {
+                                    Some(hex) => u32::from_str_radix(hex, 16),
+                                    None => offset.parse(),
+                                }
+                                .map_err(|_| error(line, "Bad offset"))?;
+                            } else if let Some(align) = atom.strip_prefix("align=") {
+                                let align: u32 =
+                                    align.parse().map_err(|_| error(line, "Bad alignment"))?;
+                                if !align.is_power_of_two() {
+                                    return Err(error(line, "Alignment must be a power of two"));
+                                }
+                                memarg.align = align.trailing_zeros();
+                            } else {
+                                break;
+                            }
+                            i += 1;
+                        }
+                        Instr::Memory(op, memarg)
+                    }
+                    None => Instr::Plain(op),
+                }
+            }
+        };
+        body.push(instruction);
+    }
+    if !labels.is_empty() {
+        return Err(error(
+            items.last().map_or(0, Sexp::line),
+            "Block without an end",
+        ));
+    }
+    body.push(Instr::End);
+    Ok(body)
+}
+
+/// Writes a module in the binary format
+pub fn encode(module: &Module) -> Vec<u8> {
+    let mut out = b"\0asm".to_vec();
+    out.extend_from_slice(&1u32.to_le_bytes());
+
+    section(&mut out, 1, &module.types, |out, ty| {
+        out.push(0x60);
+        vector(out, &ty.params, |out, param| out.push(param.code()));
+        vector(out, &ty.results, |out, result| out.push(result.code()));
+    });
+    section(&mut out, 2, &module.imports, |out, import| {
+        name(out, &import.module);
+        name(out, &import.name);
+        out.push(0x00);
+        unsigned(out, u64::from(import.ty));
+    });
+    section(&mut out, 3, &module.functions, |out, function| {
+        unsigned(out, u64::from(function.ty));
+    });
+    section(
+        &mut out,
+        4,
+        &module.table.into_iter().collect::<Vec<_>>(),
+        |out, table| {
+            out.push(0x70);
+            encode_limits(out, table);
+        },
+    );
+    section(
+        &mut out,
+        5,
+        &module.memory.into_iter().collect::<Vec<_>>(),
+        encode_limits,
+    );
+    section(&mut out, 6, &module.globals, |out, global| {
+        out.push(global.ty.code());
+        out.push(u8::from(global.mutable));
+        expression(out, &global.init);
+    });
+    section(&mut out, 7, &module.exports, |out, export| {
+        name(out, &export.name);
+        out.push(match export.kind {
+            ExportKind::Func => 0x00,
+            ExportKind::Table => 0x01,
+            ExportKind::Memory => 0x02,
+            ExportKind::Global => 0x03,
+        });
+        unsigned(out, u64::from(export.index));
+    });
+    section(&mut out, 9, &module.elements, |out, element| {
+        out.push(0x00);
+        expression(out, &element.offset);
+        vector(out, &element.functions, |out, function| {
+            unsigned(out, u64::from(*function))
+        });
+    });
+    section(&mut out, 10, &module.functions, |out, function| {
+        let mut code = Vec::new();
+        // Locals are declared in runs of one type
+        let mut runs: Vec<(u32, ValType)> = Vec::new();
+        for local in &function.locals {
+            match runs.last_mut() {
+                Some((count, ty)) if ty == local => *count += 1,
+                _ => runs.push((1, *local)),
+            }
+        }
+        vector(&mut code, &runs, |out, (count, ty)| {
+            unsigned(out, u64::from(*count));
+            out.push(ty.code());
+        });
+        for instruction in &function.body {
+            encode_instruction(&mut code, instruction);
+        }
+        unsigned(out, code.len() as u64);
+        out.extend(code);
+    });
+    section(&mut out, 11, &module.data, |out, data| {
+        out.push(0x00);
+        expression(out, &data.offset);
+        vector(out, &data.bytes, |out, byte| out.push(*byte));
+    });
+
+    // The name section, which lets runtimes name functions in stack traces
+    let named: Vec<(u32, String)> = (0..module.function_count() as u32)
+        .filter_map(|index| {
+            let id = match module.imports.get(index as usize) {
+                Some(import) => import.id.as_ref(),
+                None => module.functions[index as usize - module.imports.len()]
+                    .id
+                    .as_ref(),
+            }?;
+            Some((index, id.trim_start_matches('$').to_string()))
+        })
+        .collect();
+    let mut names = Vec::new();
+    name(&mut names, "name");
+    let mut functions = Vec::new();
+    vector(&mut functions, &named, |out, (index, id)| {
+        unsigned(out, u64::from(*index));
+        name(out, id);
+    });
+    names.push(1);
+    unsigned(&mut names, functions.len() as u64);
+    names.extend(functions);
+    out.push(0);
+    unsigned(&mut out, names.len() as u64);
+    out.extend(names);
+    out
+}
+
+// A section of items, left out if there are none
+fn section<T>(out: &mut Vec<u8>, id: u8, items: &[T], item: impl Fn(&mut Vec<u8>, &T)) {
+    if items.is_empty() {
+        return;
+    }
+    let mut contents = Vec::new();
+    vector(&mut contents, items, item);
+    out.push(id);
+    unsigned(out, contents.len() as u64);
+    out.extend(contents);
+}
+
+fn vector<T>(out: &mut Vec<u8>, items: &[T], item: impl Fn(&mut Vec<u8>, &T)) {
+    unsigned(out, items.len() as u64);
+    for each in items {
+        item(out, each);
+    }
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    unsigned(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn encode_limits(out: &mut Vec<u8>, limits: &Limits) {
+    match limits.max {
+        None => {
+            out.push(0x00);
+            unsigned(out, u64::from(limits.min));
+        }
+        Some(max) => {
+            out.push(0x01);
+            unsigned(out, u64::from(limits.min));
+            unsigned(out, u64::from(max));
+        }
+    }
+}
+
+fn expression(out: &mut Vec<u8>, instruction: &Instr) {
+    encode_instruction(out, instruction);
+    out.push(0x0B);
+}
+
+fn block_type(out: &mut Vec<u8>, ty: BlockType) {
+    match ty {
+        BlockType::Empty => out.push(0x40),
+        BlockType::Value(ty) => out.push(ty.code()),
+    }
+}
+
+fn encode_instruction(out: &mut Vec<u8>, instruction: &Instr) {
+    match instruction {
+        Instr::Block(ty) => {
+            out.push(0x02);
+            block_type(out, *ty);
+        }
+        Instr::Loop(ty) => {
+            out.push(0x03);
+            block_type(out, *ty);
+        }
+        Instr::If(ty) => {
+            out.push(0x04);
+            block_type(out, *ty);
+        }
+        Instr::Else => out.push(0x05),
+        Instr::End => out.push(0x0B),
+        Instr::Br(depth) => {
+            out.push(0x0C);
+            unsigned(out, u64::from(*depth));
+        }
+        Instr::BrIf(depth) => {
+            out.push(0x0D);
+            unsigned(out, u64::from(*depth));
+        }
+        Instr::BrTable(targets, default) => {
+            out.push(0x0E);
+            vector(out, targets, |out, target| {
+                unsigned(out, u64::from(*target))
+            });
+            unsigned(out, u64::from(*default));
+        }
+        Instr::Call(function) => {
+            out.push(0x10);
+            unsigned(out, u64::from(*function));
+        }
+        Instr::CallIndirect(ty) => {
+            out.push(0x11);
+            unsigned(out, u64::from(*ty));
+            out.push(0x00);
+        }
+        Instr::LocalGet(local) => {
+            out.push(0x20);
+            unsigned(out, u64::from(*local));
+        }
+        Instr::LocalSet(local) => {
+            out.push(0x21);
+            unsigned(out, u64::from(*local));
+        }
+        Instr::LocalTee(local) => {
+            out.push(0x22);
+            unsigned(out, u64::from(*local));
+        }
+        Instr::GlobalGet(global) => {
+            out.push(0x23);
+            unsigned(out, u64::from(*global));
+        }
+        Instr::GlobalSet(global) => {
+            out.push(0x24);
+            unsigned(out, u64::from(*global));
+        }
+        Instr::I32Const(value) => {
+            out.push(0x41);
+            signed(out, i64::from(*value));
+        }
+        Instr::I64Const(value) => {
+            out.push(0x42);
+            signed(out, *value);
+        }
+        Instr::Memory(op, memarg) => {
+            out.extend_from_slice(op.code);
+            unsigned(out, u64::from(memarg.align));
+            unsigned(out, u64::from(memarg.offset));
+        }
+        Instr::Plain(op) => out.extend_from_slice(op.code),
+    }
+}
+
+// LEB128
+fn unsigned(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn signed(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
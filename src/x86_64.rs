@@ -0,0 +1,2153 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+use std::collections::HashMap;
+
+use crate::generator::{self, ICInstruction};
+use crate::ir::{self, BlockId, Function, Instruction, Operand, Reg, Terminator};
+use crate::lexer::{Length, Literal, Span, Type};
+use crate::options::Options;
+use crate::parser::{BinaryOp, UnaryOp};
+use crate::sema::{self, FormatPiece, Intrinsic, IoFunction, TraitMethod};
+
+/// Where the System V calling convention passes integer arguments, in order
+const ARGUMENT_REGISTERS: [&str; 6] = ["%rdi", "%rsi", "%rdx", "%rcx", "%r8", "%r9"];
+
+/// The registers a call preserves, which hold the scalars register allocation picks
+const ALLOCATABLE: [&str; 5] = ["%rbx", "%r12", "%r13", "%r14", "%r15"];
+
+/// Copies and clears longer than this many bytes use string instructions
+/// rather than a move per word
+const INLINE_COPY_LIMIT: u64 = 64;
+
+/// Where a value lives in memory
+#[derive(Debug, Clone)]
+enum Mem {
+    /// At an offset from the frame pointer
+    Frame(i64),
+    /// At an offset from a label in read-only data
+    Label(String, i64),
+    /// At an offset from the address in a register
+    Base(&'static str, i64),
+}
+
+impl Mem {
+    fn at(&self, offset: u64) -> Mem {
+        let offset = offset as i64;
+        match self {
+            Mem::Frame(base) => Mem::Frame(base + offset),
+            Mem::Label(label, base) => Mem::Label(label.clone(), base + offset),
+            Mem::Base(reg, base) => Mem::Base(reg, base + offset),
+        }
+    }
+
+    fn operand(&self) -> String {
+        match self {
+            Mem::Frame(offset) => format!("{offset}(%rbp)"),
+            Mem::Label(label, 0) => format!("{label}(%rip)"),
+            Mem::Label(label, offset) => format!("{label}+{offset}(%rip)"),
+            Mem::Base(reg, offset) => format!("{offset}({reg})"),
+        }
+    }
+}
+
+/// A value generated code reads or writes
+#[derive(Debug, Clone)]
+enum Value {
+    Reg(&'static str),
+    Imm(i64),
+    Mem(Mem),
+    /// The address of a label
+    Address(String),
+    /// A value of a type with no size, which takes no code to move
+    Void,
+}
+
+/// How a call passes an argument
+#[derive(Debug, Clone, Copy)]
+enum Passing {
+    /// In this many registers, starting from the given one
+    Registers(usize, usize),
+    /// At this offset in the arguments on the stack
+    Stack(u64),
+    Nothing,
+}
+
+/// What a call goes to
+enum Target {
+    Label(String),
+    /// The function whose address is stored here
+    Pointer(Mem),
+    /// An entry of the vtable whose address is stored here
+    Vtable(Mem, usize),
+}
+
+/// What integer arithmetic does when its result does not fit in its type
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Overflow {
+    Panic,
+    Wrap,
+    Saturate,
+}
+
+/// Generates GNU assembly for x86-64 Linux that links against the runtime in
+/// `runtime/x86_64.s`, and follows the System V calling convention
+pub fn generate(ic: &[ICInstruction], options: &Options) -> Result<String, String> {
+    Program::new(ic, options).generate()
+}
+
+struct Program<'a> {
+    ic: &'a [ICInstruction],
+    structs: HashMap<String, Vec<(String, Type)>>,
+    traits: HashMap<String, Vec<TraitMethod>>,
+    returns: HashMap<String, Type>,
+    owned: Vec<Type>,
+    debug: bool,
+    file: String,
+    text: String,
+    data: String,
+    strings: HashMap<String, String>,
+    locations: HashMap<(usize, usize), String>,
+    names: HashMap<String, String>,
+    labels: usize,
+    functions: usize,
+}
+
+impl<'a> Program<'a> {
+    fn new(ic: &'a [ICInstruction], options: &Options) -> Program<'a> {
+        let mut structs = HashMap::new();
+        let mut traits = HashMap::new();
+        let mut returns = HashMap::new();
+        let mut owned = Vec::new();
+        for item in ic {
+            match item {
+                ICInstruction::StructDecl { name, fields } => {
+                    structs.insert(name.clone(), fields.clone());
+                }
+                ICInstruction::TraitDecl { name, methods } => {
+                    traits.insert(name.clone(), methods.clone());
+                }
+                ICInstruction::Function(function) => {
+                    returns.insert(function.name.clone(), function.return_type.clone());
+                }
+                ICInstruction::OwnedType(ty) => owned.push(ty.clone()),
+                _ => {}
+            }
+        }
+        Program {
+            ic,
+            structs,
+            traits,
+            returns,
+            owned,
+            debug: !options.release,
+            file: options.input.clone(),
+            text: String::new(),
+            data: String::new(),
+            strings: HashMap::new(),
+            locations: HashMap::new(),
+            names: HashMap::new(),
+            labels: 0,
+            functions: 0,
+        }
+    }
+
+    fn generate(&mut self) -> Result<String, String> {
+        let ic = self.ic;
+        let mut main = None;
+        for item in ic {
+            match item {
+                ICInstruction::Function(function) => {
+                    if function.name == "main" {
+                        main = Some(function.return_type.clone());
+                    }
+                    self.function(function, &format!("fn_{}", function.name), self.debug)?;
+                }
+                ICInstruction::Vtable {
+                    trait_name,
+                    ty,
+                    methods,
+                } => self.vtable(trait_name, ty, methods)?,
+                ICInstruction::OwnedType(ty) => self.drop_glue(ty),
+                _ => {}
+            }
+        }
+        let main = main.ok_or("There is no main function")?;
+        self.function(&ir::entry(&main), "nrt_main", false)?;
+        Ok(format!(
+            "    .text\n    .globl nrt_main\n{}    .section .rodata\n{}    .section .note.GNU-stack, \"\", @progbits\n",
+            self.text, self.data
+        ))
+    }
+
+    fn function(&mut self, function: &Function, label: &str, frame: bool) -> Result<(), String> {
+        let index = self.functions;
+        self.functions += 1;
+        let code = FunctionGen::new(self, function, index, frame).generate(label)?;
+        self.text.push_str(&code);
+        Ok(())
+    }
+
+    /// Emits the vtable for `ty` as a `dyn Trait`, and the functions in it,
+    /// which take a pointer to the value and call the method with the value
+    fn vtable(
+        &mut self,
+        trait_name: &str,
+        ty: &Type,
+        methods: &[TraitMethod],
+    ) -> Result<(), String> {
+        let mut entries = String::new();
+        for method in self.traits.get(trait_name).cloned().unwrap_or_default() {
+            if !methods.iter().any(|m| m.name == method.name) {
+                entries.push_str("    .quad 0\n");
+                continue;
+            }
+            let function = generator::mangle_method(trait_name, ty, &method.name);
+            let label = format!("th_{function}");
+            self.function(&ir::thunk(ty, &method, &function), &label, false)?;
+            entries.push_str(&format!("    .quad {label}\n"));
+        }
+        self.data.push_str(&format!(
+            "    .balign 8\n{}:\n{entries}",
+            vtable_label(trait_name, ty)
+        ));
+        Ok(())
+    }
+
+    /// Emits the function that frees the heap memory the value at the address
+    /// in rdi owns
+    fn drop_glue(&mut self, ty: &Type) {
+        let mut code = format!("dr_{}:\n", generator::mangle(ty));
+        match ty {
+            Type::Box(inner) => {
+                code.push_str("    pushq %rbx\n    movq %rdi, %rbx\n");
+                if self.is_owned(inner) {
+                    code.push_str(&format!(
+                        "    movq (%rbx), %rdi\n    call dr_{}\n",
+                        generator::mangle(inner)
+                    ));
+                }
+                code.push_str("    movq (%rbx), %rdi\n    call nrt_free\n    popq %rbx\n    ret\n");
+            }
+            Type::String => code.push_str("    movq (%rdi), %rdi\n    jmp nrt_free\n"),
+            Type::Buffer(_) => code.push_str("    movq (%rdi), %rdi\n    jmp nrt_buffer_free\n"),
+            Type::Array(element, Length::Known(len)) if self.is_owned(element) => {
+                code.push_str(&format!(
+                    "    pushq %rbx\n    pushq %r12\n    pushq %r13\n    movq %rdi, %rbx\n    movq ${len}, %r12\n1:  testq %r12, %r12\n    jz 2f\n    movq %rbx, %rdi\n    call dr_{}\n    addq ${}, %rbx\n    decq %r12\n    jmp 1b\n2:  popq %r13\n    popq %r12\n    popq %rbx\n    ret\n",
+                    generator::mangle(element),
+                    self.size(element)
+                ));
+            }
+            Type::Struct(..) | Type::Option(_) | Type::Result(..) => {
+                code.push_str("    pushq %rbx\n    movq %rdi, %rbx\n");
+                for (_, offset, field) in self.struct_layout(ty).0 {
+                    if self.is_owned(&field) {
+                        code.push_str(&format!(
+                            "    leaq {offset}(%rbx), %rdi\n    call dr_{}\n",
+                            generator::mangle(&field)
+                        ));
+                    }
+                }
+                code.push_str("    popq %rbx\n    ret\n");
+            }
+            _ => code.push_str("    ret\n"),
+        }
+        self.text.push_str(&code);
+    }
+
+    fn is_owned(&self, ty: &Type) -> bool {
+        self.owned.contains(ty)
+    }
+
+    fn size(&self, ty: &Type) -> u64 {
+        self.layout(ty).0
+    }
+
+    /// The size and alignment of a type, which are those of the C backend's
+    /// type for it
+    fn layout(&self, ty: &Type) -> (u64, u64) {
+        match ty {
+            Type::Void => (0, 1),
+            Type::Bool | Type::I8 | Type::U8 => (1, 1),
+            Type::I16 | Type::U16 => (2, 2),
+            Type::I32 | Type::U32 | Type::Char => (4, 4),
+            Type::I64 | Type::U64 | Type::Ref(..) | Type::Box(_) | Type::Buffer(_) => (8, 8),
+            Type::Str | Type::Dyn(_) => (16, 8),
+            Type::String | Type::Fn(..) => (24, 8),
+            Type::Array(element, len) => {
+                let (size, align) = self.layout(element);
+                let len = match len {
+                    Length::Known(len) => *len,
+                    Length::Const(_) => 0,
+                };
+                (size * len, align)
+            }
+            Type::Struct(..) | Type::Option(_) | Type::Result(..) => {
+                let (_, size, align) = self.struct_layout(ty);
+                (size, align)
+            }
+            Type::Param(name) => unreachable!("type parameter {} outlived monomorphization", name),
+        }
+    }
+
+    /// The name, offset and type of each field of a struct, `Option` or
+    /// `Result`, and the size and alignment of the whole
+    fn struct_layout(&self, ty: &Type) -> (Vec<(String, u64, Type)>, u64, u64) {
+        let mut fields = Vec::new();
+        let mut offset: u64 = 0;
+        let mut align = 1;
+        for (name, field) in self
+            .structs
+            .get(&generator::mangle(ty))
+            .into_iter()
+            .flatten()
+        {
+            let (field_size, field_align) = self.layout(field);
+            offset = offset.next_multiple_of(field_align);
+            fields.push((name.clone(), offset, field.clone()));
+            offset += field_size;
+            align = align.max(field_align);
+        }
+        (fields, offset.next_multiple_of(align), align)
+    }
+
+    fn field(&self, ty: &Type, name: &str) -> Result<(u64, Type), String> {
+        self.struct_layout(ty)
+            .0
+            .into_iter()
+            .find(|(field, _, _)| field == name)
+            .map(|(_, offset, ty)| (offset, ty))
+            .ok_or_else(|| format!("{ty} has no field {name}"))
+    }
+
+    /// How the System V calling convention passes arguments of these types:
+    /// a value of up to 16 bytes in a register per word if enough are left,
+    /// and anything else on the stack. Also returns how many bytes of
+    /// arguments go on the stack.
+    fn classify(&self, types: &[Type], hidden_result: bool) -> (Vec<Passing>, u64) {
+        let mut next = usize::from(hidden_result);
+        let mut stack = 0;
+        let passing = types
+            .iter()
+            .map(|ty| {
+                let size = self.size(ty);
+                let words = size.div_ceil(8) as usize;
+                if size == 0 {
+                    Passing::Nothing
+                } else if size <= 16 && next + words <= ARGUMENT_REGISTERS.len() {
+                    next += words;
+                    Passing::Registers(next - words, words)
+                } else {
+                    stack += size.next_multiple_of(8);
+                    Passing::Stack(stack - size.next_multiple_of(8))
+                }
+            })
+            .collect();
+        (passing, stack)
+    }
+
+    /// Results of more than 16 bytes are written wherever the caller passes
+    /// a pointer to in rdi, and returned in rax:rdx otherwise
+    fn returns_in_memory(&self, ty: &Type) -> bool {
+        self.size(ty) > 16
+    }
+
+    fn new_label(&mut self, kind: &str) -> String {
+        self.labels += 1;
+        format!(".L{kind}{}", self.labels)
+    }
+
+    /// The label of a `str` for the text, as a pointer and a length
+    fn string(&mut self, text: &str) -> String {
+        if let Some(label) = self.strings.get(text) {
+            return label.clone();
+        }
+        let label = self.new_label("str");
+        self.data.push_str(&format!(
+            "    .balign 8\n{label}:\n    .quad {label}_bytes, {}\n{label}_bytes:\n    .ascii {}\n",
+            text.len(),
+            escape_string(text)
+        ));
+        self.strings.insert(text.to_string(), label.clone());
+        label
+    }
+
+    /// The label of a location as the runtime takes it: the file name, which
+    /// is 0 for code from a standard module written in Nimra, then the line
+    /// and column
+    fn location(&mut self, span: Span) -> String {
+        if let Some(label) = self.locations.get(&(span.line, span.column)) {
+            return label.clone();
+        }
+        let file = if span.line == 0 {
+            "0".to_string()
+        } else {
+            self.name(&self.file.clone())
+        };
+        let label = self.new_label("loc");
+        self.data.push_str(&format!(
+            "    .balign 8\n{label}:\n    .quad {file}\n    .long {}, {}\n",
+            span.line, span.column
+        ));
+        self.locations
+            .insert((span.line, span.column), label.clone());
+        label
+    }
+
+    /// The label of a null-terminated copy of `name`
+    fn name(&mut self, name: &str) -> String {
+        if let Some(label) = self.names.get(name) {
+            return label.clone();
+        }
+        let label = self.new_label("name");
+        self.data
+            .push_str(&format!("{label}:\n    .asciz {}\n", escape_string(name)));
+        self.names.insert(name.to_string(), label.clone());
+        label
+    }
+}
+
+fn vtable_label(trait_name: &str, ty: &Type) -> String {
+    format!(
+        "vi_{}{trait_name}{}",
+        trait_name.len(),
+        generator::mangle(ty)
+    )
+}
+
+/// Types that fit in a register and are copied as a whole
+fn is_scalar(ty: &Type) -> bool {
+    ty.is_integer()
+        || matches!(
+            ty,
+            Type::Bool | Type::Char | Type::Ref(..) | Type::Box(_) | Type::Buffer(_)
+        )
+}
+
+/// The type a reference or box points to
+fn pointee(ty: &Type) -> Type {
+    match ty {
+        Type::Ref(inner, _) | Type::Box(inner) => (**inner).clone(),
+        ty => ty.clone(),
+    }
+}
+
+/// The name of the low `size` bytes of a 64-bit register
+fn sized(reg: &str, size: u64) -> String {
+    let name = &reg[1..];
+    let numbered = name[1..].parse::<u8>().is_ok();
+    let sized = match size {
+        8 => name.to_string(),
+        4 if numbered => format!("{name}d"),
+        2 if numbered => format!("{name}w"),
+        _ if numbered => format!("{name}b"),
+        4 => format!("e{}", &name[1..]),
+        2 => name[1..].to_string(),
+        _ if name.ends_with('x') => format!("{}l", &name[1..2]),
+        _ => format!("{}l", &name[1..]),
+    };
+    format!("%{sized}")
+}
+
+/// The suffix of an instruction on `size` bytes
+fn suffix(size: u64) -> char {
+    match size {
+        1 => 'b',
+        2 => 'w',
+        4 => 'l',
+        _ => 'q',
+    }
+}
+
+/// An integer as its type holds it once sign or zero extended to 64 bits
+fn canonical(n: i64, ty: &Type) -> i64 {
+    match ty {
+        Type::I8 => i64::from(n as i8),
+        Type::I16 => i64::from(n as i16),
+        Type::I32 => i64::from(n as i32),
+        Type::U8 => i64::from(n as u8),
+        Type::U16 => i64::from(n as u16),
+        Type::U32 | Type::Char => i64::from(n as u32),
+        _ => n,
+    }
+}
+
+fn escape_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for byte in s.bytes() {
+        match byte {
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\{byte:03o}")),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// Assigns registers to the scalars `candidates` marks, by a linear scan over
+// the blocks in the order they are laid out. A register's interval runs from
+// the first position it is live at to the last, so it covers whole loops, and
+// intervals that share a position conflict, since an instruction may write
+// its result before it has read all of its operands. Registers that do not
+// get one stay in memory.
+fn allocate(function: &Function, candidates: &[bool]) -> Vec<Option<&'static str>> {
+    let count = function.regs.len();
+    let blocks = &function.blocks;
+    let read = |operands: Vec<&Operand>| -> Vec<Reg> {
+        operands
+            .into_iter()
+            .filter_map(|operand| match operand {
+                Operand::Reg(reg) => Some(*reg),
+                Operand::Const(..) => None,
+            })
+            .collect()
+    };
+    // What each block reads before writing it, and what it writes
+    let mut uses = vec![vec![false; count]; blocks.len()];
+    let mut defs = vec![vec![false; count]; blocks.len()];
+    for (id, block) in blocks.iter().enumerate() {
+        for instruction in &block.instructions {
+            for reg in read(instruction.operands()) {
+                uses[id][reg] |= !defs[id][reg];
+            }
+            if let Some(dest) = instruction.dest() {
+                defs[id][dest] = true;
+            }
+        }
+        for reg in read(block.terminator.operands()) {
+            uses[id][reg] |= !defs[id][reg];
+        }
+    }
+    let mut live_in = vec![vec![false; count]; blocks.len()];
+    let mut live_out = vec![vec![false; count]; blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for id in (0..blocks.len()).rev() {
+            let mut out = vec![false; count];
+            for successor in blocks[id].terminator.successors() {
+                for (live, &reg_live) in out.iter_mut().zip(&live_in[successor]) {
+                    *live |= reg_live;
+                }
+            }
+            let input: Vec<bool> = (0..count)
+                .map(|reg| uses[id][reg] || (out[reg] && !defs[id][reg]))
+                .collect();
+            if input != live_in[id] || out != live_out[id] {
+                live_in[id] = input;
+                live_out[id] = out;
+                changed = true;
+            }
+        }
+    }
+
+    let mut start = vec![usize::MAX; count];
+    let mut end = vec![0; count];
+    let mut extend = |reg: Reg, pos: usize| {
+        start[reg] = start[reg].min(pos);
+        end[reg] = end[reg].max(pos);
+    };
+    for &param in function.env.iter().chain(&function.params) {
+        extend(param, 0);
+    }
+    let mut pos = 0;
+    for (id, block) in blocks.iter().enumerate() {
+        let last = pos + block.instructions.len();
+        for reg in 0..count {
+            if live_in[id][reg] {
+                extend(reg, pos);
+            }
+            if live_out[id][reg] {
+                extend(reg, last);
+            }
+        }
+        for instruction in &block.instructions {
+            for reg in read(instruction.operands()) {
+                extend(reg, pos);
+            }
+            if let Some(dest) = instruction.dest() {
+                extend(dest, pos);
+            }
+            pos += 1;
+        }
+        for reg in read(block.terminator.operands()) {
+            extend(reg, pos);
+        }
+        pos += 1;
+    }
+
+    let mut order: Vec<Reg> = (0..count)
+        .filter(|&reg| candidates[reg] && start[reg] != usize::MAX)
+        .collect();
+    order.sort_by_key(|&reg| (start[reg], reg));
+    let mut assigned = vec![None; count];
+    let mut active: Vec<Reg> = Vec::new();
+    let mut free: Vec<&'static str> = ALLOCATABLE.iter().rev().copied().collect();
+    for reg in order {
+        let (expired, still_active): (Vec<Reg>, Vec<Reg>) =
+            active.iter().partition(|&&other| end[other] < start[reg]);
+        for other in expired {
+            free.extend(assigned[other]);
+        }
+        active = still_active;
+        if let Some(phys) = free.pop() {
+            assigned[reg] = Some(phys);
+            active.push(reg);
+            continue;
+        }
+        // Out of registers: whichever interval ends last stays in memory
+        let Some(&furthest) = active.iter().max_by_key(|&&other| end[other]) else {
+            continue;
+        };
+        if end[furthest] > end[reg] {
+            assigned[reg] = assigned[furthest].take();
+            active.retain(|&other| other != furthest);
+            active.push(reg);
+        }
+    }
+    assigned
+}
+
+struct FunctionGen<'p, 'a> {
+    program: &'p mut Program<'a>,
+    function: &'p Function,
+    homes: Vec<Value>,
+    /// The callee-saved registers the function uses, pushed after the frame pointer
+    saved: Vec<&'static str>,
+    /// Bytes of the frame below the saved registers
+    frame_size: u64,
+    /// Where the registers kept in memory start, which are cleared on entry
+    homes_size: u64,
+    frame_record: Option<Mem>,
+    result_address: Option<Mem>,
+    prefix: String,
+    labels: usize,
+    code: String,
+}
+
+impl<'p, 'a> FunctionGen<'p, 'a> {
+    fn new(
+        program: &'p mut Program<'a>,
+        function: &'p Function,
+        index: usize,
+        frame: bool,
+    ) -> FunctionGen<'p, 'a> {
+        let mut address_taken = vec![false; function.regs.len()];
+        for block in &function.blocks {
+            for instruction in &block.instructions {
+                if let Instruction::AddressOf { reg, .. } = instruction {
+                    address_taken[*reg] = true;
+                }
+            }
+        }
+        let candidates: Vec<bool> = function
+            .regs
+            .iter()
+            .zip(&address_taken)
+            .map(|(reg, &taken)| !taken && is_scalar(&reg.ty))
+            .collect();
+        let assigned = allocate(function, &candidates);
+        let saved: Vec<&'static str> = ALLOCATABLE
+            .iter()
+            .copied()
+            .filter(|phys| assigned.contains(&Some(*phys)))
+            .collect();
+        let mut generator = FunctionGen {
+            program,
+            function,
+            homes: Vec::new(),
+            saved,
+            frame_size: 0,
+            homes_size: 0,
+            frame_record: None,
+            result_address: None,
+            prefix: format!(".L{index}_"),
+            labels: 0,
+            code: String::new(),
+        };
+        for (reg, phys) in function.regs.iter().zip(assigned) {
+            let size = generator.program.size(&reg.ty);
+            let home = match phys {
+                _ if size == 0 => Value::Void,
+                Some(phys) => Value::Reg(phys),
+                None => Value::Mem(generator.slot(size)),
+            };
+            generator.homes.push(home);
+        }
+        generator.homes_size = generator.frame_size;
+        if frame {
+            generator.frame_record = Some(generator.slot(16));
+        }
+        if generator.program.returns_in_memory(&function.return_type) {
+            generator.result_address = Some(generator.slot(8));
+        }
+        generator
+    }
+
+    // Reserves a new part of the frame, a multiple of eight bytes long
+    fn slot(&mut self, size: u64) -> Mem {
+        self.frame_size += size.next_multiple_of(8);
+        Mem::Frame(-((8 * self.saved.len() as u64 + self.frame_size) as i64))
+    }
+
+    fn generate(mut self, label: &str) -> Result<String, String> {
+        self.enter();
+        let function = self.function;
+        for (id, block) in function.blocks.iter().enumerate() {
+            let label = self.block_label(id);
+            self.place(&label);
+            for instruction in &block.instructions {
+                self.instruction(instruction)?;
+            }
+            self.terminator(&block.terminator, id)?;
+        }
+        let saved = 8 * self.saved.len() as u64;
+        let frame = (saved + self.frame_size).next_multiple_of(16) - saved;
+        let mut code = format!("{label}:\n    pushq %rbp\n    movq %rsp, %rbp\n");
+        for reg in &self.saved {
+            code.push_str(&format!("    pushq {reg}\n"));
+        }
+        if frame > 0 {
+            code.push_str(&format!("    subq ${frame}, %rsp\n"));
+        }
+        code.push_str(&self.code);
+        code.push_str(&format!("{}ret:\n", self.prefix));
+        if self.saved.is_empty() {
+            code.push_str("    leave\n");
+        } else {
+            code.push_str(&format!("    leaq -{saved}(%rbp), %rsp\n"));
+            for reg in self.saved.iter().rev() {
+                code.push_str(&format!("    popq {reg}\n"));
+            }
+            code.push_str("    popq %rbp\n");
+        }
+        code.push_str("    ret\n");
+        Ok(code)
+    }
+
+    fn emit(&mut self, instruction: &str) {
+        self.code.push_str("    ");
+        self.code.push_str(instruction);
+        self.code.push('\n');
+    }
+
+    fn place(&mut self, label: &str) {
+        self.code.push_str(label);
+        self.code.push_str(":\n");
+    }
+
+    fn new_label(&mut self) -> String {
+        self.labels += 1;
+        format!("{}{}", self.prefix, self.labels)
+    }
+
+    fn block_label(&self, id: BlockId) -> String {
+        format!("{}b{id}", self.prefix)
+    }
+
+    // Clears every register, so one read before it is written is zero, takes
+    // the arguments, and pushes the function's frame in debug builds
+    fn enter(&mut self) {
+        for reg in self.saved.clone() {
+            let reg = sized(reg, 4);
+            self.emit(&format!("xorl {reg}, {reg}"));
+        }
+        let base = 8 * self.saved.len() as i64;
+        let homes = self.homes_size as i64;
+        if homes > INLINE_COPY_LIMIT as i64 {
+            // Only rax and r11 hold no argument
+            let top = self.new_label();
+            self.emit(&format!("leaq {}(%rbp), %rax", -(base + homes)));
+            self.emit(&format!("leaq {}(%rbp), %r11", -base));
+            self.place(&top);
+            self.emit("movq $0, (%rax)");
+            self.emit("addq $8, %rax");
+            self.emit("cmpq %r11, %rax");
+            self.emit(&format!("jb {top}"));
+        } else {
+            for offset in (0..homes).step_by(8) {
+                self.emit(&format!("movq $0, {}(%rbp)", -(base + homes) + offset));
+            }
+        }
+
+        let function = self.function;
+        let params: Vec<Reg> = function
+            .env
+            .iter()
+            .chain(&function.params)
+            .copied()
+            .collect();
+        let types: Vec<Type> = params
+            .iter()
+            .map(|&param| function.regs[param].ty.clone())
+            .collect();
+        let (passing, _) = self.program.classify(&types, self.result_address.is_some());
+        if let Some(result) = self.result_address.clone() {
+            self.emit(&format!("movq %rdi, {}", result.operand()));
+        }
+        // Arguments on the stack come last, since copying them may take
+        // registers other arguments are in
+        for ((&param, ty), &passing) in params.iter().zip(&types).zip(&passing) {
+            let Passing::Registers(first, words) = passing else {
+                continue;
+            };
+            let home = self.homes[param].clone();
+            if is_scalar(ty) {
+                self.store(ARGUMENT_REGISTERS[first], &home, ty);
+            } else {
+                let home = memory(&home);
+                for word in 0..words {
+                    self.emit(&format!(
+                        "movq {}, {}",
+                        ARGUMENT_REGISTERS[first + word],
+                        home.at(8 * word as u64).operand()
+                    ));
+                }
+            }
+        }
+        for ((&param, ty), &passing) in params.iter().zip(&types).zip(&passing) {
+            if let Passing::Stack(offset) = passing {
+                let home = self.homes[param].clone();
+                self.copy(
+                    &Value::Mem(Mem::Base("%rbp", 16 + offset as i64)),
+                    &home,
+                    ty,
+                );
+            }
+        }
+
+        if let Some(record) = self.frame_record.clone() {
+            let name = self.program.name(&function.source_name);
+            self.emit(&format!("leaq {name}(%rip), %rax"));
+            self.emit(&format!("movq %rax, {}", record.operand()));
+            self.emit("movq nrt_frame_top(%rip), %rax");
+            self.emit(&format!("movq %rax, {}", record.at(8).operand()));
+            self.emit(&format!("leaq {}, %rax", record.operand()));
+            self.emit("movq %rax, nrt_frame_top(%rip)");
+        }
+    }
+
+    fn operand(&mut self, operand: &Operand) -> Result<Value, String> {
+        match operand {
+            Operand::Reg(reg) => Ok(self.homes[*reg].clone()),
+            Operand::Const(Literal::Number(n), ty) => Ok(Value::Imm(canonical(*n, ty))),
+            Operand::Const(Literal::Bool(b), _) => Ok(Value::Imm(i64::from(*b))),
+            Operand::Const(Literal::Char(c), _) => Ok(Value::Imm(i64::from(u32::from(*c)))),
+            Operand::Const(Literal::String(s), Type::Str) => {
+                Ok(Value::Mem(Mem::Label(self.program.string(s), 0)))
+            }
+            Operand::Const(literal, ty) => {
+                Err(format!("Cannot use {literal:?} as a constant of type {ty}"))
+            }
+        }
+    }
+
+    fn operand_type(&self, operand: &Operand) -> Type {
+        self.function.operand_type(operand)
+    }
+
+    fn reg_type(&self, reg: Reg) -> Type {
+        self.function.regs[reg].ty.clone()
+    }
+
+    // Loads a scalar into a 64-bit register, sign or zero extended as its type is
+    fn load(&mut self, value: &Value, ty: &Type, reg: &str) {
+        match value {
+            Value::Reg(source) if *source == reg => {}
+            Value::Reg(source) => self.emit(&format!("movq {source}, {reg}")),
+            Value::Imm(n) if i64::from(*n as i32) == *n => self.emit(&format!("movq ${n}, {reg}")),
+            Value::Imm(n) => self.emit(&format!("movabsq ${n}, {reg}")),
+            Value::Address(label) => self.emit(&format!("leaq {label}(%rip), {reg}")),
+            Value::Mem(mem) => {
+                let mem = mem.operand();
+                let instruction = match (self.program.size(ty), ty.is_signed()) {
+                    (1, true) => format!("movsbq {mem}, {reg}"),
+                    (1, false) => format!("movzbq {mem}, {reg}"),
+                    (2, true) => format!("movswq {mem}, {reg}"),
+                    (2, false) => format!("movzwq {mem}, {reg}"),
+                    (4, true) => format!("movslq {mem}, {reg}"),
+                    (4, false) => format!("movl {mem}, {}", sized(reg, 4)),
+                    _ => format!("movq {mem}, {reg}"),
+                };
+                self.emit(&instruction);
+            }
+            Value::Void => {}
+        }
+    }
+
+    // Sign or zero extends the low bytes of a register that hold a value of `ty`
+    fn normalize(&mut self, reg: &str, ty: &Type) {
+        let size = self.program.size(ty);
+        let instruction = match (size, ty.is_signed()) {
+            (1, true) => format!("movsbq {}, {reg}", sized(reg, 1)),
+            (1, false) => format!("movzbq {}, {reg}", sized(reg, 1)),
+            (2, true) => format!("movswq {}, {reg}", sized(reg, 2)),
+            (2, false) => format!("movzwq {}, {reg}", sized(reg, 2)),
+            (4, true) => format!("movslq {}, {reg}", sized(reg, 4)),
+            (4, false) => format!("movl {0}, {0}", sized(reg, 4)),
+            _ => return,
+        };
+        self.emit(&instruction);
+    }
+
+    // Stores a scalar from a 64-bit register, which may change the register
+    fn store(&mut self, reg: &str, dest: &Value, ty: &Type) {
+        match dest {
+            Value::Reg(dest) => {
+                self.normalize(reg, ty);
+                if *dest != reg {
+                    self.emit(&format!("movq {reg}, {dest}"));
+                }
+            }
+            Value::Mem(mem) => {
+                let size = self.program.size(ty);
+                self.emit(&format!(
+                    "mov{} {}, {}",
+                    suffix(size),
+                    sized(reg, size),
+                    mem.operand()
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    // Copies a value of `ty`; this takes r11, and rcx, rsi and rdi for long copies
+    fn copy(&mut self, source: &Value, dest: &Value, ty: &Type) {
+        let size = self.program.size(ty);
+        if size == 0 {
+            return;
+        }
+        if is_scalar(ty) {
+            match dest {
+                Value::Reg(reg) => self.load(source, ty, reg),
+                _ => {
+                    self.load(source, ty, "%r11");
+                    self.store("%r11", dest, ty);
+                }
+            }
+            return;
+        }
+        self.copy_memory(&memory(dest), &memory(source), size);
+    }
+
+    fn copy_memory(&mut self, dest: &Mem, source: &Mem, size: u64) {
+        if size > INLINE_COPY_LIMIT {
+            self.emit(&format!("leaq {}, %r11", dest.operand()));
+            self.emit(&format!("leaq {}, %rsi", source.operand()));
+            self.emit("movq %r11, %rdi");
+            self.emit(&format!("movq ${size}, %rcx"));
+            self.emit("rep movsb");
+            return;
+        }
+        let mut offset = 0;
+        for chunk in [8, 4, 2, 1] {
+            while size - offset >= chunk {
+                let reg = sized("%r11", chunk);
+                let suffix = suffix(chunk);
+                self.emit(&format!(
+                    "mov{suffix} {}, {reg}",
+                    source.at(offset).operand()
+                ));
+                self.emit(&format!("mov{suffix} {reg}, {}", dest.at(offset).operand()));
+                offset += chunk;
+            }
+        }
+    }
+
+    // Sets a value to zero; this takes rax, rcx and rdi for long values
+    fn zero(&mut self, dest: &Value, ty: &Type) {
+        match dest {
+            Value::Reg(reg) => {
+                let reg = sized(reg, 4);
+                self.emit(&format!("xorl {reg}, {reg}"));
+            }
+            Value::Mem(mem) => {
+                let size = self.program.size(ty);
+                self.zero_memory(mem, size);
+            }
+            _ => {}
+        }
+    }
+
+    fn zero_memory(&mut self, dest: &Mem, size: u64) {
+        if size > INLINE_COPY_LIMIT {
+            self.emit(&format!("leaq {}, %rdi", dest.operand()));
+            self.emit(&format!("movq ${size}, %rcx"));
+            self.emit("xorl %eax, %eax");
+            self.emit("rep stosb");
+            return;
+        }
+        let mut offset = 0;
+        for chunk in [8, 4, 2, 1] {
+            while size - offset >= chunk {
+                self.emit(&format!(
+                    "mov{} $0, {}",
+                    suffix(chunk),
+                    dest.at(offset).operand()
+                ));
+                offset += chunk;
+            }
+        }
+    }
+
+    // The memory a pointer points to, loading the pointer into `scratch`
+    // unless it is already in a register
+    fn deref(&mut self, pointer: &Value, scratch: &'static str) -> Mem {
+        match pointer {
+            Value::Reg(reg) => Mem::Base(reg, 0),
+            pointer => {
+                self.load(pointer, &Type::U64, scratch);
+                Mem::Base(scratch, 0)
+            }
+        }
+    }
+
+    // The address of a value, in rdi; one in a register is copied to the
+    // frame first
+    fn address_of(&mut self, value: &Value, ty: &Type) {
+        let mem = match value {
+            Value::Mem(mem) => mem.clone(),
+            value => {
+                let slot = self.slot(self.program.size(ty));
+                self.copy(value, &Value::Mem(slot.clone()), ty);
+                slot
+            }
+        };
+        self.emit(&format!("leaq {}, %rdi", mem.operand()));
+    }
+
+    // Calls a function the way the System V calling convention says,
+    // storing its result in `dest` if there is one
+    fn call(
+        &mut self,
+        target: Target,
+        args: &[(Value, Type)],
+        return_type: &Type,
+        dest: Option<Value>,
+    ) {
+        let hidden_result = self.program.returns_in_memory(return_type);
+        let types: Vec<Type> = args.iter().map(|(_, ty)| ty.clone()).collect();
+        let (passing, stack) = self.program.classify(&types, hidden_result);
+        let stack = stack.next_multiple_of(16);
+        if stack > 0 {
+            self.emit(&format!("subq ${stack}, %rsp"));
+        }
+        for ((value, ty), passing) in args.iter().zip(&passing) {
+            if let Passing::Stack(offset) = passing {
+                self.copy(value, &Value::Mem(Mem::Base("%rsp", *offset as i64)), ty);
+            }
+        }
+        for ((value, ty), passing) in args.iter().zip(&passing) {
+            let Passing::Registers(first, words) = *passing else {
+                continue;
+            };
+            if is_scalar(ty) {
+                self.load(value, ty, ARGUMENT_REGISTERS[first]);
+            } else {
+                let mem = memory(value);
+                for word in 0..words {
+                    self.emit(&format!(
+                        "movq {}, {}",
+                        mem.at(8 * word as u64).operand(),
+                        ARGUMENT_REGISTERS[first + word]
+                    ));
+                }
+            }
+        }
+        if hidden_result {
+            let result = match &dest {
+                Some(Value::Mem(mem)) => mem.clone(),
+                _ => self.slot(self.program.size(return_type)),
+            };
+            self.emit(&format!("leaq {}, %rdi", result.operand()));
+        }
+        match target {
+            Target::Label(label) => self.emit(&format!("call {label}")),
+            Target::Pointer(mem) => {
+                self.emit(&format!("movq {}, %r11", mem.operand()));
+                self.emit("call *%r11");
+            }
+            Target::Vtable(mem, index) => {
+                self.emit(&format!("movq {}, %r11", mem.operand()));
+                self.emit(&format!("movq {}(%r11), %r11", 8 * index));
+                self.emit("call *%r11");
+            }
+        }
+        if stack > 0 {
+            self.emit(&format!("addq ${stack}, %rsp"));
+        }
+        let Some(dest) = dest else {
+            return;
+        };
+        if hidden_result || self.program.size(return_type) == 0 {
+            return;
+        }
+        if is_scalar(return_type) {
+            self.store("%rax", &dest, return_type);
+            return;
+        }
+        // The frame has room for whole words
+        let dest = memory(&dest);
+        self.emit(&format!("movq %rax, {}", dest.operand()));
+        if self.program.size(return_type) > 8 {
+            self.emit(&format!("movq %rdx, {}", dest.at(8).operand()));
+        }
+    }
+
+    // Calls a function of the runtime
+    fn runtime(
+        &mut self,
+        function: &str,
+        args: &[(Value, Type)],
+        return_type: &Type,
+        dest: Option<Reg>,
+    ) {
+        let dest = dest.map(|dest| self.homes[dest].clone());
+        self.call(Target::Label(function.to_string()), args, return_type, dest);
+    }
+
+    fn panic(&mut self, span: Span, message: &str) {
+        let location = self.program.location(span);
+        let message = self.program.string(message);
+        self.runtime(
+            "nrt_panic",
+            &[
+                (Value::Address(location), Type::U64),
+                (Value::Mem(Mem::Label(message, 0)), Type::Str),
+            ],
+            &Type::Void,
+            None,
+        );
+    }
+
+    fn location(&mut self, span: Span) -> Value {
+        Value::Address(self.program.location(span))
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) -> Result<(), String> {
+        match instruction {
+            Instruction::Copy { dest, value } => {
+                let value = self.operand(value)?;
+                let ty = self.reg_type(*dest);
+                let dest = self.homes[*dest].clone();
+                self.copy(&value, &dest, &ty);
+            }
+            Instruction::Zero { dest } => {
+                let ty = self.reg_type(*dest);
+                let dest = self.homes[*dest].clone();
+                self.zero(&dest, &ty);
+            }
+            Instruction::Binary {
+                dest,
+                op,
+                lhs,
+                rhs,
+                span,
+            } => {
+                let ty = self.operand_type(lhs);
+                if !is_scalar(&ty) {
+                    return Err(format!("Cannot apply {op:?} to a value of type {ty}"));
+                }
+                let lhs = self.operand(lhs)?;
+                let rhs = self.operand(rhs)?;
+                self.load(&lhs, &ty, "%rax");
+                self.load(&rhs, &ty, "%rcx");
+                let dest_type = self.reg_type(*dest);
+                let condition = match op {
+                    BinaryOp::Equal => Some("e"),
+                    BinaryOp::NotEqual => Some("ne"),
+                    BinaryOp::Less if ty.is_signed() => Some("l"),
+                    BinaryOp::LessEqual if ty.is_signed() => Some("le"),
+                    BinaryOp::Greater if ty.is_signed() => Some("g"),
+                    BinaryOp::GreaterEqual if ty.is_signed() => Some("ge"),
+                    BinaryOp::Less => Some("b"),
+                    BinaryOp::LessEqual => Some("be"),
+                    BinaryOp::Greater => Some("a"),
+                    BinaryOp::GreaterEqual => Some("ae"),
+                    _ => None,
+                };
+                if let Some(condition) = condition {
+                    self.emit("cmpq %rcx, %rax");
+                    self.emit(&format!("set{condition} %al"));
+                    self.emit("movzbq %al, %rax");
+                } else if *op == BinaryOp::And {
+                    self.emit("andq %rcx, %rax");
+                } else if *op == BinaryOp::Or {
+                    self.emit("orq %rcx, %rax");
+                } else {
+                    // Division by zero panics even when overflow wraps around
+                    let overflow =
+                        if self.program.debug || matches!(op, BinaryOp::Div | BinaryOp::Rem) {
+                            Overflow::Panic
+                        } else {
+                            Overflow::Wrap
+                        };
+                    self.arithmetic(*op, &ty, overflow, *span)?;
+                }
+                let dest = self.homes[*dest].clone();
+                self.store("%rax", &dest, &dest_type);
+            }
+            Instruction::Unary {
+                dest,
+                op,
+                operand,
+                span,
+            } => {
+                let ty = self.reg_type(*dest);
+                let operand_type = self.operand_type(operand);
+                let operand = self.operand(operand)?;
+                self.load(&operand, &operand_type, "%rax");
+                match op {
+                    UnaryOp::Not => {
+                        self.emit("testq %rax, %rax");
+                        self.emit("sete %al");
+                        self.emit("movzbq %al, %rax");
+                    }
+                    UnaryOp::Neg => {
+                        if self.program.debug {
+                            let (min, _) = ty
+                                .integer_range()
+                                .ok_or_else(|| format!("Cannot negate a value of type {ty}"))?;
+                            let ok = self.new_label();
+                            self.load(&Value::Imm(min as i64), &ty, "%rdx");
+                            self.emit("cmpq %rdx, %rax");
+                            self.emit(&format!("jne {ok}"));
+                            self.panic(*span, "attempt to negate with overflow");
+                            self.place(&ok);
+                        }
+                        self.emit("negq %rax");
+                    }
+                }
+                let dest = self.homes[*dest].clone();
+                self.store("%rax", &dest, &ty);
+            }
+            Instruction::Cast { dest, value } => {
+                let ty = self.reg_type(*dest);
+                let value_type = self.operand_type(value);
+                let value = self.operand(value)?;
+                self.load(&value, &value_type, "%rax");
+                if ty == Type::Bool && value_type != Type::Bool {
+                    self.emit("testq %rax, %rax");
+                    self.emit("setne %al");
+                }
+                let dest = self.homes[*dest].clone();
+                self.store("%rax", &dest, &ty);
+            }
+            Instruction::Call {
+                dest,
+                function,
+                args,
+            } => {
+                let return_type = self
+                    .program
+                    .returns
+                    .get(function)
+                    .cloned()
+                    .ok_or_else(|| format!("Call to unknown function {function}"))?;
+                let args = self.arguments(args)?;
+                let dest = dest.map(|dest| self.homes[dest].clone());
+                self.call(
+                    Target::Label(format!("fn_{function}")),
+                    &args,
+                    &return_type,
+                    dest,
+                );
+            }
+            Instruction::CallValue { dest, callee, args } => {
+                let Type::Fn(_, return_type) = self.operand_type(callee) else {
+                    return Err(format!(
+                        "Cannot call a value of type {}",
+                        self.operand_type(callee)
+                    ));
+                };
+                let callee = memory(&self.operand(callee)?);
+                let args = self.arguments(args)?;
+                let dest = dest.map(|dest| self.homes[dest].clone());
+                // A closure with an environment takes it before its arguments
+                let plain = self.new_label();
+                let done = self.new_label();
+                self.emit(&format!("cmpq $0, {}", callee.at(8).operand()));
+                self.emit(&format!("je {plain}"));
+                let with_env: Vec<(Value, Type)> =
+                    std::iter::once((Value::Mem(callee.at(16)), Type::U64))
+                        .chain(args.iter().cloned())
+                        .collect();
+                self.call(
+                    Target::Pointer(callee.at(8)),
+                    &with_env,
+                    &return_type,
+                    dest.clone(),
+                );
+                self.emit(&format!("jmp {done}"));
+                self.place(&plain);
+                self.call(Target::Pointer(callee.clone()), &args, &return_type, dest);
+                self.place(&done);
+            }
+            Instruction::CallDyn {
+                dest,
+                trait_name,
+                method,
+                args,
+            } => {
+                let methods = self
+                    .program
+                    .traits
+                    .get(trait_name)
+                    .cloned()
+                    .unwrap_or_default();
+                let Some(index) = methods.iter().position(|m| m.name == *method) else {
+                    return Err(format!("dyn {trait_name} has no method {method}"));
+                };
+                let Some((receiver, args)) = args.split_first() else {
+                    return Err(format!("Call to {method} has no receiver"));
+                };
+                let receiver = memory(&self.operand(receiver)?);
+                let args: Vec<(Value, Type)> =
+                    std::iter::once((Value::Mem(receiver.clone()), Type::U64))
+                        .chain(self.arguments(args)?)
+                        .collect();
+                let dest = dest.map(|dest| self.homes[dest].clone());
+                self.call(
+                    Target::Vtable(receiver.at(8), index),
+                    &args,
+                    &methods[index].return_type,
+                    dest,
+                );
+            }
+            Instruction::Intrinsic {
+                dest,
+                intrinsic,
+                args,
+                span,
+            } => self.intrinsic(*dest, *intrinsic, args, *span)?,
+            Instruction::Print {
+                function,
+                pieces,
+                args,
+            } => self.print(*function, pieces, args)?,
+            Instruction::ToDyn { dest, value } => {
+                let Type::Dyn(trait_name) = self.reg_type(*dest) else {
+                    return Err(format!(
+                        "Cannot convert a value to {}",
+                        self.reg_type(*dest)
+                    ));
+                };
+                let ty = self.operand_type(value);
+                let value = self.operand(value)?;
+                let size = self.program.size(&ty);
+                self.runtime(
+                    "nrt_alloc",
+                    &[(Value::Imm(size as i64), Type::U64)],
+                    &Type::U64,
+                    None,
+                );
+                let dest = memory(&self.homes[*dest]);
+                self.emit(&format!("movq %rax, {}", dest.operand()));
+                self.emit(&format!(
+                    "leaq {}(%rip), %rcx",
+                    vtable_label(&trait_name, &ty)
+                ));
+                self.emit(&format!("movq %rcx, {}", dest.at(8).operand()));
+                self.emit("movq %rax, %rdi");
+                self.copy(&value, &Value::Mem(Mem::Base("%rdi", 0)), &ty);
+            }
+            Instruction::MakeFn {
+                dest,
+                function,
+                env,
+            } => {
+                let dest = memory(&self.homes[*dest]);
+                self.emit(&format!("leaq fn_{function}(%rip), %rax"));
+                match env {
+                    Some(env) => {
+                        let ty = self.operand_type(env);
+                        let env = self.operand(env)?;
+                        self.emit(&format!("movq $0, {}", dest.operand()));
+                        self.emit(&format!("movq %rax, {}", dest.at(8).operand()));
+                        self.copy(&env, &Value::Mem(dest.at(16)), &ty);
+                    }
+                    None => {
+                        self.emit(&format!("movq %rax, {}", dest.operand()));
+                        self.emit(&format!("movq $0, {}", dest.at(8).operand()));
+                        self.emit(&format!("movq $0, {}", dest.at(16).operand()));
+                    }
+                }
+            }
+            Instruction::Struct { dest, fields } => {
+                let ty = self.reg_type(*dest);
+                let home = self.homes[*dest].clone();
+                self.zero(&home, &ty);
+                let dest = memory(&home);
+                for (field, value) in fields {
+                    let (offset, field_type) = self.program.field(&ty, field)?;
+                    let value = self.operand(value)?;
+                    self.copy(&value, &Value::Mem(dest.at(offset)), &field_type);
+                }
+            }
+            Instruction::Array { dest, elements } => {
+                let Type::Array(element, _) = self.reg_type(*dest) else {
+                    return Err(format!(
+                        "Cannot build an array of type {}",
+                        self.reg_type(*dest)
+                    ));
+                };
+                let size = self.program.size(&element);
+                let dest = memory(&self.homes[*dest]);
+                for (i, value) in elements.iter().enumerate() {
+                    let value = self.operand(value)?;
+                    self.copy(&value, &Value::Mem(dest.at(i as u64 * size)), &element);
+                }
+            }
+            Instruction::ArrayRepeat { dest, value } => {
+                let Type::Array(element, Length::Known(len)) = self.reg_type(*dest) else {
+                    return Err(format!(
+                        "Cannot build an array of type {}",
+                        self.reg_type(*dest)
+                    ));
+                };
+                if len > 0 {
+                    let size = self.program.size(&element);
+                    let value = self.operand(value)?;
+                    let dest = memory(&self.homes[*dest]);
+                    let top = self.new_label();
+                    self.emit(&format!("leaq {}, %r9", dest.operand()));
+                    self.emit(&format!("movq ${len}, %r10"));
+                    self.place(&top);
+                    self.copy(&value, &Value::Mem(Mem::Base("%r9", 0)), &element);
+                    self.emit(&format!("addq ${size}, %r9"));
+                    self.emit("decq %r10");
+                    self.emit(&format!("jnz {top}"));
+                }
+            }
+            Instruction::Field { dest, value, field } => {
+                let ty = self.operand_type(value);
+                let (offset, field_type) = self.program.field(&ty, field)?;
+                let value = memory(&self.operand(value)?);
+                let dest = self.homes[*dest].clone();
+                self.copy(&Value::Mem(value.at(offset)), &dest, &field_type);
+            }
+            Instruction::Index {
+                dest,
+                array,
+                index,
+                span,
+            } => {
+                let ty = self.reg_type(*dest);
+                let element = self.element_address(array, index, *span)?;
+                let dest = self.homes[*dest].clone();
+                self.copy(&Value::Mem(element), &dest, &ty);
+            }
+            Instruction::AddressOf { dest, reg } => {
+                let home = memory(&self.homes[*reg]);
+                self.emit(&format!("leaq {}, %rax", home.operand()));
+                let dest = self.homes[*dest].clone();
+                self.store("%rax", &dest, &Type::U64);
+            }
+            Instruction::FieldAddress { dest, base, field } => {
+                let ty = pointee(&self.operand_type(base));
+                let (offset, _) = self.program.field(&ty, field)?;
+                let base = self.operand(base)?;
+                self.load(&base, &Type::U64, "%rax");
+                if offset > 0 {
+                    self.emit(&format!("addq ${offset}, %rax"));
+                }
+                let dest = self.homes[*dest].clone();
+                self.store("%rax", &dest, &Type::U64);
+            }
+            Instruction::IndexAddress {
+                dest,
+                base,
+                index,
+                span,
+            } => {
+                let element = self.element_address(base, index, *span)?;
+                self.emit(&format!("leaq {}, %rax", element.operand()));
+                let dest = self.homes[*dest].clone();
+                self.store("%rax", &dest, &Type::U64);
+            }
+            Instruction::Load { dest, address } => {
+                let ty = self.reg_type(*dest);
+                let address = self.operand(address)?;
+                let source = self.deref(&address, "%rsi");
+                let dest = self.homes[*dest].clone();
+                self.copy(&Value::Mem(source), &dest, &ty);
+            }
+            Instruction::Store { address, value } => {
+                let ty = self.operand_type(value);
+                let address = self.operand(address)?;
+                let value = self.operand(value)?;
+                let dest = self.deref(&address, "%rdi");
+                self.copy(&value, &Value::Mem(dest), &ty);
+            }
+            Instruction::Alloc { dest } => {
+                let size = self.program.size(&pointee(&self.reg_type(*dest)));
+                self.runtime(
+                    "nrt_alloc",
+                    &[(Value::Imm(size as i64), Type::U64)],
+                    &Type::U64,
+                    Some(*dest),
+                );
+            }
+            Instruction::Move { dest, address } => {
+                let ty = self.reg_type(*dest);
+                let address = self.operand(address)?;
+                let source = self.deref(&address, "%rsi");
+                let dest = self.homes[*dest].clone();
+                self.copy(&Value::Mem(source), &dest, &ty);
+                let place = self.deref(&address, "%rdi");
+                let size = self.program.size(&ty);
+                self.zero_memory(&place, size);
+            }
+            Instruction::Drop { address } => {
+                let ty = pointee(&self.operand_type(address));
+                let address = self.operand(address)?;
+                self.runtime(
+                    &format!("dr_{}", generator::mangle(&ty)),
+                    &[(address, Type::U64)],
+                    &Type::Void,
+                    None,
+                );
+            }
+            Instruction::NextChar { dest, text, pos } => {
+                let text = self.operand(text)?;
+                let pos = self.operand(pos)?;
+                self.runtime(
+                    "nrt_str_next_char",
+                    &[(text, Type::Str), (pos, Type::U64)],
+                    &Type::Char,
+                    Some(*dest),
+                );
+            }
+            Instruction::Phi { .. } => {
+                return Err("Phis must be removed before code generation".to_string())
+            }
+        }
+        Ok(())
+    }
+
+    fn arguments(&mut self, args: &[Operand]) -> Result<Vec<(Value, Type)>, String> {
+        args.iter()
+            .map(|arg| Ok((self.operand(arg)?, self.operand_type(arg))))
+            .collect()
+    }
+
+    // Where an element of an array, or of the array or buffer a pointer is
+    // to, is, after checking the index against the length. The address is
+    // in rax.
+    fn element_address(
+        &mut self,
+        base: &Operand,
+        index: &Operand,
+        span: Span,
+    ) -> Result<Mem, String> {
+        let ty = self.operand_type(base);
+        let base = self.operand(base)?;
+        let index_type = self.operand_type(index);
+        let index = self.operand(index)?;
+        let location = self.location(span);
+        let (element, len, by_pointer) = match &ty {
+            Type::Buffer(element) => {
+                let size = self.program.size(element);
+                self.runtime(
+                    "nrt_buffer_at",
+                    &[
+                        (base, ty.clone()),
+                        (index, index_type),
+                        (Value::Imm(size as i64), Type::U64),
+                        (location, Type::U64),
+                    ],
+                    &Type::U64,
+                    None,
+                );
+                return Ok(Mem::Base("%rax", 0));
+            }
+            Type::Array(element, Length::Known(len)) => ((**element).clone(), *len, false),
+            Type::Ref(inner, _) => match &**inner {
+                Type::Array(element, Length::Known(len)) => ((**element).clone(), *len, true),
+                _ => return Err(format!("Cannot index into a value of type {ty}")),
+            },
+            _ => return Err(format!("Cannot index into a value of type {ty}")),
+        };
+        self.runtime(
+            "nrt_check_index",
+            &[
+                (index, index_type),
+                (Value::Imm(len as i64), Type::U64),
+                (location, Type::U64),
+            ],
+            &Type::U64,
+            None,
+        );
+        if by_pointer {
+            self.load(&base, &Type::U64, "%rcx");
+        } else {
+            self.emit(&format!("leaq {}, %rcx", memory(&base).operand()));
+        }
+        self.emit(&format!(
+            "imulq ${}, %rax, %rax",
+            self.program.size(&element)
+        ));
+        self.emit("addq %rcx, %rax");
+        Ok(Mem::Base("%rax", 0))
+    }
+
+    fn terminator(&mut self, terminator: &Terminator, id: BlockId) -> Result<(), String> {
+        match terminator {
+            Terminator::Jump(target) => self.jump(*target, id),
+            Terminator::Branch {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                let condition = self.operand(condition)?;
+                self.load(&condition, &Type::Bool, "%rax");
+                self.emit("testq %rax, %rax");
+                let then_label = self.block_label(*then_block);
+                self.emit(&format!("jnz {then_label}"));
+                self.jump(*else_block, id);
+            }
+            Terminator::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                let ty = self.operand_type(value);
+                let value = self.operand(value)?;
+                self.load(&value, &ty, "%rax");
+                for (literal, target) in cases {
+                    let case = self.operand(&Operand::Const(literal.clone(), ty.clone()))?;
+                    self.load(&case, &ty, "%rcx");
+                    self.emit("cmpq %rcx, %rax");
+                    let label = self.block_label(*target);
+                    self.emit(&format!("je {label}"));
+                }
+                self.jump(*default, id);
+            }
+            Terminator::Return(value) => {
+                let return_type = self.function.return_type.clone();
+                if let Some(value) = value {
+                    let value = self.operand(value)?;
+                    if let Some(result) = self.result_address.clone() {
+                        self.emit(&format!("movq {}, %rax", result.operand()));
+                        self.copy(&value, &Value::Mem(Mem::Base("%rax", 0)), &return_type);
+                    } else if is_scalar(&return_type) {
+                        self.load(&value, &return_type, "%rax");
+                    } else if self.program.size(&return_type) > 0 {
+                        let value = memory(&value);
+                        self.emit(&format!("movq {}, %rax", value.operand()));
+                        if self.program.size(&return_type) > 8 {
+                            self.emit(&format!("movq {}, %rdx", value.at(8).operand()));
+                        }
+                    }
+                }
+                if let Some(record) = self.frame_record.clone() {
+                    self.emit(&format!("movq {}, %rcx", record.at(8).operand()));
+                    self.emit("movq %rcx, nrt_frame_top(%rip)");
+                }
+                self.emit(&format!("jmp {}ret", self.prefix));
+            }
+            Terminator::Unreachable => self.emit("ud2"),
+        }
+        Ok(())
+    }
+
+    // Jumps to a block, unless it comes next
+    fn jump(&mut self, target: BlockId, from: BlockId) {
+        if target != from + 1 {
+            let label = self.block_label(target);
+            self.emit(&format!("jmp {label}"));
+        }
+    }
+
+    // Computes rax op rcx into rax for integers of `ty`, both sign or zero
+    // extended to 64 bits; this takes rdx and r8 too
+    fn arithmetic(
+        &mut self,
+        op: BinaryOp,
+        ty: &Type,
+        overflow: Overflow,
+        span: Span,
+    ) -> Result<(), String> {
+        let (min, max) = ty
+            .integer_range()
+            .ok_or_else(|| format!("Cannot apply {op:?} to a value of type {ty}"))?;
+        let (min, max) = (min as i64, max as i64);
+        let signed = ty.is_signed();
+        let wide = self.program.size(ty) == 8;
+        if matches!(op, BinaryOp::Div | BinaryOp::Rem) {
+            let divide = op == BinaryOp::Div;
+            let nonzero = self.new_label();
+            self.emit("testq %rcx, %rcx");
+            self.emit(&format!("jnz {nonzero}"));
+            self.panic(
+                span,
+                if divide {
+                    "attempt to divide by zero"
+                } else {
+                    "attempt to calculate the remainder with a divisor of zero"
+                },
+            );
+            self.place(&nonzero);
+            if signed {
+                let fits = self.new_label();
+                self.emit("cmpq $-1, %rcx");
+                self.emit(&format!("jne {fits}"));
+                self.load(&Value::Imm(min), ty, "%rdx");
+                self.emit("cmpq %rdx, %rax");
+                self.emit(&format!("jne {fits}"));
+                self.panic(
+                    span,
+                    if divide {
+                        "attempt to divide with overflow"
+                    } else {
+                        "attempt to calculate the remainder with overflow"
+                    },
+                );
+                self.place(&fits);
+                self.emit("cqto");
+                self.emit("idivq %rcx");
+            } else {
+                self.emit("xorl %edx, %edx");
+                self.emit("divq %rcx");
+            }
+            if !divide {
+                self.emit("movq %rdx, %rax");
+            }
+            return Ok(());
+        }
+        let (instruction, message) = match op {
+            BinaryOp::Add => ("addq %rcx, %rax", "attempt to add with overflow"),
+            BinaryOp::Sub => ("subq %rcx, %rax", "attempt to subtract with overflow"),
+            BinaryOp::Mul if wide && !signed => ("mulq %rcx", "attempt to multiply with overflow"),
+            BinaryOp::Mul => ("imulq %rcx, %rax", "attempt to multiply with overflow"),
+            op => return Err(format!("Cannot apply {op:?} to a value of type {ty}")),
+        };
+        if overflow == Overflow::Wrap {
+            self.emit(instruction);
+            return Ok(());
+        }
+        self.emit("movq %rax, %r8");
+        self.emit(instruction);
+        let overflowed = self.new_label();
+        let done = self.new_label();
+        if wide {
+            let carry = !signed && op != BinaryOp::Mul;
+            self.emit(&format!("j{} {overflowed}", if carry { "c" } else { "o" }));
+        } else {
+            // The result is exact in 64 bits, so it overflowed if it changes
+            // when narrowed
+            self.emit("movq %rax, %rdx");
+            self.normalize("%rdx", ty);
+            self.emit("cmpq %rdx, %rax");
+            self.emit(&format!("jne {overflowed}"));
+        }
+        self.emit(&format!("jmp {done}"));
+        self.place(&overflowed);
+        match overflow {
+            Overflow::Panic => self.panic(span, message),
+            _ => {
+                // Overflow only goes past max when adding a positive number,
+                // and so on
+                self.load(&Value::Imm(max), ty, "%rax");
+                self.load(&Value::Imm(min), ty, "%rdx");
+                match op {
+                    BinaryOp::Mul => {
+                        self.emit("testq %r8, %r8");
+                        self.emit(&format!("set{} %r8b", if signed { "g" } else { "ne" }));
+                        self.emit("testq %rcx, %rcx");
+                        self.emit(&format!("set{} %cl", if signed { "g" } else { "ne" }));
+                        self.emit("cmpb %cl, %r8b");
+                        self.emit("cmovneq %rdx, %rax");
+                    }
+                    _ => {
+                        // Past min unless adding a positive number, or
+                        // subtracting one that is not positive
+                        let take_min = match (op == BinaryOp::Add, signed) {
+                            (true, true) => "le",
+                            (true, false) => "e",
+                            (false, true) => "g",
+                            (false, false) => "ne",
+                        };
+                        self.emit("testq %rcx, %rcx");
+                        self.emit(&format!("cmov{take_min}q %rdx, %rax"));
+                    }
+                }
+            }
+        }
+        self.place(&done);
+        Ok(())
+    }
+
+    // Sets %rax to whether integer arithmetic on %rax and %rcx overflows or
+    // divides by zero
+    fn overflows(&mut self, op: BinaryOp, ty: &Type) -> Result<(), String> {
+        let (min, _) = ty
+            .integer_range()
+            .ok_or_else(|| format!("Cannot apply {op:?} to a value of type {ty}"))?;
+        let signed = ty.is_signed();
+        let wide = self.program.size(ty) == 8;
+        if matches!(op, BinaryOp::Div | BinaryOp::Rem) {
+            self.emit("testq %rcx, %rcx");
+            self.emit("sete %r8b");
+            if signed {
+                self.load(&Value::Imm(min as i64), ty, "%rdx");
+                self.emit("cmpq %rdx, %rax");
+                self.emit("sete %dl");
+                self.emit("cmpq $-1, %rcx");
+                self.emit("sete %cl");
+                self.emit("andb %cl, %dl");
+                self.emit("orb %dl, %r8b");
+            }
+            self.emit("movzbq %r8b, %rax");
+            return Ok(());
+        }
+        let instruction = match op {
+            BinaryOp::Add => "addq %rcx, %rax",
+            BinaryOp::Sub => "subq %rcx, %rax",
+            BinaryOp::Mul if wide && !signed => "mulq %rcx",
+            BinaryOp::Mul => "imulq %rcx, %rax",
+            op => return Err(format!("Cannot apply {op:?} to a value of type {ty}")),
+        };
+        self.emit(instruction);
+        if wide {
+            let carry = !signed && op != BinaryOp::Mul;
+            self.emit(&format!("set{} %al", if carry { "c" } else { "o" }));
+        } else {
+            // The result is exact in 64 bits, so it overflowed if it changes
+            // when narrowed
+            self.emit("movq %rax, %rdx");
+            self.normalize("%rdx", ty);
+            self.emit("cmpq %rdx, %rax");
+            self.emit("setne %al");
+        }
+        self.emit("movzbq %al, %rax");
+        Ok(())
+    }
+
+    fn intrinsic(
+        &mut self,
+        dest: Option<Reg>,
+        intrinsic: Intrinsic,
+        args: &[Operand],
+        span: Span,
+    ) -> Result<(), String> {
+        let return_type = dest.map_or(Type::Void, |dest| self.reg_type(dest));
+        let arg_types: Vec<Type> = args.iter().map(|arg| self.operand_type(arg)).collect();
+        let mut values = self.arguments(args)?;
+        let home = dest.map(|dest| self.homes[dest].clone());
+        let function = match intrinsic {
+            Intrinsic::Exit => "nrt_exit",
+            Intrinsic::Panic => {
+                let location = self.location(span);
+                values.insert(0, (location, Type::U64));
+                "nrt_panic"
+            }
+            Intrinsic::StringNew | Intrinsic::BufferNew => {
+                if let Some(home) = home {
+                    self.zero(&home, &return_type);
+                }
+                return Ok(());
+            }
+            Intrinsic::StringFrom => "nrt_string_from",
+            Intrinsic::StringPushStr => "nrt_string_push_str",
+            Intrinsic::StringPushChar => "nrt_string_push_char",
+            Intrinsic::StringAsStr | Intrinsic::StrLen => {
+                // A String starts with the str it holds, which starts with
+                // its pointer
+                let (offset, ty) = if intrinsic == Intrinsic::StrLen {
+                    (8, Type::U64)
+                } else {
+                    (0, Type::Str)
+                };
+                if let Some(home) = home {
+                    self.copy(&Value::Mem(memory(&values[0].0).at(offset)), &home, &ty);
+                }
+                return Ok(());
+            }
+            Intrinsic::StrSlice | Intrinsic::StrParseI64 => {
+                let location = self.location(span);
+                values.push((location, Type::U64));
+                if intrinsic == Intrinsic::StrSlice {
+                    "nrt_str_slice"
+                } else {
+                    "nrt_str_parse_i64"
+                }
+            }
+            Intrinsic::StrConcat => "nrt_str_concat",
+            Intrinsic::StrEqual => "nrt_str_equal",
+            Intrinsic::StrCompare => "nrt_str_compare",
+            Intrinsic::IntToString if arg_types[0].is_signed() => "nrt_i64_to_string",
+            Intrinsic::IntToString => "nrt_u64_to_string",
+            Intrinsic::CharToString => "nrt_char_to_string",
+            Intrinsic::BufferLen => "nrt_buffer_len",
+            Intrinsic::Hash => match &arg_types[0] {
+                Type::Str => "nrt_hash_str",
+                Type::String => {
+                    values[0] = (Value::Mem(memory(&values[0].0)), Type::Str);
+                    "nrt_hash_str"
+                }
+                ty => {
+                    values[0].1 = ty.clone();
+                    "nrt_hash_u64"
+                }
+            },
+            Intrinsic::ArrayLen => {
+                return Err("The length of an array is known before code generation".to_string())
+            }
+            Intrinsic::BoxNew => {
+                let size = self.program.size(&arg_types[0]);
+                self.runtime(
+                    "nrt_alloc",
+                    &[(Value::Imm(size as i64), Type::U64)],
+                    &Type::U64,
+                    None,
+                );
+                if let Some(home) = home {
+                    self.store("%rax", &home, &Type::U64);
+                }
+                self.copy(
+                    &values[0].0,
+                    &Value::Mem(Mem::Base("%rax", 0)),
+                    &arg_types[0],
+                );
+                return Ok(());
+            }
+            Intrinsic::BufferPush => {
+                let element = buffer_element(&arg_types[0])?;
+                let size = self.program.size(&element);
+                let place = self.deref(&values[0].0, "%rsi");
+                self.runtime(
+                    "nrt_buffer_push",
+                    &[
+                        (Value::Mem(place), Type::U64),
+                        (Value::Imm(size as i64), Type::U64),
+                    ],
+                    &Type::U64,
+                    None,
+                );
+                let place = self.deref(&values[0].0, "%rdi");
+                self.emit(&format!("movq %rax, {}", place.operand()));
+                self.emit("movq -16(%rax), %rdx");
+                self.emit(&format!("imulq ${size}, %rdx, %rdx"));
+                self.emit(&format!("leaq -{size}(%rax,%rdx), %rdx"));
+                self.copy(&values[1].0, &Value::Mem(Mem::Base("%rdx", 0)), &element);
+                return Ok(());
+            }
+            Intrinsic::BufferPop => {
+                let element = buffer_element(&arg_types[0])?;
+                let size = self.program.size(&element);
+                let location = self.location(span);
+                let place = self.deref(&values[0].0, "%rsi");
+                self.runtime(
+                    "nrt_buffer_pop",
+                    &[(Value::Mem(place), Type::U64), (location, Type::U64)],
+                    &Type::U64,
+                    None,
+                );
+                let place = self.deref(&values[0].0, "%rdi");
+                self.emit(&format!("movq {}, %rcx", place.operand()));
+                self.emit(&format!("imulq ${size}, %rax, %rax"));
+                self.emit("addq %rcx, %rax");
+                if let Some(home) = home {
+                    self.copy(&Value::Mem(Mem::Base("%rax", 0)), &home, &element);
+                }
+                return Ok(());
+            }
+            Intrinsic::Checked(_) => {
+                return Err("Checked arithmetic is lowered before code generation".to_string())
+            }
+            Intrinsic::Overflows(op) | Intrinsic::Wrapping(op) | Intrinsic::Saturating(op) => {
+                self.load(&values[0].0, &arg_types[0], "%rax");
+                self.load(&values[1].0, &arg_types[1], "%rcx");
+                match intrinsic {
+                    Intrinsic::Overflows(_) => self.overflows(op, &arg_types[0])?,
+                    Intrinsic::Wrapping(_) => {
+                        self.arithmetic(op, &arg_types[0], Overflow::Wrap, span)?
+                    }
+                    _ => self.arithmetic(op, &arg_types[0], Overflow::Saturate, span)?,
+                }
+                if let Some(home) = home {
+                    self.store("%rax", &home, &return_type);
+                }
+                return Ok(());
+            }
+            Intrinsic::Unwrap | Intrinsic::UnwrapOr => {
+                return self.unwrap(intrinsic, &values, &arg_types, home, span)
+            }
+        };
+        self.call(
+            Target::Label(function.to_string()),
+            &values,
+            &return_type,
+            home,
+        );
+        Ok(())
+    }
+
+    // Takes the value out of an Option or Result: Unwrap panics if there is
+    // none, and UnwrapOr returns the default instead, dropping whichever of
+    // the value, the error and the default it does not return
+    fn unwrap(
+        &mut self,
+        intrinsic: Intrinsic,
+        values: &[(Value, Type)],
+        arg_types: &[Type],
+        home: Option<Value>,
+        span: Span,
+    ) -> Result<(), String> {
+        let ty = &arg_types[0];
+        let value = memory(&values[0].0);
+        let (tag, _) = self.program.field(ty, sema::tag_field(ty))?;
+        let some = self.new_label();
+        self.emit(&format!("cmpb $0, {}", value.at(tag).operand()));
+        self.emit(&format!("jne {some}"));
+        let inner = match ty {
+            Type::Option(inner) | Type::Result(inner, _) => (**inner).clone(),
+            ty => return Err(format!("Cannot unwrap a value of type {ty}")),
+        };
+        if intrinsic == Intrinsic::Unwrap {
+            let message = match ty {
+                Type::Result(..) => "called unwrap on an Err",
+                _ => "called unwrap on None",
+            };
+            self.panic(span, message);
+        } else {
+            let done = self.new_label();
+            if let Type::Result(_, error) = ty {
+                if self.program.is_owned(error) {
+                    let (offset, _) = self.program.field(ty, "error")?;
+                    self.drop_value(&Value::Mem(value.at(offset)), error);
+                }
+            }
+            if let Some(home) = &home {
+                self.copy(&values[1].0, home, &inner);
+            }
+            self.emit(&format!("jmp {done}"));
+            self.place(&some);
+            if self.program.is_owned(&inner) {
+                self.drop_value(&values[1].0, &inner);
+            }
+            if let Some(home) = &home {
+                let (offset, _) = self.program.field(ty, "value")?;
+                self.copy(&Value::Mem(value.at(offset)), home, &inner);
+            }
+            self.place(&done);
+            return Ok(());
+        }
+        self.place(&some);
+        if let Some(home) = &home {
+            let (offset, _) = self.program.field(ty, "value")?;
+            self.copy(&Value::Mem(value.at(offset)), home, &inner);
+        }
+        Ok(())
+    }
+
+    fn drop_value(&mut self, value: &Value, ty: &Type) {
+        self.address_of(value, ty);
+        self.emit(&format!("call dr_{}", generator::mangle(ty)));
+    }
+
+    // Writes the text of a print in as few writes as it can, and each
+    // argument with the runtime function for its type
+    fn print(
+        &mut self,
+        function: IoFunction,
+        pieces: &[FormatPiece],
+        args: &[Operand],
+    ) -> Result<(), String> {
+        let fd = Value::Imm(if function == IoFunction::Eprintln {
+            2
+        } else {
+            1
+        });
+        let mut text = String::new();
+        let mut args = args.iter();
+        for piece in pieces {
+            match piece {
+                FormatPiece::Text(piece) => text.push_str(piece),
+                FormatPiece::Placeholder => {
+                    let Some(arg) = args.next() else {
+                        return Err(format!("{function:?} has too few arguments"));
+                    };
+                    self.write(&fd, &std::mem::take(&mut text));
+                    let ty = self.operand_type(arg);
+                    let value = self.operand(arg)?;
+                    let (function, args) = match &ty {
+                        Type::Bool => ("nrt_print_bool", vec![(value, ty)]),
+                        Type::Char => ("nrt_print_char", vec![(value, ty)]),
+                        Type::Str => ("nrt_write", vec![(value, ty)]),
+                        Type::String => {
+                            ("nrt_write", vec![(Value::Mem(memory(&value)), Type::Str)])
+                        }
+                        ty if ty.is_signed() => ("nrt_print_i64", vec![(value, ty.clone())]),
+                        ty if ty.is_integer() => ("nrt_print_u64", vec![(value, ty.clone())]),
+                        ty => return Err(format!("Cannot print a value of type {ty}")),
+                    };
+                    let args: Vec<(Value, Type)> = std::iter::once((fd.clone(), Type::I32))
+                        .chain(args)
+                        .collect();
+                    self.call(
+                        Target::Label(function.to_string()),
+                        &args,
+                        &Type::Void,
+                        None,
+                    );
+                }
+            }
+        }
+        if function != IoFunction::Print {
+            text.push('\n');
+        }
+        self.write(&fd, &text);
+        Ok(())
+    }
+
+    fn write(&mut self, fd: &Value, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let label = self.program.string(text);
+        self.call(
+            Target::Label("nrt_write".to_string()),
+            &[
+                (fd.clone(), Type::I32),
+                (Value::Mem(Mem::Label(label, 0)), Type::Str),
+            ],
+            &Type::Void,
+            None,
+        );
+    }
+}
+
+// The type of the elements of the buffer a pointer is to
+fn buffer_element(ty: &Type) -> Result<Type, String> {
+    match pointee(ty) {
+        Type::Buffer(element) => Ok(*element),
+        ty => Err(format!("{ty} is not a buffer")),
+    }
+}
+
+// A value that lives in memory; one without a size is never read or written
+fn memory(value: &Value) -> Mem {
+    match value {
+        Value::Mem(mem) => mem.clone(),
+        _ => Mem::Frame(0),
+    }
+}
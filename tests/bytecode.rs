@@ -0,0 +1,117 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+// Tests the `.nbc` bytecode format. Every example program that builds must
+// disassemble the same from the file it is written to as from its source,
+// and a file cut short anywhere must be rejected rather than run.
+
+extern crate tempfile;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn nimra(args: &[&str], dir: &Path) -> Result<Output, String> {
+    Command::new(env!("CARGO_BIN_EXE_nimra"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to run the compiler: {e}"))
+}
+
+fn examples(tests: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(tests)
+        .expect("Failed to read the tests directory")
+        .map(|entry| entry.expect("Failed to read the tests directory").path())
+        .filter(|path| path.extension().is_some_and(|found| found == "nimra") && builds(path))
+        .collect();
+    files.sort();
+    files
+}
+
+// Whether an example builds, which one whose `.err` holds errors does not
+fn builds(source: &Path) -> bool {
+    !fs::read_to_string(source.with_extension("err")).is_ok_and(|err| err.contains("error["))
+}
+
+// Writes an example as bytecode and disassembles it from the file and from
+// its source, which must give the same text
+fn round_trip(tests: &Path, source: &Path, level: &str, out_dir: &Path) -> Result<(), String> {
+    let source = source.to_str().ok_or("Path is not UTF-8")?;
+    let file = out_dir.join("program.nbc");
+    let file = file.to_str().ok_or("Path is not UTF-8")?;
+    let written = nimra(&[source, level, "--backend=bytecode", "-o", file], tests)?;
+    if !written.status.success() || !Path::new(file).exists() {
+        return Err(format!(
+            "Did not write its bytecode:\n{}",
+            String::from_utf8_lossy(&written.stderr)
+        ));
+    }
+    let expected = nimra(&["disasm", source, level], tests)?;
+    let read = nimra(&["disasm", file], tests)?;
+    if read.stdout != expected.stdout || !read.stderr.is_empty() {
+        return Err(format!(
+            "Read back as:\n{}\nfrom:\n{}\nstderr:\n{}",
+            String::from_utf8_lossy(&read.stdout),
+            String::from_utf8_lossy(&expected.stdout),
+            String::from_utf8_lossy(&read.stderr)
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn examples_read_back_from_bytecode() {
+    let tests = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let out_dir = tempfile::tempdir().expect("Failed to create temp directory");
+    let failures: Vec<String> = examples(&tests)
+        .iter()
+        .flat_map(|source| ["-O0", "-O3"].map(|level| (source, level)))
+        .filter_map(|(source, level)| {
+            round_trip(&tests, source, level, out_dir.path())
+                .err()
+                .map(|e| format!("{} at {level}: {e}", source.display()))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+#[test]
+fn truncated_bytecode_is_rejected() {
+    let tests = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let out_dir = tempfile::tempdir().expect("Failed to create temp directory");
+    let file = out_dir.path().join("program.nbc");
+    let file = file.to_str().expect("Path is not UTF-8");
+    let written = nimra(
+        &["hello_world.nimra", "--backend=bytecode", "-o", file],
+        &tests,
+    )
+    .expect("Failed to write bytecode");
+    assert!(written.status.success(), "Did not write its bytecode");
+    let bytes = fs::read(file).expect("Failed to read the bytecode");
+    for len in 0..bytes.len() {
+        fs::write(file, &bytes[..len]).expect("Failed to write the bytecode");
+        let run = nimra(&["run", file], &tests).expect("Failed to run the VM");
+        let err = String::from_utf8_lossy(&run.stderr);
+        assert!(
+            run.stdout.is_empty() && err.starts_with("Bytecode error: "),
+            "Ran {len} of {} bytes, printing:\n{}\n{err}",
+            bytes.len(),
+            String::from_utf8_lossy(&run.stdout)
+        );
+    }
+}
@@ -0,0 +1,280 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+// Tests the diagnostics the compiler reports: what each stage finds out about
+// a program, and how it is rendered for people and for tools.
+
+extern crate nimra;
+
+use nimra::diagnostic::codes;
+use nimra::{compile_source, Diagnostic, Options};
+
+fn errors(source: &str) -> Vec<Diagnostic> {
+    let mut options = Options::new("main.nimra");
+    options.emit_ic = true;
+    compile_source(source, options).expect_err("Compiled a program with errors")
+}
+
+// The code of the first error in a program, and the line and column it points at
+fn found(source: &str) -> (&'static str, usize, usize) {
+    let diagnostics = errors(source);
+    let label = diagnostics[0].primary().expect("Points at nothing");
+    (diagnostics[0].code, label.span.line, label.span.column)
+}
+
+#[test]
+fn lexer_reports_every_unknown_character() {
+    let diagnostics = errors("void fn main() {\n    let a = 1 @ 2;\n    let b = 3 # 4;\n}\n");
+    let found: Vec<(&str, usize, usize)> = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            let label = diagnostic.primary().expect("Points at nothing");
+            (diagnostic.code, label.span.line, label.span.column)
+        })
+        .collect();
+    assert_eq!(
+        found,
+        [
+            (codes::UNKNOWN_CHARACTER, 2, 15),
+            (codes::UNKNOWN_CHARACTER, 3, 15)
+        ]
+    );
+}
+
+#[test]
+fn errors_render_with_the_source_they_point_at() {
+    let source = "void fn main() {\n    let count = 1;\n    let x = cont + 1;\n}\n";
+    let diagnostics = errors(source);
+    assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+    assert_eq!(
+        diagnostics[0].render("main.nimra", source),
+        "Semantic error[E0201]: In function main: Unknown variable cont\n \
+         --> main.nimra:3:13\n  \
+         |\n\
+         3 |     let x = cont + 1;\n  \
+         |             ^^^^ not found in this scope\n  \
+         |\n  \
+         = note: a variable named count is in scope\n"
+    );
+
+    let source = "void fn main() {\n    let x = 1\n    let y = 2;\n}\n";
+    let diagnostics = errors(source);
+    assert_eq!(diagnostics[0].code, codes::UNEXPECTED_TOKEN);
+    assert!(
+        diagnostics[0]
+            .render("main.nimra", source)
+            .ends_with("  = help: insert the missing token: `;`\n"),
+        "{}",
+        diagnostics[0].render("main.nimra", source)
+    );
+}
+
+#[test]
+fn errors_render_as_json_for_tools() {
+    let diagnostics = errors("void fn main() {\n    let s = \"a\\tb\n}\n");
+    assert_eq!(
+        diagnostics[0].to_json("dir/main.nimra"),
+        "{\"severity\":\"error\",\"code\":\"E0002\",\"stage\":\"lex\",\
+         \"message\":\"String literal has no closing quote\",\"file\":\"dir/main.nimra\",\
+         \"labels\":[{\"line\":2,\"column\":13,\"message\":\"\",\"primary\":true}],\"notes\":[],\
+         \"fixes\":[{\"message\":\"close the string at the end of the input\",\
+         \"line\":4,\"column\":1,\"length\":0,\"replacement\":\"\\\"\"}]}"
+    );
+
+    let diagnostics = errors("void fn f() {}\n");
+    assert_eq!(
+        diagnostics[0].to_json("main.nimra"),
+        "{\"severity\":\"error\",\"code\":\"E0202\",\"stage\":\"semantic\",\
+         \"message\":\"No main function\",\"file\":\"main.nimra\",\"labels\":[],\
+         \"notes\":[\"a program starts by calling `void fn main()`\"],\"fixes\":[]}"
+    );
+}
+
+#[test]
+fn strings_are_moved_and_borrowed_rather_than_copied() {
+    let diagnostics = errors(
+        "import println from io;\nvoid fn main() {\n    let s = String::from(\"a\");\n    let t = s;\n    println(\"{} {}\", s, t);\n}\n",
+    );
+    assert_eq!(
+        diagnostics[0].message,
+        "In function main: Cannot use s after it was moved"
+    );
+
+    let diagnostics = errors(
+        "void fn main() {\n    let mut s = String::from(\"a\");\n    let view: str = s;\n    s.push('b');\n}\n",
+    );
+    assert_eq!(
+        diagnostics[0].message,
+        "In function main: Cannot mutate s while it is borrowed"
+    );
+}
+
+#[test]
+fn temporary_boxes_cannot_be_printed() {
+    let diagnostics =
+        errors("import println from io;\nvoid fn main() {\n    println(\"{}\", Box::new(5));\n}\n");
+    assert_eq!(
+        diagnostics[0].message,
+        "In function main: A temporary Box<i32> must be stored in a variable before it is used here"
+    );
+}
+
+#[test]
+fn semantic_errors_have_codes_of_their_own_and_point_at_the_problem() {
+    assert_eq!(
+        found("void fn main() {\n    let mut x = 1;\n    let a = &mut x;\n    let b = &mut x;\n    *a = 2;\n}\n"),
+        (codes::BORROW_CONFLICT, 4, 9)
+    );
+    assert_eq!(
+        found("void fn main() {\n    let x: bool = 1;\n}\n"),
+        (codes::MISMATCHED_TYPES, 2, 9)
+    );
+    assert_eq!(
+        found("i32 fn f(a: i32) {\n    return a;\n}\nvoid fn main() {\n    let y = f(1, 2);\n}\n"),
+        (codes::WRONG_ARGUMENT_COUNT, 5, 13)
+    );
+
+    let diagnostics =
+        errors("import exit from os;\nvoid fn main() {\n    let a = [1, 2];\n    exit(a[2]);\n}\n");
+    assert_eq!(diagnostics[0].code, codes::INDEX_OUT_OF_BOUNDS);
+    assert_eq!(
+        diagnostics[0].primary().map(|label| label.span.line),
+        Some(4)
+    );
+}
+
+#[test]
+fn changing_an_immutable_variable_points_at_its_declaration() {
+    let source = "void fn main() {\n    let y = 2;\n    y = 3;\n}\n";
+    let diagnostics = errors(source);
+    assert_eq!(
+        diagnostics[0].render("main.nimra", source),
+        "Semantic error[E0212]: In function main: Cannot assign to immutable variable y\n \
+         --> main.nimra:3:7\n  \
+         |\n\
+         2 |     let y = 2;\n  \
+         |         - y is declared here\n\
+         3 |     y = 3;\n  \
+         |       ^ cannot be changed\n  \
+         |\n  \
+         = help: make y mutable: `mut`\n"
+    );
+}
+
+#[test]
+fn unneeded_mut_is_fixed_by_removing_it() {
+    let diagnostics = errors(
+        "void fn f(mut a: i32) {}\nvoid fn main() {\n    let mut\n        x = 1;\n    f(x);\n}\n",
+    );
+    let fix = &diagnostics[0].fixes[0];
+    assert_eq!(diagnostics[0].code, codes::UNNEEDED_MUT);
+    assert_eq!((fix.span.line, fix.span.column, fix.len), (1, 11, 4));
+    assert_eq!(fix.replacement, "");
+
+    let diagnostics = errors("void fn main() {\n    let mut\n        x = 1;\n}\n");
+    let fix = &diagnostics[0].fixes[0];
+    assert_eq!(
+        diagnostics[0].message,
+        "In function main: Variable x does not need to be mutable"
+    );
+    assert_eq!((fix.span.line, fix.span.column, fix.len), (2, 9, 4));
+}
+
+#[test]
+fn containers_only_hold_plain_values() {
+    let diagnostics = errors(
+        "import Vec from collections;\nvoid fn main() {\n    let names: Vec<String> = Vec::new();\n}\n",
+    );
+    assert_eq!(diagnostics[0].code, codes::INVALID_TYPE);
+    assert_eq!(
+        diagnostics[0].message,
+        "In function main: Vec<String> cannot hold values that own heap memory"
+    );
+}
+
+#[test]
+fn errors_about_a_declaration_or_statement_point_at_it() {
+    assert_eq!(
+        found("void fn f() {}\nvoid fn f() {}\nvoid fn main() {}\n"),
+        (codes::DUPLICATE_DEFINITION, 2, 9)
+    );
+    assert_eq!(
+        found("void fn main() {\n    let s = String::from(\"a\");\n    while true {\n        let t = s;\n    }\n}\n"),
+        (codes::CANNOT_MOVE, 4, 13)
+    );
+    assert_eq!(
+        found("void fn main() {\n    let i = 1;\n    match i {\n        0 => return,\n    }\n}\n"),
+        (codes::NON_EXHAUSTIVE_MATCH, 3, 5)
+    );
+}
+
+#[test]
+fn markers_line_up_under_tabs() {
+    let source = "void fn main() {\n\tlet x = cont;\n}\n";
+    let rendered = errors(source)[0].render("main.nimra", source);
+    assert!(
+        rendered.contains("2 | \tlet x = cont;\n  | \t    ^ not found in this scope\n"),
+        "{}",
+        rendered
+    );
+}
+
+#[test]
+fn variables_are_used_only_once_they_have_a_value() {
+    assert_eq!(
+        found("import println from io;\nvoid fn main() {\n    let x: i32;\n    if true {\n        x = 1;\n    }\n    println(\"{}\", x);\n}\n"),
+        (codes::UNINITIALIZED_VARIABLE, 7, 5)
+    );
+}
+
+#[test]
+fn references_cannot_outlive_what_they_refer_to() {
+    assert_eq!(
+        found("&i32 fn f(a: &i32) {\n    let x = 1;\n    return &x;\n}\nvoid fn main() {}\n"),
+        (codes::DANGLING_REFERENCE, 3, 5)
+    );
+    assert_eq!(
+        found("void fn main() {\n    let r: &i32;\n    if true {\n        let y = 2;\n        r = &y;\n    }\n    let z = *r;\n}\n"),
+        (codes::DANGLING_REFERENCE, 5, 11)
+    );
+}
+
+#[test]
+fn functions_that_can_finish_without_returning_point_at_their_name() {
+    assert_eq!(
+        found(
+            "i32 fn f(a: i32) {\n    if a > 0 {\n        return 1;\n    }\n}\nvoid fn main() {}\n"
+        ),
+        (codes::MISSING_RETURN, 1, 8)
+    );
+}
+
+#[test]
+fn type_arguments_must_satisfy_their_bounds() {
+    assert_eq!(
+        found("struct P {\n    x: i32,\n}\nbool fn same<T: Eq>(a: T, b: T) {\n    return a == b;\n}\nvoid fn main() {\n    let s = same(P { x: 1 }, P { x: 2 });\n}\n"),
+        (codes::UNSATISFIED_BOUND, 8, 13)
+    );
+}
+
+#[test]
+fn a_trait_is_implemented_once_for_a_type() {
+    assert_eq!(
+        found("trait Show {\n    void fn show(self);\n}\nimpl Show for i32 {\n    void fn show(self) {}\n}\nimpl Show for i32 {\n    void fn show(self) {}\n}\nvoid fn main() {}\n"),
+        (codes::DUPLICATE_DEFINITION, 7, 6)
+    );
+}
@@ -0,0 +1,224 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+// Compiles every example program in this directory and checks what it does
+// against the golden files beside it: `<name>.out` holds what it prints to
+// standard output, and the optional `<name>.err` and `<name>.exit` what it
+// prints to standard error and the code it exits with, which is otherwise 0.
+// An example whose `.err` holds errors is one that must not build, and the
+// compiler's own output is what is compared.
+// Each example is compiled unoptimized and fully optimized, by every
+// backend, and run by the interpreter, none of which may change what it does.
+// Bytecode is run by the VM in the compiler, and WebAssembly by node's WASI.
+// The LLVM backend is left out where neither clang nor llc is installed, and
+// the WebAssembly backend where node is not.
+
+extern crate tempfile;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// Runs the WebAssembly module it is given under WASI with this environment,
+// exiting as the module does
+const WASI_RUNNER: &str = "const { WASI } = require('node:wasi');
+const wasi = new WASI({ version: 'preview1', env: process.env, returnOnExit: true });
+const compiled = new WebAssembly.Module(require('node:fs').readFileSync(process.argv[1]));
+process.exitCode = wasi.start(new WebAssembly.Instance(compiled, wasi.getImportObject()));";
+
+// Compiles and runs one example at an optimization level with a backend,
+// describing how it differs from its golden files
+fn check(
+    tests: &Path,
+    source: &Path,
+    level: &str,
+    backend: &str,
+    out_dir: &Path,
+) -> Result<(), String> {
+    let name = example_name(source)?;
+    let mut executable = out_dir.join(format!("{name}{level}-{backend}"));
+    match backend {
+        "bytecode" => {
+            executable.set_extension("nbc");
+        }
+        "wasm" => {
+            executable.set_extension("wasm");
+        }
+        _ => {}
+    }
+    // Panics report the path the compiler was given, so it is kept short
+    let compiled = Command::new(env!("CARGO_BIN_EXE_nimra"))
+        .arg(source.file_name().ok_or("Example with no file name")?)
+        .arg(level)
+        .arg(format!("--backend={backend}"))
+        .arg("-o")
+        .arg(&executable)
+        .current_dir(tests)
+        .output()
+        .map_err(|e| format!("Failed to run the compiler: {e}"))?;
+    // An example that does not build must fail to the same errors
+    if !executable.exists() {
+        return compare(tests, name, &compiled).map_err(|e| format!("Did not compile: {e}"));
+    }
+    let mut command = match backend {
+        "bytecode" => {
+            let mut vm = Command::new(env!("CARGO_BIN_EXE_nimra"));
+            vm.arg("run").arg(&executable);
+            vm
+        }
+        "wasm" => {
+            let mut node = Command::new("node");
+            node.args(["--no-warnings", "-e", WASI_RUNNER])
+                .arg(&executable);
+            node
+        }
+        _ => Command::new(&executable),
+    };
+    let run = command
+        .env_remove("NIMRA_BACKTRACE")
+        .output()
+        .map_err(|e| format!("Failed to run: {e}"))?;
+    compare(tests, name, &run)
+}
+
+// Runs one example with the interpreter, which must do the same as the
+// compiled program
+fn interpret(tests: &Path, source: &Path) -> Result<(), String> {
+    let name = example_name(source)?;
+    let run = Command::new(env!("CARGO_BIN_EXE_nimra"))
+        .arg("run")
+        .arg("--interp")
+        .arg(source.file_name().ok_or("Example with no file name")?)
+        .env_remove("NIMRA_BACKTRACE")
+        .current_dir(tests)
+        .output()
+        .map_err(|e| format!("Failed to run the interpreter: {e}"))?;
+    compare(tests, name, &run)
+}
+
+fn example_name(source: &Path) -> Result<&str, String> {
+    source
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| "Example with no name".to_string())
+}
+
+// Describes how what a run of an example did differs from its golden files
+fn compare(tests: &Path, name: &str, run: &Output) -> Result<(), String> {
+    let golden = |extension: &str| fs::read_to_string(tests.join(name).with_extension(extension));
+    let expected_out = golden("out").map_err(|e| format!("Cannot read {name}.out: {e}"))?;
+    let expected_exit: i32 = match golden("exit") {
+        Ok(code) => code
+            .trim()
+            .parse()
+            .map_err(|e| format!("Bad exit code in {name}.exit: {e}"))?,
+        Err(_) => 0,
+    };
+    let out = String::from_utf8_lossy(&run.stdout);
+    let err = String::from_utf8_lossy(&run.stderr);
+    if out != expected_out {
+        return Err(format!("Printed:\n{out}\nbut expected:\n{expected_out}"));
+    }
+    if let Ok(expected_err) = golden("err") {
+        if err != expected_err {
+            return Err(format!(
+                "Printed to stderr:\n{err}\nbut expected:\n{expected_err}"
+            ));
+        }
+    }
+    if run.status.code() != Some(expected_exit) {
+        return Err(format!(
+            "Exited with {:?} but expected {expected_exit}; stderr:\n{err}",
+            run.status.code()
+        ));
+    }
+    Ok(())
+}
+
+// The example programs, in order
+fn sources(tests: &Path) -> Vec<PathBuf> {
+    let mut sources: Vec<PathBuf> = fs::read_dir(tests)
+        .expect("Failed to read the tests directory")
+        .map(|entry| entry.expect("Failed to read the tests directory").path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "nimra")
+        })
+        .collect();
+    sources.sort();
+    sources
+}
+
+#[test]
+fn examples_match_golden_files() {
+    let tests = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let out_dir = tempfile::tempdir().expect("Failed to create temp directory");
+    let sources = sources(&tests);
+    let installed = |tool: &str| {
+        Command::new(tool)
+            .arg("--version")
+            .output()
+            .is_ok_and(|output| output.status.success())
+    };
+    let llvm_installed = installed("clang") || installed("llc");
+    let node_installed = installed("node");
+    let failures: Vec<String> = sources
+        .iter()
+        .flat_map(|source| {
+            [
+                ("-O0", "c"),
+                ("-O3", "c"),
+                ("-O0", "asm"),
+                ("-O3", "asm"),
+                ("-O0", "bytecode"),
+                ("-O3", "bytecode"),
+                ("-O0", "llvm"),
+                ("-O3", "llvm"),
+                ("-O0", "wasm"),
+                ("-O3", "wasm"),
+            ]
+            .map(|(level, backend)| (source, level, backend))
+        })
+        .filter(|(_, _, backend)| *backend != "llvm" || llvm_installed)
+        .filter(|(_, _, backend)| *backend != "wasm" || node_installed)
+        .filter_map(|(source, level, backend)| {
+            check(&tests, source, level, backend, out_dir.path())
+                .err()
+                .map(|e| {
+                    format!(
+                        "{} at {level} with --backend={backend}: {e}",
+                        source.display()
+                    )
+                })
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+#[test]
+fn interpreted_examples_match_golden_files() {
+    let tests = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let failures: Vec<String> = sources(&tests)
+        .iter()
+        .filter_map(|source| {
+            interpret(&tests, source)
+                .err()
+                .map(|e| format!("{} with --interp: {e}", source.display()))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
@@ -0,0 +1,109 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+// Tests the compiler used as a library, which must report what it finds as
+// diagnostics rather than print them, and hand back what the options ask it
+// to emit.
+
+extern crate nimra;
+
+use nimra::{compile_source, Backend, Options, Output, Session, Severity, Stage};
+
+fn emit_ic() -> Options {
+    let mut options = Options::new("main.nimra");
+    options.emit_ic = true;
+    options
+}
+
+#[test]
+fn compiles_source_to_what_the_options_ask_for() {
+    let source = "import println from io;\n\nvoid fn main() {\n    println(\"{}\", 1 + 2);\n}\n";
+    let artifacts = compile_source(source, emit_ic()).expect("Did not compile");
+    assert!(artifacts.warnings.is_empty(), "{:?}", artifacts.warnings);
+    let Output::Text(ic) = artifacts.output else {
+        panic!(
+            "Built {:?} instead of emitting intermediate code",
+            artifacts.output
+        );
+    };
+    assert!(ic.contains("fn main"), "Emitted:\n{}", ic);
+
+    let mut options = Options::new("main.nimra");
+    options.backend = Backend::Wasm;
+    options.emit_wat = true;
+    let artifacts = compile_source(source, options).expect("Did not compile");
+    assert!(
+        matches!(&artifacts.output, Output::Text(wat) if wat.starts_with("(module")),
+        "{:?}",
+        artifacts.output
+    );
+}
+
+#[test]
+fn errors_and_warnings_come_back_as_diagnostics() {
+    let diagnostics = compile_source("void fn main() { let x = ; }", emit_ic())
+        .expect_err("Compiled a program that does not parse");
+    assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+    assert_eq!(diagnostics[0].severity, Severity::Error);
+    assert_eq!(diagnostics[0].stage, Stage::Parse);
+
+    let source = "void fn main() {\n    return;\n    let x = 1;\n}\n";
+    let diagnostics = compile_source(&source.replace("1;", "y;"), emit_ic())
+        .expect_err("Compiled a program that uses an unknown variable");
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "Semantic error[E0201]: In function main: Unknown variable y"
+    );
+
+    let artifacts = compile_source(source, emit_ic()).expect("Did not compile");
+    assert_eq!(artifacts.warnings.len(), 1, "{:?}", artifacts.warnings);
+    assert_eq!(
+        artifacts.warnings[0].to_string(),
+        "Warning[W0001]: In function main: Unreachable code after the return on line 2"
+    );
+}
+
+#[test]
+fn stages_run_one_at_a_time() {
+    let mut session = Session::new(emit_ic());
+    let (tokens, spans) = session.lex("void fn main() {}").expect("Did not lex");
+    let ast = session.parse(&tokens, &spans).expect("Did not parse");
+    let program = session.check(&ast).expect("Did not check");
+    let ic = session.lower(program).expect("Did not lower");
+    let ic = session.optimize(ic).expect("Did not optimize");
+    let Ok(Output::Text(text)) = session.build(ic) else {
+        panic!("Did not emit intermediate code");
+    };
+    let ic = session.parse_ic(&text).expect("Did not read back");
+    assert!(session.build(ic).is_ok());
+    assert!(session.warnings().is_empty());
+}
+
+#[test]
+fn constant_overflow_wraps_in_release_builds() {
+    let source = "void fn main() {\n    let z: u8 = 255 + 1;\n}\n";
+    let diagnostics =
+        compile_source(source, emit_ic()).expect_err("Compiled an overflow in a debug build");
+    assert_eq!(diagnostics[0].code, "E0403", "{:?}", diagnostics);
+
+    let mut options = emit_ic();
+    options.release = true;
+    assert!(compile_source(source, options.clone()).is_ok());
+    let diagnostics = compile_source(&source.replace("255 + 1", "255 / 0"), options)
+        .expect_err("Compiled a division by zero in a release build");
+    assert_eq!(diagnostics[0].code, "E0403", "{:?}", diagnostics);
+}
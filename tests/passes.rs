@@ -0,0 +1,147 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+// Tests the optimizer on intermediate code written as text. Each
+// `passes/<name>.ic` starts with a comment giving the options it is
+// compiled with, and `<name>.out` holds the code `--emit=ic` prints for it,
+// with the optional `<name>.err` holding what is printed to standard error.
+// Every example program that builds must also read back from the text it
+// prints as.
+
+extern crate tempfile;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn nimra(args: &[&str], dir: &Path) -> Result<Output, String> {
+    Command::new(env!("CARGO_BIN_EXE_nimra"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to run the compiler: {e}"))
+}
+
+fn files_with_extension(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("Failed to read the tests directory")
+        .map(|entry| entry.expect("Failed to read the tests directory").path())
+        .filter(|path| path.extension().is_some_and(|found| found == extension))
+        .collect();
+    files.sort();
+    files
+}
+
+// Whether an example builds, which one whose `.err` holds errors does not
+fn builds(source: &Path) -> bool {
+    !fs::read_to_string(source.with_extension("err")).is_ok_and(|err| err.contains("error["))
+}
+
+// Runs the optimizer on one file, describing how what it prints differs
+// from the golden files
+fn check(passes: &Path, source: &Path) -> Result<(), String> {
+    let text = fs::read_to_string(source).map_err(|e| format!("Cannot read it: {e}"))?;
+    let flags = text
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("//"))
+        .ok_or("The first line should be a comment giving its options")?;
+    let file_name = source
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("File with no name")?;
+    let mut args = vec![file_name, "--emit=ic"];
+    args.extend(flags.split_whitespace());
+    let run = nimra(&args, passes)?;
+    let golden = |extension: &str| fs::read_to_string(source.with_extension(extension));
+    let expected_out = golden("out").map_err(|e| format!("Cannot read its .out: {e}"))?;
+    let out = String::from_utf8_lossy(&run.stdout);
+    let err = String::from_utf8_lossy(&run.stderr);
+    if out != expected_out {
+        return Err(format!(
+            "Printed:\n{out}\nbut expected:\n{expected_out}\nstderr:\n{err}"
+        ));
+    }
+    let expected_err = golden("err").unwrap_or_default();
+    if err != expected_err {
+        return Err(format!(
+            "Printed to stderr:\n{err}\nbut expected:\n{expected_err}"
+        ));
+    }
+    // Reporting an error makes the compiler fail
+    if run.status.success() == expected_err.contains("error[") {
+        return Err(format!("Exited with {:?}", run.status.code()));
+    }
+    Ok(())
+}
+
+#[test]
+fn passes_match_golden_files() {
+    let passes = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/passes");
+    let failures: Vec<String> = files_with_extension(&passes, "ic")
+        .iter()
+        .filter_map(|source| {
+            check(&passes, source)
+                .err()
+                .map(|e| format!("{}: {e}", source.display()))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+// Prints an example as text, reads that back and prints it again, which
+// must give the same text
+fn round_trip(tests: &Path, source: &Path, level: &str, out_dir: &Path) -> Result<(), String> {
+    let source = source.to_str().ok_or("Path is not UTF-8")?;
+    let emitted = nimra(&[source, level, "--emit=ic"], tests)?;
+    if !emitted.status.success() || !emitted.stderr.is_empty() {
+        return Err(format!(
+            "Did not print its code:\n{}",
+            String::from_utf8_lossy(&emitted.stderr)
+        ));
+    }
+    let text = out_dir.join("program.ic");
+    fs::write(&text, &emitted.stdout).map_err(|e| format!("Cannot write the code: {e}"))?;
+    let text = text.to_str().ok_or("Path is not UTF-8")?;
+    let reread = nimra(&[text, "-O0", "--emit=ic"], tests)?;
+    if reread.stdout != emitted.stdout {
+        return Err(format!(
+            "Read back as:\n{}\nfrom:\n{}\nstderr:\n{}",
+            String::from_utf8_lossy(&reread.stdout),
+            String::from_utf8_lossy(&emitted.stdout),
+            String::from_utf8_lossy(&reread.stderr)
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn examples_read_back_from_text() {
+    let tests = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let out_dir = tempfile::tempdir().expect("Failed to create temp directory");
+    let failures: Vec<String> = files_with_extension(&tests, "nimra")
+        .iter()
+        .filter(|source| builds(source))
+        .flat_map(|source| ["-O0", "-O3"].map(|level| (source, level)))
+        .filter_map(|(source, level)| {
+            round_trip(&tests, source, level, out_dir.path())
+                .err()
+                .map(|e| format!("{} at {level}: {e}", source.display()))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
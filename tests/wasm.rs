@@ -0,0 +1,127 @@
+/*
+ * Copyright (C) 2025 Vihaan Krishnan
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+ */
+
+// Tests the WebAssembly backend without running anything it builds. Every
+// example program that builds must make a module the validator in the
+// compiler accepts, and a module written by hand that is not valid must be
+// rejected.
+
+extern crate tempfile;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn nimra(args: &[&str], dir: &Path) -> Result<Output, String> {
+    Command::new(env!("CARGO_BIN_EXE_nimra"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to run the compiler: {e}"))
+}
+
+fn examples(tests: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(tests)
+        .expect("Failed to read the tests directory")
+        .map(|entry| entry.expect("Failed to read the tests directory").path())
+        .filter(|path| path.extension().is_some_and(|found| found == "nimra") && builds(path))
+        .collect();
+    files.sort();
+    files
+}
+
+// Whether an example builds, which one whose `.err` holds errors does not
+fn builds(source: &Path) -> bool {
+    !fs::read_to_string(source.with_extension("err")).is_ok_and(|err| err.contains("error["))
+}
+
+// Builds an example as a module, which is only written once it validates
+fn build(tests: &Path, source: &Path, level: &str, out_dir: &Path) -> Result<(), String> {
+    let source = source.to_str().ok_or("Path is not UTF-8")?;
+    let file = out_dir.join("program.wasm");
+    let _ = fs::remove_file(&file);
+    let file = file.to_str().ok_or("Path is not UTF-8")?;
+    let built = nimra(&[source, level, "--backend=wasm", "-o", file], tests)?;
+    let bytes = fs::read(file).map_err(|_| {
+        format!(
+            "Did not write its module:\n{}",
+            String::from_utf8_lossy(&built.stderr)
+        )
+    })?;
+    if !bytes.starts_with(b"\0asm\x01\0\0\0") {
+        return Err("Wrote a file that is not a WebAssembly module".to_string());
+    }
+    Ok(())
+}
+
+// Assembles WebAssembly text written by hand, returning what the compiler
+// printed to standard error and whether it wrote the module
+fn assemble(text: &str) -> (String, bool) {
+    let out_dir = tempfile::tempdir().expect("Failed to create temp directory");
+    fs::write(out_dir.path().join("module.wat"), text).expect("Failed to write the module");
+    let built = nimra(&["module.wat", "-o", "module.wasm"], out_dir.path())
+        .expect("Failed to run the compiler");
+    (
+        String::from_utf8_lossy(&built.stderr).into_owned(),
+        out_dir.path().join("module.wasm").exists(),
+    )
+}
+
+#[test]
+fn examples_build_valid_modules() {
+    let tests = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let out_dir = tempfile::tempdir().expect("Failed to create temp directory");
+    let failures: Vec<String> = examples(&tests)
+        .iter()
+        .flat_map(|source| ["-O0", "-O3"].map(|level| (source, level)))
+        .filter_map(|(source, level)| {
+            build(&tests, source, level, out_dir.path())
+                .err()
+                .map(|e| format!("{} at {level}: {e}", source.display()))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+#[test]
+fn handwritten_modules_are_validated() {
+    let (err, written) = assemble(
+        "(module\n  (func $answer (export \"answer\") (result i32)\n    i32.const 42\n  )\n)\n",
+    );
+    assert!(
+        written && err.is_empty(),
+        "Rejected a valid module:\n{}",
+        err
+    );
+
+    for (text, expected) in [
+        (
+            "(module\n  (func $f (result i32)\n    i64.const 1\n  )\n)\n",
+            "Expected I32 on the stack, found I64",
+        ),
+        ("(module\n  (func $f\n    call $g\n  )\n)\n", "$g"),
+        ("(module\n  (func $f\n    br 1\n  )\n)\n", "br"),
+    ] {
+        let (err, written) = assemble(text);
+        assert!(
+            !written && err.starts_with("Compilation error: ") && err.contains(expected),
+            "Accepted or misreported:\n{}\nprinting:\n{}",
+            text,
+            err
+        );
+    }
+}